@@ -0,0 +1,107 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A client for the Espresso address book.
+//!
+//! The address book maps user addresses to the public keys needed to send to them. Keys are
+//! inserted with an [InsertPubKey] request, which the key's owner signs, and looked up with a
+//! [LookupPubKey] request. [AddressBook] speaks this protocol, and checks that every key it looks
+//! up really belongs to the requested address, so a compromised address book cannot substitute its
+//! own keys.
+
+use crate::client::{parse_error_body, response_body};
+use crate::error::Error;
+use crate::types::{AddressBookEntry, InsertPubKey, LookupPubKey, UserAddress};
+use crate::wire::binary;
+use jf_cap::keys::{UserKeyPair, UserPubKey};
+use std::marker::PhantomData;
+use surf::http::mime;
+use surf::{StatusCode, Url};
+
+/// A typed client for the address book service.
+///
+/// `E` is the error type of the address book API.
+pub struct AddressBook<E> {
+    url: Url,
+    client: surf::Client,
+    _phantom: PhantomData<fn() -> E>,
+}
+
+impl<E> Clone for AddressBook<E> {
+    fn clone(&self) -> Self {
+        Self {
+            url: self.url.clone(),
+            client: self.client.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<E: Error> AddressBook<E> {
+    /// A client for the address book at `url`.
+    pub fn new(url: Url) -> Self {
+        Self {
+            url,
+            client: surf::Client::new().with(parse_error_body::<E>),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Publish the public key of `key_pair`.
+    pub async fn insert(&self, key_pair: &UserKeyPair) -> Result<(), E> {
+        let body = InsertPubKey::new(key_pair).map_err(|err| E::catch_all(err.to_string()))?;
+        self.post("insert_pubkey", &body)
+            .await
+            .map_err(E::from_client_error)?;
+        Ok(())
+    }
+
+    /// Look up the public key for `address`.
+    ///
+    /// Returns [None] if the address book has no key for `address`, and fails if the address book
+    /// responds with a key for a different address.
+    pub async fn lookup(&self, address: &UserAddress) -> Result<Option<UserPubKey>, E> {
+        let body = LookupPubKey {
+            address: address.clone(),
+        };
+        let mut res = match self.post("request_pubkey", &body).await {
+            Ok(res) => res,
+            Err(err) if err.status() == StatusCode::NotFound => return Ok(None),
+            Err(err) => return Err(E::from_client_error(err)),
+        };
+        let entry: AddressBookEntry = response_body(&mut res)
+            .await
+            .map_err(E::from_client_error)?;
+        if entry.address != *address || !entry.is_consistent() {
+            return Err(E::catch_all(format!(
+                "address book returned a key for the wrong address (requested {}, got {})",
+                address,
+                UserAddress::from(entry.pub_key.address())
+            )));
+        }
+        Ok(Some(entry.pub_key))
+    }
+
+    async fn post(
+        &self,
+        path: &str,
+        body: &impl serde::Serialize,
+    ) -> Result<surf::Response, surf::Error> {
+        let url = self
+            .url
+            .join(path)
+            .map_err(|err| surf::Error::from_str(StatusCode::BadRequest, err.to_string()))?;
+        let bytes = binary::serialize(body)
+            .map_err(|err| surf::Error::from_str(StatusCode::BadRequest, err.to_string()))?;
+        self.client
+            .post(url)
+            .header("Accept", mime::BYTE_STREAM.to_string())
+            .body(bytes)
+            .content_type(mime::BYTE_STREAM)
+            .await
+    }
+}
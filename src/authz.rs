@@ -0,0 +1,508 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Role-based authorization of authenticated clients.
+//!
+//! A client can prove who it is in several ways: with an API key in an `Authorization: Bearer`
+//! header, with a TLS client certificate (see [peer](crate::server::peer)), or by signing its
+//! request with a key, like a CAP user key, which the service checks with its own middleware and
+//! records with [authenticate]. Each of these is an [Identity].
+//!
+//! The [Authz] middleware decides what a client may do. A [RoleProvider] maps each identity of a
+//! request to a set of roles, and routes declare the roles which may use them. A request to a
+//! route which needs a role the client does not have fails with 403 Forbidden, and the error is a
+//! [Forbidden] which says which roles were needed and which the client has.
+//! [ErrorBody](crate::server::ErrorBody) recognizes this error, and adds it to JSON error bodies in
+//! a `forbidden` field next to the error (see [wire::error](crate::wire::error)). A request with no
+//! identity at all fails with 401 Unauthorized instead.
+//!
+//! Routes which declare no roles are open to everyone. Handlers can make finer decisions with
+//! [roles], which are found for every request with an identity.
+//!
+//! ```
+//! use net::authz::{Authz, StaticRoles};
+//!
+//! let roles = StaticRoles::new()
+//!     .api_key("operator-key", ["operator"])
+//!     .peer("spiffe://espresso/relayer", ["relayer"]);
+//! let mut app = tide::new();
+//! app.with(
+//!     Authz::new(roles)
+//!         .require("/admin/*", ["operator"])
+//!         .require_method(tide::http::Method::Post, "/submit", ["relayer", "operator"]),
+//! );
+//! ```
+
+use crate::redact::SafeToServe;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub use server::*;
+
+/// The error of a request to a route which needs a role the client does not have.
+///
+/// [ErrorBody](crate::server::ErrorBody) recognizes this error, and adds it to the error body.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Forbidden {
+    /// The template of the route, like `/admin/*`.
+    pub route: String,
+    /// The roles which may use the route. Any one of them is enough.
+    pub required: Vec<String>,
+    /// The roles the client has.
+    pub granted: Vec<String>,
+}
+
+impl Display for Forbidden {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} requires one of the roles [{}]",
+            self.route,
+            self.required.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for Forbidden {}
+
+impl SafeToServe for Forbidden {}
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+mod server {
+    use super::*;
+    use crate::server::peer::{peer_identity, PeerIdentity};
+    use crate::server::route::RouteTable;
+    use sha2::{Digest, Sha256};
+    use std::collections::{BTreeSet, HashMap};
+    use std::sync::Arc;
+    use tide::http::Method;
+    use tide::{Middleware, Next, Request, Response, StatusCode};
+
+    /// A way in which a client has proven who it is.
+    #[derive(Clone, PartialEq, Eq)]
+    pub enum Identity {
+        /// The API key in the `Authorization: Bearer` header.
+        ApiKey(String),
+        /// The certificate the client presented to a TLS-terminating proxy.
+        Peer(PeerIdentity),
+        /// A key which signed the request, like a CAP user key in its tagged base64 encoding.
+        Signer(String),
+    }
+
+    // API keys are secrets, so they are left out of logs.
+    impl fmt::Debug for Identity {
+        fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+            match self {
+                Self::ApiKey(_) => f.write_str("ApiKey(..)"),
+                Self::Peer(peer) => f.debug_tuple("Peer").field(peer).finish(),
+                Self::Signer(key) => f.debug_tuple("Signer").field(key).finish(),
+            }
+        }
+    }
+
+    // Identities established by other middleware, through [authenticate].
+    #[derive(Clone, Debug, Default)]
+    struct Authenticated(Vec<Identity>);
+
+    /// Record that the client of `req` has proven `identity`.
+    ///
+    /// This is for authentication which this crate does not do itself, like checking a signature on
+    /// the request. It must be done by middleware added before [Authz].
+    pub fn authenticate<State>(req: &mut Request<State>, identity: Identity) {
+        if let Some(authenticated) = req.ext_mut::<Authenticated>() {
+            authenticated.0.push(identity);
+        } else {
+            req.set_ext(Authenticated(vec![identity]));
+        }
+    }
+
+    /// All the identities of the client of `req`.
+    pub fn identities<State>(req: &Request<State>) -> Vec<Identity> {
+        let mut identities = vec![];
+        if let Some(key) = req
+            .header("Authorization")
+            .and_then(|value| value.as_str().strip_prefix("Bearer "))
+        {
+            identities.push(Identity::ApiKey(key.to_string()));
+        }
+        identities.extend(authenticated(req));
+        identities
+    }
+
+    /// The identities of the client of `req` which something has checked: its certificate, and
+    /// those recorded with [authenticate].
+    ///
+    /// Unlike [identities], this leaves out the bearer token unless middleware has validated it,
+    /// since any client can send any token.
+    pub fn authenticated<State>(req: &Request<State>) -> Vec<Identity> {
+        let mut identities = vec![];
+        if let Some(peer) = peer_identity(req) {
+            identities.push(Identity::Peer(peer));
+        }
+        if let Some(authenticated) = req.ext::<Authenticated>() {
+            identities.extend(authenticated.0.iter().cloned());
+        }
+        identities
+    }
+
+    /// The roles granted to the client of a request.
+    #[derive(Clone, Debug, Default, PartialEq, Eq)]
+    pub struct Roles(pub BTreeSet<String>);
+
+    impl Roles {
+        pub fn has(&self, role: &str) -> bool {
+            self.0.contains(role)
+        }
+    }
+
+    /// The roles of the client of a request, if it passed through [Authz] and has an identity.
+    pub fn roles<State>(req: &Request<State>) -> Option<&Roles> {
+        req.ext()
+    }
+
+    /// A source of the roles of identities.
+    ///
+    /// Providers may look roles up in a database or a directory service, so errors are reported
+    /// as strings; a request whose roles cannot be found fails with 503 Service Unavailable.
+    #[tide::utils::async_trait]
+    pub trait RoleProvider: Send + Sync + 'static {
+        /// The roles of `identity`, which are empty if the identity is unknown.
+        async fn roles(&self, identity: &Identity) -> Result<BTreeSet<String>, String>;
+    }
+
+    /// A [RoleProvider] with a fixed set of identities.
+    #[derive(Clone, Debug, Default)]
+    pub struct StaticRoles {
+        // API keys are looked up by their hashes, so the time taken does not depend on the key.
+        api_keys: HashMap<[u8; 32], BTreeSet<String>>,
+        peers: HashMap<String, BTreeSet<String>>,
+        signers: HashMap<String, BTreeSet<String>>,
+    }
+
+    fn role_set<R: Into<String>>(roles: impl IntoIterator<Item = R>) -> BTreeSet<String> {
+        roles.into_iter().map(Into::into).collect()
+    }
+
+    impl StaticRoles {
+        /// No identities, and so no roles.
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Grant `roles` to clients with the API key `key`.
+        pub fn api_key<R: Into<String>>(
+            mut self,
+            key: impl AsRef<[u8]>,
+            roles: impl IntoIterator<Item = R>,
+        ) -> Self {
+            let hash = Sha256::digest(key.as_ref()).into();
+            self.api_keys
+                .entry(hash)
+                .or_default()
+                .extend(role_set(roles));
+            self
+        }
+
+        /// Grant `roles` to clients with a certificate which has `name` as a subject alternative
+        /// name, or has the hex-encoded fingerprint `name`.
+        pub fn peer<R: Into<String>>(
+            mut self,
+            name: impl Into<String>,
+            roles: impl IntoIterator<Item = R>,
+        ) -> Self {
+            self.peers
+                .entry(name.into())
+                .or_default()
+                .extend(role_set(roles));
+            self
+        }
+
+        /// Grant `roles` to clients which sign their requests with `key`.
+        pub fn signer<R: Into<String>>(
+            mut self,
+            key: impl Into<String>,
+            roles: impl IntoIterator<Item = R>,
+        ) -> Self {
+            self.signers
+                .entry(key.into())
+                .or_default()
+                .extend(role_set(roles));
+            self
+        }
+    }
+
+    #[tide::utils::async_trait]
+    impl RoleProvider for StaticRoles {
+        async fn roles(&self, identity: &Identity) -> Result<BTreeSet<String>, String> {
+            let mut roles = BTreeSet::new();
+            match identity {
+                Identity::ApiKey(key) => {
+                    let hash: [u8; 32] = Sha256::digest(key.as_bytes()).into();
+                    roles.extend(self.api_keys.get(&hash).into_iter().flatten().cloned());
+                }
+                Identity::Peer(peer) => {
+                    let fingerprint = hex::encode(peer.fingerprint);
+                    for name in peer.san.iter().chain([&fingerprint]) {
+                        roles.extend(self.peers.get(name).into_iter().flatten().cloned());
+                    }
+                }
+                Identity::Signer(key) => {
+                    roles.extend(self.signers.get(key).into_iter().flatten().cloned());
+                }
+            }
+            Ok(roles)
+        }
+    }
+
+    /// Server middleware which only lets clients with the roles a route requires use it.
+    ///
+    /// See the [module documentation](self).
+    #[derive(Clone)]
+    pub struct Authz {
+        provider: Arc<dyn RoleProvider>,
+        routes: RouteTable<Vec<String>>,
+        methods: HashMap<Method, RouteTable<Vec<String>>>,
+    }
+
+    impl Authz {
+        /// Authorize requests using the roles from `provider`.
+        ///
+        /// Until routes declare roles, every request is allowed.
+        pub fn new(provider: impl RoleProvider) -> Self {
+            Self {
+                provider: Arc::new(provider),
+                routes: RouteTable::new(),
+                methods: HashMap::new(),
+            }
+        }
+
+        /// Allow only clients with one of `roles` to use routes matching `template`.
+        ///
+        /// If several templates match a request, the most specific one applies.
+        pub fn require<R: Into<String>>(
+            mut self,
+            template: impl Into<String>,
+            roles: impl IntoIterator<Item = R>,
+        ) -> Self {
+            self.routes
+                .insert(template, roles.into_iter().map(Into::into).collect());
+            self
+        }
+
+        /// Allow only clients with one of `roles` to use `method` on routes matching `template`.
+        ///
+        /// For requests with `method`, this takes precedence over roles declared with
+        /// [require](Self::require).
+        pub fn require_method<R: Into<String>>(
+            mut self,
+            method: Method,
+            template: impl Into<String>,
+            roles: impl IntoIterator<Item = R>,
+        ) -> Self {
+            self.methods
+                .entry(method)
+                .or_default()
+                .insert(template, roles.into_iter().map(Into::into).collect());
+            self
+        }
+
+        // The route template matched by a request, and the roles which may use it, if any.
+        fn required(&self, method: Method, path: &str) -> Option<(String, Vec<String>)> {
+            self.methods
+                .get(&method)
+                .and_then(|routes| routes.lookup(path))
+                .or_else(|| self.routes.lookup(path))
+                .map(|(template, roles)| (template.as_str().to_string(), roles.clone()))
+        }
+    }
+
+    #[tide::utils::async_trait]
+    impl<State: Clone + Send + Sync + 'static> Middleware<State> for Authz {
+        async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+            let identities = identities(&req);
+            if !identities.is_empty() {
+                let mut roles = BTreeSet::new();
+                for identity in &identities {
+                    let granted = self.provider.roles(identity).await.map_err(|err| {
+                        tide::Error::from_str(
+                            StatusCode::ServiceUnavailable,
+                            format!("roles unavailable: {}", err),
+                        )
+                    })?;
+                    roles.extend(granted);
+                }
+                req.set_ext(Roles(roles));
+            }
+
+            let (route, required) = match self.required(req.method(), req.url().path()) {
+                Some(required) => required,
+                None => return Ok(next.run(req).await),
+            };
+            let granted = match roles(&req) {
+                Some(roles) => roles,
+                None => {
+                    let mut res = Response::new(StatusCode::Unauthorized);
+                    res.set_error(tide::Error::from_str(
+                        StatusCode::Unauthorized,
+                        format!("{} requires authentication", route),
+                    ));
+                    return Ok(res);
+                }
+            };
+            if required.iter().any(|role| granted.has(role)) {
+                return Ok(next.run(req).await);
+            }
+            let mut res = Response::new(StatusCode::Forbidden);
+            res.set_error(tide::Error::new(
+                StatusCode::Forbidden,
+                Forbidden {
+                    route,
+                    required,
+                    granted: granted.0.iter().cloned().collect(),
+                },
+            ));
+            Ok(res)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use super::*;
+    use crate::server::forwarded::{ForwardingHeader, TrustedProxies};
+    use crate::server::peer::{ClientCerts, CLIENT_CERT};
+    use crate::wire::error::extract_forbidden;
+    use snafu::Snafu;
+    use std::convert::TryInto;
+    use tide::http::{Method, Url};
+    use tide::StatusCode;
+
+    #[derive(Clone, Debug, Deserialize, Serialize, Snafu, PartialEq, Eq)]
+    struct Error {
+        status: u16,
+        msg: String,
+    }
+
+    impl crate::Error for Error {
+        fn catch_all(msg: String) -> Self {
+            Self::catch_all_with_status(StatusCode::InternalServerError, msg)
+        }
+
+        fn catch_all_with_status(status: StatusCode, msg: String) -> Self {
+            Self {
+                status: status.into(),
+                msg,
+            }
+        }
+
+        fn status(&self) -> StatusCode {
+            self.status.try_into().unwrap()
+        }
+    }
+
+    // Records the `X-Signer` header as the signer of the request, standing in for middleware which
+    // checks a signature.
+    fn check_signature<'a>(
+        mut req: tide::Request<()>,
+        next: tide::Next<'a, ()>,
+    ) -> futures::future::BoxFuture<'a, tide::Result> {
+        Box::pin(async move {
+            if let Some(signer) = req.header("X-Signer").map(|h| h.as_str().to_string()) {
+                authenticate(&mut req, Identity::Signer(signer));
+            }
+            Ok(next.run(req).await)
+        })
+    }
+
+    async fn send(
+        app: &tide::Server<()>,
+        method: Method,
+        path: &str,
+        headers: &[(&str, String)],
+    ) -> tide::http::Response {
+        let mut req = tide::http::Request::new(
+            method,
+            Url::parse("http://localhost").unwrap().join(path).unwrap(),
+        );
+        req.insert_header("Accept", "application/json");
+        req.set_peer_addr(Some("10.0.0.1:4000"));
+        for (name, value) in headers {
+            req.insert_header(*name, value.as_str());
+        }
+        app.respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_authz() {
+        let provider = StaticRoles::new()
+            .api_key("operator-key", ["operator"])
+            .api_key("reader-key", ["reader"])
+            .peer("spiffe://espresso/relayer", ["relayer"])
+            .signer("USERPUBKEY~alice", ["reader"]);
+        let mut app = tide::new();
+        app.with(crate::server::add_error_body::<_, Error>);
+        app.with(ClientCerts::new(
+            TrustedProxies::new(ForwardingHeader::XForwardedFor)
+                .trust("10.0.0.0/8".parse().unwrap()),
+        ));
+        app.with(check_signature);
+        app.with(
+            Authz::new(provider)
+                .require("/admin/*", ["operator"])
+                .require("/submit", ["reader", "operator"])
+                .require_method(Method::Post, "/submit", ["relayer", "operator"]),
+        );
+        app.at("/admin/state").get(|_| async { Ok("state") });
+        app.at("/submit")
+            .get(|_| async { Ok("status") })
+            .post(|_| async { Ok("submitted") });
+        app.at("/whoami").get(|req: tide::Request<()>| async move {
+            Ok(format!("{:?}", roles(&req).map(|roles| &roles.0)))
+        });
+
+        let bearer = |key: &str| vec![("Authorization", format!("Bearer {}", key))];
+        let relayer = vec![(
+            CLIENT_CERT,
+            format!("Hash={};URI=spiffe://espresso/relayer", "ab".repeat(32)),
+        )];
+        let signer = vec![("X-Signer", "USERPUBKEY~alice".to_string())];
+
+        // Open routes need no identity, but handlers can still see the roles of identified clients.
+        let mut res = send(&app, Method::Get, "/whoami", &[]).await;
+        assert_eq!(res.body_string().await.unwrap(), "None");
+        let mut res = send(&app, Method::Get, "/whoami", &relayer).await;
+        assert_eq!(res.body_string().await.unwrap(), r#"Some({"relayer"})"#);
+
+        // Protected routes need an identity, and one of their roles.
+        let res = send(&app, Method::Get, "/admin/state", &[]).await;
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        let res = send(&app, Method::Get, "/admin/state", &bearer("operator-key")).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let mut res = send(&app, Method::Get, "/admin/state", &bearer("reader-key")).await;
+        assert_eq!(res.status(), StatusCode::Forbidden);
+        let body = res.body_bytes().await.unwrap();
+        assert_eq!(
+            extract_forbidden(&body),
+            Some(Forbidden {
+                route: "/admin/*".into(),
+                required: vec!["operator".into()],
+                granted: vec!["reader".into()],
+            })
+        );
+
+        // Roles can depend on the method.
+        let res = send(&app, Method::Post, "/submit", &relayer).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let res = send(&app, Method::Post, "/submit", &signer).await;
+        assert_eq!(res.status(), StatusCode::Forbidden);
+        let res = send(&app, Method::Get, "/submit", &signer).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let res = send(&app, Method::Get, "/submit", &relayer).await;
+        assert_eq!(res.status(), StatusCode::Forbidden);
+    }
+}
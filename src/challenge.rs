@@ -0,0 +1,654 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Challenges which clients must solve before using abuse-prone routes.
+//!
+//! Some routes, like the faucet, are cheap for a client to call and expensive or valuable for the
+//! server to serve. The [Challenges] middleware guards such routes: a request which does not carry
+//! the solution to a challenge fails with 429 Too Many Requests, and the [Challenge] is sent in the
+//! standard error body, in a `challenge` field next to the error (see
+//! [wire::error](crate::wire::error)). The client solves it and repeats the request with the
+//! solution in the [CHALLENGE_RESPONSE](crate::headers::CHALLENGE_RESPONSE) header.
+//!
+//! There are two kinds of challenge:
+//!  * a hashcash-style proof of work, which any client can solve by spending CPU time. The
+//!    [SolveChallenges] client middleware solves these automatically.
+//!  * a CAPTCHA from an external provider, which needs a human. The server is given a function
+//!    which checks the token produced by the provider's widget.
+//!
+//! Proof-of-work challenges are stateless: the token is signed by the server and carries its own
+//! difficulty and expiry, so any server sharing the secret can check a solution. The signature also
+//! covers the method and path of the request the challenge was issued for, and the client it was
+//! issued to (its [peer identity](crate::server::peer_identity), or else its
+//! [address](crate::server::client_info)), so a solution only unlocks the route it was solved for,
+//! and cannot be solved by one client and handed to many. Each solution is accepted only once by
+//! the servers which share a [replay store](crate::server::replay), which by default is a given
+//! server on its own.
+//!
+//! The middleware should be added only to the routes which need it, inside the error body
+//! middleware so that the challenge is added to the error:
+//!
+//! ```
+//! use net::challenge::Challenges;
+//! # #[derive(Clone, Debug, serde::Deserialize, serde::Serialize, snafu::Snafu)]
+//! # struct Error { msg: String }
+//! # impl net::Error for Error {
+//! #     fn catch_all(msg: String) -> Self { Self { msg } }
+//! #     fn status(&self) -> tide::StatusCode { tide::StatusCode::BadRequest }
+//! # }
+//!
+//! let mut app = tide::new();
+//! app.with(net::server::add_error_body::<_, Error>);
+//! app.at("/faucet")
+//!     .with(Challenges::proof_of_work([0x42; 32], 20))
+//!     .post(|_| async { Ok("funded") });
+//! ```
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::{self, Display, Formatter};
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub use server::*;
+
+#[cfg(feature = "client")]
+pub use client::*;
+
+/// The largest supported proof-of-work difficulty, in bits.
+pub const MAX_DIFFICULTY: u32 = 64;
+
+/// A challenge which a client must solve before its request is accepted.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Challenge {
+    /// Find a counter such that [hash](work_hash)`(token, counter)` starts with `difficulty` zero
+    /// bits.
+    ProofOfWork { token: String, difficulty: u32 },
+    /// Solve a CAPTCHA from `provider`, using the widget for `site_key`, and send the resulting
+    /// token.
+    Captcha { provider: String, site_key: String },
+}
+
+/// The error of a request which must solve `challenge` before it is accepted.
+///
+/// [ErrorBody](crate::server::ErrorBody) recognizes this error, and adds the challenge to the error
+/// body.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChallengeRequired {
+    pub challenge: Challenge,
+    /// Why the request was not accepted.
+    pub reason: String,
+}
+
+impl Display for ChallengeRequired {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl std::error::Error for ChallengeRequired {}
+
+/// The hash of a proof-of-work attempt.
+pub fn work_hash(token: &str, counter: u64) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(token.as_bytes());
+    hasher.update(counter.to_le_bytes());
+    hasher.finalize().into()
+}
+
+fn leading_zeros(hash: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in hash {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+/// Whether `counter` solves the proof-of-work challenge `token` with `difficulty`.
+pub fn check_work(token: &str, counter: u64, difficulty: u32) -> bool {
+    leading_zeros(&work_hash(token, counter)) >= difficulty
+}
+
+/// Find the smallest counter which solves the proof-of-work challenge `token` with `difficulty`.
+///
+/// This takes about `2^difficulty` hashes.
+pub fn solve(token: &str, difficulty: u32) -> u64 {
+    (0..)
+        .find(|counter| check_work(token, *counter, difficulty))
+        .unwrap()
+}
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+mod server {
+    use super::*;
+    use crate::headers::{ChallengeResponse, TypedHeader};
+    use crate::server::replay::{ReplayError, Replays};
+    use crate::server::{client_info, peer_identity};
+    use futures::future::BoxFuture;
+    use hmac::{Hmac, Mac};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tide::{Middleware, Next, Request, Response, StatusCode};
+
+    /// The minimum length of the signing secret for proof-of-work tokens, in bytes.
+    pub const MIN_SECRET_LEN: usize = 32;
+
+    type VerifyCaptcha = Arc<dyn Fn(String) -> BoxFuture<'static, bool> + Send + Sync>;
+
+    #[derive(Clone)]
+    enum Mode {
+        ProofOfWork {
+            key: Vec<u8>,
+            difficulty: u32,
+            // Tokens whose solutions have been accepted, until they expire.
+            used: Replays,
+        },
+        Captcha {
+            provider: String,
+            site_key: String,
+            verify: VerifyCaptcha,
+        },
+    }
+
+    /// Server middleware which requires each request to solve a [Challenge].
+    ///
+    /// See the [module documentation](super).
+    #[derive(Clone)]
+    pub struct Challenges {
+        mode: Mode,
+        ttl: Duration,
+    }
+
+    impl Challenges {
+        /// Require a proof of work of `difficulty` bits, with tokens signed by `secret`.
+        ///
+        /// Solving a challenge takes about `2^difficulty` hashes; 20 bits takes a fraction of a
+        /// second on a laptop.
+        ///
+        /// # Panics
+        ///
+        /// Panics if `secret` is shorter than [MIN_SECRET_LEN] bytes, or if `difficulty` is greater
+        /// than [MAX_DIFFICULTY].
+        pub fn proof_of_work(secret: impl AsRef<[u8]>, difficulty: u32) -> Self {
+            let key = secret.as_ref().to_vec();
+            assert!(
+                key.len() >= MIN_SECRET_LEN,
+                "challenge secret must be at least {} bytes",
+                MIN_SECRET_LEN
+            );
+            assert!(
+                difficulty <= MAX_DIFFICULTY,
+                "difficulty must be at most {} bits",
+                MAX_DIFFICULTY
+            );
+            Self {
+                mode: Mode::ProofOfWork {
+                    key,
+                    difficulty,
+                    used: Replays::in_memory(),
+                },
+                ttl: Duration::from_secs(5 * 60),
+            }
+        }
+
+        /// Require a CAPTCHA from `provider`, for the widget identified by `site_key`.
+        ///
+        /// `verify` is called with the token sent by the client, and checks it with the provider.
+        pub fn captcha(
+            provider: impl Into<String>,
+            site_key: impl Into<String>,
+            verify: impl Fn(String) -> BoxFuture<'static, bool> + Send + Sync + 'static,
+        ) -> Self {
+            Self {
+                mode: Mode::Captcha {
+                    provider: provider.into(),
+                    site_key: site_key.into(),
+                    verify: Arc::new(verify),
+                },
+                ttl: Duration::from_secs(5 * 60),
+            }
+        }
+
+        /// Remember used proof-of-work solutions in `replays`, instead of in memory.
+        ///
+        /// Servers which share `replays`, and the secret, accept each solution only once between
+        /// them. This has no effect on CAPTCHA challenges.
+        pub fn replays(mut self, replays: Replays) -> Self {
+            if let Mode::ProofOfWork { used, .. } = &mut self.mode {
+                *used = replays;
+            }
+            self
+        }
+
+        /// Proof-of-work challenges expire `ttl` after they are issued.
+        pub fn ttl(mut self, ttl: Duration) -> Self {
+            self.ttl = ttl;
+            self
+        }
+
+        /// A new challenge for `req`.
+        ///
+        /// A proof-of-work solution is only accepted for a request with the same method, path and
+        /// client as `req`.
+        pub fn challenge<State>(&self, req: &Request<State>) -> Challenge {
+            match &self.mode {
+                Mode::ProofOfWork {
+                    key, difficulty, ..
+                } => {
+                    let claims = format!(
+                        "{}.{}.{}",
+                        difficulty,
+                        unix_time() + self.ttl.as_secs(),
+                        new_nonce()
+                    );
+                    let tag = mac(key, &claims, &binding(req)).finalize().into_bytes();
+                    Challenge::ProofOfWork {
+                        token: format!("{}.{}", claims, hex::encode(tag)),
+                        difficulty: *difficulty,
+                    }
+                }
+                Mode::Captcha {
+                    provider, site_key, ..
+                } => Challenge::Captcha {
+                    provider: provider.clone(),
+                    site_key: site_key.clone(),
+                },
+            }
+        }
+
+        // Check the solution to a challenge for `req`, or explain why it is not accepted.
+        async fn check<State>(
+            &self,
+            req: &Request<State>,
+            response: Option<ChallengeResponse>,
+        ) -> Result<(), &'static str> {
+            match (&self.mode, response) {
+                (_, None) => Err("this route requires solving a challenge"),
+                (
+                    Mode::ProofOfWork {
+                        key,
+                        difficulty,
+                        used,
+                    },
+                    Some(ChallengeResponse::ProofOfWork { token, counter }),
+                ) => {
+                    let expires = check_token(key, *difficulty, &token, &binding(req))?;
+                    if !check_work(&token, counter, *difficulty) {
+                        return Err("invalid proof of work");
+                    }
+                    used.check(&token, expires).await.map_err(|err| match err {
+                        ReplayError::Replayed => "challenge has already been used",
+                        // Fail closed, so an outage of a shared store can't be used to replay.
+                        ReplayError::Store { .. } => "challenge could not be checked, try again",
+                    })
+                }
+                (Mode::Captcha { verify, .. }, Some(ChallengeResponse::Captcha(token))) => {
+                    if verify(token).await {
+                        Ok(())
+                    } else {
+                        Err("invalid CAPTCHA")
+                    }
+                }
+                _ => Err("wrong kind of challenge response"),
+            }
+        }
+    }
+
+    // The request and client a token is issued for. This is signed along with the claims in the
+    // token, but not sent in it, since the server can work it out again from the solution.
+    fn binding<State>(req: &Request<State>) -> String {
+        let client = match peer_identity(req) {
+            Some(peer) => hex::encode(peer.fingerprint),
+            None => client_info(req)
+                .addr
+                .map(|addr| addr.to_string())
+                .unwrap_or_default(),
+        };
+        format!("{} {} {}", req.method(), req.url().path(), client)
+    }
+
+    fn mac(key: &[u8], claims: &str, binding: &str) -> Hmac<Sha256> {
+        // HMAC accepts keys of any length.
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        // Claims never contain a newline, so this separates them from the binding.
+        mac.update(claims.as_bytes());
+        mac.update(b"\n");
+        mac.update(binding.as_bytes());
+        mac
+    }
+
+    // Check the signature and expiry of a proof-of-work token for a request with `binding`,
+    // returning its expiry.
+    fn check_token(
+        key: &[u8],
+        difficulty: u32,
+        token: &str,
+        binding: &str,
+    ) -> Result<u64, &'static str> {
+        let invalid = "invalid challenge token";
+        let (claims, tag) = token.rsplit_once('.').ok_or(invalid)?;
+        let tag = hex::decode(tag).map_err(|_| invalid)?;
+        mac(key, claims, binding)
+            .verify_slice(&tag)
+            .map_err(|_| invalid)?;
+        let mut parts = claims.split('.');
+        let signed_difficulty = parts.next().and_then(|d| d.parse::<u32>().ok());
+        let expires = parts.next().and_then(|e| e.parse::<u64>().ok());
+        match (signed_difficulty, expires) {
+            // A token issued before the difficulty was raised is not enough.
+            (Some(signed), _) if signed < difficulty => Err(invalid),
+            (Some(_), Some(expires)) if expires > unix_time() => Ok(expires),
+            (Some(_), Some(_)) => Err("challenge has expired"),
+            _ => Err(invalid),
+        }
+    }
+
+    // A value which is never repeated by this process, so that tokens issued in the same second are
+    // different.
+    fn new_nonce() -> String {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        let mut hasher = Sha256::new();
+        hasher.update(nanos.to_le_bytes());
+        hasher.update(COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+        hex::encode(&hasher.finalize()[..16])
+    }
+
+    fn unix_time() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+
+    #[tide::utils::async_trait]
+    impl<State: Clone + Send + Sync + 'static> Middleware<State> for Challenges {
+        async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+            let response = ChallengeResponse::get(&req)?;
+            match self.check(&req, response).await {
+                Ok(()) => Ok(next.run(req).await),
+                Err(reason) => {
+                    let mut res = Response::new(StatusCode::TooManyRequests);
+                    res.set_error(tide::Error::new(
+                        StatusCode::TooManyRequests,
+                        ChallengeRequired {
+                            challenge: self.challenge(&req),
+                            reason: reason.to_string(),
+                        },
+                    ));
+                    Ok(res)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+mod client {
+    use super::*;
+    use crate::headers::{ChallengeResponse, TypedHeader};
+    use crate::wire::error::extract_challenge;
+    use surf::middleware::{Middleware, Next};
+    use surf::{Client, Request, StatusCode};
+
+    /// Client middleware which solves proof-of-work challenges automatically.
+    ///
+    /// When a response is a 429 with a proof-of-work [Challenge], the challenge is solved and the
+    /// request is sent again with the solution. Other responses, including CAPTCHA challenges, are
+    /// passed through unchanged.
+    ///
+    /// Challenges harder than the maximum difficulty are not solved, so that a misconfigured or
+    /// malicious server cannot make the client spin forever. Challenges are solved on a blocking
+    /// thread, except in WebAssembly builds, where there is none.
+    ///
+    /// Sending a request again means sending its body again, so the body is kept in memory.
+    /// Requests whose bodies are streamed, or larger than [MAX_RETRY_BODY] bytes, are only sent
+    /// once, and a challenge in the response is passed through.
+    #[derive(Clone, Copy, Debug)]
+    pub struct SolveChallenges {
+        max_difficulty: u32,
+    }
+
+    /// The size of the largest request body [SolveChallenges] keeps in order to send it again.
+    pub const MAX_RETRY_BODY: usize = 64 << 10;
+
+    impl Default for SolveChallenges {
+        fn default() -> Self {
+            Self { max_difficulty: 24 }
+        }
+    }
+
+    impl SolveChallenges {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Only solve challenges of at most `max_difficulty` bits.
+        pub fn max_difficulty(mut self, max_difficulty: u32) -> Self {
+            self.max_difficulty = max_difficulty;
+            self
+        }
+    }
+
+    #[surf::utils::async_trait]
+    impl Middleware for SolveChallenges {
+        async fn handle(&self, mut req: Request, client: Client, next: Next<'_>) -> surf::Result {
+            // Keep the body, in case the request has to be sent again, unless it is too big to
+            // keep.
+            let body = req.take_body();
+            let body = match body.len() {
+                Some(len) if len <= MAX_RETRY_BODY => body.into_bytes().await?,
+                _ => {
+                    req.set_body(body);
+                    return next.run(req, client).await;
+                }
+            };
+            let mut retry = req.clone();
+            req.set_body(body.clone());
+            let mut res = next.run(req, client.clone()).await?;
+            if res.status() != StatusCode::TooManyRequests {
+                return Ok(res);
+            }
+
+            let bytes = res.body_bytes().await?;
+            let (token, difficulty) = match extract_challenge(&bytes) {
+                Some(Challenge::ProofOfWork { token, difficulty })
+                    if difficulty <= self.max_difficulty =>
+                {
+                    (token, difficulty)
+                }
+                _ => {
+                    res.set_body(bytes);
+                    return Ok(res);
+                }
+            };
+            #[cfg(not(target_arch = "wasm32"))]
+            let (token, counter) = async_std::task::spawn_blocking(move || {
+                let counter = solve(&token, difficulty);
+                (token, counter)
+            })
+            .await;
+            #[cfg(target_arch = "wasm32")]
+            let counter = solve(&token, difficulty);
+            retry.set_body(body);
+            ChallengeResponse::ProofOfWork { token, counter }.insert(&mut retry);
+            next.run(retry, client).await
+        }
+    }
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod test {
+    use super::*;
+    use crate::headers::{ChallengeResponse, TypedHeader};
+    use crate::test_util::{Error, Server};
+    use crate::wire::error::extract_challenge;
+    use futures::FutureExt;
+    use std::time::Duration;
+    use tide::http::{Method, Url};
+    use tide::StatusCode;
+
+    fn server(challenges: Challenges) -> tide::Server<()> {
+        let mut app = tide::new();
+        app.with(crate::server::add_error_body::<_, Error>);
+        app.at("/faucet")
+            .with(challenges.clone())
+            .post(|_| async { Ok("funded") });
+        app.at("/airdrop")
+            .with(challenges)
+            .post(|_| async { Ok("dropped") });
+        app
+    }
+
+    async fn post(
+        app: &tide::Server<()>,
+        response: Option<ChallengeResponse>,
+    ) -> tide::http::Response {
+        post_to(app, "/faucet", response).await
+    }
+
+    async fn post_to(
+        app: &tide::Server<()>,
+        path: &str,
+        response: Option<ChallengeResponse>,
+    ) -> tide::http::Response {
+        let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+        let mut req = tide::http::Request::new(Method::Post, url);
+        req.insert_header("Accept", "application/octet-stream");
+        if let Some(response) = response {
+            response.insert(&mut req);
+        }
+        app.respond(req).await.unwrap()
+    }
+
+    // The challenge in the body of a response, which must be a 429.
+    async fn challenge(mut res: tide::http::Response) -> Challenge {
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+        // Challenges are always sent as JSON, even if the client prefers binary.
+        assert_eq!(res.content_type().unwrap(), tide::http::mime::JSON);
+        let body = res.body_bytes().await.unwrap();
+        let err: Error = serde_json::from_slice(&body).unwrap();
+        assert_eq!(err.msg, "this route requires solving a challenge");
+        extract_challenge(&body).unwrap()
+    }
+
+    #[test]
+    fn test_solve() {
+        let counter = solve("token", 8);
+        assert!(check_work("token", counter, 8));
+        assert!((0..counter).all(|c| !check_work("token", c, 8)));
+        assert_eq!(leading_zeros(&[0, 0x10, 0xff]), 11);
+    }
+
+    #[async_std::test]
+    async fn test_proof_of_work() {
+        let app = server(Challenges::proof_of_work([1; 32], 8));
+        let (token, difficulty) = match challenge(post(&app, None).await).await {
+            Challenge::ProofOfWork { token, difficulty } => (token, difficulty),
+            challenge => panic!("expected proof of work, got {:?}", challenge),
+        };
+        assert_eq!(difficulty, 8);
+
+        // A wrong solution is rejected.
+        let counter = solve(&token, difficulty);
+        let wrong = (0..).find(|c| !check_work(&token, *c, difficulty)).unwrap();
+        let res = post(
+            &app,
+            Some(ChallengeResponse::ProofOfWork {
+                token: token.clone(),
+                counter: wrong,
+            }),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+
+        // The right solution is only accepted for the route it was issued for, and only once.
+        let solution = ChallengeResponse::ProofOfWork { token, counter };
+        let res = post_to(&app, "/airdrop", Some(solution.clone())).await;
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+        let res = post(&app, Some(solution.clone())).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let res = post(&app, Some(solution)).await;
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+
+        // Tokens signed with another secret, or expired, are rejected.
+        let other = Challenges::proof_of_work([2; 32], 8);
+        let expired = Challenges::proof_of_work([1; 32], 8).ttl(Duration::ZERO);
+        for challenges in [other, expired] {
+            let token = match challenge(post(&server(challenges), None).await).await {
+                Challenge::ProofOfWork { token, .. } => token,
+                _ => unreachable!(),
+            };
+            let counter = solve(&token, 8);
+            let res = post(
+                &app,
+                Some(ChallengeResponse::ProofOfWork { token, counter }),
+            )
+            .await;
+            assert_eq!(res.status(), StatusCode::TooManyRequests);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_captcha() {
+        let app = server(Challenges::captcha("hcaptcha", "site", |token| {
+            async move { token == "human" }.boxed()
+        }));
+        assert_eq!(
+            challenge(post(&app, None).await).await,
+            Challenge::Captcha {
+                provider: "hcaptcha".into(),
+                site_key: "site".into()
+            }
+        );
+        let res = post(&app, Some(ChallengeResponse::Captcha("robot".into()))).await;
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+        let res = post(&app, Some(ChallengeResponse::Captcha("human".into()))).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    #[async_std::test]
+    async fn test_solve_challenges() {
+        let app = server(Challenges::proof_of_work([1; 32], 8));
+        let client = surf::Client::new()
+            .with(SolveChallenges::new())
+            .with(Server(app.clone()));
+        let mut res = client
+            .post("http://localhost/faucet")
+            .body("request")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.body_string().await.unwrap(), "funded");
+
+        // Challenges which are too hard are passed through.
+        let client = surf::Client::new()
+            .with(SolveChallenges::new().max_difficulty(4))
+            .with(Server(app));
+        let res = client.post("http://localhost/faucet").await.unwrap();
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+
+        // So are challenges to requests with streamed bodies, which can't be sent again.
+        let client = surf::Client::new()
+            .with(SolveChallenges::new())
+            .with(Server(server(Challenges::proof_of_work([1; 32], 8))));
+        let body = surf::Body::from_reader(futures::io::Cursor::new(b"request".to_vec()), None);
+        let res = client
+            .post("http://localhost/faucet")
+            .body(body)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+    }
+}
@@ -5,11 +5,79 @@
 // This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
 // You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::disco::ServerError;
 use crate::error::Error;
+use crate::headers::{LedgerHeight, TypedHeader};
+use crate::wire::{
+    binary,
+    body::{self, BodyBytes, Origin},
+    envelope::{Envelope, EnvelopeVersion, Meta, VERSION},
+    error::extract_help,
+    evolve::Decoding,
+    ndjson,
+    problem::{self, Problem},
+    stream,
+};
 use futures::future::BoxFuture;
 use futures::prelude::*;
-use serde::Deserialize;
-use surf::{middleware::Next, Client, Request, Response, StatusCode};
+use futures::stream::BoxStream;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+use surf::{
+    http::{mime, Mime},
+    middleware::Next,
+    Client, Request, Response, StatusCode,
+};
+
+pub mod backend;
+pub mod cache;
+pub mod cookies;
+#[cfg(feature = "types")]
+pub mod eqs;
+pub mod hedge;
+pub mod poll;
+pub mod queue;
+pub mod stack;
+pub mod tail;
+pub mod url;
+pub mod vcr;
+
+pub use crate::url;
+pub use cookies::CookieJar;
+pub use hedge::{hedge, Hedge};
+pub use poll::{poll_pages, poll_stream, PageStream, PollStream};
+pub use tail::backfill_then_tail;
+pub use url::Route;
+
+// A timer for measuring latency. `std::time::Instant` is not implemented in browsers, so
+// WebAssembly builds use the JavaScript clock instead.
+#[derive(Clone, Copy)]
+pub(crate) struct Stopwatch {
+    #[cfg(not(target_arch = "wasm32"))]
+    start: std::time::Instant,
+    #[cfg(target_arch = "wasm32")]
+    start_ms: f64,
+}
+
+impl Stopwatch {
+    pub(crate) fn start() -> Self {
+        Self {
+            #[cfg(not(target_arch = "wasm32"))]
+            start: std::time::Instant::now(),
+            #[cfg(target_arch = "wasm32")]
+            start_ms: js_sys::Date::now(),
+        }
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        #[cfg(not(target_arch = "wasm32"))]
+        return self.start.elapsed();
+        #[cfg(target_arch = "wasm32")]
+        return Duration::from_secs_f64((js_sys::Date::now() - self.start_ms).max(0.0) / 1000.0);
+    }
+}
 
 /// Deserialize the body of a response.
 ///
@@ -20,31 +88,91 @@ use surf::{middleware::Next, Client, Request, Response, StatusCode};
 pub async fn response_body<T: for<'de> Deserialize<'de>>(
     res: &mut Response,
 ) -> Result<T, surf::Error> {
-    if let Some(content_type) = res.header("Content-Type") {
-        match content_type.as_str() {
-            "application/json" => res.body_json().await,
-            "application/octet-stream" => {
-                bincode::deserialize(&res.body_bytes().await?).map_err(|err| {
-                    surf::Error::from_str(
-                        StatusCode::InternalServerError,
-                        format!("response body fails to deserialize: {}", err),
-                    )
-                })
-            }
-            content_type => Err(surf::Error::from_str(
-                StatusCode::UnsupportedMediaType,
-                format!("unsupported content type {}", content_type),
-            )),
-        }
-    } else {
-        Err(surf::Error::from_str(
-            StatusCode::UnsupportedMediaType,
-            "unspecified content type in response",
-        ))
+    response_bytes(res).await?.decode()
+}
+
+/// Deserialize the body of a response, as strictly as `decoding` says.
+///
+/// With [Decoding::Tolerant], a client can read responses from newer versions of a service which
+/// have added fields to their types; see [wire::evolve](crate::wire::evolve).
+pub async fn response_body_with<T: for<'de> Deserialize<'de>>(
+    res: &mut Response,
+    decoding: Decoding,
+) -> Result<T, surf::Error> {
+    response_bytes(res).await?.decode_with(decoding)
+}
+
+/// Deserialize the body of a response to a request for an [envelope](crate::wire::envelope).
+///
+/// If the server wrapped the body, the envelope is returned as it is. Otherwise, for example
+/// because the server does not support envelopes, the bare body is returned with whatever metadata
+/// can be found in the response headers.
+pub async fn response_envelope<T: for<'de> Deserialize<'de>>(
+    res: &mut Response,
+) -> Result<Envelope<T>, surf::Error> {
+    if EnvelopeVersion::get(&*res)? == Some(EnvelopeVersion(VERSION)) {
+        return response_body(res).await;
     }
+    let meta = Meta {
+        ledger_height: LedgerHeight::get(&*res)?.map(|LedgerHeight(height)| height),
+        ..Default::default()
+    };
+    Ok(Envelope {
+        data: response_body(res).await?,
+        meta,
+    })
+}
+
+/// A request body which sends each item of `items` as it becomes available.
+///
+/// This is for uploads too large to hold in memory; the server reads the items one at a time with
+/// [request_body_stream](crate::server::request_body_stream). See
+/// [wire::stream](crate::wire::stream) for the format, and
+/// [with_progress](crate::wire::stream::with_progress) to observe the progress of an upload.
+///
+/// # Panics
+///
+/// Panics if `ty` is not `application/json` or `application/octet-stream`.
+pub fn stream_body<T, S>(ty: Mime, items: S) -> surf::Body
+where
+    T: Serialize,
+    S: Stream<Item = T> + Unpin + Send + Sync + 'static,
+{
+    stream::encode(ty, items)
+}
+
+/// Deserialize the lines of a newline-delimited JSON response as they arrive.
+///
+/// This is the client side of [ndjson_response](crate::server::ndjson_response), for exports too
+/// large to hold in memory. The stream ends after the first error.
+pub fn response_ndjson<T: DeserializeOwned>(
+    res: &mut Response,
+) -> BoxStream<'static, Result<T, surf::Error>> {
+    ndjson::decode(Origin::Response, res.take_body())
+}
+
+/// Read the body of a response into memory, without deserializing it.
+///
+/// The result can be deserialized into types which borrow from the body using [BodyBytes::decode].
+pub async fn response_bytes(res: &mut Response) -> Result<BodyBytes, surf::Error> {
+    let content_type = res
+        .header("Content-Type")
+        .map(|content_type| content_type.as_str().to_string());
+    BodyBytes::read(Origin::Response, content_type, res.take_body()).await
+}
+
+/// Deserialize a response body which has already been read into memory.
+///
+/// This implements the same protocol as [response_body], for client backends which do not use
+/// surf responses. `content_type` is the value of the Content-Type header of the response, if any.
+pub fn decode_body<T: for<'de> Deserialize<'de>>(
+    content_type: Option<&str>,
+    bytes: &[u8],
+) -> Result<T, surf::Error> {
+    body::decode(Origin::Response, content_type, bytes)
 }
 
-async fn response_error<E: Error>(res: &mut Response) -> E {
+async fn response_error<E: Error>(res: &mut Response) -> (E, Option<String>) {
     // To add context to the error, try to interpret the response body as a serialized error. Since
     // `body_json`, `body_string`, etc. consume the response body, we will extract the body as raw
     // bytes and then try various potential decodings based on the response headers and the contents
@@ -54,66 +182,174 @@ async fn response_error<E: Error>(res: &mut Response) -> E {
         Err(err) => {
             // If we are unable to even read the body, just return a generic error message based on
             // the status code.
-            return E::catch_all(format!(
-                "Request terminated with error {}. Failed to read request body due to {}",
+            let err = E::catch_all_with_status(
                 res.status(),
-                err
-            ));
+                format!(
+                    "Request terminated with error {}. Failed to read request body due to {}",
+                    res.status(),
+                    err
+                ),
+            );
+            return (err, None);
         }
     };
-    if let Some(content_type) = res.header("Content-Type") {
-        // If the response specifies a content type, check if it is one of the types we know how to
-        // deserialize, and if it is, we can then see if it deserializes to an `E`.
-        match content_type.as_str() {
-            "application/json" => {
-                if let Ok(err) = serde_json::from_slice(&bytes) {
-                    return err;
-                }
+    decode_error_and_help(
+        res.status(),
+        res.header("Content-Type")
+            .map(|content_type| content_type.as_str()),
+        &bytes,
+    )
+}
+
+/// Interpret the body of an error response, which has already been read into memory.
+///
+/// This implements the same protocol as the [parse_error_body] middleware, for client backends
+/// which do not use surf responses. `content_type` is the value of the Content-Type header of the
+/// response, if any.
+pub fn decode_error<E: Error>(status: StatusCode, content_type: Option<&str>, bytes: &[u8]) -> E {
+    decode_error_and_help(status, content_type, bytes).0
+}
+
+/// Interpret the body of an error response, along with its help message, if it has one.
+///
+/// See [wire::error](crate::wire::error) for the encoding of help messages.
+pub fn decode_error_and_help<E: Error>(
+    status: StatusCode,
+    content_type: Option<&str>,
+    bytes: &[u8],
+) -> (E, Option<String>) {
+    if let Some(err) = decode_exact_error(content_type, bytes) {
+        return err;
+    }
+    // If the body is not an `E`, it may still be the generic error envelope used by tide-disco
+    // services, in which case we can recover the error message.
+    match content_type {
+        Some("application/json") => {
+            if let Ok(err) = serde_json::from_slice::<ServerError>(bytes) {
+                return (err.into_error(), None);
+            }
+        }
+        Some("application/octet-stream") => {
+            if let Ok(err) = binary::deserialize::<ServerError>(bytes) {
+                return (err.into_error(), None);
             }
-            "application/octet-stream" => {
-                if let Ok(err) = bincode::deserialize(&bytes) {
-                    return err;
-                }
+        }
+        // Problem details from a service which does not use `E`.
+        Some(problem::CONTENT_TYPE) => {
+            if let Ok(problem) = serde_json::from_slice::<Problem>(bytes) {
+                let help = extract_help(mime::JSON.essence(), bytes, &());
+                return (problem.into_error(), help);
             }
-            _ => {}
         }
+        _ => {}
     }
     // If we get here, then we were not able to interpret the response body as an `E` directly. This
     // can be because:
     //  * the content type is not supported for deserialization
     //  * the content type was unspecified
-    //  * the body did not deserialize to an `E`
+    //  * the body did not deserialize to an `E` or a tide-disco error envelope
     // We have one thing left we can try: if the body is a string, we can use the `catch_all`
     // variant of `E` to include the contents of the string in the error message.
-    if let Ok(msg) = std::str::from_utf8(&bytes) {
-        return E::catch_all(msg.to_string());
+    if let Ok(msg) = std::str::from_utf8(bytes) {
+        return (E::catch_all_with_status(status, msg.to_string()), None);
     }
 
     // The response body was not an `E` or a string. Return the most helpful error message we can,
     // including the status code, content type, and raw body.
-    E::catch_all(format!(
-        "Request terminated with error {}. Content-Type: {}. Body: 0x{}",
-        res.status(),
-        match res.header("Content-Type") {
-            Some(content_type) => content_type.as_str(),
-            None => "unspecified",
-        },
-        hex::encode(&bytes)
-    ))
+    let err = E::catch_all_with_status(
+        status,
+        format!(
+            "Request terminated with error {}. Content-Type: {}. Body: 0x{}",
+            status,
+            content_type.unwrap_or("unspecified"),
+            hex::encode(bytes)
+        ),
+    );
+    (err, None)
+}
+
+// Deserialize an error body as an `E`, if the content type is one we know how to deserialize and
+// the body is a serialized `E`.
+fn decode_exact_error<E: Error>(
+    content_type: Option<&str>,
+    bytes: &[u8],
+) -> Option<(E, Option<String>)> {
+    let err: E = match content_type? {
+        "application/json" => serde_json::from_slice(bytes).ok()?,
+        "application/octet-stream" => binary::deserialize(bytes).ok()?,
+        // Problem details carry the serialized error as an extension member.
+        problem::CONTENT_TYPE => {
+            let err = serde_json::from_slice::<Problem>(bytes).ok()?.error()?;
+            let help = extract_help(mime::JSON.essence(), bytes, &err);
+            return Some((err, help));
+        }
+        _ => return None,
+    };
+    let help = extract_help(content_type?, bytes, &err);
+    Some((err, help))
+}
+
+/// The help message attached to an error.
+///
+/// This is attached as context to errors produced by [parse_error_body] when the error response
+/// includes a help message. It displays as the error it is attached to, so it does not change how
+/// the error is printed.
+#[derive(Clone, Debug)]
+pub struct ErrorHelp {
+    message: String,
+    pub help: String,
+}
+
+impl Display for ErrorHelp {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// The help message from the server for an error returned by [parse_error_body], if any.
+///
+/// Help messages are intended to be shown verbatim to end users.
+pub fn error_help(err: &surf::Error) -> Option<&str> {
+    err.downcast_ref::<ErrorHelp>()
+        .map(|help| help.help.as_str())
+}
+
+// Whether a response with `status` is passed through to the caller, rather than turned into an
+// error.
+pub(crate) fn is_success(status: StatusCode) -> bool {
+    // A 304 is not an error: it is only sent in response to a conditional request, and tells the
+    // client to keep using its copy of the resource. Nor is a 206, which carries part of a result
+    // that was too large to send at once (see [cursor](crate::cursor)).
+    matches!(
+        status,
+        StatusCode::Ok | StatusCode::PartialContent | StatusCode::NotModified
+    )
 }
 
 pub async fn response_to_result<E: Error>(mut res: Response) -> surf::Result<Response> {
-    if res.status() == StatusCode::Ok {
+    if is_success(res.status()) {
         Ok(res)
     } else {
-        let err = response_error::<E>(&mut res).await;
-        Err(surf::Error::new(res.status(), err))
+        let (err, help) = response_error::<E>(&mut res).await;
+        Err(into_client_error(res.status(), err, help))
+    }
+}
+
+fn into_client_error<E: Error>(status: StatusCode, err: E, help: Option<String>) -> surf::Error {
+    match help {
+        Some(help) => {
+            let message = err.to_string();
+            let err = anyhow::Error::new(err).context(ErrorHelp { message, help });
+            surf::Error::new(status, err)
+        }
+        None => surf::Error::new(status, err),
     }
 }
 
 /// Client middleware which turns responses with non-success statuses into errors.
 ///
-/// If the status code of the response is Ok (200), the response is passed through unchanged.
+/// If the status code of the response is Ok (200), Partial Content (206) or Not Modified (304),
+/// the response is passed through unchanged.
 /// Otherwise, the body of the response is treated as an [Error] which is lifted into a
 /// [surf::Error]. This can then be converted into a module-specific error type using
 /// [FromApiError::from_client_error].
@@ -135,27 +371,83 @@ pub fn parse_error_body<E: Error>(
     )
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use serde::{Deserialize, Serialize};
-    use snafu::Snafu;
-    use surf::http::{self, mime, Body};
+type DecodeError =
+    Arc<dyn Fn(StatusCode, Option<&str>, &[u8]) -> Option<surf::Error> + Send + Sync>;
+
+/// Client middleware which turns error responses into errors of several possible types.
+///
+/// This is like [parse_error_body], for clients of servers whose routes use different [Error]
+/// types (see [ErrorRegistry](crate::server::ErrorRegistry)). The body of an error response is
+/// deserialized as each registered type in turn, in the order they were registered, and the first
+/// type which fits is used; the resulting [surf::Error] can be downcast to that type. Since a body
+/// may deserialize as more than one type, more specific types should be registered first.
+///
+/// If the body does not deserialize as any registered type, it is interpreted as the first
+/// registered type would be by [parse_error_body].
+#[derive(Clone, Default)]
+pub struct ErrorTypes {
+    decoders: Vec<DecodeError>,
+    fallback: Option<DecodeError>,
+}
 
-    #[derive(Clone, Debug, Deserialize, Serialize, Snafu, PartialEq, Eq)]
-    struct Error {
-        msg: String,
+impl ErrorTypes {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    impl crate::Error for Error {
-        fn catch_all(msg: String) -> Self {
-            Self { msg }
+    /// Try deserializing error bodies as `E`, after all previously registered types.
+    pub fn with<E: Error>(mut self) -> Self {
+        self.decoders.push(Arc::new(|status, content_type, bytes| {
+            let (err, help) = decode_exact_error::<E>(content_type, bytes)?;
+            Some(into_client_error(status, err, help))
+        }));
+        if self.fallback.is_none() {
+            self.fallback = Some(Arc::new(|status, content_type, bytes| {
+                let (err, help) = decode_error_and_help::<E>(status, content_type, bytes);
+                Some(into_client_error(status, err, help))
+            }));
         }
+        self
+    }
+
+    /// Interpret the body of an error response, which has already been read into memory.
+    pub fn decode(
+        &self,
+        status: StatusCode,
+        content_type: Option<&str>,
+        bytes: &[u8],
+    ) -> surf::Error {
+        self.decoders
+            .iter()
+            .chain(&self.fallback)
+            .find_map(|decode| decode(status, content_type, bytes))
+            .unwrap_or_else(|| {
+                surf::Error::from_str(status, String::from_utf8_lossy(bytes).into_owned())
+            })
+    }
+}
 
-        fn status(&self) -> StatusCode {
-            StatusCode::InternalServerError
+#[surf::utils::async_trait]
+impl surf::middleware::Middleware for ErrorTypes {
+    async fn handle(&self, req: Request, client: Client, next: Next<'_>) -> surf::Result {
+        let mut res = next.run(req, client).await?;
+        if is_success(res.status()) {
+            return Ok(res);
         }
+        let bytes = res.body_bytes().await?;
+        let content_type = res
+            .header("Content-Type")
+            .map(|content_type| content_type.as_str().to_string());
+        Err(self.decode(res.status(), content_type.as_deref(), &bytes))
     }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::Error;
+    use serde::{Deserialize, Serialize};
+    use surf::http::{self, mime, Body};
 
     #[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
     struct Data {
@@ -179,7 +471,7 @@ mod test {
         let data = Data::default();
         let mut res = http::Response::new(StatusCode::Ok);
         res.set_content_type(mime::BYTE_STREAM);
-        res.set_body(bincode::serialize(&data).unwrap());
+        res.set_body(binary::serialize(&data).unwrap());
 
         // Convert the resopnse to a result, check that it is Ok, and deserialize the body.
         let mut res = response_to_result::<Error>(res.into()).await.unwrap();
@@ -205,13 +497,54 @@ mod test {
         let err = Error { msg };
         let mut res = http::Response::new(StatusCode::InternalServerError);
         res.set_content_type(mime::BYTE_STREAM);
-        res.set_body(bincode::serialize(&err).unwrap());
+        res.set_body(binary::serialize(&err).unwrap());
 
         // Convert the response to a result, check that it is Err, and deserialize the body.
         let res = response_to_result::<Error>(res.into()).await.unwrap_err();
         assert_eq!(err, res.downcast().unwrap());
     }
 
+    #[async_std::test]
+    async fn test_response_error_disco_envelope() {
+        let envelope = ServerError {
+            status: 404,
+            message: "This is an error message".to_string(),
+        };
+        let mut res = http::Response::new(StatusCode::NotFound);
+        res.set_content_type(mime::JSON);
+        res.set_body(Body::from_json(&envelope).unwrap());
+
+        // Convert the response to a result, check that it is Err, and check that the message was
+        // extracted from the envelope.
+        let res = response_to_result::<Error>(res.into()).await.unwrap_err();
+        let err: Error = res.downcast().unwrap();
+        assert_eq!(err.msg, envelope.message);
+    }
+
+    #[async_std::test]
+    async fn test_response_error_help() {
+        let err = Error {
+            msg: "This is an error message".to_string(),
+        };
+        for ty in [mime::JSON, mime::BYTE_STREAM] {
+            let mut body = if ty == mime::JSON {
+                serde_json::to_vec(&err).unwrap()
+            } else {
+                binary::serialize(&err).unwrap()
+            };
+            crate::wire::error::append_help(ty.essence(), &mut body, "Try again later").unwrap();
+            let mut res = http::Response::new(StatusCode::InternalServerError);
+            res.set_content_type(ty);
+            res.set_body(body);
+
+            // The error is recovered, along with the help message.
+            let res = response_to_result::<Error>(res.into()).await.unwrap_err();
+            assert_eq!(error_help(&res), Some("Try again later"));
+            assert_eq!(res.to_string(), err.to_string());
+            assert_eq!(err, res.downcast().unwrap());
+        }
+    }
+
     #[async_std::test]
     async fn test_response_error_plaintext() {
         let msg = "This is an error message".to_string();
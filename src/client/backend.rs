@@ -0,0 +1,215 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! HTTP client backends.
+//!
+//! The middleware in the parent module is specific to surf. This module abstracts over the HTTP
+//! client with the [HttpClient] trait, so that the same protocol can be spoken from runtimes where
+//! surf is not a good fit. [HttpClient] is implemented for [surf::Client] and, with the `reqwest`
+//! feature, for `reqwest::Client`, which is the natural choice for tokio-based services.
+//!
+//! The functions [get] and [post] work with any backend. They encode request bodies, decode
+//! response bodies with [decode_body], and interpret error responses with [decode_error], so the
+//! semantics are exactly those of [response_body](super::response_body) and
+//! [parse_error_body](super::parse_error_body).
+
+use super::{decode_body, decode_error, is_success};
+use crate::error::Error;
+use futures::future::BoxFuture;
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Display;
+use surf::http::{mime, Method};
+use surf::StatusCode;
+
+/// A request to be sent by an [HttpClient].
+#[derive(Clone, Debug)]
+pub struct RawRequest {
+    pub method: Method,
+    pub url: String,
+    /// The value of the Accept header.
+    pub accept: String,
+    /// The content type and serialized contents of the body, if there is one.
+    pub body: Option<(String, Vec<u8>)>,
+}
+
+/// A response received by an [HttpClient], with the body read into memory.
+#[derive(Clone, Debug)]
+pub struct RawResponse {
+    pub status: StatusCode,
+    /// The value of the Content-Type header, if there is one.
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// An HTTP client backend.
+///
+/// Implementations only need to move bytes; all interpretation of the request and response is
+/// handled by the functions in this module.
+pub trait HttpClient: Send + Sync {
+    type Error: Display + Send;
+
+    /// Send a request and read the entire response.
+    ///
+    /// This should only fail if no response was received at all. Responses with an error status
+    /// must be returned as [RawResponse]s, so that their bodies can be interpreted.
+    fn send(&self, req: RawRequest) -> BoxFuture<'_, Result<RawResponse, Self::Error>>;
+}
+
+/// Send a GET request and deserialize the response.
+pub async fn get<C: HttpClient, T: DeserializeOwned, E: Error>(
+    client: &C,
+    url: impl Into<String>,
+) -> Result<T, E> {
+    send(
+        client,
+        RawRequest {
+            method: Method::Get,
+            url: url.into(),
+            accept: accept(),
+            body: None,
+        },
+    )
+    .await
+}
+
+/// Send a POST request with a JSON body and deserialize the response.
+pub async fn post<C: HttpClient, T: DeserializeOwned, E: Error>(
+    client: &C,
+    url: impl Into<String>,
+    body: &impl Serialize,
+) -> Result<T, E> {
+    let body = serde_json::to_vec(body)
+        .map_err(|err| E::catch_all(format!("request body fails to serialize: {}", err)))?;
+    send(
+        client,
+        RawRequest {
+            method: Method::Post,
+            url: url.into(),
+            accept: accept(),
+            body: Some((mime::JSON.to_string(), body)),
+        },
+    )
+    .await
+}
+
+async fn send<C: HttpClient, T: DeserializeOwned, E: Error>(
+    client: &C,
+    req: RawRequest,
+) -> Result<T, E> {
+    let res = client
+        .send(req)
+        .await
+        .map_err(|err| E::catch_all(format!("request failed: {}", err)))?;
+    if is_success(res.status) {
+        decode_body(res.content_type.as_deref(), &res.body).map_err(E::from_client_error)
+    } else {
+        Err(decode_error(
+            res.status,
+            res.content_type.as_deref(),
+            &res.body,
+        ))
+    }
+}
+
+fn accept() -> String {
+    format!("{}, {}", mime::JSON, mime::BYTE_STREAM)
+}
+
+impl HttpClient for surf::Client {
+    type Error = surf::Error;
+
+    fn send(&self, req: RawRequest) -> BoxFuture<'_, Result<RawResponse, Self::Error>> {
+        Box::pin(async move {
+            let url = req.url.parse::<surf::Url>()?;
+            let mut builder =
+                surf::RequestBuilder::new(req.method, url).header("Accept", req.accept);
+            if let Some((content_type, body)) = req.body {
+                builder = builder.header("Content-Type", content_type).body(body);
+            }
+            let mut res = self.send(builder).await?;
+            Ok(RawResponse {
+                status: res.status(),
+                content_type: res
+                    .header("Content-Type")
+                    .map(|content_type| content_type.as_str().to_string()),
+                body: res.body_bytes().await?,
+            })
+        })
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl HttpClient for reqwest::Client {
+    type Error = reqwest::Error;
+
+    fn send(&self, req: RawRequest) -> BoxFuture<'_, Result<RawResponse, Self::Error>> {
+        use reqwest::header::{ACCEPT, CONTENT_TYPE};
+        use std::convert::TryFrom;
+
+        Box::pin(async move {
+            // `Method` is a simple enum, so its name is always a valid reqwest method.
+            let method = reqwest::Method::from_bytes(req.method.as_ref().as_bytes()).unwrap();
+            let mut builder = self.request(method, &req.url).header(ACCEPT, req.accept);
+            if let Some((content_type, body)) = req.body {
+                builder = builder.header(CONTENT_TYPE, content_type).body(body);
+            }
+            let res = builder.send().await?;
+            let status = StatusCode::try_from(res.status().as_u16())
+                .unwrap_or(StatusCode::InternalServerError);
+            let content_type = res
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .map(String::from);
+            Ok(RawResponse {
+                status,
+                content_type,
+                body: res.bytes().await?.to_vec(),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::Error;
+
+    /// A backend which responds to every request with a fixed response.
+    struct MockClient(RawResponse);
+
+    impl HttpClient for MockClient {
+        type Error = String;
+
+        fn send(&self, _req: RawRequest) -> BoxFuture<'_, Result<RawResponse, Self::Error>> {
+            Box::pin(async move { Ok(self.0.clone()) })
+        }
+    }
+
+    #[async_std::test]
+    async fn test_get_ok() {
+        let client = MockClient(RawResponse {
+            status: StatusCode::Ok,
+            content_type: Some(mime::JSON.to_string()),
+            body: serde_json::to_vec(&42u64).unwrap(),
+        });
+        assert_eq!(42u64, get::<_, u64, Error>(&client, "url").await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_get_error() {
+        let err = Error {
+            msg: "This is an error message".to_string(),
+        };
+        let client = MockClient(RawResponse {
+            status: StatusCode::InternalServerError,
+            content_type: Some(mime::JSON.to_string()),
+            body: serde_json::to_vec(&err).unwrap(),
+        });
+        assert_eq!(err, get::<_, u64, Error>(&client, "url").await.unwrap_err());
+    }
+}
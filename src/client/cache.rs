@@ -0,0 +1,352 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A persistent, on-disk cache of responses.
+//!
+//! Many resources served by Espresso services never change once they exist (a committed block, for
+//! instance). A client which restarts should not have to fetch them again. [ResponseCache] is a
+//! client middleware which stores successful responses to GET requests in a directory, and answers
+//! later identical requests from the directory without contacting the server, even across
+//! restarts.
+//!
+//! Entries are keyed by URL and Accept header. They expire after a configurable time to live, and
+//! the oldest entries are evicted when the total size of the cache exceeds a limit. Only requests
+//! whose path starts with one of the configured prefixes are cached, so that mutable resources can
+//! be excluded. Entries can also be invalidated explicitly through any clone of the cache.
+//!
+//! The cache uses blocking file I/O, which is appropriate for the small number of large, immutable
+//! entries it is designed for.
+
+use crate::wire::binary;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use surf::http::Method;
+use surf::middleware::{Middleware, Next};
+use surf::{Client, Request, StatusCode};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    stored_at: u64,
+    content_type: Option<String>,
+    body: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct IndexEntry {
+    stored_at: u64,
+    size: u64,
+}
+
+struct Inner {
+    dir: PathBuf,
+    ttl: Option<Duration>,
+    max_size: u64,
+    prefixes: Vec<String>,
+    // The key of every entry on disk, along with the metadata needed for eviction.
+    index: Mutex<HashMap<String, IndexEntry>>,
+}
+
+/// Client middleware which caches responses on disk.
+#[derive(Clone)]
+pub struct ResponseCache {
+    inner: Arc<Inner>,
+}
+
+impl ResponseCache {
+    /// Open the cache stored in `dir`, creating it if necessary.
+    ///
+    /// By default, entries never expire, the cache holds at most 1 GB, and no requests are cached
+    /// until prefixes are added with [cache_prefix](Self::cache_prefix).
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let mut index = HashMap::new();
+        for file in fs::read_dir(&dir)? {
+            let path = file?.path();
+            // Skip (and remove) files which are not valid entries, for example because a write was
+            // interrupted.
+            match read_entry(&path) {
+                Some(entry) => {
+                    index.insert(
+                        entry.key,
+                        IndexEntry {
+                            stored_at: entry.stored_at,
+                            size: entry.body.len() as u64,
+                        },
+                    );
+                }
+                None => {
+                    fs::remove_file(&path).ok();
+                }
+            }
+        }
+        Ok(Self {
+            inner: Arc::new(Inner {
+                dir,
+                ttl: None,
+                max_size: 1 << 30,
+                prefixes: Vec::new(),
+                index: Mutex::new(index),
+            }),
+        })
+    }
+
+    /// Entries expire `ttl` after they are stored.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.config().ttl = Some(ttl);
+        self
+    }
+
+    /// The oldest entries are evicted when the total size of the cached bodies exceeds `max_size`.
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.config().max_size = max_size;
+        self
+    }
+
+    /// Cache GET requests whose path starts with `prefix`.
+    pub fn cache_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.config().prefixes.push(prefix.into());
+        self
+    }
+
+    fn config(&mut self) -> &mut Inner {
+        Arc::get_mut(&mut self.inner).expect("cache must be configured before it is shared")
+    }
+
+    /// Remove all cached responses for `url`.
+    pub fn invalidate(&self, url: &str) {
+        self.invalidate_where(|key| key_url(key) == url);
+    }
+
+    /// Remove all cached responses for URLs starting with `prefix`.
+    pub fn invalidate_prefix(&self, prefix: &str) {
+        self.invalidate_where(|key| key_url(key).starts_with(prefix));
+    }
+
+    /// Remove all cached responses.
+    pub fn clear(&self) {
+        self.invalidate_where(|_| true);
+    }
+
+    /// The number of cached responses.
+    pub fn len(&self) -> usize {
+        self.inner.index.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn invalidate_where(&self, pred: impl Fn(&str) -> bool) {
+        let mut index = self.inner.index.lock().unwrap();
+        index.retain(|key, _| {
+            if pred(key) {
+                fs::remove_file(self.path(key)).ok();
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.inner
+            .dir
+            .join(format!("{:016x}", fnv1a(key.as_bytes())))
+    }
+
+    fn cacheable(&self, req: &Request) -> bool {
+        req.method() == Method::Get
+            && self
+                .inner
+                .prefixes
+                .iter()
+                .any(|prefix| req.url().path().starts_with(prefix.as_str()))
+    }
+
+    fn expired(&self, stored_at: u64) -> bool {
+        match self.inner.ttl {
+            Some(ttl) => now().saturating_sub(stored_at) >= ttl.as_secs(),
+            None => false,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<Entry> {
+        let stored_at = self.inner.index.lock().unwrap().get(key)?.stored_at;
+        if self.expired(stored_at) {
+            self.invalidate_where(|k| k == key);
+            return None;
+        }
+        // Different keys may hash to the same file, so check that the entry is the one we want.
+        read_entry(&self.path(key)).filter(|entry| entry.key == key)
+    }
+
+    fn put(&self, entry: Entry) -> io::Result<()> {
+        let size = entry.body.len() as u64;
+        if size > self.inner.max_size {
+            return Ok(());
+        }
+        let path = self.path(&entry.key);
+        let bytes = binary::serialize(&entry).map_err(io::Error::other)?;
+        // Write to a temporary file and rename it into place, so that readers never see a partial
+        // entry.
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, &path)?;
+
+        let mut index = self.inner.index.lock().unwrap();
+        index.insert(
+            entry.key,
+            IndexEntry {
+                stored_at: entry.stored_at,
+                size,
+            },
+        );
+        let mut total: u64 = index.values().map(|entry| entry.size).sum();
+        while total > self.inner.max_size {
+            let oldest = index
+                .iter()
+                .min_by_key(|(_, entry)| entry.stored_at)
+                .map(|(key, entry)| (key.clone(), entry.size))
+                .unwrap();
+            fs::remove_file(self.path(&oldest.0)).ok();
+            index.remove(&oldest.0);
+            total -= oldest.1;
+        }
+        Ok(())
+    }
+}
+
+fn read_entry(path: &Path) -> Option<Entry> {
+    binary::deserialize(&fs::read(path).ok()?).ok()
+}
+
+fn key(req: &Request) -> String {
+    format!(
+        "{}\n{}",
+        req.url(),
+        req.header("Accept").map(|h| h.as_str()).unwrap_or("")
+    )
+}
+
+fn key_url(key: &str) -> &str {
+    key.split('\n').next().unwrap_or("")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// A stable hash for file names. Collisions are harmless, since entries record their full key.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[surf::utils::async_trait]
+impl Middleware for ResponseCache {
+    async fn handle(&self, req: Request, client: Client, next: Next<'_>) -> surf::Result {
+        if !self.cacheable(&req) {
+            return next.run(req, client).await;
+        }
+        let key = key(&req);
+        if let Some(entry) = self.get(&key) {
+            let mut res = surf::http::Response::new(StatusCode::Ok);
+            res.set_body(entry.body);
+            match entry.content_type {
+                Some(content_type) => {
+                    res.insert_header("Content-Type", content_type);
+                }
+                None => {
+                    res.remove_header("Content-Type");
+                }
+            }
+            return Ok(res.into());
+        }
+
+        let mut res = next.run(req, client).await?;
+        if res.status() == StatusCode::Ok {
+            let content_type = res.header("Content-Type").map(|h| h.as_str().to_string());
+            let body = res.body_bytes().await?;
+            res.set_body(body.clone());
+            let entry = Entry {
+                key,
+                stored_at: now(),
+                content_type,
+                body,
+            };
+            if let Err(err) = self.put(entry) {
+                tracing::warn!("failed to cache response: {}", err);
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // A fake server which counts the requests that reach it.
+    struct Server(Arc<AtomicUsize>);
+
+    #[surf::utils::async_trait]
+    impl Middleware for Server {
+        async fn handle(&self, _req: Request, _client: Client, _next: Next<'_>) -> surf::Result {
+            let n = self.0.fetch_add(1, Ordering::SeqCst);
+            let mut res = surf::http::Response::new(StatusCode::Ok);
+            res.set_body(format!("response {}", n));
+            Ok(res.into())
+        }
+    }
+
+    #[async_std::test]
+    async fn test_cache() {
+        let dir = std::env::temp_dir().join(format!("net-cache-test-{}", std::process::id()));
+        let hits = Arc::new(AtomicUsize::new(0));
+        let cache = ResponseCache::open(&dir).unwrap().cache_prefix("/block");
+        let client = Client::new().with(cache.clone()).with(Server(hits.clone()));
+
+        // The first request reaches the server; the second is served from the cache.
+        let body = |url: &'static str| {
+            let client = client.clone();
+            async move { client.get(url).recv_string().await.unwrap() }
+        };
+        assert_eq!(body("http://localhost/block/0").await, "response 0");
+        assert_eq!(body("http://localhost/block/0").await, "response 0");
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        // Requests outside the cached prefixes are not cached.
+        body("http://localhost/status").await;
+        body("http://localhost/status").await;
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+        // The cache persists across restarts.
+        drop(client);
+        drop(cache);
+        let cache = ResponseCache::open(&dir).unwrap();
+        assert_eq!(cache.len(), 1);
+
+        // Invalidation removes entries.
+        cache.invalidate("http://localhost/block/0");
+        assert!(cache.is_empty());
+        assert!(ResponseCache::open(&dir).unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,402 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A cookie jar for clients.
+//!
+//! Routes with [sessions](crate::server::session) remember a client by a cookie. Browsers keep
+//! cookies on their own, but other clients, like scripts driving the faucet or tests of an admin
+//! UI, need to store them. [CookieJar] is a client middleware which stores the cookies set by
+//! responses and sends them back with later requests, following the rules browsers use for which
+//! cookies go with which requests:
+//!  * a cookie without a Domain attribute is only sent to the host which set it, and a cookie with
+//!    one is also sent to subdomains of that domain
+//!  * a host can only set cookies for its own domain or a parent of it, and not for a public suffix
+//!    like `com` or `co.uk`, which would share the cookie with unrelated sites
+//!  * a cookie is only sent for paths under its Path attribute
+//!  * a `Secure` cookie is only sent over HTTPS
+//!  * a cookie is removed when it expires, or when a response sets it with an expiry in the past
+//!
+//! The jar is kept in memory, and is shared by clones, so one jar can be used by several clients.
+//!
+//! The jar does not load the full public suffix list. It treats every single-label domain as a
+//! public suffix, along with the common registry suffixes in [PUBLIC_SUFFIXES].
+
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use surf::http::url::Host;
+use surf::http::{Cookie, Url};
+use surf::middleware::{Middleware, Next};
+use surf::{Client, Request};
+
+/// Multi-label public suffixes, under which unrelated parties register domains.
+///
+/// Cookies cannot be set for these domains, or for any single-label domain like `com`.
+pub const PUBLIC_SUFFIXES: &[&str] = &[
+    "ac.uk",
+    "co.jp",
+    "co.kr",
+    "co.nz",
+    "co.uk",
+    "co.za",
+    "com.au",
+    "com.br",
+    "com.cn",
+    "com.mx",
+    "com.sg",
+    "com.tw",
+    "gov.uk",
+    "ne.jp",
+    "net.au",
+    "or.jp",
+    "org.au",
+    "org.uk",
+    "github.io",
+    "herokuapp.com",
+    "netlify.app",
+    "pages.dev",
+    "vercel.app",
+];
+
+#[derive(Clone, Debug)]
+struct StoredCookie {
+    name: String,
+    value: String,
+    domain: String,
+    host_only: bool,
+    path: String,
+    secure: bool,
+    expires: Option<SystemTime>,
+}
+
+impl StoredCookie {
+    fn parse(url: &Url, header: &str, now: SystemTime) -> Option<Self> {
+        let cookie = Cookie::parse(header).ok()?;
+        let host = url.host_str()?.to_ascii_lowercase();
+        let is_ip = !matches!(url.host(), Some(Host::Domain(_)));
+        let (domain, host_only) = match cookie.domain() {
+            Some(domain) => {
+                let domain = domain.trim_start_matches('.').to_ascii_lowercase();
+                if domain == host && (is_ip || is_public_suffix(&host)) {
+                    // An IP address or a public suffix has no subdomains to share the cookie
+                    // with, but can still set cookies for itself.
+                    (host, true)
+                } else if is_public_suffix(&domain) || is_ip || !domain_matches(&host, &domain) {
+                    // A server can only set cookies for its own domain or a parent of it below
+                    // the public suffix, and an IP address has no parents.
+                    return None;
+                } else {
+                    (domain, false)
+                }
+            }
+            None => (host, true),
+        };
+        let path = match cookie.path() {
+            Some(path) if path.starts_with('/') => path.to_string(),
+            _ => default_path(url),
+        };
+        // Max-Age takes precedence over Expires.
+        let expires = match (cookie.max_age(), cookie.expires()) {
+            (Some(max_age), _) => Some(match u64::try_from(max_age.whole_seconds()) {
+                Ok(secs) => now + Duration::from_secs(secs),
+                Err(_) => UNIX_EPOCH,
+            }),
+            (None, Some(expires)) => Some(match u64::try_from(expires.unix_timestamp()) {
+                Ok(secs) => UNIX_EPOCH + Duration::from_secs(secs),
+                Err(_) => UNIX_EPOCH,
+            }),
+            (None, None) => None,
+        };
+        Some(Self {
+            name: cookie.name().to_string(),
+            value: cookie.value().to_string(),
+            domain,
+            host_only,
+            path,
+            secure: cookie.secure().unwrap_or(false),
+            expires,
+        })
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        matches!(self.expires, Some(expires) if expires <= now)
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host.to_ascii_lowercase(),
+            None => return false,
+        };
+        let host_matches = if self.host_only {
+            host == self.domain
+        } else {
+            domain_matches(&host, &self.domain)
+        };
+        host_matches && path_matches(url.path(), &self.path) && (!self.secure || is_secure(url))
+    }
+}
+
+// Whether `host` is `domain` or a subdomain of it.
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain || (host.ends_with(domain) && host[..host.len() - domain.len()].ends_with('.'))
+}
+
+// Whether cookies must not be shared across `domain`.
+fn is_public_suffix(domain: &str) -> bool {
+    !domain.contains('.') || PUBLIC_SUFFIXES.contains(&domain)
+}
+
+// Whether `path` is `prefix` or under it.
+fn path_matches(path: &str, prefix: &str) -> bool {
+    path == prefix
+        || (path.starts_with(prefix)
+            && (prefix.ends_with('/') || path[prefix.len()..].starts_with('/')))
+}
+
+// The directory of the request path, used when a cookie has no Path attribute.
+fn default_path(url: &Url) -> String {
+    match url.path().rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => url.path()[..i].to_string(),
+    }
+}
+
+fn is_secure(url: &Url) -> bool {
+    url.scheme() == "https" || url.scheme() == "wss"
+}
+
+/// Client middleware which stores cookies and sends them with later requests.
+///
+/// A request which already has a Cookie header is sent as it is.
+#[derive(Clone, Debug, Default)]
+pub struct CookieJar {
+    cookies: Arc<Mutex<Vec<StoredCookie>>>,
+}
+
+impl CookieJar {
+    /// An empty cookie jar.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The value of the cookie `name` which would be sent with a request to `url`.
+    pub fn get(&self, url: &Url, name: &str) -> Option<String> {
+        let now = SystemTime::now();
+        let mut cookies = self.cookies.lock().unwrap();
+        cookies.retain(|cookie| !cookie.is_expired(now));
+        cookies
+            .iter()
+            .find(|cookie| cookie.name == name && cookie.matches(url))
+            .map(|cookie| cookie.value.clone())
+    }
+
+    /// Store a cookie as if a response from `url` had set it with the Set-Cookie header `header`.
+    ///
+    /// Invalid cookies, and cookies for a domain which `url` cannot set cookies for, are ignored.
+    pub fn set(&self, url: &Url, header: &str) {
+        let now = SystemTime::now();
+        let cookie = match StoredCookie::parse(url, header, now) {
+            Some(cookie) => cookie,
+            None => return,
+        };
+        let mut cookies = self.cookies.lock().unwrap();
+        // A cookie replaces any cookie with the same name, domain and path.
+        cookies.retain(|old| {
+            !(old.name == cookie.name && old.domain == cookie.domain && old.path == cookie.path)
+        });
+        if !cookie.is_expired(now) {
+            cookies.push(cookie);
+        }
+    }
+
+    /// Remove all cookies.
+    pub fn clear(&self) {
+        self.cookies.lock().unwrap().clear();
+    }
+
+    /// The number of cookies in the jar, including any which have expired but not been removed.
+    pub fn len(&self) -> usize {
+        self.cookies.lock().unwrap().len()
+    }
+
+    /// Whether the jar has no cookies.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The value of the Cookie header for a request to `url`, if any cookies match it.
+    fn header(&self, url: &Url) -> Option<String> {
+        let now = SystemTime::now();
+        let mut cookies = self.cookies.lock().unwrap();
+        cookies.retain(|cookie| !cookie.is_expired(now));
+        let mut matching = cookies
+            .iter()
+            .filter(|cookie| cookie.matches(url))
+            .collect::<Vec<_>>();
+        if matching.is_empty() {
+            return None;
+        }
+        // Cookies with longer paths are sent first, as browsers do.
+        matching.sort_by_key(|cookie| std::cmp::Reverse(cookie.path.len()));
+        Some(
+            matching
+                .into_iter()
+                .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+                .collect::<Vec<_>>()
+                .join("; "),
+        )
+    }
+}
+
+#[surf::utils::async_trait]
+impl Middleware for CookieJar {
+    async fn handle(&self, mut req: Request, client: Client, next: Next<'_>) -> surf::Result {
+        let url = req.url().clone();
+        if req.header("Cookie").is_none() {
+            if let Some(cookies) = self.header(&url) {
+                req.insert_header("Cookie", cookies);
+            }
+        }
+        let res = next.run(req, client).await?;
+        if let Some(values) = res.header("Set-Cookie") {
+            for value in values.iter() {
+                self.set(&url, value.as_str());
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use surf::StatusCode;
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_matching() {
+        let jar = CookieJar::new();
+        let origin = url("https://api.example.com/request");
+        jar.set(&origin, "host=1");
+        jar.set(&origin, "domain=2; Domain=.example.com");
+        jar.set(&origin, "admin=3; Path=/admin");
+        jar.set(&origin, "secure=4; Secure");
+        // Cookies for other domains are rejected.
+        jar.set(&origin, "other=5; Domain=other.com");
+        jar.set(&origin, "sub=6; Domain=www.api.example.com");
+        assert_eq!(jar.len(), 4);
+
+        let cookies = |u: &str| jar.header(&url(u));
+        assert_eq!(
+            cookies("https://api.example.com/faucet/x").unwrap(),
+            "host=1; domain=2; secure=4"
+        );
+        assert_eq!(
+            cookies("https://api.example.com/admin/users").unwrap(),
+            "admin=3; host=1; domain=2; secure=4"
+        );
+        assert!(!cookies("https://api.example.com/administrator")
+            .unwrap()
+            .contains("admin"));
+        assert_eq!(
+            cookies("http://www.example.com/faucet").unwrap(),
+            "domain=2"
+        );
+        assert_eq!(cookies("https://badexample.com/faucet"), None);
+        assert_eq!(
+            jar.get(&url("https://example.com/"), "domain").unwrap(),
+            "2"
+        );
+        assert_eq!(jar.get(&url("https://example.com/"), "host"), None);
+    }
+
+    #[test]
+    fn test_public_suffixes() {
+        let jar = CookieJar::new();
+        for (origin, domain) in [
+            ("https://api.example.com/", "com"),
+            ("https://shop.example.co.uk/", "co.uk"),
+            ("https://example.co.uk/", ".CO.UK"),
+            ("https://1.2.3.4/", "2.3.4"),
+            ("https://localhost/", "host"),
+        ] {
+            jar.set(&url(origin), &format!("a=1; Domain={}", domain));
+            assert!(jar.is_empty(), "{} set a cookie for {}", origin, domain);
+        }
+
+        // A public suffix or IP address can set a cookie for itself, which is not shared.
+        jar.set(&url("https://localhost/"), "a=1; Domain=localhost");
+        jar.set(&url("https://1.2.3.4/"), "b=2; Domain=1.2.3.4");
+        assert_eq!(jar.len(), 2);
+        assert_eq!(jar.get(&url("https://localhost/"), "a").unwrap(), "1");
+        assert_eq!(jar.get(&url("https://1.2.3.4/"), "b").unwrap(), "2");
+        assert_eq!(jar.get(&url("https://www.localhost/"), "a"), None);
+
+        // Parents of the host below the public suffix can still share cookies.
+        jar.set(
+            &url("https://shop.example.co.uk/"),
+            "c=3; Domain=example.co.uk",
+        );
+        assert_eq!(
+            jar.get(&url("https://www.example.co.uk/"), "c").unwrap(),
+            "3"
+        );
+    }
+
+    #[test]
+    fn test_expiry() {
+        let jar = CookieJar::new();
+        let origin = url("https://localhost/");
+        jar.set(&origin, "a=1; Max-Age=3600");
+        jar.set(&origin, "b=2");
+        assert_eq!(jar.len(), 2);
+
+        // A cookie is replaced by a cookie with the same name, and removed by one which has
+        // expired.
+        jar.set(&origin, "a=3");
+        assert_eq!(jar.get(&origin, "a").unwrap(), "3");
+        jar.set(&origin, "a=; Max-Age=0");
+        jar.set(&origin, "b=; Expires=Thu, 01 Jan 1970 00:00:00 GMT");
+        assert!(jar.is_empty());
+    }
+
+    // A fake server which sets a cookie and echoes the cookies it receives.
+    struct Server;
+
+    #[surf::utils::async_trait]
+    impl Middleware for Server {
+        async fn handle(&self, req: Request, _client: Client, _next: Next<'_>) -> surf::Result {
+            let mut res = surf::http::Response::new(StatusCode::Ok);
+            res.append_header("Set-Cookie", "session=abc; Path=/; HttpOnly");
+            res.append_header("Set-Cookie", "visited=1; Path=/faucet");
+            if let Some(cookies) = req.header("Cookie") {
+                res.set_body(cookies.as_str());
+            }
+            Ok(res.into())
+        }
+    }
+
+    #[async_std::test]
+    async fn test_middleware() {
+        let jar = CookieJar::new();
+        let client = Client::new().with(jar.clone()).with(Server);
+        let body = |u: &'static str| {
+            let client = client.clone();
+            async move { client.get(u).recv_string().await.unwrap() }
+        };
+        assert_eq!(body("http://localhost/faucet").await, "");
+        assert_eq!(
+            body("http://localhost/faucet").await,
+            "visited=1; session=abc"
+        );
+        assert_eq!(body("http://localhost/status").await, "session=abc");
+        assert_eq!(jar.len(), 2);
+    }
+}
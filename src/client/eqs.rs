@@ -0,0 +1,247 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A typed client for the Espresso Query Service (EQS).
+//!
+//! [Eqs] has a method for each of the query endpoints most tools need, so that they do not have to
+//! build the URLs and parse the responses themselves:
+//!
+//! | Method                    | Endpoint                        |
+//! |---------------------------|---------------------------------|
+//! | [Eqs::block]              | `GET getblock/:id`              |
+//! | [Eqs::transaction]        | `GET gettransaction/:block/:i`  |
+//! | [Eqs::is_nullifier_spent] | `GET checknullifier/:nullifier` |
+//! | [Eqs::frontier]           | `GET getfrontier`               |
+//!
+//! The types of blocks, transactions and frontiers depend on the ledger the service follows, so
+//! they are given by a [QueryTypes] implementation.
+//!
+//! Methods which look up something which may not exist yet return [None] when the service responds
+//! with 404 Not Found for the thing itself. A 404 from the service's router, because the service
+//! has no such route (for example, because its URL is wrong), is an error, like any other failure.
+//!
+//! ```no_run
+//! use net::client::eqs::{Eqs, QueryTypes};
+//! use net::BlockId;
+//! # #[derive(Clone, Debug, serde::Deserialize, serde::Serialize, snafu::Snafu)]
+//! # struct ApiError { msg: String }
+//! # impl net::Error for ApiError {
+//! #     fn catch_all(msg: String) -> Self { Self { msg } }
+//! #     fn status(&self) -> tide::StatusCode { tide::StatusCode::InternalServerError }
+//! # }
+//!
+//! struct Ledger;
+//!
+//! impl QueryTypes for Ledger {
+//!     type Block = serde_json::Value;
+//!     type Transaction = serde_json::Value;
+//!     type Frontier = serde_json::Value;
+//! }
+//!
+//! # async_std::task::block_on(async {
+//! let eqs = Eqs::<Ledger, ApiError>::new("http://eqs.example.com/".parse().unwrap());
+//! let block = eqs.block(BlockId(42)).await?;
+//! # Ok::<_, ApiError>(())
+//! # });
+//! ```
+
+use super::stack::ClientStack;
+use super::url::tagged;
+use super::{response_body, Route};
+use crate::error::Error;
+use crate::types::{BlockId, TransactionId};
+use jf_cap::structs::Nullifier;
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
+use surf::{StatusCode, Url};
+
+/// The types of the responses of a query service, which depend on the ledger it follows.
+pub trait QueryTypes {
+    type Block: DeserializeOwned;
+    type Transaction: DeserializeOwned;
+    /// The frontier of the record Merkle tree.
+    type Frontier: DeserializeOwned;
+}
+
+/// A typed client for a query service.
+///
+/// `T` gives the types of the responses, and `E` is the error type of the query service API.
+pub struct Eqs<T, E> {
+    url: Url,
+    client: surf::Client,
+    _phantom: PhantomData<fn() -> (T, E)>,
+}
+
+impl<T, E> Clone for Eqs<T, E> {
+    fn clone(&self) -> Self {
+        Self {
+            url: self.url.clone(),
+            client: self.client.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<T: QueryTypes, E: Error> Eqs<T, E> {
+    /// A client for the query service at `url`, with no middleware but error parsing.
+    pub fn new(url: Url) -> Self {
+        Self::with_client(url, ClientStack::new().errors::<E>().build())
+    }
+
+    /// A client for the query service at `url`, which sends requests with `client`.
+    ///
+    /// Use this to add retries, timeouts and other middleware with a [ClientStack]. The client must
+    /// parse error responses as `E`, as [parse_error_body](super::parse_error_body) does, and
+    /// should not have a base URL.
+    pub fn with_client(url: Url, client: surf::Client) -> Self {
+        Self {
+            url,
+            client,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// The block with index `id`, or [None] if there is no such block yet.
+    pub async fn block(&self, id: BlockId) -> Result<Option<T::Block>, E> {
+        self.get_if_exists(crate::url!("getblock", id.0)).await
+    }
+
+    /// The transaction `id`, or [None] if there is no such transaction.
+    pub async fn transaction(&self, id: TransactionId) -> Result<Option<T::Transaction>, E> {
+        let TransactionId(block, index) = id;
+        self.get_if_exists(crate::url!("gettransaction", block.0, index))
+            .await
+    }
+
+    /// Whether `nullifier` has been published, meaning that the record it nullifies is spent.
+    pub async fn is_nullifier_spent(&self, nullifier: &Nullifier) -> Result<bool, E> {
+        self.get(crate::url!("checknullifier", tagged(nullifier)))
+            .await
+    }
+
+    /// The current frontier of the record Merkle tree.
+    pub async fn frontier(&self) -> Result<T::Frontier, E> {
+        self.get(crate::url!("getfrontier")).await
+    }
+
+    async fn get<R: DeserializeOwned>(&self, route: Route) -> Result<R, E> {
+        self.get_if_exists(route).await?.ok_or_else(|| {
+            E::catch_all_with_status(StatusCode::NotFound, "resource not found".into())
+        })
+    }
+
+    async fn get_if_exists<R: DeserializeOwned>(&self, route: Route) -> Result<Option<R>, E> {
+        let url = route.join(&self.url);
+        let path = url.path().to_string();
+        let mut res = match self.client.get(url).await {
+            Ok(res) => res,
+            Err(err) if err.status() == StatusCode::NotFound => {
+                let err = E::from_client_error(err);
+                return if is_unrouted(&err, &path) {
+                    Err(err)
+                } else {
+                    Ok(None)
+                };
+            }
+            Err(err) => return Err(E::from_client_error(err)),
+        };
+        response_body(&mut res)
+            .await
+            .map(Some)
+            .map_err(E::from_client_error)
+    }
+}
+
+// Whether a 404 for `path` came from the service's router rather than from its handler for the
+// route: either the error the server-side error middleware gives such responses, or an empty body.
+fn is_unrouted<E: Error>(err: &E, path: &str) -> bool {
+    let err = serde_json::to_value(err).ok();
+    [
+        E::route_not_found(path),
+        E::catch_all_with_status(StatusCode::NotFound, String::new()),
+    ]
+    .iter()
+    .any(|unrouted| serde_json::to_value(unrouted).ok() == err)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::parse_error_body;
+    use crate::redact::Public;
+    use crate::test_util::{Error as QueryError, Server};
+    use surf::Client;
+
+    struct Ledger;
+
+    impl QueryTypes for Ledger {
+        type Block = Vec<String>;
+        type Transaction = String;
+        type Frontier = Vec<u64>;
+    }
+
+    fn spent() -> Nullifier {
+        Nullifier::default()
+    }
+
+    fn eqs(url: &str) -> Eqs<Ledger, QueryError> {
+        let mut app = tide::new();
+        app.with(crate::server::add_error_body::<_, QueryError>);
+        app.at("/v1/getblock/:id")
+            .get(|req: tide::Request<()>| async move {
+                match req.param("id")? {
+                    "0" => crate::server::response(&req, Public(vec!["tx0".to_string()])),
+                    _ => Err(tide::Error::from_str(StatusCode::NotFound, "no such block")),
+                }
+            });
+        app.at("/v1/gettransaction/:block/:index")
+            .get(|req: tide::Request<()>| async move {
+                let tx = format!("{}.{}", req.param("block")?, req.param("index")?);
+                crate::server::response(&req, Public(tx))
+            });
+        app.at("/v1/getfrontier")
+            .get(|req: tide::Request<()>| async move {
+                crate::server::response(&req, vec![1u64, 2, 3])
+            });
+        app.at("/v1/checknullifier/:nullifier")
+            .get(|req: tide::Request<()>| async move {
+                let spent = req.param("nullifier")? == tagged(&spent()).to_string();
+                crate::server::response(&req, spent)
+            });
+        let client = Client::new()
+            .with(parse_error_body::<QueryError>)
+            .with(Server(app));
+        Eqs::with_client(url.parse().unwrap(), client)
+    }
+
+    #[async_std::test]
+    async fn test_eqs() {
+        let eqs = eqs("http://eqs/v1");
+        assert_eq!(
+            eqs.block(BlockId(0)).await.unwrap(),
+            Some(vec!["tx0".into()])
+        );
+        assert_eq!(eqs.block(BlockId(1)).await.unwrap(), None);
+        assert_eq!(
+            eqs.transaction(TransactionId(BlockId(3), 1))
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("3.1")
+        );
+        assert_eq!(eqs.frontier().await.unwrap(), vec![1, 2, 3]);
+        assert!(eqs.is_nullifier_spent(&spent()).await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_unrouted() {
+        // A service at the wrong URL has no blocks, rather than missing the one asked for.
+        let eqs = eqs("http://eqs/v2");
+        let err = eqs.block(BlockId(1)).await.unwrap_err();
+        assert_eq!(err, QueryError::route_not_found("/v2/getblock/1"));
+    }
+}
@@ -0,0 +1,300 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Hedged requests, for reads whose tail latency matters.
+//!
+//! When a service is replicated, the latency of a read is often dominated by the occasional slow
+//! replica. The [hedge] middleware sends a GET request to one replica and, if it has not responded
+//! within a short delay, sends the same request to another replica, and so on up to a limit. The
+//! first successful response is used, and the requests which are still outstanding are cancelled.
+//!
+//! Replicas are identified by their base URLs. A request is hedged only if its URL starts with the
+//! base URL of one of the replicas; copies of it are sent to the same path under the base URLs of
+//! the other replicas, in the order they were added.
+//!
+//! ```
+//! # use net::client::hedge;
+//! # use std::time::Duration;
+//! # use surf::Url;
+//! let client = surf::Client::new().with(
+//!     hedge(Duration::from_millis(50), 2)
+//!         .replica(Url::parse("http://node0.example.com/wallet/").unwrap())
+//!         .replica(Url::parse("http://node1.example.com/wallet/").unwrap()),
+//! );
+//! ```
+//!
+//! Only GET and HEAD requests are hedged, since only they are safe to send more than once
+//! concurrently. Hedging multiplies the load on the replicas for slow requests, so the delay should
+//! be around the usual latency of the fastest requests, not much below it.
+
+use super::Stopwatch;
+use futures::future::{self, Either};
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::time::Duration;
+use surf::http::Method;
+use surf::middleware::{Middleware, Next};
+use surf::{Client, Request, Url};
+
+/// Client middleware which hedges reads across replicas.
+///
+/// This sends copies of a request which has not been answered within `delay` to other replicas,
+/// with at most `max_parallel` copies in flight at once. See the [module documentation](self).
+///
+/// # Panics
+///
+/// Panics if `max_parallel` is 0.
+pub fn hedge(delay: Duration, max_parallel: usize) -> Hedge {
+    assert!(max_parallel > 0, "at least one request must be allowed");
+    Hedge {
+        replicas: Vec::new(),
+        delay,
+        max_parallel,
+    }
+}
+
+/// Client middleware which hedges reads across replicas, created by [hedge].
+#[derive(Clone, Debug)]
+pub struct Hedge {
+    replicas: Vec<Url>,
+    delay: Duration,
+    max_parallel: usize,
+}
+
+impl Hedge {
+    /// Add a replica whose API is served under `base_url`.
+    pub fn replica(mut self, base_url: Url) -> Self {
+        self.replicas.push(base_url);
+        self
+    }
+
+    /// Add several replicas.
+    pub fn replicas(mut self, base_urls: impl IntoIterator<Item = Url>) -> Self {
+        self.replicas.extend(base_urls);
+        self
+    }
+
+    // The URLs to try for a request to `url`, starting with `url` itself.
+    fn targets(&self, url: &Url) -> Vec<Url> {
+        let (primary, path) = match self
+            .replicas
+            .iter()
+            .enumerate()
+            .find_map(|(i, base)| Some((i, url.as_str().strip_prefix(base.as_str())?)))
+        {
+            Some(matched) => matched,
+            None => return vec![url.clone()],
+        };
+        let mut targets = vec![url.clone()];
+        for (i, base) in self.replicas.iter().enumerate() {
+            if i != primary {
+                if let Ok(url) = base.join(path) {
+                    targets.push(url);
+                }
+            }
+        }
+        targets
+    }
+}
+
+// Whether a response is good enough to stop waiting for the others.
+fn succeeded(res: &surf::Result) -> bool {
+    match res {
+        Ok(res) => !res.status().is_server_error(),
+        // An error status which is not a server error is the answer, not a failure.
+        Err(err) => err.status().is_client_error(),
+    }
+}
+
+#[surf::utils::async_trait]
+impl Middleware for Hedge {
+    async fn handle(&self, req: Request, client: Client, next: Next<'_>) -> surf::Result {
+        if !matches!(req.method(), Method::Get | Method::Head) {
+            return next.run(req, client).await;
+        }
+        let mut targets = self.targets(req.url()).into_iter();
+        let send = |url: Url| {
+            let mut copy = req.clone();
+            *AsMut::<surf::http::Request>::as_mut(&mut copy).url_mut() = url;
+            next.run(copy, client.clone())
+        };
+
+        // Dropping a request future cancels it, so when this function returns, all outstanding
+        // requests are cancelled.
+        let mut pending = FuturesUnordered::new();
+        pending.push(send(targets.next().unwrap()));
+        let start = Stopwatch::start();
+        let mut next_launch = self.delay;
+        loop {
+            let can_launch = pending.len() < self.max_parallel && !targets.as_slice().is_empty();
+            let timer = if can_launch {
+                Either::Left(async_std::task::sleep(
+                    next_launch.saturating_sub(start.elapsed()),
+                ))
+            } else {
+                Either::Right(future::pending())
+            };
+            match future::select(pending.next(), Box::pin(timer)).await {
+                Either::Left((Some(res), _)) => {
+                    if succeeded(&res) || (pending.is_empty() && targets.as_slice().is_empty()) {
+                        return res;
+                    }
+                    // A failure is as good as a timeout: try the next replica now.
+                    if pending.is_empty() {
+                        pending.push(send(targets.next().unwrap()));
+                        next_launch = start.elapsed() + self.delay;
+                    }
+                }
+                Either::Left((None, _)) => unreachable!("the last response is always returned"),
+                Either::Right(_) => {
+                    tracing::debug!("hedging slow request to {}", req.url());
+                    pending.push(send(targets.next().unwrap()));
+                    next_launch = start.elapsed() + self.delay;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+    use surf::StatusCode;
+
+    // Fake replicas, distinguished by host, each with its own latency and status.
+    #[derive(Clone, Default)]
+    struct Fake {
+        replicas: Arc<Mutex<Vec<(Duration, StatusCode)>>>,
+        started: Arc<AtomicUsize>,
+        finished: Arc<AtomicUsize>,
+    }
+
+    #[surf::utils::async_trait]
+    impl Middleware for Fake {
+        async fn handle(&self, req: Request, _client: Client, _next: Next<'_>) -> surf::Result {
+            let host = req.url().host_str().unwrap();
+            let i: usize = host.trim_start_matches("node").parse().unwrap();
+            let (latency, status) = self.replicas.lock().unwrap()[i];
+            self.started.fetch_add(1, Ordering::SeqCst);
+            async_std::task::sleep(latency).await;
+            self.finished.fetch_add(1, Ordering::SeqCst);
+            let mut res = surf::http::Response::new(status);
+            res.set_body(format!("{} {}", host, req.url().path()));
+            Ok(res.into())
+        }
+    }
+
+    fn replicated(
+        fake: &Fake,
+        replicas: Vec<(Duration, StatusCode)>,
+        max_parallel: usize,
+    ) -> Client {
+        let urls = (0..replicas.len())
+            .map(|i| Url::parse(&format!("http://node{}/api/", i)).unwrap())
+            .collect::<Vec<_>>();
+        *fake.replicas.lock().unwrap() = replicas;
+        Client::new()
+            .with(hedge(Duration::from_millis(20), max_parallel).replicas(urls))
+            .with(fake.clone())
+    }
+
+    const FAST: Duration = Duration::from_millis(0);
+    const SLOW: Duration = Duration::from_millis(300);
+
+    #[async_std::test]
+    async fn test_hedge() {
+        // A fast primary is not hedged.
+        let fake = Fake::default();
+        let client = replicated(
+            &fake,
+            vec![(FAST, StatusCode::Ok), (FAST, StatusCode::Ok)],
+            2,
+        );
+        let mut res = client.get("http://node0/api/balance").await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "node0 /api/balance");
+        assert_eq!(fake.started.load(Ordering::SeqCst), 1);
+
+        // A slow primary is hedged, and the slow request is cancelled.
+        let fake = Fake::default();
+        let client = replicated(
+            &fake,
+            vec![(SLOW, StatusCode::Ok), (FAST, StatusCode::Ok)],
+            2,
+        );
+        let start = Instant::now();
+        let mut res = client.get("http://node0/api/balance").await.unwrap();
+        assert!(start.elapsed() < SLOW);
+        assert_eq!(res.body_string().await.unwrap(), "node1 /api/balance");
+        assert_eq!(fake.started.load(Ordering::SeqCst), 2);
+        assert_eq!(fake.finished.load(Ordering::SeqCst), 1);
+
+        // Requests outside the replicas, and requests which are not reads, are not hedged.
+        let fake = Fake::default();
+        let client = replicated(
+            &fake,
+            vec![
+                (Duration::from_millis(50), StatusCode::Ok),
+                (FAST, StatusCode::Ok),
+            ],
+            2,
+        );
+        let mut res = client.get("http://node0/other").await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "node0 /other");
+        let mut res = client.post("http://node0/api/submit").await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "node0 /api/submit");
+        assert_eq!(fake.started.load(Ordering::SeqCst), 2);
+    }
+
+    #[async_std::test]
+    async fn test_hedge_failures() {
+        // A failure moves on to the next replica without waiting.
+        let fake = Fake::default();
+        let client = replicated(
+            &fake,
+            vec![
+                (FAST, StatusCode::ServiceUnavailable),
+                (FAST, StatusCode::ServiceUnavailable),
+                (FAST, StatusCode::Ok),
+            ],
+            1,
+        );
+        let mut res = client.get("http://node0/api/balance").await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "node2 /api/balance");
+
+        // When every replica fails, the last failure is returned.
+        let fake = Fake::default();
+        let client = replicated(
+            &fake,
+            vec![
+                (FAST, StatusCode::ServiceUnavailable),
+                (FAST, StatusCode::BadGateway),
+            ],
+            2,
+        );
+        let res = client.get("http://node0/api/balance").await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadGateway);
+
+        // The number of requests in flight is limited.
+        let fake = Fake::default();
+        let client = replicated(
+            &fake,
+            vec![
+                (SLOW, StatusCode::Ok),
+                (SLOW, StatusCode::Ok),
+                (FAST, StatusCode::Ok),
+            ],
+            2,
+        );
+        let start = Instant::now();
+        let mut res = client.get("http://node0/api/balance").await.unwrap();
+        assert!(start.elapsed() >= SLOW);
+        assert_eq!(res.body_string().await.unwrap(), "node0 /api/balance");
+        assert_eq!(fake.started.load(Ordering::SeqCst), 2);
+    }
+}
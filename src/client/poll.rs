@@ -0,0 +1,506 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Streams of items from services which can only be polled.
+//!
+//! Many services expose their data only as an indexed query: "give me the items after position
+//! `n`", like the blocks after a given height or the memos after a given index. A consumer which
+//! wants a stream of new items has to poll such an endpoint, remember where it got to, and ignore
+//! the items it has already seen. [poll_stream] does that, and presents the endpoint as a
+//! [Stream]:
+//!
+//! ```
+//! # use net::client::poll::{poll_stream, Positioned};
+//! # use std::time::Duration;
+//! # use surf::Url;
+//! # #[derive(Clone, Debug, serde::Deserialize, serde::Serialize, snafu::Snafu)]
+//! # struct Error;
+//! # impl net::Error for Error {
+//! #     fn catch_all(_: String) -> Self { Error }
+//! #     fn status(&self) -> surf::StatusCode { surf::StatusCode::InternalServerError }
+//! # }
+//! #[derive(serde::Deserialize)]
+//! struct Block {
+//!     height: u64,
+//! }
+//!
+//! impl Positioned for Block {
+//!     type Position = u64;
+//!
+//!     fn position(&self) -> u64 {
+//!         self.height
+//!     }
+//! }
+//!
+//! let blocks = poll_stream::<Block, Error, _>(
+//!     |after| {
+//!         let from = after.map_or(0, |height| height + 1);
+//!         Url::parse(&format!("http://eqs.example.com/blocks?from={}", from)).unwrap()
+//!     },
+//!     Duration::from_secs(1),
+//! );
+//! ```
+//!
+//! Each poll requests the URL returned by the URL function, given the position of the last item
+//! yielded so far (or [None] at first). The response is a list of items. Items are yielded in order
+//! of position, and items at or before the last position are dropped, so it does not matter if
+//! the endpoint includes the last item again, or if pages overlap. If a poll returns new items, the
+//! next poll is made as soon as they have been consumed, so the stream catches up quickly; once a
+//! poll returns nothing new, the stream waits for the interval before polling again.
+//!
+//! The position of the last item yielded is available from [PollStream::position]. A consumer can
+//! save it, and later continue where it left off with [PollStream::resume_from]. Positions are the
+//! items' own, not opaque [Cursor]s, since the URL function needs to know what they mean.
+//!
+//! Failed polls are yielded as errors, and polling continues after the interval, so the stream
+//! never ends on its own.
+//!
+//! # Paginated endpoints
+//!
+//! Endpoints which return a [Page] of results at a time, with the opaque [Cursor] of the next page,
+//! can be polled with [poll_pages] instead. The URL function is given the cursor of the next page
+//! (or [None] for the first page), and the items do not need to know their positions. The stream
+//! ends when a page has no next cursor. [PageStream::cursor] is the cursor of the first page whose
+//! items have not all been yielded, so a consumer which resumes from it may see some items again.
+
+use super::{parse_error_body, response_body};
+use crate::cursor::{Cursor, Page};
+use crate::error::Error;
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use surf::http::mime;
+use surf::Url;
+
+/// An item which knows its position in the results of a query.
+pub trait Positioned {
+    type Position: Clone + Ord + Send + Sync + 'static;
+
+    fn position(&self) -> Self::Position;
+}
+
+type UrlFn<P> = Arc<dyn Fn(Option<&P>) -> Url + Send + Sync>;
+
+/// A stream of the items of an indexed query endpoint.
+///
+/// `url_fn` gives the URL to poll for the items after a position, and `interval` is how long to
+/// wait between polls once the stream has caught up. See the [module documentation](self).
+pub fn poll_stream<T, E, F>(url_fn: F, interval: Duration) -> PollStream<T, E>
+where
+    T: Positioned + DeserializeOwned + Send + 'static,
+    E: Error,
+    F: Fn(Option<&T::Position>) -> Url + Send + Sync + 'static,
+{
+    PollStream {
+        url_fn: Arc::new(url_fn),
+        interval,
+        client: surf::Client::new().with(parse_error_body::<E>),
+        position: Default::default(),
+        items: None,
+    }
+}
+
+/// A stream of the items of an indexed query endpoint, created by [poll_stream].
+pub struct PollStream<T: Positioned, E> {
+    url_fn: UrlFn<T::Position>,
+    interval: Duration,
+    client: surf::Client,
+    position: Arc<Mutex<Option<T::Position>>>,
+    // Created when the stream is first polled.
+    items: Option<BoxStream<'static, Result<T, E>>>,
+}
+
+impl<T, E> PollStream<T, E>
+where
+    T: Positioned + DeserializeOwned + Send + 'static,
+    E: Error,
+{
+    /// Use `client` to poll.
+    ///
+    /// The client should have the [parse_error_body] middleware, so that failed polls are reported
+    /// as `E`. This has no effect once the stream has been polled.
+    pub fn client(mut self, client: surf::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Start after the item at `position`, instead of at the beginning.
+    ///
+    /// This has no effect once the stream has been polled.
+    pub fn resume_from(self, position: T::Position) -> Self {
+        *self.position.lock().unwrap() = Some(position);
+        self
+    }
+
+    /// The position of the last item yielded by the stream, if any.
+    pub fn position(&self) -> Option<T::Position> {
+        self.position.lock().unwrap().clone()
+    }
+
+    fn items(&self) -> BoxStream<'static, Result<T, E>> {
+        struct State<T> {
+            buffer: VecDeque<T>,
+            // Whether to wait before polling again.
+            wait: bool,
+        }
+
+        let url_fn = self.url_fn.clone();
+        let interval = self.interval;
+        let client = self.client.clone();
+        let position = self.position.clone();
+        let state = State {
+            buffer: VecDeque::new(),
+            wait: false,
+        };
+        stream::unfold(state, move |mut state| {
+            let url_fn = url_fn.clone();
+            let client = client.clone();
+            let position = position.clone();
+            async move {
+                loop {
+                    if let Some(item) = state.buffer.pop_front() {
+                        *position.lock().unwrap() = Some(item.position());
+                        return Some((Ok(item), state));
+                    }
+                    if state.wait {
+                        async_std::task::sleep(interval).await;
+                    }
+
+                    let after = position.lock().unwrap().clone();
+                    let mut page = match poll::<Vec<T>>(&client, url_fn(after.as_ref())).await {
+                        Ok(page) => page,
+                        Err(err) => {
+                            state.wait = true;
+                            return Some((Err(E::from_client_error(err)), state));
+                        }
+                    };
+                    page.sort_by_key(|item| item.position());
+                    page.dedup_by_key(|item| item.position());
+                    if let Some(after) = &after {
+                        page.retain(|item| item.position() > *after);
+                    }
+                    state.wait = page.is_empty();
+                    state.buffer = page.into();
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+async fn poll<R: DeserializeOwned>(client: &surf::Client, url: Url) -> Result<R, surf::Error> {
+    let mut res = client
+        .get(url)
+        .header("Accept", mime::BYTE_STREAM.to_string())
+        .await?;
+    response_body(&mut res).await
+}
+
+impl<T, E> Stream for PollStream<T, E>
+where
+    T: Positioned + DeserializeOwned + Send + 'static,
+    E: Error,
+{
+    type Item = Result<T, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.items.is_none() {
+            self.items = Some(self.items());
+        }
+        self.items.as_mut().unwrap().poll_next_unpin(cx)
+    }
+}
+
+/// A stream of the items of a paginated endpoint.
+///
+/// `url_fn` gives the URL of the page at a cursor, or of the first page, and `interval` is how
+/// long to wait before polling again after an empty page. See
+/// [Paginated endpoints](self#paginated-endpoints).
+pub fn poll_pages<T, E, F>(url_fn: F, interval: Duration) -> PageStream<T, E>
+where
+    T: DeserializeOwned + Send + 'static,
+    E: Error,
+    F: Fn(Option<&Cursor>) -> Url + Send + Sync + 'static,
+{
+    PageStream {
+        url_fn: Arc::new(url_fn),
+        interval,
+        client: surf::Client::new().with(parse_error_body::<E>),
+        cursor: Default::default(),
+        items: None,
+    }
+}
+
+/// A stream of the items of a paginated endpoint, created by [poll_pages].
+pub struct PageStream<T, E> {
+    url_fn: UrlFn<Cursor>,
+    interval: Duration,
+    client: surf::Client,
+    cursor: Arc<Mutex<Option<Cursor>>>,
+    // Created when the stream is first polled.
+    items: Option<BoxStream<'static, Result<T, E>>>,
+}
+
+impl<T, E> PageStream<T, E>
+where
+    T: DeserializeOwned + Send + 'static,
+    E: Error,
+{
+    /// Use `client` to poll.
+    ///
+    /// The client should have the [parse_error_body] middleware, so that failed polls are reported
+    /// as `E`. This has no effect once the stream has been polled.
+    pub fn client(mut self, client: surf::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Start at the page at `cursor`, instead of the first page.
+    ///
+    /// This has no effect once the stream has been polled.
+    pub fn resume_from(self, cursor: Cursor) -> Self {
+        *self.cursor.lock().unwrap() = Some(cursor);
+        self
+    }
+
+    /// The cursor of the first page whose items have not all been yielded, if it is not the first
+    /// page.
+    pub fn cursor(&self) -> Option<Cursor> {
+        self.cursor.lock().unwrap().clone()
+    }
+
+    fn items(&self) -> BoxStream<'static, Result<T, E>> {
+        struct State<T> {
+            buffer: VecDeque<T>,
+            // The cursor of the page after the one in `buffer`, or [None] if it is the last page.
+            next: Option<Cursor>,
+            // Whether to wait before polling again.
+            wait: bool,
+        }
+
+        let url_fn = self.url_fn.clone();
+        let interval = self.interval;
+        let client = self.client.clone();
+        let cursor = self.cursor.clone();
+        let state = State {
+            buffer: VecDeque::new(),
+            next: None,
+            wait: false,
+        };
+        stream::unfold(Some(state), move |state| {
+            let url_fn = url_fn.clone();
+            let client = client.clone();
+            let cursor = cursor.clone();
+            async move {
+                // [None] once the last page has been consumed.
+                let mut state = state?;
+                loop {
+                    if let Some(item) = state.buffer.pop_front() {
+                        if state.buffer.is_empty() {
+                            // The whole page has been yielded, so the stream resumes after it.
+                            match state.next.take() {
+                                Some(next) => *cursor.lock().unwrap() = Some(next),
+                                None => return Some((Ok(item), None)),
+                            }
+                        }
+                        return Some((Ok(item), Some(state)));
+                    }
+                    if state.wait {
+                        async_std::task::sleep(interval).await;
+                    }
+
+                    let at = cursor.lock().unwrap().clone();
+                    let page = match poll::<Page<T>>(&client, url_fn(at.as_ref())).await {
+                        Ok(page) => page,
+                        Err(err) => {
+                            state.wait = true;
+                            return Some((Err(E::from_client_error(err)), Some(state)));
+                        }
+                    };
+                    match (page.items.is_empty(), page.next) {
+                        (true, Some(next)) => {
+                            state.wait = true;
+                            *cursor.lock().unwrap() = Some(next);
+                        }
+                        (true, None) => return None,
+                        (false, next) => {
+                            state.wait = false;
+                            state.next = next;
+                            state.buffer = page.items.into();
+                        }
+                    }
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+impl<T, E> Stream for PageStream<T, E>
+where
+    T: DeserializeOwned + Send + 'static,
+    E: Error,
+{
+    type Item = Result<T, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.items.is_none() {
+            self.items = Some(self.items());
+        }
+        self.items.as_mut().unwrap().poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::response;
+    use crate::test_util::{Error, Server};
+    use serde::{Deserialize, Serialize};
+    use surf::{Client, StatusCode};
+
+    #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+    struct Item(u64);
+
+    impl crate::SafeToServe for Item {}
+
+    impl Positioned for Item {
+        type Position = u64;
+
+        fn position(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Service {
+        items: Arc<Mutex<Vec<Item>>>,
+        down: Arc<Mutex<bool>>,
+    }
+
+    fn stream(service: &Service) -> PollStream<Item, Error> {
+        // Pages of at most 2 items, which include the item at `from`, so pages overlap.
+        let mut app = tide::with_state(service.clone());
+        app.at("/items")
+            .get(|req: tide::Request<Service>| async move {
+                if *req.state().down.lock().unwrap() {
+                    return Err(tide::Error::from_str(
+                        StatusCode::ServiceUnavailable,
+                        "down",
+                    ));
+                }
+                let from = req
+                    .url()
+                    .query_pairs()
+                    .find(|(name, _)| name == "from")
+                    .map_or(0, |(_, from)| from.parse().unwrap());
+                let items = req.state().items.lock().unwrap().clone();
+                let page = items
+                    .into_iter()
+                    .filter(|item| item.0 >= from)
+                    .take(2)
+                    .collect::<Vec<_>>();
+                response(&req, page)
+            });
+        poll_stream(
+            |after: Option<&u64>| {
+                let from = after.copied().unwrap_or(0);
+                Url::parse(&format!("http://service/items?from={}", from)).unwrap()
+            },
+            Duration::from_millis(10),
+        )
+        .client(
+            Client::new()
+                .with(parse_error_body::<Error>)
+                .with(Server(app)),
+        )
+    }
+
+    #[async_std::test]
+    async fn test_poll_stream() {
+        let service = Service::default();
+        *service.items.lock().unwrap() = vec![Item(0), Item(1), Item(2)];
+        let mut items = stream(&service);
+        assert_eq!(items.position(), None);
+
+        // Existing items are yielded once each, despite the overlapping pages.
+        for i in 0..3 {
+            assert_eq!(items.next().await.unwrap().unwrap(), Item(i));
+        }
+        assert_eq!(items.position(), Some(2));
+
+        // New items are picked up by later polls.
+        service.items.lock().unwrap().push(Item(3));
+        assert_eq!(items.next().await.unwrap().unwrap(), Item(3));
+
+        // Failed polls are reported, and polling continues.
+        *service.down.lock().unwrap() = true;
+        items.next().await.unwrap().unwrap_err();
+        *service.down.lock().unwrap() = false;
+        service.items.lock().unwrap().push(Item(4));
+        assert_eq!(items.next().await.unwrap().unwrap(), Item(4));
+
+        // A new stream can resume from the position of an old one.
+        let position = items.position().unwrap();
+        drop(items);
+        service.items.lock().unwrap().push(Item(5));
+        let mut items = stream(&service).resume_from(position);
+        assert_eq!(items.next().await.unwrap().unwrap(), Item(5));
+    }
+
+    #[async_std::test]
+    async fn test_poll_pages() {
+        use crate::cursor::CursorKey;
+
+        // Pages of 2 items, each with the cursor of the next page, except the last.
+        let key = CursorKey::new([3; 32]);
+        let mut app = tide::with_state(key);
+        app.at("/items")
+            .get(|req: tide::Request<CursorKey>| async move {
+                let from: u64 = req.state().from_query(&req, "cursor", 0)?.unwrap_or(0);
+                let items = (from..5).take(2).map(Item).collect::<Vec<_>>();
+                let next = if from + 2 < 5 {
+                    Some(req.state().seal(0, &(from + 2)))
+                } else {
+                    None
+                };
+                response(&req, Page { items, next })
+            });
+        let pages = move || {
+            poll_pages::<Item, Error, _>(
+                |cursor| match cursor {
+                    Some(cursor) => {
+                        Url::parse(&format!("http://service/items?cursor={}", cursor)).unwrap()
+                    }
+                    None => Url::parse("http://service/items").unwrap(),
+                },
+                Duration::from_millis(10),
+            )
+            .client(
+                Client::new()
+                    .with(parse_error_body::<Error>)
+                    .with(Server(app.clone())),
+            )
+        };
+
+        let mut items = pages();
+        assert_eq!(items.next().await.unwrap().unwrap(), Item(0));
+        // The cursor only moves on once the whole page has been yielded.
+        assert_eq!(items.cursor(), None);
+        assert_eq!(items.next().await.unwrap().unwrap(), Item(1));
+        let cursor = items.cursor().unwrap();
+
+        // A new stream can resume from the cursor, and the stream ends after the last page.
+        let items = pages().resume_from(cursor);
+        let rest = items.map(Result::unwrap).collect::<Vec<_>>().await;
+        assert_eq!(rest, vec![Item(2), Item(3), Item(4)]);
+    }
+}
@@ -0,0 +1,458 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A persistent queue of outbound requests, for clients on unreliable networks.
+//!
+//! A wallet on a flaky mobile connection should not lose a memo post just because the bulletin
+//! board was unreachable at the moment it was sent. [OutboundQueue] is a client middleware which
+//! catches requests that fail to reach the server, stores them in a directory, and answers them
+//! with 202 Accepted. The stored requests survive restarts, and are sent again, in order, by
+//! [OutboundQueue::flush] or by a background [OutboundQueue::run] loop, which retries with
+//! exponential backoff until the server is reachable.
+//!
+//! Every request which passes through the queue is given an
+//! [IDEMPOTENCY_KEY] header, if it does not already
+//! have one, which stays the same when the request is sent again. A request which reached the
+//! server but whose response was lost may therefore be received twice; servers should use the
+//! [Idempotency](crate::server::idempotency::Idempotency) middleware to recognize the retry and
+//! replay the original response.
+//!
+//! Only requests which change state (anything but GET and HEAD) are queued. Like [ResponseCache],
+//! the queue uses blocking file I/O, which is appropriate for the small number of small requests
+//! it is designed for.
+//!
+//! [ResponseCache]: super::cache::ResponseCache
+
+use crate::wire::binary;
+use async_std::channel::{bounded, Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use surf::http::Method;
+use surf::middleware::{Middleware, Next};
+use surf::{Client, Request, StatusCode};
+
+pub use crate::wire::IDEMPOTENCY_KEY;
+
+/// A request waiting to be sent.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuedRequest {
+    /// The position of the request in the queue.
+    pub seq: u64,
+    /// The value of the idempotency key header.
+    pub key: String,
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl QueuedRequest {
+    async fn read(seq: u64, mut req: Request) -> surf::Result<Self> {
+        let key = match req.header(IDEMPOTENCY_KEY) {
+            Some(key) => key.as_str().to_string(),
+            None => new_key(),
+        };
+        let body = req.take_body().into_bytes().await?;
+        let headers = req
+            .iter()
+            .filter(|(name, _)| *name != IDEMPOTENCY_KEY)
+            .map(|(name, values)| (name.to_string(), values.as_str().to_string()))
+            .collect();
+        Ok(Self {
+            seq,
+            key,
+            method: req.method().to_string(),
+            url: req.url().to_string(),
+            headers,
+            body,
+        })
+    }
+
+    fn to_request(&self) -> surf::Result<Request> {
+        let mut req = Request::new(self.method.parse()?, self.url.parse()?);
+        // Set the body first, so that it doesn't override the original Content-Type.
+        req.set_body(self.body.clone());
+        for (name, value) in &self.headers {
+            req.insert_header(name.as_str(), value.as_str());
+        }
+        req.insert_header(IDEMPOTENCY_KEY, self.key.as_str());
+        Ok(req)
+    }
+}
+
+// A key which is unique across clients and restarts with overwhelming probability.
+fn new_key() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!(
+        "{:x}-{:x}-{:x}",
+        nanos,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+struct Inner {
+    dir: PathBuf,
+    backoff: Duration,
+    max_backoff: Duration,
+    pending: Mutex<BTreeMap<u64, QueuedRequest>>,
+    next_seq: AtomicU64,
+    // Only one flush may run at a time, so that requests are sent in order.
+    flushing: async_lock::Mutex<()>,
+    // Wakes up the background loop when a request is queued.
+    wake: (Sender<()>, Receiver<()>),
+}
+
+/// Client middleware which queues requests that fail to reach the server.
+#[derive(Clone)]
+pub struct OutboundQueue {
+    inner: Arc<Inner>,
+}
+
+impl OutboundQueue {
+    /// Open the queue stored in `dir`, creating it if necessary.
+    ///
+    /// By default, [run](Self::run) retries after 1 second, doubling the delay after each failure
+    /// up to 5 minutes.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let mut pending = BTreeMap::new();
+        for file in fs::read_dir(&dir)? {
+            let path = file?.path();
+            // Skip (and remove) files which are not valid requests, for example because a write
+            // was interrupted.
+            match read_request(&path) {
+                Some(req) => {
+                    pending.insert(req.seq, req);
+                }
+                None => {
+                    fs::remove_file(&path).ok();
+                }
+            }
+        }
+        let next_seq = pending.keys().next_back().map(|seq| seq + 1).unwrap_or(0);
+        Ok(Self {
+            inner: Arc::new(Inner {
+                dir,
+                backoff: Duration::from_secs(1),
+                max_backoff: Duration::from_secs(300),
+                pending: Mutex::new(pending),
+                next_seq: AtomicU64::new(next_seq),
+                flushing: async_lock::Mutex::new(()),
+                wake: bounded(1),
+            }),
+        })
+    }
+
+    /// The delay before [run](Self::run) retries after a failure, which doubles after each
+    /// consecutive failure up to `max`.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        let inner = self.config();
+        inner.backoff = initial;
+        inner.max_backoff = max;
+        self
+    }
+
+    fn config(&mut self) -> &mut Inner {
+        Arc::get_mut(&mut self.inner).expect("queue must be configured before it is shared")
+    }
+
+    /// Add a request to the queue, returning its idempotency key.
+    pub async fn enqueue(&self, req: Request) -> surf::Result<String> {
+        let req = QueuedRequest::read(self.next_seq(), req).await?;
+        let key = req.key.clone();
+        self.put(req)?;
+        Ok(key)
+    }
+
+    /// The requests waiting to be sent, in order.
+    pub fn pending(&self) -> Vec<QueuedRequest> {
+        self.inner
+            .pending
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Remove the request with idempotency key `key` from the queue, returning whether it was
+    /// queued.
+    pub fn cancel(&self, key: &str) -> bool {
+        let mut pending = self.inner.pending.lock().unwrap();
+        let seq = match pending.values().find(|req| req.key == key) {
+            Some(req) => req.seq,
+            None => return false,
+        };
+        pending.remove(&seq);
+        fs::remove_file(self.path(seq)).ok();
+        true
+    }
+
+    /// The number of requests waiting to be sent.
+    pub fn len(&self) -> usize {
+        self.inner.pending.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Send queued requests, in order, until the queue is empty or the server is unreachable.
+    ///
+    /// `client` must not use this queue as middleware, or requests which fail would be queued
+    /// again. A request is removed from the queue once the server responds with a status that
+    /// retrying will not change; requests which fail without a response, or with a 5xx, 408 or 429
+    /// status, stop the flush and stay at the front of the queue. Returns the idempotency key and
+    /// response status of each request which was removed.
+    pub async fn flush(&self, client: &Client) -> Vec<(String, StatusCode)> {
+        let _guard = self.inner.flushing.lock().await;
+        let mut sent = Vec::new();
+        loop {
+            let next = self.inner.pending.lock().unwrap().values().next().cloned();
+            let queued = match next {
+                Some(queued) => queued,
+                None => break,
+            };
+            let req = match queued.to_request() {
+                Ok(req) => req,
+                Err(err) => {
+                    // A stored request which can't be rebuilt will never succeed.
+                    tracing::warn!("dropping malformed queued request {}: {}", queued.key, err);
+                    self.remove(queued.seq);
+                    continue;
+                }
+            };
+            match client.send(req).await {
+                Ok(res) if !retryable(res.status()) => {
+                    self.remove(queued.seq);
+                    sent.push((queued.key, res.status()));
+                }
+                Ok(res) => {
+                    tracing::info!(
+                        "queued request {} failed with status {}, will retry",
+                        queued.key,
+                        res.status()
+                    );
+                    break;
+                }
+                Err(err) => {
+                    tracing::info!("queued request {} failed, will retry: {}", queued.key, err);
+                    break;
+                }
+            }
+        }
+        sent
+    }
+
+    /// Flush the queue whenever it is not empty, retrying with backoff while the server is
+    /// unreachable.
+    ///
+    /// This never returns; spawn it as a background task. See [flush](Self::flush) for the
+    /// requirements on `client`.
+    pub async fn run(&self, client: Client) {
+        let mut backoff = self.inner.backoff;
+        loop {
+            if self.is_empty() {
+                // Both ends of the channel are owned by `self`, so this never fails.
+                self.inner.wake.1.recv().await.ok();
+                continue;
+            }
+            if self.flush(&client).await.is_empty() && !self.is_empty() {
+                async_std::task::sleep(backoff).await;
+                backoff = (backoff * 2).min(self.inner.max_backoff);
+            } else {
+                backoff = self.inner.backoff;
+            }
+        }
+    }
+
+    fn next_seq(&self) -> u64 {
+        self.inner.next_seq.fetch_add(1, Ordering::SeqCst)
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        self.inner.dir.join(format!("{:020}", seq))
+    }
+
+    fn put(&self, req: QueuedRequest) -> io::Result<()> {
+        let path = self.path(req.seq);
+        let bytes = binary::serialize(&req).map_err(io::Error::other)?;
+        // Write to a temporary file and rename it into place, so that a crash never leaves a
+        // partial request.
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, &path)?;
+        self.inner.pending.lock().unwrap().insert(req.seq, req);
+        self.inner.wake.0.try_send(()).ok();
+        Ok(())
+    }
+
+    fn remove(&self, seq: u64) {
+        self.inner.pending.lock().unwrap().remove(&seq);
+        fs::remove_file(self.path(seq)).ok();
+    }
+}
+
+fn read_request(path: &Path) -> Option<QueuedRequest> {
+    binary::deserialize(&fs::read(path).ok()?).ok()
+}
+
+fn retryable(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::RequestTimeout
+        || status == StatusCode::TooManyRequests
+}
+
+#[surf::utils::async_trait]
+impl Middleware for OutboundQueue {
+    async fn handle(&self, req: Request, client: Client, next: Next<'_>) -> surf::Result {
+        if req.method() == Method::Get || req.method() == Method::Head {
+            return next.run(req, client).await;
+        }
+        // Buffer the request so that it can be stored if it fails.
+        let queued = QueuedRequest::read(self.next_seq(), req).await?;
+        match next.run(queued.to_request()?, client).await {
+            Ok(res) => Ok(res),
+            Err(err) => {
+                tracing::info!("request {} failed, queueing: {}", queued.key, err);
+                let key = queued.key.clone();
+                self.put(queued)?;
+                let mut res = surf::http::Response::new(StatusCode::Accepted);
+                res.insert_header(IDEMPOTENCY_KEY, key);
+                Ok(res.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::idempotency::Idempotency;
+    use std::sync::atomic::AtomicUsize;
+
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    enum Network {
+        Down,
+        // Requests reach the server, but responses are lost.
+        LosesResponses,
+        Up,
+    }
+
+    // A fake network connecting the client to an in-process server.
+    #[derive(Clone)]
+    struct Fake {
+        state: Arc<Mutex<Network>>,
+        server: Arc<tide::Server<()>>,
+    }
+
+    #[surf::utils::async_trait]
+    impl Middleware for Fake {
+        async fn handle(&self, req: Request, _client: Client, _next: Next<'_>) -> surf::Result {
+            let state = *self.state.lock().unwrap();
+            if state == Network::Down {
+                return Err(surf::Error::from_str(
+                    StatusCode::ServiceUnavailable,
+                    "network unreachable",
+                ));
+            }
+            let req: surf::http::Request = req.into();
+            let res: surf::http::Response = self.server.respond(req).await?;
+            if state == Network::LosesResponses {
+                return Err(surf::Error::from_str(
+                    StatusCode::ServiceUnavailable,
+                    "connection reset",
+                ));
+            }
+            Ok(res.into())
+        }
+    }
+
+    fn fake() -> (Fake, Arc<AtomicUsize>) {
+        let posts = Arc::new(AtomicUsize::new(0));
+        let mut server = tide::new();
+        server.with(Idempotency::new(100));
+        server.at("/memo").post({
+            let posts = posts.clone();
+            move |mut req: tide::Request<()>| {
+                let posts = posts.clone();
+                async move {
+                    assert_eq!(req.body_string().await?, "memo");
+                    posts.fetch_add(1, Ordering::SeqCst);
+                    Ok("posted")
+                }
+            }
+        });
+        let fake = Fake {
+            state: Arc::new(Mutex::new(Network::Down)),
+            server: Arc::new(server),
+        };
+        (fake, posts)
+    }
+
+    #[async_std::test]
+    async fn test_queue() {
+        let dir = std::env::temp_dir().join(format!("net-queue-test-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let (network, posts) = fake();
+        let queue = OutboundQueue::open(&dir).unwrap();
+        let client = Client::new().with(queue.clone()).with(network.clone());
+        let direct = Client::new().with(network.clone());
+
+        // While the network is down, posts are queued and accepted.
+        let res = client
+            .post("http://localhost/memo")
+            .body("memo")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Accepted);
+        let key = res.header(IDEMPOTENCY_KEY).unwrap().as_str().to_string();
+        assert_eq!(queue.len(), 1);
+        assert!(queue.flush(&direct).await.is_empty());
+
+        // The queue survives a restart.
+        drop(queue);
+        let queue = OutboundQueue::open(&dir).unwrap();
+        assert_eq!(queue.pending()[0].key, key);
+
+        // A retry whose response is lost reaches the server, but stays queued.
+        *network.state.lock().unwrap() = Network::LosesResponses;
+        assert!(queue.flush(&direct).await.is_empty());
+        assert_eq!(posts.load(Ordering::SeqCst), 1);
+        assert_eq!(queue.len(), 1);
+
+        // Once the network is up, the request is sent again, and the server recognizes it.
+        *network.state.lock().unwrap() = Network::Up;
+        assert_eq!(queue.flush(&direct).await, vec![(key, StatusCode::Ok)]);
+        assert_eq!(posts.load(Ordering::SeqCst), 1);
+        assert!(queue.is_empty());
+
+        // Requests which succeed are not queued.
+        let res = client
+            .post("http://localhost/memo")
+            .body("memo")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(posts.load(Ordering::SeqCst), 2);
+        assert!(queue.is_empty());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,503 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A client with the standard middleware, in the right order.
+//!
+//! Most clients want the same things: error parsing, retries, a timeout, an auth token, redirects
+//! and some logging. Each of these is easy to add to a [surf::Client], but the order in which they
+//! are added matters, and most orders are subtly wrong. For example, if redirects were followed
+//! outside error parsing, every 3xx response would already have been turned into an error; if the
+//! timeout were outside retries, one slow attempt would use up the time for all of them.
+//!
+//! [ClientStack] builds a client with the middleware it is configured with, in this order, from
+//! outermost (the first to see a request) to innermost:
+//!  1. retries, so that each attempt runs through everything below
+//!  2. tracing, which logs each attempt separately
+//!  3. the timeout, which applies to each attempt separately
+//!  4. authentication, so that the token is added to the first request of every attempt
+//!  5. error parsing, which sees only the final response after redirects
+//!  6. redirects, which are followed before any other middleware sees the response
+//!
+//! ```
+//! # use net::client::stack::ClientStack;
+//! # use std::time::Duration;
+//! # #[derive(Clone, Debug, serde::Deserialize, serde::Serialize, snafu::Snafu)]
+//! # struct ApiError { msg: String }
+//! # impl net::Error for ApiError {
+//! #     fn catch_all(msg: String) -> Self { Self { msg } }
+//! #     fn status(&self) -> tide::StatusCode { tide::StatusCode::InternalServerError }
+//! # }
+//! let client = ClientStack::new()
+//!     .errors::<ApiError>()
+//!     .retry(3, Duration::from_millis(100))
+//!     .timeout(Duration::from_secs(10))
+//!     .bearer_token("secret")
+//!     .redirects(5)
+//!     .trace()
+//!     .build();
+//! ```
+
+use super::queue::IDEMPOTENCY_KEY;
+use super::{ErrorTypes, Stopwatch};
+use crate::error::Error;
+use std::convert::TryInto;
+use std::time::Duration;
+use surf::http::{Body, Method, Mime};
+use surf::middleware::{Middleware, Next};
+use surf::{Client, Config, Request, Response, StatusCode, Url};
+
+/// A builder for a [surf::Client] with standard middleware.
+///
+/// By default, the client has none of the middleware described in the [module
+/// documentation](self); each setter adds one layer.
+#[derive(Clone, Default)]
+pub struct ClientStack {
+    base_url: Option<Url>,
+    retry: Option<Retry>,
+    trace: bool,
+    timeout: Option<Duration>,
+    bearer_token: Option<String>,
+    errors: Option<ErrorTypes>,
+    redirects: u8,
+}
+
+impl ClientStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolve relative request URLs against `url`.
+    pub fn base_url(mut self, url: Url) -> Self {
+        self.base_url = Some(url);
+        self
+    }
+
+    /// Make up to `max_attempts` attempts at each request.
+    ///
+    /// Requests are retried if they fail without a response, or with a 408, 429 or 5xx status. The
+    /// first retry happens after `backoff`, and the delay doubles with each subsequent retry.
+    ///
+    /// Only requests which are safe to repeat are retried: those whose method is idempotent, and
+    /// those with an [IDEMPOTENCY_KEY] header.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_attempts` is 0.
+    pub fn retry(mut self, max_attempts: usize, backoff: Duration) -> Self {
+        assert!(max_attempts > 0, "at least one attempt is required");
+        self.retry = Some(Retry {
+            max_attempts,
+            backoff,
+        });
+        self
+    }
+
+    /// Log the outcome and duration of each attempt.
+    pub fn trace(mut self) -> Self {
+        self.trace = true;
+        self
+    }
+
+    /// Fail each attempt which has not received a response within `timeout`.
+    ///
+    /// The timeout covers the request and the response headers, but not reading the response
+    /// body. An attempt which times out fails with a 408 status, and is retried.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Authenticate requests with `token`, in an `Authorization: Bearer` header.
+    ///
+    /// The token is not added to requests which already have an Authorization header, and it is
+    /// not sent along when a redirect leads to a different origin.
+    pub fn bearer_token(mut self, token: impl Into<String>) -> Self {
+        self.bearer_token = Some(token.into());
+        self
+    }
+
+    /// Parse error responses as `E`, after any previously registered types.
+    ///
+    /// With a single error type, this is equivalent to the
+    /// [parse_error_body](super::parse_error_body) middleware. With several, it is equivalent to
+    /// [ErrorTypes].
+    pub fn errors<E: Error>(mut self) -> Self {
+        self.errors = Some(self.errors.take().unwrap_or_default().with::<E>());
+        self
+    }
+
+    /// Follow up to `max` redirects for each attempt.
+    pub fn redirects(mut self, max: u8) -> Self {
+        self.redirects = max;
+        self
+    }
+
+    /// Build the client.
+    pub fn build(self) -> Client {
+        let mut client = match self.base_url {
+            Some(url) => Config::new()
+                .set_base_url(url)
+                .try_into()
+                .expect("default HTTP client configuration is valid"),
+            None => Client::new(),
+        };
+        if let Some(retry) = self.retry {
+            client = client.with(retry);
+        }
+        if self.trace {
+            client = client.with(Trace);
+        }
+        if let Some(timeout) = self.timeout {
+            client = client.with(Timeout(timeout));
+        }
+        if let Some(token) = self.bearer_token {
+            client = client.with(BearerToken(format!("Bearer {}", token)));
+        }
+        if let Some(errors) = self.errors {
+            client = client.with(errors);
+        }
+        if self.redirects > 0 {
+            client = client.with(FollowRedirects(self.redirects));
+        }
+        client
+    }
+}
+
+// A request body which has been read into memory, so that it can be sent more than once.
+type Buffered = Option<(Vec<u8>, Mime)>;
+
+async fn buffer(req: &mut Request) -> surf::Result<Buffered> {
+    let body = req.take_body();
+    if body.is_empty() == Some(true) {
+        return Ok(None);
+    }
+    let mime = body.mime().clone();
+    Ok(Some((body.into_bytes().await?, mime)))
+}
+
+fn copy(req: &Request, body: &Buffered) -> Request {
+    let mut copy = req.clone();
+    if let Some((bytes, mime)) = body {
+        let mut body = Body::from_bytes(bytes.clone());
+        body.set_mime(mime.clone());
+        copy.set_body(body);
+    }
+    copy
+}
+
+#[derive(Clone, Copy)]
+struct Retry {
+    max_attempts: usize,
+    backoff: Duration,
+}
+
+fn retryable(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::RequestTimeout
+        || status == StatusCode::TooManyRequests
+}
+
+fn idempotent(req: &Request) -> bool {
+    matches!(
+        req.method(),
+        Method::Get | Method::Head | Method::Put | Method::Delete | Method::Options
+    ) || req.header(IDEMPOTENCY_KEY).is_some()
+}
+
+#[surf::utils::async_trait]
+impl Middleware for Retry {
+    async fn handle(&self, mut req: Request, client: Client, next: Next<'_>) -> surf::Result {
+        if self.max_attempts == 1 || !idempotent(&req) {
+            return next.run(req, client).await;
+        }
+        let body = buffer(&mut req).await?;
+        let mut backoff = self.backoff;
+        for attempt in 1.. {
+            let res = next.run(copy(&req, &body), client.clone()).await;
+            match &res {
+                Err(err) if attempt < self.max_attempts && retryable(err.status()) => {
+                    tracing::info!(
+                        "{} {} failed (attempt {}), retrying: {}",
+                        req.method(),
+                        req.url(),
+                        attempt,
+                        err
+                    );
+                    async_std::task::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                _ => return res,
+            }
+        }
+        unreachable!()
+    }
+}
+
+struct Trace;
+
+#[surf::utils::async_trait]
+impl Middleware for Trace {
+    async fn handle(&self, req: Request, client: Client, next: Next<'_>) -> surf::Result {
+        let method = req.method();
+        let url = req.url().clone();
+        let start = Stopwatch::start();
+        let res = next.run(req, client).await;
+        match &res {
+            Ok(res) => tracing::debug!(
+                "{} {} -> {} in {:?}",
+                method,
+                url,
+                res.status(),
+                start.elapsed()
+            ),
+            Err(err) => tracing::warn!(
+                "{} {} failed in {:?}: {} ({})",
+                method,
+                url,
+                start.elapsed(),
+                err,
+                err.status()
+            ),
+        }
+        res
+    }
+}
+
+struct Timeout(Duration);
+
+#[surf::utils::async_trait]
+impl Middleware for Timeout {
+    async fn handle(&self, req: Request, client: Client, next: Next<'_>) -> surf::Result {
+        async_std::future::timeout(self.0, next.run(req, client))
+            .await
+            .unwrap_or_else(|_| {
+                Err(surf::Error::from_str(
+                    StatusCode::RequestTimeout,
+                    format!("no response within {:?}", self.0),
+                ))
+            })
+    }
+}
+
+struct BearerToken(String);
+
+#[surf::utils::async_trait]
+impl Middleware for BearerToken {
+    async fn handle(&self, mut req: Request, client: Client, next: Next<'_>) -> surf::Result {
+        if req.header("Authorization").is_none() {
+            req.insert_header("Authorization", self.0.as_str());
+        }
+        next.run(req, client).await
+    }
+}
+
+struct FollowRedirects(u8);
+
+#[surf::utils::async_trait]
+impl Middleware for FollowRedirects {
+    async fn handle(&self, mut req: Request, client: Client, next: Next<'_>) -> surf::Result {
+        let mut body = buffer(&mut req).await?;
+        let mut redirects = 0;
+        loop {
+            let res = next.run(copy(&req, &body), client.clone()).await?;
+            let location = match redirect_location(&req, &res) {
+                Some(location) if redirects < self.0 => location?,
+                _ => return Ok(res),
+            };
+            redirects += 1;
+
+            // Like browsers, switch to GET after a 303, or after a 301 or 302 in response to a
+            // POST; 307 and 308 preserve the method and body.
+            let status = res.status();
+            if status == StatusCode::SeeOther
+                || (req.method() == Method::Post
+                    && (status == StatusCode::MovedPermanently || status == StatusCode::Found))
+            {
+                AsMut::<surf::http::Request>::as_mut(&mut req).set_method(Method::Get);
+                req.remove_header("Content-Type");
+                body = None;
+            }
+            // Never send credentials to a different origin than the one they were meant for.
+            if location.origin() != req.url().origin() {
+                req.remove_header("Authorization");
+            }
+            *AsMut::<surf::http::Request>::as_mut(&mut req).url_mut() = location;
+        }
+    }
+}
+
+fn redirect_location(req: &Request, res: &Response) -> Option<surf::Result<Url>> {
+    if !matches!(
+        res.status(),
+        StatusCode::MovedPermanently
+            | StatusCode::Found
+            | StatusCode::SeeOther
+            | StatusCode::TemporaryRedirect
+            | StatusCode::PermanentRedirect
+    ) {
+        return None;
+    }
+    let location = res.header("Location")?;
+    Some(req.url().join(location.last().as_str()).map_err(|err| {
+        surf::Error::from_str(
+            StatusCode::BadGateway,
+            format!("invalid redirect location {}: {}", location.last(), err),
+        )
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::Error as ApiError;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Serves requests from an in-process server, and counts them.
+    #[derive(Clone)]
+    struct Fake {
+        server: Arc<tide::Server<()>>,
+        requests: Arc<AtomicUsize>,
+    }
+
+    #[surf::utils::async_trait]
+    impl Middleware for Fake {
+        async fn handle(&self, req: Request, _client: Client, _next: Next<'_>) -> surf::Result {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            let req: surf::http::Request = req.into();
+            let res: surf::http::Response = self.server.respond(req).await?;
+            Ok(res.into())
+        }
+    }
+
+    fn fake() -> Fake {
+        let mut server = tide::new();
+        // Fails the first two requests.
+        let flaky = Arc::new(AtomicUsize::new(0));
+        server.at("/flaky").post(move |mut req: tide::Request<()>| {
+            let flaky = flaky.clone();
+            async move {
+                let body = req.body_string().await?;
+                if flaky.fetch_add(1, Ordering::SeqCst) < 2 {
+                    Ok(tide::Response::new(StatusCode::ServiceUnavailable))
+                } else {
+                    Ok(tide::Response::from(body))
+                }
+            }
+        });
+        server.at("/slow").get(|_| async {
+            async_std::task::sleep(Duration::from_secs(1)).await;
+            Ok("done")
+        });
+        server.at("/fail").get(|_| async {
+            let mut res = tide::Response::new(StatusCode::BadRequest);
+            res.set_body(tide::Body::from_json(&ApiError {
+                msg: "bad".to_string(),
+            })?);
+            Ok(res)
+        });
+        server
+            .at("/moved")
+            .get(|_| async { Ok(tide::Redirect::new("/whoami")) });
+        server
+            .at("/elsewhere")
+            .get(|_| async { Ok(tide::Redirect::new("http://example.com/whoami")) });
+        server
+            .at("/whoami")
+            .get(|req: tide::Request<()>| async move {
+                Ok(req
+                    .header("Authorization")
+                    .map(|auth| auth.as_str().to_string())
+                    .unwrap_or_default())
+            });
+        Fake {
+            server: Arc::new(server),
+            requests: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn client(stack: ClientStack, fake: &Fake) -> Client {
+        stack
+            .base_url(Url::parse("http://localhost/").unwrap())
+            .build()
+            .with(fake.clone())
+    }
+
+    #[async_std::test]
+    async fn test_retry() {
+        let fake = fake();
+        let client = client(
+            ClientStack::new()
+                .retry(3, Duration::from_millis(1))
+                .errors::<ApiError>(),
+            &fake,
+        );
+
+        // Non-idempotent requests are not retried.
+        let err = client.post("flaky").body("hi").await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::ServiceUnavailable);
+        assert_eq!(fake.requests.load(Ordering::SeqCst), 1);
+
+        // With an idempotency key, the body is sent again with each attempt.
+        let mut res = client
+            .post("flaky")
+            .header(IDEMPOTENCY_KEY, "key")
+            .body("hi")
+            .await
+            .unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "hi");
+        assert_eq!(fake.requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[async_std::test]
+    async fn test_timeout_and_errors() {
+        let fake = fake();
+        let client = client(
+            ClientStack::new()
+                .retry(2, Duration::from_millis(1))
+                .timeout(Duration::from_millis(10))
+                .errors::<ApiError>(),
+            &fake,
+        );
+
+        // The timeout applies to each attempt.
+        let err = client.get("slow").await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::RequestTimeout);
+        assert_eq!(fake.requests.load(Ordering::SeqCst), 2);
+
+        // Error responses are parsed, and client errors are not retried.
+        let err = client.get("fail").await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::BadRequest);
+        assert_eq!(
+            ApiError::from_client_error(err),
+            ApiError {
+                msg: "bad".to_string()
+            }
+        );
+        assert_eq!(fake.requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[async_std::test]
+    async fn test_redirects() {
+        let fake = fake();
+        let client = client(
+            ClientStack::new()
+                .bearer_token("secret")
+                .errors::<ApiError>()
+                .redirects(1),
+            &fake,
+        );
+
+        // Redirects are followed before errors are parsed, and keep the token on the same origin.
+        let mut res = client.get("moved").await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "Bearer secret");
+
+        // The token is not sent to another origin.
+        let mut res = client.get("elsewhere").await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "");
+    }
+}
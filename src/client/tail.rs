@@ -0,0 +1,261 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A gap-free stream of events, from history followed by a live subscription.
+//!
+//! An indexer starting from some position needs every event from there on, exactly once and in
+//! order. The service has a history endpoint, which returns the events from a position up to its
+//! current head, and a live subscription, which delivers new events as they happen. Stitching the
+//! two together is subtle: events which happen while the history is being read must not be lost,
+//! events which appear in both must not be delivered twice, and if the subscription drops an event
+//! (for example, while reconnecting), the gap must be filled from history.
+//!
+//! [backfill_then_tail] does this. The subscription is read from the start, while the history is
+//! being read, and its events are buffered. The history is read page by page until it has nothing
+//! more, and then the buffered and new events from the subscription are delivered. Events at or
+//! before the last position delivered are dropped. If the subscription skips a position, the
+//! history is read again from the missing position. If the history does not have the missing events
+//! yet, it is read again when the next event arrives from the subscription.
+//!
+//! Positions must be consecutive integers, like block heights or event indices, so that gaps can be
+//! detected.
+
+use super::poll::Positioned;
+use futures::future::{self, Either};
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Backfill,
+    Live,
+}
+
+struct State<T, E, H> {
+    history: H,
+    live: stream::Fuse<BoxStream<'static, Result<T, E>>>,
+    mode: Mode,
+    // The position of the next event to deliver.
+    next: u64,
+    // Events and errors ready to be delivered.
+    ready: VecDeque<Result<T, E>>,
+    // Events from the subscription which have not been delivered yet.
+    buffer: BTreeMap<u64, T>,
+    // Whether we have tried to fill the gap before the first buffered event since it arrived.
+    gap_checked: bool,
+}
+
+/// A stream of every event from `start` on, from `history` and then `live`.
+///
+/// `history(from)` should return the events at positions `from` and after, in order. It may return
+/// only a page of them, and it should return no events once `from` is past the head. `live` is the
+/// subscription to new events, and the stream ends when it ends. See the
+/// [module documentation](self).
+///
+/// Errors from either source are yielded as they occur, without ending the stream. After an error
+/// from `history`, the stream retries the same request when it is next polled.
+pub fn backfill_then_tail<T, E, H, F, L>(
+    start: u64,
+    history: H,
+    live: L,
+) -> BoxStream<'static, Result<T, E>>
+where
+    T: Positioned<Position = u64> + Send + 'static,
+    E: Send + 'static,
+    H: Fn(u64) -> F + Send + 'static,
+    F: Future<Output = Result<Vec<T>, E>> + Send + 'static,
+    L: Stream<Item = Result<T, E>> + Send + 'static,
+{
+    let state = State {
+        history,
+        live: live.boxed().fuse(),
+        mode: Mode::Backfill,
+        next: start,
+        ready: VecDeque::new(),
+        buffer: BTreeMap::new(),
+        gap_checked: false,
+    };
+    stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(res) = state.ready.pop_front() {
+                if let Ok(event) = &res {
+                    state.next = event.position() + 1;
+                }
+                return Some((res, state));
+            }
+            match state.mode {
+                Mode::Backfill => state.backfill().await,
+                Mode::Live => {
+                    if !state.tail().await {
+                        return None;
+                    }
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+impl<T, E, H, F> State<T, E, H>
+where
+    T: Positioned<Position = u64>,
+    H: Fn(u64) -> F,
+    F: Future<Output = Result<Vec<T>, E>>,
+{
+    // Read a page of history, buffering events from the subscription in the meantime.
+    async fn backfill(&mut self) {
+        let mut fetch = Box::pin((self.history)(self.next));
+        let page = loop {
+            if self.live.is_done() {
+                break fetch.await;
+            }
+            match future::select(fetch, self.live.next()).await {
+                Either::Left((page, _)) => break page,
+                Either::Right((event, unfinished)) => {
+                    fetch = unfinished;
+                    match event {
+                        Some(Ok(event)) => {
+                            self.buffer.insert(event.position(), event);
+                        }
+                        Some(Err(err)) => self.ready.push_back(Err(err)),
+                        None => {}
+                    }
+                }
+            }
+        };
+        let mut page = match page {
+            Ok(page) => page,
+            Err(err) => {
+                self.ready.push_back(Err(err));
+                return;
+            }
+        };
+
+        page.sort_by_key(|event| event.position());
+        let mut next = self.next;
+        for event in page {
+            if event.position() >= next {
+                next = event.position() + 1;
+                self.ready.push_back(Ok(event));
+            }
+        }
+        // Once the history has nothing new, or the subscription has already caught up with it,
+        // switch to the subscription.
+        if next == self.next || self.buffer.range(..=next).next().is_some() {
+            self.mode = Mode::Live;
+        }
+    }
+
+    // Deliver the next event from the subscription, returning whether the subscription is still
+    // open. If the subscription has skipped ahead, switch back to the history to fill the gap.
+    async fn tail(&mut self) -> bool {
+        // Drop buffered events which have already been delivered from history.
+        self.buffer = self.buffer.split_off(&self.next);
+        match self.buffer.keys().next().copied() {
+            Some(position) if position == self.next => {
+                let event = self.buffer.remove(&position).unwrap();
+                self.ready.push_back(Ok(event));
+                return true;
+            }
+            Some(_) if !self.gap_checked => {
+                self.gap_checked = true;
+                self.mode = Mode::Backfill;
+                return true;
+            }
+            _ => {}
+        }
+        match self.live.next().await {
+            Some(Ok(event)) => {
+                self.buffer.insert(event.position(), event);
+                self.gap_checked = false;
+                true
+            }
+            Some(Err(err)) => {
+                self.ready.push_back(Err(err));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::channel::mpsc;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    struct Event(u64);
+
+    impl Positioned for Event {
+        type Position = u64;
+
+        fn position(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[async_std::test]
+    async fn test_backfill_then_tail() {
+        let history = Arc::new(Mutex::new((0..6).map(Event).collect::<Vec<_>>()));
+        let (live_tx, live_rx) = mpsc::unbounded::<Result<Event, String>>();
+        // The subscription starts in the middle of the history, overlapping it.
+        for i in 3..7 {
+            live_tx.unbounded_send(Ok(Event(i))).unwrap();
+        }
+        history.lock().unwrap().push(Event(6));
+        // The history lags behind the subscription, but catches up by the time event 9 arrives.
+        let live = live_rx.inspect({
+            let history = history.clone();
+            move |event| {
+                if event == &Ok(Event(9)) {
+                    history
+                        .lock()
+                        .unwrap()
+                        .extend([Event(7), Event(8), Event(9)]);
+                }
+            }
+        });
+
+        let mut events = backfill_then_tail(
+            1,
+            move |from| {
+                // Pages of at most 2 events.
+                let page = history
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|event| event.0 >= from)
+                    .take(2)
+                    .cloned()
+                    .collect();
+                async move { Ok(page) }
+            },
+            live,
+        );
+        for i in 1..7 {
+            assert_eq!(events.next().await.unwrap().unwrap(), Event(i));
+        }
+
+        // If the subscription skips an event, it is filled in from history.
+        live_tx.unbounded_send(Ok(Event(8))).unwrap();
+        live_tx.unbounded_send(Ok(Event(9))).unwrap();
+        for i in 7..10 {
+            assert_eq!(events.next().await.unwrap().unwrap(), Event(i));
+        }
+
+        // Errors are passed through, and the stream ends with the subscription.
+        live_tx.unbounded_send(Err("reconnecting".into())).unwrap();
+        live_tx.unbounded_send(Ok(Event(10))).unwrap();
+        drop(live_tx);
+        assert_eq!(events.next().await.unwrap().unwrap_err(), "reconnecting");
+        assert_eq!(events.next().await.unwrap().unwrap(), Event(10));
+        assert!(events.next().await.is_none());
+    }
+}
@@ -0,0 +1,249 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Building request URLs from typed path segments.
+//!
+//! Formatting a request path by hand, as in `format!("getaccount/{}", address)`, breaks as soon as
+//! a value contains a character with a meaning in URLs. Base64 identifiers are the usual culprit: a
+//! `/` splits the segment in two, and a `+` may be read as a space. A [Route] is built from one
+//! [PathSegment] at a time instead, and encodes each segment so the server sees exactly one segment
+//! with exactly the value it was given.
+//!
+//! The [url!](crate::client::url!) macro builds a route from a list of segments:
+//!
+//! ```
+//! use net::client::url;
+//!
+//! let route = url!("getblock", 42u64, "memos/pending");
+//! assert_eq!(route.as_str(), "getblock/42/memos%2Fpending");
+//! ```
+//!
+//! A [Route] can be passed anywhere surf expects a path relative to the client's base URL, or
+//! joined to a base URL explicitly with [Route::join].
+
+use http_types::Url;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use std::fmt::{self, Display, Formatter};
+
+// Characters which are encoded in a path segment. This is the WHATWG path segment set, plus
+// characters which some servers and proxies treat specially: `+`, which form decoding reads as a
+// space, and characters which are not allowed unencoded by RFC 3986.
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'+')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b'\\')
+    .add(b']')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+/// A value which can be used as one segment of a URL path.
+///
+/// The segment is the text of the value, before percent-encoding. Strings, integers and booleans
+/// are segments as they are. With the `types` feature, [TaggedBase64](tagged_base64::TaggedBase64)
+/// values are segments in their tagged base64 form, as are other tagged blobs, by way of
+/// [tagged].
+pub trait PathSegment {
+    fn segment(&self) -> String;
+}
+
+impl<T: PathSegment + ?Sized> PathSegment for &T {
+    fn segment(&self) -> String {
+        (**self).segment()
+    }
+}
+
+impl PathSegment for str {
+    fn segment(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl PathSegment for String {
+    fn segment(&self) -> String {
+        self.clone()
+    }
+}
+
+macro_rules! display_segment {
+    ($($t:ty),*) => {
+        $(
+            impl PathSegment for $t {
+                fn segment(&self) -> String {
+                    self.to_string()
+                }
+            }
+        )*
+    };
+}
+
+display_segment!(bool, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+// Tagged base64 segments are always in the canonical, URL-safe form, whatever alphabet the value
+// was parsed from.
+#[cfg(feature = "types")]
+impl PathSegment for tagged_base64::TaggedBase64 {
+    fn segment(&self) -> String {
+        let value = self.to_string();
+        crate::tagged_blob::normalize_tagged_base64(&value).unwrap_or(value)
+    }
+}
+
+/// A path segment for a tagged blob, in tagged base64 form.
+///
+/// This encodes `value` the way it is served, using its tag and canonical serialization, so that
+/// the server can parse the segment with [TaggedBlob](crate::TaggedBlob).
+#[cfg(feature = "types")]
+pub fn tagged<T: jf_utils::Tagged + ark_serialize::CanonicalSerialize>(
+    value: &T,
+) -> tagged_base64::TaggedBase64 {
+    let mut bytes = Vec::new();
+    value
+        .serialize(&mut bytes)
+        .expect("serializing to a Vec cannot fail");
+    tagged_base64::TaggedBase64::new(&T::tag(), &bytes).expect("tags of tagged types are valid")
+}
+
+/// A URL path, relative to the base URL of an API.
+///
+/// Build a route with [url!](crate::client::url!), or with [Route::new] and [Route::push].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Route {
+    path: String,
+}
+
+impl Route {
+    /// An empty route, which refers to the base URL itself.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a segment to the route.
+    ///
+    /// The segment is percent-encoded, so it is always exactly one segment, even if it contains a
+    /// `/`. The segments `.` and `..` are encoded too, so they are not interpreted as references to
+    /// the current or parent directory.
+    pub fn push(mut self, segment: impl PathSegment) -> Self {
+        if !self.path.is_empty() {
+            self.path.push('/');
+        }
+        let segment = segment.segment();
+        if segment == "." || segment == ".." {
+            self.path.push_str(&segment.replace('.', "%2E"));
+        } else {
+            self.path.extend(utf8_percent_encode(&segment, SEGMENT));
+        }
+        self
+    }
+
+    /// The encoded path, with no leading `/`.
+    pub fn as_str(&self) -> &str {
+        &self.path
+    }
+
+    /// The URL of this route under `base`.
+    ///
+    /// Unlike [Url::join], this treats `base` as a directory whether or not it ends with `/`, so
+    /// the last segment of `base` is kept. The query string of `base`, if any, is kept as well.
+    pub fn join(&self, base: &Url) -> Url {
+        let mut url = base.clone();
+        let path = format!("{}/{}", base.path().trim_end_matches('/'), self.path);
+        url.set_path(&path);
+        url
+    }
+}
+
+impl AsRef<str> for Route {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl Display for Route {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path)
+    }
+}
+
+/// Build a [Route](crate::client::url::Route) from a list of path segments.
+///
+/// Each argument is one [PathSegment](crate::client::url::PathSegment), and is percent-encoded on
+/// its own, so `url!("block", id)` has exactly two segments whatever the value of `id`.
+#[macro_export]
+macro_rules! url {
+    ($($segment:expr),+ $(,)?) => {
+        $crate::client::url::Route::new()$(.push($segment))+
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use percent_encoding::percent_decode_str;
+
+    #[test]
+    fn test_route() {
+        // Segments which need no encoding are unchanged.
+        assert_eq!(url!("getblock", 42u64, true).as_str(), "getblock/42/true");
+
+        // Standard base64, which is the recurring cause of broken routes.
+        let id = "ab+c/d==";
+        let route = url!("gettransaction", id);
+        assert_eq!(route.as_str(), "gettransaction/ab%2Bc%2Fd==");
+        let segments = route.as_str().split('/').collect::<Vec<_>>();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(percent_decode_str(segments[1]).decode_utf8().unwrap(), id);
+
+        // Other special characters, and dot segments.
+        assert_eq!(
+            url!("a b", "q?x#y", "50%", "..", ".").as_str(),
+            "a%20b/q%3Fx%23y/50%25/%2E%2E/%2E"
+        );
+        assert_eq!(url!(String::from("é")).as_str(), "%C3%A9");
+    }
+
+    #[test]
+    fn test_join() {
+        let route = url!("getblock", 1u8);
+        for base in ["http://localhost:50000/api", "http://localhost:50000/api/"] {
+            assert_eq!(
+                route.join(&Url::parse(base).unwrap()).as_str(),
+                "http://localhost:50000/api/getblock/1"
+            );
+        }
+        assert_eq!(
+            url!("a+b")
+                .join(&Url::parse("http://localhost/?v=1").unwrap())
+                .as_str(),
+            "http://localhost/a%2Bb?v=1"
+        );
+    }
+
+    #[cfg(feature = "types")]
+    #[test]
+    fn test_tagged() {
+        let blob = tagged_base64::TaggedBase64::new("TX", &[0xfb; 16]).unwrap();
+        let route = url!("gettransaction", &blob);
+        let segment = route.as_str().strip_prefix("gettransaction/").unwrap();
+        assert!(!segment.contains('/'));
+        let decoded = percent_decode_str(segment).decode_utf8().unwrap();
+        assert_eq!(
+            decoded.parse::<tagged_base64::TaggedBase64>().unwrap(),
+            blob
+        );
+    }
+}
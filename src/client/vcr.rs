@@ -0,0 +1,270 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Recording and replay of HTTP interactions, for deterministic tests.
+//!
+//! The [Vcr] middleware runs in one of two modes. When recording, it passes requests through to the
+//! server and saves each request along with the full response (status, content type, and body,
+//! including error bodies) to a fixture file, called a [Cassette]. When replaying, it never touches
+//! the network: each request is answered with the recorded response for an identical request.
+//!
+//! A test against a live service can be made deterministic and offline by recording a cassette
+//! once and committing it. [Vcr::from_env] makes it easy to re-record when the service changes.
+//!
+//! The [Vcr] middleware should be attached to the client after
+//! [parse_error_body](super::parse_error_body), so that it sits closer to the network and records
+//! raw error responses rather than parsed errors.
+
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use surf::middleware::{Middleware, Next};
+use surf::{Client, Request, StatusCode};
+
+/// Environment variable which puts [Vcr::from_env] into recording mode.
+pub const RECORD_ENV_VAR: &str = "NET_VCR_RECORD";
+
+/// The parts of a request which are used to match it against recorded interactions.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub url: String,
+    pub content_type: Option<String>,
+    pub accept: Option<String>,
+    /// The request body, hex-encoded.
+    pub body: String,
+}
+
+/// A recorded response.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub content_type: Option<String>,
+    /// The response body, hex-encoded.
+    pub body: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Interaction {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+/// A sequence of recorded interactions.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+impl Cassette {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(serde_json::to_writer_pretty(file, self)?)
+    }
+}
+
+enum Mode {
+    Record(PathBuf),
+    // In replay mode, we keep track of which interactions have already been used, so that repeated
+    // identical requests get successive responses, in the order they were recorded.
+    Replay(Vec<bool>),
+}
+
+struct State {
+    mode: Mode,
+    cassette: Cassette,
+}
+
+/// Client middleware which records or replays interactions.
+pub struct Vcr {
+    state: Mutex<State>,
+}
+
+impl Vcr {
+    /// Record interactions to a new cassette, which is saved at `path` after each interaction.
+    pub fn record(path: impl Into<PathBuf>) -> Self {
+        Self {
+            state: Mutex::new(State {
+                mode: Mode::Record(path.into()),
+                cassette: Cassette::default(),
+            }),
+        }
+    }
+
+    /// Replay interactions from a cassette.
+    pub fn replay(cassette: Cassette) -> Self {
+        Self {
+            state: Mutex::new(State {
+                mode: Mode::Replay(vec![false; cassette.interactions.len()]),
+                cassette,
+            }),
+        }
+    }
+
+    /// Replay interactions from the cassette saved at `path`.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::replay(Cassette::load(path)?))
+    }
+
+    /// Record to `path` if [RECORD_ENV_VAR] is set, otherwise replay from `path`.
+    pub fn from_env(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        if std::env::var_os(RECORD_ENV_VAR).is_some() {
+            Ok(Self::record(path))
+        } else {
+            Self::load(path)
+        }
+    }
+}
+
+#[surf::utils::async_trait]
+impl Middleware for Vcr {
+    async fn handle(&self, mut req: Request, client: Client, next: Next<'_>) -> surf::Result {
+        // Read the request body so that we can record or match it, then put it back.
+        let req_body = req.take_body().into_bytes().await?;
+        if !req_body.is_empty() {
+            req.set_body(req_body.clone());
+        }
+        let request = RecordedRequest {
+            method: req.method().to_string(),
+            url: req.url().to_string(),
+            // surf assigns a content type even to requests with no body. Ignore it, so that it doesn't
+            // affect matching.
+            content_type: if req_body.is_empty() {
+                None
+            } else {
+                req.header("Content-Type").map(|h| h.as_str().to_string())
+            },
+            accept: req.header("Accept").map(|h| h.as_str().to_string()),
+            body: hex::encode(&req_body),
+        };
+
+        {
+            let mut state = self.state.lock().unwrap();
+            let State { mode, cassette } = &mut *state;
+            if let Mode::Replay(used) = mode {
+                return replay(used, cassette, &request);
+            }
+        }
+
+        let mut res = next.run(req, client).await?;
+        let content_type = res.header("Content-Type").map(|h| h.as_str().to_string());
+        let res_body = res.body_bytes().await?;
+        if !res_body.is_empty() {
+            res.set_body(res_body.clone());
+        }
+        let interaction = Interaction {
+            request,
+            response: RecordedResponse {
+                status: res.status() as u16,
+                content_type,
+                body: hex::encode(&res_body),
+            },
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.cassette.interactions.push(interaction);
+        if let Mode::Record(path) = &state.mode {
+            state.cassette.save(path)?;
+        }
+        Ok(res)
+    }
+}
+
+fn replay(used: &mut [bool], cassette: &Cassette, request: &RecordedRequest) -> surf::Result {
+    for (used, interaction) in used.iter_mut().zip(&cassette.interactions) {
+        if !*used && interaction.request == *request {
+            *used = true;
+            return recorded_response(&interaction.response);
+        }
+    }
+    Err(surf::Error::from_str(
+        StatusCode::InternalServerError,
+        format!(
+            "no recorded interaction for {} {}",
+            request.method, request.url
+        ),
+    ))
+}
+
+fn recorded_response(recorded: &RecordedResponse) -> surf::Result {
+    let mut res = surf::http::Response::new(StatusCode::try_from(recorded.status)?);
+    let body = hex::decode(&recorded.body)?;
+    if !body.is_empty() {
+        res.set_body(body);
+    }
+    match &recorded.content_type {
+        Some(content_type) => {
+            res.insert_header("Content-Type", content_type.as_str());
+        }
+        None => {
+            res.remove_header("Content-Type");
+        }
+    }
+    Ok(res.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::{parse_error_body, response_body};
+    use crate::test_util::Error;
+
+    fn interaction(url: &str, status: u16, body: &[u8]) -> Interaction {
+        Interaction {
+            request: RecordedRequest {
+                method: "GET".to_string(),
+                url: url.to_string(),
+                content_type: None,
+                accept: None,
+                body: String::new(),
+            },
+            response: RecordedResponse {
+                status,
+                content_type: Some("application/json".to_string()),
+                body: hex::encode(body),
+            },
+        }
+    }
+
+    #[async_std::test]
+    async fn test_replay() {
+        let err = Error {
+            msg: "This is an error message".to_string(),
+        };
+        let cassette = Cassette {
+            interactions: vec![
+                interaction("http://localhost/ok", 200, b"42"),
+                interaction(
+                    "http://localhost/err",
+                    500,
+                    &serde_json::to_vec(&err).unwrap(),
+                ),
+            ],
+        };
+        let client = Client::new()
+            .with(parse_error_body::<Error>)
+            .with(Vcr::replay(cassette));
+
+        let mut res = client.get("http://localhost/ok").await.unwrap();
+        assert_eq!(42, response_body::<u64>(&mut res).await.unwrap());
+
+        let res = client.get("http://localhost/err").await.unwrap_err();
+        assert_eq!(err, res.downcast().unwrap());
+
+        // Each interaction can only be replayed once.
+        client.get("http://localhost/ok").await.unwrap_err();
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Sharing the server's clock with clients.
+//!
+//! Anything a client timestamps for a server to check (a signed request, for instance) is only
+//! accepted if it is [within](crate::Timestamp::within) some tolerance of the server's clock. A
+//! client whose clock is off by more than that can find out by how much from any response: the
+//! [server_time] middleware adds a [SERVER_TIME] header with the server's current time, and
+//! [clock_skew] compares it to the local clock.
+
+use crate::types::Timestamp;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+use futures::future::BoxFuture;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+use tide::{Next, Request};
+
+pub use crate::headers::SERVER_TIME;
+
+/// Server middleware which adds a [SERVER_TIME] header to every response.
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub fn server_time<'a, State: Clone + Send + Sync + 'static>(
+    req: Request<State>,
+    next: Next<'a, State>,
+) -> BoxFuture<'a, tide::Result> {
+    Box::pin(async {
+        let mut res = next.run(req).await;
+        res.insert_header(SERVER_TIME, Timestamp::now().to_string());
+        Ok(res)
+    })
+}
+
+/// The time in the [SERVER_TIME] header of a response, if there is a valid one.
+#[cfg(feature = "client")]
+pub fn response_time(res: &surf::Response) -> Option<Timestamp> {
+    res.header(SERVER_TIME)?.as_str().parse().ok()
+}
+
+/// Seconds from the local clock to the server's clock, according to a response.
+///
+/// The result is positive if the server's clock is ahead. It includes the time the response spent
+/// in flight, so it is only accurate to within the latency of the request.
+#[cfg(feature = "client")]
+pub fn clock_skew(res: &surf::Response) -> Option<i64> {
+    Some(response_time(res)?.skew_from(Timestamp::now()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wire::binary;
+    use std::time::Duration;
+
+    #[test]
+    fn test_timestamp() {
+        let t = Timestamp(1654084800);
+        assert_eq!(t.to_string(), "2022-06-01T12:00:00Z");
+        assert_eq!(
+            serde_json::to_string(&t).unwrap(),
+            "\"2022-06-01T12:00:00Z\""
+        );
+        assert_eq!(binary::serialize(&t).unwrap(), 1654084800i64.to_le_bytes());
+        assert_eq!(
+            binary::deserialize::<Timestamp>(&binary::serialize(&t).unwrap()).unwrap(),
+            t
+        );
+
+        // Any UTC offset is accepted.
+        let parsed: Timestamp = serde_json::from_str("\"2022-06-01T14:00:00+02:00\"").unwrap();
+        assert_eq!(parsed, t);
+        assert!(serde_json::from_str::<Timestamp>("\"yesterday\"").is_err());
+
+        let tolerance = Duration::from_secs(30);
+        assert!(t.within_of(Timestamp(t.unix() + 30), tolerance));
+        assert!(t.within_of(Timestamp(t.unix() - 30), tolerance));
+        assert!(!t.within_of(Timestamp(t.unix() + 31), tolerance));
+        assert!(!t.within(tolerance));
+        assert!(Timestamp::now().within(tolerance));
+    }
+
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[async_std::test]
+    async fn test_server_time() {
+        use tide::http::{Method, Url};
+
+        let mut app = tide::new();
+        app.with(server_time);
+        app.at("/").get(|_| async { Ok("") });
+        let req = tide::http::Request::new(Method::Get, Url::parse("http://localhost/").unwrap());
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        let res: surf::Response = res.into();
+        assert!(response_time(&res).unwrap().within(Duration::from_secs(5)));
+        assert!(clock_skew(&res).unwrap().abs() <= 5);
+    }
+}
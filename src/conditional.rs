@@ -0,0 +1,169 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Conditional requests based on ledger height.
+//!
+//! Much of the state served by Espresso services (balances, record sets, snapshots of the ledger)
+//! only changes when a new block is committed. Rather than using timestamps as validators, like
+//! `Last-Modified` and `If-Modified-Since`, these helpers use the height of the ledger at which a
+//! response was computed:
+//!  * the server tags each response with a [LEDGER_HEIGHT] header, meaning "valid as of block N"
+//!  * the client remembers the height and later sends it in an [IF_LEDGER_HEIGHT_GT] header,
+//!    meaning "only send the response if the ledger has grown past block N"
+//!  * if the ledger has not grown, the server responds with 304 Not Modified and an empty body,
+//!    and the client keeps using its copy
+//!
+//! On the server, use [respond_at_height] in place of [response](crate::server::response). On the
+//! client, add the condition with [if_newer_than] and read the result with
+//! [response_body_if_modified].
+
+#[cfg(feature = "client")]
+use crate::client::response_body;
+#[cfg(any(
+    feature = "client",
+    all(feature = "server", not(target_arch = "wasm32"))
+))]
+use crate::headers::{IfLedgerHeightGt, LedgerHeight, TypedHeader};
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+use crate::redact::SafeToServe;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+use crate::server::response;
+use http_types::StatusCode;
+use serde::{Deserialize, Serialize};
+
+pub use crate::headers::{IF_LEDGER_HEIGHT_GT, LEDGER_HEIGHT};
+
+/// The height in the [IF_LEDGER_HEIGHT_GT] header of a request, if there is one.
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub fn if_ledger_height_gt<S>(req: &tide::Request<S>) -> Result<Option<u64>, tide::Error> {
+    Ok(IfLedgerHeightGt::get(req)?.map(|IfLedgerHeightGt(height)| height))
+}
+
+/// Respond with `body`, which is valid as of ledger height `height`.
+///
+/// If the request has an [IF_LEDGER_HEIGHT_GT] header with a height at least `height`, the client
+/// already has an up-to-date copy, and the response is 304 Not Modified with an empty body.
+/// Otherwise, `body` is serialized as by [response](crate::server::response). Either way, the
+/// response has a [LEDGER_HEIGHT] header.
+///
+/// If computing the body is expensive, check [if_ledger_height_gt] first, and use
+/// [not_modified] to skip the computation.
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub fn respond_at_height<T: Serialize + SafeToServe, S>(
+    req: &tide::Request<S>,
+    height: u64,
+    body: T,
+) -> Result<tide::Response, tide::Error> {
+    if is_fresh(req, height)? {
+        return Ok(not_modified(height));
+    }
+    let mut res = response(req, body)?;
+    LedgerHeight(height).insert(&mut res);
+    Ok(res)
+}
+
+/// Whether the client's copy of a resource valid as of `height` is still up to date.
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub fn is_fresh<S>(req: &tide::Request<S>, height: u64) -> Result<bool, tide::Error> {
+    Ok(matches!(if_ledger_height_gt(req)?, Some(known) if known >= height))
+}
+
+/// A 304 Not Modified response for a resource valid as of `height`.
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub fn not_modified(height: u64) -> tide::Response {
+    let mut res = tide::Response::new(StatusCode::NotModified);
+    LedgerHeight(height).insert(&mut res);
+    res
+}
+
+/// Only fetch a resource if it has changed since ledger height `height`.
+#[cfg(feature = "client")]
+pub fn if_newer_than(req: surf::RequestBuilder, height: u64) -> surf::RequestBuilder {
+    req.header(IF_LEDGER_HEIGHT_GT, IfLedgerHeightGt(height).encode())
+}
+
+/// The height in the [LEDGER_HEIGHT] header of a response, if there is one.
+#[cfg(feature = "client")]
+pub fn ledger_height(res: &surf::Response) -> Result<Option<u64>, surf::Error> {
+    LedgerHeight::get(res)
+        .map(|height| height.map(|LedgerHeight(height)| height))
+        .map_err(|err| surf::Error::from_str(StatusCode::InternalServerError, err.to_string()))
+}
+
+/// A response to a conditional request.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Conditional<T> {
+    /// The resource has not changed since the height the client sent.
+    NotModified,
+    /// The resource has changed; this is the new version, valid as of `height`.
+    Modified { height: Option<u64>, body: T },
+}
+
+/// Deserialize the response to a conditional request.
+#[cfg(feature = "client")]
+pub async fn response_body_if_modified<T: for<'de> Deserialize<'de>>(
+    res: &mut surf::Response,
+) -> Result<Conditional<T>, surf::Error> {
+    if res.status() == StatusCode::NotModified {
+        return Ok(Conditional::NotModified);
+    }
+    let height = ledger_height(res)?;
+    let body = response_body(res).await?;
+    Ok(Conditional::Modified { height, body })
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod test {
+    use super::*;
+    use crate::redact::Public;
+    use tide::http::{Method, Request, Url};
+
+    async fn get(server: &tide::Server<()>, known: Option<u64>) -> surf::Response {
+        let mut req = Request::new(Method::Get, Url::parse("http://localhost/state").unwrap());
+        req.insert_header("Accept", "application/json");
+        if let Some(known) = known {
+            req.insert_header(IF_LEDGER_HEIGHT_GT, known.to_string());
+        }
+        let res: tide::http::Response = server.respond(req).await.unwrap();
+        res.into()
+    }
+
+    #[async_std::test]
+    async fn test_conditional() {
+        let mut server = tide::new();
+        server
+            .at("/state")
+            .get(|req: tide::Request<()>| async move {
+                respond_at_height(&req, 10, Public("state"))
+            });
+
+        // An unconditional request gets the body and the height.
+        let mut res = get(&server, None).await;
+        assert_eq!(
+            response_body_if_modified::<String>(&mut res).await.unwrap(),
+            Conditional::Modified {
+                height: Some(10),
+                body: "state".to_string()
+            }
+        );
+
+        // A client with an older copy gets the new body.
+        let mut res = get(&server, Some(9)).await;
+        assert!(matches!(
+            response_body_if_modified::<String>(&mut res).await.unwrap(),
+            Conditional::Modified { .. }
+        ));
+
+        // A client with an up-to-date copy does not.
+        let mut res = get(&server, Some(10)).await;
+        assert_eq!(res.status(), StatusCode::NotModified);
+        assert_eq!(
+            response_body_if_modified::<String>(&mut res).await.unwrap(),
+            Conditional::NotModified
+        );
+    }
+}
@@ -0,0 +1,495 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Standard configuration for services.
+//!
+//! Every service needs to know where to listen, how it is reached from outside, and how much load
+//! to accept. [ServiceConfig] holds these settings, and [Loader] reads them the same way for every
+//! service, from three sources, in increasing order of precedence:
+//!  1. a TOML file
+//!  2. environment variables
+//!  3. command line flags
+//!
+//! A setting from a later source overrides the same setting from an earlier one, and settings which
+//! appear in none of them have the defaults of [ServiceConfig::default]. Each setting has the same
+//! name in every source, in the style of that source:
+//!
+//! | Setting            | TOML               | Environment               | Flag                 |
+//! |--------------------|--------------------|---------------------------|----------------------|
+//! | listen address     | `bind`             | `<PREFIX>_BIND`           | `--bind`             |
+//! | external URL       | `external_url`     | `<PREFIX>_EXTERNAL_URL`   | `--external-url`     |
+//! | concurrency limit  | `max_in_flight`    | `<PREFIX>_MAX_IN_FLIGHT`  | `--max-in-flight`    |
+//! | requests per second| `rate_limit`       | `<PREFIX>_RATE_LIMIT`     | `--rate-limit`       |
+//! | burst size         | `rate_limit_burst` | `<PREFIX>_RATE_LIMIT_BURST` | `--rate-limit-burst` |
+//!
+//! `<PREFIX>` is chosen by the service, such as `ESPRESSO_WALLET`. The TOML file is the one named by
+//! the `--config` flag, or else by `<PREFIX>_CONFIG`, or else by [Loader::file]. Flags are written
+//! as `--name value` or `--name=value`.
+//!
+//! There are no TLS settings: services are served over plain HTTP, and a service which needs TLS
+//! runs behind a terminating proxy, with [external_url](ServiceConfig::external_url) set to the
+//! proxy's URL. Nor is there a log level, since logging is set up by each service's own tracing
+//! subscriber, which reads its filter from the environment in whatever way the service chooses.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! use net::config::ServiceConfig;
+//!
+//! let config = ServiceConfig::load("ESPRESSO_WALLET")?;
+//! let app = tide::new();
+//! net::server::serve(app, &config).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use http_types::Url;
+use snafu::{OptionExt, ResultExt, Snafu};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use toml_edit::{Document, Value};
+
+/// The settings every service is configured with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceConfig {
+    /// The address to listen on.
+    pub bind: SocketAddr,
+    /// The URL at which clients reach the service, if it is not the listen address; for instance,
+    /// when the service is behind a proxy.
+    pub external_url: Option<Url>,
+    /// The maximum number of requests to handle at once. See
+    /// [concurrency_limit](crate::server::concurrency_limit).
+    pub max_in_flight: Option<usize>,
+    /// The maximum average number of requests per second. See
+    /// [rate_limit](crate::server::rate_limit()).
+    pub rate_limit: Option<f64>,
+    /// The maximum number of requests in a burst, when there is a rate limit.
+    ///
+    /// If this is not set, it is the rate limit, rounded up.
+    pub rate_limit_burst: Option<u32>,
+}
+
+impl Default for ServiceConfig {
+    /// Listen on port 8080 of every interface, with no limits.
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            external_url: None,
+            max_in_flight: None,
+            rate_limit: None,
+            rate_limit_burst: None,
+        }
+    }
+}
+
+impl ServiceConfig {
+    /// Load the configuration of the current process.
+    ///
+    /// This reads the command line, the environment variables starting with `prefix`, and the
+    /// TOML file they name, if any.
+    pub fn load(prefix: impl Into<String>) -> Result<Self, ConfigError> {
+        Loader::new(prefix)
+            .env(std::env::vars())
+            .args(std::env::args().skip(1))
+            .load()
+    }
+
+    /// The URL at which clients reach the service.
+    ///
+    /// This is [external_url](Self::external_url) if it is set. Otherwise it is derived from the
+    /// listen address, using `localhost` if the service listens on every interface.
+    pub fn url(&self) -> Url {
+        if let Some(url) = &self.external_url {
+            return url.clone();
+        }
+        let host = if self.bind.ip().is_unspecified() {
+            "localhost".to_string()
+        } else {
+            match self.bind {
+                SocketAddr::V4(addr) => addr.ip().to_string(),
+                SocketAddr::V6(addr) => format!("[{}]", addr.ip()),
+            }
+        };
+        Url::parse(&format!("http://{}:{}/", host, self.bind.port())).unwrap()
+    }
+
+    /// The rate limit and burst size, if there is a rate limit.
+    pub fn rate_limit(&self) -> Option<(f64, u32)> {
+        let per_second = self.rate_limit?;
+        let burst = self
+            .rate_limit_burst
+            .unwrap_or_else(|| per_second.ceil().max(1.0) as u32);
+        Some((per_second, burst))
+    }
+}
+
+/// Reasons a [ServiceConfig] cannot be loaded.
+#[derive(Debug, Snafu)]
+pub enum ConfigError {
+    #[snafu(display("failed to read config file {}: {}", path.display(), source))]
+    ReadFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("malformed config file {}: {}", path.display(), reason))]
+    MalformedFile { path: PathBuf, reason: String },
+    #[snafu(display("unknown setting {} in {}", name, origin))]
+    UnknownSetting { name: String, origin: String },
+    #[snafu(display("{} requires a value", flag))]
+    MissingValue { flag: String },
+    #[snafu(display("invalid {} {:?} from {}: {}", name, value, origin, reason))]
+    InvalidValue {
+        name: String,
+        value: String,
+        origin: String,
+        reason: String,
+    },
+}
+
+// The name of each setting, as it is written in TOML.
+const SETTINGS: [&str; 5] = [
+    "bind",
+    "external_url",
+    "max_in_flight",
+    "rate_limit",
+    "rate_limit_burst",
+];
+
+// A setting's value, and the source it came from, for error messages.
+type Sourced = (String, String);
+type Settings = Vec<(&'static str, Sourced)>;
+
+/// Reads a [ServiceConfig] from a TOML file, environment variables and command line flags.
+///
+/// [ServiceConfig::load] is a loader for the current process. Constructing a loader directly allows
+/// the environment and flags to be supplied explicitly, as in tests, or a default config file to be
+/// used. See the [module documentation](self) for how the sources are combined.
+#[derive(Clone, Debug)]
+pub struct Loader {
+    prefix: String,
+    file: Option<PathBuf>,
+    env: Vec<(String, String)>,
+    args: Vec<String>,
+}
+
+impl Loader {
+    /// A loader which reads environment variables starting with `prefix` followed by `_`.
+    ///
+    /// Initially, the environment and the command line are empty.
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            file: None,
+            env: Vec::new(),
+            args: Vec::new(),
+        }
+    }
+
+    /// Read `path` if no config file is named by a flag or environment variable.
+    ///
+    /// Unlike a file which is named explicitly, this file does not have to exist.
+    pub fn file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.file = Some(path.into());
+        self
+    }
+
+    /// Use `vars` as the environment.
+    ///
+    /// Variables which do not start with the prefix are ignored, as are variables with the prefix
+    /// which are not settings, since the service may have settings of its own.
+    pub fn env(mut self, vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.env = vars.into_iter().collect();
+        self
+    }
+
+    /// Use `args` as the command line flags, not including the name of the program.
+    ///
+    /// Every flag must be a setting or `--config`.
+    pub fn args(mut self, args: impl IntoIterator<Item = String>) -> Self {
+        self.args = args.into_iter().collect();
+        self
+    }
+
+    /// The config file this loader reads, if any.
+    ///
+    /// The file may not exist, if it was given by [file](Self::file).
+    pub fn path(&self) -> Option<PathBuf> {
+        let (config_flag, _) = self.flags().ok()?;
+        self.config_file(config_flag).map(|(path, _)| path)
+    }
+
+    /// Combine the settings from every source.
+    pub fn load(self) -> Result<ServiceConfig, ConfigError> {
+        let (config_flag, flags) = self.flags()?;
+
+        // Later sources overwrite earlier ones.
+        let mut settings = HashMap::new();
+        if let Some((path, required)) = self.config_file(config_flag) {
+            if required || path.exists() {
+                settings.extend(read_file(&path)?);
+            }
+        }
+        settings.extend(self.vars());
+        settings.extend(flags);
+        build(settings)
+    }
+
+    // The config file, named by the `--config` flag, the environment or [file](Self::file), and
+    // whether it was named explicitly, and so must exist.
+    fn config_file(&self, config_flag: Option<String>) -> Option<(PathBuf, bool)> {
+        let config_var = format!("{}_CONFIG", self.prefix);
+        match config_flag {
+            Some(path) => Some((PathBuf::from(path), true)),
+            None => match self.env.iter().find(|(name, _)| *name == config_var) {
+                Some((_, path)) => Some((PathBuf::from(path), true)),
+                None => self.file.clone().map(|path| (path, false)),
+            },
+        }
+    }
+
+    // The settings in the environment, with the names of the variables they came from.
+    fn vars(&self) -> Settings {
+        SETTINGS
+            .iter()
+            .filter_map(|name| {
+                let var = format!("{}_{}", self.prefix, name.to_uppercase());
+                let (_, value) = self.env.iter().find(|(name, _)| *name == var)?;
+                Some((*name, (value.clone(), var)))
+            })
+            .collect()
+    }
+
+    // The `--config` flag, if given, and the settings on the command line.
+    fn flags(&self) -> Result<(Option<String>, Settings), ConfigError> {
+        let mut config = None;
+        let mut settings = Vec::new();
+        let mut args = self.args.iter();
+        while let Some(arg) = args.next() {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), value.to_string()),
+                None => {
+                    let value = args.next().context(MissingValueSnafu { flag: arg })?;
+                    (arg.clone(), value.clone())
+                }
+            };
+            let name = flag
+                .strip_prefix("--")
+                .map(|name| name.replace('-', "_"))
+                .unwrap_or_default();
+            if name == "config" {
+                config = Some(value);
+            } else {
+                let name = setting(&name).context(UnknownSettingSnafu {
+                    name: &flag,
+                    origin: "the command line",
+                })?;
+                settings.push((name, (value, flag)));
+            }
+        }
+        Ok((config, settings))
+    }
+}
+
+fn setting(name: &str) -> Option<&'static str> {
+    SETTINGS.iter().find(|setting| **setting == name).copied()
+}
+
+// The settings in a TOML file, as strings, with the name of the file they came from.
+fn read_file(path: &Path) -> Result<Settings, ConfigError> {
+    let text = fs::read_to_string(path).context(ReadFileSnafu { path })?;
+    let doc: Document<String> = text.parse().map_err(|err| ConfigError::MalformedFile {
+        path: path.to_path_buf(),
+        reason: format!("{}", err),
+    })?;
+    let origin = path.display().to_string();
+    doc.as_table()
+        .iter()
+        .map(|(key, item)| {
+            let name = setting(key).context(UnknownSettingSnafu {
+                name: key,
+                origin: &origin,
+            })?;
+            let value = match item.as_value() {
+                Some(Value::String(s)) => s.value().clone(),
+                Some(Value::Integer(n)) => n.value().to_string(),
+                Some(Value::Float(x)) => x.value().to_string(),
+                _ => {
+                    return Err(ConfigError::InvalidValue {
+                        name: key.to_string(),
+                        value: item.type_name().to_string(),
+                        origin: origin.clone(),
+                        reason: "expected a string or a number".to_string(),
+                    })
+                }
+            };
+            Ok((name, (value, origin.clone())))
+        })
+        .collect()
+}
+
+// Parse the value of `name`, if it was given.
+fn parse<T: FromStr>(
+    settings: &HashMap<&'static str, Sourced>,
+    name: &str,
+) -> Result<Option<T>, ConfigError>
+where
+    T::Err: Display,
+{
+    settings
+        .get(name)
+        .map(|(value, origin)| {
+            value
+                .parse()
+                .map_err(|err: T::Err| ConfigError::InvalidValue {
+                    name: name.to_string(),
+                    value: value.clone(),
+                    origin: origin.clone(),
+                    reason: err.to_string(),
+                })
+        })
+        .transpose()
+}
+
+fn build(settings: HashMap<&'static str, Sourced>) -> Result<ServiceConfig, ConfigError> {
+    let default = ServiceConfig::default();
+    let rate_limit = parse::<f64>(&settings, "rate_limit")?;
+    if let Some(rate) = rate_limit {
+        if !(rate > 0.0 && rate.is_finite()) {
+            let (value, origin) = &settings["rate_limit"];
+            return Err(ConfigError::InvalidValue {
+                name: "rate_limit".to_string(),
+                value: value.clone(),
+                origin: origin.clone(),
+                reason: "must be a positive number".to_string(),
+            });
+        }
+    }
+    let rate_limit_burst = parse::<u32>(&settings, "rate_limit_burst")?;
+    if rate_limit_burst == Some(0) {
+        let (value, origin) = &settings["rate_limit_burst"];
+        return Err(ConfigError::InvalidValue {
+            name: "rate_limit_burst".to_string(),
+            value: value.clone(),
+            origin: origin.clone(),
+            reason: "must be at least 1".to_string(),
+        });
+    }
+    Ok(ServiceConfig {
+        bind: parse(&settings, "bind")?.unwrap_or(default.bind),
+        external_url: parse(&settings, "external_url")?,
+        max_in_flight: parse(&settings, "max_in_flight")?,
+        rate_limit,
+        rate_limit_burst,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_precedence() {
+        let dir = std::env::temp_dir().join(format!("net-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("service.toml");
+        fs::write(
+            &file,
+            "bind = \"127.0.0.1:9000\"\nmax_in_flight = 64\nrate_limit = 10\n",
+        )
+        .unwrap();
+
+        // Without any sources, the defaults are used.
+        assert_eq!(Loader::new("SVC").load().unwrap(), ServiceConfig::default());
+        // A default config file which does not exist is ignored.
+        assert_eq!(
+            Loader::new("SVC")
+                .file(dir.join("missing.toml"))
+                .load()
+                .unwrap(),
+            ServiceConfig::default()
+        );
+
+        // The environment overrides the file, and flags override the environment.
+        let config = Loader::new("SVC")
+            .file(&file)
+            .env(env(&[
+                ("SVC_BIND", "127.0.0.1:9001"),
+                ("SVC_MAX_IN_FLIGHT", "32"),
+                ("SVC_DATABASE", "ignored"),
+                ("OTHER_BIND", "ignored"),
+            ]))
+            .args(args(&[
+                "--max-in-flight",
+                "16",
+                "--external-url=https://svc.example.com/",
+            ]))
+            .load()
+            .unwrap();
+        assert_eq!(config.bind, "127.0.0.1:9001".parse().unwrap());
+        assert_eq!(config.max_in_flight, Some(16));
+        assert_eq!(config.rate_limit(), Some((10.0, 10)));
+        assert_eq!(config.url().as_str(), "https://svc.example.com/");
+
+        // The config file can be named by a variable or a flag.
+        let file_name = file.to_str().unwrap();
+        let config = Loader::new("SVC")
+            .env(env(&[("SVC_CONFIG", file_name)]))
+            .load()
+            .unwrap();
+        assert_eq!(config.url().as_str(), "http://127.0.0.1:9000/");
+        let config = Loader::new("SVC")
+            .args(args(&["--config", file_name]))
+            .load()
+            .unwrap();
+        assert_eq!(config.max_in_flight, Some(64));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        let load = |vars: &[(&str, &str)], flags: &[&str]| {
+            Loader::new("SVC").env(env(vars)).args(args(flags)).load()
+        };
+        assert!(matches!(
+            load(&[], &["--port", "80"]),
+            Err(ConfigError::UnknownSetting { .. })
+        ));
+        assert!(matches!(
+            load(&[], &["--bind"]),
+            Err(ConfigError::MissingValue { .. })
+        ));
+        let err = load(&[("SVC_BIND", "localhost")], &[]).unwrap_err();
+        assert!(err.to_string().contains("SVC_BIND"), "{}", err);
+        // TLS is terminated at a proxy, so there are no TLS settings.
+        assert!(matches!(
+            load(&[], &["--tls-cert", "cert.pem"]),
+            Err(ConfigError::UnknownSetting { .. })
+        ));
+        assert!(matches!(
+            load(&[], &["--rate-limit=0"]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            load(&[("SVC_CONFIG", "/nonexistent/service.toml")], &[]),
+            Err(ConfigError::ReadFile { .. })
+        ));
+    }
+}
@@ -0,0 +1,330 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Read-your-writes consistency across replicated query nodes.
+//!
+//! Replicas of a query service follow the ledger independently, so a wallet which posts a memo to
+//! one replica and immediately queries for it may be routed to another replica which has not seen
+//! the block containing it yet. To avoid this, the height of the ledger is used as a consistency
+//! token:
+//!  * a mutating endpoint responds with a [LEDGER_HEIGHT] header giving the height at which its
+//!    effect is visible
+//!  * later reads send the highest such height in a [MIN_LEDGER_HEIGHT] header
+//!  * the [Consistency] server middleware holds a read until the replica has caught up to that
+//!    height, and if it does not catch up in time, redirects the read to a replica which has (such
+//!    as the primary), or fails it with 503 Service Unavailable
+//!
+//! On the client, the [ReadYourWrites] middleware keeps the token: it records the height from the
+//! response to each successful write, and sends it with each read.
+//!
+//! ```
+//! use net::consistency::Consistency;
+//! # use std::sync::atomic::{AtomicU64, Ordering};
+//! # use std::sync::Arc;
+//! # use std::time::Duration;
+//!
+//! let height = Arc::new(AtomicU64::new(0));
+//! let mut app = tide::new();
+//! app.with(
+//!     Consistency::new(move || height.load(Ordering::SeqCst))
+//!         .max_wait(Duration::from_secs(2))
+//!         .redirect_to("http://primary.example.com/".parse().unwrap()),
+//! );
+//! ```
+//!
+//! Reads without a token are served immediately, whatever the height of the replica.
+
+pub use crate::headers::{LEDGER_HEIGHT, MIN_LEDGER_HEIGHT};
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub use server::*;
+
+#[cfg(feature = "client")]
+pub use client::*;
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+mod server {
+    use crate::deadline::Deadline;
+    use crate::headers::{LedgerHeight, MinLedgerHeight, TypedHeader};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tide::http::Url;
+    use tide::{Middleware, Next, Request, Response, StatusCode};
+
+    type Height = Arc<dyn Fn() -> u64 + Send + Sync>;
+
+    /// Server middleware which holds reads until the replica has caught up to their token.
+    ///
+    /// See the [module documentation](self).
+    #[derive(Clone)]
+    pub struct Consistency {
+        height: Height,
+        max_wait: Duration,
+        poll_interval: Duration,
+        redirect: Option<Url>,
+    }
+
+    impl Consistency {
+        /// Hold reads until `height`, the current ledger height of this replica, reaches their
+        /// token.
+        ///
+        /// By default, reads wait for at most 1 second, and then fail.
+        pub fn new(height: impl Fn() -> u64 + Send + Sync + 'static) -> Self {
+            Self {
+                height: Arc::new(height),
+                max_wait: Duration::from_secs(1),
+                poll_interval: Duration::from_millis(20),
+                redirect: None,
+            }
+        }
+
+        /// Wait for at most `max_wait` for this replica to catch up.
+        ///
+        /// If the request has a [Deadline], it waits no longer than the deadline allows.
+        pub fn max_wait(mut self, max_wait: Duration) -> Self {
+            self.max_wait = max_wait;
+            self
+        }
+
+        /// Check the height of this replica every `interval` while waiting.
+        pub fn poll_interval(mut self, interval: Duration) -> Self {
+            self.poll_interval = interval;
+            self
+        }
+
+        /// Redirect reads which this replica cannot serve in time to the same path under
+        /// `base_url`.
+        ///
+        /// The replica at `base_url` should always be caught up, like the primary, or at least
+        /// not redirect back to this one.
+        pub fn redirect_to(mut self, base_url: Url) -> Self {
+            self.redirect = Some(base_url);
+            self
+        }
+
+        // Wait until the height reaches `min`, or `max_wait` passes. Returns the last height seen.
+        async fn wait_for(&self, min: u64, max_wait: Duration) -> u64 {
+            let start = Instant::now();
+            loop {
+                let height = (self.height)();
+                let waited = start.elapsed();
+                if height >= min || waited >= max_wait {
+                    return height;
+                }
+                async_std::task::sleep(self.poll_interval.min(max_wait - waited)).await;
+            }
+        }
+
+        fn redirect(&self, url: &Url) -> Option<Response> {
+            let mut location = self
+                .redirect
+                .as_ref()?
+                .join(url.path().trim_start_matches('/'))
+                .ok()?;
+            location.set_query(url.query());
+            let mut res = Response::new(StatusCode::TemporaryRedirect);
+            res.insert_header("Location", location.as_str());
+            Some(res)
+        }
+    }
+
+    #[tide::utils::async_trait]
+    impl<State: Clone + Send + Sync + 'static> Middleware<State> for Consistency {
+        async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+            let min = match MinLedgerHeight::get(&req)? {
+                Some(MinLedgerHeight(min)) => min,
+                None => return Ok(next.run(req).await),
+            };
+            let max_wait = match req.ext::<Deadline>() {
+                Some(deadline) => self.max_wait.min(deadline.remaining()),
+                None => self.max_wait,
+            };
+            let height = self.wait_for(min, max_wait).await;
+            if height >= min {
+                return Ok(next.run(req).await);
+            }
+
+            let mut res = match self.redirect(req.url()) {
+                Some(res) => res,
+                None => {
+                    let mut res = Response::new(StatusCode::ServiceUnavailable);
+                    res.set_error(tide::Error::from_str(
+                        StatusCode::ServiceUnavailable,
+                        format!(
+                            "replica is at ledger height {}, behind the requested height {}",
+                            height, min
+                        ),
+                    ));
+                    res.insert_header("Retry-After", "1");
+                    res
+                }
+            };
+            LedgerHeight(height).insert(&mut res);
+            Ok(res)
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+mod client {
+    use crate::headers::{LedgerHeight, MinLedgerHeight, TypedHeader};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use surf::http::Method;
+    use surf::middleware::{Middleware, Next};
+    use surf::{Client, Request};
+
+    /// Client middleware which keeps a consistency token, so that reads see earlier writes.
+    ///
+    /// Clones share the same token, so the same middleware can be added to the clients of several
+    /// services which follow the same ledger. See the [module documentation](self).
+    #[derive(Clone, Debug, Default)]
+    pub struct ReadYourWrites {
+        // The highest ledger height seen in a response to a write, or 0 if there has been none.
+        token: Arc<AtomicU64>,
+    }
+
+    impl ReadYourWrites {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// The current consistency token, if any writes have been observed.
+        pub fn token(&self) -> Option<u64> {
+            match self.token.load(Ordering::SeqCst) {
+                0 => None,
+                height => Some(height),
+            }
+        }
+
+        /// Make later reads wait for ledger height `height`, for example after a write made
+        /// through another client.
+        pub fn observe(&self, height: u64) {
+            self.token.fetch_max(height, Ordering::SeqCst);
+        }
+    }
+
+    #[surf::utils::async_trait]
+    impl Middleware for ReadYourWrites {
+        async fn handle(&self, mut req: Request, client: Client, next: Next<'_>) -> surf::Result {
+            let is_read = matches!(req.method(), Method::Get | Method::Head);
+            if is_read && req.header(MinLedgerHeight::NAME).is_none() {
+                if let Some(token) = self.token() {
+                    MinLedgerHeight(token).insert(&mut req);
+                }
+            }
+            let res = next.run(req, client).await?;
+            if !is_read && res.status().is_success() {
+                if let Ok(Some(LedgerHeight(height))) = LedgerHeight::get(&res) {
+                    self.observe(height);
+                }
+            }
+            Ok(res)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod test {
+    use super::*;
+    use crate::headers::{LedgerHeight, MinLedgerHeight, TypedHeader};
+    use crate::test_util::Server;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use surf::{Client, StatusCode};
+    use tide::http::{Method, Url};
+
+    fn replica(height: Arc<AtomicU64>, consistency: Consistency) -> tide::Server<()> {
+        let mut app = tide::new();
+        app.with(consistency);
+        app.at("/memo").get(move |_| {
+            let height = height.clone();
+            async move { Ok(height.load(Ordering::SeqCst).to_string()) }
+        });
+        app
+    }
+
+    async fn get(app: &tide::Server<()>, min: u64) -> tide::http::Response {
+        let mut req = tide::http::Request::new(
+            Method::Get,
+            Url::parse("http://localhost/memo?id=1").unwrap(),
+        );
+        MinLedgerHeight(min).insert(&mut req);
+        app.respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_consistency() {
+        let height = Arc::new(AtomicU64::new(3));
+        let consistency = Consistency::new({
+            let height = height.clone();
+            move || height.load(Ordering::SeqCst)
+        });
+
+        // A replica which is behind fails the read, or redirects it.
+        let app = replica(height.clone(), consistency.clone().max_wait(Duration::ZERO));
+        let res = get(&app, 5).await;
+        assert_eq!(res.status(), StatusCode::ServiceUnavailable);
+        assert_eq!(LedgerHeight::get(&res).unwrap(), Some(LedgerHeight(3)));
+        let app = replica(
+            height.clone(),
+            consistency
+                .clone()
+                .max_wait(Duration::ZERO)
+                .redirect_to("http://primary/api/".parse().unwrap()),
+        );
+        let res = get(&app, 5).await;
+        assert_eq!(res.status(), StatusCode::TemporaryRedirect);
+        assert_eq!(res["Location"], "http://primary/api/memo?id=1");
+
+        // A replica which catches up in time serves the read.
+        let app = replica(height.clone(), consistency);
+        let (mut res, ()) = futures::join!(get(&app, 5), async {
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            height.store(5, Ordering::SeqCst);
+        });
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.body_string().await.unwrap(), "5");
+    }
+
+    // The token the server received with a read.
+    async fn read(client: &Client) -> String {
+        client
+            .get("http://localhost/memo")
+            .recv_string()
+            .await
+            .unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_read_your_writes() {
+        let mut app = tide::new();
+        app.at("/memo").post(|_| async {
+            let mut res = tide::Response::new(StatusCode::Ok);
+            LedgerHeight(7).insert(&mut res);
+            Ok(res)
+        });
+        app.at("/memo").get(|req: tide::Request<()>| async move {
+            Ok(match MinLedgerHeight::get(&req)? {
+                Some(MinLedgerHeight(min)) => min.to_string(),
+                None => "none".into(),
+            })
+        });
+
+        let ryw = ReadYourWrites::new();
+        let client = Client::new().with(ryw.clone()).with(Server(app));
+        assert_eq!(read(&client).await, "none");
+        client.post("http://localhost/memo").await.unwrap();
+        assert_eq!(ryw.token(), Some(7));
+        assert_eq!(read(&client).await, "7");
+
+        // The token only moves forward.
+        ryw.observe(3);
+        assert_eq!(read(&client).await, "7");
+    }
+}
@@ -0,0 +1,433 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Estimating the cost of expensive queries before running them.
+//!
+//! Some queries, like a scan of every record viewable by an auditor, can take a long time and
+//! produce a large response, and a client may rather not run them at all than wait for the result.
+//! Any query can be asked for a [CostEstimate] instead of its result, by adding
+//! `?estimate=true` ([ESTIMATE_PARAM]) to its URL:
+//!  * the [Estimates] server middleware answers these requests with the estimate from the route's
+//!    estimator, without running the query, marked with the [COST_ESTIMATE] header, or with 501
+//!    Not Implemented if the route has none
+//!  * the [CostBudget] client middleware asks for an estimate before each query to the routes it
+//!    is configured for, and refuses to run queries whose estimate is over its budget
+//!
+//! ```
+//! use net::cost::{CostEstimate, Estimates};
+//!
+//! let mut app = tide::new();
+//! app.with(Estimates::new().route("/records/:owner", |req: &tide::Request<()>| {
+//!     // Each record is a few hundred bytes, and takes about 1ms to find.
+//!     let limit = req
+//!         .url()
+//!         .query_pairs()
+//!         .find(|(name, _)| name == "limit")
+//!         .and_then(|(_, limit)| limit.parse().ok())
+//!         .unwrap_or(1000);
+//!     Ok(CostEstimate {
+//!         rows: limit,
+//!         bytes: 300 * limit,
+//!         time_ms: limit,
+//!     })
+//! }));
+//! ```
+//!
+//! Estimates are only as good as their estimators, and routes without one are assumed to be cheap:
+//! [CostBudget] lets their queries through. A server without [Estimates] ignores the parameter and
+//! runs the query; since its response is not marked as an estimate, [CostBudget] takes it as the
+//! result rather than running the query again.
+
+use crate::redact::SafeToServe;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+pub use crate::headers::COST_ESTIMATE;
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub use server::*;
+
+#[cfg(feature = "client")]
+pub use client::*;
+
+/// The query parameter which asks for a [CostEstimate] instead of the result of a query.
+pub const ESTIMATE_PARAM: &str = "estimate";
+
+/// The estimated cost of a query.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CostEstimate {
+    /// The number of rows, like records or transactions, the query will read.
+    pub rows: u64,
+    /// The size of the response, in bytes.
+    pub bytes: u64,
+    /// The time the query will take to run, in milliseconds.
+    pub time_ms: u64,
+}
+
+impl CostEstimate {
+    /// An estimate which no query exceeds.
+    pub const UNLIMITED: Self = Self {
+        rows: u64::MAX,
+        bytes: u64::MAX,
+        time_ms: u64::MAX,
+    };
+
+    /// The time the query will take to run.
+    pub fn time(&self) -> Duration {
+        Duration::from_millis(self.time_ms)
+    }
+
+    /// Whether any part of this estimate is over the corresponding part of `budget`.
+    pub fn exceeds(&self, budget: &CostEstimate) -> bool {
+        self.rows > budget.rows || self.bytes > budget.bytes || self.time_ms > budget.time_ms
+    }
+}
+
+impl SafeToServe for CostEstimate {}
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+mod server {
+    use super::*;
+    use crate::server::route::RouteTable;
+    use std::sync::Arc;
+    use tide::{Middleware, Next, Request, StatusCode};
+
+    type Estimator<State> =
+        Arc<dyn Fn(&Request<State>) -> tide::Result<CostEstimate> + Send + Sync>;
+
+    /// Server middleware which answers requests for cost estimates.
+    ///
+    /// See the [module documentation](self).
+    pub struct Estimates<State> {
+        routes: RouteTable<Estimator<State>>,
+    }
+
+    impl<State> Clone for Estimates<State> {
+        fn clone(&self) -> Self {
+            Self {
+                routes: self.routes.clone(),
+            }
+        }
+    }
+
+    impl<State> Default for Estimates<State> {
+        fn default() -> Self {
+            Self {
+                routes: RouteTable::new(),
+            }
+        }
+    }
+
+    impl<State> Estimates<State> {
+        /// Answer requests for estimates, for routes with no estimator yet.
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Estimate the cost of queries to the route `template` with `estimator`.
+        ///
+        /// tide does not tell middleware the parameters of the route a request matched, so
+        /// `estimator` gets the whole request, and should be quick: it runs in place of the query.
+        pub fn route<F>(mut self, template: impl Into<String>, estimator: F) -> Self
+        where
+            F: Fn(&Request<State>) -> tide::Result<CostEstimate> + Send + Sync + 'static,
+        {
+            self.routes.insert(template, Arc::new(estimator));
+            self
+        }
+    }
+
+    // Whether `req` asks for an estimate.
+    fn wants_estimate<State>(req: &Request<State>) -> tide::Result<bool> {
+        match req
+            .url()
+            .query_pairs()
+            .find(|(name, _)| name == ESTIMATE_PARAM)
+        {
+            None => Ok(false),
+            Some((_, value)) => value.parse().map_err(|_| {
+                tide::Error::from_str(
+                    StatusCode::BadRequest,
+                    format!("{} must be true or false, not {}", ESTIMATE_PARAM, value),
+                )
+            }),
+        }
+    }
+
+    #[tide::utils::async_trait]
+    impl<State: Clone + Send + Sync + 'static> Middleware<State> for Estimates<State> {
+        async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+            if !wants_estimate(&req)? {
+                return Ok(next.run(req).await);
+            }
+            match self.routes.lookup(req.url().path()) {
+                Some((_, estimator)) => {
+                    let mut res = crate::server::response(&req, estimator(&req)?)?;
+                    res.insert_header(COST_ESTIMATE, "true");
+                    Ok(res)
+                }
+                None => Err(tide::Error::from_str(
+                    StatusCode::NotImplemented,
+                    "this route has no cost estimate",
+                )),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+mod client {
+    use super::*;
+    use crate::client::response_body;
+    use snafu::Snafu;
+    use std::convert::TryInto;
+    use surf::http::Method;
+    use surf::middleware::{Middleware, Next};
+    use surf::{Client, Request, StatusCode};
+
+    /// The error with which [CostBudget] refuses a query.
+    ///
+    /// It is reported as a [surf::Error] with status 413 Payload Too Large, which can be downcast
+    /// to this type.
+    #[derive(Clone, Debug, Snafu, PartialEq, Eq)]
+    #[snafu(display("query is over budget: estimated {:?}, budget {:?}", estimate, budget))]
+    pub struct OverBudget {
+        pub estimate: CostEstimate,
+        pub budget: CostEstimate,
+    }
+
+    /// Client middleware which refuses to run queries whose estimated cost is over a budget.
+    ///
+    /// Checking a query costs a round trip, so only the routes registered with
+    /// [route](Self::route) are checked. Each `GET` request to one of them is first sent with
+    /// `?estimate=true`. If the estimate is over the budget, the request fails with [OverBudget].
+    /// If the route has no estimator, or the estimate is within the budget, the request is sent as
+    /// it is. If the server answered with the result of the query instead of an estimate, that
+    /// result is the response. If the estimate fails for any other reason, that failure is the
+    /// response. Requests with other methods, and requests which ask for an estimate themselves,
+    /// are not checked. See the [module documentation](self).
+    #[derive(Clone, Debug)]
+    pub struct CostBudget {
+        budget: CostEstimate,
+        routes: Vec<String>,
+    }
+
+    impl Default for CostBudget {
+        fn default() -> Self {
+            Self {
+                budget: CostEstimate::UNLIMITED,
+                routes: Vec::new(),
+            }
+        }
+    }
+
+    impl CostBudget {
+        /// A budget with no limits, to be narrowed down with the other methods, which checks no
+        /// routes until they are registered with [route](Self::route).
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Check queries to paths starting with `prefix`, like `/records/`.
+        ///
+        /// The prefix is matched against the whole path of the request URL, including the path of
+        /// the client's base URL, if it has one.
+        pub fn route(mut self, prefix: impl Into<String>) -> Self {
+            self.routes.push(prefix.into());
+            self
+        }
+
+        /// Refuse queries which are estimated to read more than `rows` rows.
+        pub fn max_rows(mut self, rows: u64) -> Self {
+            self.budget.rows = rows;
+            self
+        }
+
+        /// Refuse queries whose responses are estimated to be larger than `bytes`.
+        pub fn max_bytes(mut self, bytes: u64) -> Self {
+            self.budget.bytes = bytes;
+            self
+        }
+
+        /// Refuse queries which are estimated to take longer than `time`.
+        pub fn max_time(mut self, time: Duration) -> Self {
+            self.budget.time_ms = time.as_millis().try_into().unwrap_or(u64::MAX);
+            self
+        }
+
+        /// The budget, as the largest estimate which is allowed.
+        pub fn budget(&self) -> CostEstimate {
+            self.budget
+        }
+    }
+
+    #[surf::utils::async_trait]
+    impl Middleware for CostBudget {
+        async fn handle(&self, req: Request, client: Client, next: Next<'_>) -> surf::Result {
+            let asks_for_estimate = req
+                .url()
+                .query_pairs()
+                .any(|(name, _)| name == ESTIMATE_PARAM);
+            let checked = self
+                .routes
+                .iter()
+                .any(|prefix| req.url().path().starts_with(prefix.as_str()));
+            if req.method() != Method::Get || asks_for_estimate || !checked {
+                return next.run(req, client).await;
+            }
+
+            let mut estimate_req = req.clone();
+            AsMut::<surf::http::Request>::as_mut(&mut estimate_req)
+                .url_mut()
+                .query_pairs_mut()
+                .append_pair(ESTIMATE_PARAM, "true");
+            let estimate = match next.run(estimate_req, client.clone()).await {
+                Ok(mut res)
+                    if res.status() == StatusCode::Ok && res.header(COST_ESTIMATE).is_some() =>
+                {
+                    Some(response_body::<CostEstimate>(&mut res).await?)
+                }
+                // A server which does not serve estimates ran the query, and this is its result.
+                Ok(res) if res.header(COST_ESTIMATE).is_none() && res.status().is_success() => {
+                    return Ok(res)
+                }
+                // The route has no estimator, whether the error was parsed by middleware outside
+                // this one or not.
+                Ok(res) if res.status() == StatusCode::NotImplemented => None,
+                Err(err) if err.status() == StatusCode::NotImplemented => None,
+                other => return other,
+            };
+            if let Some(estimate) = estimate {
+                if estimate.exceeds(&self.budget) {
+                    return Err(surf::Error::new(
+                        StatusCode::PayloadTooLarge,
+                        OverBudget {
+                            estimate,
+                            budget: self.budget,
+                        },
+                    ));
+                }
+            }
+            next.run(req, client).await
+        }
+    }
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod test {
+    use super::*;
+    use crate::test_util::Server;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use surf::{Client, StatusCode};
+
+    // The number of records a query asks for, 100 by default.
+    fn limit(req: &tide::Request<()>) -> u64 {
+        req.url()
+            .query_pairs()
+            .find(|(name, _)| name == "limit")
+            .map_or(100, |(_, limit)| limit.parse().unwrap())
+    }
+
+    #[async_std::test]
+    async fn test_cost_budget() {
+        // Count the queries which actually run.
+        let queries = Arc::new(AtomicUsize::new(0));
+        let mut app = tide::new();
+        app.with(
+            Estimates::new().route("/records/:owner", |req: &tide::Request<()>| {
+                let rows = limit(req);
+                Ok(CostEstimate {
+                    rows,
+                    bytes: rows * 300,
+                    time_ms: rows,
+                })
+            }),
+        );
+        for route in ["/records/:owner", "/status"] {
+            let queries = queries.clone();
+            app.at(route).get(move |_| {
+                queries.fetch_add(1, Ordering::SeqCst);
+                async { Ok("done") }
+            });
+        }
+
+        // Estimates are served without running the query.
+        let client = Client::new().with(Server(app.clone()));
+        let estimate: CostEstimate = client
+            .get("http://localhost/records/alice?limit=200&estimate=true")
+            .recv_json()
+            .await
+            .unwrap();
+        assert_eq!(estimate.rows, 200);
+        let res = client
+            .get("http://localhost/records/alice?estimate=true")
+            .await
+            .unwrap();
+        assert_eq!(res[COST_ESTIMATE], "true");
+        let res = client
+            .get("http://localhost/status?estimate=true")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NotImplemented);
+        assert_eq!(queries.load(Ordering::SeqCst), 0);
+
+        // Queries within the budget, without an estimate, or to routes which are not checked, run.
+        let budget = CostBudget::new()
+            .max_rows(150)
+            .route("/records/")
+            .route("/status");
+        let client = Client::new().with(budget.clone()).with(Server(app.clone()));
+        let cheap = client.get("http://localhost/records/alice").recv_string();
+        assert_eq!(cheap.await.unwrap(), "done");
+        let status = client.get("http://localhost/status").recv_string();
+        assert_eq!(status.await.unwrap(), "done");
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+        // Queries over the budget don't.
+        let err = client
+            .get("http://localhost/records/alice?limit=200")
+            .await
+            .unwrap_err();
+        assert_eq!(err.status(), StatusCode::PayloadTooLarge);
+        let over = err.downcast_ref::<OverBudget>().unwrap();
+        assert_eq!(over.estimate.rows, 200);
+        assert_eq!(over.budget, budget.budget());
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+        let client = Client::new()
+            .with(CostBudget::new().max_rows(150).route("/status"))
+            .with(Server(app));
+        let expensive = client
+            .get("http://localhost/records/alice?limit=200")
+            .recv_string();
+        assert_eq!(expensive.await.unwrap(), "done");
+        assert_eq!(queries.load(Ordering::SeqCst), 3);
+    }
+
+    #[async_std::test]
+    async fn test_server_without_estimates() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let mut app = tide::new();
+        app.at("/records/:owner").get({
+            let queries = queries.clone();
+            move |_| {
+                queries.fetch_add(1, Ordering::SeqCst);
+                async { Ok("done") }
+            }
+        });
+
+        // The server ignores the request for an estimate and runs the query. Its response is not
+        // marked as an estimate, so the client takes it as the result, without running the query
+        // again.
+        let client = Client::new()
+            .with(CostBudget::new().max_rows(150).route("/records/"))
+            .with(Server(app));
+        let res = client.get("http://localhost/records/alice").recv_string();
+        assert_eq!(res.await.unwrap(), "done");
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,573 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Opaque, tamper-proof positions in paginated results.
+//!
+//! A server which pages through results has to tell the client where the next page starts. If it
+//! sends its internal position (an offset, a key, a block height and an index within the block),
+//! clients come to depend on its format, and can send back positions the server never issued. A
+//! [Cursor] avoids both: it is an opaque string, sealed by the server with a [CursorKey], which the
+//! server can later open to recover the position.
+//!
+//! ```
+//! use net::cursor::CursorKey;
+//!
+//! let key = CursorKey::new([7; 32]);
+//! let cursor = key.seal(1, &(1234u64, 5u32));
+//! // ... sent to the client, and later back to the server ...
+//! let opened = key.open(&cursor).unwrap();
+//! assert_eq!(opened.version(), 1);
+//! assert_eq!(opened.decode::<(u64, u32)>().unwrap(), (1234, 5));
+//! ```
+//!
+//! A cursor is authenticated with HMAC-SHA256, so a cursor which was not sealed with the server's
+//! key fails to open. It is not encrypted, though: a client can see the position it contains, but
+//! it cannot change it. Each cursor records the version of the position's representation, so a
+//! server which changes its representation can still open the cursors it issued before, by
+//! decoding them according to their version.
+//!
+//! A [Page] of results carries the cursor of the next page, and
+//! [poll_pages](crate::client::poll::poll_pages) follows those cursors to present a paginated
+//! endpoint as a stream.
+//!
+//! Streams whose positions are part of the items themselves, like block heights or the indices of
+//! audit log entries, do not use cursors. The client already knows those positions, and needs them
+//! to be consecutive integers to notice gaps, so
+//! [poll_stream](crate::client::poll::poll_stream),
+//! [backfill_then_tail](crate::client::backfill_then_tail) and the `from` parameter of
+//! [AuditLog::serve](crate::server::audit::AuditLog::serve) all take plain positions.
+//! [mux](crate::rpc::mux) subscriptions only deliver events from when they are opened, and have no
+//! position to resume from at all.
+//!
+//! Cursors also let a server cut short a result which is too large to send at once. A client which
+//! cannot handle arbitrarily large responses sends the largest size it wants in the
+//! [MAX_RESPONSE_BYTES] header. The server builds its response with [respond_partial], which stops
+//! adding items once their estimated size would exceed the limit, and responds with
+//! 206 Partial Content and a [Page] whose `next` cursor continues the result. The client requests
+//! the continuation by sending the cursor back in the [CONTINUATION_PARAM] query parameter, which
+//! [get_all] does until it has the whole result. If even the first item is too large, the server
+//! responds with 413 Payload Too Large.
+
+use crate::redact::SafeToServe;
+use crate::wire::binary;
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use snafu::Snafu;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+pub use crate::headers::{MaxResponseBytes, MAX_RESPONSE_BYTES};
+
+/// The query parameter in which a client sends the cursor of the continuation of a partial result.
+pub const CONTINUATION_PARAM: &str = "cursor";
+
+/// The minimum length of the secret of a [CursorKey], in bytes.
+pub const MIN_SECRET_LEN: usize = 32;
+
+const TAG_LEN: usize = 32;
+
+/// An opaque position in a paginated result.
+///
+/// Cursors serialize as strings, which are safe to use in URLs.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Cursor(String);
+
+impl Cursor {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for Cursor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = CursorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Only the server can tell whether a cursor is valid, but anything which is not hex is
+        // certainly not a cursor.
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(CursorError::Invalid);
+        }
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl SafeToServe for Cursor {}
+
+#[derive(Clone, Debug, Snafu, PartialEq, Eq)]
+pub enum CursorError {
+    #[snafu(display("invalid cursor"))]
+    Invalid,
+    #[snafu(display("unsupported cursor version {}", version))]
+    UnsupportedVersion { version: u16 },
+}
+
+/// The key a server uses to seal and open [Cursor]s.
+#[derive(Clone)]
+pub struct CursorKey {
+    secret: Vec<u8>,
+}
+
+impl CursorKey {
+    /// A key with the given secret.
+    ///
+    /// Servers which share cursors, like replicas behind a load balancer, must use the same secret.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `secret` is shorter than [MIN_SECRET_LEN] bytes.
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        let secret = secret.into();
+        assert!(
+            secret.len() >= MIN_SECRET_LEN,
+            "cursor secret must be at least {} bytes",
+            MIN_SECRET_LEN
+        );
+        Self { secret }
+    }
+
+    /// Seal `position`, in the representation with the given `version`, into a cursor.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `position` cannot be serialized.
+    pub fn seal<P: Serialize + ?Sized>(&self, version: u16, position: &P) -> Cursor {
+        let mut bytes = version.to_be_bytes().to_vec();
+        bytes.extend(binary::serialize(position).expect("cursor position must serialize"));
+        let tag = self.mac(&bytes).finalize().into_bytes();
+        bytes.extend(tag);
+        Cursor(hex::encode(bytes))
+    }
+
+    /// Open a cursor sealed with this key.
+    pub fn open(&self, cursor: &Cursor) -> Result<OpenCursor, CursorError> {
+        let bytes = hex::decode(&cursor.0).map_err(|_| CursorError::Invalid)?;
+        if bytes.len() < 2 + TAG_LEN {
+            return Err(CursorError::Invalid);
+        }
+        let (sealed, tag) = bytes.split_at(bytes.len() - TAG_LEN);
+        // `verify_slice` compares in constant time.
+        self.mac(sealed)
+            .verify_slice(tag)
+            .map_err(|_| CursorError::Invalid)?;
+        Ok(OpenCursor {
+            version: u16::from_be_bytes([sealed[0], sealed[1]]),
+            position: sealed[2..].to_vec(),
+        })
+    }
+
+    /// Open a cursor, which must have been sealed with this key with the given version, and decode
+    /// its position.
+    pub fn open_as<P: DeserializeOwned>(
+        &self,
+        version: u16,
+        cursor: &Cursor,
+    ) -> Result<P, CursorError> {
+        let opened = self.open(cursor)?;
+        if opened.version != version {
+            return Err(CursorError::UnsupportedVersion {
+                version: opened.version,
+            });
+        }
+        opened.decode()
+    }
+
+    /// Open the cursor in the query parameter `param` of `req`, if there is one.
+    ///
+    /// Fails with status 400 (Bad Request) if the cursor was not sealed with this key, or if it
+    /// has a version other than `version`.
+    #[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+    pub fn from_query<P: DeserializeOwned, State>(
+        &self,
+        req: &tide::Request<State>,
+        param: &str,
+        version: u16,
+    ) -> Result<Option<P>, tide::Error> {
+        let cursor = req
+            .url()
+            .query_pairs()
+            .find(|(name, _)| name == param)
+            .map(|(_, value)| value.into_owned());
+        let cursor = match cursor {
+            Some(cursor) => cursor,
+            None => return Ok(None),
+        };
+        cursor
+            .parse()
+            .and_then(|cursor| self.open_as(version, &cursor))
+            .map(Some)
+            .map_err(|err| tide::Error::from_str(tide::StatusCode::BadRequest, err.to_string()))
+    }
+
+    fn mac(&self, bytes: &[u8]) -> Hmac<Sha256> {
+        // HMAC accepts keys of any length.
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+        mac.update(bytes);
+        mac
+    }
+}
+
+/// A cursor which has been opened with [CursorKey::open].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpenCursor {
+    version: u16,
+    position: Vec<u8>,
+}
+
+impl OpenCursor {
+    /// The version of the representation of the position.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Decode the position, according to the representation of its version.
+    pub fn decode<P: DeserializeOwned>(&self) -> Result<P, CursorError> {
+        binary::deserialize(&self.position).map_err(|_| CursorError::Invalid)
+    }
+}
+
+/// A page of results.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// The cursor of the next page, or [None] if this is the last page.
+    pub next: Option<Cursor>,
+}
+
+impl<T: SafeToServe> SafeToServe for Page<T> {}
+
+// An io::Write which only counts the bytes written to it.
+#[derive(Default)]
+struct ByteCounter(u64);
+
+impl std::io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The estimated size of `item` in a response with content type `ty`, in bytes.
+///
+/// This is the size of its [binary](crate::wire::binary) serialization for
+/// `application/octet-stream`, and of its compact JSON serialization otherwise. Neither is an
+/// upper bound on the other: the binary format writes integers at their full width, so small
+/// numbers are larger than in JSON, while byte strings are larger in JSON.
+pub fn estimated_size<T: Serialize>(item: &T, ty: &http_types::Mime) -> u64 {
+    if ty.essence() == http_types::mime::BYTE_STREAM.essence() {
+        use bincode::Options;
+        return binary::options().serialized_size(item).unwrap_or(0);
+    }
+    let mut counter = ByteCounter::default();
+    match serde_json::to_writer(&mut counter, item) {
+        Ok(()) => counter.0,
+        Err(_) => 0,
+    }
+}
+
+/// Respond with a [Page] of `items`, cut short if it is larger than the client wants.
+///
+/// If the request has a [MAX_RESPONSE_BYTES] header, items are taken from `items` only until
+/// their [estimated size](estimated_size), in the format the response will be sent in, would exceed
+/// the limit, so a lazy iterator is not advanced further than needed. If any items are left over,
+/// the response is 206 Partial Content, and its `next` cursor is `cursor_after` the last item it
+/// contains; the handler should continue from that cursor when it is sent back in the
+/// [CONTINUATION_PARAM] query parameter. Otherwise, the response is a normal page with cursor
+/// `next`. If the first item alone exceeds the limit, this fails with 413 Payload Too Large.
+///
+/// The limit is approximate. It applies to the items, and the page around them adds a small,
+/// constant overhead. Items are also sized as [estimated_size] serializes them, before
+/// [response](crate::server::response) applies the JSON options of the request: `?pretty`, a
+/// [JSON_INTEGERS](crate::headers::JSON_INTEGERS) header asking for strings, and `?fields`. The
+/// first two make the body which is sent larger than the estimate, so clients using them should
+/// ask for less than they can take.
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub fn respond_partial<T, State>(
+    req: &tide::Request<State>,
+    items: impl IntoIterator<Item = T>,
+    next: Option<Cursor>,
+    cursor_after: impl FnOnce(&T) -> Cursor,
+) -> tide::Result
+where
+    T: Serialize + SafeToServe,
+{
+    use crate::headers::TypedHeader;
+    use tide::StatusCode;
+
+    let limit = match MaxResponseBytes::get(req)? {
+        Some(MaxResponseBytes(limit)) => limit,
+        None => {
+            let items = items.into_iter().collect();
+            return crate::server::response(req, Page { items, next });
+        }
+    };
+
+    let ty = crate::server::response_type(req)?;
+    let is_json = ty.essence() != http_types::mime::BYTE_STREAM.essence();
+    let mut page = Vec::new();
+    let mut size = 0;
+    for item in items {
+        // In JSON, each item after the first is preceded by a comma.
+        let item_size = estimated_size(&item, &ty) + (is_json && !page.is_empty()) as u64;
+        if size + item_size > limit {
+            let last = match page.last() {
+                Some(last) => last,
+                None => {
+                    return Err(tide::Error::from_str(
+                        StatusCode::PayloadTooLarge,
+                        format!(
+                            "the first item is {} bytes, larger than the limit of {} bytes",
+                            item_size, limit
+                        ),
+                    ))
+                }
+            };
+            let next = Some(cursor_after(last));
+            let mut res = crate::server::response(req, Page { items: page, next })?;
+            res.set_status(StatusCode::PartialContent);
+            return Ok(res);
+        }
+        size += item_size;
+        page.push(item);
+    }
+    crate::server::response(req, Page { items: page, next })
+}
+
+/// Get a result which the server may split into partial results, following the continuations.
+///
+/// Each request asks for at most `max_bytes`, and the items of the partial results are collected
+/// into a single [Page], whose `next` cursor is that of the last response. Only the continuations
+/// of 206 Partial Content responses are followed; a full page with a `next` cursor is the end of
+/// the result, as far as this function is concerned, and its cursor is returned.
+///
+/// `client` should have the [parse_error_body](crate::client::parse_error_body) middleware, so that
+/// error responses are reported as `E`.
+#[cfg(feature = "client")]
+pub async fn get_all<T: DeserializeOwned, E: crate::Error>(
+    client: &surf::Client,
+    mut url: surf::Url,
+    max_bytes: u64,
+) -> Result<Page<T>, E> {
+    use crate::headers::TypedHeader;
+    use surf::StatusCode;
+
+    let mut items = Vec::new();
+    loop {
+        let mut req = client.get(url.clone()).build();
+        MaxResponseBytes(max_bytes).insert(&mut req);
+        let mut res = client.send(req).await.map_err(E::from_client_error)?;
+        let page: Page<T> = crate::client::response_body(&mut res)
+            .await
+            .map_err(E::from_client_error)?;
+        let partial = res.status() == StatusCode::PartialContent;
+        if partial && page.items.is_empty() {
+            return Err(E::catch_all(
+                "server sent an empty partial result".to_string(),
+            ));
+        }
+        items.extend(page.items);
+        match page.next {
+            Some(next) if partial => {
+                let query = url
+                    .query_pairs()
+                    .filter(|(name, _)| name != CONTINUATION_PARAM)
+                    .map(|(name, value)| (name.into_owned(), value.into_owned()))
+                    .collect::<Vec<_>>();
+                url.query_pairs_mut()
+                    .clear()
+                    .extend_pairs(query)
+                    .append_pair(CONTINUATION_PARAM, next.as_str());
+            }
+            next => return Ok(Page { items, next }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cursor() {
+        let key = CursorKey::new([1; 32]);
+        let cursor = key.seal(2, &(10u64, "memo".to_string()));
+        assert_eq!(
+            key.open_as::<(u64, String)>(2, &cursor).unwrap(),
+            (10, "memo".to_string())
+        );
+
+        // Cursors survive a round trip through a URL or JSON.
+        assert_eq!(cursor.to_string().parse::<Cursor>().unwrap(), cursor);
+        let json = serde_json::to_string(&cursor).unwrap();
+        assert_eq!(json, format!("\"{}\"", cursor));
+        assert_eq!(serde_json::from_str::<Cursor>(&json).unwrap(), cursor);
+
+        // Servers can tell which representation a cursor uses.
+        assert_eq!(
+            key.open_as::<(u64, String)>(3, &cursor).unwrap_err(),
+            CursorError::UnsupportedVersion { version: 2 }
+        );
+
+        // Cursors cannot be forged, changed, or opened with a different key.
+        let forged = CursorKey::new([2; 32]).seal(2, &(11u64, "memo".to_string()));
+        assert_eq!(key.open(&forged).unwrap_err(), CursorError::Invalid);
+        let mut bytes = hex::decode(cursor.as_str()).unwrap();
+        bytes[2] ^= 1;
+        let tampered = Cursor(hex::encode(bytes));
+        assert_eq!(key.open(&tampered).unwrap_err(), CursorError::Invalid);
+        assert_eq!(
+            key.open(&"00".parse().unwrap()).unwrap_err(),
+            CursorError::Invalid
+        );
+        assert!("not a cursor".parse::<Cursor>().is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_short_secret() {
+        CursorKey::new([1; 16]);
+    }
+
+    #[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+    #[async_std::test]
+    async fn test_from_query() {
+        use tide::http::{Method, Url};
+
+        let key = CursorKey::new([1; 32]);
+        let mut app = tide::with_state(key.clone());
+        app.at("/items")
+            .get(|req: tide::Request<CursorKey>| async move {
+                let from: Option<u64> = req.state().from_query(&req, "cursor", 1)?;
+                Ok(format!("{:?}", from))
+            });
+        let get = |query: String| {
+            let app = app.clone();
+            async move {
+                let url = Url::parse(&format!("http://localhost/items{}", query)).unwrap();
+                let mut res: tide::http::Response = app
+                    .respond(tide::http::Request::new(Method::Get, url))
+                    .await
+                    .unwrap();
+                (res.status(), res.body_string().await.unwrap())
+            }
+        };
+
+        assert_eq!(get("".into()).await.1, "None");
+        let cursor = key.seal(1, &5u64);
+        assert_eq!(get(format!("?cursor={}", cursor)).await.1, "Some(5)");
+        let forged = CursorKey::new([2; 32]).seal(1, &5u64);
+        assert_eq!(
+            get(format!("?cursor={}", forged)).await.0,
+            tide::StatusCode::BadRequest
+        );
+    }
+
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[async_std::test]
+    async fn test_partial() {
+        use crate::client::{parse_error_body, ErrorTypes};
+        use crate::test_util::Server;
+        use std::convert::TryFrom;
+        use surf::{Client, StatusCode};
+
+        #[derive(Clone, Debug, Deserialize, Serialize, Snafu)]
+        #[snafu(display("{}", msg))]
+        struct QueryError {
+            status: u16,
+            msg: String,
+        }
+
+        impl crate::Error for QueryError {
+            fn catch_all(msg: String) -> Self {
+                Self::catch_all_with_status(StatusCode::InternalServerError, msg)
+            }
+
+            fn catch_all_with_status(status: StatusCode, msg: String) -> Self {
+                Self {
+                    status: status.into(),
+                    msg,
+                }
+            }
+
+            fn status(&self) -> StatusCode {
+                StatusCode::try_from(self.status).unwrap_or(StatusCode::InternalServerError)
+            }
+        }
+
+        // The numbers from 0 to 99, or from the position in the cursor.
+        let mut app = tide::with_state(CursorKey::new([1; 32]));
+        app.with(crate::server::add_error_body::<_, QueryError>);
+        app.at("/numbers")
+            .get(|req: tide::Request<CursorKey>| async move {
+                let key = req.state();
+                let start: u64 = key.from_query(&req, CONTINUATION_PARAM, 1)?.unwrap_or(0);
+                respond_partial(&req, start..100, None, |i| key.seal(1, &(i + 1)))
+            });
+        let client = Client::new()
+            .with(parse_error_body::<QueryError>)
+            .with(Server(app.clone()));
+        let url: surf::Url = "http://localhost/numbers".parse().unwrap();
+
+        // Without a limit, the whole result comes at once.
+        let page: Page<u64> = client.get(url.clone()).recv_json().await.unwrap();
+        assert_eq!(page.items, (0..100).collect::<Vec<_>>());
+
+        // With a limit, it comes in pieces no larger than the limit.
+        let mut res = client
+            .get(url.clone())
+            .header(MAX_RESPONSE_BYTES, "50")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PartialContent);
+        let page: Page<u64> = res.body_json().await.unwrap();
+        assert_eq!(page.items, (0..20).collect::<Vec<_>>());
+        assert!(page.next.is_some());
+
+        // The size is estimated in the format of the response, where small numbers take 8 bytes.
+        let mut res = client
+            .get(url.clone())
+            .header("Accept", "application/octet-stream")
+            .header(MAX_RESPONSE_BYTES, "50")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PartialContent);
+        let page: Page<u64> = binary::deserialize(&res.body_bytes().await.unwrap()).unwrap();
+        assert_eq!(page.items, (0..6).collect::<Vec<_>>());
+
+        let page = get_all::<u64, QueryError>(&client, url.clone(), 50)
+            .await
+            .unwrap();
+        assert_eq!(page.items, (0..100).collect::<Vec<_>>());
+        assert_eq!(page.next, None);
+
+        // Partial results are not errors to clients which decode errors with `ErrorTypes` either.
+        let typed = Client::new()
+            .with(ErrorTypes::new().with::<QueryError>())
+            .with(Server(app));
+        let page = get_all::<u64, QueryError>(&typed, url.clone(), 50)
+            .await
+            .unwrap();
+        assert_eq!(page.items, (0..100).collect::<Vec<_>>());
+
+        // An item larger than the limit can't be sent at all.
+        let err = get_all::<u64, QueryError>(&client, url, 0)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, 413);
+    }
+}
@@ -0,0 +1,354 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Deadlines which follow a request from service to service.
+//!
+//! When a wallet calls a relayer, which calls the query service, each hop has its own timeout, and
+//! none of them knows how long the caller is actually willing to wait. The query service may keep
+//! working on a response long after the wallet has given up on it. Instead, the caller can send its
+//! remaining budget in the [REQUEST_DEADLINE] header:
+//!  * the [Deadlines] server middleware turns the header into a [Deadline] in the request
+//!    extensions, rejects requests whose budget is too short to be worth starting, and fails the
+//!    request with 504 Gateway Timeout when the deadline passes
+//!  * handlers which call other services pass their [Deadline] to the client as middleware, which
+//!    sends what is left of the budget with the nested request, and gives up on it when the
+//!    deadline passes
+//!
+//! ```
+//! use net::deadline::{Deadline, Deadlines};
+//! # use std::time::Duration;
+//!
+//! let mut app = tide::new();
+//! app.with(Deadlines::new().min_budget(Duration::from_millis(50)));
+//! app.at("/balance").get(|req: tide::Request<()>| async move {
+//!     let mut nested = surf::get("http://query.example.com/balance");
+//!     if let Some(deadline) = req.ext::<Deadline>() {
+//!         nested = nested.middleware(*deadline);
+//!     }
+//!     Ok(nested.recv_string().await?)
+//! });
+//! ```
+//!
+//! The budget shrinks naturally at each hop by the time already spent. It is measured relative to
+//! when the request arrives, rather than as an absolute time, so that services do not need
+//! synchronized clocks; the cost is that network latency is not counted against it. Budgets are
+//! capped at [MAX_BUDGET], so that a huge budget in a header cannot overflow the clock.
+
+use std::time::Duration;
+
+pub use crate::headers::{RequestDeadline, REQUEST_DEADLINE};
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub use server::*;
+
+/// The longest budget a deadline can have.
+///
+/// Longer budgets are shortened to this. A request which is willing to wait this long is, for all
+/// practical purposes, willing to wait forever.
+pub const MAX_BUDGET: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+// WebAssembly builds have no `Instant`, and measure deadlines on the JavaScript clock instead, as
+// the time since the Unix epoch.
+#[cfg(not(target_arch = "wasm32"))]
+type Time = std::time::Instant;
+#[cfg(target_arch = "wasm32")]
+type Time = Duration;
+
+fn now() -> Time {
+    #[cfg(not(target_arch = "wasm32"))]
+    return std::time::Instant::now();
+    #[cfg(target_arch = "wasm32")]
+    return Duration::from_secs_f64(js_sys::Date::now().max(0.0) / 1000.0);
+}
+
+/// The time by which a request must be answered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Deadline {
+    expires: Time,
+}
+
+impl Deadline {
+    /// A deadline `budget` from now, or [MAX_BUDGET] from now if `budget` is longer.
+    pub fn after(budget: Duration) -> Self {
+        Self {
+            expires: now() + budget.min(MAX_BUDGET),
+        }
+    }
+
+    /// The time left until the deadline, or zero if it has passed.
+    pub fn remaining(&self) -> Duration {
+        #[cfg(not(target_arch = "wasm32"))]
+        return self.expires.saturating_duration_since(now());
+        #[cfg(target_arch = "wasm32")]
+        return self.expires.saturating_sub(now());
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.remaining() == Duration::ZERO
+    }
+
+    /// The header to send with a request which must be answered by this deadline.
+    pub fn header(&self) -> RequestDeadline {
+        RequestDeadline(self.remaining())
+    }
+}
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+mod server {
+    use super::*;
+    use crate::headers::TypedHeader;
+    use crate::server::route::RouteTable;
+    use futures::future::BoxFuture;
+    use tide::{Middleware, Next, Request, Response, StatusCode};
+
+    /// Server middleware which enforces the deadlines of requests.
+    ///
+    /// This is equivalent to the default configuration of [Deadlines].
+    pub fn deadlines<'a, State: Clone + Send + Sync + 'static>(
+        req: Request<State>,
+        next: Next<'a, State>,
+    ) -> BoxFuture<'a, tide::Result> {
+        Box::pin(async { Deadlines::default().handle(req, next).await })
+    }
+
+    /// Configurable server middleware which enforces the deadlines of requests.
+    ///
+    /// The deadline of each request is added to the request extensions as a [Deadline]. Requests
+    /// without a [REQUEST_DEADLINE] header have no deadline, unless a default budget is
+    /// configured.
+    #[derive(Clone, Debug, Default)]
+    pub struct Deadlines {
+        min_budget: Duration,
+        routes: RouteTable<Duration>,
+        default_budget: Option<Duration>,
+    }
+
+    impl Deadlines {
+        /// Accept any budget, and impose no deadline on requests without one.
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Reject requests with less than `budget` left, on routes without their own minimum.
+        pub fn min_budget(mut self, budget: Duration) -> Self {
+            self.min_budget = budget;
+            self
+        }
+
+        /// Reject requests with less than `budget` left on routes matching `template`.
+        ///
+        /// This is for routes which are known to be slow, where a request with a short deadline
+        /// would only waste work that nobody will wait for.
+        pub fn route(mut self, template: impl Into<String>, budget: Duration) -> Self {
+            self.routes.insert(template, budget);
+            self
+        }
+
+        /// Give requests without a [REQUEST_DEADLINE] header a deadline `budget` after they arrive.
+        pub fn default_budget(mut self, budget: Duration) -> Self {
+            self.default_budget = Some(budget);
+            self
+        }
+
+        fn min_budget_for(&self, path: &str) -> Duration {
+            self.routes
+                .lookup(path)
+                .map(|(_, budget)| *budget)
+                .unwrap_or(self.min_budget)
+        }
+    }
+
+    fn gateway_timeout(msg: String) -> Response {
+        let mut res = Response::new(StatusCode::GatewayTimeout);
+        res.set_error(tide::Error::from_str(StatusCode::GatewayTimeout, msg));
+        res
+    }
+
+    #[tide::utils::async_trait]
+    impl<State: Clone + Send + Sync + 'static> Middleware<State> for Deadlines {
+        async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+            let budget = match RequestDeadline::get(&req) {
+                Ok(Some(RequestDeadline(budget))) => budget,
+                Ok(None) => match self.default_budget {
+                    Some(budget) => budget,
+                    None => return Ok(next.run(req).await),
+                },
+                Err(err) => {
+                    let mut res = Response::new(err.status());
+                    res.set_error(err);
+                    return Ok(res);
+                }
+            };
+            let min_budget = self.min_budget_for(req.url().path());
+            if budget < min_budget {
+                return Ok(gateway_timeout(format!(
+                    "deadline of {:?} is too short; this route needs at least {:?}",
+                    budget, min_budget
+                )));
+            }
+            let budget = budget.min(MAX_BUDGET);
+            req.set_ext(Deadline::after(budget));
+            Ok(async_std::future::timeout(budget, next.run(req))
+                .await
+                .unwrap_or_else(|_| gateway_timeout(format!("deadline of {:?} exceeded", budget))))
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+mod client {
+    use super::*;
+    use crate::headers::TypedHeader;
+    use surf::middleware::{Middleware, Next};
+    use surf::{Client, Request, StatusCode};
+
+    /// A [Deadline] is client middleware, which sends the rest of its budget with each request.
+    ///
+    /// If the request already has a shorter deadline, that one is kept. The request fails with
+    /// 504 Gateway Timeout if the deadline passes before the response arrives, or if it has already
+    /// passed, in which case the request is not sent at all.
+    #[surf::utils::async_trait]
+    impl Middleware for Deadline {
+        async fn handle(&self, mut req: Request, client: Client, next: Next<'_>) -> surf::Result {
+            let mut budget = self.remaining();
+            if let Ok(Some(RequestDeadline(existing))) = RequestDeadline::get(&req) {
+                budget = budget.min(existing);
+            }
+            if budget == Duration::ZERO {
+                return Err(surf::Error::from_str(
+                    StatusCode::GatewayTimeout,
+                    "deadline exceeded before the request was sent",
+                ));
+            }
+            RequestDeadline(budget).insert(&mut req);
+            async_std::future::timeout(budget, next.run(req, client))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(surf::Error::from_str(
+                        StatusCode::GatewayTimeout,
+                        format!("no response within the deadline of {:?}", budget),
+                    ))
+                })
+        }
+    }
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod test {
+    use super::*;
+    use crate::test_util::Server;
+    use surf::StatusCode;
+    use tide::http::{Method, Url};
+
+    // A service which reports the budget it was given, and takes `delay` to do so.
+    fn service(middleware: Deadlines, delay: Duration) -> tide::Server<()> {
+        let mut app = tide::new();
+        app.with(middleware);
+        app.at("/*").get(move |req: tide::Request<()>| async move {
+            async_std::task::sleep(delay).await;
+            Ok(match req.ext::<Deadline>() {
+                Some(deadline) => format!("{}", deadline.remaining().as_millis()),
+                None => "none".into(),
+            })
+        });
+        app
+    }
+
+    async fn get(app: &tide::Server<()>, path: &str, budget: Option<&str>) -> tide::http::Response {
+        let mut req = tide::http::Request::new(
+            Method::Get,
+            Url::parse("http://localhost").unwrap().join(path).unwrap(),
+        );
+        if let Some(budget) = budget {
+            req.insert_header(REQUEST_DEADLINE, budget);
+        }
+        app.respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_server() {
+        let app = service(
+            Deadlines::new()
+                .min_budget(Duration::from_millis(10))
+                .route("/slow", Duration::from_secs(5)),
+            Duration::ZERO,
+        );
+
+        let mut res = get(&app, "/fast", Some("1000")).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let remaining: u64 = res.body_string().await.unwrap().parse().unwrap();
+        assert!(remaining <= 1000 && remaining > 500, "{}", remaining);
+        let mut res = get(&app, "/fast", None).await;
+        assert_eq!(res.body_string().await.unwrap(), "none");
+
+        // Budgets too long to add to the clock are capped.
+        let mut res = get(&app, "/fast", Some(&u64::MAX.to_string())).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let remaining: u128 = res.body_string().await.unwrap().parse().unwrap();
+        assert!(remaining <= MAX_BUDGET.as_millis(), "{}", remaining);
+
+        // Requests which cannot be served in time are rejected up front.
+        assert_eq!(
+            get(&app, "/fast", Some("5")).await.status(),
+            StatusCode::GatewayTimeout
+        );
+        assert_eq!(
+            get(&app, "/slow", Some("1000")).await.status(),
+            StatusCode::GatewayTimeout
+        );
+        assert_eq!(
+            get(&app, "/fast", Some("-1")).await.status(),
+            StatusCode::BadRequest
+        );
+
+        // Requests which take too long are cut off.
+        let app = service(
+            Deadlines::new().default_budget(Duration::from_millis(20)),
+            Duration::from_secs(10),
+        );
+        assert_eq!(
+            get(&app, "/fast", None).await.status(),
+            StatusCode::GatewayTimeout
+        );
+    }
+
+    #[async_std::test]
+    async fn test_forward() {
+        let upstream = Server(service(Deadlines::new(), Duration::ZERO));
+
+        let deadline = Deadline::after(Duration::from_millis(800));
+        let remaining: u64 = surf::get("http://upstream/budget")
+            .middleware(deadline)
+            .middleware(upstream.clone())
+            .recv_string()
+            .await
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(remaining <= 800 && remaining > 400, "{}", remaining);
+
+        // A shorter deadline already on the request is kept.
+        let remaining: u64 = surf::get("http://upstream/budget")
+            .header(REQUEST_DEADLINE, "300")
+            .middleware(deadline)
+            .middleware(upstream.clone())
+            .recv_string()
+            .await
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(remaining <= 300, "{}", remaining);
+
+        // Once the deadline has passed, nothing is sent.
+        let err = surf::get("http://upstream/budget")
+            .middleware(Deadline::after(Duration::ZERO))
+            .middleware(upstream)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status(), StatusCode::GatewayTimeout);
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Delta responses for large, evolving state.
+//!
+//! A client which holds version N of some state (for example, the results of a keystore scan as of
+//! block N) and wants the latest version sends N in a [DELTA_BASE] header. If the server still
+//! knows version N, it responds with a [Delta::Patch] which transforms the client's copy into the
+//! latest version, along with a commitment to the latest version. Otherwise, it responds with the
+//! full state in a [Delta::Full]. The client applies the delta with [Delta::apply], which checks
+//! the patched state against the commitment, so a buggy diff can never silently corrupt the
+//! client's state.
+//!
+//! The patch format is chosen by implementing [Diff]. [JsonPatch](crate::wire::patch::JsonPatch) is
+//! a good default, and works for any serializable type: implement [Diff] with it using
+//! [json_diff].
+
+#[cfg(any(
+    feature = "client",
+    all(feature = "server", not(target_arch = "wasm32"))
+))]
+use crate::headers::{DeltaBase, TypedHeader};
+use crate::wire::patch::PatchError;
+use commit::{Commitment, Committable};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+
+pub use crate::headers::DELTA_BASE;
+
+#[derive(Debug, Snafu)]
+pub enum DeltaError {
+    #[snafu(display(
+        "patch applies to version {}, but the client has {:?}",
+        expected,
+        actual
+    ))]
+    BaseMismatch { expected: u64, actual: Option<u64> },
+    #[snafu(display("failed to apply patch: {}", reason))]
+    InvalidPatch { reason: String },
+    #[snafu(display("patched state does not match the commitment sent by the server"))]
+    CommitmentMismatch,
+}
+
+impl From<PatchError> for DeltaError {
+    fn from(err: PatchError) -> Self {
+        Self::InvalidPatch {
+            reason: err.to_string(),
+        }
+    }
+}
+
+/// Types which can be sent as patches.
+pub trait Diff: Sized {
+    type Patch: Serialize + DeserializeOwned;
+
+    /// Compute a patch which transforms `old` into `new`.
+    ///
+    /// This may return [None] if a patch would not be worthwhile, in which case the full state is
+    /// sent instead.
+    fn diff(old: &Self, new: &Self) -> Option<Self::Patch>;
+
+    /// Apply a patch to `self`.
+    fn apply(&self, patch: &Self::Patch) -> Result<Self, DeltaError>;
+}
+
+/// Implement [Diff] for a serializable type using [JsonPatch](crate::wire::patch::JsonPatch).
+#[macro_export]
+macro_rules! json_diff {
+    ($t:ty) => {
+        impl $crate::delta::Diff for $t {
+            type Patch = $crate::wire::patch::JsonPatch;
+
+            fn diff(old: &Self, new: &Self) -> Option<Self::Patch> {
+                $crate::wire::patch::JsonPatch::diff(old, new).ok()
+            }
+
+            fn apply(&self, patch: &Self::Patch) -> Result<Self, $crate::delta::DeltaError> {
+                Ok(patch.apply(self)?)
+            }
+        }
+    };
+}
+
+/// A response containing either the full state or a patch to a version the client already has.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "T: Serialize, T::Patch: Serialize",
+    deserialize = "T: DeserializeOwned, T::Patch: DeserializeOwned"
+))]
+pub enum Delta<T: Diff + Committable> {
+    Full {
+        version: u64,
+        state: T,
+    },
+    Patch {
+        base: u64,
+        version: u64,
+        patch: T::Patch,
+        commitment: Commitment<T>,
+    },
+}
+
+impl<T: Diff + Committable> Delta<T> {
+    /// Prepare a response containing `state`, which is at `version`.
+    ///
+    /// `base` is the version the client already has, and the server's copy of that version, if the
+    /// client sent a [DELTA_BASE] and the server still has that version. If `base` is provided, the
+    /// response will be a patch, unless [Diff::diff] declines to produce one.
+    pub fn new(base: Option<(u64, &T)>, version: u64, state: T) -> Self {
+        if let Some((base, old)) = base {
+            if let Some(patch) = T::diff(old, &state) {
+                return Self::Patch {
+                    base,
+                    version,
+                    patch,
+                    commitment: state.commit(),
+                };
+            }
+        }
+        Self::Full { version, state }
+    }
+
+    /// The version of the state this delta produces.
+    pub fn version(&self) -> u64 {
+        match self {
+            Self::Full { version, .. } | Self::Patch { version, .. } => *version,
+        }
+    }
+
+    /// Produce the new state from the state the client already has, if any.
+    ///
+    /// `held` is the version of the state the client has, and the state itself. If this delta is a
+    /// patch, it must have been computed against the same version, and the patched state must match
+    /// the commitment from the server.
+    pub fn apply(self, held: Option<(u64, &T)>) -> Result<T, DeltaError> {
+        match self {
+            Self::Full { state, .. } => Ok(state),
+            Self::Patch {
+                base,
+                patch,
+                commitment,
+                ..
+            } => {
+                let old = match held {
+                    Some((version, old)) if version == base => old,
+                    _ => {
+                        return Err(DeltaError::BaseMismatch {
+                            expected: base,
+                            actual: held.map(|(version, _)| version),
+                        })
+                    }
+                };
+                let new = old.apply(&patch)?;
+                if new.commit() == commitment {
+                    Ok(new)
+                } else {
+                    Err(DeltaError::CommitmentMismatch)
+                }
+            }
+        }
+    }
+}
+
+/// The version in the [DELTA_BASE] header of a request, if there is one.
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub fn delta_base<S>(req: &tide::Request<S>) -> Result<Option<u64>, tide::Error> {
+    Ok(DeltaBase::get(req)?.map(|DeltaBase(version)| version))
+}
+
+/// Ask for a [Delta] relative to `version`, which the client already has.
+#[cfg(feature = "client")]
+pub fn with_delta_base(req: surf::RequestBuilder, version: u64) -> surf::RequestBuilder {
+    req.header(DELTA_BASE, DeltaBase(version).encode())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wire::binary;
+    use commit::RawCommitmentBuilder;
+
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    struct Scan {
+        height: u64,
+        records: Vec<u64>,
+    }
+
+    json_diff!(Scan);
+
+    impl Committable for Scan {
+        fn commit(&self) -> Commitment<Self> {
+            RawCommitmentBuilder::new("Scan")
+                .var_size_bytes(&binary::serialize(self).unwrap())
+                .finalize()
+        }
+    }
+
+    #[test]
+    fn test_delta() {
+        let old = Scan {
+            height: 10,
+            records: vec![1, 2, 3],
+        };
+        let new = Scan {
+            height: 20,
+            records: vec![1, 3, 4, 5],
+        };
+
+        // A client with the base version gets a patch, which survives the wire and applies cleanly.
+        let delta = Delta::new(Some((10, &old)), 20, new.clone());
+        assert!(matches!(delta, Delta::Patch { .. }));
+        let delta: Delta<Scan> = binary::deserialize(&binary::serialize(&delta).unwrap()).unwrap();
+        assert_eq!(delta.version(), 20);
+        assert_eq!(delta.clone().apply(Some((10, &old))).unwrap(), new);
+
+        // The patch does not apply to other versions.
+        assert!(matches!(
+            delta.clone().apply(Some((11, &old))),
+            Err(DeltaError::BaseMismatch { expected: 10, .. })
+        ));
+        assert!(matches!(
+            delta.clone().apply(None),
+            Err(DeltaError::BaseMismatch { .. })
+        ));
+
+        // A patch which produces the wrong state is rejected.
+        let wrong = Scan {
+            height: 10,
+            records: vec![9, 9, 9],
+        };
+        assert!(matches!(
+            delta.apply(Some((10, &wrong))),
+            Err(DeltaError::CommitmentMismatch)
+        ));
+
+        // A client without a base version gets the full state.
+        let delta = Delta::new(None, 20, new.clone());
+        assert_eq!(delta.apply(None).unwrap(), new);
+    }
+}
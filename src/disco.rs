@@ -0,0 +1,111 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Interoperability with services built on tide-disco.
+//!
+//! Services built on tide-disco report errors using a generic envelope, `ServerError`, containing
+//! just a status code and a message. The client middleware in this crate recognizes that envelope
+//! in error responses (see [ServerError]), so a client of this crate can talk to a tide-disco
+//! service without any special configuration.
+//!
+//! With the `tide-disco` feature enabled, this module also provides conversions between
+//! implementations of [Error] and tide-disco's own error types, for services which are partway
+//! through migrating.
+
+use crate::error::Error;
+use http_types::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// The error envelope used by tide-disco services.
+///
+/// This has the same wire format as tide-disco's `ServerError`. The status is stored as a raw
+/// integer, because [StatusCode] relies on `deserialize_any`, and so cannot be deserialized from
+/// formats like bincode which are not self-describing.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ServerError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl ServerError {
+    /// The status code of this error.
+    ///
+    /// If the envelope contains a status which is not a valid HTTP status code, this returns
+    /// `InternalServerError`.
+    pub fn status(&self) -> StatusCode {
+        StatusCode::try_from(self.status).unwrap_or(StatusCode::InternalServerError)
+    }
+
+    /// Convert a tide-disco error envelope to a specific error type.
+    ///
+    /// The envelope only carries a status and a message, so the result is always constructed using
+    /// [Error::catch_all_with_status].
+    pub fn into_error<E: Error>(self) -> E {
+        E::catch_all_with_status(self.status(), self.message)
+    }
+
+    /// Wrap an [Error] in a tide-disco error envelope.
+    pub fn from_error<E: Error>(err: &E) -> Self {
+        Self {
+            status: err.status() as u16,
+            message: err.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "tide-disco")]
+mod conversions {
+    use super::*;
+    use std::fmt::Display;
+    use tide_disco::{RequestError, RouteError};
+
+    impl From<tide_disco::error::ServerError> for ServerError {
+        fn from(err: tide_disco::error::ServerError) -> Self {
+            Self {
+                status: err.status as u16,
+                message: err.message,
+            }
+        }
+    }
+
+    impl From<ServerError> for tide_disco::error::ServerError {
+        fn from(err: ServerError) -> Self {
+            Self {
+                status: err.status(),
+                message: err.message,
+            }
+        }
+    }
+
+    /// Convert a tide-disco [RequestError] into a specific error type.
+    ///
+    /// Request errors indicate a malformed request, so the result is constructed with
+    /// [Error::catch_all] using the message from the [RequestError].
+    pub fn from_request_error<E: Error>(err: RequestError) -> E {
+        E::catch_all(err.to_string())
+    }
+
+    /// Convert a tide-disco [RouteError] into a specific error type.
+    ///
+    /// Application-specific errors are converted using [Into]. All other errors are converted using
+    /// [Error::catch_all].
+    pub fn from_route_error<E: Error, F: Display + Into<E>>(err: RouteError<F>) -> E {
+        match err {
+            RouteError::AppSpecific(err) => err.into(),
+            err => E::catch_all(err.to_string()),
+        }
+    }
+
+    /// Convert an [Error] into a tide-disco [RouteError].
+    pub fn into_route_error<E: Error>(err: E) -> RouteError<E> {
+        RouteError::AppSpecific(err)
+    }
+}
+
+#[cfg(feature = "tide-disco")]
+pub use conversions::*;
@@ -0,0 +1,603 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! End-to-end encryption of request and response bodies.
+//!
+//! Some deployments terminate TLS at a proxy or load balancer which is not trusted with the
+//! contents of the requests it forwards, like the receiver memos posted to a bulletin board. For
+//! these, bodies can be encrypted to the public key of their recipient, so that only the service
+//! (or, for a response, the client) can read them.
+//!
+//! Bodies are encrypted with a hybrid scheme: the sender generates an ephemeral X25519 key, and
+//! encrypts the body with ChaCha20-Poly1305 under a key derived with HKDF-SHA256 from its
+//! Diffie-Hellman secret with the recipient's key. An [Encrypted] value carries the ephemeral
+//! public key along with the ciphertext.
+//!
+//! An encrypted body has the content type [ENCRYPTED], and its plaintext keeps the original content
+//! type. The plaintext of an encrypted request also carries a fresh reply key, to which the
+//! response is encrypted. Nothing about the response is negotiated in the clear, so a proxy cannot
+//! substitute its own reply key. The key which encrypts the response is also derived in part from
+//! the Diffie-Hellman secret of the request, which only the client and the service know, so a
+//! proxy cannot forge a response either, even though anyone can encrypt to the reply key.
+//!
+//! The [EncryptTo] client middleware encrypts each request to the service's key, including requests
+//! without a body like GETs, and the [Encryption] server middleware decrypts requests and encrypts
+//! responses. Both are transparent to the code around them.
+//!
+//! ```
+//! use net::encryption::{Encryption, StaticSecret};
+//! # fn load_key() -> [u8; 32] { [7; 32] }
+//!
+//! let secret = StaticSecret::from(load_key());
+//! let mut app = tide::new();
+//! app.with(Encryption::new(secret).required());
+//! ```
+//!
+//! Only bodies are encrypted. The method, path, query string and headers are still visible to the
+//! proxy, so private data should not be sent in URLs.
+
+use crate::wire::binary;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use snafu::Snafu;
+use std::fmt::{self, Debug, Formatter};
+use std::marker::PhantomData;
+use x25519_dalek::SharedSecret;
+
+pub use x25519_dalek::{PublicKey, StaticSecret};
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub use server::*;
+
+#[cfg(feature = "client")]
+pub use client::*;
+
+/// The content type of an encrypted body.
+pub const ENCRYPTED: &str = "application/vnd.espresso.encrypted";
+
+// Binds derived keys to this scheme, so they are never the same as keys derived for another
+// purpose from the same Diffie-Hellman secret.
+const INFO: &[u8] = b"espresso net encrypted body v1";
+
+// Binds the secret which ties a response to its request.
+const RESPONSE_INFO: &[u8] = b"espresso net encrypted response v1";
+
+/// Reasons a value cannot be encrypted or decrypted.
+#[derive(Clone, Debug, Snafu, PartialEq, Eq)]
+pub enum EncryptionError {
+    #[snafu(display("failed to serialize plaintext: {}", reason))]
+    Serialize { reason: String },
+    /// The ciphertext was not encrypted to this key, or has been modified.
+    #[snafu(display("failed to decrypt body"))]
+    Decrypt,
+    #[snafu(display("failed to deserialize plaintext: {}", reason))]
+    Deserialize { reason: String },
+}
+
+/// A value of type `T`, encrypted to the public key of its recipient.
+///
+/// The plaintext is the [binary] serialization of the value.
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Encrypted<T> {
+    /// The sender's ephemeral public key.
+    ephemeral_key: [u8; 32],
+    ciphertext: Vec<u8>,
+    #[serde(skip)]
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Encrypted<T> {
+    fn clone(&self) -> Self {
+        Self {
+            ephemeral_key: self.ephemeral_key,
+            ciphertext: self.ciphertext.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> Debug for Encrypted<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encrypted")
+            .field("ephemeral_key", &hex::encode(self.ephemeral_key))
+            .field("ciphertext_len", &self.ciphertext.len())
+            .finish()
+    }
+}
+
+impl<T: Serialize> Encrypted<T> {
+    /// Encrypt `value` so that only the holder of the secret key for `recipient` can read it.
+    pub fn seal(value: &T, recipient: &PublicKey) -> Result<Self, EncryptionError> {
+        Self::seal_from(
+            value,
+            &StaticSecret::random_from_rng(OsRng),
+            recipient,
+            None,
+        )
+    }
+
+    // Encrypt `value` from `ephemeral`, which must never be used again, mixing `binding` into the
+    // key.
+    fn seal_from(
+        value: &T,
+        ephemeral: &StaticSecret,
+        recipient: &PublicKey,
+        binding: Option<&[u8]>,
+    ) -> Result<Self, EncryptionError> {
+        let plaintext = binary::serialize(value).map_err(|err| EncryptionError::Serialize {
+            reason: err.to_string(),
+        })?;
+        let ephemeral_key = PublicKey::from(ephemeral);
+        let cipher = cipher(
+            ephemeral.diffie_hellman(recipient),
+            &ephemeral_key,
+            recipient,
+            binding,
+        );
+        let ciphertext = cipher
+            .encrypt(&Default::default(), plaintext.as_slice())
+            .map_err(|_| EncryptionError::Serialize {
+                reason: "plaintext too large".into(),
+            })?;
+        Ok(Self {
+            ephemeral_key: ephemeral_key.to_bytes(),
+            ciphertext,
+            _phantom: PhantomData,
+        })
+    }
+}
+
+impl<T: DeserializeOwned> Encrypted<T> {
+    /// Decrypt the value with the recipient's secret key.
+    pub fn open(&self, secret: &StaticSecret) -> Result<T, EncryptionError> {
+        self.open_bound(secret, None)
+    }
+
+    // Decrypt a value which was sealed with `binding`.
+    fn open_bound(
+        &self,
+        secret: &StaticSecret,
+        binding: Option<&[u8]>,
+    ) -> Result<T, EncryptionError> {
+        let ephemeral_key = PublicKey::from(self.ephemeral_key);
+        let cipher = cipher(
+            secret.diffie_hellman(&ephemeral_key),
+            &ephemeral_key,
+            &PublicKey::from(secret),
+            binding,
+        );
+        let plaintext = cipher
+            .decrypt(&Default::default(), self.ciphertext.as_slice())
+            .map_err(|_| EncryptionError::Decrypt)?;
+        binary::deserialize(&plaintext).map_err(|err| EncryptionError::Deserialize {
+            reason: err.to_string(),
+        })
+    }
+}
+
+// The cipher for a message from `ephemeral` to `recipient`, given their shared secret and an
+// optional `binding` to another secret.
+//
+// Each message is sent from a new ephemeral key, so each key derived here encrypts only one
+// message, and a fixed nonce is safe.
+fn cipher(
+    shared: SharedSecret,
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+    binding: Option<&[u8]>,
+) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(&derive(&shared, INFO, ephemeral, recipient, binding).into())
+}
+
+// The secret which binds the response to a request from `ephemeral` to `recipient`.
+fn response_binding(
+    shared: &SharedSecret,
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+) -> [u8; 32] {
+    derive(shared, RESPONSE_INFO, ephemeral, recipient, None)
+}
+
+fn derive(
+    shared: &SharedSecret,
+    purpose: &[u8],
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+    salt: Option<&[u8]>,
+) -> [u8; 32] {
+    let mut info = purpose.to_vec();
+    info.extend_from_slice(ephemeral.as_bytes());
+    info.extend_from_slice(recipient.as_bytes());
+    let mut key = [0; 32];
+    Hkdf::<Sha256>::new(salt, shared.as_bytes())
+        .expand(&info, &mut key)
+        .expect("32 bytes is a valid HKDF output length");
+    key
+}
+
+// The plaintext of an encrypted message body.
+#[derive(Debug, Serialize, Deserialize)]
+struct Payload {
+    content_type: Option<String>,
+    body: Vec<u8>,
+    // For a request, the public key to encrypt the response to.
+    reply_key: Option<[u8; 32]>,
+}
+
+impl Payload {
+    // Seal a request to `service`, returning the sealed bytes and the binding for the response.
+    fn seal_request(&self, service: &PublicKey) -> Result<(Vec<u8>, [u8; 32]), EncryptionError> {
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let sealed = Encrypted::seal_from(self, &ephemeral, service, None)?;
+        let binding = response_binding(
+            &ephemeral.diffie_hellman(service),
+            &PublicKey::from(&ephemeral),
+            service,
+        );
+        Ok((to_bytes(&sealed)?, binding))
+    }
+
+    // Open a request, returning the payload and the binding for the response.
+    fn open_request(
+        bytes: &[u8],
+        secret: &StaticSecret,
+    ) -> Result<(Self, [u8; 32]), EncryptionError> {
+        let sealed = from_bytes::<Encrypted<Self>>(bytes)?;
+        let ephemeral_key = PublicKey::from(sealed.ephemeral_key);
+        let binding = response_binding(
+            &secret.diffie_hellman(&ephemeral_key),
+            &ephemeral_key,
+            &PublicKey::from(secret),
+        );
+        Ok((sealed.open(secret)?, binding))
+    }
+
+    fn seal_response(
+        &self,
+        reply_key: &PublicKey,
+        binding: &[u8; 32],
+    ) -> Result<Vec<u8>, EncryptionError> {
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        to_bytes(&Encrypted::seal_from(
+            self,
+            &ephemeral,
+            reply_key,
+            Some(binding),
+        )?)
+    }
+
+    fn open_response(
+        bytes: &[u8],
+        reply: &StaticSecret,
+        binding: &[u8; 32],
+    ) -> Result<Self, EncryptionError> {
+        from_bytes::<Encrypted<Self>>(bytes)?.open_bound(reply, Some(binding))
+    }
+
+    fn into_body(self) -> http_types::Body {
+        let mut body = http_types::Body::from_bytes(self.body);
+        let content_type = self
+            .content_type
+            .and_then(|ty| ty.parse::<http_types::Mime>().ok());
+        if let Some(content_type) = content_type {
+            body.set_mime(content_type);
+        }
+        body
+    }
+}
+
+fn to_bytes<T: Serialize>(value: &T) -> Result<Vec<u8>, EncryptionError> {
+    binary::serialize(value).map_err(|err| EncryptionError::Serialize {
+        reason: err.to_string(),
+    })
+}
+
+fn from_bytes<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, EncryptionError> {
+    binary::deserialize(bytes).map_err(|err| EncryptionError::Deserialize {
+        reason: err.to_string(),
+    })
+}
+
+fn is_encrypted(content_type: Option<http_types::Mime>) -> bool {
+    content_type.map_or(false, |ty| ty.essence() == ENCRYPTED)
+}
+
+fn encrypted_body(bytes: Vec<u8>) -> http_types::Body {
+    let mut body = http_types::Body::from_bytes(bytes);
+    body.set_mime(ENCRYPTED);
+    body
+}
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+mod server {
+    use super::*;
+    use crate::wire::body::read_body;
+    use std::sync::Arc;
+    use tide::{Middleware, Next, Request, StatusCode};
+
+    /// Server middleware which decrypts request bodies and encrypts response bodies.
+    ///
+    /// It should be added before the other middleware, so that it sees the complete response,
+    /// including any error body. See the [module documentation](self).
+    #[derive(Clone)]
+    pub struct Encryption {
+        secret: Arc<StaticSecret>,
+        required: bool,
+    }
+
+    impl Encryption {
+        /// Decrypt requests encrypted to the public key of `secret`.
+        ///
+        /// By default, plaintext requests are also accepted, and answered in plaintext.
+        pub fn new(secret: StaticSecret) -> Self {
+            Self {
+                secret: Arc::new(secret),
+                required: false,
+            }
+        }
+
+        /// Reject plaintext requests.
+        pub fn required(mut self) -> Self {
+            self.required = true;
+            self
+        }
+
+        /// The public key clients should encrypt requests to.
+        pub fn public_key(&self) -> PublicKey {
+            PublicKey::from(&*self.secret)
+        }
+    }
+
+    #[tide::utils::async_trait]
+    impl<State: Clone + Send + Sync + 'static> Middleware<State> for Encryption {
+        async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+            if !is_encrypted(req.content_type()) {
+                if self.required {
+                    return Err(tide::Error::from_str(
+                        StatusCode::UnsupportedMediaType,
+                        format!("requests must be encrypted, as {}", ENCRYPTED),
+                    ));
+                }
+                return Ok(next.run(req).await);
+            }
+            let bytes = read_body(req.take_body()).await?;
+            let (payload, binding) = Payload::open_request(&bytes, &self.secret)
+                .map_err(|err| tide::Error::from_str(StatusCode::BadRequest, err))?;
+            let reply_key = match payload.reply_key {
+                Some(key) => PublicKey::from(key),
+                None => {
+                    return Err(tide::Error::from_str(
+                        StatusCode::BadRequest,
+                        "encrypted request has no reply key",
+                    ));
+                }
+            };
+            req.set_body(payload.into_body());
+
+            let mut res = next.run(req).await;
+            let payload = Payload {
+                content_type: res.content_type().map(|ty| ty.to_string()),
+                body: read_body(res.take_body()).await?,
+                reply_key: None,
+            };
+            let sealed = payload
+                .seal_response(&reply_key, &binding)
+                .map_err(|err| tide::Error::from_str(StatusCode::InternalServerError, err))?;
+            res.set_body(encrypted_body(sealed));
+            Ok(res)
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+mod client {
+    use super::*;
+    use crate::wire::body::read_body;
+    use surf::middleware::{Middleware, Next};
+    use surf::{Client, Request, StatusCode};
+
+    /// Client middleware which encrypts requests to a service, and decrypts its responses.
+    ///
+    /// Each request gets a new reply key, whose secret is kept only for as long as the request.
+    /// Responses which are not encrypted, or not encrypted by the service, are rejected. See the
+    /// [module documentation](self).
+    #[derive(Clone, Debug)]
+    pub struct EncryptTo {
+        service: PublicKey,
+    }
+
+    impl EncryptTo {
+        /// Encrypt requests to the service with public key `service`.
+        pub fn new(service: PublicKey) -> Self {
+            Self { service }
+        }
+    }
+
+    #[surf::utils::async_trait]
+    impl Middleware for EncryptTo {
+        async fn handle(&self, mut req: Request, client: Client, next: Next<'_>) -> surf::Result {
+            let reply = StaticSecret::random_from_rng(OsRng);
+            let payload = Payload {
+                content_type: req.content_type().map(|ty| ty.to_string()),
+                body: read_body(req.take_body()).await?,
+                reply_key: Some(PublicKey::from(&reply).to_bytes()),
+            };
+            let (sealed, binding) = payload
+                .seal_request(&self.service)
+                .map_err(|err| surf::Error::from_str(StatusCode::BadRequest, err))?;
+            req.set_body(encrypted_body(sealed));
+
+            let mut res = next.run(req, client).await?;
+            if !is_encrypted(res.content_type()) {
+                return Err(surf::Error::from_str(
+                    StatusCode::BadGateway,
+                    "service responded without encryption",
+                ));
+            }
+            let bytes = read_body(res.take_body()).await?;
+            let payload = Payload::open_response(&bytes, &reply, &binding)
+                .map_err(|err| surf::Error::from_str(StatusCode::BadGateway, err))?;
+            res.set_body(payload.into_body());
+            Ok(res)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use surf::middleware::{Middleware, Next};
+    use surf::{Client, Request, StatusCode};
+
+    #[test]
+    fn test_encrypted() {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        let sealed = Encrypted::seal(&"memo".to_string(), &public).unwrap();
+        assert_eq!(sealed.open(&secret).unwrap(), "memo");
+
+        // Only the recipient can decrypt, and any change to the ciphertext is detected.
+        let other = StaticSecret::random_from_rng(OsRng);
+        assert_eq!(sealed.open(&other), Err(EncryptionError::Decrypt));
+        let mut tampered = sealed.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert_eq!(tampered.open(&secret), Err(EncryptionError::Decrypt));
+
+        // Each message is encrypted under a new key.
+        let again = Encrypted::seal(&"memo".to_string(), &public).unwrap();
+        assert_ne!(again.ciphertext, sealed.ciphertext);
+    }
+
+    // Serve requests from a client using `app`, recording the request bodies a proxy in between
+    // would see.
+    struct Server(tide::Server<()>, Arc<Mutex<Vec<Vec<u8>>>>);
+
+    #[surf::utils::async_trait]
+    impl Middleware for Server {
+        async fn handle(&self, req: Request, _client: Client, _next: Next<'_>) -> surf::Result {
+            let mut req: surf::http::Request = req.into();
+            let body = req.body_bytes().await?;
+            self.1.lock().unwrap().push(body.clone());
+            req.set_body(body);
+            let res: surf::http::Response = self.0.respond(req).await?;
+            Ok(res.into())
+        }
+    }
+
+    #[async_std::test]
+    async fn test_encryption() {
+        let encryption = Encryption::new(StaticSecret::random_from_rng(OsRng)).required();
+        let mut app = tide::new();
+        app.with(encryption.clone());
+        app.at("/memos")
+            .post(|mut req: tide::Request<()>| async move {
+                let memo: String = req.body_json().await?;
+                Ok(tide::Body::from_json(&format!("posted {}", memo))?)
+            })
+            .get(|_| async { Ok(tide::Body::from_json(&"secret memos")?) });
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let client = Client::new()
+            .with(EncryptTo::new(encryption.public_key()))
+            .with(Server(app, seen.clone()));
+        let mut res = client
+            .post("http://localhost/memos")
+            .body(surf::Body::from_json(&"secret memo").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let posted: String = res.body_json().await.unwrap();
+        assert_eq!(posted, "posted secret memo");
+        assert!(!seen.lock().unwrap()[0]
+            .windows(6)
+            .any(|bytes| bytes == b"secret"));
+
+        // Requests without a body are encrypted too, so that they carry a reply key.
+        let mut res = client.get("http://localhost/memos").await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        let memos: String = res.body_json().await.unwrap();
+        assert_eq!(memos, "secret memos");
+        assert!(!seen.lock().unwrap()[1].is_empty());
+
+        // Plaintext requests are rejected, with or without a body.
+        let mut app = tide::new();
+        app.with(encryption);
+        app.at("/memos")
+            .post(|_| async { Ok("") })
+            .get(|_| async { Ok("") });
+        let client = Client::new().with(Server(app, Default::default()));
+        let res = client
+            .post("http://localhost/memos")
+            .body("secret memo")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UnsupportedMediaType);
+        let res = client.get("http://localhost/memos").await.unwrap();
+        assert_eq!(res.status(), StatusCode::UnsupportedMediaType);
+    }
+
+    // A proxy which adds a reply key of its own to each request, in the header which once carried
+    // it, and tries to read the response with it, and replaces the response with one of its own.
+    struct Proxy {
+        secret: StaticSecret,
+        opened: Arc<Mutex<Vec<bool>>>,
+    }
+
+    #[surf::utils::async_trait]
+    impl Middleware for Proxy {
+        async fn handle(&self, mut req: Request, client: Client, next: Next<'_>) -> surf::Result {
+            req.insert_header(
+                "X-Reply-Key",
+                hex::encode(PublicKey::from(&self.secret).as_bytes()),
+            );
+            let mut res = next.run(req, client).await?;
+            let bytes = res.body_bytes().await?;
+            let opened = Payload::open_response(&bytes, &self.secret, &[0; 32]).is_ok()
+                || from_bytes::<Encrypted<Payload>>(&bytes)
+                    .and_then(|sealed| sealed.open(&self.secret))
+                    .is_ok();
+            self.opened.lock().unwrap().push(opened);
+
+            // Anyone can encrypt to a public key, but the proxy does not know the client's reply
+            // key, nor the secret the response key is derived from.
+            let forged = Payload {
+                content_type: Some("application/json".into()),
+                body: br#""forged""#.to_vec(),
+                reply_key: None,
+            };
+            let sealed = to_bytes(&Encrypted::seal(&forged, &PublicKey::from(&self.secret))?)?;
+            res.set_body(encrypted_body(sealed));
+            Ok(res)
+        }
+    }
+
+    #[async_std::test]
+    async fn test_proxy() {
+        let encryption = Encryption::new(StaticSecret::random_from_rng(OsRng)).required();
+        let mut app = tide::new();
+        app.with(encryption.clone());
+        app.at("/memos")
+            .get(|_| async { Ok(tide::Body::from_json(&"secret memos")?) });
+
+        let opened = Arc::new(Mutex::new(Vec::new()));
+        let client = Client::new()
+            .with(EncryptTo::new(encryption.public_key()))
+            .with(Proxy {
+                secret: StaticSecret::random_from_rng(OsRng),
+                opened: opened.clone(),
+            })
+            .with(Server(app, Default::default()));
+        let err = client.get("http://localhost/memos").await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::BadGateway);
+        assert_eq!(*opened.lock().unwrap(), [false]);
+    }
+}
@@ -22,17 +22,97 @@ use snafu::{ErrorCompat, IntoError};
 /// instance and encoded as an API error using the `catch_all` function.
 pub trait Error: std::error::Error + Serialize + DeserializeOwned + Send + Sync + 'static {
     fn catch_all(msg: String) -> Self;
-    fn status(&self) -> tide::StatusCode;
+    fn status(&self) -> http_types::StatusCode;
+
+    /// A catch-all error for a failure which was reported with the HTTP status `status`.
+    ///
+    /// This is used instead of [catch_all](Self::catch_all) whenever the status of the failure is
+    /// known, for example when an error response from a server cannot be deserialized as `Self`.
+    /// The default implementation ignores `status`. Types which can represent it should override
+    /// this, and return `status` from [status](Self::status), so that clients can tell a 404 from
+    /// a 500 even when the server did not send a `Self`.
+    fn catch_all_with_status(status: http_types::StatusCode, msg: String) -> Self {
+        let _ = status;
+        Self::catch_all(msg)
+    }
+
+    /// A URI identifying the kind of this error, for the `type` member of
+    /// [problem details](crate::wire::problem).
+    ///
+    /// The default implementation returns [None], and problems are given the generic type
+    /// `about:blank`.
+    fn problem_type(&self) -> Option<String> {
+        None
+    }
+
+    /// The error for a request to `path`, which matches no route.
+    ///
+    /// The default implementation is a catch-all error with status 404, and a message which does
+    /// not change between releases. Types with a variant for missing resources may override this.
+    fn route_not_found(path: &str) -> Self {
+        Self::catch_all_with_status(
+            http_types::StatusCode::NotFound,
+            format!("no route for {}", path),
+        )
+    }
+
+    /// The error for a request to `path`, whose route has no handler for `method`.
+    ///
+    /// The default implementation is a catch-all error with status 405, and a message which does
+    /// not change between releases.
+    fn method_not_allowed(method: http_types::Method, path: &str) -> Self {
+        Self::catch_all_with_status(
+            http_types::StatusCode::MethodNotAllowed,
+            format!("method {} not allowed for {}", method, path),
+        )
+    }
 
     /// Convert from a generic client-side error to a specific error type.
     ///
-    /// If `source` can be downcast to `Self`, it is simply downcasted. Otherwise, it is converted
-    /// to a [String] using [Display] and then converted to `Self` using [catch_all].
-    fn from_client_error(source: surf::Error) -> Self {
-        match source.downcast::<Self>() {
-            Ok(err) => err,
-            Err(err) => Self::catch_all(err.to_string()),
-        }
+    /// If `source` can be downcast to `Self`, it is simply downcasted. Otherwise, the chain of
+    /// causes of `source` is searched for a `Self`, so that errors wrapped in context or in other
+    /// error types are not lost, and then for a cause whose message is a JSON-serialized `Self`, as
+    /// produced by services which report errors as strings. If all else fails, `source` is converted
+    /// to a [String] using [Display] and then converted to `Self` using
+    /// [catch_all_with_status](Self::catch_all_with_status), with the status of `source`.
+    fn from_client_error(source: http_types::Error) -> Self {
+        let source = match source.downcast::<Self>() {
+            Ok(err) => return err,
+            Err(source) => source,
+        };
+        let status = source.status();
+        let source = source.into_inner();
+        source
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<Self>().and_then(copy_error))
+            .or_else(|| {
+                source
+                    .chain()
+                    .find_map(|cause| embedded_error(&cause.to_string()))
+            })
+            .unwrap_or_else(|| Self::catch_all_with_status(status, source.to_string()))
+    }
+}
+
+// `Error` does not require `Clone`, but it does require serialization, so an error found by
+// reference can be copied by round-tripping it through JSON.
+fn copy_error<E: Error>(err: &E) -> Option<E> {
+    serde_json::to_value(err)
+        .and_then(serde_json::from_value)
+        .ok()
+}
+
+// Deserialize an `E` from an error message which is, or contains, a JSON object.
+fn embedded_error<E: Error>(msg: &str) -> Option<E> {
+    if let Ok(err) = serde_json::from_str(msg) {
+        return Some(err);
+    }
+    let start = msg.find('{')?;
+    let end = msg.rfind('}')?;
+    if start < end {
+        serde_json::from_str(&msg[start..=end]).ok()
+    } else {
+        None
     }
 }
 
@@ -63,9 +143,9 @@ pub trait Error: std::error::Error + Serialize + DeserializeOwned + Send + Sync
 /// form `(...) -> Result<impl Serialize, impl Error>` and then calls `server_error` internal. This
 /// would also be a good place to put other common server-related code, such as parsing api.toml and
 /// route dispatching.
-pub fn server_error<E: Error>(error: impl Into<E>) -> tide::Error {
+pub fn server_error<E: Error>(error: impl Into<E>) -> http_types::Error {
     let error = error.into();
-    tide::Error::new(error.status(), error)
+    http_types::Error::new(error.status(), error)
 }
 
 /// Context for embedding network client errors into specific error types.
@@ -73,16 +153,16 @@ pub fn server_error<E: Error>(error: impl Into<E>) -> tide::Error {
 /// This type implements the [IntoError] trait from SNAFU, so it can be used with
 /// [ResultExt::context] just like automatically generated SNAFU contexts.
 ///
-/// Calling `some_result.context(ClientError)` will convert a potential error from a [surf::Error]
-/// to a specific error type `E` using the method `E::from_client_error`, provided by the
-/// [Error] trait.
+/// Calling `some_result.context(ClientError)` will convert a potential error from a `surf::Error`
+/// (which is an [http_types::Error]) to a specific error type `E` using the method
+/// `E::from_client_error`, provided by the [Error] trait.
 ///
 /// This is the inverse of [server_error], and can be used on the client side to recover errors
 /// which were generated on the server using [server_error].
 pub struct ClientError;
 
 impl<E: Error + ErrorCompat + std::error::Error> IntoError<E> for ClientError {
-    type Source = surf::Error;
+    type Source = http_types::Error;
 
     fn into_error(self, source: Self::Source) -> E {
         E::from_client_error(source)
@@ -92,11 +172,136 @@ impl<E: Error + ErrorCompat + std::error::Error> IntoError<E> for ClientError {
 /// Convert a concrete error type into a client error.
 ///
 /// The error is first converted into an [Error] using the [Into] instance. That error is then
-/// upcasted into an anyhow error to be embedded in the [surf::Error].
+/// upcasted into an anyhow error to be embedded in the [http_types::Error], which is also a
+/// `surf::Error`.
 ///
 /// This is the equivalent for [server_error] for errors generated on the client side; for instance,
 /// in middleware.
-pub fn client_error<E: Error>(error: impl Into<E>) -> surf::Error {
+pub fn client_error<E: Error>(error: impl Into<E>) -> http_types::Error {
     let error = error.into();
-    surf::Error::new(error.status(), error)
+    http_types::Error::new(error.status(), error)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http_types::StatusCode;
+    use serde::Deserialize;
+    use snafu::Snafu;
+    use std::convert::TryFrom;
+
+    #[derive(Clone, Debug, Deserialize, Serialize, Snafu, PartialEq, Eq)]
+    #[snafu(display("error {}: {}", code, msg))]
+    struct QueryError {
+        code: u16,
+        msg: String,
+    }
+
+    impl Error for QueryError {
+        fn catch_all(msg: String) -> Self {
+            Self { code: 0, msg }
+        }
+
+        fn status(&self) -> StatusCode {
+            StatusCode::BadRequest
+        }
+    }
+
+    #[derive(Debug, Snafu)]
+    #[snafu(display("wallet error"))]
+    struct WalletError {
+        source: QueryError,
+    }
+
+    fn query_error() -> QueryError {
+        QueryError {
+            code: 7,
+            msg: "no such block".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_from_client_error() {
+        // A nested error is found in the chain of causes.
+        let err = http_types::Error::new(
+            StatusCode::BadRequest,
+            WalletError {
+                source: query_error(),
+            },
+        );
+        assert_eq!(QueryError::from_client_error(err), query_error());
+
+        // So is an error with added context.
+        let err = anyhow::Error::new(query_error()).context("while syncing");
+        let err = http_types::Error::new(StatusCode::BadRequest, err);
+        assert_eq!(QueryError::from_client_error(err), query_error());
+
+        // An error reported as a string is deserialized.
+        let json = serde_json::to_string(&query_error()).unwrap();
+        let err =
+            http_types::Error::from_str(StatusCode::BadRequest, format!("upstream: {}", json));
+        assert_eq!(QueryError::from_client_error(err), query_error());
+
+        // Anything else is a catch-all.
+        let err = http_types::Error::from_str(StatusCode::BadRequest, "timed out");
+        assert_eq!(
+            QueryError::from_client_error(err),
+            QueryError::catch_all("timed out".to_string())
+        );
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize, Snafu, PartialEq, Eq)]
+    #[snafu(display("{}", msg))]
+    struct StatusError {
+        status: u16,
+        msg: String,
+    }
+
+    impl Error for StatusError {
+        fn catch_all(msg: String) -> Self {
+            Self::catch_all_with_status(StatusCode::InternalServerError, msg)
+        }
+
+        fn catch_all_with_status(status: StatusCode, msg: String) -> Self {
+            Self {
+                status: status.into(),
+                msg,
+            }
+        }
+
+        fn status(&self) -> StatusCode {
+            StatusCode::try_from(self.status).unwrap_or(StatusCode::InternalServerError)
+        }
+    }
+
+    #[test]
+    fn test_catch_all_status() {
+        let err = http_types::Error::from_str(StatusCode::NotFound, "no such block");
+        assert_eq!(
+            StatusError::from_client_error(err).status(),
+            StatusCode::NotFound
+        );
+
+        // Types which don't override `catch_all_with_status` are unaffected.
+        let err = http_types::Error::from_str(StatusCode::NotFound, "no such block");
+        assert_eq!(
+            QueryError::from_client_error(err).status(),
+            StatusCode::BadRequest
+        );
+    }
+
+    #[cfg(feature = "client")]
+    #[async_std::test]
+    async fn test_catch_all_status_response() {
+        // The status of an error response which is not a `StatusError` is preserved.
+        let mut res = http_types::Response::new(StatusCode::NotFound);
+        res.set_body("no such block");
+        let err = crate::client::response_to_result::<StatusError>(res.into())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<StatusError>().unwrap().status(),
+            StatusCode::NotFound
+        );
+    }
 }
@@ -0,0 +1,323 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Selecting the fields of a response.
+//!
+//! Blocks and other large objects are mostly things a given client does not need: a wallet on a
+//! phone showing recent blocks wants their heights and timestamps, not every transaction and proof.
+//! A client can ask for only the parts it needs with a comma-separated list of dotted paths in the
+//! [FIELDS_PARAM] query parameter, as in `block/10?fields=height,header.timestamp`. A path selects
+//! a field and everything under it. Paths through an array select from each of its elements, so
+//! `fields=transactions.hash` selects the hash of every transaction.
+//!
+//! [response](crate::server::response) trims JSON responses to the selected fields, and leaves
+//! binary responses alone, since a binary response without some of its fields could not be
+//! deserialized. Types which can do better implement [Project], which resets the fields which were
+//! not selected to cheap defaults without changing the shape of the type, and are served with
+//! [response_projected](crate::server::response_projected):
+//!
+//! ```
+//! use net::fields::{Fields, Project};
+//!
+//! #[derive(Default)]
+//! struct Header {
+//!     height: u64,
+//!     timestamp: u64,
+//! }
+//!
+//! impl Project for Header {
+//!     fn project(&mut self, fields: &Fields) {
+//!         fields.retain("height", &mut self.height);
+//!         fields.retain("timestamp", &mut self.timestamp);
+//!     }
+//! }
+//!
+//! struct Block {
+//!     header: Header,
+//!     transactions: Vec<Vec<u8>>,
+//! }
+//!
+//! impl Project for Block {
+//!     fn project(&mut self, fields: &Fields) {
+//!         fields.retain_projected("header", &mut self.header);
+//!         fields.retain("transactions", &mut self.transactions);
+//!     }
+//! }
+//!
+//! let mut block = Block {
+//!     header: Header { height: 10, timestamp: 1650000000 },
+//!     transactions: vec![vec![0; 1024]],
+//! };
+//! block.project(&"header.height".parse().unwrap());
+//! assert_eq!(block.header.height, 10);
+//! assert_eq!(block.header.timestamp, 0);
+//! assert!(block.transactions.is_empty());
+//! ```
+//!
+//! Implementations of [Project] should use the same names for fields as the JSON serialization of
+//! the type, so that a selection means the same thing in either format.
+//!
+//! [FIELDS_PARAM] is a reserved query parameter: every route served with
+//! [response](crate::server::response) honours it, so routes must not use a parameter of that name
+//! for anything else. Responses which are only meaningful whole, like
+//! [signed responses](crate::signing), are served with
+//! [response_untrimmed](crate::server::response_untrimmed), which ignores it. Responses sent with
+//! [respond](crate::server::respond) are not trimmed either, since it does not see the request.
+
+use serde_json::Value;
+use snafu::Snafu;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// The query parameter in which a client selects the fields of a response.
+///
+/// This is reserved on every route; see the [module documentation](self).
+pub const FIELDS_PARAM: &str = "fields";
+
+#[derive(Clone, Debug, Snafu, PartialEq, Eq)]
+pub enum FieldsError {
+    #[snafu(display("no fields selected"))]
+    Empty,
+    #[snafu(display("invalid field path {:?}", path))]
+    InvalidPath { path: String },
+}
+
+/// A selection of fields, parsed from a list of dotted paths like `height,header.timestamp`.
+///
+/// A selection which selects nothing in particular selects everything; this is what a path
+/// selects beneath the field it ends at.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Fields {
+    children: BTreeMap<String, Fields>,
+}
+
+impl Fields {
+    /// Select everything.
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    /// Whether this selection selects everything.
+    pub fn is_all(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    /// The selection within the field `name`, or [None] if `name` is not selected.
+    pub fn get(&self, name: &str) -> Option<&Fields> {
+        if self.is_all() {
+            // Everything under a field which is selected in full is also selected in full.
+            Some(self)
+        } else {
+            self.children.get(name)
+        }
+    }
+
+    /// Whether the field `name` is selected, in part or in full.
+    pub fn has(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Reset `field` to its default value unless `name` is selected.
+    pub fn retain<T: Default>(&self, name: &str, field: &mut T) {
+        if !self.has(name) {
+            *field = T::default();
+        }
+    }
+
+    /// Project `field` onto the selection within `name`, or reset it if `name` is not selected.
+    pub fn retain_projected<T: Default + Project>(&self, name: &str, field: &mut T) {
+        match self.get(name) {
+            Some(fields) => field.project(fields),
+            None => *field = T::default(),
+        }
+    }
+
+    /// Remove the fields of `value` which are not selected.
+    ///
+    /// Selections apply to each element of an array. Scalars are left alone, even if the selection
+    /// names fields within them.
+    pub fn select(&self, value: &mut Value) {
+        if self.is_all() {
+            return;
+        }
+        match value {
+            Value::Object(map) => {
+                *map = std::mem::take(map)
+                    .into_iter()
+                    .filter_map(|(name, mut value)| {
+                        self.children.get(&name)?.select(&mut value);
+                        Some((name, value))
+                    })
+                    .collect();
+            }
+            Value::Array(items) => {
+                for item in items {
+                    self.select(item);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn insert(&mut self, path: &[&str]) {
+        let (name, rest) = match path.split_first() {
+            Some(split) => split,
+            None => return,
+        };
+        match self.children.get_mut(*name) {
+            // Already selected in full.
+            Some(child) if child.is_all() => {}
+            Some(child) if rest.is_empty() => child.children.clear(),
+            Some(child) => child.insert(rest),
+            None => {
+                let mut child = Fields::all();
+                child.insert(rest);
+                self.children.insert(name.to_string(), child);
+            }
+        }
+    }
+
+    fn fmt_paths(&self, prefix: &str, first: &mut bool, f: &mut Formatter) -> fmt::Result {
+        for (name, child) in &self.children {
+            let path = format!("{}{}", prefix, name);
+            if child.is_all() {
+                if !*first {
+                    write!(f, ",")?;
+                }
+                *first = false;
+                write!(f, "{}", path)?;
+            } else {
+                child.fmt_paths(&format!("{}.", path), first, f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Fields {
+    type Err = FieldsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().is_empty() {
+            return Err(FieldsError::Empty);
+        }
+        let mut fields = Fields::all();
+        for path in s.split(',') {
+            let path = path.trim();
+            let names = path.split('.').collect::<Vec<_>>();
+            if names.iter().any(|name| name.is_empty()) {
+                return Err(FieldsError::InvalidPath {
+                    path: path.to_string(),
+                });
+            }
+            fields.insert(&names);
+        }
+        Ok(fields)
+    }
+}
+
+/// Formats the selection as it is sent in [FIELDS_PARAM].
+impl Display for Fields {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.fmt_paths("", &mut true, f)
+    }
+}
+
+/// Types which can be trimmed to a selection of their fields.
+///
+/// Projecting a value resets the fields which are not selected to cheap defaults (empty vectors,
+/// [None], zeros), leaving the type the same so that it can still be serialized and deserialized
+/// in binary.
+pub trait Project {
+    fn project(&mut self, fields: &Fields);
+}
+
+impl<T: Project> Project for Vec<T> {
+    fn project(&mut self, fields: &Fields) {
+        for item in self {
+            item.project(fields);
+        }
+    }
+}
+
+impl<T: Project> Project for Option<T> {
+    fn project(&mut self, fields: &Fields) {
+        if let Some(value) = self {
+            value.project(fields);
+        }
+    }
+}
+
+impl<T: Project + ?Sized> Project for Box<T> {
+    fn project(&mut self, fields: &Fields) {
+        (**self).project(fields);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse() {
+        let fields = "height, header.timestamp,header.proposer"
+            .parse::<Fields>()
+            .unwrap();
+        assert!(fields.has("height"));
+        assert!(fields.get("height").unwrap().is_all());
+        assert!(!fields.has("transactions"));
+        let header = fields.get("header").unwrap();
+        assert!(header.has("timestamp") && header.has("proposer"));
+        assert!(!header.has("height"));
+        assert_eq!(
+            fields.to_string(),
+            "header.proposer,header.timestamp,height"
+        );
+
+        // Selecting a field in full takes precedence over selecting part of it, in either order.
+        for s in ["header,header.timestamp", "header.timestamp,header"] {
+            let fields = s.parse::<Fields>().unwrap();
+            assert!(fields.get("header").unwrap().is_all(), "{}", s);
+            assert_eq!(fields.to_string(), "header");
+        }
+
+        assert_eq!("".parse::<Fields>(), Err(FieldsError::Empty));
+        for s in ["a,,b", "a.", ".a", "a..b"] {
+            assert!(
+                matches!(s.parse::<Fields>(), Err(FieldsError::InvalidPath { .. })),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn test_select() {
+        let mut block = json!({
+            "height": 10,
+            "header": {"timestamp": 1650000000, "proposer": "alice"},
+            "transactions": [
+                {"hash": "a", "proof": [1, 2, 3]},
+                {"hash": "b", "proof": [4, 5, 6]},
+            ],
+        });
+        let fields = "height,header.timestamp,transactions.hash,height.missing"
+            .parse::<Fields>()
+            .unwrap();
+        fields.select(&mut block);
+        assert_eq!(
+            block,
+            json!({
+                "height": 10,
+                "header": {"timestamp": 1650000000},
+                "transactions": [{"hash": "a"}, {"hash": "b"}],
+            })
+        );
+    }
+}
@@ -0,0 +1,528 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Filters for subscriptions to event streams.
+//!
+//! A light client subscribed to a stream of events usually cares about very few of them: the ones
+//! touching its own addresses, or the assets it holds. Rather than receive every event and discard
+//! most, it can attach a [Filter] to its subscription, which the server evaluates before sending
+//! each event. Events say which addresses, assets and so on they involve by implementing
+//! [Filterable].
+//!
+//! ```
+//! use net::filter::Filter;
+//! # fn f(alice: net::UserAddress, bob: net::UserAddress) {
+//!
+//! // Memos for either of our addresses.
+//! let filter = Filter::kind("memo").and(Filter::Address(alice).or(Filter::Address(bob)));
+//! # }
+//! ```
+//!
+//! # Bloom filters
+//!
+//! A filter naming the client's addresses tells the server exactly which addresses are the
+//! client's. A [BloomFilter] lets a client narrow the events it receives without revealing that:
+//! the client inserts its addresses and record commitments, and the server sends the events
+//! involving anything which might be in the filter. Bloom filters have false positives, and the
+//! false positive rate is the privacy knob: at a rate of 1%, each of the client's addresses hides
+//! among about 1% of all addresses, and the client receives about 1% of the events which are not
+//! its own. A higher rate hides the client's addresses better, at the cost of bandwidth.
+//!
+//! ```
+//! use net::filter::{BloomFilter, Filter};
+//! # fn f(alice: net::UserAddress) {
+//!
+//! let mut bloom = BloomFilter::new(10, 0.01);
+//! bloom.insert_address(&alice);
+//! let filter = Filter::kind("memo").and(Filter::Bloom(bloom));
+//! # }
+//! ```
+//!
+//! Events are matched against Bloom filters by their [keys](Filterable::bloom_keys).
+//!
+//! Filters come from clients, so servers should not evaluate arbitrarily large ones. A filter can
+//! have at most [MAX_TERMS] terms, and Bloom filters of at most [MAX_BLOOM_BYTES] in total, which
+//! deserialization enforces. To make that possible without
+//! unbounded recursion, a filter is serialized as a flat list of its terms in prefix order, where
+//! `and` and `or` record how many operands follow them: `{"and": 2}, {"kind": "memo"}, "all"`.
+
+use crate::types::UserAddress;
+use crate::wire::binary;
+use jf_cap::structs::AssetCode;
+use jf_cap::structs::RecordCommitment;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::Snafu;
+use std::convert::TryFrom;
+
+/// The maximum number of terms in a [Filter].
+pub const MAX_TERMS: usize = 64;
+
+/// The maximum total size of the [Bloom filters](BloomFilter) in a [Filter], in bytes.
+pub const MAX_BLOOM_BYTES: usize = 1 << 14;
+
+/// The maximum number of hash functions of a [BloomFilter].
+pub const MAX_BLOOM_HASHES: u32 = 32;
+
+/// An event which can be matched against a [Filter].
+///
+/// Each method has a default implementation which matches nothing, so events only implement the
+/// methods which apply to them.
+pub trait Filterable {
+    /// The kind of this event, like `memo` or `block`.
+    fn kind(&self) -> Option<&str> {
+        None
+    }
+
+    /// Whether this event involves `address`.
+    fn touches_address(&self, _address: &UserAddress) -> bool {
+        false
+    }
+
+    /// Whether this event involves the asset with code `asset`.
+    fn touches_asset(&self, _asset: &AssetCode) -> bool {
+        false
+    }
+
+    /// The keys of the addresses and records this event involves, computed with [address_key]
+    /// and [commitment_key], for matching against [Bloom filters](BloomFilter).
+    fn bloom_keys(&self) -> Vec<Vec<u8>> {
+        vec![]
+    }
+}
+
+/// The key of an address in a [BloomFilter].
+pub fn address_key(address: &UserAddress) -> Vec<u8> {
+    bloom_key(b"ADDR", address)
+}
+
+/// The key of a record commitment in a [BloomFilter].
+pub fn commitment_key(commitment: &RecordCommitment) -> Vec<u8> {
+    bloom_key(b"REC", commitment)
+}
+
+fn bloom_key<T: Serialize>(tag: &[u8], value: &T) -> Vec<u8> {
+    // Addresses and commitments always serialize.
+    [tag, &binary::serialize(value).unwrap()].concat()
+}
+
+/// A Bloom filter over the keys of addresses and records.
+///
+/// See [Bloom filters](self#bloom-filters).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// An empty filter sized to hold `items` keys with the given false positive rate.
+    ///
+    /// The filter is capped at [MAX_BLOOM_BYTES], so for very many items or very low rates, the
+    /// actual false positive rate will be higher.
+    pub fn new(items: usize, false_positive_rate: f64) -> Self {
+        let items = items.max(1) as f64;
+        let rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 1.0);
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-items * rate.ln() / (ln2 * ln2)).ceil();
+        let bytes = ((bits / 8.0).ceil() as usize).clamp(1, MAX_BLOOM_BYTES);
+        let hashes = ((bytes * 8) as f64 / items * ln2).round() as u32;
+        Self {
+            bits: vec![0; bytes],
+            hashes: hashes.clamp(1, MAX_BLOOM_HASHES),
+        }
+    }
+
+    /// A filter from its bits and its number of hash functions.
+    pub fn from_parts(bits: Vec<u8>, hashes: u32) -> Result<Self, FilterError> {
+        if bits.is_empty()
+            || bits.len() > MAX_BLOOM_BYTES
+            || hashes == 0
+            || hashes > MAX_BLOOM_HASHES
+        {
+            return Err(FilterError::InvalidBloomFilter);
+        }
+        Ok(Self { bits, hashes })
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for i in self.indices(key) {
+            self.bits[i / 8] |= 1 << (i % 8);
+        }
+    }
+
+    pub fn insert_address(&mut self, address: &UserAddress) {
+        self.insert(&address_key(address));
+    }
+
+    pub fn insert_commitment(&mut self, commitment: &RecordCommitment) {
+        self.insert(&commitment_key(commitment));
+    }
+
+    /// Whether `key` may have been inserted into the filter.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.indices(key)
+            .all(|i| self.bits[i / 8] & (1 << (i % 8)) != 0)
+    }
+
+    /// The expected false positive rate of this filter once `items` keys have been inserted.
+    pub fn false_positive_rate(&self, items: usize) -> f64 {
+        let bits = (self.bits.len() * 8) as f64;
+        let hashes = self.hashes as f64;
+        (1.0 - (-hashes * items as f64 / bits).exp()).powf(hashes)
+    }
+
+    // The bits of `key`, by double hashing: the i-th bit is `h1 + i * h2`, modulo the size.
+    fn indices(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let digest = Sha256::digest(key);
+        let mut h1 = [0; 8];
+        let mut h2 = [0; 8];
+        h1.copy_from_slice(&digest[..8]);
+        h2.copy_from_slice(&digest[8..16]);
+        let h1 = u64::from_le_bytes(h1);
+        // An odd step visits distinct bits for as many hashes as there are bits.
+        let h2 = u64::from_le_bytes(h2) | 1;
+        let bits = (self.bits.len() * 8) as u64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+}
+
+impl<'de> Deserialize<'de> for BloomFilter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Parts {
+            bits: Vec<u8>,
+            hashes: u32,
+        }
+
+        let Parts { bits, hashes } = Parts::deserialize(deserializer)?;
+        Self::from_parts(bits, hashes).map_err(D::Error::custom)
+    }
+}
+
+/// A predicate on events.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "Vec<Term>", try_from = "Vec<Term>")]
+pub enum Filter {
+    /// Matches every event.
+    All,
+    /// Matches events of the given [kind](Filterable::kind).
+    Kind(String),
+    /// Matches events which [touch](Filterable::touches_address) the address.
+    Address(UserAddress),
+    /// Matches events which [touch](Filterable::touches_asset) the asset.
+    Asset(AssetCode),
+    /// Matches events with any [key](Filterable::bloom_keys) which may be in the Bloom filter.
+    Bloom(BloomFilter),
+    /// Matches events matching every one of the filters.
+    And(Vec<Filter>),
+    /// Matches events matching any one of the filters.
+    Or(Vec<Filter>),
+    /// Matches events which do not match the filter.
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    pub fn kind(kind: impl Into<String>) -> Self {
+        Self::Kind(kind.into())
+    }
+
+    pub fn and(self, other: Filter) -> Self {
+        match self {
+            Self::And(mut filters) => {
+                filters.push(other);
+                Self::And(filters)
+            }
+            filter => Self::And(vec![filter, other]),
+        }
+    }
+
+    pub fn or(self, other: Filter) -> Self {
+        match self {
+            Self::Or(mut filters) => {
+                filters.push(other);
+                Self::Or(filters)
+            }
+            filter => Self::Or(vec![filter, other]),
+        }
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> Self {
+        Self::Not(Box::new(self))
+    }
+
+    /// Whether `event` matches this filter.
+    pub fn matches<T: Filterable + ?Sized>(&self, event: &T) -> bool {
+        match self {
+            Self::All => true,
+            Self::Kind(kind) => event.kind() == Some(kind.as_str()),
+            Self::Address(address) => event.touches_address(address),
+            Self::Asset(asset) => event.touches_asset(asset),
+            Self::Bloom(bloom) => event.bloom_keys().iter().any(|key| bloom.contains(key)),
+            Self::And(filters) => filters.iter().all(|filter| filter.matches(event)),
+            Self::Or(filters) => filters.iter().any(|filter| filter.matches(event)),
+            Self::Not(filter) => !filter.matches(event),
+        }
+    }
+
+    /// The number of terms in this filter.
+    pub fn terms(&self) -> usize {
+        match self {
+            Self::And(filters) | Self::Or(filters) => {
+                1 + filters.iter().map(Self::terms).sum::<usize>()
+            }
+            Self::Not(filter) => 1 + filter.terms(),
+            _ => 1,
+        }
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self::All
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum FilterError {
+    #[snafu(display(
+        "filter has more than {} terms, or more than {} bytes of Bloom filters",
+        MAX_TERMS,
+        MAX_BLOOM_BYTES
+    ))]
+    TooLarge,
+    #[snafu(display("filter is malformed"))]
+    Malformed,
+    #[snafu(display(
+        "Bloom filter must have 1 to {} bytes and 1 to {} hash functions",
+        MAX_BLOOM_BYTES,
+        MAX_BLOOM_HASHES
+    ))]
+    InvalidBloomFilter,
+}
+
+/// One term of a [Filter], in its serialized form.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Term {
+    All,
+    Kind(String),
+    Address(UserAddress),
+    Asset(AssetCode),
+    Bloom(BloomFilter),
+    And(u32),
+    Or(u32),
+    Not,
+}
+
+impl From<Filter> for Vec<Term> {
+    fn from(filter: Filter) -> Self {
+        fn push(terms: &mut Vec<Term>, filter: Filter) {
+            match filter {
+                Filter::All => terms.push(Term::All),
+                Filter::Kind(kind) => terms.push(Term::Kind(kind)),
+                Filter::Address(address) => terms.push(Term::Address(address)),
+                Filter::Asset(asset) => terms.push(Term::Asset(asset)),
+                Filter::Bloom(bloom) => terms.push(Term::Bloom(bloom)),
+                Filter::And(filters) => {
+                    terms.push(Term::And(filters.len() as u32));
+                    filters.into_iter().for_each(|filter| push(terms, filter));
+                }
+                Filter::Or(filters) => {
+                    terms.push(Term::Or(filters.len() as u32));
+                    filters.into_iter().for_each(|filter| push(terms, filter));
+                }
+                Filter::Not(filter) => {
+                    terms.push(Term::Not);
+                    push(terms, *filter);
+                }
+            }
+        }
+
+        let mut terms = vec![];
+        push(&mut terms, filter);
+        terms
+    }
+}
+
+impl TryFrom<Vec<Term>> for Filter {
+    type Error = FilterError;
+
+    fn try_from(terms: Vec<Term>) -> Result<Self, Self::Error> {
+        let bloom_bytes = terms
+            .iter()
+            .map(|term| match term {
+                Term::Bloom(bloom) => bloom.bits.len(),
+                _ => 0,
+            })
+            .sum::<usize>();
+        if terms.len() > MAX_TERMS || bloom_bytes > MAX_BLOOM_BYTES {
+            return Err(FilterError::TooLarge);
+        }
+        fn pop(stack: &mut Vec<Filter>, n: u32) -> Result<Vec<Filter>, FilterError> {
+            let n = n as usize;
+            if n > stack.len() {
+                return Err(FilterError::Malformed);
+            }
+            Ok(stack.drain(stack.len() - n..).rev().collect())
+        }
+
+        // Build the filter from the last term to the first, so the operands of each operator are
+        // already on the stack, in reverse order, when we reach it.
+        let mut stack = vec![];
+        for term in terms.into_iter().rev() {
+            let filter = match term {
+                Term::All => Filter::All,
+                Term::Kind(kind) => Filter::Kind(kind),
+                Term::Address(address) => Filter::Address(address),
+                Term::Asset(asset) => Filter::Asset(asset),
+                Term::Bloom(bloom) => Filter::Bloom(bloom),
+                Term::And(n) => Filter::And(pop(&mut stack, n)?),
+                Term::Or(n) => Filter::Or(pop(&mut stack, n)?),
+                Term::Not => Filter::Not(Box::new(stack.pop().ok_or(FilterError::Malformed)?)),
+            };
+            stack.push(filter);
+        }
+        match (stack.pop(), stack.is_empty()) {
+            (Some(filter), true) => Ok(filter),
+            _ => Err(FilterError::Malformed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wire::binary;
+    use jf_cap::keys::UserKeyPair;
+
+    struct Event {
+        kind: &'static str,
+        addresses: Vec<UserAddress>,
+        asset: AssetCode,
+    }
+
+    impl Filterable for Event {
+        fn kind(&self) -> Option<&str> {
+            Some(self.kind)
+        }
+
+        fn touches_address(&self, address: &UserAddress) -> bool {
+            self.addresses.contains(address)
+        }
+
+        fn touches_asset(&self, asset: &AssetCode) -> bool {
+            self.asset == *asset
+        }
+
+        fn bloom_keys(&self) -> Vec<Vec<u8>> {
+            self.addresses.iter().map(address_key).collect()
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut rng = ark_std::test_rng();
+        let alice: UserAddress = UserKeyPair::generate(&mut rng).address().into();
+        let bob: UserAddress = UserKeyPair::generate(&mut rng).address().into();
+        let memo = Event {
+            kind: "memo",
+            addresses: vec![alice.clone()],
+            asset: AssetCode::native(),
+        };
+
+        assert!(Filter::All.matches(&memo));
+        assert!(Filter::kind("memo").matches(&memo));
+        assert!(!Filter::kind("block").matches(&memo));
+        assert!(Filter::Asset(AssetCode::native()).matches(&memo));
+        assert!(!Filter::Asset(AssetCode::dummy()).matches(&memo));
+
+        let ours = Filter::kind("memo").and(Filter::Address(alice).or(Filter::Address(bob)));
+        assert!(ours.matches(&memo));
+        assert!(!ours.clone().not().matches(&memo));
+        assert_eq!(ours.terms(), 5);
+
+        // Filters survive a round trip in either format.
+        let json = serde_json::to_string(&ours).unwrap();
+        assert!(json.starts_with(r#"[{"and":2},{"kind":"memo"},{"or":2},"#));
+        assert_eq!(serde_json::from_str::<Filter>(&json).unwrap(), ours);
+        let bytes = binary::serialize(&ours).unwrap();
+        assert_eq!(binary::deserialize::<Filter>(&bytes).unwrap(), ours);
+    }
+
+    #[test]
+    fn test_invalid_filters() {
+        // Operators need enough operands, and there must be exactly one filter.
+        assert!(serde_json::from_str::<Filter>(r#"[{"and": 2}, "all"]"#).is_err());
+        assert!(serde_json::from_str::<Filter>(r#"["not"]"#).is_err());
+        assert!(serde_json::from_str::<Filter>(r#"["all", "all"]"#).is_err());
+        assert!(serde_json::from_str::<Filter>("[]").is_err());
+        assert_eq!(
+            serde_json::from_str::<Filter>(r#"[{"and": 0}]"#).unwrap(),
+            Filter::And(vec![])
+        );
+
+        // Deeply nested filters are rejected.
+        let mut deep = Filter::All;
+        for _ in 0..MAX_TERMS {
+            deep = deep.not();
+        }
+        let json = serde_json::to_string(&deep).unwrap();
+        assert!(serde_json::from_str::<Filter>(&json).is_err());
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        // The filter is sized for the requested false positive rate.
+        let mut bloom = BloomFilter::new(100, 0.01);
+        assert_eq!(bloom.bits.len(), 120);
+        assert_eq!(bloom.hashes, 7);
+        assert!(bloom.false_positive_rate(100) < 0.011);
+        assert!(BloomFilter::new(100, 0.1).bits.len() < bloom.bits.len());
+        assert_eq!(
+            BloomFilter::new(usize::MAX, 0.01).bits.len(),
+            MAX_BLOOM_BYTES
+        );
+
+        // Inserted keys are always found, and others are found at about the expected rate.
+        for i in 0..100u32 {
+            bloom.insert(&i.to_le_bytes());
+        }
+        assert!((0..100u32).all(|i| bloom.contains(&i.to_le_bytes())));
+        let false_positives = (100..10100u32)
+            .filter(|i| bloom.contains(&i.to_le_bytes()))
+            .count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+
+        // Events match if any of their addresses may be in the filter.
+        let mut rng = ark_std::test_rng();
+        let alice: UserAddress = UserKeyPair::generate(&mut rng).address().into();
+        let bob: UserAddress = UserKeyPair::generate(&mut rng).address().into();
+        let mut bloom = BloomFilter::new(1, 0.0001);
+        bloom.insert_address(&alice);
+        let event = |address: &UserAddress| Event {
+            kind: "memo",
+            addresses: vec![address.clone()],
+            asset: AssetCode::native(),
+        };
+        let filter = Filter::Bloom(bloom);
+        assert!(filter.matches(&event(&alice)));
+        assert!(!filter.matches(&event(&bob)));
+        let bytes = binary::serialize(&filter).unwrap();
+        assert_eq!(binary::deserialize::<Filter>(&bytes).unwrap(), filter);
+
+        // Invalid and oversized Bloom filters are rejected.
+        assert!(BloomFilter::from_parts(vec![], 1).is_err());
+        assert!(BloomFilter::from_parts(vec![0], 0).is_err());
+        assert!(BloomFilter::from_parts(vec![0], MAX_BLOOM_HASHES + 1).is_err());
+        let half = BloomFilter::from_parts(vec![0; MAX_BLOOM_BYTES / 2 + 1], 1).unwrap();
+        let json =
+            serde_json::to_string(&Filter::Bloom(half.clone()).or(Filter::Bloom(half))).unwrap();
+        assert!(serde_json::from_str::<Filter>(&json).is_err());
+    }
+}
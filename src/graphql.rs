@@ -0,0 +1,169 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Serving GraphQL schemas.
+//!
+//! [serve] mounts an async-graphql schema on a tide route. Queries can be sent either as GET
+//! requests, with the query in the query string, or as POST requests with a JSON body, as
+//! described in the GraphQL over HTTP spec.
+//!
+//! Responses follow the protocol used by the rest of this crate, rather than the GraphQL convention
+//! of a 200 response containing both data and errors:
+//!  * if the query succeeds, the body is the `data` of the GraphQL response, serialized in the
+//!    content type requested by the client (see [response](crate::server::response)). Note that
+//!    the data is a map, so a binary body can only be decoded into map types.
+//!  * if the query fails, the first error is converted into an `E` and returned as a [tide::Error],
+//!    so that [add_error_body](crate::server::add_error_body) serializes it in the standard way. If
+//!    the resolver raised the error from an `E` (using [resolver_error]), the original `E` is
+//!    recovered, with its status code. Otherwise, the error is converted with [Error::catch_all].
+
+use crate::error::{server_error, Error};
+use crate::redact::SafeToServe;
+use crate::server::{request_body, response};
+use async_graphql::http::parse_query_string;
+use async_graphql::{Executor, ServerError};
+use tide::{Request, StatusCode};
+
+// GraphQL responses only contain what the schema exposes.
+impl SafeToServe for async_graphql::Value {}
+
+/// Convert an `E` into a resolver error, so that it can be recovered by [serve].
+pub fn resolver_error<E: Error>(err: E) -> async_graphql::Error {
+    let message = err.to_string();
+    let mut gql = async_graphql::Error::new(message);
+    gql.source = Some(std::sync::Arc::new(err));
+    gql
+}
+
+fn convert_error<E: Error>(err: &ServerError) -> E {
+    match err.source::<E>() {
+        // `E` is not `Clone`, but it is serializable, so we can copy it by round-tripping it
+        // through JSON.
+        Some(source) => serde_json::to_value(source)
+            .and_then(serde_json::from_value)
+            .unwrap_or_else(|_| E::catch_all(err.message.clone())),
+        None => E::catch_all(err.message.clone()),
+    }
+}
+
+async fn handle<X: Executor, E: Error, State: Clone + Send + Sync + 'static>(
+    executor: X,
+    mut req: Request<State>,
+) -> tide::Result {
+    let query = if req.method() == tide::http::Method::Get {
+        parse_query_string(req.url().query().unwrap_or(""))
+            .map_err(|err| tide::Error::from_str(StatusCode::BadRequest, err.to_string()))?
+    } else {
+        request_body::<async_graphql::Request, _>(&mut req).await?
+    };
+    let res = executor.execute(query).await;
+    match res.errors.first() {
+        Some(err) => Err(server_error::<E>(convert_error::<E>(err))),
+        None => response(&req, res.data),
+    }
+}
+
+/// Serve GraphQL queries against `executor` (usually an [async_graphql::Schema]) at `route`.
+///
+/// Errors are reported using `E`.
+pub fn serve<X: Executor, E: Error, State: Clone + Send + Sync + 'static>(
+    route: &mut tide::Route<'_, State>,
+    executor: X,
+) {
+    let get = executor.clone();
+    route.get(move |req| handle::<X, E, State>(get.clone(), req));
+    route.post(move |req| handle::<X, E, State>(executor.clone(), req));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_graphql::{EmptyMutation, EmptySubscription, Object, Schema};
+    use serde::{Deserialize, Serialize};
+    use snafu::Snafu;
+
+    #[derive(Clone, Debug, Deserialize, Serialize, Snafu, PartialEq, Eq)]
+    enum QueryError {
+        #[snafu(display("block {} not found", height))]
+        NotFound { height: u64 },
+        #[snafu(display("{}", msg))]
+        Other { msg: String },
+    }
+
+    impl crate::Error for QueryError {
+        fn catch_all(msg: String) -> Self {
+            Self::Other { msg }
+        }
+
+        fn status(&self) -> StatusCode {
+            match self {
+                Self::NotFound { .. } => StatusCode::NotFound,
+                Self::Other { .. } => StatusCode::InternalServerError,
+            }
+        }
+    }
+
+    struct Query;
+
+    #[Object]
+    impl Query {
+        async fn block_hash(&self, height: u64) -> async_graphql::Result<String> {
+            if height < 10 {
+                Ok(format!("hash{}", height))
+            } else {
+                Err(resolver_error(QueryError::NotFound { height }))
+            }
+        }
+    }
+
+    #[async_std::test]
+    async fn test_graphql() {
+        let mut app = tide::new();
+        app.with(crate::server::add_error_body::<(), QueryError>);
+        serve::<_, QueryError, _>(
+            &mut app.at("/graphql"),
+            Schema::new(Query, EmptyMutation, EmptySubscription),
+        );
+
+        let post = |query: &str| {
+            let mut req = tide::http::Request::new(
+                tide::http::Method::Post,
+                tide::http::Url::parse("http://localhost/graphql").unwrap(),
+            );
+            req.insert_header("Accept", "application/json");
+            req.set_content_type(tide::http::mime::JSON);
+            req.set_body(serde_json::json!({ "query": query }).to_string());
+            let app = app.clone();
+            async move {
+                let mut res: tide::http::Response = app.respond(req).await.unwrap();
+                let body: serde_json::Value = res.body_json().await.unwrap();
+                (res.status(), body)
+            }
+        };
+
+        assert_eq!(
+            post("{ blockHash(height: 1) }").await,
+            (StatusCode::Ok, serde_json::json!({ "blockHash": "hash1" }))
+        );
+
+        // Errors from resolvers are recovered, with their status codes.
+        let (status, body) = post("{ blockHash(height: 11) }").await;
+        assert_eq!(status, StatusCode::NotFound);
+        assert_eq!(
+            serde_json::from_value::<QueryError>(body).unwrap(),
+            QueryError::NotFound { height: 11 }
+        );
+
+        // Other errors use `catch_all`.
+        let (status, body) = post("{ nonsense }").await;
+        assert_eq!(status, StatusCode::InternalServerError);
+        assert!(matches!(
+            serde_json::from_value::<QueryError>(body).unwrap(),
+            QueryError::Other { .. }
+        ));
+    }
+}
@@ -0,0 +1,718 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! The custom headers of the protocol, as types.
+//!
+//! Each header defined by this crate has a name constant and a type implementing [TypedHeader],
+//! which parses and formats its value. Both are defined here, in one place, so that the client and
+//! server cannot disagree about what a header is called or what its value looks like.
+//!
+//! [TypedHeader::get] and [TypedHeader::insert] work on anything with headers: tide and surf
+//! requests and responses, and the underlying `http_types` messages.
+//!
+//! ```
+//! use net::headers::{LedgerHeight, TypedHeader};
+//!
+//! let mut res = tide::Response::new(200);
+//! LedgerHeight(42).insert(&mut res);
+//! assert_eq!(LedgerHeight::get(&res).unwrap(), Some(LedgerHeight(42)));
+//! ```
+
+use crate::wire::integers::IntegerFormat;
+use http_types::{headers::Headers, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+/// Header carrying the ID of a request.
+pub const REQUEST_ID: &str = "X-Request-Id";
+
+/// Request header identifying a logical request across retries.
+pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+/// Response header giving the ledger height at which the response was computed.
+pub const LEDGER_HEIGHT: &str = "Ledger-Height";
+
+/// Request header asking the server to respond only if the ledger height is greater than the
+/// given height.
+pub const IF_LEDGER_HEIGHT_GT: &str = "If-Ledger-Height-Gt";
+
+/// Request header asking the server not to answer from state older than the given ledger height.
+pub const MIN_LEDGER_HEIGHT: &str = "X-Min-Ledger-Height";
+
+/// Request header giving the version of the state which the client already has.
+pub const DELTA_BASE: &str = "Delta-Base";
+
+/// Response header containing the hex-encoded signature of the response body and the request it
+/// answers.
+pub const RESPONSE_SIGNATURE: &str = "X-Response-Signature";
+
+/// Response header giving the time at which the [RESPONSE_SIGNATURE] was made, in seconds since
+/// the Unix epoch.
+pub const SIGNED_AT: &str = "X-Signed-At";
+
+/// Response header giving the ID of the key, in the service's [keyring](crate::keyring), which
+/// made the [RESPONSE_SIGNATURE].
+pub const SIGNING_KEY_ID: &str = "X-Signing-Key-Id";
+
+/// Header containing the signature of a webhook delivery, as `sha256=<hex HMAC>` of its
+/// [WEBHOOK_DELIVERY_ID], the [WEBHOOK_TIMESTAMP] and the body.
+pub const WEBHOOK_SIGNATURE: &str = "X-Webhook-Signature";
+
+/// Header containing a unique identifier for each webhook event, which is the same for every
+/// attempt to deliver it, so subscribers can ignore duplicates.
+pub const WEBHOOK_DELIVERY_ID: &str = "X-Webhook-Delivery";
+
+/// Header giving the time at which a webhook delivery was signed, in seconds since the Unix epoch.
+pub const WEBHOOK_TIMESTAMP: &str = "X-Webhook-Timestamp";
+
+/// Request header containing the solution to a [challenge](crate::challenge).
+pub const CHALLENGE_RESPONSE: &str = "X-Challenge-Response";
+
+/// Request header asking the server to write large integers in JSON responses as strings.
+pub const JSON_INTEGERS: &str = "Json-Integers";
+
+/// Request header giving the time the client is willing to wait for a response, in milliseconds.
+pub const REQUEST_DEADLINE: &str = "X-Request-Deadline";
+
+/// Header identifying the position of a request in a distributed trace (W3C Trace Context).
+pub const TRACEPARENT: &str = "traceparent";
+
+/// Header carrying vendor-specific trace information along with [TRACEPARENT].
+pub const TRACESTATE: &str = "tracestate";
+
+/// Response header listing problems with a successful response, as a JSON array of [Warning]s.
+pub const WARNINGS: &str = "X-Warnings";
+
+/// Header asking for a response body wrapped in an [envelope](crate::wire::envelope) with metadata
+/// or, in a response, saying that the body is wrapped.
+pub const ENVELOPE: &str = "X-Envelope";
+
+/// Response header marking the body as a [cost estimate](crate::cost) rather than the result of
+/// the query.
+pub const COST_ESTIMATE: &str = "X-Cost-Estimate";
+
+/// Response header giving the server's time when the response was sent, in RFC 3339 format.
+pub const SERVER_TIME: &str = "Server-Time";
+
+/// Header added to requests which [shadow](crate::server::shadow) middleware mirrors to a
+/// secondary deployment.
+pub const SHADOWED: &str = "X-Shadowed";
+
+/// Request header naming the tenant a request is for, when a [multi-tenant](crate::server::tenancy)
+/// server identifies tenants by header.
+pub const TENANT: &str = "X-Tenant";
+
+/// Request header giving the size, in bytes, of the largest response the client wants. Servers
+/// which support it answer larger results with a [partial result](crate::cursor::respond_partial).
+pub const MAX_RESPONSE_BYTES: &str = "Max-Response-Bytes";
+
+/// A header with a typed value.
+pub trait TypedHeader: Sized {
+    /// The name of the header.
+    const NAME: &'static str;
+
+    /// Parse the value of the header, or explain why it is invalid.
+    fn decode(value: &str) -> Result<Self, String>;
+
+    /// The value of the header.
+    fn encode(&self) -> String;
+
+    /// The value of this header in `headers`, if it is present.
+    ///
+    /// If the header appears more than once, the last value is used. A value which cannot be
+    /// parsed is a 400 Bad Request error.
+    fn get(headers: impl AsRef<Headers>) -> Result<Option<Self>, http_types::Error> {
+        let value = match headers.as_ref().get(Self::NAME) {
+            Some(values) => values.last().as_str().to_string(),
+            None => return Ok(None),
+        };
+        Self::decode(&value).map(Some).map_err(|reason| {
+            http_types::Error::from_str(
+                StatusCode::BadRequest,
+                format!("invalid {} header {:?}: {}", Self::NAME, value, reason),
+            )
+        })
+    }
+
+    /// Set this header in `headers`, replacing any existing value.
+    fn insert(&self, mut headers: impl AsMut<Headers>) {
+        headers.as_mut().insert(Self::NAME, self.encode());
+    }
+}
+
+// Header values must be visible ASCII, so that they survive proxies unchanged.
+fn check_token(value: &str) -> Result<(), String> {
+    if value.is_empty() {
+        Err("empty value".into())
+    } else if !value.bytes().all(|b| b.is_ascii_graphic()) {
+        Err("value must be visible ASCII".into())
+    } else {
+        Ok(())
+    }
+}
+
+/// The ID of a request, in the [REQUEST_ID] header.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RequestId(pub String);
+
+impl TypedHeader for RequestId {
+    const NAME: &'static str = REQUEST_ID;
+
+    fn decode(value: &str) -> Result<Self, String> {
+        check_token(value)?;
+        Ok(Self(value.to_string()))
+    }
+
+    fn encode(&self) -> String {
+        self.0.clone()
+    }
+}
+
+/// The key identifying a logical request across retries, in the [IDEMPOTENCY_KEY] header.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct IdempotencyKey(pub String);
+
+impl TypedHeader for IdempotencyKey {
+    const NAME: &'static str = IDEMPOTENCY_KEY;
+
+    fn decode(value: &str) -> Result<Self, String> {
+        check_token(value)?;
+        Ok(Self(value.to_string()))
+    }
+
+    fn encode(&self) -> String {
+        self.0.clone()
+    }
+}
+
+fn decode_u64(value: &str) -> Result<u64, String> {
+    // `u64::from_str` accepts a leading `+`, which is not valid in any of our headers.
+    if !value.bytes().all(|b| b.is_ascii_digit()) {
+        return Err("expected a decimal integer".into());
+    }
+    value.parse().map_err(|err| format!("{}", err))
+}
+
+/// The ledger height at which a response was computed, in the [LEDGER_HEIGHT] header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LedgerHeight(pub u64);
+
+impl TypedHeader for LedgerHeight {
+    const NAME: &'static str = LEDGER_HEIGHT;
+
+    fn decode(value: &str) -> Result<Self, String> {
+        decode_u64(value).map(Self)
+    }
+
+    fn encode(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+/// The ledger height a client already has a response for, in the [IF_LEDGER_HEIGHT_GT] header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IfLedgerHeightGt(pub u64);
+
+impl TypedHeader for IfLedgerHeightGt {
+    const NAME: &'static str = IF_LEDGER_HEIGHT_GT;
+
+    fn decode(value: &str) -> Result<Self, String> {
+        decode_u64(value).map(Self)
+    }
+
+    fn encode(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+/// The oldest ledger height the client will accept a response from, in the [MIN_LEDGER_HEIGHT]
+/// header.
+///
+/// See [consistency](crate::consistency).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MinLedgerHeight(pub u64);
+
+impl TypedHeader for MinLedgerHeight {
+    const NAME: &'static str = MIN_LEDGER_HEIGHT;
+
+    fn decode(value: &str) -> Result<Self, String> {
+        decode_u64(value).map(Self)
+    }
+
+    fn encode(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+/// The version of a resource a client already has, in the [DELTA_BASE] header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DeltaBase(pub u64);
+
+impl TypedHeader for DeltaBase {
+    const NAME: &'static str = DELTA_BASE;
+
+    fn decode(value: &str) -> Result<Self, String> {
+        decode_u64(value).map(Self)
+    }
+
+    fn encode(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+/// The signature of a response body, in the [RESPONSE_SIGNATURE] header.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ResponseSignature(pub Vec<u8>);
+
+impl TypedHeader for ResponseSignature {
+    const NAME: &'static str = RESPONSE_SIGNATURE;
+
+    fn decode(value: &str) -> Result<Self, String> {
+        hex::decode(value)
+            .map(Self)
+            .map_err(|err| format!("{}", err))
+    }
+
+    fn encode(&self) -> String {
+        hex::encode(&self.0)
+    }
+}
+
+/// The ID of the key which signed a response, in the [SIGNING_KEY_ID] header.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SigningKeyId(pub String);
+
+impl TypedHeader for SigningKeyId {
+    const NAME: &'static str = SIGNING_KEY_ID;
+
+    fn decode(value: &str) -> Result<Self, String> {
+        check_token(value)?;
+        Ok(Self(value.to_string()))
+    }
+
+    fn encode(&self) -> String {
+        self.0.clone()
+    }
+}
+
+/// The time at which a response was signed, in the [SIGNED_AT] header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SignedAt(pub u64);
+
+impl TypedHeader for SignedAt {
+    const NAME: &'static str = SIGNED_AT;
+
+    fn decode(value: &str) -> Result<Self, String> {
+        decode_u64(value).map(Self)
+    }
+
+    fn encode(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+/// The time at which a webhook delivery was signed, in the [WEBHOOK_TIMESTAMP] header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WebhookTimestamp(pub u64);
+
+impl TypedHeader for WebhookTimestamp {
+    const NAME: &'static str = WEBHOOK_TIMESTAMP;
+
+    fn decode(value: &str) -> Result<Self, String> {
+        decode_u64(value).map(Self)
+    }
+
+    fn encode(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+/// The HMAC of a webhook delivery, in the [WEBHOOK_SIGNATURE] header.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct WebhookSignature(pub Vec<u8>);
+
+const WEBHOOK_SIGNATURE_PREFIX: &str = "sha256=";
+
+impl TypedHeader for WebhookSignature {
+    const NAME: &'static str = WEBHOOK_SIGNATURE;
+
+    fn decode(value: &str) -> Result<Self, String> {
+        let mac = value
+            .strip_prefix(WEBHOOK_SIGNATURE_PREFIX)
+            .ok_or_else(|| format!("expected {}<hex>", WEBHOOK_SIGNATURE_PREFIX))?;
+        hex::decode(mac).map(Self).map_err(|err| format!("{}", err))
+    }
+
+    fn encode(&self) -> String {
+        format!("{}{}", WEBHOOK_SIGNATURE_PREFIX, hex::encode(&self.0))
+    }
+}
+
+/// The solution to a [challenge](crate::challenge), in the [CHALLENGE_RESPONSE] header.
+///
+/// The value is `pow <token> <counter>` for a proof of work, or `captcha <token>` for a CAPTCHA.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ChallengeResponse {
+    ProofOfWork { token: String, counter: u64 },
+    Captcha(String),
+}
+
+impl TypedHeader for ChallengeResponse {
+    const NAME: &'static str = CHALLENGE_RESPONSE;
+
+    fn decode(value: &str) -> Result<Self, String> {
+        let parts = value.split(' ').collect::<Vec<_>>();
+        match parts.as_slice() {
+            ["pow", token, counter] => {
+                check_token(token)?;
+                let counter = counter
+                    .parse()
+                    .map_err(|err| format!("invalid counter: {}", err))?;
+                Ok(Self::ProofOfWork {
+                    token: token.to_string(),
+                    counter,
+                })
+            }
+            ["captcha", token] => {
+                check_token(token)?;
+                Ok(Self::Captcha(token.to_string()))
+            }
+            _ => Err("expected `pow <token> <counter>` or `captcha <token>`".into()),
+        }
+    }
+
+    fn encode(&self) -> String {
+        match self {
+            Self::ProofOfWork { token, counter } => format!("pow {} {}", token, counter),
+            Self::Captcha(token) => format!("captcha {}", token),
+        }
+    }
+}
+
+/// How large integers should be written in a JSON response, in the [JSON_INTEGERS] header.
+///
+/// The value is `number` or `string`; see [wire::integers](crate::wire::integers).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct JsonIntegers(pub IntegerFormat);
+
+impl TypedHeader for JsonIntegers {
+    const NAME: &'static str = JSON_INTEGERS;
+
+    fn decode(value: &str) -> Result<Self, String> {
+        match value {
+            "number" => Ok(Self(IntegerFormat::Number)),
+            "string" => Ok(Self(IntegerFormat::String)),
+            _ => Err("expected `number` or `string`".into()),
+        }
+    }
+
+    fn encode(&self) -> String {
+        match self.0 {
+            IntegerFormat::Number => "number".into(),
+            IntegerFormat::String => "string".into(),
+        }
+    }
+}
+
+/// The time a client is willing to wait for a response, in the [REQUEST_DEADLINE] header.
+///
+/// The value is the remaining budget in whole milliseconds, rather than an absolute time, so that
+/// it does not depend on the clocks of the client and server agreeing; see
+/// [deadline](crate::deadline).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RequestDeadline(pub Duration);
+
+impl TypedHeader for RequestDeadline {
+    const NAME: &'static str = REQUEST_DEADLINE;
+
+    fn decode(value: &str) -> Result<Self, String> {
+        decode_u64(value).map(|ms| Self(Duration::from_millis(ms)))
+    }
+
+    fn encode(&self) -> String {
+        self.0.as_millis().to_string()
+    }
+}
+
+/// The position of a request in a distributed trace, in the [TRACEPARENT] header.
+///
+/// The value is `00-<trace ID>-<parent ID>-<flags>`, in lowercase hex, as defined by the W3C
+/// Trace Context recommendation. Values with a later version are accepted if they start with the
+/// same fields, as the recommendation requires.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TraceParent {
+    pub trace_id: [u8; 16],
+    /// The ID of the span which sent the request.
+    pub parent_id: [u8; 8],
+    pub flags: u8,
+}
+
+fn decode_hex_field<const N: usize>(field: Option<&str>, name: &str) -> Result<[u8; N], String> {
+    let field = field.ok_or_else(|| format!("missing {}", name))?;
+    if field.len() != 2 * N
+        || !field
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    {
+        return Err(format!("{} must be {} lowercase hex digits", name, 2 * N));
+    }
+    let mut bytes = [0; N];
+    hex::decode_to_slice(field, &mut bytes).map_err(|err| format!("{}", err))?;
+    Ok(bytes)
+}
+
+impl TypedHeader for TraceParent {
+    const NAME: &'static str = TRACEPARENT;
+
+    fn decode(value: &str) -> Result<Self, String> {
+        let mut fields = value.split('-');
+        let [version] = decode_hex_field::<1>(fields.next(), "version")?;
+        if version == 0xff {
+            return Err("invalid version ff".into());
+        }
+        let trace_id = decode_hex_field(fields.next(), "trace ID")?;
+        let parent_id = decode_hex_field(fields.next(), "parent ID")?;
+        let [flags] = decode_hex_field::<1>(fields.next(), "flags")?;
+        if version == 0 && fields.next().is_some() {
+            return Err("unexpected fields after flags".into());
+        }
+        if trace_id == [0; 16] || parent_id == [0; 8] {
+            return Err("IDs must not be all zeros".into());
+        }
+        Ok(Self {
+            trace_id,
+            parent_id,
+            flags,
+        })
+    }
+
+    fn encode(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            hex::encode(self.trace_id),
+            hex::encode(self.parent_id),
+            self.flags
+        )
+    }
+}
+
+/// Vendor-specific trace information, in the [TRACESTATE] header.
+///
+/// The value is a list of `key=value` pairs, which is passed along without being interpreted.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TraceState(pub String);
+
+impl TypedHeader for TraceState {
+    const NAME: &'static str = TRACESTATE;
+
+    fn decode(value: &str) -> Result<Self, String> {
+        if value.len() > 512 {
+            return Err("value must be at most 512 characters".into());
+        }
+        if !value.bytes().all(|b| b == b' ' || b.is_ascii_graphic()) {
+            return Err("value must be printable ASCII".into());
+        }
+        Ok(Self(value.to_string()))
+    }
+
+    fn encode(&self) -> String {
+        self.0.clone()
+    }
+}
+
+/// A problem with a successful response, which the client may want to know about.
+///
+/// For example, a service whose view of the ledger is lagging can still answer a query, with a
+/// warning that the answer may be stale.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Warning {
+    /// A stable identifier for the kind of problem, like `stale_state`, for clients to match on.
+    pub code: String,
+    /// A description of the problem, for people.
+    pub message: String,
+}
+
+impl Warning {
+    pub fn new(code: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            code: code.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+/// The warnings attached to a successful response, in the [WARNINGS] header.
+///
+/// The value is a JSON array of [Warning] objects, with non-ASCII characters escaped, so that
+/// messages can contain any text.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Warnings(pub Vec<Warning>);
+
+impl TypedHeader for Warnings {
+    const NAME: &'static str = WARNINGS;
+
+    fn decode(value: &str) -> Result<Self, String> {
+        serde_json::from_str(value)
+            .map(Self)
+            .map_err(|err| err.to_string())
+    }
+
+    fn encode(&self) -> String {
+        let json = serde_json::to_string(&self.0).unwrap();
+        // Header values must be ASCII. JSON strings can escape any other character as UTF-16 code
+        // units, and there are no non-ASCII characters outside of strings.
+        let mut value = String::with_capacity(json.len());
+        for c in json.chars() {
+            if c.is_ascii() {
+                value.push(c);
+            } else {
+                for unit in c.encode_utf16(&mut [0; 2]) {
+                    value.push_str(&format!("\\u{:04x}", unit));
+                }
+            }
+        }
+        value
+    }
+}
+
+/// The version of the envelope format, in the [ENVELOPE] header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EnvelopeVersion(pub u64);
+
+impl TypedHeader for EnvelopeVersion {
+    const NAME: &'static str = ENVELOPE;
+
+    fn decode(value: &str) -> Result<Self, String> {
+        decode_u64(value).map(Self)
+    }
+
+    fn encode(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+/// The response size limit, in the [MAX_RESPONSE_BYTES] header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MaxResponseBytes(pub u64);
+
+impl TypedHeader for MaxResponseBytes {
+    const NAME: &'static str = MAX_RESPONSE_BYTES;
+
+    fn decode(value: &str) -> Result<Self, String> {
+        decode_u64(value).map(Self)
+    }
+
+    fn encode(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http_types::{Method, Request, Response, Url};
+
+    fn round_trip<H: TypedHeader + PartialEq + std::fmt::Debug>(header: H) {
+        let mut req = Request::new(Method::Get, Url::parse("http://localhost/").unwrap());
+        assert_eq!(H::get(&req).unwrap(), None);
+        header.insert(&mut req);
+        assert_eq!(H::get(&req).unwrap(), Some(header));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(RequestId("abc123".into()));
+        round_trip(IdempotencyKey("key-1".into()));
+        round_trip(LedgerHeight(u64::MAX));
+        round_trip(IfLedgerHeightGt(0));
+        round_trip(MinLedgerHeight(12));
+        round_trip(DeltaBase(7));
+        round_trip(ResponseSignature(vec![0xde, 0xad, 0xbe, 0xef]));
+        round_trip(SigningKeyId("2022-06".into()));
+        round_trip(SignedAt(1_650_000_000));
+        round_trip(WebhookSignature(vec![1, 2, 3]));
+        round_trip(WebhookTimestamp(1_650_000_000));
+        round_trip(ChallengeResponse::ProofOfWork {
+            token: "1.2.ab.cd".into(),
+            counter: 12345,
+        });
+        round_trip(ChallengeResponse::Captcha("tok3n".into()));
+        round_trip(JsonIntegers(IntegerFormat::String));
+        round_trip(RequestDeadline(Duration::from_millis(1500)));
+        round_trip(TraceParent {
+            trace_id: [0x4b; 16],
+            parent_id: [0xf0; 8],
+            flags: 1,
+        });
+        round_trip(TraceState("rojo=00f067aa0ba902b7,congo=t61rcWkgMzE".into()));
+        round_trip(Warnings(vec![
+            Warning::new(
+                "stale_state",
+                "responding from stale state, 3 blocks behind",
+            ),
+            Warning::new(
+                "deprecated",
+                "use \"/v2\" instead — this route goes away in 𝟚𝟘𝟚𝟛",
+            ),
+        ]));
+        assert!(Warnings(vec![Warning::new("a", "ü")]).encode().is_ascii());
+        round_trip(EnvelopeVersion(1));
+        round_trip(MaxResponseBytes(1 << 20));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut res = Response::new(StatusCode::Ok);
+        for (name, value) in [
+            (LEDGER_HEIGHT, "+1"),
+            (LEDGER_HEIGHT, "-1"),
+            (LEDGER_HEIGHT, "1.0"),
+            (RESPONSE_SIGNATURE, "xyz"),
+            (WEBHOOK_SIGNATURE, "deadbeef"),
+        ] {
+            res.insert_header(name, value);
+        }
+        let err = LedgerHeight::get(&res).unwrap_err();
+        assert_eq!(err.status(), StatusCode::BadRequest);
+        assert!(ResponseSignature::get(&res).is_err());
+        assert!(WebhookSignature::get(&res).is_err());
+        assert!(RequestId::decode("has space").is_err());
+        assert!(RequestId::decode("").is_err());
+        assert!(ChallengeResponse::decode("pow token").is_err());
+        assert!(ChallengeResponse::decode("pow token -1").is_err());
+        assert!(ChallengeResponse::decode("captcha").is_err());
+        assert!(JsonIntegers::decode("bigint").is_err());
+
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let parsed = TraceParent::decode(traceparent).unwrap();
+        assert_eq!(parsed.encode(), traceparent);
+        assert_eq!(parsed.flags, 1);
+        // Later versions may add fields.
+        assert_eq!(
+            TraceParent::decode(&format!("{}-extra", traceparent.replacen("00", "01", 1))).unwrap(),
+            parsed
+        );
+        for invalid in [
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert!(TraceParent::decode(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,468 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Sets of named keys, for rotating secrets without downtime.
+//!
+//! Signed responses (see [signing](crate::signing)), session cookies (see
+//! [Sessions](crate::server::session::Sessions)) and webhook deliveries (see
+//! [webhooks](crate::webhooks)) are each signed with a secret, which operators have to change
+//! from time to time. Whatever was signed with the old secret, like a cookie in a browser, should
+//! stay valid for a while after the change. A [Keyring] holds several keys, each with an ID: the
+//! active key, which signs everything new, and retired keys, which are still accepted until their
+//! grace period ends.
+//!
+//! Clones of a keyring share its keys, so a key rotated in one place is used everywhere at once:
+//!
+//! ```
+//! use net::keyring::Keyring;
+//! use std::time::Duration;
+//!
+//! let keys = Keyring::new("2022-01", vec![1; 32]);
+//! let shared = keys.clone();
+//! keys.rotate("2022-06", vec![2; 32], Duration::from_secs(24 * 60 * 60));
+//! assert_eq!(shared.active(), ("2022-06".to_string(), vec![2; 32]));
+//! // The old key is still accepted for a day.
+//! assert_eq!(shared.get("2022-01"), Some(vec![1; 32]));
+//! ```
+//!
+//! Operators can also keep the keys in a [KeyringFile], as JSON with hex-encoded keys and the
+//! expiry of each retired key in seconds since the Unix epoch:
+//!
+//! ```json
+//! {
+//!     "active": "2022-06",
+//!     "keys": [
+//!         { "id": "2022-06", "key": "0202...02" },
+//!         { "id": "2022-01", "key": "0101...01", "expires": 1656633600 }
+//!     ]
+//! }
+//! ```
+//!
+//! and rotate by editing the file. A running service picks up the change when the file is
+//! [watched](Keyring::watch), or when an operator asks it to reload through the admin endpoints
+//! (see [Admin::reload](crate::server::admin::Admin::reload)).
+
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+use std::convert::TryInto;
+use std::fmt::{self, Debug, Formatter};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The minimum length of an HMAC secret loaded from a [KeyringFile], in bytes.
+pub const MIN_SECRET_LEN: usize = 32;
+
+/// A type of key which can be kept in a [Keyring] and loaded from a [KeyringFile].
+pub trait KeyMaterial: Clone + Send + Sync + 'static {
+    /// Decode a key from the bytes in a [KeyringFile].
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String>;
+}
+
+/// A secret for HMACs, of at least [MIN_SECRET_LEN] bytes.
+impl KeyMaterial for Vec<u8> {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < MIN_SECRET_LEN {
+            return Err(format!(
+                "secret must be at least {} bytes, but it is {}",
+                MIN_SECRET_LEN,
+                bytes.len()
+            ));
+        }
+        Ok(bytes.to_vec())
+    }
+}
+
+/// An Ed25519 signing key, from its 32-byte secret.
+impl KeyMaterial for SigningKey {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let bytes = bytes
+            .try_into()
+            .map_err(|_| "signing key must be 32 bytes".to_string())?;
+        Ok(SigningKey::from_bytes(&bytes))
+    }
+}
+
+/// An Ed25519 public key, for clients which check the signatures of a service.
+impl KeyMaterial for VerifyingKey {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let bytes = bytes
+            .try_into()
+            .map_err(|_| "verifying key must be 32 bytes".to_string())?;
+        VerifyingKey::from_bytes(&bytes).map_err(|err| err.to_string())
+    }
+}
+
+/// The serialized form of a [Keyring].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyringFile {
+    /// The ID of the active key.
+    pub active: String,
+    pub keys: Vec<KeyEntry>,
+}
+
+/// A key in a [KeyringFile].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyEntry {
+    pub id: String,
+    /// The hex-encoded key.
+    pub key: String,
+    /// When a retired key stops being accepted, in seconds since the Unix epoch, or [None] for a
+    /// key which has not been retired.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+/// Reasons a keyring cannot be loaded.
+#[derive(Clone, Debug, Snafu, PartialEq, Eq)]
+pub enum KeyringError {
+    #[snafu(display("failed to read keyring file: {}", reason))]
+    Io { reason: String },
+    #[snafu(display("malformed keyring file: {}", reason))]
+    Malformed { reason: String },
+    #[snafu(display("invalid key {}: {}", id, reason))]
+    InvalidKey { id: String, reason: String },
+    #[snafu(display("active key {} is not in the keyring", id))]
+    MissingActive { id: String },
+}
+
+#[derive(Clone)]
+struct Entry<K> {
+    id: String,
+    key: K,
+    expires: Option<u64>,
+}
+
+impl<K> Entry<K> {
+    fn is_valid(&self, now: u64) -> bool {
+        self.expires.map_or(true, |expires| expires > now)
+    }
+}
+
+struct Keys<K> {
+    active: String,
+    entries: Vec<Entry<K>>,
+}
+
+/// A set of keys by ID, one of which is active.
+///
+/// See the [module documentation](self).
+pub struct Keyring<K> {
+    keys: Arc<RwLock<Keys<K>>>,
+}
+
+impl<K> Clone for Keyring<K> {
+    fn clone(&self) -> Self {
+        Self {
+            keys: self.keys.clone(),
+        }
+    }
+}
+
+impl<K> Debug for Keyring<K> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        // Only the IDs: the keys are secret.
+        let keys = self.keys.read().unwrap();
+        f.debug_struct("Keyring")
+            .field("active", &keys.active)
+            .field(
+                "ids",
+                &keys
+                    .entries
+                    .iter()
+                    .map(|entry| &entry.id)
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl<K: Clone> Keyring<K> {
+    /// A keyring with a single key, `key`, which is active.
+    pub fn new(id: impl Into<String>, key: K) -> Self {
+        let id = id.into();
+        Self {
+            keys: Arc::new(RwLock::new(Keys {
+                active: id.clone(),
+                entries: vec![Entry {
+                    id,
+                    key,
+                    expires: None,
+                }],
+            })),
+        }
+    }
+
+    /// The ID of the active key, and the key.
+    pub fn active(&self) -> (String, K) {
+        let keys = self.keys.read().unwrap();
+        let entry = keys
+            .entries
+            .iter()
+            .find(|entry| entry.id == keys.active)
+            .expect("active key is in the keyring");
+        (entry.id.clone(), entry.key.clone())
+    }
+
+    /// The key with ID `id`, if it is active or in its grace period.
+    pub fn get(&self, id: &str) -> Option<K> {
+        let now = unix_time();
+        let keys = self.keys.read().unwrap();
+        keys.entries
+            .iter()
+            .find(|entry| entry.id == id && entry.is_valid(now))
+            .map(|entry| entry.key.clone())
+    }
+
+    /// The keys which are accepted, with their IDs, starting with the active key.
+    ///
+    /// This is for checking signatures which do not say which key made them, against every key
+    /// which might have.
+    pub fn valid(&self) -> Vec<(String, K)> {
+        let now = unix_time();
+        let keys = self.keys.read().unwrap();
+        let mut valid = keys
+            .entries
+            .iter()
+            .filter(|entry| entry.is_valid(now))
+            .map(|entry| (entry.id.clone(), entry.key.clone()))
+            .collect::<Vec<_>>();
+        valid.sort_by_key(|(id, _)| *id != keys.active);
+        valid
+    }
+
+    /// Make `key` the active key, and retire the previous active key after `grace`.
+    ///
+    /// Keys whose grace period has ended are forgotten. If there is already a key with ID `id`, it
+    /// is replaced.
+    pub fn rotate(&self, id: impl Into<String>, key: K, grace: Duration) {
+        let id = id.into();
+        let now = unix_time();
+        let mut keys = self.keys.write().unwrap();
+        let expires = now.saturating_add(grace.as_secs());
+        let previous = keys.active.clone();
+        for entry in &mut keys.entries {
+            if entry.id == previous {
+                entry.expires = Some(entry.expires.map_or(expires, |old| old.min(expires)));
+            }
+        }
+        keys.entries
+            .retain(|entry| entry.id != id && entry.is_valid(now));
+        keys.entries.push(Entry {
+            id: id.clone(),
+            key,
+            expires: None,
+        });
+        keys.active = id;
+    }
+}
+
+impl<K: KeyMaterial> Keyring<K> {
+    /// A keyring with the keys in `file`.
+    pub fn from_file(file: KeyringFile) -> Result<Self, KeyringError> {
+        let keys = decode(file)?;
+        Ok(Self {
+            keys: Arc::new(RwLock::new(keys)),
+        })
+    }
+
+    /// A keyring with the keys in the [KeyringFile] at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, KeyringError> {
+        Self::from_file(read(path.as_ref())?)
+    }
+
+    /// Replace the keys with those in `file`.
+    ///
+    /// If `file` is invalid, the keys are left as they were.
+    pub fn replace(&self, file: KeyringFile) -> Result<(), KeyringError> {
+        let keys = decode(file)?;
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+
+    /// Replace the keys with those in the [KeyringFile] at `path`.
+    pub fn reload(&self, path: impl AsRef<Path>) -> Result<(), KeyringError> {
+        self.replace(read(path.as_ref())?)
+    }
+
+    /// Reload the keys from `path` whenever the file changes, checking every `interval`.
+    ///
+    /// The keyring is reloaded in a background task, which runs until the returned handle is
+    /// cancelled. If the file becomes invalid, the keys are left as they were, and the error is
+    /// logged.
+    #[cfg(any(feature = "client", feature = "server"))]
+    pub fn watch(
+        &self,
+        path: impl Into<std::path::PathBuf>,
+        interval: Duration,
+    ) -> async_std::task::JoinHandle<()> {
+        let keyring = self.clone();
+        let path = path.into();
+        async_std::task::spawn(async move {
+            let modified = |path: &Path| path.metadata().and_then(|meta| meta.modified()).ok();
+            let mut last = modified(&path);
+            loop {
+                async_std::task::sleep(interval).await;
+                let current = modified(&path);
+                if current == last {
+                    continue;
+                }
+                last = current;
+                match keyring.reload(&path) {
+                    Ok(()) => tracing::event!(
+                        tracing::Level::WARN,
+                        "reloaded keyring from {}",
+                        path.display()
+                    ),
+                    Err(err) => tracing::event!(
+                        tracing::Level::ERROR,
+                        "failed to reload keyring from {}: {}",
+                        path.display(),
+                        err
+                    ),
+                }
+            }
+        })
+    }
+}
+
+fn read(path: &Path) -> Result<KeyringFile, KeyringError> {
+    let bytes = std::fs::read(path).map_err(|err| KeyringError::Io {
+        reason: err.to_string(),
+    })?;
+    serde_json::from_slice(&bytes).map_err(|err| KeyringError::Malformed {
+        reason: err.to_string(),
+    })
+}
+
+fn decode<K: KeyMaterial>(file: KeyringFile) -> Result<Keys<K>, KeyringError> {
+    let entries = file
+        .keys
+        .into_iter()
+        .map(|entry| {
+            let invalid = |reason: String| KeyringError::InvalidKey {
+                id: entry.id.clone(),
+                reason,
+            };
+            let bytes = hex::decode(&entry.key).map_err(|err| invalid(err.to_string()))?;
+            Ok(Entry {
+                key: K::from_bytes(&bytes).map_err(invalid)?,
+                id: entry.id,
+                expires: entry.expires,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    match entries.iter().find(|entry| entry.id == file.active) {
+        None => return Err(KeyringError::MissingActive { id: file.active }),
+        // Everything new is signed with the active key, so if it expired, nothing signed from then
+        // on could be verified.
+        Some(entry) if entry.expires.is_some() => {
+            return Err(KeyringError::InvalidKey {
+                id: file.active,
+                reason: "the active key cannot expire".into(),
+            })
+        }
+        Some(_) => {}
+    }
+    Ok(Keys {
+        active: file.active,
+        entries,
+    })
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rotate() {
+        let keys = Keyring::new("a", vec![1]);
+        keys.rotate("b", vec![2], Duration::from_secs(60));
+        assert_eq!(keys.active(), ("b".to_string(), vec![2]));
+        assert_eq!(keys.get("a"), Some(vec![1]));
+        assert_eq!(
+            keys.valid(),
+            vec![("b".to_string(), vec![2]), ("a".to_string(), vec![1])]
+        );
+
+        // Once its grace period ends, a retired key is no longer accepted, and it is forgotten.
+        keys.rotate("c", vec![3], Duration::ZERO);
+        assert_eq!(keys.get("b"), None);
+        assert_eq!(keys.get("a"), Some(vec![1]));
+        assert_eq!(keys.keys.read().unwrap().entries.len(), 2);
+    }
+
+    #[test]
+    fn test_file() {
+        let file = KeyringFile {
+            active: "new".into(),
+            keys: vec![
+                KeyEntry {
+                    id: "new".into(),
+                    key: hex::encode([2; 32]),
+                    expires: None,
+                },
+                KeyEntry {
+                    id: "old".into(),
+                    key: hex::encode([1; 32]),
+                    expires: Some(unix_time() + 60),
+                },
+                KeyEntry {
+                    id: "expired".into(),
+                    key: hex::encode([0; 32]),
+                    expires: Some(1),
+                },
+            ],
+        };
+        let keys = Keyring::<SigningKey>::from_file(file.clone()).unwrap();
+        assert_eq!(keys.active().1, SigningKey::from_bytes(&[2; 32]));
+        assert_eq!(keys.get("old"), Some(SigningKey::from_bytes(&[1; 32])));
+        assert_eq!(keys.get("expired"), None);
+        assert!(!format!("{:?}", keys).contains("0202"));
+
+        // An invalid file leaves the keys as they were.
+        let mut invalid = file.clone();
+        invalid.active = "missing".into();
+        assert_eq!(
+            keys.replace(invalid),
+            Err(KeyringError::MissingActive {
+                id: "missing".into()
+            })
+        );
+        let mut invalid = file.clone();
+        invalid.keys[0].key = "0202".into();
+        assert!(matches!(
+            keys.replace(invalid),
+            Err(KeyringError::InvalidKey { .. })
+        ));
+        let mut invalid = file.clone();
+        invalid.keys[0].expires = Some(unix_time() + 60);
+        assert!(matches!(
+            keys.replace(invalid),
+            Err(KeyringError::InvalidKey { .. })
+        ));
+        assert_eq!(keys.active().0, "new");
+
+        // HMAC secrets must be long enough not to be guessed.
+        let secrets = Keyring::<Vec<u8>>::from_file(file.clone()).unwrap();
+        let mut short = file;
+        short.keys[1].key = hex::encode([1; MIN_SECRET_LEN - 1]);
+        assert!(matches!(
+            secrets.replace(short),
+            Err(KeyringError::InvalidKey { .. })
+        ));
+        assert_eq!(secrets.get("old"), Some(vec![1; 32]));
+    }
+}
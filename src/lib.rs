@@ -26,13 +26,102 @@
 //! responses, supporting a number of different serialization content types. Errors compatible with
 //! the `Error` trait are also automatically serialized into the body of an error response and
 //! deserialized into a Rust `Result` in the client.
+//!
+//! The crate also builds for `wasm32-unknown-unknown`, for clients running in a browser. There,
+//! `surf` uses the browser's fetch API, and the `server` module, along with the server-side helpers
+//! in other modules, is not available.
+//!
+//! The crate is split by cargo features, all enabled by default, so that a dependent can build only
+//! the parts it uses:
+//!  * `types`: the shared type definitions in [types] and [tagged_blob]
+//!  * `client`: the `client` module, and the client side of the other modules, with `surf`
+//!  * `server`: the `server` module, and the server side of the other modules, with `tide`
+//!
+//! The client and server both enable `std`, which provides the parts of the protocol they share,
+//! like [wire] and [error]. With only `types`, the crate is `no_std`, and contains just the type
+//! definitions and [SafeToServe]. This lets embedded signers and circuit tooling use the same types
+//! as the services they talk to, without an HTTP stack. Functions which need the clock, signatures,
+//! or the binary wire format, like [Timestamp::now], are only available with `std`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
 
+#[cfg(all(feature = "client", feature = "types"))]
+pub mod address_book;
+#[cfg(feature = "std")]
+pub mod authz;
+#[cfg(feature = "std")]
+pub mod challenge;
+#[cfg(feature = "client")]
 pub mod client;
+#[cfg(all(feature = "types", any(feature = "client", feature = "server")))]
+pub mod clock;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod conditional;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod config;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod consistency;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod cost;
+#[cfg(feature = "std")]
+pub mod cursor;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod deadline;
+#[cfg(feature = "std")]
+pub mod delta;
+#[cfg(feature = "std")]
+pub mod disco;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+#[cfg(feature = "std")]
 pub mod error;
+#[cfg(feature = "std")]
+pub mod fields;
+#[cfg(all(feature = "std", feature = "types"))]
+pub mod filter;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+#[cfg(feature = "std")]
+pub mod headers;
+#[cfg(feature = "std")]
+pub mod keyring;
+#[cfg(feature = "std")]
+pub mod maintenance;
+#[cfg(all(feature = "noise", not(target_arch = "wasm32")))]
+pub mod noise;
+pub mod redact;
+#[cfg(feature = "std")]
+pub mod rpc;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
 pub mod server;
+#[cfg(feature = "std")]
+pub mod signing;
+#[cfg(feature = "types")]
 pub mod tagged_blob;
+#[cfg(all(test, feature = "std"))]
+mod test_util;
+#[cfg(all(feature = "testing", not(target_arch = "wasm32")))]
+pub mod testing;
+#[cfg(all(
+    any(feature = "client", feature = "server"),
+    not(target_arch = "wasm32")
+))]
+pub mod trace_context;
+#[cfg(feature = "types")]
 pub mod types;
+#[cfg(feature = "std")]
+pub mod warnings;
+#[cfg(feature = "std")]
+pub mod webhooks;
+#[cfg(feature = "std")]
+pub mod wire;
 
+#[cfg(feature = "std")]
 pub use error::*;
+pub use redact::{Public, Redacted, SafeToServe};
+#[cfg(feature = "types")]
 pub use tagged_blob::*;
+#[cfg(feature = "types")]
 pub use types::*;
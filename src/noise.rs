@@ -0,0 +1,598 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! HTTP over Noise, for node-to-node APIs.
+//!
+//! Validators talk to each other over networks where there is no certificate authority to issue
+//! TLS certificates, but where each node already has a long-lived key pair which the others know.
+//! This module serves HTTP over connections secured with the Noise protocol framework instead of
+//! TLS, using the `Noise_XX_25519_ChaChaPoly_BLAKE2s` handshake: each side proves that it holds
+//! its [NodeKey], and learns the public key of the other.
+//!
+//! The public key of the other end of a connection is its identity. The server puts it in each
+//! request as a [PeerKey] extension, and the client puts it in each response, so handlers can
+//! authorize requests by who sent them:
+//!
+//! ```no_run
+//! use net::noise::{NodeKey, NoiseServer, PeerKey};
+//!
+//! # async_std::task::block_on(async {
+//! let mut app = tide::new();
+//! app.at("/whoami").get(|req: tide::Request<()>| async move {
+//!     Ok(req.ext::<PeerKey>().unwrap().to_string())
+//! });
+//! let peer = NodeKey::generate().public();
+//! NoiseServer::new(NodeKey::generate())
+//!     .allow(peer)
+//!     .listen(app, "0.0.0.0:8080")
+//!     .await
+//! # });
+//! ```
+//!
+//! On the client side, [NoiseClient] is an HTTP client backend for surf, so the usual client
+//! middleware works unchanged. An XX handshake proves that the server holds some key, not that it
+//! is the server the client meant to reach, so the client must know the key of each server it
+//! talks to in advance, and it refuses to talk to hosts whose key it does not know:
+//!
+//! ```no_run
+//! use net::noise::{NodeKey, NoiseClient};
+//!
+//! # async_std::task::block_on(async {
+//! # let server_key = NodeKey::generate().public();
+//! let noise = NoiseClient::new(NodeKey::generate()).server("validator-1", server_key);
+//! let client = surf::Client::with_http_client(noise);
+//! let whoami = client.get("http://validator-1:8080/whoami").recv_string().await;
+//! # });
+//! ```
+//!
+//! URLs use the `http` scheme, since Noise takes the place of TLS. Each request is sent over a new
+//! connection.
+
+use async_std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::{ready, StreamExt};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use surf::http::{self, StatusCode};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+// The largest Noise message, and the size of the authentication tag on each transport message.
+const MAX_MESSAGE: usize = 65535;
+const TAG_LEN: usize = 16;
+
+/// The static key pair identifying a node.
+#[derive(Clone)]
+pub struct NodeKey {
+    secret: [u8; 32],
+    public: PeerKey,
+}
+
+impl NodeKey {
+    /// A new random key pair.
+    pub fn generate() -> Self {
+        let keypair = Builder::new(PATTERN.parse().unwrap())
+            .generate_keypair()
+            .expect("Curve25519 key generation cannot fail");
+        Self::from_secret(keypair.private.try_into().unwrap())
+    }
+
+    /// The key pair with the secret key `secret`.
+    pub fn from_secret(secret: [u8; 32]) -> Self {
+        let public = PublicKey::from(&StaticSecret::from(secret));
+        Self {
+            secret,
+            public: PeerKey(public.to_bytes()),
+        }
+    }
+
+    /// The public key, which identifies this node to its peers.
+    pub fn public(&self) -> PeerKey {
+        self.public
+    }
+}
+
+impl Debug for NodeKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NodeKey")
+            .field("public", &self.public)
+            .finish()
+    }
+}
+
+/// The public key of the node at the other end of a connection.
+///
+/// Displays as lowercase hex.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PeerKey(pub [u8; 32]);
+
+impl Display for PeerKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+fn invalid_data(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn builder(key: &NodeKey) -> Builder<'_> {
+    Builder::new(PATTERN.parse().unwrap()).local_private_key(&key.secret)
+}
+
+// Handshake messages, and the transport messages of a [NoiseStream], are each sent with a 2-byte
+// big-endian length prefix.
+async fn write_frame(tcp: &mut TcpStream, message: &[u8]) -> io::Result<()> {
+    tcp.write_all(&(message.len() as u16).to_be_bytes()).await?;
+    tcp.write_all(message).await
+}
+
+async fn read_frame(tcp: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut len = [0; 2];
+    tcp.read_exact(&mut len).await?;
+    let mut message = vec![0; u16::from_be_bytes(len) as usize];
+    tcp.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+// Perform the handshake, and check that the peer is one `allowed` accepts.
+async fn handshake(
+    tcp: &mut TcpStream,
+    mut state: HandshakeState,
+    allowed: impl FnOnce(&PeerKey) -> bool,
+) -> io::Result<(StatelessTransportState, PeerKey)> {
+    let mut buf = vec![0; MAX_MESSAGE];
+    while !state.is_handshake_finished() {
+        if state.is_my_turn() {
+            let len = state.write_message(&[], &mut buf).map_err(invalid_data)?;
+            write_frame(tcp, &buf[..len]).await?;
+        } else {
+            let message = read_frame(tcp).await?;
+            state
+                .read_message(&message, &mut buf)
+                .map_err(invalid_data)?;
+        }
+    }
+    let peer = state
+        .get_remote_static()
+        .and_then(|key| key.try_into().ok())
+        .map(PeerKey)
+        .ok_or_else(|| invalid_data("peer did not send a static key"))?;
+    if !allowed(&peer) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("peer {} is not allowed", peer),
+        ));
+    }
+    let transport = state
+        .into_stateless_transport_mode()
+        .map_err(invalid_data)?;
+    Ok((transport, peer))
+}
+
+// An encrypted byte stream over a TCP connection, after the handshake.
+//
+// The HTTP implementation reads and writes through separate clones of the stream, so each
+// direction has its own state, with its own nonce.
+#[derive(Clone)]
+struct NoiseStream {
+    tcp: TcpStream,
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    transport: StatelessTransportState,
+    reader: Mutex<Reader>,
+    writer: Mutex<Writer>,
+}
+
+#[derive(Default)]
+struct Reader {
+    nonce: u64,
+    // The part of the next message received so far, including its length prefix.
+    frame: Vec<u8>,
+    // The decrypted contents of the last message, and how much of it has been read.
+    plaintext: Vec<u8>,
+    pos: usize,
+}
+
+#[derive(Default)]
+struct Writer {
+    nonce: u64,
+    // The message being sent, including its length prefix, and how much of it has been sent.
+    frame: Vec<u8>,
+    pos: usize,
+}
+
+impl NoiseStream {
+    fn new(tcp: TcpStream, transport: StatelessTransportState) -> Self {
+        Self {
+            tcp,
+            inner: Arc::new(Inner {
+                transport,
+                reader: Default::default(),
+                writer: Default::default(),
+            }),
+        }
+    }
+
+    // Finish sending the message in progress, if there is one.
+    fn poll_send(
+        tcp: &mut TcpStream,
+        writer: &mut Writer,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        while writer.pos < writer.frame.len() {
+            let n = ready!(Pin::new(&mut *tcp).poll_write(cx, &writer.frame[writer.pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            writer.pos += n;
+        }
+        writer.frame.clear();
+        writer.pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for NoiseStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut reader = this.inner.reader.lock().unwrap();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        while reader.pos == reader.plaintext.len() {
+            let needed = match *reader.frame.as_slice() {
+                [a, b, ..] => {
+                    let len = u16::from_be_bytes([a, b]) as usize;
+                    if len < TAG_LEN {
+                        return Poll::Ready(Err(invalid_data("truncated Noise message")));
+                    }
+                    2 + len
+                }
+                _ => 2,
+            };
+            if reader.frame.len() < needed {
+                let mut chunk = [0; 4096];
+                let want = (needed - reader.frame.len()).min(chunk.len());
+                let n = ready!(Pin::new(&mut this.tcp).poll_read(cx, &mut chunk[..want]))?;
+                if n == 0 {
+                    // The connection may only close between messages.
+                    return Poll::Ready(if reader.frame.is_empty() {
+                        Ok(0)
+                    } else {
+                        Err(io::ErrorKind::UnexpectedEof.into())
+                    });
+                }
+                reader.frame.extend_from_slice(&chunk[..n]);
+                continue;
+            }
+
+            let Reader {
+                nonce,
+                frame,
+                plaintext,
+                pos,
+            } = &mut *reader;
+            plaintext.resize(frame.len() - 2, 0);
+            let len = this
+                .inner
+                .transport
+                .read_message(*nonce, &frame[2..], plaintext)
+                .map_err(invalid_data)?;
+            plaintext.truncate(len);
+            *nonce += 1;
+            *pos = 0;
+            frame.clear();
+        }
+
+        let n = buf.len().min(reader.plaintext.len() - reader.pos);
+        buf[..n].copy_from_slice(&reader.plaintext[reader.pos..reader.pos + n]);
+        reader.pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for NoiseStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut writer = this.inner.writer.lock().unwrap();
+        ready!(Self::poll_send(&mut this.tcp, &mut writer, cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let len = buf.len().min(MAX_MESSAGE - TAG_LEN);
+        let mut frame = vec![0; 2 + len + TAG_LEN];
+        let message_len = this
+            .inner
+            .transport
+            .write_message(writer.nonce, &buf[..len], &mut frame[2..])
+            .map_err(invalid_data)?;
+        frame[..2].copy_from_slice(&(message_len as u16).to_be_bytes());
+        frame.truncate(2 + message_len);
+        writer.nonce += 1;
+        writer.frame = frame;
+        // The message is queued, so the bytes count as written even if the socket is not ready for
+        // all of them yet. The rest is sent by the next write or flush.
+        if let Poll::Ready(Err(err)) = Self::poll_send(&mut this.tcp, &mut writer, cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let mut writer = this.inner.writer.lock().unwrap();
+        ready!(Self::poll_send(&mut this.tcp, &mut writer, cx))?;
+        Pin::new(&mut this.tcp).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let mut writer = this.inner.writer.lock().unwrap();
+        ready!(Self::poll_send(&mut this.tcp, &mut writer, cx))?;
+        Pin::new(&mut this.tcp).poll_close(cx)
+    }
+}
+
+/// Serves a tide app over Noise connections.
+///
+/// See the [module documentation](self).
+#[derive(Clone, Debug)]
+pub struct NoiseServer {
+    key: NodeKey,
+    allowed: Option<HashSet<PeerKey>>,
+    handshake_timeout: Duration,
+}
+
+impl NoiseServer {
+    /// Accept connections as the node with key pair `key`.
+    ///
+    /// By default, any peer which completes the handshake is served. Use [allow](Self::allow) to
+    /// only serve known peers.
+    pub fn new(key: NodeKey) -> Self {
+        Self {
+            key,
+            allowed: None,
+            handshake_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Serve `peer`, and only the peers allowed with this method.
+    pub fn allow(mut self, peer: PeerKey) -> Self {
+        self.allowed.get_or_insert_with(HashSet::new).insert(peer);
+        self
+    }
+
+    /// Close connections whose handshake does not finish within `timeout`.
+    ///
+    /// The default is 10 seconds. Without a limit, peers could hold connections open forever
+    /// without ever proving who they are.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Listen on `addr`, and serve `app` to each peer which connects.
+    pub async fn listen<State: Clone + Send + Sync + 'static>(
+        self,
+        app: tide::Server<State>,
+        addr: impl ToSocketAddrs,
+    ) -> io::Result<()> {
+        self.accept(app, TcpListener::bind(addr).await?).await
+    }
+
+    /// Serve `app` to each peer which connects to `listener`.
+    pub async fn accept<State: Clone + Send + Sync + 'static>(
+        self,
+        app: tide::Server<State>,
+        listener: TcpListener,
+    ) -> io::Result<()> {
+        let server = Arc::new(self);
+        let mut incoming = listener.incoming();
+        while let Some(tcp) = incoming.next().await {
+            let tcp = match tcp {
+                Ok(tcp) => tcp,
+                Err(err) => {
+                    tracing::warn!("failed to accept connection: {}", err);
+                    continue;
+                }
+            };
+            let server = server.clone();
+            let app = app.clone();
+            async_std::task::spawn(async move {
+                if let Err(err) = server.serve(app, tcp).await {
+                    tracing::debug!("Noise connection failed: {}", err);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    async fn serve<State: Clone + Send + Sync + 'static>(
+        &self,
+        app: tide::Server<State>,
+        mut tcp: TcpStream,
+    ) -> http::Result<()> {
+        let state = builder(&self.key).build_responder()?;
+        let allowed = |peer: &PeerKey| {
+            self.allowed
+                .as_ref()
+                .map_or(true, |allowed| allowed.contains(peer))
+        };
+        let (transport, peer) =
+            async_std::future::timeout(self.handshake_timeout, handshake(&mut tcp, state, allowed))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))??;
+        let peer_addr = tcp.peer_addr().ok();
+        let local_addr = tcp.local_addr().ok();
+        async_h1::accept(NoiseStream::new(tcp, transport), |mut req| {
+            req.set_peer_addr(peer_addr);
+            req.set_local_addr(local_addr);
+            req.ext_mut().insert(peer);
+            app.respond(req)
+        })
+        .await
+    }
+}
+
+/// An HTTP client backend which sends requests over Noise connections.
+///
+/// Use it with `surf::Client::with_http_client`. See the [module documentation](self).
+#[derive(Clone, Debug)]
+pub struct NoiseClient {
+    key: NodeKey,
+    servers: HashMap<String, PeerKey>,
+}
+
+impl NoiseClient {
+    /// Connect as the node with key pair `key`.
+    ///
+    /// Requests can only be sent to hosts whose keys are given with [server](Self::server).
+    pub fn new(key: NodeKey) -> Self {
+        Self {
+            key,
+            servers: HashMap::new(),
+        }
+    }
+
+    /// Expect the server at `host` to have the key `peer`.
+    ///
+    /// `host` is the host of request URLs, without the port. Connections to `host` are refused
+    /// unless the server proves that it holds the secret key for `peer`; a server with any other
+    /// key, even one which is expected for another host, cannot impersonate it.
+    pub fn server(mut self, host: impl Into<String>, peer: PeerKey) -> Self {
+        self.servers.insert(host.into(), peer);
+        self
+    }
+}
+
+#[surf::utils::async_trait]
+impl surf::HttpClient for NoiseClient {
+    async fn send(&self, req: http::Request) -> http::Result<http::Response> {
+        let url = req.url();
+        if url.scheme() != "http" {
+            return Err(http::Error::from_str(
+                StatusCode::BadRequest,
+                format!("Noise connections use http URLs, not {}", url.scheme()),
+            ));
+        }
+        let host = url.host_str().ok_or_else(|| {
+            http::Error::from_str(StatusCode::BadRequest, format!("no host in {}", url))
+        })?;
+        let expected = *self.servers.get(host).ok_or_else(|| {
+            http::Error::from_str(
+                StatusCode::BadRequest,
+                format!("the key of the server at {} is not known", host),
+            )
+        })?;
+        let mut tcp = TcpStream::connect((host, url.port_or_known_default().unwrap_or(80))).await?;
+        let state = builder(&self.key).build_initiator()?;
+        let (transport, peer) = handshake(&mut tcp, state, |peer| *peer == expected).await?;
+        let mut res = async_h1::connect(NoiseStream::new(tcp, transport), req).await?;
+        res.ext_mut().insert(peer);
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn server(server: NoiseServer) -> String {
+        let mut app = tide::new();
+        app.at("/whoami").get(|req: tide::Request<()>| async move {
+            Ok(req.ext::<PeerKey>().unwrap().to_string())
+        });
+        app.at("/echo")
+            .post(|mut req: tide::Request<()>| async move { Ok(req.body_bytes().await?) });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        async_std::task::spawn(server.accept(app, listener));
+        url
+    }
+
+    #[async_std::test]
+    async fn test_noise() {
+        let server_key = NodeKey::generate();
+        let client_key = NodeKey::generate();
+        let url = server(NoiseServer::new(server_key.clone()).allow(client_key.public())).await;
+        let client = surf::Client::with_http_client(
+            NoiseClient::new(client_key.clone()).server("127.0.0.1", server_key.public()),
+        );
+
+        // Each side learns the identity of the other.
+        let mut res = client.get(format!("{}/whoami", url)).await.unwrap();
+        assert_eq!(
+            res.body_string().await.unwrap(),
+            client_key.public().to_string()
+        );
+        assert_eq!(res.ext::<PeerKey>(), Some(&server_key.public()));
+
+        // Bodies larger than one Noise message survive the trip.
+        let body = (0..200_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let echoed = client
+            .post(format!("{}/echo", url))
+            .body(body.clone())
+            .recv_bytes()
+            .await
+            .unwrap();
+        assert_eq!(echoed, body);
+
+        // Peers which are not allowed are refused, on either side.
+        let stranger = surf::Client::with_http_client(
+            NoiseClient::new(NodeKey::generate()).server("127.0.0.1", server_key.public()),
+        );
+        assert!(stranger.get(format!("{}/whoami", url)).await.is_err());
+        let wary = surf::Client::with_http_client(
+            NoiseClient::new(client_key.clone()).server("127.0.0.1", NodeKey::generate().public()),
+        );
+        assert!(wary.get(format!("{}/whoami", url)).await.is_err());
+
+        // Clients refuse to talk to servers whose keys they do not know.
+        let unpinned = surf::Client::with_http_client(
+            NoiseClient::new(client_key).server("localhost", server_key.public()),
+        );
+        assert!(unpinned.get(format!("{}/whoami", url)).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_handshake_timeout() {
+        let url = server(
+            NoiseServer::new(NodeKey::generate()).handshake_timeout(Duration::from_millis(100)),
+        )
+        .await;
+        let mut tcp = TcpStream::connect(url.trim_start_matches("http://"))
+            .await
+            .unwrap();
+
+        // A peer which never sends its first handshake message is disconnected.
+        let mut buf = [0; 1];
+        let read = async_std::future::timeout(Duration::from_secs(5), tcp.read(&mut buf))
+            .await
+            .expect("server should close the connection");
+        assert_eq!(read.unwrap(), 0);
+    }
+}
@@ -0,0 +1,249 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Which types may be sent in response bodies.
+//!
+//! Many types in the Espresso ecosystem hold private key material (user, auditor and freezer key
+//! pairs, for instance) and also implement [Serialize], because wallets need to store them. Nothing
+//! stops such a value from being passed to a generic serialization function, and so a debug endpoint
+//! can leak keys without anyone noticing.
+//!
+//! To prevent this, [response](crate::server::response) and the helpers built on it only accept
+//! bodies which implement the marker trait [SafeToServe]. It is implemented for primitive types,
+//! standard containers of safe types, and the public types in this crate, but deliberately not for
+//! any type holding private keys. Services implement it for their own response types:
+//!
+//! ```
+//! # use net::SafeToServe;
+//! # use serde::Serialize;
+//! #[derive(Serialize)]
+//! struct Balance {
+//!     owner: String,
+//!     amount: u64,
+//! }
+//!
+//! impl SafeToServe for Balance {}
+//! ```
+//!
+//! Strings and JSON values are not [SafeToServe], since they can hold anything: `json!(key_pair)`
+//! or `format!("{:?}", key_pair)` would otherwise get a key past the check. A handler which serves
+//! one says that it has checked the contents by wrapping it in [Public]:
+//!
+//! ```
+//! # use net::{server::response, Public};
+//! fn reloaded(req: &tide::Request<()>, name: &str) -> tide::Result {
+//!     response(req, Public(format!("{} reloaded", name)))
+//! }
+//! ```
+//!
+//! A value which must appear in a response but whose contents must not, such as a key in a debug
+//! dump, can be wrapped in [Redacted].
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::rc::Rc;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{self, Debug, Formatter};
+use serde::{Deserialize, Serialize, Serializer};
+#[cfg(feature = "std")]
+use std::collections::{HashMap, HashSet};
+
+/// Types which contain no secrets, and so may be serialized in response bodies.
+///
+/// Implement this only for types which are safe to show to anyone who can reach the endpoint. Do
+/// not implement it for types which contain private keys, even indirectly. Responding with a type
+/// which does not implement it is a compile error:
+///
+/// ```compile_fail
+/// # use net::server::response;
+/// #[derive(serde::Serialize)]
+/// struct KeyPair {
+///     secret: Vec<u8>,
+/// }
+///
+/// fn leak(req: &tide::Request<()>, key: KeyPair) -> tide::Result {
+///     response(req, key)
+/// }
+/// ```
+///
+/// So is responding with it converted to a string or a JSON value, unless it is wrapped in
+/// [Public]:
+///
+/// ```compile_fail
+/// # use net::server::response;
+/// # #[derive(serde::Serialize)]
+/// # struct KeyPair {
+/// #     secret: Vec<u8>,
+/// # }
+/// fn leak(req: &tide::Request<()>, key: KeyPair) -> tide::Result {
+///     response(req, serde_json::json!(key))
+/// }
+/// ```
+pub trait SafeToServe {}
+
+macro_rules! safe_to_serve {
+    ($($t:ty),* $(,)?) => {
+        $(impl SafeToServe for $t {})*
+    };
+}
+
+safe_to_serve!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64,
+);
+
+impl<T: SafeToServe + ?Sized> SafeToServe for &T {}
+impl<T: SafeToServe + ?Sized> SafeToServe for Box<T> {}
+impl<T: SafeToServe + ?Sized> SafeToServe for Rc<T> {}
+impl<T: SafeToServe + ?Sized> SafeToServe for Arc<T> {}
+impl<T: SafeToServe> SafeToServe for Option<T> {}
+impl<T: SafeToServe> SafeToServe for [T] {}
+impl<T: SafeToServe, const N: usize> SafeToServe for [T; N] {}
+impl<T: SafeToServe> SafeToServe for Vec<T> {}
+impl<T: SafeToServe> SafeToServe for VecDeque<T> {}
+impl<T: SafeToServe> SafeToServe for BTreeSet<T> {}
+#[cfg(feature = "std")]
+impl<T: SafeToServe, S> SafeToServe for HashSet<T, S> {}
+impl<K: SafeToServe, V: SafeToServe> SafeToServe for BTreeMap<K, V> {}
+#[cfg(feature = "std")]
+impl<K: SafeToServe, V: SafeToServe, S> SafeToServe for HashMap<K, V, S> {}
+impl<T: SafeToServe, E: SafeToServe> SafeToServe for Result<T, E> {}
+
+macro_rules! safe_to_serve_tuple {
+    ($($t:ident),+) => {
+        impl<$($t: SafeToServe),+> SafeToServe for ($($t,)+) {}
+    };
+}
+
+safe_to_serve_tuple!(A);
+safe_to_serve_tuple!(A, B);
+safe_to_serve_tuple!(A, B, C);
+safe_to_serve_tuple!(A, B, C, D);
+safe_to_serve_tuple!(A, B, C, D, E);
+safe_to_serve_tuple!(A, B, C, D, E, F);
+
+/// A value which the handler serving it has checked contains no secrets.
+///
+/// `Public` serializes and deserializes exactly as the value it wraps. It is for values like
+/// strings and [serde_json::Value], which are not [SafeToServe] on their own because they can hold
+/// anything; see the [module documentation](self).
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize,
+)]
+#[serde(transparent)]
+pub struct Public<T>(pub T);
+
+impl<T> Public<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> SafeToServe for Public<T> {}
+
+/// A value which is never revealed.
+///
+/// `Redacted` serializes and formats as the string `"<redacted>"`, whatever it contains, so it is
+/// always safe to serve.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Redacted<T>(pub T);
+
+impl<T> Redacted<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Serialize for Redacted<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("<redacted>")
+    }
+}
+
+impl<T> Debug for Redacted<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+impl<T> SafeToServe for Redacted<T> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::response;
+    use tide::http::{Method, Request, Url};
+
+    // A type holding a secret, which must never be served.
+    #[derive(Clone, Serialize)]
+    struct KeyPair {
+        public: String,
+        secret: String,
+    }
+
+    #[derive(Serialize)]
+    struct DebugInfo {
+        name: String,
+        key: Redacted<KeyPair>,
+    }
+
+    impl SafeToServe for DebugInfo {}
+
+    #[async_std::test]
+    async fn test_redacted() {
+        let key = KeyPair {
+            public: "pub".to_string(),
+            secret: "hunter2".to_string(),
+        };
+        let mut app = tide::new();
+        app.at("/debug").get(move |req: tide::Request<()>| {
+            let key = key.clone();
+            async move {
+                response(
+                    &req,
+                    DebugInfo {
+                        name: key.public.clone(),
+                        key: Redacted(key),
+                    },
+                )
+            }
+        });
+        let mut req = Request::new(Method::Get, Url::parse("http://localhost/debug").unwrap());
+        req.insert_header("Accept", "application/json");
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(
+            res.body_string().await.unwrap(),
+            r#"{"name":"pub","key":"<redacted>"}"#
+        );
+        assert_eq!(
+            format!(
+                "{:?}",
+                Redacted(KeyPair {
+                    public: String::new(),
+                    secret: "hunter2".to_string()
+                })
+            ),
+            "<redacted>"
+        );
+    }
+}
@@ -0,0 +1,271 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Transport-agnostic typed RPC.
+//!
+//! The protocol defined by this crate boils down to "send a typed request, receive a typed response
+//! or a typed [Error]". This module captures that protocol independently of HTTP:
+//!  * an [Endpoint] defines the name, request type, and response type of a single call
+//!  * an [RpcClient] can call any [Endpoint]
+//!  * an [RpcServer] can register a handler for any [Endpoint]
+//!
+//! The [http] module implements these traits on top of the HTTP client and server in this crate,
+//! and the [jsonrpc] module serves endpoints as JSON-RPC 2.0 methods. For message-based transports,
+//! such as libp2p request-response streams, [Dispatcher] implements [RpcServer] by routing
+//! binary-encoded messages to handlers, and [MessageClient] implements [RpcClient] on top of any
+//! [Transport] which can deliver those messages to a [Dispatcher]. With the `grpc` feature, the
+//! `grpc` module serves the endpoints of a [Dispatcher] as a gRPC service. Over a long-lived
+//! connection like a WebSocket, the [mux] module multiplexes concurrent calls and server pushes,
+//! and with the `websockets` feature, the `websocket` module runs it over WebSockets.
+
+use crate::error::Error;
+use crate::redact::SafeToServe;
+use crate::wire::binary;
+use futures::future::BoxFuture;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::Arc;
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod http;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod jsonrpc;
+pub mod mux;
+#[cfg(all(feature = "websockets", not(target_arch = "wasm32")))]
+pub mod websocket;
+
+/// The definition of a single RPC call.
+pub trait Endpoint {
+    type Request: Serialize + DeserializeOwned + Send + Sync + 'static;
+    type Response: Serialize + DeserializeOwned + SafeToServe + Send + Sync + 'static;
+
+    /// The name of this endpoint.
+    ///
+    /// Names must be unique within a service. Transports may use the name to route requests; for
+    /// example, the HTTP transport uses it as the path of the route.
+    const NAME: &'static str;
+}
+
+/// A client which can make typed RPC calls, failing with errors of type `E`.
+pub trait RpcClient<E: Error> {
+    fn call<P: Endpoint>(&self, req: P::Request) -> BoxFuture<'_, Result<P::Response, E>>;
+}
+
+/// A server which can dispatch typed RPC calls, failing with errors of type `E`.
+pub trait RpcServer<E: Error> {
+    /// Register a handler for the endpoint `P`.
+    ///
+    /// If a handler for `P` has already been registered, it is replaced.
+    fn register<P, F, Fut>(&mut self, handler: F)
+    where
+        P: Endpoint,
+        F: Fn(P::Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<P::Response, E>> + Send + 'static;
+}
+
+type Handler = Arc<dyn Fn(Vec<u8>) -> BoxFuture<'static, Vec<u8>> + Send + Sync>;
+
+/// An [RpcServer] for message-based transports.
+///
+/// Requests are binary-encoded messages, which are routed to a handler by endpoint name. The
+/// response is a binary encoding of `Result<P::Response, E>`.
+pub struct Dispatcher<E: Error> {
+    handlers: HashMap<&'static str, Handler>,
+    _error: std::marker::PhantomData<fn() -> E>,
+}
+
+impl<E: Error> Dispatcher<E> {
+    pub fn new() -> Self {
+        Self {
+            handlers: HashMap::new(),
+            _error: Default::default(),
+        }
+    }
+
+    /// The names of the registered endpoints.
+    pub fn endpoints(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.handlers.keys().copied()
+    }
+
+    /// Handle an encoded request for the endpoint named `endpoint`.
+    ///
+    /// This always produces an encoded response. Failures to decode the request, or requests for
+    /// unknown endpoints, produce an encoded `E`.
+    pub fn dispatch(&self, endpoint: &str, req: Vec<u8>) -> BoxFuture<'static, Vec<u8>> {
+        match self.handlers.get(endpoint) {
+            Some(handler) => handler(req),
+            None => {
+                let res = encode_result::<(), E>(Err(E::catch_all(format!(
+                    "unknown endpoint {}",
+                    endpoint
+                ))));
+                Box::pin(async move { res })
+            }
+        }
+    }
+}
+
+impl<E: Error> Default for Dispatcher<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E: Error> RpcServer<E> for Dispatcher<E> {
+    fn register<P, F, Fut>(&mut self, handler: F)
+    where
+        P: Endpoint,
+        F: Fn(P::Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<P::Response, E>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        self.handlers.insert(
+            P::NAME,
+            Arc::new(move |req: Vec<u8>| {
+                let handler = handler.clone();
+                Box::pin(async move {
+                    let res = match binary::deserialize::<P::Request>(&req) {
+                        Ok(req) => handler(req).await,
+                        Err(err) => Err(E::catch_all(format!(
+                            "request fails to deserialize: {}",
+                            err
+                        ))),
+                    };
+                    encode_result(res)
+                })
+            }),
+        );
+    }
+}
+
+fn encode_result<T: Serialize, E: Error>(res: Result<T, E>) -> Vec<u8> {
+    binary::serialize(&res).unwrap_or_else(|err| {
+        // If the result cannot be serialized, we can still report the failure.
+        let res: Result<T, E> = Err(E::catch_all(format!(
+            "response fails to serialize: {}",
+            err
+        )));
+        binary::serialize(&res).unwrap()
+    })
+}
+
+/// A transport which can deliver encoded requests to a [Dispatcher].
+pub trait Transport: Send + Sync {
+    type Error: Display + Send;
+
+    /// Send an encoded request for the endpoint named `endpoint` and wait for the encoded
+    /// response.
+    ///
+    /// On the other end of the transport, the request should be passed to [Dispatcher::dispatch].
+    fn round_trip(
+        &self,
+        endpoint: &'static str,
+        req: Vec<u8>,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Self::Error>>;
+}
+
+/// An [RpcClient] for message-based transports.
+pub struct MessageClient<T: Transport> {
+    transport: T,
+}
+
+impl<T: Transport> MessageClient<T> {
+    pub fn new(transport: T) -> Self {
+        Self { transport }
+    }
+}
+
+impl<T: Transport, E: Error> RpcClient<E> for MessageClient<T> {
+    fn call<P: Endpoint>(&self, req: P::Request) -> BoxFuture<'_, Result<P::Response, E>> {
+        Box::pin(async move {
+            let req = binary::serialize(&req)
+                .map_err(|err| E::catch_all(format!("request fails to serialize: {}", err)))?;
+            let res = self
+                .transport
+                .round_trip(P::NAME, req)
+                .await
+                .map_err(|err| E::catch_all(format!("request failed: {}", err)))?;
+            binary::deserialize::<Result<P::Response, E>>(&res)
+                .map_err(|err| E::catch_all(format!("response fails to deserialize: {}", err)))?
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::Error;
+
+    struct Double;
+
+    impl Endpoint for Double {
+        type Request = u64;
+        type Response = u64;
+        const NAME: &'static str = "double";
+    }
+
+    struct Missing;
+
+    impl Endpoint for Missing {
+        type Request = ();
+        type Response = ();
+        const NAME: &'static str = "missing";
+    }
+
+    // A transport which delivers requests directly to an in-process dispatcher.
+    struct Loopback(Dispatcher<Error>);
+
+    impl Transport for Loopback {
+        type Error = String;
+
+        fn round_trip(
+            &self,
+            endpoint: &'static str,
+            req: Vec<u8>,
+        ) -> BoxFuture<'_, Result<Vec<u8>, Self::Error>> {
+            let res = self.0.dispatch(endpoint, req);
+            Box::pin(async move { Ok(res.await) })
+        }
+    }
+
+    #[async_std::test]
+    async fn test_message_transport() {
+        let mut dispatcher = Dispatcher::<Error>::new();
+        dispatcher.register::<Double, _, _>(|n| async move {
+            n.checked_mul(2).ok_or_else(|| Error {
+                msg: "overflow".to_string(),
+            })
+        });
+        let client = MessageClient::new(Loopback(dispatcher));
+
+        assert_eq!(
+            RpcClient::<Error>::call::<Double>(&client, 21)
+                .await
+                .unwrap(),
+            42
+        );
+        assert_eq!(
+            RpcClient::<Error>::call::<Double>(&client, u64::MAX)
+                .await
+                .unwrap_err(),
+            Error {
+                msg: "overflow".to_string()
+            }
+        );
+        assert_eq!(
+            RpcClient::<Error>::call::<Missing>(&client, ())
+                .await
+                .unwrap_err(),
+            Error {
+                msg: "unknown endpoint missing".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,303 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A gRPC gateway for RPC endpoints.
+//!
+//! [GrpcGateway] exposes the endpoints registered with a [Dispatcher] as a tonic gRPC service,
+//! which can be added to a `tonic::transport::Server` like any generated service. Rather than
+//! deriving a protobuf schema for every request and response type, all methods use a single
+//! message type which wraps the binary encoding of the typed request or response (see
+//! [wire::binary](crate::wire::binary)). The schema is described by [GrpcGateway::proto]:
+//!
+//! ```proto
+//! syntax = "proto3";
+//! package net;
+//! message Payload { bytes payload = 1; }
+//! service Rpc {
+//!   rpc <endpoint name>(Payload) returns (Payload);
+//!   ...
+//! }
+//! ```
+//!
+//! Errors are reported as gRPC statuses. The code is derived from the error's HTTP status by
+//! [grpc_code], the message is the error's [Display](std::fmt::Display), and the details are the
+//! binary encoding of the error, so clients of this crate can recover the original `E` with
+//! [status_error].
+
+use super::Dispatcher;
+use crate::error::Error;
+use crate::wire::binary;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tide::StatusCode;
+use tonic::codegen::{http, Body, BoxFuture, Context, Poll, Service, StdError};
+use tonic::{Code, Status};
+
+/// The name of the gRPC service.
+pub const SERVICE_NAME: &str = "net.Rpc";
+
+/// The message type of every gRPC request and response.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Payload {
+    #[prost(bytes = "vec", tag = "1")]
+    pub payload: Vec<u8>,
+}
+
+/// The gRPC status code corresponding to an HTTP status code.
+pub fn grpc_code(status: StatusCode) -> Code {
+    match status {
+        StatusCode::BadRequest | StatusCode::UnprocessableEntity => Code::InvalidArgument,
+        StatusCode::Unauthorized => Code::Unauthenticated,
+        StatusCode::Forbidden => Code::PermissionDenied,
+        StatusCode::NotFound => Code::NotFound,
+        StatusCode::Conflict => Code::AlreadyExists,
+        StatusCode::PreconditionFailed => Code::FailedPrecondition,
+        StatusCode::TooManyRequests => Code::ResourceExhausted,
+        StatusCode::RequestTimeout | StatusCode::GatewayTimeout => Code::DeadlineExceeded,
+        StatusCode::NotImplemented => Code::Unimplemented,
+        StatusCode::ServiceUnavailable => Code::Unavailable,
+        _ if status.is_client_error() => Code::FailedPrecondition,
+        _ => Code::Internal,
+    }
+}
+
+/// Convert an error into a gRPC status.
+pub fn error_status<E: Error>(err: &E) -> Status {
+    let details = binary::serialize(err).unwrap_or_default();
+    Status::with_details(grpc_code(err.status()), err.to_string(), details.into())
+}
+
+/// Recover an error from a gRPC status produced by [error_status].
+pub fn status_error<E: Error>(status: &Status) -> E {
+    binary::deserialize(status.details())
+        .unwrap_or_else(|_| E::catch_all(status.message().to_string()))
+}
+
+/// A gRPC service which serves the endpoints of a [Dispatcher].
+pub struct GrpcGateway<E: Error> {
+    dispatcher: Arc<Dispatcher<E>>,
+}
+
+impl<E: Error> Clone for GrpcGateway<E> {
+    fn clone(&self) -> Self {
+        Self {
+            dispatcher: self.dispatcher.clone(),
+        }
+    }
+}
+
+impl<E: Error> GrpcGateway<E> {
+    pub fn new(dispatcher: Dispatcher<E>) -> Self {
+        Self {
+            dispatcher: Arc::new(dispatcher),
+        }
+    }
+
+    /// A protobuf description of this service.
+    pub fn proto(&self) -> String {
+        let mut endpoints = self.dispatcher.endpoints().collect::<Vec<_>>();
+        endpoints.sort_unstable();
+        let mut proto = String::from(
+            "syntax = \"proto3\";\npackage net;\nmessage Payload { bytes payload = 1; }\nservice Rpc {\n",
+        );
+        for endpoint in endpoints {
+            proto += &format!("  rpc {}(Payload) returns (Payload);\n", endpoint);
+        }
+        proto += "}\n";
+        proto
+    }
+}
+
+// Handles calls to one endpoint.
+struct Call<E: Error> {
+    dispatcher: Arc<Dispatcher<E>>,
+    endpoint: String,
+}
+
+impl<E: Error> Service<tonic::Request<Payload>> for Call<E> {
+    type Response = tonic::Response<Payload>;
+    type Error = Status;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: tonic::Request<Payload>) -> Self::Future {
+        let res = self
+            .dispatcher
+            .dispatch(&self.endpoint, req.into_inner().payload);
+        Box::pin(async move {
+            // The dispatcher encodes a `Result<P::Response, E>`. We don't know `P::Response`
+            // here, but we can still split the result into its variant and payload: bincode encodes
+            // the variant index as a little-endian u32, followed by the payload.
+            let res = res.await;
+            if res.len() < 4 {
+                return Err(Status::internal("malformed response from dispatcher"));
+            }
+            let (variant, payload) = res.split_at(4);
+            if variant == [0, 0, 0, 0] {
+                Ok(tonic::Response::new(Payload {
+                    payload: payload.to_vec(),
+                }))
+            } else {
+                let err = binary::deserialize::<E>(payload).unwrap_or_else(|err| {
+                    E::catch_all(format!("malformed error from dispatcher: {}", err))
+                });
+                Err(error_status(&err))
+            }
+        })
+    }
+}
+
+impl<E: Error, B> Service<http::Request<B>> for GrpcGateway<E>
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let endpoint = req
+            .uri()
+            .path()
+            .strip_prefix(&format!("/{}/", SERVICE_NAME))
+            .map(|endpoint| endpoint.to_string());
+        let dispatcher = self.dispatcher.clone();
+        Box::pin(async move {
+            match endpoint {
+                Some(endpoint) => {
+                    let mut grpc = tonic::server::Grpc::new(tonic::codec::ProstCodec::default());
+                    Ok(grpc
+                        .unary(
+                            Call {
+                                dispatcher,
+                                endpoint,
+                            },
+                            req,
+                        )
+                        .await)
+                }
+                None => Ok(http::Response::builder()
+                    .status(200)
+                    .header("grpc-status", (Code::Unimplemented as i32).to_string())
+                    .header("content-type", "application/grpc")
+                    .body(tonic::codegen::empty_body())
+                    .unwrap()),
+            }
+        })
+    }
+}
+
+impl<E: Error> tonic::server::NamedService for GrpcGateway<E> {
+    const NAME: &'static str = SERVICE_NAME;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::{Endpoint, RpcServer};
+    use crate::test_util::Error;
+    use prost::Message;
+    use std::pin::Pin;
+    use tonic::codegen::Bytes;
+
+    struct Double;
+
+    impl Endpoint for Double {
+        type Request = u64;
+        type Response = u64;
+        const NAME: &'static str = "double";
+    }
+
+    // A request body containing a single gRPC message.
+    struct OneMessage(Option<Bytes>);
+
+    impl Body for OneMessage {
+        type Data = Bytes;
+        type Error = Infallible;
+
+        fn poll_data(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+            Poll::Ready(self.0.take().map(Ok))
+        }
+
+        fn poll_trailers(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+            Poll::Ready(Ok(None))
+        }
+    }
+
+    async fn call(
+        gateway: &mut GrpcGateway<Error>,
+        endpoint: &str,
+        req: u64,
+    ) -> http::Response<tonic::body::BoxBody> {
+        let msg = Payload {
+            payload: binary::serialize(&req).unwrap(),
+        }
+        .encode_to_vec();
+        // A gRPC message is framed with a compression flag and a big-endian length.
+        let mut frame = vec![0];
+        frame.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&msg);
+        let req = http::Request::builder()
+            .method("POST")
+            .uri(format!("http://localhost/{}/{}", SERVICE_NAME, endpoint))
+            .header("content-type", "application/grpc")
+            .body(OneMessage(Some(frame.into())))
+            .unwrap();
+        gateway.call(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_grpc_gateway() {
+        let mut dispatcher = Dispatcher::<Error>::new();
+        dispatcher.register::<Double, _, _>(|n| async move {
+            n.checked_mul(2).ok_or_else(|| Error {
+                msg: "overflow".to_string(),
+            })
+        });
+        let mut gateway = GrpcGateway::new(dispatcher);
+        assert!(gateway
+            .proto()
+            .contains("rpc double(Payload) returns (Payload);"));
+
+        // A successful call returns the binary-encoded response.
+        let mut res = call(&mut gateway, "double", 21).await;
+        let frame = res.body_mut().data().await.unwrap().unwrap();
+        let msg = Payload::decode(&frame[5..]).unwrap();
+        assert_eq!(binary::deserialize::<u64>(&msg.payload).unwrap(), 42);
+
+        // A failed call returns a status from which we can recover the error.
+        let res = call(&mut gateway, "double", u64::MAX).await;
+        let status = Status::from_header_map(res.headers()).unwrap();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), "overflow");
+        assert_eq!(
+            status_error::<Error>(&status),
+            Error {
+                msg: "overflow".to_string()
+            }
+        );
+
+        // Unknown endpoints are reported by the dispatcher.
+        let res = call(&mut gateway, "missing", 0).await;
+        let status = Status::from_header_map(res.headers()).unwrap();
+        assert_eq!(status.message(), "unknown endpoint missing");
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! The HTTP transport for [RpcClient] and [RpcServer].
+//!
+//! Each endpoint is served as `POST /<name>`. Request and response bodies use the content types and
+//! error protocol defined by the `client` and `server` modules, so RPC endpoints can be called by
+//! any client which speaks that protocol, not just [HttpRpcClient].
+
+#[cfg(feature = "client")]
+use super::{Endpoint, RpcClient};
+#[cfg(feature = "client")]
+use crate::client::backend::{self, HttpClient};
+#[cfg(feature = "client")]
+use crate::error::Error;
+#[cfg(feature = "client")]
+use futures::future::BoxFuture;
+
+/// An [RpcClient] which makes calls over HTTP.
+#[cfg(feature = "client")]
+pub struct HttpRpcClient<C: HttpClient> {
+    client: C,
+    base_url: String,
+}
+
+#[cfg(feature = "client")]
+impl<C: HttpClient> HttpRpcClient<C> {
+    /// Create a client for the service at `base_url`.
+    pub fn new(client: C, base_url: impl Into<String>) -> Self {
+        Self {
+            client,
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+impl<C: HttpClient, E: Error> RpcClient<E> for HttpRpcClient<C> {
+    fn call<P: Endpoint>(&self, req: P::Request) -> BoxFuture<'_, Result<P::Response, E>> {
+        Box::pin(async move {
+            backend::post(&self.client, format!("{}/{}", self.base_url, P::NAME), &req).await
+        })
+    }
+}
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+mod server {
+    use crate::error::{server_error, Error};
+    use crate::rpc::{Endpoint, RpcServer};
+    use crate::server::{request_body, response};
+    use std::future::Future;
+    use std::sync::Arc;
+
+    /// Serve RPC endpoints from a tide server.
+    ///
+    /// Handlers report errors using [server_error], so the server should also use the
+    /// [add_error_body](crate::server::add_error_body) middleware with the same error type `E`.
+    impl<S: Clone + Send + Sync + 'static, E: Error> RpcServer<E> for tide::Server<S> {
+        fn register<P, F, Fut>(&mut self, handler: F)
+        where
+            P: Endpoint,
+            F: Fn(P::Request) -> Fut + Send + Sync + 'static,
+            Fut: Future<Output = Result<P::Response, E>> + Send + 'static,
+        {
+            let handler = Arc::new(handler);
+            self.at(P::NAME).post(move |mut req: tide::Request<S>| {
+                let handler = handler.clone();
+                async move {
+                    let body = request_body::<P::Request, S>(&mut req).await?;
+                    let res = handler(body).await.map_err(server_error::<E>)?;
+                    response(&req, res)
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,390 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A JSON-RPC 2.0 transport for [RpcServer].
+//!
+//! [JsonRpc] serves every registered [Endpoint] as a JSON-RPC method named [Endpoint::NAME], behind
+//! a single HTTP route, following the [JSON-RPC 2.0 specification](https://www.jsonrpc.org/specification):
+//!  * a request is an object with `jsonrpc: "2.0"`, a `method`, optional `params`, and an `id`
+//!    unless it is a notification
+//!  * a batch is an array of requests, answered with an array of responses
+//!  * notifications are executed, but get no response
+//!
+//! The `params` of a request are deserialized into the endpoint's request type. As a convenience
+//! for positional calling conventions, if `params` is an array containing a single value which is
+//! the request, that also works.
+//!
+//! Errors returned by handlers are reported as JSON-RPC error objects with code [SERVER_ERROR], the
+//! error's [Display](std::fmt::Display) as the message, and the serialized error as the `data`, so
+//! clients of this crate can recover the original `E`.
+
+use super::{Endpoint, RpcServer};
+use crate::error::Error;
+use futures::future::{join_all, BoxFuture};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use tide::{Response, StatusCode};
+
+/// Invalid JSON was received.
+pub const PARSE_ERROR: i64 = -32700;
+/// The JSON sent is not a valid request object.
+pub const INVALID_REQUEST: i64 = -32600;
+/// The method does not exist.
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// Invalid method parameters.
+pub const INVALID_PARAMS: i64 = -32602;
+/// An error returned by a handler.
+pub const SERVER_ERROR: i64 = -32000;
+
+/// A JSON-RPC request.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    pub method: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<Value>,
+    /// The ID of the request, or [None] for notifications.
+    ///
+    /// Only a request without an `id` is a notification. A request with `"id": null` has the ID
+    /// `Some(Value::Null)`, and gets a response.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_id",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub id: Option<Value>,
+}
+
+// Deserialize an `id` which is present, even if it is null, as [Some]. A missing `id` is [None]
+// through `#[serde(default)]`.
+fn deserialize_id<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(d).map(Some)
+}
+
+/// A JSON-RPC error object.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ErrorObject {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl ErrorObject {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    fn from_error<E: Error>(err: E) -> Self {
+        Self {
+            code: SERVER_ERROR,
+            message: err.to_string(),
+            data: serde_json::to_value(&err).ok(),
+        }
+    }
+
+    /// Recover the error returned by the handler, if this is a [SERVER_ERROR].
+    pub fn into_error<E: Error>(self) -> E {
+        let message = self.message;
+        self.data
+            .and_then(|data| serde_json::from_value(data).ok())
+            .unwrap_or_else(|| E::catch_all(message))
+    }
+}
+
+/// A JSON-RPC response.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JsonRpcResponse {
+    pub jsonrpc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorObject>,
+    pub id: Value,
+}
+
+impl JsonRpcResponse {
+    fn new(id: Value, result: Result<Value, ErrorObject>) -> Self {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        Self {
+            jsonrpc: "2.0".into(),
+            result,
+            error,
+            id,
+        }
+    }
+}
+
+type Method = Arc<dyn Fn(Value) -> BoxFuture<'static, Result<Value, ErrorObject>> + Send + Sync>;
+
+/// An [RpcServer] which serves endpoints as JSON-RPC methods.
+pub struct JsonRpc<E: Error> {
+    methods: HashMap<&'static str, Method>,
+    _error: std::marker::PhantomData<fn() -> E>,
+}
+
+impl<E: Error> Default for JsonRpc<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E: Error> RpcServer<E> for JsonRpc<E> {
+    fn register<P, F, Fut>(&mut self, handler: F)
+    where
+        P: Endpoint,
+        F: Fn(P::Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<P::Response, E>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        self.methods.insert(
+            P::NAME,
+            Arc::new(move |params| {
+                let handler = handler.clone();
+                Box::pin(async move {
+                    let req = parse_params::<P::Request>(params)?;
+                    let res = handler(req).await.map_err(ErrorObject::from_error)?;
+                    serde_json::to_value(res)
+                        .map_err(|err| ErrorObject::from_error(E::catch_all(err.to_string())))
+                })
+            }),
+        );
+    }
+}
+
+fn parse_params<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, ErrorObject> {
+    match serde_json::from_value(params.clone()) {
+        Ok(req) => Ok(req),
+        Err(err) => match params {
+            Value::Array(mut params) if params.len() == 1 => {
+                serde_json::from_value(params.remove(0))
+                    .map_err(|err| ErrorObject::new(INVALID_PARAMS, err.to_string()))
+            }
+            _ => Err(ErrorObject::new(INVALID_PARAMS, err.to_string())),
+        },
+    }
+}
+
+impl<E: Error> JsonRpc<E> {
+    pub fn new() -> Self {
+        Self {
+            methods: HashMap::new(),
+            _error: Default::default(),
+        }
+    }
+
+    /// Handle a JSON-RPC message (a request or a batch).
+    ///
+    /// Returns the response to send, or [None] if nothing should be sent (because the message only
+    /// contained notifications).
+    pub async fn handle(&self, body: &[u8]) -> Option<Value> {
+        let message: Value = match serde_json::from_slice(body) {
+            Ok(message) => message,
+            Err(err) => {
+                return Some(json(JsonRpcResponse::new(
+                    Value::Null,
+                    Err(ErrorObject::new(PARSE_ERROR, err.to_string())),
+                )))
+            }
+        };
+        match message {
+            Value::Array(batch) if batch.is_empty() => Some(json(JsonRpcResponse::new(
+                Value::Null,
+                Err(ErrorObject::new(INVALID_REQUEST, "empty batch")),
+            ))),
+            Value::Array(batch) => {
+                let responses = join_all(batch.into_iter().map(|req| self.handle_one(req)))
+                    .await
+                    .into_iter()
+                    .flatten()
+                    .map(json)
+                    .collect::<Vec<_>>();
+                if responses.is_empty() {
+                    None
+                } else {
+                    Some(Value::Array(responses))
+                }
+            }
+            req => self.handle_one(req).await.map(json),
+        }
+    }
+
+    async fn handle_one(&self, req: Value) -> Option<JsonRpcResponse> {
+        let req = match serde_json::from_value::<Request>(req) {
+            Ok(req) if req.jsonrpc == "2.0" => req,
+            Ok(_) => {
+                return Some(JsonRpcResponse::new(
+                    Value::Null,
+                    Err(ErrorObject::new(
+                        INVALID_REQUEST,
+                        "unsupported jsonrpc version",
+                    )),
+                ))
+            }
+            Err(err) => {
+                return Some(JsonRpcResponse::new(
+                    Value::Null,
+                    Err(ErrorObject::new(INVALID_REQUEST, err.to_string())),
+                ))
+            }
+        };
+        let result = match self.methods.get(req.method.as_str()) {
+            Some(method) => method(req.params.unwrap_or(Value::Null)).await,
+            None => Err(ErrorObject::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method {}", req.method),
+            )),
+        };
+        req.id.map(|id| JsonRpcResponse::new(id, result))
+    }
+
+    /// Serve JSON-RPC messages as POST requests to `route`.
+    pub fn serve<State: Clone + Send + Sync + 'static>(self, route: &mut tide::Route<'_, State>) {
+        let rpc = Arc::new(self);
+        route.post(move |mut req: tide::Request<State>| {
+            let rpc = rpc.clone();
+            async move {
+                let body = req.body_bytes().await?;
+                Ok(match rpc.handle(&body).await {
+                    Some(res) => Response::builder(StatusCode::Ok)
+                        .body(res)
+                        .content_type(tide::http::mime::JSON)
+                        .build(),
+                    None => Response::new(StatusCode::NoContent),
+                })
+            }
+        });
+    }
+}
+
+fn json(res: JsonRpcResponse) -> Value {
+    serde_json::to_value(res).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::Error;
+    use serde_json::json;
+
+    struct Double;
+
+    impl Endpoint for Double {
+        type Request = u64;
+        type Response = u64;
+        const NAME: &'static str = "double";
+    }
+
+    async fn call(rpc: &JsonRpc<Error>, msg: Value) -> Option<Value> {
+        rpc.handle(msg.to_string().as_bytes()).await
+    }
+
+    #[async_std::test]
+    async fn test_jsonrpc() {
+        let mut rpc = JsonRpc::<Error>::new();
+        rpc.register::<Double, _, _>(|n| async move {
+            n.checked_mul(2).ok_or_else(|| Error {
+                msg: "overflow".to_string(),
+            })
+        });
+
+        assert_eq!(
+            call(
+                &rpc,
+                json!({"jsonrpc": "2.0", "method": "double", "params": 21, "id": 1})
+            )
+            .await,
+            Some(json!({"jsonrpc": "2.0", "result": 42, "id": 1}))
+        );
+        assert_eq!(
+            call(
+                &rpc,
+                json!({"jsonrpc": "2.0", "method": "double", "params": [21], "id": 2})
+            )
+            .await,
+            Some(json!({"jsonrpc": "2.0", "result": 42, "id": 2}))
+        );
+
+        // Errors from handlers can be recovered.
+        let res = call(
+            &rpc,
+            json!({"jsonrpc": "2.0", "method": "double", "params": u64::MAX, "id": 3}),
+        )
+        .await
+        .unwrap();
+        let res: JsonRpcResponse = serde_json::from_value(res).unwrap();
+        let err = res.error.unwrap();
+        assert_eq!(err.code, SERVER_ERROR);
+        assert_eq!(
+            err.into_error::<Error>(),
+            Error {
+                msg: "overflow".to_string()
+            }
+        );
+
+        // Batches, with notifications and protocol errors.
+        let res = call(
+            &rpc,
+            json!([
+                {"jsonrpc": "2.0", "method": "double", "params": 1, "id": "a"},
+                {"jsonrpc": "2.0", "method": "double", "params": 1},
+                {"jsonrpc": "2.0", "method": "triple", "params": 1, "id": "b"},
+                {"jsonrpc": "2.0", "method": "double", "params": "one", "id": "c"},
+            ]),
+        )
+        .await
+        .unwrap();
+        let codes = res
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|res| (res["id"].clone(), res["error"]["code"].clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            codes,
+            [
+                (json!("a"), Value::Null),
+                (json!("b"), json!(METHOD_NOT_FOUND)),
+                (json!("c"), json!(INVALID_PARAMS)),
+            ]
+        );
+
+        // A null ID is an ID, not a notification.
+        assert_eq!(
+            call(
+                &rpc,
+                json!({"jsonrpc": "2.0", "method": "double", "params": 1, "id": null})
+            )
+            .await,
+            Some(json!({"jsonrpc": "2.0", "result": 2, "id": null}))
+        );
+
+        // A message with only notifications gets no response.
+        assert_eq!(
+            call(
+                &rpc,
+                json!([{"jsonrpc": "2.0", "method": "double", "params": 1}])
+            )
+            .await,
+            None
+        );
+        assert_eq!(
+            rpc.handle(b"not json").await.unwrap()["error"]["code"],
+            json!(PARSE_ERROR)
+        );
+    }
+}
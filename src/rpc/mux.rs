@@ -0,0 +1,844 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Multiplexed RPC over a single long-lived connection.
+//!
+//! A [MessageClient](super::MessageClient) waits for each response before the transport is free
+//! again. Over a long-lived, message-based connection such as a WebSocket, it is better to have
+//! many calls in flight at once, and to let the server push events to the client on the same
+//! connection. This module implements that:
+//!  * [serve] answers requests from one connection using a [Dispatcher], running them concurrently,
+//!    and returns a [Pusher] for sending events on the same connection
+//!  * [MuxClient] implements [RpcClient] on top of one connection, and receives pushed events with
+//!    [MuxClient::subscribe]
+//!
+//! A connection is any pair of a [Stream] of incoming messages and a [Sink] of outgoing messages,
+//! where a message is a `Vec<u8>`. For a WebSocket, each message is one binary WebSocket message;
+//! with the `websockets` feature, the [websocket](super::websocket) module connects both sides over
+//! WebSockets.
+//! Each message is a binary-encoded frame:
+//!  * a request, with an ID chosen by the client, the name of the endpoint, and the encoded request
+//!  * a response, with the ID of the request it answers, and the encoded `Result<P::Response, E>`
+//!  * a push, with the name of a [Topic] and the encoded item
+//!
+//!  * a subscription, with an ID chosen by the client, the name of a [Topic], and optionally an
+//!    encoded [Filter](crate::filter::Filter)
+//!  * an unsubscription, with the ID of the subscription to end
+//!
+//! Responses are sent as soon as they are ready, so they may arrive in a different order than the
+//! requests, interleaved with pushes. The ID correlates each response with its request. Each call
+//! succeeds or fails on its own: a handler's error fails only that call, with the `E` returned by
+//! the handler. When the connection closes, calls still in flight fail with a catch-all error, and
+//! subscriptions end.
+//!
+//! Subscriptions let a light client avoid receiving events it does not care about: with
+//! [MuxClient::subscribe_filtered], the client sends its filter to the server, and events published
+//! with [Pusher::publish] are only sent if they match the filter of some subscription to their
+//! topic. The client can change the filter of a subscription with
+//! [FilteredSubscription::set_filter], which sends the server the new filter. Since the server's
+//! filters come from the client, each connection can have at most [MAX_SUBSCRIPTIONS]
+//! subscriptions; the server ignores any more.
+//!
+//! Neither side lets the other make it buffer without limit. The server handles at most
+//! [MAX_IN_FLIGHT] requests from a connection at once, and stops reading the connection until some
+//! of them finish. Each side queues at most [MAX_QUEUED] outgoing messages; past that, calls wait
+//! for room, and [Pusher::push] fails with [PushError::Full] rather than queueing events for a
+//! client which is not reading them. Likewise, a subscription which falls more than [MAX_QUEUED]
+//! events behind is dropped, and its stream ends. A call whose future is dropped before it
+//! completes is forgotten, and its response discarded when it arrives.
+//!
+//! Neither side spawns tasks. [serve] and [MuxClient::new] each return a future which drives the
+//! connection, and which the caller should spawn on its executor; it completes when the connection
+//! closes.
+
+use super::{Dispatcher, Endpoint, RpcClient};
+use crate::error::Error;
+#[cfg(feature = "types")]
+use crate::filter::{Filter, Filterable};
+use crate::redact::SafeToServe;
+use crate::wire::binary;
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, BoxFuture, FutureExt};
+use futures::sink::{Sink, SinkExt};
+use futures::stream::{BoxStream, FuturesUnordered, Stream, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use snafu::Snafu;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "types")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// The maximum number of subscriptions the server keeps for each connection.
+pub const MAX_SUBSCRIPTIONS: usize = 256;
+
+/// The maximum number of requests the server handles at once for each connection.
+pub const MAX_IN_FLIGHT: usize = 64;
+
+/// The maximum number of messages queued for sending on each side of a connection, and of events
+/// queued for each subscription.
+pub const MAX_QUEUED: usize = 256;
+
+/// A stream of events which a server can push to its clients.
+pub trait Topic {
+    type Item: Serialize + DeserializeOwned + SafeToServe + Send + Sync + 'static;
+
+    /// The name of this topic.
+    ///
+    /// Names must be unique within a service.
+    const NAME: &'static str;
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+enum Frame {
+    Request {
+        id: u64,
+        endpoint: String,
+        body: Vec<u8>,
+    },
+    Response {
+        id: u64,
+        body: Vec<u8>,
+    },
+    Push {
+        topic: String,
+        body: Vec<u8>,
+    },
+    Subscribe {
+        id: u64,
+        topic: String,
+        filter: Option<Vec<u8>>,
+    },
+    Unsubscribe {
+        id: u64,
+    },
+}
+
+impl Frame {
+    fn encode(&self) -> Vec<u8> {
+        // A frame is just integers, strings and byte arrays, which always serialize.
+        binary::serialize(self).unwrap()
+    }
+}
+
+/// An error pushing an event to a client.
+#[derive(Debug, Snafu)]
+pub enum PushError {
+    #[snafu(display("event fails to serialize: {}", source))]
+    Serialize { source: bincode::Error },
+    #[snafu(display("the client is not keeping up with pushed events"))]
+    Full,
+    #[snafu(display("the connection is closed"))]
+    Closed,
+}
+
+/// A handle for pushing events to the client of a connection being served by [serve].
+#[derive(Clone, Debug)]
+pub struct Pusher {
+    // Shared rather than cloned, since each clone of a sender can queue a message of its own.
+    outgoing: Arc<Mutex<mpsc::Sender<Vec<u8>>>>,
+    // The client's subscriptions, by ID, with their topics and filters.
+    #[cfg(feature = "types")]
+    subscriptions: Arc<Mutex<HashMap<u64, (String, Option<Filter>)>>>,
+}
+
+impl Pusher {
+    /// Push `item` to the client's subscribers to the topic `T`.
+    ///
+    /// If the client has no subscribers to `T`, the item is discarded by the client. If
+    /// [MAX_QUEUED] messages are already waiting to be sent to the client, the item is not sent,
+    /// and this fails with [PushError::Full].
+    pub fn push<T: Topic>(&self, item: &T::Item) -> Result<(), PushError> {
+        let body = binary::serialize(item).map_err(|source| PushError::Serialize { source })?;
+        let frame = Frame::Push {
+            topic: T::NAME.to_string(),
+            body,
+        };
+        self.outgoing
+            .lock()
+            .unwrap()
+            .try_send(frame.encode())
+            .map_err(|err| {
+                if err.is_full() {
+                    PushError::Full
+                } else {
+                    PushError::Closed
+                }
+            })
+    }
+
+    /// Push `item` to the client's subscribers to the topic `T`, if it matches their filters.
+    ///
+    /// The item is sent if it matches the filter of any of the client's subscriptions to `T`, or if
+    /// the client has not told the server about any subscriptions to `T`.
+    #[cfg(feature = "types")]
+    pub fn publish<T: Topic>(&self, item: &T::Item) -> Result<(), PushError>
+    where
+        T::Item: Filterable,
+    {
+        let wanted = {
+            let subscriptions = self.subscriptions.lock().unwrap();
+            let mut filters = subscriptions
+                .values()
+                .filter(|(topic, _)| topic == T::NAME)
+                .map(|(_, filter)| filter)
+                .peekable();
+            filters.peek().is_none()
+                || filters.any(|filter| filter.as_ref().map_or(true, |f| f.matches(item)))
+        };
+        if wanted {
+            self.push::<T>(item)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether the connection has closed.
+    pub fn is_closed(&self) -> bool {
+        self.outgoing.lock().unwrap().is_closed()
+    }
+
+    #[cfg(feature = "types")]
+    fn subscribe(&self, id: u64, topic: String, filter: Option<Vec<u8>>) {
+        let filter = match filter.map(|filter| binary::deserialize::<Filter>(&filter)) {
+            Some(Ok(filter)) => Some(filter),
+            Some(Err(_)) => return,
+            None => None,
+        };
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        if subscriptions.len() < MAX_SUBSCRIPTIONS || subscriptions.contains_key(&id) {
+            subscriptions.insert(id, (topic, filter));
+        }
+    }
+
+    #[cfg(feature = "types")]
+    fn unsubscribe(&self, id: u64) {
+        self.subscriptions.lock().unwrap().remove(&id);
+    }
+}
+
+/// Serve requests from one connection with `dispatcher`.
+///
+/// Returns a [Pusher] for the connection, and a future which serves the connection until either
+/// `incoming` ends or `outgoing` fails. Up to [MAX_IN_FLIGHT] requests are handled concurrently,
+/// and each response is sent as soon as it is ready. Malformed messages, and messages the server
+/// does not expect, are ignored.
+pub fn serve<E, I, O>(
+    dispatcher: Arc<Dispatcher<E>>,
+    incoming: I,
+    outgoing: O,
+) -> (Pusher, BoxFuture<'static, ()>)
+where
+    E: Error,
+    I: Stream<Item = Vec<u8>> + Send + 'static,
+    O: Sink<Vec<u8>> + Send + 'static,
+{
+    let (mut tx, rx) = mpsc::channel(MAX_QUEUED);
+    let pusher = Pusher {
+        outgoing: Arc::new(Mutex::new(tx.clone())),
+        #[cfg(feature = "types")]
+        subscriptions: Default::default(),
+    };
+    #[cfg(feature = "types")]
+    let subscriptions = pusher.clone();
+
+    let read = async move {
+        let mut incoming = Box::pin(incoming.fuse());
+        let mut in_flight = FuturesUnordered::new();
+        loop {
+            // Stop reading requests while too many are in flight, until some of them finish.
+            let mut next = if in_flight.len() < MAX_IN_FLIGHT {
+                incoming.next().left_future()
+            } else {
+                future::pending().right_future()
+            };
+            futures::select! {
+                msg = next => match msg {
+                    Some(msg) => match binary::deserialize(&msg) {
+                        Ok(Frame::Request { id, endpoint, body }) => {
+                            let res = dispatcher.dispatch(&endpoint, body);
+                            in_flight.push(res.map(move |body| Frame::Response { id, body }));
+                        }
+                        #[cfg(feature = "types")]
+                        Ok(Frame::Subscribe { id, topic, filter }) => {
+                            subscriptions.subscribe(id, topic, filter);
+                        }
+                        #[cfg(feature = "types")]
+                        Ok(Frame::Unsubscribe { id }) => subscriptions.unsubscribe(id),
+                        _ => {}
+                    },
+                    None => break,
+                },
+                res = in_flight.select_next_some() => {
+                    if tx.send(res.encode()).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    };
+    let write = rx.map(Ok).forward(outgoing);
+    let conn = async move {
+        future::select(Box::pin(read), Box::pin(write)).await;
+    };
+    (pusher, Box::pin(conn))
+}
+
+type Subscribers = HashMap<&'static str, Vec<mpsc::Sender<Vec<u8>>>>;
+
+#[derive(Default)]
+struct State {
+    pending: HashMap<u64, oneshot::Sender<Vec<u8>>>,
+    subscribers: Subscribers,
+}
+
+/// An [RpcClient] which multiplexes calls over one connection.
+///
+/// See the [module documentation](self).
+#[derive(Clone)]
+pub struct MuxClient {
+    next_id: Arc<AtomicU64>,
+    outgoing: mpsc::Sender<Vec<u8>>,
+    // [None] once the connection has closed.
+    state: Arc<Mutex<Option<State>>>,
+}
+
+impl MuxClient {
+    /// A client for the server on the other end of `incoming` and `outgoing`.
+    ///
+    /// Returns the client, and a future which drives the connection until either `incoming` ends or
+    /// `outgoing` fails.
+    pub fn new<I, O>(incoming: I, outgoing: O) -> (Self, BoxFuture<'static, ()>)
+    where
+        I: Stream<Item = Vec<u8>> + Send + 'static,
+        O: Sink<Vec<u8>> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(MAX_QUEUED);
+        let client = Self {
+            next_id: Default::default(),
+            outgoing: tx,
+            state: Arc::new(Mutex::new(Some(State::default()))),
+        };
+
+        let state = client.state.clone();
+        let read = incoming.for_each(move |msg| {
+            match binary::deserialize(&msg) {
+                Ok(Frame::Response { id, body }) => {
+                    let sender = state
+                        .lock()
+                        .unwrap()
+                        .as_mut()
+                        .and_then(|state| state.pending.remove(&id));
+                    if let Some(sender) = sender {
+                        // The caller may have given up on the call, which is fine.
+                        sender.send(body).ok();
+                    }
+                }
+                Ok(Frame::Push { topic, body }) => {
+                    if let Some(state) = state.lock().unwrap().as_mut() {
+                        if let Some(subscribers) = state.subscribers.get_mut(topic.as_str()) {
+                            // Drop subscriptions which are closed or too far behind.
+                            subscribers.retain_mut(|sub| sub.try_send(body.clone()).is_ok());
+                        }
+                    }
+                }
+                _ => {}
+            }
+            future::ready(())
+        });
+        let write = rx.map(Ok).forward(outgoing);
+        let state = client.state.clone();
+        let conn = async move {
+            future::select(Box::pin(read), Box::pin(write)).await;
+            // Fail calls in flight and end subscriptions, by dropping their senders.
+            state.lock().unwrap().take();
+        };
+        (client, Box::pin(conn))
+    }
+
+    /// Subscribe to the events pushed by the server for the topic `T`.
+    ///
+    /// The stream ends when the connection closes, or if it falls more than [MAX_QUEUED] events
+    /// behind. Events which fail to deserialize are reported as errors, without ending the stream.
+    pub fn subscribe<T: Topic, E: Error>(&self) -> BoxStream<'static, Result<T::Item, E>> {
+        let (guard, rx) = self.open::<T>(None);
+        rx.map(move |body| {
+            let _subscription = &guard;
+            decode_event::<T, E>(&body)
+        })
+        .boxed()
+    }
+
+    /// Subscribe to the events pushed by the server for the topic `T` which match `filter`.
+    ///
+    /// The server only sends events matching the filter, if it publishes them with
+    /// [Pusher::publish]. Events are also checked against the filter as they are received, so the
+    /// stream never yields events which do not match.
+    #[cfg(feature = "types")]
+    pub fn subscribe_filtered<T: Topic, E: Error>(
+        &self,
+        filter: Filter,
+    ) -> FilteredSubscription<T, E>
+    where
+        T::Item: Filterable,
+    {
+        let (subscription, rx) = self.open::<T>(Some(encode_filter(&filter)));
+        let filter = Arc::new(Mutex::new(filter));
+        let events = rx
+            .filter_map({
+                let filter = filter.clone();
+                move |body| {
+                    let event = match decode_event::<T, E>(&body) {
+                        Ok(event) if !filter.lock().unwrap().matches(&event) => None,
+                        res => Some(res),
+                    };
+                    future::ready(event)
+                }
+            })
+            .boxed();
+        FilteredSubscription {
+            events,
+            filter,
+            subscription,
+        }
+    }
+
+    // Register a local subscriber to `T`, and tell the server about it.
+    fn open<T: Topic>(&self, filter: Option<Vec<u8>>) -> (Subscription, mpsc::Receiver<Vec<u8>>) {
+        let (tx, rx) = mpsc::channel(MAX_QUEUED);
+        if let Some(state) = self.state.lock().unwrap().as_mut() {
+            state.subscribers.entry(T::NAME).or_default().push(tx);
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let frame = Frame::Subscribe {
+            id,
+            topic: T::NAME.to_string(),
+            filter,
+        };
+        // If the connection is closed, the stream ends immediately.
+        self.outgoing.clone().try_send(frame.encode()).ok();
+        let guard = Subscription {
+            id,
+            topic: T::NAME,
+            outgoing: self.outgoing.clone(),
+        };
+        (guard, rx)
+    }
+
+    /// Whether the connection has closed.
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().is_none()
+    }
+}
+
+fn decode_event<T: Topic, E: Error>(body: &[u8]) -> Result<T::Item, E> {
+    binary::deserialize(body)
+        .map_err(|err| E::catch_all(format!("event fails to deserialize: {}", err)))
+}
+
+#[cfg(feature = "types")]
+fn encode_filter(filter: &Filter) -> Vec<u8> {
+    // A filter always serializes.
+    binary::serialize(filter).unwrap()
+}
+
+// Tells the server when the stream of a subscription is dropped.
+struct Subscription {
+    id: u64,
+    topic: &'static str,
+    outgoing: mpsc::Sender<Vec<u8>>,
+}
+
+#[cfg(feature = "types")]
+impl Subscription {
+    // Replace the filter the server has for this subscription.
+    fn update(&self, filter: Vec<u8>) {
+        let frame = Frame::Subscribe {
+            id: self.id,
+            topic: self.topic.to_string(),
+            filter: Some(filter),
+        };
+        self.outgoing.clone().try_send(frame.encode()).ok();
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let frame = Frame::Unsubscribe { id: self.id };
+        self.outgoing.try_send(frame.encode()).ok();
+    }
+}
+
+/// A subscription with a filter, created by [MuxClient::subscribe_filtered].
+///
+/// This is a stream of the events matching the filter. The filter can be changed without
+/// subscribing again, for example when a wallet adds an address to its Bloom filter.
+#[cfg(feature = "types")]
+pub struct FilteredSubscription<T: Topic, E> {
+    events: BoxStream<'static, Result<T::Item, E>>,
+    filter: Arc<Mutex<Filter>>,
+    subscription: Subscription,
+}
+
+#[cfg(feature = "types")]
+impl<T: Topic, E> FilteredSubscription<T, E> {
+    pub fn filter(&self) -> Filter {
+        self.filter.lock().unwrap().clone()
+    }
+
+    /// Replace the filter of this subscription.
+    ///
+    /// The new filter applies immediately to events already received but not yet yielded. Events
+    /// the server sends before it receives the new filter are still selected by the old one, so
+    /// events matching only the new filter may be missed for a moment after the change.
+    pub fn set_filter(&self, filter: Filter) {
+        self.subscription.update(encode_filter(&filter));
+        *self.filter.lock().unwrap() = filter;
+    }
+}
+
+#[cfg(feature = "types")]
+impl<T: Topic, E> Stream for FilteredSubscription<T, E> {
+    type Item = Result<T::Item, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_next_unpin(cx)
+    }
+}
+
+// Forgets a call when its future is dropped, whether or not it has completed.
+struct Pending<'a> {
+    id: u64,
+    state: &'a Mutex<Option<State>>,
+}
+
+impl<'a> Drop for Pending<'a> {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.lock().unwrap().as_mut() {
+            state.pending.remove(&self.id);
+        }
+    }
+}
+
+impl<E: Error> RpcClient<E> for MuxClient {
+    fn call<P: Endpoint>(&self, req: P::Request) -> BoxFuture<'_, Result<P::Response, E>> {
+        Box::pin(async move {
+            let body = binary::serialize(&req)
+                .map_err(|err| E::catch_all(format!("request fails to serialize: {}", err)))?;
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            let (tx, rx) = oneshot::channel();
+            match self.state.lock().unwrap().as_mut() {
+                Some(state) => {
+                    state.pending.insert(id, tx);
+                }
+                None => return Err(E::catch_all("the connection is closed".into())),
+            }
+            let _pending = Pending {
+                id,
+                state: &self.state,
+            };
+            let frame = Frame::Request {
+                id,
+                endpoint: P::NAME.to_string(),
+                body,
+            };
+            if self.outgoing.clone().send(frame.encode()).await.is_err() {
+                return Err(E::catch_all("the connection is closed".into()));
+            }
+            let res = rx
+                .await
+                .map_err(|_| E::catch_all("the connection closed during the call".into()))?;
+            binary::deserialize::<Result<P::Response, E>>(&res)
+                .map_err(|err| E::catch_all(format!("response fails to deserialize: {}", err)))?
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::redact::Public;
+    use crate::rpc::RpcServer;
+    use crate::test_util::Error;
+    use futures::future::join;
+
+    struct Double;
+
+    impl Endpoint for Double {
+        type Request = u64;
+        type Response = u64;
+        const NAME: &'static str = "double";
+    }
+
+    // Waits for the test to release it, so that calls finish out of order.
+    struct Slow;
+
+    impl Endpoint for Slow {
+        type Request = ();
+        type Response = Public<String>;
+        const NAME: &'static str = "slow";
+    }
+
+    struct Blocks;
+
+    impl Topic for Blocks {
+        type Item = u64;
+        const NAME: &'static str = "blocks";
+    }
+
+    fn call<P: Endpoint>(
+        client: &MuxClient,
+        req: P::Request,
+    ) -> BoxFuture<'_, Result<P::Response, Error>> {
+        RpcClient::<Error>::call::<P>(client, req)
+    }
+
+    #[async_std::test]
+    async fn test_mux() {
+        let (release, wait) = oneshot::channel::<()>();
+        let wait = wait.shared();
+        let mut dispatcher = Dispatcher::<Error>::new();
+        dispatcher.register::<Double, _, _>(|n| async move {
+            n.checked_mul(2).ok_or_else(|| Error {
+                msg: "overflow".to_string(),
+            })
+        });
+        dispatcher.register::<Slow, _, _>(move |()| {
+            let wait = wait.clone();
+            async move {
+                wait.await.ok();
+                Ok(Public("done".to_string()))
+            }
+        });
+
+        // Connect a client and a server with a pair of channels, like the two ends of a socket.
+        let (client_tx, server_rx) = mpsc::unbounded();
+        let (server_tx, client_rx) = mpsc::unbounded();
+        let (pusher, server) = serve(Arc::new(dispatcher), server_rx, server_tx);
+        let (client, conn) = MuxClient::new(client_rx, client_tx);
+        let server = async_std::task::spawn(server);
+        let conn = async_std::task::spawn(conn);
+        let mut blocks = client.subscribe::<Blocks, Error>();
+
+        // A slow call does not hold up the calls after it, or pushes.
+        let slow = call::<Slow>(&client, ());
+        let fast = async {
+            assert_eq!(call::<Double>(&client, 21).await.unwrap(), 42);
+            assert_eq!(
+                call::<Double>(&client, u64::MAX).await.unwrap_err(),
+                Error {
+                    msg: "overflow".to_string()
+                }
+            );
+            pusher.push::<Blocks>(&7).unwrap();
+            assert_eq!(blocks.next().await.unwrap().unwrap(), 7);
+            release.send(()).unwrap();
+        };
+        let (slow, ()) = join(slow, fast).await;
+        assert_eq!(slow.unwrap().0, "done");
+
+        // When the client hangs up, the server stops.
+        drop(blocks);
+        let closed = client.clone();
+        drop(client);
+        conn.cancel().await;
+        server.await;
+        assert!(pusher.is_closed());
+        assert!(matches!(
+            pusher.push::<Blocks>(&8).unwrap_err(),
+            PushError::Closed
+        ));
+        call::<Double>(&closed, 1).await.unwrap_err();
+    }
+
+    #[async_std::test]
+    async fn test_dropped_calls() {
+        let (client_tx, _server_rx) = mpsc::unbounded();
+        let (_server_tx, client_rx) = mpsc::unbounded();
+        let (client, conn) = MuxClient::new(client_rx, client_tx);
+        async_std::task::spawn(conn);
+
+        // A call which is given up on before the server responds is forgotten.
+        assert!(call::<Double>(&client, 1).now_or_never().is_none());
+        let state = client.state.lock().unwrap();
+        assert!(state.as_ref().unwrap().pending.is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_bounded_dispatch() {
+        let (release, wait) = oneshot::channel::<()>();
+        let wait = wait.shared();
+        let started = Arc::new(AtomicU64::new(0));
+        let mut dispatcher = Dispatcher::<Error>::new();
+        dispatcher.register::<Slow, _, _>({
+            let started = started.clone();
+            move |()| {
+                let wait = wait.clone();
+                started.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    wait.await.ok();
+                    Ok(Public("done".to_string()))
+                }
+            }
+        });
+        let (client_tx, server_rx) = mpsc::unbounded();
+        let (server_tx, client_rx) = mpsc::unbounded();
+        let (_pusher, server) = serve(Arc::new(dispatcher), server_rx, server_tx);
+        let (client, conn) = MuxClient::new(client_rx, client_tx);
+        async_std::task::spawn(server);
+        async_std::task::spawn(conn);
+
+        // The server stops reading requests once it is handling as many as it will at once.
+        let calls = async_std::task::spawn(async move {
+            let calls = (0..MAX_IN_FLIGHT + 10).map(|_| call::<Slow>(&client, ()));
+            for res in future::join_all(calls).await {
+                assert_eq!(res.unwrap().0, "done");
+            }
+        });
+        async_std::task::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(started.load(Ordering::SeqCst), MAX_IN_FLIGHT as u64);
+
+        // It reads the rest as the first ones finish.
+        release.send(()).unwrap();
+        calls.await;
+        assert_eq!(started.load(Ordering::SeqCst), MAX_IN_FLIGHT as u64 + 10);
+    }
+
+    #[async_std::test]
+    async fn test_bounded_pushes() {
+        // A client which never reads what the server sends.
+        let (_client_tx, server_rx) = mpsc::unbounded::<Vec<u8>>();
+        let (server_tx, _client_rx) = mpsc::channel(0);
+        let (pusher, server) = serve(Arc::new(Dispatcher::<Error>::new()), server_rx, server_tx);
+        async_std::task::spawn(server);
+
+        // The server does not queue events for it without limit.
+        let pushed = (0..)
+            .take_while(|i| pusher.push::<Blocks>(i).is_ok())
+            .count();
+        assert!(pushed < 2 * MAX_QUEUED, "{}", pushed);
+        assert!(matches!(
+            pusher.push::<Blocks>(&0).unwrap_err(),
+            PushError::Full
+        ));
+    }
+
+    #[async_std::test]
+    async fn test_lagging_subscriptions() {
+        let mut dispatcher = Dispatcher::<Error>::new();
+        dispatcher.register::<Double, _, _>(|n| async move { Ok(2 * n) });
+        let (client_tx, server_rx) = mpsc::unbounded();
+        let (server_tx, client_rx) = mpsc::unbounded();
+        let (pusher, server) = serve(Arc::new(dispatcher), server_rx, server_tx);
+        let (client, conn) = MuxClient::new(client_rx, client_tx);
+        async_std::task::spawn(server);
+        async_std::task::spawn(conn);
+
+        // A subscription which is not read from falls behind, and is dropped.
+        let blocks = client.subscribe::<Blocks, Error>();
+        let sent = 2 * MAX_QUEUED as u64;
+        for i in 0..sent {
+            while let Err(err) = pusher.push::<Blocks>(&i) {
+                assert!(matches!(err, PushError::Full));
+                async_std::task::yield_now().await;
+            }
+        }
+        // The client receives messages in order, so once this call completes, it has every event.
+        assert_eq!(call::<Double>(&client, 1).await.unwrap(), 2);
+        let received = blocks.map(Result::unwrap).collect::<Vec<_>>().await;
+        assert!(received.len() < sent as usize);
+        assert_eq!(received, (0..received.len() as u64).collect::<Vec<_>>());
+    }
+
+    #[cfg(feature = "types")]
+    #[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+    struct Note(String);
+
+    #[cfg(feature = "types")]
+    impl SafeToServe for Note {}
+
+    #[cfg(feature = "types")]
+    impl Filterable for Note {
+        fn kind(&self) -> Option<&str> {
+            Some(&self.0)
+        }
+    }
+
+    #[cfg(feature = "types")]
+    struct Notes;
+
+    #[cfg(feature = "types")]
+    impl Topic for Notes {
+        type Item = Note;
+        const NAME: &'static str = "notes";
+    }
+
+    #[cfg(feature = "types")]
+    #[async_std::test]
+    async fn test_filtered_subscriptions() {
+        let mut dispatcher = Dispatcher::<Error>::new();
+        dispatcher.register::<Double, _, _>(|n| async move { Ok(2 * n) });
+        let (client_tx, server_rx) = mpsc::unbounded();
+        let (server_tx, client_rx) = mpsc::unbounded::<Vec<u8>>();
+
+        // Count the messages the client receives.
+        let received = Arc::new(AtomicU64::new(0));
+        let client_rx = client_rx.inspect({
+            let received = received.clone();
+            move |_| {
+                received.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        let (pusher, server) = serve(Arc::new(dispatcher), server_rx, server_tx);
+        let (client, conn) = MuxClient::new(client_rx, client_tx);
+        async_std::task::spawn(server);
+        async_std::task::spawn(conn);
+
+        let mut memos = client.subscribe_filtered::<Notes, Error>(Filter::kind("memo"));
+        // The server handles messages in order, so once this call completes, it has the filter.
+        assert_eq!(call::<Double>(&client, 1).await.unwrap(), 2);
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+
+        // Events which do not match are not sent at all.
+        pusher.publish::<Notes>(&Note("block".into())).unwrap();
+        pusher.publish::<Notes>(&Note("memo".into())).unwrap();
+        assert_eq!(memos.next().await.unwrap().unwrap(), Note("memo".into()));
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+
+        // Once the client has an unfiltered subscription, it gets everything.
+        let mut notes = client.subscribe::<Notes, Error>();
+        assert_eq!(call::<Double>(&client, 1).await.unwrap(), 2);
+        pusher.publish::<Notes>(&Note("block".into())).unwrap();
+        pusher.publish::<Notes>(&Note("memo".into())).unwrap();
+        assert_eq!(notes.next().await.unwrap().unwrap(), Note("block".into()));
+        assert_eq!(notes.next().await.unwrap().unwrap(), Note("memo".into()));
+        // The filtered subscription still only yields matching events.
+        assert_eq!(memos.next().await.unwrap().unwrap(), Note("memo".into()));
+
+        // Dropping the unfiltered subscription turns filtering back on.
+        drop(notes);
+        assert_eq!(call::<Double>(&client, 1).await.unwrap(), 2);
+        let before = received.load(Ordering::SeqCst);
+        pusher.publish::<Notes>(&Note("block".into())).unwrap();
+        pusher.publish::<Notes>(&Note("memo".into())).unwrap();
+        assert_eq!(memos.next().await.unwrap().unwrap(), Note("memo".into()));
+        assert_eq!(received.load(Ordering::SeqCst), before + 1);
+
+        // The filter can be changed without subscribing again.
+        memos.set_filter(Filter::kind("block"));
+        assert_eq!(memos.filter(), Filter::kind("block"));
+        assert_eq!(call::<Double>(&client, 1).await.unwrap(), 2);
+        let before = received.load(Ordering::SeqCst);
+        pusher.publish::<Notes>(&Note("memo".into())).unwrap();
+        pusher.publish::<Notes>(&Note("block".into())).unwrap();
+        assert_eq!(memos.next().await.unwrap().unwrap(), Note("block".into()));
+        assert_eq!(received.load(Ordering::SeqCst), before + 1);
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Multiplexed RPC over WebSockets.
+//!
+//! The [mux] module works over any stream and sink of binary messages. This module connects it to
+//! WebSockets: [serve] answers WebSocket connections to a tide route with a [Dispatcher], and
+//! [connect] opens a WebSocket to such a route and returns a [MuxClient] for it.
+//!
+//! ```no_run
+//! # use net::rpc::{mux::Pusher, websocket, Dispatcher};
+//! # use std::sync::Arc;
+//! # #[derive(Clone, Debug, serde::Deserialize, serde::Serialize, snafu::Snafu)]
+//! # struct Error;
+//! # impl net::Error for Error {
+//! #     fn catch_all(_: String) -> Self { Error }
+//! #     fn status(&self) -> tide::StatusCode { tide::StatusCode::InternalServerError }
+//! # }
+//! # async fn example(dispatcher: Dispatcher<Error>, url: surf::Url) {
+//! let mut app = tide::new();
+//! websocket::serve(&mut app.at("/rpc"), Arc::new(dispatcher), |pusher: Pusher| {
+//!     // Keep `pusher` to push events to this client.
+//! });
+//! async_std::task::spawn(app.listen("0.0.0.0:8080"));
+//!
+//! let (client, conn) = websocket::connect(&url).await.unwrap();
+//! async_std::task::spawn(conn);
+//! # }
+//! ```
+//!
+//! Each frame of the multiplexing protocol is one binary WebSocket message. Text messages are
+//! ignored, and the connection ends at the first close message or error.
+
+use super::mux::{self, MuxClient, Pusher};
+use super::Dispatcher;
+use crate::error::Error;
+use async_tungstenite::tungstenite::{self, Message};
+use futures::future::{self, BoxFuture};
+use futures::sink::{self, SinkExt};
+use futures::stream::{Stream, StreamExt};
+use std::sync::Arc;
+use surf::Url;
+use tide_websockets::{WebSocket, WebSocketConnection};
+
+/// Serve multiplexed RPC with `dispatcher` to WebSocket connections on `route`.
+///
+/// `on_connect` is called with the [Pusher] of each connection as it opens, so that the service can
+/// push events to it.
+pub fn serve<State, E, F>(
+    route: &mut tide::Route<'_, State>,
+    dispatcher: Arc<Dispatcher<E>>,
+    on_connect: F,
+) where
+    State: Clone + Send + Sync + 'static,
+    E: Error,
+    F: Fn(Pusher) + Send + Sync + 'static,
+{
+    route.get(WebSocket::new(
+        move |_req: tide::Request<State>, conn: WebSocketConnection| {
+            let outgoing = sink::unfold(conn.clone(), |conn, msg: Vec<u8>| async move {
+                conn.send_bytes(msg).await?;
+                Ok::<_, tide_websockets::Error>(conn)
+            });
+            let (pusher, conn) = mux::serve(dispatcher.clone(), binary_messages(conn), outgoing);
+            on_connect(pusher);
+            async move {
+                conn.await;
+                Ok(())
+            }
+        },
+    ));
+}
+
+/// Connect to a route served with [serve].
+///
+/// `url` is the `ws://` or `wss://` URL of the route. Returns a client and a future which drives
+/// the connection, as [MuxClient::new] does.
+pub async fn connect(url: &Url) -> Result<(MuxClient, BoxFuture<'static, ()>), tungstenite::Error> {
+    let (socket, _) = async_tungstenite::async_std::connect_async(url.as_str()).await?;
+    let (outgoing, incoming) = socket.split();
+    let outgoing =
+        outgoing.with(|msg| future::ready(Ok::<_, tungstenite::Error>(Message::Binary(msg))));
+    Ok(MuxClient::new(binary_messages(incoming), outgoing))
+}
+
+// The binary messages of a WebSocket, up to the first close message or error.
+fn binary_messages<S, E>(messages: S) -> impl Stream<Item = Vec<u8>> + Send + 'static
+where
+    S: Stream<Item = Result<Message, E>> + Send + 'static,
+{
+    messages
+        .take_while(|msg| future::ready(matches!(msg, Ok(msg) if !msg.is_close())))
+        .filter_map(|msg| {
+            future::ready(match msg {
+                Ok(Message::Binary(body)) => Some(body),
+                _ => None,
+            })
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::mux::Topic;
+    use crate::rpc::{Endpoint, RpcClient, RpcServer};
+    use crate::test_util::Error;
+    use async_std::net::TcpListener;
+    use futures::channel::oneshot;
+    use std::sync::Mutex;
+
+    struct Double;
+
+    impl Endpoint for Double {
+        type Request = u64;
+        type Response = u64;
+        const NAME: &'static str = "double";
+    }
+
+    struct Blocks;
+
+    impl Topic for Blocks {
+        type Item = u64;
+        const NAME: &'static str = "blocks";
+    }
+
+    #[async_std::test]
+    async fn test_websocket() {
+        let mut dispatcher = Dispatcher::<Error>::new();
+        dispatcher.register::<Double, _, _>(|n| async move { Ok(2 * n) });
+        let (tx, rx) = oneshot::channel();
+        let tx = Mutex::new(Some(tx));
+        let mut app = tide::new();
+        serve(&mut app.at("/rpc"), Arc::new(dispatcher), move |pusher| {
+            if let Some(tx) = tx.lock().unwrap().take() {
+                tx.send(pusher).ok();
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        async_std::task::spawn(app.listen(listener));
+
+        let url = format!("ws://127.0.0.1:{}/rpc", port).parse().unwrap();
+        let (client, conn) = connect(&url).await.unwrap();
+        async_std::task::spawn(conn);
+        let pusher = rx.await.unwrap();
+        let mut blocks = client.subscribe::<Blocks, Error>();
+
+        let res = RpcClient::<Error>::call::<Double>(&client, 21)
+            .await
+            .unwrap();
+        assert_eq!(res, 42);
+        pusher.push::<Blocks>(&7).unwrap();
+        assert_eq!(blocks.next().await.unwrap().unwrap(), 7);
+    }
+}
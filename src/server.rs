@@ -5,13 +5,94 @@
 // This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
 // You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::config::ServiceConfig;
 use crate::error::Error;
+use crate::fields::{Fields, Project, FIELDS_PARAM};
+use crate::headers::{JsonIntegers, TypedHeader};
+use crate::redact::SafeToServe;
+use crate::wire::{
+    binary,
+    body::{BodyBytes, Origin},
+    csv::{self, TabularSerialize},
+    evolve::Decoding,
+    integers::{IntegerFormat, IntegersAsStrings},
+    ndjson, stream,
+};
+use bincode::Options;
 use futures::future::BoxFuture;
+use futures::stream::{BoxStream, Stream};
 use mime::Mime;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tide::http::{content::Accept, mime};
-use tide::{Body, Next, Request, Response, StatusCode};
-use tracing::{event, Level};
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+pub mod admin;
+pub mod audit;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod coalesce;
+pub mod concurrency;
+#[cfg(all(feature = "dev", debug_assertions))]
+pub mod dev;
+pub mod envelope;
+pub mod error_body;
+pub mod forwarded;
+pub mod health;
+pub mod idempotency;
+pub mod instrument;
+pub mod ip_filter;
+pub mod logging;
+pub mod methods;
+pub mod negotiate;
+pub mod panics;
+pub mod peer;
+pub mod pool;
+#[cfg(feature = "client")]
+pub mod proxy;
+pub mod quota;
+pub mod rate_limit;
+pub mod replay;
+pub mod response_cache;
+pub mod route;
+pub mod session;
+#[cfg(feature = "client")]
+pub mod shadow;
+pub mod static_files;
+pub mod tenancy;
+#[cfg(feature = "tower")]
+pub mod tower;
+pub mod transform;
+
+pub use admin::Admin;
+pub use audit::AuditLog;
+pub use coalesce::Coalesce;
+pub use concurrency::{concurrency_limit, ConcurrencyLimit, Priority};
+#[cfg(all(feature = "dev", debug_assertions))]
+pub use dev::{dev_mode, DevMode};
+pub use envelope::Envelopes;
+pub use error_body::{ErrorBody, ErrorContext, ErrorRegistry, Unrouted};
+pub use forwarded::{client_info, ClientInfo, ForwardingHeader, ProxyHeaders, TrustedProxies};
+pub use health::Health;
+pub use idempotency::Idempotency;
+pub use instrument::Instrument;
+pub use ip_filter::{ip_filter, Cidr, IpFilter, Policy};
+pub use logging::Logger;
+pub use methods::{auto_methods, AutoMethods};
+pub use negotiate::{negotiate, respond, respond_tabular, Negotiator, ResponseType};
+pub use panics::catch_panics;
+pub use peer::{peer_identity, ClientCerts, PeerIdentity};
+#[cfg(feature = "client")]
+pub use proxy::{proxy, Proxy};
+pub use quota::{MemoryQuotaStore, QuotaStore, Quotas};
+pub use rate_limit::{rate_limit, rate_limit_per_client, RateLimit};
+pub use replay::{MemoryReplayStore, ReplayStore, Replays};
+pub use response_cache::BodyCache;
+pub use session::{session, Session, Sessions};
+#[cfg(feature = "client")]
+pub use shadow::{shadow_to, Shadow};
+pub use static_files::serve_static;
+pub use tenancy::{tenant, Tenants};
+pub use transform::Transforms;
 
 /// Deserialize the body of a request.
 ///
@@ -19,112 +100,447 @@ use tracing::{event, Level};
 pub async fn request_body<T: for<'de> Deserialize<'de>, S>(
     req: &mut Request<S>,
 ) -> Result<T, tide::Error> {
-    if let Some(content_type) = req.header("Content-Type") {
-        match content_type.as_str() {
-            "application/json" => req.body_json().await,
-            "application/octet-stream" => {
-                let bytes = req.body_bytes().await?;
-                bincode::deserialize(&bytes).map_err(|err| {
-                    tide::Error::from_str(
-                        StatusCode::BadRequest,
-                        format!("unable to deserialie request body: {}", err),
-                    )
-                })
-            }
-            content_type => Err(tide::Error::from_str(
-                StatusCode::BadRequest,
-                format!("unsupported content type {}", content_type),
-            )),
-        }
-    } else {
-        Err(tide::Error::from_str(
-            StatusCode::BadRequest,
-            "unspecified content type",
-        ))
-    }
+    request_bytes(req).await?.decode()
+}
+
+/// Deserialize the body of a request, as strictly as `decoding` says.
+///
+/// With [Decoding::Tolerant], a server can read requests from newer clients which have added
+/// fields to their types; see [wire::evolve](crate::wire::evolve).
+pub async fn request_body_with<T: for<'de> Deserialize<'de>, S>(
+    req: &mut Request<S>,
+    decoding: Decoding,
+) -> Result<T, tide::Error> {
+    request_bytes(req).await?.decode_with(decoding)
+}
+
+/// Read the body of a request into memory, without deserializing it.
+///
+/// The result can be deserialized into types which borrow from the body using [BodyBytes::decode].
+pub async fn request_bytes<S>(req: &mut Request<S>) -> Result<BodyBytes, tide::Error> {
+    let content_type = req
+        .header("Content-Type")
+        .map(|content_type| content_type.as_str().to_string());
+    BodyBytes::read(Origin::Request, content_type, req.take_body()).await
+}
+
+/// Deserialize the items of a streaming request body as they arrive.
+///
+/// The body must have been produced by [stream_body](crate::client::stream_body); see
+/// [wire::stream](crate::wire::stream) for the format. The Content-Type header determines the
+/// serialization format of each item.
+pub fn request_body_stream<T: DeserializeOwned, S>(
+    req: &mut Request<S>,
+) -> BoxStream<'static, Result<T, tide::Error>> {
+    let content_type = req
+        .header("Content-Type")
+        .map(|content_type| content_type.as_str().to_string());
+    stream::decode(Origin::Request, content_type, req.take_body())
+}
+
+/// Parse a tagged base64 route parameter.
+///
+/// The parameter is percent-decoded and then normalized with
+/// [normalize_tagged_base64](crate::normalize_tagged_base64), so clients may send either base64
+/// alphabet, with or without padding. A parameter which is missing or malformed is a 400 Bad
+/// Request error. To get a typed value, pass the result to
+/// [TaggedBlob::from_tagged_blob](crate::TaggedBlob::from_tagged_blob).
+#[cfg(feature = "types")]
+pub fn tagged_param<S>(
+    req: &Request<S>,
+    name: &str,
+) -> Result<tagged_base64::TaggedBase64, tide::Error> {
+    let bad_request = |msg: String| tide::Error::from_str(StatusCode::BadRequest, msg);
+    let param = req
+        .param(name)
+        .map_err(|err| bad_request(err.to_string()))?;
+    let param = percent_encoding::percent_decode_str(param)
+        .decode_utf8()
+        .map_err(|err| bad_request(format!("invalid parameter {}: {}", name, err)))?;
+    let param = crate::normalize_tagged_base64(&param)
+        .map_err(|err| bad_request(format!("invalid parameter {}: {}", name, err)))?;
+    tagged_base64::TaggedBase64::parse(&param)
+        .map_err(|err| bad_request(format!("invalid parameter {}: {}", name, err)))
 }
 
+/// Choose the content type of a response.
+///
+/// Each type in `available` is given the weight (q-factor) of the most specific proposal in
+/// `accept` which matches it: an exact match takes precedence over `type/*`, which takes
+/// precedence over `*/*`. The type with the highest weight is chosen, and ties go to the type which
+/// comes first in `available`, so `available` should be in the server's order of preference. Types
+/// with a weight of 0 are never chosen.
+///
+/// If there is no Accept header, the first available type is chosen. If no available type is
+/// acceptable, the result is a 406 Not Acceptable error.
+///
+/// This knows nothing of the defaults and preferences of a [Negotiator]. The functions in this
+/// module which negotiate for themselves, like [response], use the type the negotiator chose for
+/// requests which passed through one, and only call this for other requests.
 pub fn best_response_type(
     accept: &mut Option<Accept>,
     available: &[Mime],
 ) -> Result<Mime, tide::Error> {
-    match accept {
-        Some(accept) => {
-            // The Accept type has a `negotiate` method, but it doesn't properly handle
-            // wildcards. It handles * but not */* and basetype/*, because for content type
-            // proposals like */* and basetype/*, it looks for a literal match in `available`,
-            // it does not perform pattern matching. So, we implement negotiation ourselves.
-            //
-            // First sort by the weight parameter, which the Accept type does do correctly.
-            accept.sort();
-            // Go through each proposed content type, in the order specified by the client, and
-            // match them against our available types, respecting wildcards.
-            for proposed in accept.iter() {
-                if proposed.basetype() == "*" {
-                    // The only acceptable Accept value with a basetype of * is */*, therefore
-                    // this will match any available type.
-                    return Ok(available[0].clone());
-                } else if proposed.subtype() == "*" {
-                    // If the subtype is * but the basetype is not, look for a proposed type
-                    // with a matching basetype and any subtype.
-                    for mime in available {
-                        if mime.basetype() == proposed.basetype() {
-                            return Ok(mime.clone());
-                        }
-                    }
-                } else if available.contains(proposed) {
-                    // If neither part of the proposal is a wildcard, look for a literal match.
-                    return Ok((**proposed).clone());
-                }
-            }
-
-            if accept.wildcard() {
-                // If no proposals are available but a wildcard flag * was given, return any
-                // available content type.
-                Ok(available[0].clone())
-            } else {
-                Err(tide::Error::from_str(
-                    StatusCode::NotAcceptable,
-                    "No suitable Content-Type found",
-                ))
+    let accept = match accept {
+        Some(accept) => accept,
+        None => return Ok(available[0].clone()),
+    };
+    // The Accept type has a `negotiate` method, but it doesn't properly handle wildcards (it looks
+    // for literal matches of proposals like */* in `available`), and it chooses by the order of the
+    // proposals rather than by the order of the available types. So, we implement negotiation
+    // ourselves.
+    let mut best: Option<(&Mime, f32)> = None;
+    for mime in available {
+        match (accept_weight(accept, mime), best) {
+            (Some(weight), Some((_, best_weight))) if weight > best_weight => {
+                best = Some((mime, weight))
             }
+            (Some(weight), None) if weight > 0.0 => best = Some((mime, weight)),
+            _ => {}
         }
-        None => {
-            // If no content type is explicitly requested, default to the first available type.
-            Ok(available[0].clone())
+    }
+    match best {
+        Some((mime, _)) => Ok(mime.clone()),
+        None => Err(tide::Error::from_str(
+            StatusCode::NotAcceptable,
+            "No suitable Content-Type found",
+        )),
+    }
+}
+
+// The weight given to `mime` by its most specific matching proposal in `accept`, or [None] if no
+// proposal matches.
+fn accept_weight(accept: &Accept, mime: &Mime) -> Option<f32> {
+    // A bare `*` in the Accept header is not a proposal; it sets the wildcard flag, which matches
+    // anything with the lowest specificity.
+    let mut best = if accept.wildcard() {
+        Some((0, 1.0))
+    } else {
+        None
+    };
+    for proposed in accept.iter() {
+        let specificity = if proposed.basetype() == "*" {
+            0
+        } else if proposed.basetype() != mime.basetype() {
+            continue;
+        } else if proposed.subtype() == "*" {
+            1
+        } else if proposed.subtype() == mime.subtype() {
+            2
+        } else {
+            continue;
+        };
+        match best {
+            Some((best_specificity, _)) if best_specificity >= specificity => {}
+            _ => best = Some((specificity, proposed.weight().unwrap_or(1.0))),
         }
     }
+    best.map(|(_, weight)| weight)
+}
+
+/// The content types which can be used for response bodies, in order of preference.
+pub(crate) const RESPONSE_TYPES: [Mime; 2] = [mime::JSON, mime::BYTE_STREAM];
+
+/// Serialize a response body using `ty`, which must be one of [RESPONSE_TYPES].
+pub(crate) fn serialize_body<T: Serialize>(ty: &Mime, body: &T) -> Result<Vec<u8>, tide::Error> {
+    let mut buf = Vec::new();
+    serialize_body_into(ty, body, &mut buf)?;
+    Ok(buf)
+}
+
+/// Serialize a response body using `ty`, appending it to `buf`.
+pub(crate) fn serialize_body_into<T: Serialize>(
+    ty: &Mime,
+    body: &T,
+    buf: &mut Vec<u8>,
+) -> Result<(), tide::Error> {
+    if *ty == mime::BYTE_STREAM {
+        Ok(binary::options().serialize_into(buf, body)?)
+    } else if *ty == mime::JSON {
+        Ok(serde_json::to_writer(buf, body)?)
+    } else {
+        unreachable!()
+    }
 }
 
-fn respond_with<T: Serialize>(
+// Negotiate a response type using `accept` and serialize `body`, whether or not it is
+// [SafeToServe]. Only for bodies which are known to be safe, like errors.
+fn respond_to_accept<T: Serialize>(
     accept: &mut Option<Accept>,
     body: T,
 ) -> Result<Response, tide::Error> {
-    let ty = best_response_type(accept, &[mime::JSON, mime::BYTE_STREAM])?;
-    if ty == mime::BYTE_STREAM {
-        let bytes = bincode::serialize(&body)?;
-        Ok(Response::builder(tide::StatusCode::Ok)
-            .body(bytes)
-            .content_type(mime::BYTE_STREAM)
-            .build())
-    } else if ty == mime::JSON {
-        Ok(Response::builder(tide::StatusCode::Ok)
-            .body(Body::from_json(&body)?)
-            .content_type(mime::JSON)
-            .build())
+    let ty = best_response_type(accept, &RESPONSE_TYPES)?;
+    serialize_response(ty, &body)
+}
+
+// Serialize `body` using `ty`, which must be one of [RESPONSE_TYPES], or [pretty_json].
+fn serialize_response<T: Serialize>(ty: Mime, body: &T) -> Result<Response, tide::Error> {
+    let mut buf = pool::global().get();
+    let ty = if is_pretty(&ty) {
+        serde_json::to_writer_pretty(buf.as_mut_vec(), body)?;
+        mime::JSON
     } else {
-        unreachable!()
+        serialize_body_into(&ty, body, buf.as_mut_vec())?;
+        ty
+    };
+    Ok(Response::builder(tide::StatusCode::Ok)
+        .body(buf.into_body())
+        .content_type(ty)
+        .build())
+}
+
+/// Serialize the body of a response using the content type `ty`.
+///
+/// This is for handlers which must always use a particular type, regardless of what the client
+/// asked for (for instance, proofs which are only meaningful in binary). `ty` must be one of the
+/// supported response types, `application/json` or `application/octet-stream`.
+///
+/// JSON is pretty-printed if `ty` is [pretty_json], as it is when [negotiate] chooses JSON for a
+/// request which asks for pretty-printing.
+pub fn respond_with<T: Serialize + SafeToServe>(
+    ty: &Mime,
+    body: T,
+) -> Result<Response, tide::Error> {
+    if !RESPONSE_TYPES.contains(ty) && !is_pretty(ty) {
+        return Err(tide::Error::from_str(
+            StatusCode::InternalServerError,
+            format!("unsupported response type {}", ty),
+        ));
+    }
+    serialize_response(ty.clone(), &body)
+}
+
+/// A response containing each item of `items` as a line of JSON, as it becomes available.
+///
+/// This is for exports too large to hold in memory, like every block in a ledger; the client reads
+/// the items one at a time with [response_ndjson](crate::client::response_ndjson). See
+/// [wire::ndjson](crate::wire::ndjson) for the format.
+pub fn ndjson_response<T, S>(items: S) -> Response
+where
+    T: Serialize + SafeToServe + 'static,
+    S: Stream<Item = T> + Unpin + Send + Sync + 'static,
+{
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(ndjson::encode(items));
+    res
+}
+
+// Compare secrets without leaking the position of the first difference through timing.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The query parameter, or parameter of a type in the Accept header, which asks for pretty-printed
+/// JSON, as in `?pretty` or `Accept: application/json; pretty=true`.
+///
+/// This is for people exploring an API with curl; clients should not use it. A value of `false` or
+/// `0` turns pretty-printing off.
+pub const PRETTY_PARAM: &str = "pretty";
+
+/// `application/json`, marked to be pretty-printed by [respond_with].
+pub fn pretty_json() -> Mime {
+    format!("{}; {}=true", mime::JSON.essence(), PRETTY_PARAM)
+        .parse()
+        .unwrap()
+}
+
+fn is_pretty(ty: &Mime) -> bool {
+    ty.essence() == mime::JSON.essence()
+        && ty
+            .param(PRETTY_PARAM)
+            .map_or(false, |value| is_set(value.as_str()))
+}
+
+fn is_set(flag: &str) -> bool {
+    !matches!(flag, "false" | "0")
+}
+
+/// Whether `req` asks for pretty-printed JSON, with [PRETTY_PARAM] in its query string or Accept
+/// header.
+pub fn wants_pretty<S>(req: &Request<S>) -> bool {
+    if let Some((_, value)) = req
+        .url()
+        .query_pairs()
+        .find(|(name, _)| name == PRETTY_PARAM)
+    {
+        return is_set(&value);
+    }
+    match Accept::from_headers(req) {
+        Ok(Some(accept)) => accept.iter().any(|proposal| {
+            proposal
+                .param(PRETTY_PARAM)
+                .map_or(false, |value| is_set(value.as_str()))
+        }),
+        _ => false,
+    }
+}
+
+// The type to respond to `req` with, when [best_response_type] chose `ty`.
+pub(crate) fn with_pretty<S>(req: &Request<S>, ty: Mime) -> Mime {
+    if ty == mime::JSON && wants_pretty(req) {
+        pretty_json()
+    } else {
+        ty
+    }
+}
+
+// Serialize `body` using `ty`, keeping only the selected `fields` of a JSON body.
+fn select_response<T: Serialize>(
+    ty: Mime,
+    body: &T,
+    fields: Option<&Fields>,
+) -> Result<Response, tide::Error> {
+    match fields {
+        Some(fields) if ty.essence() == mime::JSON.essence() => {
+            let mut value = serde_json::to_value(body)?;
+            fields.select(&mut value);
+            serialize_response(ty, &value)
+        }
+        _ => serialize_response(ty, body),
+    }
+}
+
+/// The fields selected by `req` with [FIELDS_PARAM], if any.
+///
+/// Fails with 400 Bad Request if the selection is malformed.
+pub fn requested_fields<S>(req: &Request<S>) -> Result<Option<Fields>, tide::Error> {
+    match req
+        .url()
+        .query_pairs()
+        .find(|(name, _)| name == FIELDS_PARAM)
+    {
+        Some((_, value)) => value
+            .parse()
+            .map(Some)
+            .map_err(|err| tide::Error::from_str(StatusCode::BadRequest, err)),
+        None => Ok(None),
     }
 }
 
 /// Serialize the body of a response.
 ///
-/// The Accept header of the request is used to determine the serialization format.
+/// The Accept header of the request is used to determine the serialization format. JSON is
+/// pretty-printed if the request asks for it with [PRETTY_PARAM], and trimmed to the fields it
+/// selects with [FIELDS_PARAM]; see [fields](crate::fields).
 ///
 /// This function combined with the [add_error_body] middleware defines the server-side protocol
 /// for encoding espresso types in HTTP responses.
-pub fn response<T: Serialize, S>(req: &Request<S>, body: T) -> Result<Response, tide::Error> {
-    respond_with(&mut Accept::from_headers(req)?, body)
+///
+/// `body` must be [SafeToServe], so that types holding private keys can never be sent.
+///
+/// If the request has a [JsonIntegers] header asking for strings, 64- and 128-bit integers in a
+/// JSON response are written as strings; see [wire::integers](crate::wire::integers).
+pub fn response<T: Serialize + SafeToServe, S>(
+    req: &Request<S>,
+    body: T,
+) -> Result<Response, tide::Error> {
+    let fields = requested_fields(req)?;
+    selected_response(req, body, fields.as_ref())
+}
+
+/// Serialize the body of a response exactly as `body` serializes, ignoring any fields selected by
+/// the request and its [JsonIntegers] header.
+///
+/// This is like [response], for responses which are only meaningful as they are, like signed
+/// responses, whose signature covers the entire body with its integers as numbers.
+pub fn response_untrimmed<T: Serialize + SafeToServe, S>(
+    req: &Request<S>,
+    body: T,
+) -> Result<Response, tide::Error> {
+    select_response(response_type(req)?, &body, None)
+}
+
+// The type [response] serializes a response to `req` in.
+pub(crate) fn response_type<S>(req: &Request<S>) -> Result<Mime, tide::Error> {
+    let ty = match negotiated_type(req.ext(), &RESPONSE_TYPES) {
+        Some(ty) => ty,
+        None => best_response_type(&mut Accept::from_headers(req)?, &RESPONSE_TYPES)?,
+    };
+    Ok(with_pretty(req, ty))
+}
+
+// The type [negotiate] chose, if the request passed through it and the type is one of `available`,
+// so that a [Negotiator]'s preferences and defaults also apply to responses which are not sent with
+// [respond].
+pub(crate) fn negotiated_type(ty: Option<&ResponseType>, available: &[Mime]) -> Option<Mime> {
+    let ResponseType(ty) = ty?;
+    available
+        .iter()
+        .find(|available| available.essence() == ty.essence())
+        .cloned()
+}
+
+fn selected_response<T: Serialize + SafeToServe, S>(
+    req: &Request<S>,
+    body: T,
+    fields: Option<&Fields>,
+) -> Result<Response, tide::Error> {
+    let ty = response_type(req)?;
+    match integer_format(req)? {
+        IntegerFormat::Number => select_response(ty, &body, fields),
+        IntegerFormat::String => select_response(ty, &IntegersAsStrings(&body), fields),
+    }
+}
+
+/// Serialize the body of a response, trimmed to the fields selected by the request.
+///
+/// This is like [response], but binary responses are trimmed too, by projecting `body` onto the
+/// selected fields with [Project].
+pub fn response_projected<T: Serialize + Project + SafeToServe, S>(
+    req: &Request<S>,
+    mut body: T,
+) -> Result<Response, tide::Error> {
+    if let Some(fields) = requested_fields(req)? {
+        body.project(&fields);
+    }
+    response(req, body)
+}
+
+// The format for large integers in JSON responses requested by the [JsonIntegers] header.
+fn integer_format<S>(req: &Request<S>) -> Result<IntegerFormat, tide::Error> {
+    Ok(JsonIntegers::get(req)?
+        .map(|JsonIntegers(format)| format)
+        .unwrap_or_default())
+}
+
+/// Serialize the body of a response which can also be served as CSV.
+///
+/// This is like [response], but if the client prefers `text/csv` to the other response types, the
+/// body is serialized as a table; see [wire::csv](crate::wire::csv). CSV is never chosen unless the
+/// client asks for it.
+pub fn response_tabular<T: Serialize + TabularSerialize + SafeToServe, S>(
+    req: &Request<S>,
+    body: T,
+) -> Result<Response, tide::Error> {
+    let available = [mime::JSON, mime::BYTE_STREAM, csv::mime()];
+    let ty = match negotiated_type(req.ext(), &available) {
+        Some(ty) => ty,
+        None => best_response_type(&mut Accept::from_headers(req)?, &available)?,
+    };
+    let ty = with_pretty(req, ty);
+    let fields = requested_fields(req)?;
+    match (ty.essence() == csv::CONTENT_TYPE, integer_format(req)?) {
+        (true, _) => tabular_response(ty, &body),
+        (false, IntegerFormat::Number) => select_response(ty, &body, fields.as_ref()),
+        (false, IntegerFormat::String) => {
+            select_response(ty, &IntegersAsStrings(&body), fields.as_ref())
+        }
+    }
+}
+
+// Serialize `body` using `ty`, which may be CSV or one of [RESPONSE_TYPES].
+pub(crate) fn tabular_response<T: Serialize + TabularSerialize>(
+    ty: Mime,
+    body: &T,
+) -> Result<Response, tide::Error> {
+    if ty.essence() == csv::CONTENT_TYPE {
+        Ok(Response::builder(StatusCode::Ok)
+            .body(csv::to_csv(body))
+            .content_type(ty)
+            .build())
+    } else {
+        serialize_response(ty, body)
+    }
 }
 
 /// Server middleware which automatically populates the body of error responses.
@@ -136,6 +552,9 @@ pub fn response<T: Serialize, S>(req: &Request<S>, body: T) -> Result<Response,
 ///
 /// If the response does not contain an error, it is passed through unchanged.
 ///
+/// This is equivalent to the default configuration of [ErrorBody], which can be used to transform
+/// errors or attach help messages to them.
+///
 /// This middleware is the inverse of the client-side middleware `parse_error_body`, which
 /// automatically converts error responses into [Err] variants, assuming the responses follow
 /// the convention implemented by this middleware.
@@ -143,41 +562,187 @@ pub fn add_error_body<'a, T: Clone + Send + Sync + 'static, E: Error>(
     req: Request<T>,
     next: Next<'a, T>,
 ) -> BoxFuture<'a, tide::Result> {
-    Box::pin(async {
-        let mut accept = Accept::from_headers(&req)?;
-        let mut res = next.run(req).await;
-        if let Some(error) = res.take_error() {
-            let error = E::from_client_error(error);
-            event!(Level::WARN, "responding with error: {}", error);
-            let mut res = respond_with(&mut accept, &error)?;
-            res.set_status(error.status());
-            Ok(res)
-        } else {
-            Ok(res)
-        }
-    })
+    Box::pin(async { ErrorBody::<E>::default().handle(req, next).await })
 }
 
 /// Server middleware which logs requests and responses.
+///
+/// This is equivalent to the default configuration of [Logger], which can be used for more control
+/// over what is logged.
 pub fn trace<'a, T: Clone + Send + Sync + 'static>(
     req: tide::Request<T>,
     next: tide::Next<'a, T>,
 ) -> BoxFuture<'a, tide::Result> {
-    Box::pin(async {
-        event!(
-            Level::INFO,
-            "<-- received request {{url: {}, content-type: {:?}, accept: {:?}}}",
-            req.url(),
-            req.content_type(),
-            Accept::from_headers(&req),
+    Box::pin(async { Logger::default().handle(req, next).await })
+}
+
+/// Serve `app` as configured by `config`.
+///
+/// This applies the concurrency and rate limits in `config`, if there are any, and listens on
+/// [bind](ServiceConfig::bind) over plain HTTP until the server fails. Other middleware, including
+/// logging, should already be attached to `app`, and the service is responsible for installing a
+/// tracing subscriber. A service which needs TLS should run behind a terminating proxy and set
+/// [external_url](ServiceConfig::external_url) to the proxy's URL.
+pub async fn serve<State: Clone + Send + Sync + 'static>(
+    mut app: tide::Server<State>,
+    config: &ServiceConfig,
+) -> std::io::Result<()> {
+    if let Some(max_in_flight) = config.max_in_flight {
+        app.with(concurrency_limit(max_in_flight));
+    }
+    if let Some((per_second, burst)) = config.rate_limit() {
+        app.with(rate_limit(per_second, burst));
+    }
+    tracing::info!("listening on {}, serving {}", config.bind, config.url());
+    app.listen(config.bind).await
+}
+
+#[cfg(all(test, feature = "types"))]
+mod test {
+    use super::*;
+    use tagged_base64::TaggedBase64;
+    use tide::http::{Method, Url};
+
+    #[async_std::test]
+    async fn test_tagged_param() {
+        let mut app = tide::new();
+        app.at("/tx/:id").get(|req: Request<()>| async move {
+            let id = tagged_param(&req, "id")?;
+            Ok(id.to_string())
+        });
+        let get = |path: &str| {
+            let url = Url::parse("http://localhost/").unwrap().join(path).unwrap();
+            app.respond::<_, tide::http::Response>(tide::http::Request::new(Method::Get, url))
+        };
+
+        let blob = TaggedBase64::new("TX", &[0xfb, 0xff, 0xbf]).unwrap();
+        let canonical = blob.to_string();
+        let standard = canonical.replace('-', "+").replace('_', "/");
+        assert_ne!(standard, canonical);
+
+        // The canonical form, and the standard alphabet, both percent-encoded and with padding, all
+        // parse to the same value.
+        for path in [
+            format!("tx/{}", canonical),
+            format!("tx/{}", standard.replace('+', "%2B").replace('/', "%2F")),
+            format!("tx/{}%3D", standard.replace('+', "%2B").replace('/', "%2F")),
+        ] {
+            let mut res = get(&path).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Ok, "{}", path);
+            assert_eq!(res.body_string().await.unwrap(), canonical);
+        }
+
+        // A value mixing the alphabets is rejected.
+        assert_eq!(
+            get("tx/TX~ab%2Bc_d").await.unwrap().status(),
+            StatusCode::BadRequest
+        );
+    }
+
+    #[async_std::test]
+    async fn test_json_integers() {
+        let mut app = tide::new();
+        app.at("/balance")
+            .get(|req: Request<()>| async move { response(&req, (u64::MAX, 1u8)) });
+        let get = |accept: &str, integers: Option<&str>| {
+            let url = Url::parse("http://localhost/balance").unwrap();
+            let mut req = tide::http::Request::new(Method::Get, url);
+            req.insert_header("Accept", accept);
+            if let Some(integers) = integers {
+                req.insert_header(crate::headers::JSON_INTEGERS, integers);
+            }
+            app.respond::<_, tide::http::Response>(req)
+        };
+
+        let mut res = get("application/json", None).await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "[18446744073709551615,1]");
+        let mut res = get("application/json", Some("string")).await.unwrap();
+        assert_eq!(
+            res.body_string().await.unwrap(),
+            r#"["18446744073709551615",1]"#
         );
-        let res = next.run(req).await;
-        event!(
-            Level::INFO,
-            "--> responding with {{content-type: {:?}, error: {:?}}}",
-            res.content_type(),
-            res.error(),
+
+        // Binary responses are not affected.
+        let mut res = get("application/octet-stream", Some("string"))
+            .await
+            .unwrap();
+        assert_eq!(
+            res.body_bytes().await.unwrap(),
+            binary::serialize(&(u64::MAX, 1u8)).unwrap()
+        );
+
+        let res = get("application/json", Some("bigint")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+    }
+
+    #[async_std::test]
+    async fn test_fields() {
+        #[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+        struct Block {
+            height: u64,
+            transactions: Vec<String>,
+        }
+
+        impl SafeToServe for Block {}
+
+        impl Project for Block {
+            fn project(&mut self, fields: &Fields) {
+                fields.retain("height", &mut self.height);
+                fields.retain("transactions", &mut self.transactions);
+            }
+        }
+
+        fn block() -> Block {
+            Block {
+                height: 10,
+                transactions: vec!["tx".into()],
+            }
+        }
+
+        let mut app = tide::new();
+        app.at("/block")
+            .get(|req: Request<()>| async move { response(&req, block()) });
+        app.at("/projected")
+            .get(|req: Request<()>| async move { response_projected(&req, block()) });
+        let get = |path: &str, accept: &str| {
+            let url = Url::parse("http://localhost/").unwrap().join(path).unwrap();
+            let mut req = tide::http::Request::new(Method::Get, url);
+            req.insert_header("Accept", accept);
+            app.respond::<_, tide::http::Response>(req)
+        };
+
+        let mut res = get("block?fields=height", "application/json")
+            .await
+            .unwrap();
+        assert_eq!(res.body_string().await.unwrap(), r#"{"height":10}"#);
+
+        // Without a projection, binary responses are sent in full.
+        let mut res = get("block?fields=height", "application/octet-stream")
+            .await
+            .unwrap();
+        let full: Block = binary::deserialize(&res.body_bytes().await.unwrap()).unwrap();
+        assert_eq!(full, block());
+
+        // With one, they are projected onto the selection.
+        let mut res = get("projected?fields=height", "application/octet-stream")
+            .await
+            .unwrap();
+        let projected: Block = binary::deserialize(&res.body_bytes().await.unwrap()).unwrap();
+        assert_eq!(
+            projected,
+            Block {
+                height: 10,
+                transactions: vec![],
+            }
         );
-        Ok(res)
-    })
+        let mut res = get("projected?fields=height", "application/json")
+            .await
+            .unwrap();
+        assert_eq!(res.body_string().await.unwrap(), r#"{"height":10}"#);
+
+        let res = get("block?fields=height,", "application/json")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+    }
 }
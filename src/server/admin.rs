@@ -0,0 +1,389 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Administrative endpoints for operators.
+//!
+//! [Admin] serves a group of endpoints under `/admin` which let an operator inspect and control a
+//! running service without restarting it:
+//!  * `GET /admin/log` gets the current tracing filter directives
+//!  * `PUT /admin/log` replaces the filter directives with the request body (a string), for example
+//!    to turn on debug logs for one module
+//!  * `GET /admin/state` dumps the state of middleware and metrics, as registered with
+//!    [Admin::state]
+//!  * `POST /admin/shutdown` asks the service to shut down gracefully (see [Admin::shutdown])
+//!  * `GET /admin/maintenance` gets the maintenance in effect, if any, `PUT /admin/maintenance`
+//!    turns maintenance mode on with the [UnderMaintenance] in the request body, and
+//!    `DELETE /admin/maintenance` turns it off (see [Admin::maintenance])
+//!  * `POST /admin/reload/:name` reloads a resource registered with [Admin::reload], like a
+//!    [Keyring](crate::keyring::Keyring) after its file has been edited
+//!
+//! Every endpoint requires an `Authorization: Bearer <token>` header with the token the [Admin] was
+//! created with. Requests without it fail with 401.
+//!
+//! This crate does not choose a tracing subscriber, so the log filter is changed through the
+//! [LogControl] trait. With `tracing-subscriber`, implement it using a `reload::Handle` for an
+//! `EnvFilter`.
+
+use super::{constant_time_eq, request_body, response};
+use crate::maintenance::{Maintenance, UnderMaintenance};
+use crate::redact::Public;
+use futures::channel::oneshot;
+use futures::future::{FutureExt, Shared};
+use serde_json::{Map, Value};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use tide::{Request, StatusCode};
+use tracing::{event, Level};
+
+/// Runtime control of the tracing filter.
+pub trait LogControl: Send + Sync {
+    /// The current filter directives.
+    fn directives(&self) -> String;
+
+    /// Replace the filter directives.
+    fn set_directives(&self, directives: &str) -> Result<(), String>;
+}
+
+type StateFn = Arc<dyn Fn() -> Value + Send + Sync>;
+type ReloadFn = Arc<dyn Fn() -> Result<(), String> + Send + Sync>;
+
+/// The minimum length of the token for the admin endpoints, in bytes.
+pub const MIN_TOKEN_LEN: usize = crate::keyring::MIN_SECRET_LEN;
+
+/// A group of administrative endpoints.
+#[derive(Clone)]
+pub struct Admin {
+    token: Arc<String>,
+    log: Option<Arc<dyn LogControl>>,
+    state: Vec<(String, StateFn)>,
+    reload: Vec<(String, ReloadFn)>,
+    maintenance: Option<Maintenance>,
+    shutdown_tx: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    shutdown_rx: Shared<oneshot::Receiver<()>>,
+}
+
+impl Admin {
+    /// Admin endpoints which can be used by clients with `token`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `token` is shorter than [MIN_TOKEN_LEN] bytes.
+    pub fn new(token: impl Into<String>) -> Self {
+        let token = token.into();
+        assert!(
+            token.len() >= MIN_TOKEN_LEN,
+            "admin token must be at least {} bytes",
+            MIN_TOKEN_LEN
+        );
+        let (tx, rx) = oneshot::channel();
+        Self {
+            token: Arc::new(token),
+            log: None,
+            state: Vec::new(),
+            reload: Vec::new(),
+            maintenance: None,
+            shutdown_tx: Arc::new(Mutex::new(Some(tx))),
+            shutdown_rx: rx.shared(),
+        }
+    }
+
+    /// Allow the tracing filter to be changed using `control`.
+    pub fn log_control(mut self, control: impl LogControl + 'static) -> Self {
+        self.log = Some(Arc::new(control));
+        self
+    }
+
+    /// Include the result of `f` in the state dump, under `name`.
+    ///
+    /// For example, `admin.state("concurrency", move || json!(limit.all_stats()))`. The dump is
+    /// served as [Public](crate::Public), so `f` must not include anything secret.
+    pub fn state(
+        mut self,
+        name: impl Into<String>,
+        f: impl Fn() -> Value + Send + Sync + 'static,
+    ) -> Self {
+        self.state.push((name.into(), Arc::new(f)));
+        self
+    }
+
+    /// Allow an operator to reload the resource `name` with `f`.
+    ///
+    /// For example, to rotate keys without a restart,
+    /// `admin.reload("sessions", move || keys.reload(&path).map_err(|err| err.to_string()))`.
+    pub fn reload(
+        mut self,
+        name: impl Into<String>,
+        f: impl Fn() -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.reload.push((name.into(), Arc::new(f)));
+        self
+    }
+
+    /// Allow maintenance mode to be turned on and off using `maintenance`.
+    ///
+    /// `maintenance` should be a clone of the middleware used by the service. The admin endpoints
+    /// are exempt from maintenance by default, so that it can always be turned off again.
+    pub fn maintenance(mut self, maintenance: Maintenance) -> Self {
+        self.maintenance = Some(maintenance);
+        self
+    }
+
+    /// A future which completes when an operator requests a shutdown.
+    ///
+    /// Race this against the server, and shut down gracefully when it completes.
+    pub fn shutdown(&self) -> impl Future<Output = ()> {
+        self.shutdown_rx.clone().map(|_| ())
+    }
+
+    fn authorize<State>(&self, req: &Request<State>) -> Result<(), tide::Error> {
+        let authorized = match req.header("Authorization") {
+            Some(auth) => match auth.as_str().strip_prefix("Bearer ") {
+                Some(token) => constant_time_eq(token.as_bytes(), self.token.as_bytes()),
+                None => false,
+            },
+            None => false,
+        };
+        if authorized {
+            Ok(())
+        } else {
+            Err(tide::Error::from_str(
+                StatusCode::Unauthorized,
+                "admin endpoints require a valid bearer token",
+            ))
+        }
+    }
+
+    fn logs(&self) -> Result<&dyn LogControl, tide::Error> {
+        self.log.as_deref().ok_or_else(|| {
+            tide::Error::from_str(StatusCode::NotImplemented, "log filter is not configurable")
+        })
+    }
+
+    fn maintenance_control(&self) -> Result<&Maintenance, tide::Error> {
+        self.maintenance.as_ref().ok_or_else(|| {
+            tide::Error::from_str(
+                StatusCode::NotImplemented,
+                "maintenance mode is not configured",
+            )
+        })
+    }
+
+    /// Add the admin endpoints to `app`.
+    pub fn serve<State: Clone + Send + Sync + 'static>(&self, app: &mut tide::Server<State>) {
+        let admin = self.clone();
+        app.at("/admin/log").get(move |req: Request<State>| {
+            let admin = admin.clone();
+            async move {
+                admin.authorize(&req)?;
+                response(&req, Public(admin.logs()?.directives()))
+            }
+        });
+        let admin = self.clone();
+        app.at("/admin/log").put(move |mut req: Request<State>| {
+            let admin = admin.clone();
+            async move {
+                admin.authorize(&req)?;
+                let directives: String = request_body(&mut req).await?;
+                admin
+                    .logs()?
+                    .set_directives(&directives)
+                    .map_err(|err| tide::Error::from_str(StatusCode::BadRequest, err))?;
+                event!(Level::WARN, "log filter changed to {}", directives);
+                response(&req, Public(directives))
+            }
+        });
+        let admin = self.clone();
+        app.at("/admin/state").get(move |req: Request<State>| {
+            let admin = admin.clone();
+            async move {
+                admin.authorize(&req)?;
+                let state = admin
+                    .state
+                    .iter()
+                    .map(|(name, f)| (name.clone(), f()))
+                    .collect::<Map<_, _>>();
+                response(&req, Public(state))
+            }
+        });
+        let admin = self.clone();
+        app.at("/admin/shutdown").post(move |req: Request<State>| {
+            let admin = admin.clone();
+            async move {
+                admin.authorize(&req)?;
+                event!(Level::WARN, "shutdown requested through admin endpoint");
+                if let Some(tx) = admin.shutdown_tx.lock().unwrap().take() {
+                    tx.send(()).ok();
+                }
+                let mut res = response(&req, Public("shutting down"))?;
+                res.set_status(StatusCode::Accepted);
+                Ok(res)
+            }
+        });
+        let admin = self.clone();
+        app.at("/admin/reload/:name")
+            .post(move |req: Request<State>| {
+                let admin = admin.clone();
+                async move {
+                    admin.authorize(&req)?;
+                    let name = req.param("name")?;
+                    let (_, reload) =
+                        admin
+                            .reload
+                            .iter()
+                            .find(|(n, _)| n == name)
+                            .ok_or_else(|| {
+                                tide::Error::from_str(
+                                    StatusCode::NotFound,
+                                    format!("no reloadable resource named {}", name),
+                                )
+                            })?;
+                    reload().map_err(|err| {
+                        tide::Error::from_str(StatusCode::UnprocessableEntity, err)
+                    })?;
+                    event!(Level::WARN, "{} reloaded through admin endpoint", name);
+                    response(&req, Public(format!("{} reloaded", name)))
+                }
+            });
+        let admin = self.clone();
+        app.at("/admin/maintenance")
+            .get(move |req: Request<State>| {
+                let admin = admin.clone();
+                async move {
+                    admin.authorize(&req)?;
+                    response(&req, admin.maintenance_control()?.status())
+                }
+            });
+        let admin = self.clone();
+        app.at("/admin/maintenance")
+            .put(move |mut req: Request<State>| {
+                let admin = admin.clone();
+                async move {
+                    admin.authorize(&req)?;
+                    let maintenance: UnderMaintenance = request_body(&mut req).await?;
+                    let until = maintenance
+                        .until
+                        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+                    admin
+                        .maintenance_control()?
+                        .enable(until, maintenance.reason.clone());
+                    response(&req, maintenance)
+                }
+            });
+        let admin = self.clone();
+        app.at("/admin/maintenance")
+            .delete(move |req: Request<State>| {
+                let admin = admin.clone();
+                async move {
+                    admin.authorize(&req)?;
+                    admin.maintenance_control()?.disable();
+                    response(&req, Public("maintenance mode disabled"))
+                }
+            });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tide::http::{Method, Url};
+
+    const TOKEN: &str = "0123456789abcdef0123456789abcdef";
+
+    #[derive(Default)]
+    struct Filter(Mutex<String>);
+
+    impl LogControl for Arc<Filter> {
+        fn directives(&self) -> String {
+            self.0.lock().unwrap().clone()
+        }
+
+        fn set_directives(&self, directives: &str) -> Result<(), String> {
+            *self.0.lock().unwrap() = directives.to_string();
+            Ok(())
+        }
+    }
+
+    async fn send(
+        app: &tide::Server<()>,
+        method: Method,
+        path: &str,
+        token: Option<&str>,
+        body: Option<&str>,
+    ) -> tide::http::Response {
+        let mut req = tide::http::Request::new(
+            method,
+            Url::parse("http://localhost").unwrap().join(path).unwrap(),
+        );
+        req.insert_header("Accept", "application/json");
+        if let Some(token) = token {
+            req.insert_header("Authorization", format!("Bearer {}", token));
+        }
+        if let Some(body) = body {
+            req.set_body(serde_json::to_string(body).unwrap());
+            req.set_content_type(tide::http::mime::JSON);
+        }
+        app.respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_admin() {
+        let filter = Arc::new(Filter::default());
+        let keys = crate::keyring::Keyring::new("old", vec![1]);
+        let admin = Admin::new(TOKEN)
+            .log_control(filter.clone())
+            .state("answer", || serde_json::json!(42))
+            .reload("keys", {
+                let keys = keys.clone();
+                move || {
+                    keys.rotate("new", vec![2], Duration::ZERO);
+                    Ok(())
+                }
+            });
+        let mut app = tide::new();
+        admin.serve(&mut app);
+
+        // Requests without the token are rejected.
+        let res = send(&app, Method::Get, "/admin/state", None, None).await;
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        let res = send(&app, Method::Get, "/admin/state", Some("wrong"), None).await;
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        let mut res = send(&app, Method::Get, "/admin/state", Some(TOKEN), None).await;
+        assert_eq!(
+            res.body_json::<Value>().await.unwrap(),
+            serde_json::json!({"answer": 42})
+        );
+
+        let res = send(
+            &app,
+            Method::Put,
+            "/admin/log",
+            Some(TOKEN),
+            Some("net=debug"),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(filter.directives(), "net=debug");
+
+        let res = send(&app, Method::Post, "/admin/reload/keys", Some(TOKEN), None).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(keys.active().0, "new");
+        let res = send(&app, Method::Post, "/admin/reload/none", Some(TOKEN), None).await;
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        let res = send(&app, Method::Post, "/admin/shutdown", Some(TOKEN), None).await;
+        assert_eq!(res.status(), StatusCode::Accepted);
+        admin.shutdown().await;
+    }
+
+    #[test]
+    #[should_panic(expected = "admin token must be at least")]
+    fn test_short_token() {
+        Admin::new("");
+    }
+}
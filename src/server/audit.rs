@@ -0,0 +1,490 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A tamper-evident audit log of served requests.
+//!
+//! The [AuditLog] middleware appends an [AuditEntry] for every request it serves, recording the
+//! route and path, the client address (see [client_info](super::forwarded::client_info)) and
+//! certificate (see [peer_identity](super::peer::peer_identity)), a commitment to the request
+//! parameters (query string and body), the response status, a commitment to the response body,
+//! and the time. Each entry includes the commitment of the entry before it, so the log forms a hash
+//! chain: changing, removing or reordering any entry breaks the chain from that point on, which
+//! [verify] detects.
+//!
+//! Bodies are hashed as they are read, rather than buffered, so streamed responses stay streamed.
+//! A response is logged once its body has been sent; the commitment covers the bytes which were
+//! sent, and the request commitment covers the bytes of the request body which the handler read.
+//!
+//! Only the most recent entries are kept in memory (see [AuditLog::capacity]). They can be fetched
+//! from the `/audit` endpoint added by [AuditLog::serve], and every entry can be copied to durable
+//! storage as it is appended with [AuditLog::sink].
+
+use super::forwarded::client_info;
+use super::peer::{peer_identity, PeerIdentity};
+use super::response;
+use super::route::RouteTable;
+use crate::redact::SafeToServe;
+use crate::wire::body::observe_body;
+use commit::{Commitment, Committable, RawCommitmentBuilder};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::Snafu;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tide::{Middleware, Next, Request};
+
+/// Raw bytes from a request or response, committed to in an [AuditEntry].
+///
+/// The commitment is to the SHA-256 hash of the bytes, so that it can be computed as a body is
+/// streamed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Payload(pub Vec<u8>);
+
+impl Payload {
+    fn commit_hash(hash: &[u8]) -> Commitment<Self> {
+        RawCommitmentBuilder::new("Payload")
+            .var_size_bytes(hash)
+            .finalize()
+    }
+}
+
+impl Committable for Payload {
+    fn commit(&self) -> Commitment<Self> {
+        Self::commit_hash(&Sha256::digest(&self.0))
+    }
+}
+
+/// The record of a single served request.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// The position of this entry in the log.
+    pub index: u64,
+    /// Seconds since the Unix epoch when the response was sent.
+    pub timestamp: u64,
+    pub method: String,
+    /// The route template the request matched (see [AuditLog::route]), or its path if it matched
+    /// none.
+    pub route: String,
+    /// A commitment to the query string and body of the request.
+    pub params: Commitment<Payload>,
+    pub status: u16,
+    /// A commitment to the body of the response.
+    pub response: Commitment<Payload>,
+    /// The commitment of the previous entry, if this is not the first.
+    pub prev: Option<Commitment<AuditEntry>>,
+    /// The address of the client, if it is known.
+    #[serde(default)]
+    pub client: Option<IpAddr>,
+    /// The certificate the client authenticated with, if any.
+    #[serde(default)]
+    pub peer: Option<PeerIdentity>,
+    /// The path of the request.
+    #[serde(default)]
+    pub path: Option<String>,
+}
+
+impl SafeToServe for AuditEntry {}
+
+impl Committable for AuditEntry {
+    fn commit(&self) -> Commitment<Self> {
+        let builder = RawCommitmentBuilder::new("AuditEntry")
+            .u64_field("index", self.index)
+            .u64_field("timestamp", self.timestamp)
+            .var_size_bytes(self.method.as_bytes())
+            .var_size_bytes(self.route.as_bytes())
+            .field("params", self.params)
+            .u64_field("status", self.status as u64)
+            .field("response", self.response);
+        let builder = match self.prev {
+            Some(prev) => builder.u64_field("has_prev", 1).field("prev", prev),
+            None => builder.u64_field("has_prev", 0),
+        };
+        // Entries recorded before client addresses were logged have no client field, and must keep
+        // the same commitment, so the field is only committed to when it is present.
+        let builder = match self.client {
+            Some(client) => builder
+                .u64_field("has_client", 1)
+                .var_size_bytes(client.to_string().as_bytes()),
+            None => builder,
+        };
+        // Likewise for the client certificate and the path.
+        let builder = match &self.peer {
+            Some(peer) => builder
+                .u64_field("has_peer", 1)
+                .var_size_bytes(peer.subject.as_bytes())
+                .var_size_bytes(peer.san.join("\n").as_bytes())
+                .var_size_bytes(&peer.fingerprint),
+            None => builder,
+        };
+        match &self.path {
+            Some(path) => builder
+                .u64_field("has_path", 1)
+                .var_size_bytes(path.as_bytes()),
+            None => builder,
+        }
+        .finalize()
+    }
+}
+
+/// Reasons an audit log fails to verify.
+#[derive(Clone, Debug, Snafu, PartialEq, Eq)]
+pub enum AuditError {
+    #[snafu(display("expected entry {}, found entry {}", expected, actual))]
+    Gap { expected: u64, actual: u64 },
+    #[snafu(display("entry {} does not follow the previous entry", index))]
+    BrokenChain { index: u64 },
+}
+
+/// Check that `entries` form an unbroken chain.
+///
+/// `prev` is the commitment of the entry before the first entry in `entries`, or [None] if
+/// `entries` starts at the beginning of the log. This allows the log to be fetched and verified a
+/// page at a time. On success, returns the commitment of the last entry, which is the `prev` for
+/// the next page.
+pub fn verify(
+    prev: Option<Commitment<AuditEntry>>,
+    entries: &[AuditEntry],
+) -> Result<Option<Commitment<AuditEntry>>, AuditError> {
+    let mut prev = prev;
+    for (i, entry) in entries.iter().enumerate() {
+        if i > 0 && entry.index != entries[i - 1].index + 1 {
+            return Err(AuditError::Gap {
+                expected: entries[i - 1].index + 1,
+                actual: entry.index,
+            });
+        }
+        if entry.prev != prev || (prev.is_none() && entry.index != 0) {
+            return Err(AuditError::BrokenChain { index: entry.index });
+        }
+        prev = Some(entry.commit());
+    }
+    Ok(prev)
+}
+
+type Sink = Arc<dyn Fn(&AuditEntry) + Send + Sync>;
+
+/// The number of entries an [AuditLog] keeps in memory by default.
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+// The most recent entries of the log, and the position and commitment of the last one.
+#[derive(Default)]
+struct Entries {
+    recent: VecDeque<AuditEntry>,
+    len: u64,
+    head: Option<Commitment<AuditEntry>>,
+}
+
+/// Server middleware which records an [AuditEntry] for every request.
+#[derive(Clone)]
+pub struct AuditLog {
+    entries: Arc<Mutex<Entries>>,
+    capacity: usize,
+    routes: RouteTable<()>,
+    sink: Option<Sink>,
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self {
+            entries: Default::default(),
+            capacity: DEFAULT_CAPACITY,
+            routes: RouteTable::new(),
+            sink: None,
+        }
+    }
+}
+
+impl AuditLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep at most `capacity` of the most recent entries in memory.
+    ///
+    /// Older entries are dropped from memory, so a service which must keep the whole log should
+    /// persist it with [sink](Self::sink).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "audit log capacity must be positive");
+        self.capacity = capacity;
+        self
+    }
+
+    /// Register a route template, which is recorded as the route of the requests it matches.
+    pub fn route(mut self, template: impl Into<String>) -> Self {
+        self.routes.insert(template, ());
+        self
+    }
+
+    /// Call `sink` with each entry as it is appended.
+    ///
+    /// Entries are passed to `sink` in order, while the log is locked, so `sink` should be quick.
+    pub fn sink(mut self, sink: impl Fn(&AuditEntry) + Send + Sync + 'static) -> Self {
+        self.sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// At most `limit` entries, starting from index `from`.
+    ///
+    /// Entries which are no longer kept in memory are skipped, so the result starts at the oldest
+    /// entry kept if `from` is older than that.
+    pub fn entries(&self, from: u64, limit: usize) -> Vec<AuditEntry> {
+        let entries = self.entries.lock().unwrap();
+        let first = entries.len - entries.recent.len() as u64;
+        entries
+            .recent
+            .iter()
+            .skip(from.saturating_sub(first) as usize)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// The commitment of the last entry, if there is one.
+    pub fn head(&self) -> Option<Commitment<AuditEntry>> {
+        self.entries.lock().unwrap().head
+    }
+
+    /// The number of entries appended to the log, including those no longer kept in memory.
+    pub fn len(&self) -> u64 {
+        self.entries.lock().unwrap().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Append `entry`, filling in its position in the log, the time and the previous commitment.
+    fn append(&self, mut entry: AuditEntry) {
+        entry.timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut entries = self.entries.lock().unwrap();
+        entry.index = entries.len;
+        entry.prev = entries.head;
+        if let Some(sink) = &self.sink {
+            sink(&entry);
+        }
+        entries.len += 1;
+        entries.head = Some(entry.commit());
+        if entries.recent.len() == self.capacity {
+            entries.recent.pop_front();
+        }
+        entries.recent.push_back(entry);
+    }
+
+    /// Add an `/audit` endpoint to `app`.
+    ///
+    /// The endpoint responds with a list of [AuditEntry], starting from the index in the `from`
+    /// query parameter (default 0), and containing at most `limit` entries (default and maximum
+    /// 1000).
+    pub fn serve<State: Clone + Send + Sync + 'static>(&self, app: &mut tide::Server<State>) {
+        #[derive(Deserialize)]
+        struct Query {
+            from: Option<u64>,
+            limit: Option<usize>,
+        }
+
+        let log = self.clone();
+        app.at("/audit").get(move |req: tide::Request<State>| {
+            let log = log.clone();
+            async move {
+                let query: Query = req.query()?;
+                let limit = query.limit.unwrap_or(1000).min(1000);
+                response(&req, log.entries(query.from.unwrap_or(0), limit))
+            }
+        });
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AuditLog {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let client = client_info(&req).addr;
+        let peer = peer_identity(&req);
+        let method = req.method().to_string();
+        let path = req.url().path().to_string();
+        let route = self
+            .routes
+            .lookup(&path)
+            .map(|(template, _)| template.as_str().to_string())
+            .unwrap_or_else(|| path.clone());
+
+        // Hash the request body as the handler reads it. The hasher is shared, since the handler
+        // may still hold the body when the response is logged.
+        let mut params = Sha256::new();
+        params.update(req.url().query().unwrap_or("").as_bytes());
+        params.update(b"\n");
+        let params = Arc::new(Mutex::new(params));
+        let body = req.take_body();
+        req.set_body(observe_body(
+            body,
+            params.clone(),
+            |params, chunk| params.lock().unwrap().update(chunk),
+            |_| {},
+        ));
+
+        let mut res = next.run(req).await;
+
+        // Log the response once its body has been sent.
+        let log = self.clone();
+        let status = res.status() as u16;
+        let body = res.take_body();
+        res.set_body(observe_body(
+            body,
+            Sha256::new(),
+            |hash, chunk| hash.update(chunk),
+            move |hash| {
+                let params = params.lock().unwrap().clone().finalize();
+                log.append(AuditEntry {
+                    index: 0,
+                    timestamp: 0,
+                    method,
+                    route,
+                    params: Payload::commit_hash(&params),
+                    status,
+                    response: Payload::commit_hash(&hash.finalize()),
+                    prev: None,
+                    client,
+                    peer,
+                    path: Some(path),
+                });
+            },
+        ));
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::forwarded::{ForwardingHeader, TrustedProxies};
+    use crate::server::peer::{ClientCerts, CLIENT_CERT};
+    use tide::http::{Body, Method, Url};
+
+    async fn send(app: &tide::Server<()>, method: Method, url: &str) -> tide::http::Response {
+        let mut req = tide::http::Request::new(method, Url::parse(url).unwrap());
+        req.insert_header("Accept", "application/json");
+        req.set_peer_addr(Some("192.0.2.1:4000"));
+        req.insert_header(
+            CLIENT_CERT,
+            format!("Hash={};Subject=CN=relayer", "ab".repeat(32)),
+        );
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        // Entries are appended once the response body has been sent.
+        let body = res.body_bytes().await.unwrap();
+        res.set_body(body);
+        res
+    }
+
+    #[async_std::test]
+    async fn test_audit_log() {
+        let log = AuditLog::new();
+        let mut app = tide::new();
+        app.with(ClientCerts::new(
+            TrustedProxies::new(ForwardingHeader::XForwardedFor)
+                .trust("192.0.2.0/24".parse().unwrap()),
+        ));
+        app.with(log.clone());
+        app.at("/memo")
+            .post(|mut req: tide::Request<()>| async move {
+                Ok(format!("posted {}", req.body_string().await?))
+            })
+            .get(|_| async { Ok("memo") });
+        log.serve(&mut app);
+
+        send(&app, Method::Get, "http://localhost/memo").await;
+        send(&app, Method::Post, "http://localhost/memo?x=1").await;
+        send(&app, Method::Get, "http://localhost/missing").await;
+        assert_eq!(log.len(), 3);
+
+        // The log can be fetched and verified a page at a time.
+        let mut res = send(&app, Method::Get, "http://localhost/audit?limit=2").await;
+        let page: Vec<AuditEntry> =
+            serde_json::from_str(&res.body_string().await.unwrap()).unwrap();
+        assert_eq!(page.len(), 2);
+        assert_eq!(page[1].method, "POST");
+        assert_eq!(page[1].route, "/memo");
+        assert_eq!(page[1].client, Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(page[1].peer.as_ref().unwrap().subject, "CN=relayer");
+        let prev = verify(None, &page).unwrap();
+        let mut res = send(&app, Method::Get, "http://localhost/audit?from=2").await;
+        let page: Vec<AuditEntry> =
+            serde_json::from_str(&res.body_string().await.unwrap()).unwrap();
+        assert_eq!(page[0].status, 404);
+        verify(prev, &page).unwrap();
+
+        // The request body and the path are committed to.
+        let entries = log.entries(1, 1);
+        assert_eq!(entries[0].params, Payload(b"x=1\n".to_vec()).commit());
+        assert_eq!(entries[0].response, Payload(b"posted ".to_vec()).commit());
+        assert_eq!(entries[0].path.as_deref(), Some("/memo"));
+
+        // Tampering is detected.
+        let mut entries = log.entries(0, 3);
+        entries[1].status = 500;
+        assert_eq!(
+            verify(None, &entries),
+            Err(AuditError::BrokenChain { index: 2 })
+        );
+        let mut entries = log.entries(0, 3);
+        entries.remove(1);
+        assert_eq!(
+            verify(None, &entries),
+            Err(AuditError::Gap {
+                expected: 1,
+                actual: 2
+            })
+        );
+        assert_eq!(
+            verify(None, &log.entries(1, 2)),
+            Err(AuditError::BrokenChain { index: 1 })
+        );
+    }
+
+    #[async_std::test]
+    async fn test_streamed_responses() {
+        let log = AuditLog::new().capacity(2).route("/block/:id");
+        let mut app = tide::new();
+        app.with(log.clone());
+        app.at("/block/:id").get(|_| async {
+            let mut res = tide::Response::new(tide::StatusCode::Ok);
+            res.set_body(Body::from_reader(
+                futures::io::Cursor::new(b"block".to_vec()),
+                None,
+            ));
+            Ok(res)
+        });
+
+        // A streamed response is logged once it has been sent.
+        let req =
+            tide::http::Request::new(Method::Get, Url::parse("http://localhost/block/1").unwrap());
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(log.len(), 0);
+        assert_eq!(res.body_bytes().await.unwrap(), b"block");
+        assert_eq!(log.len(), 1);
+
+        send(&app, Method::Get, "http://localhost/block/2").await;
+        send(&app, Method::Get, "http://localhost/block/3").await;
+
+        // Only the most recent entries are kept, but they still form a chain.
+        assert_eq!(log.len(), 3);
+        let entries = log.entries(0, 10);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].index, 1);
+        assert_eq!(verify(entries[0].prev, &entries).unwrap(), log.head());
+        assert_eq!(entries[1].route, "/block/:id");
+        assert_eq!(entries[1].path.as_deref(), Some("/block/3"));
+        assert_eq!(entries[1].response, Payload(b"block".to_vec()).commit());
+    }
+}
@@ -0,0 +1,276 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Fault injection, for testing how clients cope with an unreliable server.
+//!
+//! Retry logic and circuit breakers are hard to test against a server which always works. The
+//! [Chaos] middleware makes a server misbehave on purpose, with configurable probabilities for each
+//! kind of [Faults]:
+//!  * added latency before the request is handled
+//!  * error responses with a given status, instead of running the handler
+//!  * dropped connections, where the response body fails as soon as it is read
+//!  * truncated bodies, where the response body ends halfway through, despite its declared length
+//!
+//! Faults can be configured separately for each route. Faults are drawn from a seeded random number
+//! generator, so a failing test can be reproduced with the same seed, and the middleware can be
+//! switched off at runtime with [Chaos::set_enabled].
+//!
+//! This module is only available with the `chaos` feature.
+
+use super::route::RouteTable;
+use futures::io::{AsyncBufRead, AsyncRead, Cursor};
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tide::http::Body;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+/// The faults to inject into requests, and their probabilities.
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    latency: Option<(f64, Duration)>,
+    error: Option<(f64, StatusCode)>,
+    drop_connection: f64,
+    truncate_body: f64,
+}
+
+impl Faults {
+    /// No faults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// With probability `p`, wait `delay` before handling the request.
+    pub fn latency(mut self, p: f64, delay: Duration) -> Self {
+        self.latency = Some((p, delay));
+        self
+    }
+
+    /// With probability `p`, respond with `status` instead of handling the request.
+    pub fn error(mut self, p: f64, status: StatusCode) -> Self {
+        self.error = Some((p, status));
+        self
+    }
+
+    /// With probability `p`, fail the response body as soon as it is read.
+    pub fn drop_connection(mut self, p: f64) -> Self {
+        self.drop_connection = p;
+        self
+    }
+
+    /// With probability `p`, end the response body halfway through.
+    pub fn truncate_body(mut self, p: f64) -> Self {
+        self.truncate_body = p;
+        self
+    }
+}
+
+/// Server middleware which injects faults.
+#[derive(Clone)]
+pub struct Chaos {
+    faults: Faults,
+    routes: RouteTable<Faults>,
+    rng: Arc<Mutex<ChaCha8Rng>>,
+    enabled: Arc<AtomicBool>,
+}
+
+impl Chaos {
+    /// Inject `faults` into requests to all routes.
+    ///
+    /// The random number generator is seeded from the clock; use [seed](Self::seed) for
+    /// reproducible faults.
+    pub fn new(faults: Faults) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Self {
+            faults,
+            routes: RouteTable::new(),
+            rng: Arc::new(Mutex::new(ChaCha8Rng::seed_from_u64(seed))),
+            enabled: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    /// Seed the random number generator.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = Arc::new(Mutex::new(ChaCha8Rng::seed_from_u64(seed)));
+        self
+    }
+
+    /// Inject `faults`, instead of the default faults, into requests to routes matching
+    /// `template`.
+    pub fn route(mut self, template: impl Into<String>, faults: Faults) -> Self {
+        self.routes.insert(template, faults);
+        self
+    }
+
+    /// Turn fault injection on or off, for this middleware and all of its clones.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    fn faults(&self, path: &str) -> &Faults {
+        self.routes
+            .lookup(path)
+            .map(|(_, faults)| faults)
+            .unwrap_or(&self.faults)
+    }
+
+    // Returns true with probability `p`.
+    fn roll(&self, p: f64) -> bool {
+        if p <= 0.0 {
+            return false;
+        }
+        // A uniform float in [0, 1) from the top 53 bits.
+        let x = (self.rng.lock().unwrap().next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        x < p
+    }
+}
+
+// A body whose connection has been reset.
+struct Reset;
+
+impl AsyncRead for Reset {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Err(reset()))
+    }
+}
+
+impl AsyncBufRead for Reset {
+    fn poll_fill_buf(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Poll::Ready(Err(reset()))
+    }
+
+    fn consume(self: Pin<&mut Self>, _amt: usize) {}
+}
+
+fn reset() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionReset,
+        "connection dropped by chaos",
+    )
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Chaos {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return Ok(next.run(req).await);
+        }
+        let faults = self.faults(req.url().path()).clone();
+
+        if let Some((p, delay)) = faults.latency {
+            if self.roll(p) {
+                async_std::task::sleep(delay).await;
+            }
+        }
+        if let Some((p, status)) = faults.error {
+            if self.roll(p) {
+                let mut res = Response::new(status);
+                res.set_error(tide::Error::from_str(status, "fault injected by chaos"));
+                return Ok(res);
+            }
+        }
+
+        let mut res = next.run(req).await;
+        if self.roll(faults.drop_connection) {
+            let len = res.len();
+            res.set_body(Body::from_reader(Reset, len));
+        } else if self.roll(faults.truncate_body) {
+            let body = res.take_body();
+            let mime = body.mime().clone();
+            let mut bytes = body.into_bytes().await?;
+            let len = bytes.len();
+            bytes.truncate(len / 2);
+            let mut body = Body::from_reader(Cursor::new(bytes), Some(len));
+            body.set_mime(mime);
+            res.set_body(body);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+    use tide::http::{Method, Url};
+
+    async fn get(app: &tide::Server<()>, path: &str) -> tide::http::Response {
+        let req = tide::http::Request::new(
+            Method::Get,
+            Url::parse("http://localhost").unwrap().join(path).unwrap(),
+        );
+        app.respond(req).await.unwrap()
+    }
+
+    fn server(chaos: Chaos) -> tide::Server<()> {
+        let mut app = tide::new();
+        app.with(chaos);
+        app.at("/*").get(|_| async { Ok("0123456789") });
+        app
+    }
+
+    #[async_std::test]
+    async fn test_faults() {
+        let chaos = Chaos::new(Faults::new())
+            .seed(0)
+            .route("/error", Faults::new().error(1.0, StatusCode::BadGateway))
+            .route(
+                "/slow",
+                Faults::new().latency(1.0, Duration::from_millis(20)),
+            )
+            .route("/drop", Faults::new().drop_connection(1.0))
+            .route("/truncate", Faults::new().truncate_body(1.0));
+        let app = server(chaos.clone());
+
+        let mut res = get(&app, "/ok").await;
+        assert_eq!(res.body_string().await.unwrap(), "0123456789");
+
+        let res = get(&app, "/error").await;
+        assert_eq!(res.status(), StatusCode::BadGateway);
+
+        let start = Instant::now();
+        get(&app, "/slow").await;
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        let mut res = get(&app, "/drop").await;
+        assert!(res.body_string().await.is_err());
+
+        let mut res = get(&app, "/truncate").await;
+        assert_eq!(res.len(), Some(10));
+        assert_eq!(res.take_body().into_bytes().await.unwrap(), b"01234");
+
+        // Faults can be turned off.
+        chaos.set_enabled(false);
+        let res = get(&app, "/error").await;
+        assert_eq!(res.status(), StatusCode::Ok);
+    }
+
+    #[async_std::test]
+    async fn test_probability() {
+        let app =
+            server(Chaos::new(Faults::new().error(0.25, StatusCode::ServiceUnavailable)).seed(42));
+        let mut failures = 0;
+        for _ in 0..1000 {
+            if get(&app, "/").await.status() == StatusCode::ServiceUnavailable {
+                failures += 1;
+            }
+        }
+        assert!((150..350).contains(&failures), "{} failures", failures);
+    }
+}
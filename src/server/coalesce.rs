@@ -0,0 +1,262 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Coalescing concurrent identical requests.
+//!
+//! Popular resources (like the latest block) are often requested by many clients at once, and each
+//! request does exactly the same work. The [Coalesce] middleware detects GET requests which are
+//! identical to a request already in flight (same path, query and Accept header) and, instead of
+//! running the handler again, waits for the first request to finish and responds with a copy of its
+//! response.
+//!
+//! If the first request is cancelled before it finishes, the requests waiting on it run the handler
+//! themselves.
+//!
+//! Since responses are shared between clients, this middleware should only be used for routes whose
+//! responses do not depend on anything but the URL, such as routes that do not check credentials.
+//! Add it before [add_error_body](super::add_error_body), so that error responses are shared with
+//! their bodies already serialized.
+
+use super::route::RouteTable;
+use crate::wire::body::read_body;
+use futures::channel::oneshot;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tide::http::headers::{HeaderName, HeaderValues};
+use tide::http::{Body, Method, Mime};
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+// A copy of a response which can be sent to many clients.
+pub(super) struct Snapshot {
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValues)>,
+    mime: Mime,
+    body: Vec<u8>,
+    error: Option<String>,
+}
+
+impl Snapshot {
+    pub(super) async fn take(res: &mut Response) -> tide::Result<Self> {
+        let body = res.take_body();
+        let mime = body.mime().clone();
+        Ok(Self {
+            status: res.status(),
+            headers: res
+                .iter()
+                .map(|(name, values)| (name.clone(), values.clone()))
+                .collect(),
+            mime,
+            body: read_body(body).await?,
+            error: res.error().map(|err| err.to_string()),
+        })
+    }
+
+    pub(super) fn to_response(&self) -> Response {
+        let mut res = Response::new(self.status);
+        for (name, values) in &self.headers {
+            res.insert_header(name, values);
+        }
+        let mut body = Body::from_bytes(self.body.clone());
+        body.set_mime(self.mime.clone());
+        res.set_body(body);
+        if let Some(error) = &self.error {
+            res.set_error(tide::Error::from_str(self.status, error.clone()));
+        }
+        res
+    }
+}
+
+type Waiters = Vec<oneshot::Sender<Arc<Snapshot>>>;
+
+/// Server middleware which coalesces concurrent identical GET requests.
+#[derive(Clone, Default)]
+pub struct Coalesce {
+    routes: RouteTable<()>,
+    in_flight: Arc<Mutex<HashMap<String, Waiters>>>,
+    coalesced: Arc<AtomicU64>,
+}
+
+impl Coalesce {
+    /// Coalesce requests to all routes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only coalesce requests to routes matching `template`.
+    ///
+    /// This can be called more than once. If it is never called, requests to all routes are
+    /// coalesced.
+    pub fn route(mut self, template: impl Into<String>) -> Self {
+        self.routes.insert(template, ());
+        self
+    }
+
+    /// The total number of requests which have been answered with a copy of another response.
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests currently waiting on an identical request.
+    pub fn waiting(&self) -> usize {
+        self.in_flight.lock().unwrap().values().map(Vec::len).sum()
+    }
+
+    fn key<State>(&self, req: &Request<State>) -> Option<String> {
+        if req.method() != Method::Get
+            || (!self.routes.is_empty() && self.routes.lookup(req.url().path()).is_none())
+        {
+            return None;
+        }
+        let url = req.url();
+        Some(format!(
+            "{}?{}\n{}",
+            url.path(),
+            url.query().unwrap_or(""),
+            req.header("Accept").map(|h| h.as_str()).unwrap_or("")
+        ))
+    }
+}
+
+// Removes the entry for an in-flight request when the request finishes or is cancelled. Dropping
+// the entry drops the senders for any waiting requests, which tells them to run the handler
+// themselves.
+struct Leader<'a> {
+    in_flight: &'a Mutex<HashMap<String, Waiters>>,
+    key: String,
+}
+
+impl<'a> Leader<'a> {
+    fn finish(&self) -> Waiters {
+        self.in_flight
+            .lock()
+            .unwrap()
+            .remove(&self.key)
+            .unwrap_or_default()
+    }
+}
+
+impl<'a> Drop for Leader<'a> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Coalesce {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let key = match self.key(&req) {
+            Some(key) => key,
+            None => return Ok(next.run(req).await),
+        };
+
+        let waiter = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get_mut(&key) {
+                Some(waiters) => {
+                    let (sender, receiver) = oneshot::channel();
+                    waiters.push(sender);
+                    Some(receiver)
+                }
+                None => {
+                    in_flight.insert(key.clone(), Vec::new());
+                    None
+                }
+            }
+        };
+        if let Some(receiver) = waiter {
+            return match receiver.await {
+                Ok(snapshot) => {
+                    self.coalesced.fetch_add(1, Ordering::Relaxed);
+                    Ok(snapshot.to_response())
+                }
+                // The leader was cancelled.
+                Err(_) => Ok(next.run(req).await),
+            };
+        }
+
+        let leader = Leader {
+            in_flight: &self.in_flight,
+            key,
+        };
+        let mut res = next.run(req).await;
+        let snapshot = Arc::new(Snapshot::take(&mut res).await?);
+        for waiter in leader.finish() {
+            waiter.send(snapshot.clone()).ok();
+        }
+        Ok(snapshot.to_response())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::lock::Mutex;
+    use std::sync::atomic::AtomicUsize;
+
+    #[async_std::test]
+    async fn test_coalesce() {
+        let (release, wait) = oneshot::channel::<()>();
+        let wait = Arc::new(Mutex::new(Some(wait)));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let coalesce = Coalesce::new().route("/block/:height");
+        let mut app = tide::new();
+        app.with(coalesce.clone());
+        app.at("/block/:height").get({
+            let calls = calls.clone();
+            move |_| {
+                let wait = wait.clone();
+                let calls = calls.clone();
+                async move {
+                    let n = calls.fetch_add(1, Ordering::SeqCst);
+                    let wait = wait.lock().await.take();
+                    if let Some(wait) = wait {
+                        wait.await.ok();
+                    }
+                    Ok(format!("block {}", n))
+                }
+            }
+        });
+        let app = Arc::new(app);
+
+        let get = |app: Arc<tide::Server<()>>, path: &'static str| async move {
+            let req = tide::http::Request::new(
+                Method::Get,
+                tide::http::Url::parse("http://localhost")
+                    .unwrap()
+                    .join(path)
+                    .unwrap(),
+            );
+            let mut res: tide::http::Response = app.respond(req).await.unwrap();
+            (res.status(), res.body_string().await.unwrap())
+        };
+
+        // Start one request, and wait for two identical requests to queue up behind it.
+        let first = async_std::task::spawn(get(app.clone(), "/block/1"));
+        while calls.load(Ordering::SeqCst) == 0 {
+            async_std::task::yield_now().await;
+        }
+        let second = async_std::task::spawn(get(app.clone(), "/block/1"));
+        let third = async_std::task::spawn(get(app.clone(), "/block/1"));
+        while coalesce.waiting() < 2 {
+            async_std::task::yield_now().await;
+        }
+
+        // A different request is not coalesced.
+        assert_eq!(
+            get(app.clone(), "/block/2").await,
+            (StatusCode::Ok, "block 1".to_string())
+        );
+
+        release.send(()).unwrap();
+        for res in [first.await, second.await, third.await] {
+            assert_eq!(res, (StatusCode::Ok, "block 0".to_string()));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(coalesce.coalesced(), 2);
+    }
+}
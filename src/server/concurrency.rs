@@ -0,0 +1,356 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Limiting the number of requests handled concurrently.
+//!
+//! Without a limit, a burst of expensive requests can exhaust server memory, since every request is
+//! handled (and its response serialized) at once. The [ConcurrencyLimit] middleware bounds the
+//! number of requests in flight. Requests beyond the limit wait in a queue of bounded depth, and
+//! requests beyond that are shed immediately with a 503 response and a Retry-After header.
+//!
+//! The 503 is reported as a [tide::Error], so if this middleware is added after
+//! [add_error_body](super::add_error_body), the response will have a standard error body.
+//!
+//! # Priorities
+//!
+//! Routes can be tagged with a [Priority], so that cheap, critical requests (like nullifier checks
+//! needed by consensus) are not stuck behind a storm of best-effort queries. Each priority class can
+//! be given its own partition, with its own in-flight limit and queue, using
+//! [ConcurrencyLimit::partition]. A class without its own partition is handled as follows:
+//!  * [Priority::Critical] requests bypass the limiter entirely
+//!  * [Priority::BestEffort] requests share the [Priority::Normal] partition
+//!
+//! Requests to routes which are not tagged have [Priority::Normal].
+
+use super::route::RouteTable;
+use async_lock::Semaphore;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+use tracing::{event, Level};
+
+/// The priority class of a route.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Priority {
+    BestEffort,
+    Normal,
+    Critical,
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [Priority::BestEffort, Priority::Normal, Priority::Critical];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Limit the number of requests handled concurrently to `max_in_flight`.
+///
+/// This configures the partition for [Priority::Normal]. By default, up to `max_in_flight`
+/// additional requests can be queued, and shed requests are told to retry after 1 second.
+///
+/// # Panics
+///
+/// Panics if `max_in_flight` is 0, since no request could ever be handled.
+pub fn concurrency_limit(max_in_flight: usize) -> ConcurrencyLimit {
+    let mut partitions = HashMap::new();
+    partitions.insert(
+        Priority::Normal,
+        Partition::new(max_in_flight, max_in_flight),
+    );
+    ConcurrencyLimit {
+        partitions,
+        routes: RouteTable::new(),
+        retry_after: Duration::from_secs(1),
+        stats: Default::default(),
+    }
+}
+
+#[derive(Clone)]
+struct Partition {
+    permits: Arc<Semaphore>,
+    queued: Arc<AtomicUsize>,
+    max_queued: usize,
+}
+
+impl Partition {
+    fn new(max_in_flight: usize, max_queued: usize) -> Self {
+        assert!(max_in_flight > 0, "concurrency limit must be positive");
+        Self {
+            permits: Arc::new(Semaphore::new(max_in_flight)),
+            queued: Arc::new(AtomicUsize::new(0)),
+            max_queued,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    admitted: AtomicU64,
+    bypassed: AtomicU64,
+    shed: AtomicU64,
+}
+
+/// Statistics about requests in one priority class.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClassStats {
+    /// Requests which were admitted by the limiter, possibly after waiting in the queue.
+    pub admitted: u64,
+    /// Requests which bypassed the limiter, because their class has no partition.
+    pub bypassed: u64,
+    /// Requests which were shed because the queue was full.
+    pub shed: u64,
+}
+
+/// Server middleware which limits the number of requests handled concurrently.
+///
+/// Create one with [concurrency_limit].
+#[derive(Clone)]
+pub struct ConcurrencyLimit {
+    partitions: HashMap<Priority, Partition>,
+    routes: RouteTable<Priority>,
+    retry_after: Duration,
+    stats: Arc<[Counters; 3]>,
+}
+
+impl ConcurrencyLimit {
+    /// The maximum number of [Priority::Normal] requests which can wait for a slot before requests
+    /// are shed.
+    pub fn queue_depth(mut self, max_queued: usize) -> Self {
+        if let Some(partition) = self.partitions.get_mut(&Priority::Normal) {
+            partition.max_queued = max_queued;
+        }
+        self
+    }
+
+    /// Give requests with `priority` their own partition, with its own limit and queue.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_in_flight` is 0.
+    pub fn partition(
+        mut self,
+        priority: Priority,
+        max_in_flight: usize,
+        max_queued: usize,
+    ) -> Self {
+        self.partitions
+            .insert(priority, Partition::new(max_in_flight, max_queued));
+        self
+    }
+
+    /// Tag requests matching the route template `template` with `priority`.
+    pub fn route(mut self, template: impl Into<String>, priority: Priority) -> Self {
+        self.routes.insert(template, priority);
+        self
+    }
+
+    /// The delay to suggest in the Retry-After header of shed requests.
+    ///
+    /// The header has a resolution of 1 second; `retry_after` is rounded up.
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+
+    /// The number of requests currently waiting for a slot, in all partitions.
+    pub fn queued(&self) -> usize {
+        self.partitions
+            .values()
+            .map(|partition| partition.queued.load(Ordering::SeqCst))
+            .sum()
+    }
+
+    /// Statistics about requests with `priority`.
+    pub fn stats(&self, priority: Priority) -> ClassStats {
+        let counters = &self.stats[priority.index()];
+        ClassStats {
+            admitted: counters.admitted.load(Ordering::Relaxed),
+            bypassed: counters.bypassed.load(Ordering::Relaxed),
+            shed: counters.shed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Statistics about all priority classes.
+    pub fn all_stats(&self) -> Vec<(Priority, ClassStats)> {
+        Priority::ALL
+            .iter()
+            .map(|priority| (*priority, self.stats(*priority)))
+            .collect()
+    }
+
+    fn classify(&self, path: &str) -> Priority {
+        self.routes
+            .lookup(path)
+            .map(|(_, priority)| *priority)
+            .unwrap_or(Priority::Normal)
+    }
+
+    fn partition_for(&self, priority: Priority) -> Option<&Partition> {
+        match self.partitions.get(&priority) {
+            Some(partition) => Some(partition),
+            None if priority == Priority::Critical => None,
+            None => self.partitions.get(&Priority::Normal),
+        }
+    }
+
+    fn shed(&self, priority: Priority) -> Response {
+        event!(
+            Level::WARN,
+            "server overloaded, shedding {:?} request",
+            priority
+        );
+        let secs = self.retry_after.as_secs() + (self.retry_after.subsec_nanos() > 0) as u64;
+        let mut res = Response::new(StatusCode::ServiceUnavailable);
+        res.insert_header("Retry-After", secs.to_string());
+        res.set_error(tide::Error::from_str(
+            StatusCode::ServiceUnavailable,
+            format!("server overloaded, retry after {} seconds", secs),
+        ));
+        res
+    }
+}
+
+// Decrements the queue length when a queued request either gets a slot or is cancelled.
+struct QueueGuard<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for QueueGuard<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ConcurrencyLimit {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let priority = self.classify(req.url().path());
+        let counters = &self.stats[priority.index()];
+        let partition = match self.partition_for(priority) {
+            Some(partition) => partition,
+            None => {
+                counters.bypassed.fetch_add(1, Ordering::Relaxed);
+                return Ok(next.run(req).await);
+            }
+        };
+
+        let _permit = match partition.permits.try_acquire() {
+            Some(permit) => permit,
+            None => {
+                if partition.queued.fetch_add(1, Ordering::SeqCst) >= partition.max_queued {
+                    partition.queued.fetch_sub(1, Ordering::SeqCst);
+                    counters.shed.fetch_add(1, Ordering::Relaxed);
+                    return Ok(self.shed(priority));
+                }
+                let _queued = QueueGuard(&partition.queued);
+                partition.permits.acquire().await
+            }
+        };
+        counters.admitted.fetch_add(1, Ordering::Relaxed);
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::channel::oneshot;
+    use futures::lock::Mutex;
+
+    #[async_std::test]
+    async fn test_concurrency_limit() {
+        // The first request waits for a signal before completing, so we can control how many are in
+        // flight.
+        let (release, wait) = oneshot::channel::<()>();
+        let wait = Arc::new(Mutex::new(Some(wait)));
+        let started = Arc::new(AtomicUsize::new(0));
+        let limit = concurrency_limit(1).queue_depth(1);
+        let mut app = tide::new();
+        app.with(limit.clone());
+        app.at("/").get({
+            let started = started.clone();
+            move |_| {
+                let wait = wait.clone();
+                let started = started.clone();
+                async move {
+                    started.fetch_add(1, Ordering::SeqCst);
+                    if let Some(wait) = wait.lock().await.take() {
+                        wait.await.ok();
+                    }
+                    Ok("done")
+                }
+            }
+        });
+        let app = Arc::new(app);
+
+        let get = |app: Arc<tide::Server<()>>| async move {
+            let req = tide::http::Request::new(
+                tide::http::Method::Get,
+                tide::http::Url::parse("http://localhost/").unwrap(),
+            );
+            let res: tide::http::Response = app.respond(req).await.unwrap();
+            res
+        };
+
+        // The first request takes the only slot, the second is queued, and the third is shed.
+        let first = async_std::task::spawn(get(app.clone()));
+        while started.load(Ordering::SeqCst) == 0 {
+            async_std::task::yield_now().await;
+        }
+        let second = async_std::task::spawn(get(app.clone()));
+        while limit.queued() == 0 {
+            async_std::task::yield_now().await;
+        }
+        let third = get(app.clone()).await;
+        assert_eq!(third.status(), StatusCode::ServiceUnavailable);
+        assert_eq!(third["Retry-After"], "1");
+
+        release.send(()).unwrap();
+        assert_eq!(first.await.status(), StatusCode::Ok);
+        assert_eq!(second.await.status(), StatusCode::Ok);
+        assert_eq!(started.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            limit.stats(Priority::Normal),
+            ClassStats {
+                admitted: 2,
+                bypassed: 0,
+                shed: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_classify() {
+        let limit = concurrency_limit(1)
+            .route("/nullifier/:n", Priority::Critical)
+            .route("/blocks/*", Priority::BestEffort)
+            .partition(Priority::BestEffort, 1, 0);
+        assert_eq!(limit.classify("/nullifier/0"), Priority::Critical);
+        assert_eq!(limit.classify("/blocks/0/10"), Priority::BestEffort);
+        assert_eq!(limit.classify("/status"), Priority::Normal);
+
+        // Critical requests have no partition, so they bypass the limiter.
+        assert!(limit.partition_for(Priority::Critical).is_none());
+        // Best-effort requests have their own partition.
+        assert_eq!(
+            limit
+                .partition_for(Priority::BestEffort)
+                .unwrap()
+                .max_queued,
+            0
+        );
+        assert_eq!(limit.partition_for(Priority::Normal).unwrap().max_queued, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "concurrency limit must be positive")]
+    fn test_zero_limit() {
+        concurrency_limit(0);
+    }
+}
@@ -0,0 +1,429 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Permissive defaults for running a service on a developer's machine.
+//!
+//! [dev_mode] installs everything which makes a service easy to poke at locally, and which no
+//! deployed service should have:
+//!  * CORS headers allowing any origin to use any method, so a front end served from another port
+//!    can call the service
+//!  * a [Logger] which logs headers, and bodies up to 64 KiB
+//!  * pretty-printed JSON error bodies; errors which no [ErrorBody](super::ErrorBody) encoded get a
+//!    body with their message and their debug representation (which includes a backtrace if
+//!    `RUST_BACKTRACE` is set), and panics get a 500 with the panic message and backtrace
+//!  * a request inspector at [INSPECTOR_PATH], listing the most recent requests and responses
+//!  * optionally, reloading the [ServiceConfig] whenever its file changes
+//!
+//! This module only exists with the `dev` feature in builds with debug assertions, so a release
+//! build cannot enable it by accident: code which uses it does not compile.
+//!
+//! ```
+//! let mut app = tide::new();
+//! net::server::dev_mode().install(&mut app);
+//! app.at("/block/:id").get(|_| async { Ok("block") });
+//! ```
+//!
+//! Dev mode should be installed before any other middleware, so that it sees panics and errors from
+//! all of it. It replaces [catch_panics](super::catch_panics).
+
+use super::methods::AutoMethods;
+use super::panics::{install_hook, panic_message, take_backtrace};
+use super::Logger;
+use crate::config::{Loader, ServiceConfig};
+use crate::redact::SafeToServe;
+use futures::future::FutureExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tide::http::{headers::Headers, mime, Method};
+use tide::{Body, Middleware, Next, Request, Response, StatusCode};
+use tracing::{event, Level};
+
+/// The path of the request inspector.
+pub const INSPECTOR_PATH: &str = "/_dev/requests";
+
+// Headers which are never shown by the inspector, even in dev mode.
+const REDACTED_HEADERS: [&str; 3] = ["authorization", "cookie", "set-cookie"];
+
+/// A request seen by the inspector, and the response to it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Inspected {
+    /// Seconds since the Unix epoch when the request was received.
+    pub timestamp: u64,
+    pub method: String,
+    /// The path and query string of the request.
+    pub url: String,
+    pub request_headers: Vec<(String, String)>,
+    /// The request body, if it was text of at most the capture size.
+    pub request_body: Option<String>,
+    pub status: u16,
+    pub response_headers: Vec<(String, String)>,
+    /// The response body, if it was text of at most the capture size.
+    pub response_body: Option<String>,
+    pub duration_ms: u64,
+}
+
+impl SafeToServe for Inspected {}
+
+/// Local development settings for a service.
+///
+/// See the [module documentation](self).
+#[must_use = "dev mode does nothing until it is installed"]
+pub struct DevMode {
+    max_body_size: usize,
+    keep: usize,
+    config: Option<(Loader, ConfigCallback)>,
+}
+
+type ConfigCallback = Arc<dyn Fn(ServiceConfig) + Send + Sync>;
+
+/// Dev mode with the default settings.
+pub fn dev_mode() -> DevMode {
+    DevMode {
+        max_body_size: 64 * 1024,
+        keep: 100,
+        config: None,
+    }
+}
+
+impl DevMode {
+    /// Log and inspect bodies of at most `max_body_size` bytes.
+    pub fn capture_bodies(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Keep the last `keep` requests in the inspector.
+    pub fn keep_requests(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// Reload the config file read by `loader` whenever it changes, and pass the new settings to
+    /// `on_change`.
+    ///
+    /// The file is checked once a second. If it cannot be loaded, the error is logged and the old
+    /// settings stay in effect.
+    pub fn reload_config(
+        mut self,
+        loader: Loader,
+        on_change: impl Fn(ServiceConfig) + Send + Sync + 'static,
+    ) -> Self {
+        self.config = Some((loader, Arc::new(on_change)));
+        self
+    }
+
+    /// Add the dev mode middleware and endpoints to `app`.
+    pub fn install<State: Clone + Send + Sync + 'static>(self, app: &mut tide::Server<State>) {
+        event!(
+            Level::WARN,
+            "dev mode enabled: CORS is open, bodies are logged and {} is served",
+            INSPECTOR_PATH
+        );
+        let inspector = Inspector {
+            max_body_size: self.max_body_size,
+            keep: self.keep,
+            requests: Default::default(),
+        };
+        app.with(PrettyErrors);
+        app.with(
+            AutoMethods::new()
+                .route(
+                    "/*",
+                    [
+                        Method::Get,
+                        Method::Post,
+                        Method::Put,
+                        Method::Patch,
+                        Method::Delete,
+                    ],
+                )
+                .allow_origin("*"),
+        );
+        app.with(
+            Logger::new()
+                .log_headers(true)
+                .capture_bodies(self.max_body_size),
+        );
+        app.with(inspector.clone());
+        let requests = inspector.requests.clone();
+        app.at(INSPECTOR_PATH)
+            .get(move |_: Request<State>| {
+                let requests = requests.lock().unwrap().clone();
+                async move { pretty(&requests) }
+            })
+            .delete(move |_: Request<State>| {
+                inspector.requests.lock().unwrap().clear();
+                async { Ok(Response::new(StatusCode::NoContent)) }
+            });
+
+        if let Some((loader, on_change)) = self.config {
+            watch_config(loader, on_change);
+        }
+    }
+}
+
+// A 200 response with `body` as pretty-printed JSON.
+fn pretty<T: Serialize>(body: &T) -> tide::Result {
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(serde_json::to_vec_pretty(body)?);
+    res.set_content_type(mime::JSON);
+    Ok(res)
+}
+
+fn watch_config(loader: Loader, on_change: ConfigCallback) {
+    let path = match loader.path() {
+        Some(path) => path,
+        None => {
+            event!(Level::WARN, "dev mode: no config file to reload");
+            return;
+        }
+    };
+    async_std::task::spawn(async move {
+        let modified = || path.metadata().and_then(|meta| meta.modified()).ok();
+        let mut last = modified();
+        loop {
+            async_std::task::sleep(Duration::from_secs(1)).await;
+            let current = modified();
+            if current == last {
+                continue;
+            }
+            last = current;
+            match loader.clone().load() {
+                Ok(config) => {
+                    event!(Level::WARN, "reloaded config from {}", path.display());
+                    on_change(config);
+                }
+                Err(err) => event!(
+                    Level::ERROR,
+                    "failed to reload config from {}: {}",
+                    path.display(),
+                    err
+                ),
+            }
+        }
+    });
+}
+
+// Answers panics with their message and backtrace, and pretty-prints JSON error bodies.
+struct PrettyErrors;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for PrettyErrors {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        install_hook();
+        let mut res = match AssertUnwindSafe(next.run(req)).catch_unwind().await {
+            Ok(res) => res,
+            Err(payload) => {
+                let backtrace = take_backtrace()
+                    .map(|bt| bt.to_string())
+                    .unwrap_or_default();
+                let mut res = Response::new(StatusCode::InternalServerError);
+                res.set_body(serde_json::to_vec_pretty(&json!({
+                    "panic": panic_message(&*payload),
+                    "backtrace": backtrace.lines().collect::<Vec<_>>(),
+                }))?);
+                res.set_content_type(mime::JSON);
+                return Ok(res);
+            }
+        };
+        if !res.status().is_client_error() && !res.status().is_server_error() {
+            return Ok(res);
+        }
+        if let Some(err) = res.error() {
+            if res.is_empty().unwrap_or(true) {
+                let body = json!({
+                    "error": err.to_string(),
+                    "status": res.status() as u16,
+                    "debug": format!("{:?}", err).lines().collect::<Vec<_>>(),
+                });
+                res.set_body(serde_json::to_vec_pretty(&body)?);
+                res.set_content_type(mime::JSON);
+                return Ok(res);
+            }
+        }
+        if res
+            .content_type()
+            .map_or(false, |ty| ty.essence() == mime::JSON.essence())
+        {
+            let bytes = res.take_body().into_bytes().await?;
+            match serde_json::from_slice::<Value>(&bytes) {
+                Ok(json) => res.set_body(serde_json::to_vec_pretty(&json)?),
+                Err(_) => res.set_body(bytes),
+            }
+            res.set_content_type(mime::JSON);
+        }
+        Ok(res)
+    }
+}
+
+// Records requests for the inspector.
+#[derive(Clone)]
+struct Inspector {
+    max_body_size: usize,
+    keep: usize,
+    requests: Arc<Mutex<VecDeque<Inspected>>>,
+}
+
+impl Inspector {
+    // Capture a text body of at most the capture size, returning the body (which must be put back
+    // in the message) and its text.
+    async fn capture(&self, body: Body) -> tide::Result<(Body, Option<String>)> {
+        let text =
+            body.mime().basetype() == "text" || body.mime().essence() == mime::JSON.essence();
+        match body.len() {
+            Some(len) if text && len <= self.max_body_size => {
+                let mime = body.mime().clone();
+                let bytes = body.into_bytes().await?;
+                let captured = String::from_utf8_lossy(&bytes).into_owned();
+                let mut body = Body::from_bytes(bytes);
+                body.set_mime(mime);
+                Ok((body, Some(captured)))
+            }
+            _ => Ok((body, None)),
+        }
+    }
+}
+
+fn headers(headers: &Headers) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, values)| {
+            let value = if REDACTED_HEADERS.contains(&name.as_str().to_lowercase().as_str()) {
+                "<redacted>".to_string()
+            } else {
+                values.to_string()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Inspector {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if req.url().path() == INSPECTOR_PATH {
+            return Ok(next.run(req).await);
+        }
+        let start = Instant::now();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let method = req.method().to_string();
+        let url = match req.url().query() {
+            Some(query) => format!("{}?{}", req.url().path(), query),
+            None => req.url().path().to_string(),
+        };
+        let request_headers = headers(req.as_ref());
+        let (body, request_body) = self.capture(req.take_body()).await?;
+        req.set_body(body);
+
+        let mut res = next.run(req).await;
+        let (body, response_body) = self.capture(res.take_body()).await?;
+        res.set_body(body);
+
+        let mut requests = self.requests.lock().unwrap();
+        requests.push_back(Inspected {
+            timestamp,
+            method,
+            url,
+            request_headers,
+            request_body,
+            status: res.status() as u16,
+            response_headers: headers(res.as_ref()),
+            response_body,
+            duration_ms: start.elapsed().as_millis() as u64,
+        });
+        while requests.len() > self.keep {
+            requests.pop_front();
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tide::http::Url;
+
+    async fn send(
+        app: &tide::Server<()>,
+        method: Method,
+        path: &str,
+        origin: Option<&str>,
+    ) -> tide::http::Response {
+        let mut req = tide::http::Request::new(
+            method,
+            Url::parse("http://localhost").unwrap().join(path).unwrap(),
+        );
+        if let Some(origin) = origin {
+            req.insert_header("Origin", origin);
+        }
+        req.insert_header("Authorization", "Bearer secret");
+        app.respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_dev_mode() {
+        let mut app = tide::new();
+        dev_mode().keep_requests(2).install(&mut app);
+        app.at("/submit").post(|_| async { Ok("submitted") });
+        app.at("/missing").get(|_| async {
+            Err::<String, _>(tide::Error::from_str(StatusCode::NotFound, "no such block"))
+        });
+        app.at("/panic").get(|_| async {
+            if true {
+                panic!("oops");
+            }
+            Ok("unreachable")
+        });
+
+        // Any origin may use any method.
+        let res = send(
+            &app,
+            Method::Options,
+            "/submit",
+            Some("http://localhost:3000"),
+        )
+        .await;
+        assert_eq!(res["Access-Control-Allow-Origin"], "*");
+        assert!(res["Access-Control-Allow-Methods"]
+            .as_str()
+            .contains("POST"));
+
+        // Errors and panics are explained.
+        let mut res = send(&app, Method::Get, "/missing", None).await;
+        assert_eq!(res.status(), StatusCode::NotFound);
+        let body: Value = res.body_json().await.unwrap();
+        assert_eq!(body["error"], "no such block");
+        let mut res = send(&app, Method::Get, "/panic", None).await;
+        assert_eq!(res.status(), StatusCode::InternalServerError);
+        let body = res.body_string().await.unwrap();
+        assert!(body.contains('\n'));
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["panic"], "oops");
+
+        // The inspector keeps the most recent requests, without secrets.
+        send(&app, Method::Post, "/submit?x=1", None).await;
+        let mut res = send(&app, Method::Get, INSPECTOR_PATH, None).await;
+        let requests: Vec<Inspected> = res.body_json().await.unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].url, "/submit?x=1");
+        assert_eq!(requests[1].response_body.as_deref(), Some("submitted"));
+        assert!(requests[1]
+            .request_headers
+            .contains(&("authorization".into(), "<redacted>".into())));
+        send(&app, Method::Delete, INSPECTOR_PATH, None).await;
+        let mut res = send(&app, Method::Get, INSPECTOR_PATH, None).await;
+        let requests: Vec<Inspected> = res.body_json().await.unwrap();
+        assert!(requests.is_empty());
+    }
+}
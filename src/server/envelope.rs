@@ -0,0 +1,178 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Wrapping responses in envelopes for clients which ask for them.
+//!
+//! The [Envelopes] middleware wraps successful JSON responses in an
+//! [Envelope](crate::wire::envelope::Envelope) when the request asks for one with the [ENVELOPE]
+//! header. The metadata is collected from the response and the middleware itself:
+//!  * the ledger height is taken from the [LEDGER_HEIGHT](crate::headers::LEDGER_HEIGHT) header,
+//!    so handlers which already send it need no changes
+//!  * the node identity is configured with [Envelopes::node]
+//!  * the duration is measured from when the request reaches the middleware
+//!
+//! The middleware should be added before the other middleware, so that the duration includes
+//! them, and so that it sees the headers they add. Responses which other middleware has already
+//! compressed, like those served by [BodyCache](super::BodyCache) with a Content-Encoding, cannot
+//! be spliced into an envelope, and are sent bare.
+//!
+//! ```
+//! use net::server::Envelopes;
+//!
+//! let mut app = tide::new();
+//! app.with(Envelopes::new().node("query-0.us-east"));
+//! ```
+//!
+//! The body is read into memory to wrap it, so routes with streaming responses should not be
+//! wrapped; clients of those routes should not ask for envelopes.
+
+use crate::headers::{LedgerHeight, TypedHeader};
+use crate::wire::body::read_body;
+use crate::wire::envelope::{wrap, EnvelopeVersion, Meta, ENVELOPE, VERSION};
+use std::time::Instant;
+use tide::http::{mime, Body};
+use tide::{Middleware, Next, Request};
+
+/// Server middleware which wraps responses in envelopes for clients which ask for them.
+///
+/// See the [module documentation](self).
+#[derive(Clone, Debug, Default)]
+pub struct Envelopes {
+    node: Option<String>,
+}
+
+impl Envelopes {
+    /// Wrap responses in envelopes, without a node identity.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Identify this node as `node` in the metadata of each envelope.
+    pub fn node(mut self, node: impl Into<String>) -> Self {
+        self.node = Some(node.into());
+        self
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Envelopes {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let start = Instant::now();
+        // Versions this server does not understand are treated like no envelope at all, so the
+        // client can tell from the missing response header that it got a bare body.
+        let wanted = EnvelopeVersion::get(&req)? == Some(EnvelopeVersion(VERSION));
+        let mut res = next.run(req).await;
+        let is_json = res
+            .content_type()
+            .map_or(false, |ty| ty.essence() == mime::JSON.essence());
+        let is_encoded = res.header("Content-Encoding").is_some();
+        if res.error().is_some() || !res.status().is_success() || !is_json || is_encoded {
+            return Ok(res);
+        }
+        res.append_header("Vary", ENVELOPE);
+        if !wanted {
+            return Ok(res);
+        }
+
+        let meta = Meta {
+            ledger_height: LedgerHeight::get(&res)?.map(|LedgerHeight(height)| height),
+            node: self.node.clone(),
+            duration_ms: Some(start.elapsed().as_millis() as u64),
+        };
+        let data = read_body(res.take_body()).await?;
+        let mut body = Body::from_bytes(wrap(&data, &meta)?);
+        body.set_mime(mime::JSON);
+        res.set_body(body);
+        EnvelopeVersion(VERSION).insert(&mut res);
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::redact::Public;
+    use crate::server::response;
+    use crate::wire::envelope::Envelope;
+    use serde_json::{json, Value};
+    use tide::http::{Method, Url};
+    use tide::StatusCode;
+
+    async fn get(app: &tide::Server<()>, path: &str, version: Option<u64>) -> tide::http::Response {
+        let mut req = tide::http::Request::new(
+            Method::Get,
+            Url::parse("http://localhost").unwrap().join(path).unwrap(),
+        );
+        req.insert_header("Accept", "application/json");
+        if let Some(version) = version {
+            EnvelopeVersion(version).insert(&mut req);
+        }
+        app.respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_envelopes() {
+        let mut app = tide::new();
+        app.with(Envelopes::new().node("node-0"));
+        app.at("/balance").get(|req: tide::Request<()>| async move {
+            let mut res = response(&req, Public(json!({"balance": 42})))?;
+            LedgerHeight(7).insert(&mut res);
+            Ok(res)
+        });
+        app.at("/compressed").get(|_| async {
+            let mut res = tide::Response::new(StatusCode::Ok);
+            res.set_body(vec![0x1f, 0x8b]);
+            res.set_content_type(mime::JSON);
+            res.insert_header("Content-Encoding", "gzip");
+            Ok(res)
+        });
+        app.at("/empty").get(|_| async {
+            let mut res = tide::Response::new(StatusCode::Ok);
+            res.set_content_type(mime::JSON);
+            Ok(res)
+        });
+        app.at("/fail").get(|_| async {
+            Err::<String, _>(tide::Error::from_str(StatusCode::NotFound, "no such block"))
+        });
+
+        let mut res = get(&app, "/balance", Some(VERSION)).await;
+        assert_eq!(
+            EnvelopeVersion::get(&res).unwrap(),
+            Some(EnvelopeVersion(VERSION))
+        );
+        let envelope: Envelope<Value> = res.body_json().await.unwrap();
+        assert_eq!(envelope.data, json!({"balance": 42}));
+        assert_eq!(envelope.meta.ledger_height, Some(7));
+        assert_eq!(envelope.meta.node.as_deref(), Some("node-0"));
+        assert!(envelope.meta.duration_ms.is_some());
+
+        // Clients which don't ask for an envelope, or ask for a version the server does not
+        // know, get the bare body.
+        for version in [None, Some(VERSION + 1)] {
+            let mut res = get(&app, "/balance", version).await;
+            assert_eq!(EnvelopeVersion::get(&res).unwrap(), None);
+            assert_eq!(res["Vary"], ENVELOPE);
+            let body: Value = res.body_json().await.unwrap();
+            assert_eq!(body, json!({"balance": 42}));
+        }
+
+        // Compressed bodies are not wrapped.
+        let res = get(&app, "/compressed", Some(VERSION)).await;
+        assert_eq!(EnvelopeVersion::get(&res).unwrap(), None);
+        assert_eq!(res["Content-Encoding"], "gzip");
+
+        // Empty bodies are wrapped as null.
+        let mut res = get(&app, "/empty", Some(VERSION)).await;
+        let envelope: Envelope<Value> = res.body_json().await.unwrap();
+        assert_eq!(envelope.data, Value::Null);
+
+        // Errors are never wrapped.
+        let res = get(&app, "/fail", Some(VERSION)).await;
+        assert_eq!(res.status(), StatusCode::NotFound);
+        assert_eq!(EnvelopeVersion::get(&res).unwrap(), None);
+    }
+}
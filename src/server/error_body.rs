@@ -0,0 +1,610 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Configurable serialization of error responses.
+//!
+//! [ErrorBody] is the configurable form of [add_error_body](super::add_error_body). It can be given
+//! hooks which transform errors before they are serialized (to fill in message templates, or to
+//! localize messages using the client's Accept-Language), and which attach a help message to the
+//! error body (see [wire::error](crate::wire::error)) for clients to show to end users.
+//!
+//! A server whose routes belong to different APIs, each with its own [Error] type, can use
+//! [ErrorRegistry] to serialize errors with the right type for each route.
+//!
+//! Clients which ask for `application/problem+json` get errors as
+//! [problem details](crate::wire::problem) instead.
+//!
+//! Tide answers requests which match no route with an empty 404, and requests for a method their
+//! route does not handle with an empty 405. These responses get error bodies too, with the errors
+//! from [Error::route_not_found] and [Error::method_not_allowed], so clients see the same
+//! serialized errors as for any other failure, and the router's status is kept. The router's
+//! responses are told apart from a handler's by being empty and carrying no error, so a handler
+//! which means to send a 404 of its own should give it a body or an error. Once their bodies are
+//! filled in, they are marked with [Unrouted], so that middleware further up the stack can still
+//! tell them apart. The router does not say which methods a route has, so a 405 only has an Allow
+//! header if a middleware which knows, like [AutoMethods](super::AutoMethods), adds one.
+
+use super::route::RouteTable;
+use super::{best_response_type, negotiated_type, serialize_body, RESPONSE_TYPES};
+use crate::authz::Forbidden;
+use crate::challenge::{Challenge, ChallengeRequired};
+use crate::error::Error;
+use crate::maintenance::UnderMaintenance;
+use crate::wire::error::{append_challenge, append_forbidden, append_help, append_maintenance};
+use crate::wire::problem::{self, Problem};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use tide::http::{content::Accept, mime, Mime};
+use tide::{Middleware, Next, Request, Response, StatusCode};
+use tracing::{event, Level};
+
+/// A response extension marking the router's own answer to a request which matched no route (404),
+/// or no handler on its route (405).
+///
+/// [ErrorBody] adds this to the responses it fills in for the router.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Unrouted;
+
+/// Whether `res` is the router's own 404 or 405, rather than a response from a handler.
+pub(crate) fn is_unrouted(res: &Response) -> bool {
+    res.ext::<Unrouted>().is_some()
+        || (res.error().is_none()
+            && matches!(
+                res.status(),
+                StatusCode::NotFound | StatusCode::MethodNotAllowed
+            )
+            && res.is_empty() == Some(true))
+}
+
+/// Information about the request which caused an error, for use by [ErrorBody] hooks.
+#[derive(Clone, Debug)]
+pub struct ErrorContext {
+    pub method: tide::http::Method,
+    pub path: String,
+    /// The value of the Accept-Language header, if any.
+    pub accept_language: Option<String>,
+}
+
+type MapHook<E> = Arc<dyn Fn(E, &ErrorContext) -> E + Send + Sync>;
+type HelpHook<E> = Arc<dyn Fn(&E, &ErrorContext) -> Option<String> + Send + Sync>;
+
+/// Server middleware which populates the body of error responses.
+pub struct ErrorBody<E> {
+    map: Option<MapHook<E>>,
+    help: Option<HelpHook<E>>,
+    _phantom: PhantomData<fn(E)>,
+}
+
+impl<E> Clone for ErrorBody<E> {
+    fn clone(&self) -> Self {
+        Self {
+            map: self.map.clone(),
+            help: self.help.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<E> Default for ErrorBody<E> {
+    fn default() -> Self {
+        Self {
+            map: None,
+            help: None,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<E: Error> ErrorBody<E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Transform each error before it is serialized.
+    pub fn map_error(mut self, f: impl Fn(E, &ErrorContext) -> E + Send + Sync + 'static) -> Self {
+        self.map = Some(Arc::new(f));
+        self
+    }
+
+    /// Attach a help message to errors.
+    ///
+    /// `f` is called with each error, after it has been transformed by [map_error](Self::map_error),
+    /// and may return a message to include in the `help` field of the error body.
+    pub fn help(
+        mut self,
+        f: impl Fn(&E, &ErrorContext) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.help = Some(Arc::new(f));
+        self
+    }
+}
+
+// An [ErrorBody] with its error type erased, so that bodies for different error types can be
+// stored together.
+trait EncodeError: Send + Sync {
+    /// Convert `error` and serialize it as `ty`, returning the status and body of the response.
+    fn encode(
+        &self,
+        error: tide::Error,
+        cx: &ErrorContext,
+        ty: &Mime,
+    ) -> Result<(StatusCode, Vec<u8>), tide::Error>;
+
+    /// Serialize the error for a request which the router answered with `status`, because it
+    /// matched no route (404) or no handler on its route (405).
+    fn encode_unrouted(
+        &self,
+        status: StatusCode,
+        cx: &ErrorContext,
+        ty: &Mime,
+    ) -> Result<(StatusCode, Vec<u8>), tide::Error>;
+}
+
+impl<E: Error> EncodeError for ErrorBody<E> {
+    fn encode(
+        &self,
+        error: tide::Error,
+        cx: &ErrorContext,
+        ty: &Mime,
+    ) -> Result<(StatusCode, Vec<u8>), tide::Error> {
+        // A client must solve a challenge, wait for maintenance to end, or be granted a role,
+        // before it can retry, whatever the error type says.
+        let (status, attachment) = if let Some(err) = error.downcast_ref::<ChallengeRequired>() {
+            (
+                Some(StatusCode::TooManyRequests),
+                Some(Attachment::Challenge(err.challenge.clone())),
+            )
+        } else if let Some(err) = error.downcast_ref::<UnderMaintenance>() {
+            (
+                Some(StatusCode::ServiceUnavailable),
+                Some(Attachment::Maintenance(err.clone())),
+            )
+        } else if let Some(err) = error.downcast_ref::<Forbidden>() {
+            (
+                Some(StatusCode::Forbidden),
+                Some(Attachment::Forbidden(err.clone())),
+            )
+        } else {
+            (None, None)
+        };
+        self.encode_as(E::from_client_error(error), status, attachment, cx, ty)
+    }
+
+    fn encode_unrouted(
+        &self,
+        status: StatusCode,
+        cx: &ErrorContext,
+        ty: &Mime,
+    ) -> Result<(StatusCode, Vec<u8>), tide::Error> {
+        let error = if status == StatusCode::MethodNotAllowed {
+            E::method_not_allowed(cx.method, &cx.path)
+        } else {
+            E::route_not_found(&cx.path)
+        };
+        // Keep the router's status, even if the error type would report a different one, so that
+        // clients can always tell a missing route from a failed request.
+        self.encode_as(error, Some(status), None, cx, ty)
+    }
+}
+
+// Details which some errors carry in the error body, next to the serialized error.
+enum Attachment {
+    Challenge(Challenge),
+    Maintenance(UnderMaintenance),
+    Forbidden(Forbidden),
+}
+
+impl<E: Error> ErrorBody<E> {
+    // Serialize `error` as `ty`, with the status of the error unless `status` overrides it.
+    fn encode_as(
+        &self,
+        error: E,
+        status: Option<StatusCode>,
+        attachment: Option<Attachment>,
+        cx: &ErrorContext,
+        ty: &Mime,
+    ) -> Result<(StatusCode, Vec<u8>), tide::Error> {
+        let error = match &self.map {
+            Some(map) => map(error, cx),
+            None => error,
+        };
+        event!(Level::WARN, "responding with error: {}", error);
+
+        let status = status.unwrap_or_else(|| error.status());
+        let (mut body, essence) = if ty.essence() == problem::CONTENT_TYPE {
+            let mut problem = Problem::from_error(&error, Some(cx.path.clone()));
+            problem.status = status as u16;
+            problem.title = status.canonical_reason().into();
+            (serde_json::to_vec(&problem)?, mime::JSON.essence())
+        } else {
+            (serialize_body(ty, &error)?, ty.essence())
+        };
+        if let Some(help) = self.help.as_ref().and_then(|help| help(&error, cx)) {
+            append_help(essence, &mut body, &help)?;
+        }
+        match attachment {
+            Some(Attachment::Challenge(challenge)) => append_challenge(&mut body, &challenge)?,
+            Some(Attachment::Maintenance(maintenance)) if essence == mime::JSON.essence() => {
+                append_maintenance(&mut body, &maintenance)?
+            }
+            Some(Attachment::Forbidden(forbidden)) if essence == mime::JSON.essence() => {
+                append_forbidden(&mut body, &forbidden)?
+            }
+            _ => {}
+        }
+        Ok((status, body))
+    }
+}
+
+// Run the rest of the stack, and replace the body of an error response with the error encoded by
+// the encoder `select` chooses for the request path.
+async fn handle_errors<'e, State: Clone + Send + Sync + 'static>(
+    req: Request<State>,
+    next: Next<'_, State>,
+    select: impl FnOnce(&str) -> Option<&'e dyn EncodeError>,
+) -> tide::Result {
+    let mut accept = Accept::from_headers(&req)?;
+    let cx = ErrorContext {
+        method: req.method(),
+        path: req.url().path().to_string(),
+        accept_language: req
+            .header("Accept-Language")
+            .map(|h| h.as_str().to_string()),
+    };
+    let mut res: Response = next.run(req).await;
+    let encoder = match select(&cx.path) {
+        Some(encoder) => encoder,
+        None => return Ok(res),
+    };
+    // The router answers requests which match no route, or no handler on their route, with an
+    // empty 404 or 405 response, which has no error to encode.
+    let error = res.take_error();
+    let unrouted = error.is_none() && is_unrouted(&res);
+    if error.is_none() && !unrouted {
+        return Ok(res);
+    }
+    if unrouted {
+        res.insert_ext(Unrouted);
+    }
+
+    // Replace the body of the response, but keep its headers, since middleware further down the
+    // stack may have set headers which are relevant to the error (e.g. Retry-After). Problem
+    // details are only offered to clients which name them, and are preferred by those clients.
+    // Challenges are always sent as JSON objects; see [wire::error](crate::wire::error).
+    let asks_for_problem = accept.as_ref().map_or(false, |accept| {
+        accept
+            .iter()
+            .any(|proposal| proposal.essence() == problem::CONTENT_TYPE)
+    });
+    let ty = if asks_for_problem {
+        best_response_type(
+            &mut accept,
+            &[problem::mime(), mime::JSON, mime::BYTE_STREAM],
+        )?
+    } else {
+        match negotiated_type(res.ext(), &RESPONSE_TYPES) {
+            Some(ty) => ty,
+            None => best_response_type(&mut accept, &RESPONSE_TYPES)?,
+        }
+    };
+    let challenge = error.as_ref().map_or(false, |error| {
+        error.downcast_ref::<ChallengeRequired>().is_some()
+    });
+    let ty = if ty == mime::BYTE_STREAM && challenge {
+        mime::JSON
+    } else {
+        ty
+    };
+    let (status, body) = match error {
+        Some(error) => encoder.encode(error, &cx, &ty)?,
+        None => encoder.encode_unrouted(res.status(), &cx, &ty)?,
+    };
+    res.set_status(status);
+    res.set_body(body);
+    res.set_content_type(ty);
+    Ok(res)
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static, E: Error> Middleware<State> for ErrorBody<E> {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        handle_errors(req, next, |_| Some(self as &dyn EncodeError)).await
+    }
+}
+
+/// Server middleware which populates the body of error responses, using a different [Error] type
+/// for each group of routes.
+///
+/// Each group is configured with an [ErrorBody] for its error type, so hooks can also differ
+/// between groups. Errors from routes which match no group are serialized with the fallback error
+/// type, if there is one, or else passed through unchanged.
+#[derive(Clone, Default)]
+pub struct ErrorRegistry {
+    routes: RouteTable<Arc<dyn EncodeError>>,
+    fallback: Option<Arc<dyn EncodeError>>,
+}
+
+impl ErrorRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serialize errors from routes matching `template` with `body`.
+    pub fn route<E: Error>(mut self, template: impl Into<String>, body: ErrorBody<E>) -> Self {
+        self.routes.insert(template, Arc::new(body));
+        self
+    }
+
+    /// Serialize errors from routes which match no group with `body`.
+    pub fn fallback<E: Error>(mut self, body: ErrorBody<E>) -> Self {
+        self.fallback = Some(Arc::new(body));
+        self
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ErrorRegistry {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        handle_errors(req, next, |path| {
+            self.routes
+                .lookup(path)
+                .map(|(_, encoder)| encoder)
+                .or(self.fallback.as_ref())
+                .map(|encoder| &**encoder)
+        })
+        .await
+    }
+}
+
+#[cfg(all(test, feature = "client"))]
+mod test {
+    use super::*;
+    use crate::client::ErrorTypes;
+    use crate::error::server_error;
+    use crate::test_util::Error;
+    use serde::{Deserialize, Serialize};
+    use snafu::Snafu;
+    use tide::StatusCode;
+
+    #[async_std::test]
+    async fn test_hooks() {
+        let mut app = tide::new();
+        app.with(
+            ErrorBody::<Error>::new()
+                .map_error(|err, cx| match cx.accept_language.as_deref() {
+                    Some("fr") => Error {
+                        msg: format!("erreur: {}", err.msg),
+                    },
+                    _ => err,
+                })
+                .help(|_, cx| Some(format!("see the documentation for {}", cx.path))),
+        );
+        app.at("/fail").get(|_| async {
+            Err::<String, _>(server_error::<Error>(Error {
+                msg: "bad".to_string(),
+            }))
+        });
+
+        let mut req = tide::http::Request::new(
+            tide::http::Method::Get,
+            tide::http::Url::parse("http://localhost/fail").unwrap(),
+        );
+        req.insert_header("Accept", "application/json");
+        req.insert_header("Accept-Language", "fr");
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "msg": "erreur: bad",
+                "help": "see the documentation for /fail",
+            })
+        );
+    }
+
+    #[async_std::test]
+    async fn test_problem() {
+        let mut app = tide::new();
+        app.with(ErrorBody::<Error>::new().help(|_, _| Some("try again".into())));
+        app.at("/fail").get(|_| async {
+            Err::<String, _>(server_error::<Error>(Error {
+                msg: "bad".to_string(),
+            }))
+        });
+        let get = |accept: &'static str| {
+            let mut req = tide::http::Request::new(
+                tide::http::Method::Get,
+                tide::http::Url::parse("http://localhost/fail").unwrap(),
+            );
+            req.insert_header("Accept", accept);
+            app.respond::<_, tide::http::Response>(req)
+        };
+
+        // Clients which ask for problem details get them.
+        let mut res = get("application/problem+json, application/json")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        assert_eq!(res.content_type().unwrap().essence(), problem::CONTENT_TYPE);
+        let bytes = res.body_bytes().await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&bytes).unwrap(),
+            serde_json::json!({
+                "type": "about:blank",
+                "title": "Bad Request",
+                "status": 400,
+                "detail": "bad",
+                "instance": "/fail",
+                "error": {"msg": "bad"},
+                "help": "try again",
+            })
+        );
+        // Rust clients still recover the error.
+        let (err, help) = crate::client::decode_error_and_help::<Error>(
+            StatusCode::BadRequest,
+            Some(problem::CONTENT_TYPE),
+            &bytes,
+        );
+        assert_eq!(err.msg, "bad");
+        assert_eq!(help.as_deref(), Some("try again"));
+
+        // Other clients do not.
+        for accept in [
+            "*/*",
+            "application/*",
+            "application/problem+json;q=0.5, */*",
+        ] {
+            let res = get(accept).await.unwrap();
+            assert_eq!(res.content_type(), Some(mime::JSON), "{}", accept);
+        }
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize, Snafu, PartialEq, Eq)]
+    #[snafu(display("{}", reason))]
+    struct QueryError {
+        code: u16,
+        reason: String,
+    }
+
+    impl crate::Error for QueryError {
+        fn catch_all(reason: String) -> Self {
+            Self { code: 0, reason }
+        }
+
+        fn status(&self) -> StatusCode {
+            StatusCode::NotFound
+        }
+    }
+
+    // Send a request to `app` and interpret the error response with `errors`.
+    async fn error(
+        app: &tide::Server<()>,
+        errors: &ErrorTypes,
+        method: tide::http::Method,
+        path: &str,
+    ) -> (StatusCode, surf::Error) {
+        let mut req = tide::http::Request::new(
+            method,
+            tide::http::Url::parse("http://localhost")
+                .unwrap()
+                .join(path)
+                .unwrap(),
+        );
+        req.insert_header("Accept", "application/json");
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        let bytes = res.body_bytes().await.unwrap();
+        let ty = res.content_type().map(|ty| ty.essence().to_string());
+        (
+            res.status(),
+            errors.decode(res.status(), ty.as_deref(), &bytes),
+        )
+    }
+
+    #[async_std::test]
+    async fn test_registry() {
+        let mut app = tide::new();
+        app.with(
+            ErrorRegistry::new()
+                .route("/query/*", ErrorBody::<QueryError>::new())
+                .fallback(ErrorBody::<Error>::new()),
+        );
+        app.at("/query/block").get(|_| async {
+            Err::<String, _>(server_error::<QueryError>(QueryError {
+                code: 7,
+                reason: "no such block".to_string(),
+            }))
+        });
+        app.at("/submit").post(|_| async {
+            Err::<String, _>(server_error::<Error>(Error {
+                msg: "bad".to_string(),
+            }))
+        });
+        let errors = ErrorTypes::new().with::<QueryError>().with::<Error>();
+
+        // Each route group serializes its own error type, which the client recovers.
+        let (status, err) = error(&app, &errors, tide::http::Method::Get, "/query/block").await;
+        assert_eq!(status, StatusCode::NotFound);
+        assert_eq!(
+            err.downcast_ref::<QueryError>(),
+            Some(&QueryError {
+                code: 7,
+                reason: "no such block".to_string()
+            })
+        );
+        let (status, err) = error(&app, &errors, tide::http::Method::Post, "/submit").await;
+        assert_eq!(status, StatusCode::BadRequest);
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error {
+                msg: "bad".to_string()
+            })
+        );
+
+        // Errors which are neither type are interpreted using the first type.
+        let err = errors.decode(StatusCode::BadGateway, None, b"upstream unavailable");
+        assert_eq!(
+            err.downcast_ref::<QueryError>().unwrap().reason,
+            "upstream unavailable"
+        );
+    }
+
+    #[async_std::test]
+    async fn test_unrouted() {
+        let mut app = tide::new();
+        app.with(ErrorBody::<Error>::new());
+        app.at("/fail").get(|_| async {
+            Err::<String, _>(server_error::<Error>(Error {
+                msg: "bad".to_string(),
+            }))
+        });
+        app.at("/gone")
+            .get(|_| async { Ok(Response::builder(StatusCode::NotFound).body("gone")) });
+        let errors = ErrorTypes::new().with::<Error>();
+
+        // The router's own responses get error bodies, with the router's status.
+        let (status, err) = error(&app, &errors, tide::http::Method::Get, "/nothing").await;
+        assert_eq!(status, StatusCode::NotFound);
+        assert_eq!(
+            err.downcast_ref::<Error>().unwrap().msg,
+            "no route for /nothing"
+        );
+        let (status, err) = error(&app, &errors, tide::http::Method::Post, "/fail").await;
+        assert_eq!(status, StatusCode::MethodNotAllowed);
+        assert_eq!(
+            err.downcast_ref::<Error>().unwrap().msg,
+            "method POST not allowed for /fail"
+        );
+
+        // The router's responses are marked, and a 405 keeps the Allow header it was given.
+        let mut app = tide::new();
+        app.with(ErrorBody::<Error>::new());
+        app.with(crate::server::AutoMethods::new().route("/fail", [tide::http::Method::Get]));
+        app.at("/fail").get(|_| async { Ok("") });
+        let res: tide::http::Response = app
+            .respond(tide::http::Request::new(
+                tide::http::Method::Post,
+                tide::http::Url::parse("http://localhost/fail").unwrap(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::MethodNotAllowed);
+        assert_eq!(res.ext::<Unrouted>(), Some(&Unrouted));
+        assert_eq!(res["Allow"], "GET, HEAD, OPTIONS");
+
+        // Handlers which respond with a body of their own are left alone.
+        let mut res: tide::http::Response = app
+            .respond(tide::http::Request::new(
+                tide::http::Method::Get,
+                tide::http::Url::parse("http://localhost/gone").unwrap(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+        assert_eq!(res.body_string().await.unwrap(), "gone");
+    }
+}
@@ -0,0 +1,373 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! The real client of a request served behind proxies.
+//!
+//! Behind a load balancer or TLS-terminating proxy, the peer of every request is the proxy. The
+//! proxy reports who its client was, and how it was reached, in the standard `Forwarded` header
+//! (RFC 7239) or the older `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host` headers.
+//! Anyone can send these headers, though, so they can only be believed when they were added by a
+//! proxy we trust.
+//!
+//! The [ProxyHeaders] middleware is configured with [TrustedProxies]: the ranges of trusted
+//! proxies, and which of the two kinds of header they set. It reads the hops in that header from
+//! right to left (nearest first), for as long as each hop was added by a trusted proxy, and stores
+//! the resulting [ClientInfo] in the request. Handlers and other middleware get it with [client_info];
+//! the per-client [rate limit](super::rate_limit), the [audit log](super::audit), the
+//! [logger](super::logging) and [ip_filter](super::ip_filter) all do, so they agree on who the
+//! client is.
+//!
+//! The other kind of header is never read. A proxy which only appends to `X-Forwarded-For` passes
+//! on a `Forwarded` header sent by the client untouched, so reading it would let the client choose
+//! its own address.
+//!
+//! ```
+//! use net::server::forwarded::{client_info, ForwardingHeader, ProxyHeaders, TrustedProxies};
+//!
+//! let proxies =
+//!     TrustedProxies::new(ForwardingHeader::XForwardedFor).trust("10.0.0.0/8".parse().unwrap());
+//! let mut app = tide::new();
+//! app.with(ProxyHeaders::new(proxies));
+//! app.at("/whoami").get(|req: tide::Request<()>| async move {
+//!     Ok(format!("{:?}", client_info(&req).addr))
+//! });
+//! ```
+
+use super::ip_filter::Cidr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tide::{Middleware, Next, Request};
+
+/// Where a request really came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// The address of the client, or [None] if it is unknown (or hidden by a proxy).
+    pub addr: Option<IpAddr>,
+    /// The scheme the client used, like `https`.
+    pub scheme: String,
+    /// The host the client asked for.
+    pub host: Option<String>,
+}
+
+/// The client of a request.
+///
+/// If the request passed through [ProxyHeaders], this is the client it determined. Otherwise, it is
+/// the peer of the connection, and the forwarding headers are ignored.
+pub fn client_info<State>(req: &Request<State>) -> ClientInfo {
+    match req.ext::<ClientInfo>() {
+        Some(info) => info.clone(),
+        None => peer_info(req),
+    }
+}
+
+/// The header in which trusted proxies report the client of a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ForwardingHeader {
+    /// The standard `Forwarded` header (RFC 7239).
+    Forwarded,
+    /// The `X-Forwarded-For` header, along with `X-Forwarded-Proto` and `X-Forwarded-Host`.
+    XForwardedFor,
+}
+
+/// The proxies whose forwarding headers are believed, and the header they set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrustedProxies {
+    header: ForwardingHeader,
+    ranges: Vec<Cidr>,
+}
+
+impl TrustedProxies {
+    /// Trust no proxies yet; those trusted with [trust](Self::trust) report clients in `header`.
+    pub fn new(header: ForwardingHeader) -> Self {
+        Self {
+            header,
+            ranges: vec![],
+        }
+    }
+
+    /// Trust the proxies in `range`.
+    pub fn trust(mut self, range: Cidr) -> Self {
+        self.ranges.push(range);
+        self
+    }
+
+    /// The header trusted proxies set.
+    pub fn header(&self) -> ForwardingHeader {
+        self.header
+    }
+
+    /// Whether `addr` is a trusted proxy.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        self.ranges.iter().any(|range| range.contains(addr))
+    }
+
+    /// Whether no proxies are trusted.
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+// One hop of the forwarding headers.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Hop {
+    addr: Option<IpAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+// The hops in `header`, oldest first.
+fn hops<State>(req: &Request<State>, header: ForwardingHeader) -> Vec<Hop> {
+    if header == ForwardingHeader::Forwarded {
+        return match req.header("Forwarded") {
+            Some(values) => values
+                .iter()
+                .flat_map(|value| value.as_str().split(','))
+                .map(parse_forwarded_element)
+                .collect(),
+            None => vec![],
+        };
+    }
+    let mut hops = match req.header("X-Forwarded-For") {
+        Some(values) => values
+            .iter()
+            .flat_map(|value| value.as_str().split(','))
+            .map(|hop| Hop {
+                addr: parse_addr(hop.trim()),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>(),
+        None => return vec![],
+    };
+    // These headers are set, not appended to, so they describe the connection to the nearest proxy.
+    let last = |name: &str| {
+        let values = req.header(name)?;
+        let value = values.last().as_str().rsplit(',').next()?.trim();
+        Some(value.to_string())
+    };
+    if let Some(hop) = hops.last_mut() {
+        hop.proto = last("X-Forwarded-Proto");
+        hop.host = last("X-Forwarded-Host");
+    }
+    hops
+}
+
+// Parse one element of a `Forwarded` header, like `for=192.0.2.60;proto=https;by=203.0.113.43`.
+fn parse_forwarded_element(element: &str) -> Hop {
+    let mut hop = Hop::default();
+    for pair in element.split(';') {
+        let (name, value) = match pair.split_once('=') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        let value = value.trim().trim_matches('"');
+        match name.trim().to_ascii_lowercase().as_str() {
+            "for" => hop.addr = parse_addr(value),
+            "proto" => hop.proto = Some(value.to_ascii_lowercase()),
+            "host" => hop.host = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    hop
+}
+
+// Parse an address which may have a port, as in peer addresses and forwarding headers. Obfuscated
+// identifiers and `unknown` are [None].
+fn parse_addr(s: &str) -> Option<IpAddr> {
+    s.parse::<IpAddr>()
+        .or_else(|_| s.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .or_else(|| {
+            // A bracketed IPv6 address without a port.
+            s.strip_prefix('[')?.strip_suffix(']')?.parse().ok()
+        })
+}
+
+// The client of `req`, if it is the peer of the connection.
+pub(crate) fn peer_info<State>(req: &Request<State>) -> ClientInfo {
+    ClientInfo {
+        addr: req.peer_addr().and_then(parse_addr),
+        scheme: req.url().scheme().to_string(),
+        host: req.url().host_str().map(String::from),
+    }
+}
+
+// Determine the client of `req`, believing forwarding headers added by `trusted` proxies.
+pub(crate) fn resolve<State>(req: &Request<State>, trusted: &TrustedProxies) -> ClientInfo {
+    let is_trusted = |addr: Option<IpAddr>| addr.map_or(false, |addr| trusted.contains(addr));
+    let mut info = peer_info(req);
+    if !is_trusted(info.addr) {
+        return info;
+    }
+    for hop in hops(req, trusted.header).into_iter().rev() {
+        info.addr = hop.addr;
+        if let Some(proto) = hop.proto {
+            info.scheme = proto;
+        }
+        if let Some(host) = hop.host {
+            info.host = Some(host);
+        }
+        if !is_trusted(info.addr) {
+            break;
+        }
+    }
+    info
+}
+
+/// Server middleware which determines the real client of each request, from the forwarding
+/// headers added by trusted proxies.
+///
+/// See the [module documentation](self).
+#[derive(Clone, Debug)]
+pub struct ProxyHeaders {
+    trusted: Arc<TrustedProxies>,
+}
+
+impl ProxyHeaders {
+    /// Believe the forwarding headers of requests from `trusted` proxies.
+    pub fn new(trusted: TrustedProxies) -> Self {
+        Self {
+            trusted: Arc::new(trusted),
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProxyHeaders {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let info = resolve(&req, &self.trusted);
+        req.set_ext(info);
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tide::http::{Method, Url};
+
+    async fn client(
+        proxies: ProxyHeaders,
+        peer: &str,
+        headers: &[(&'static str, &'static str)],
+    ) -> ClientInfo {
+        let mut app = tide::new();
+        app.with(proxies);
+        app.at("/").get(|req: tide::Request<()>| async move {
+            Ok(serde_json::to_string(&(
+                client_info(&req).addr,
+                client_info(&req).scheme,
+                client_info(&req).host,
+            ))?)
+        });
+        let mut req =
+            tide::http::Request::new(Method::Get, Url::parse("http://localhost/").unwrap());
+        req.set_peer_addr(Some(peer));
+        for (name, value) in headers {
+            req.append_header(*name, *value);
+        }
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        let (addr, scheme, host) = res.body_json().await.unwrap();
+        ClientInfo { addr, scheme, host }
+    }
+
+    fn info(addr: Option<&str>, scheme: &str, host: &str) -> ClientInfo {
+        ClientInfo {
+            addr: addr.map(|addr| addr.parse().unwrap()),
+            scheme: scheme.into(),
+            host: Some(host.into()),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse_forwarded_element(r#"for="[2001:db8::1]:4711";Proto=HTTPS;host=api.example.com"#),
+            Hop {
+                addr: Some("2001:db8::1".parse().unwrap()),
+                proto: Some("https".into()),
+                host: Some("api.example.com".into()),
+            }
+        );
+        assert_eq!(parse_addr("[::1]"), Some("::1".parse().unwrap()));
+        assert_eq!(parse_addr("10.0.0.1:80"), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(parse_addr("unknown"), None);
+        assert_eq!(parse_addr("_hidden"), None);
+    }
+
+    #[async_std::test]
+    async fn test_proxy_headers() {
+        let trusted = |header| {
+            ProxyHeaders::new(TrustedProxies::new(header).trust("10.0.0.0/8".parse().unwrap()))
+        };
+        let proxies = trusted(ForwardingHeader::Forwarded);
+
+        // Without forwarding headers, or from an untrusted peer, the client is the peer.
+        assert_eq!(
+            client(proxies.clone(), "10.0.0.1:1", &[]).await,
+            info(Some("10.0.0.1"), "http", "localhost")
+        );
+        let spoofed = [("X-Forwarded-For", "1.2.3.4"), ("Forwarded", "for=1.2.3.4")];
+        assert_eq!(
+            client(proxies.clone(), "8.8.8.8:1", &spoofed).await,
+            info(Some("8.8.8.8"), "http", "localhost")
+        );
+
+        // The Forwarded header is read until the first untrusted hop.
+        let headers = [
+            ("X-Forwarded-For", "5.6.7.8"),
+            (
+                "Forwarded",
+                "for=9.9.9.9, for=1.2.3.4;proto=https;host=api.example.com, for=10.0.0.2",
+            ),
+        ];
+        assert_eq!(
+            client(proxies.clone(), "10.0.0.1:1", &headers).await,
+            info(Some("1.2.3.4"), "https", "api.example.com")
+        );
+
+        // A hidden client is unknown.
+        assert_eq!(
+            client(
+                proxies.clone(),
+                "10.0.0.1:1",
+                &[("Forwarded", "for=_hidden")]
+            )
+            .await,
+            info(None, "http", "localhost")
+        );
+
+        // The X-Forwarded-* headers are not read when proxies set Forwarded.
+        let headers = [
+            ("X-Forwarded-For", "9.9.9.9, 1.2.3.4"),
+            ("X-Forwarded-Proto", "https"),
+            ("X-Forwarded-Host", "api.example.com"),
+        ];
+        assert_eq!(
+            client(proxies, "10.0.0.1:1", &headers).await,
+            info(Some("10.0.0.1"), "http", "localhost")
+        );
+
+        // Proxies which set X-Forwarded-For never have their clients' Forwarded headers believed.
+        let proxies = trusted(ForwardingHeader::XForwardedFor);
+        assert_eq!(
+            client(proxies.clone(), "10.0.0.1:1", &headers).await,
+            info(Some("1.2.3.4"), "https", "api.example.com")
+        );
+        let spoofed = [
+            ("X-Forwarded-For", "1.2.3.4"),
+            ("Forwarded", "for=10.0.0.5"),
+        ];
+        assert_eq!(
+            client(proxies.clone(), "10.0.0.1:1", &spoofed).await,
+            info(Some("1.2.3.4"), "http", "localhost")
+        );
+        assert_eq!(
+            client(proxies, "10.0.0.1:1", &[("Forwarded", "for=10.0.0.5")]).await,
+            info(Some("10.0.0.1"), "http", "localhost")
+        );
+    }
+}
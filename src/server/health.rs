@@ -0,0 +1,190 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Liveness and readiness probes.
+//!
+//! Orchestrators like Kubernetes distinguish between a service which is alive (the process is
+//! running and able to respond; if not, it should be restarted) and one which is ready (it can
+//! usefully serve requests; if not, traffic should be routed elsewhere). [Health] serves both:
+//!  * `/livez` always responds 200 if the server is able to respond at all
+//!  * `/readyz` runs every registered dependency check (database reachable, consensus caught up,
+//!    etc.) concurrently, each with its own timeout, and responds 200 if all of them pass, or 503
+//!    otherwise. Either way, the body is a [Readiness] with the result of each check.
+
+use super::response;
+use crate::redact::{Public, SafeToServe};
+use futures::future::{join_all, BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tide::StatusCode;
+
+/// The result of a single dependency check.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckResult {
+    pub name: String,
+    pub healthy: bool,
+    /// The reason the check failed, if it did.
+    pub message: Option<String>,
+    pub elapsed_ms: u64,
+}
+
+/// The body of a `/readyz` response.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<CheckResult>,
+}
+
+impl SafeToServe for CheckResult {}
+impl SafeToServe for Readiness {}
+
+type Check = Arc<dyn Fn() -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+
+/// A set of dependency checks, served as liveness and readiness endpoints.
+#[derive(Clone, Default)]
+pub struct Health {
+    checks: Vec<(String, Duration, Check)>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a dependency check.
+    ///
+    /// The service is not ready unless `check` succeeds within `timeout`.
+    pub fn check<F, Fut>(mut self, name: impl Into<String>, timeout: Duration, check: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.checks
+            .push((name.into(), timeout, Arc::new(move || check().boxed())));
+        self
+    }
+
+    /// Run all the dependency checks.
+    pub async fn readiness(&self) -> Readiness {
+        let checks = join_all(self.checks.iter().map(|(name, timeout, check)| async move {
+            let start = Instant::now();
+            let result = match async_std::future::timeout(*timeout, check()).await {
+                Ok(result) => result,
+                Err(_) => Err(format!("timed out after {:?}", timeout)),
+            };
+            CheckResult {
+                name: name.clone(),
+                healthy: result.is_ok(),
+                message: result.err(),
+                elapsed_ms: start.elapsed().as_millis() as u64,
+            }
+        }))
+        .await;
+        Readiness {
+            ready: checks.iter().all(|check| check.healthy),
+            checks,
+        }
+    }
+
+    /// Add `/livez` and `/readyz` endpoints to `app`.
+    pub fn serve<State: Clone + Send + Sync + 'static>(&self, app: &mut tide::Server<State>) {
+        app.at("/livez")
+            .get(|req: tide::Request<State>| async move { response(&req, Public("ok")) });
+        let health = self.clone();
+        app.at("/readyz").get(move |req: tide::Request<State>| {
+            let health = health.clone();
+            async move {
+                let readiness = health.readiness().await;
+                let status = if readiness.ready {
+                    StatusCode::Ok
+                } else {
+                    StatusCode::ServiceUnavailable
+                };
+                let mut res = response(&req, readiness)?;
+                res.set_status(status);
+                Ok(res)
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    async fn get(app: &tide::Server<()>, path: &str) -> (StatusCode, tide::http::Response) {
+        let req = tide::http::Request::new(
+            tide::http::Method::Get,
+            tide::http::Url::parse("http://localhost")
+                .unwrap()
+                .join(path)
+                .unwrap(),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        (res.status(), res)
+    }
+
+    #[async_std::test]
+    async fn test_health() {
+        let caught_up = Arc::new(AtomicBool::new(false));
+        let health = Health::new()
+            .check("database", Duration::from_secs(1), || async { Ok(()) })
+            .check("consensus", Duration::from_secs(1), {
+                let caught_up = caught_up.clone();
+                move || {
+                    let caught_up = caught_up.load(Ordering::SeqCst);
+                    async move {
+                        if caught_up {
+                            Ok(())
+                        } else {
+                            Err("catching up".to_string())
+                        }
+                    }
+                }
+            })
+            .check("slow", Duration::from_millis(10), {
+                let caught_up = caught_up.clone();
+                move || {
+                    let caught_up = caught_up.load(Ordering::SeqCst);
+                    async move {
+                        if !caught_up {
+                            async_std::task::sleep(Duration::from_secs(10)).await;
+                        }
+                        Ok(())
+                    }
+                }
+            });
+        let mut app = tide::new();
+        health.serve(&mut app);
+
+        // The service is live but not ready.
+        assert_eq!(get(&app, "/livez").await.0, StatusCode::Ok);
+        let (status, mut res) = get(&app, "/readyz").await;
+        assert_eq!(status, StatusCode::ServiceUnavailable);
+        let readiness: Readiness = res.body_json().await.unwrap();
+        assert!(!readiness.ready);
+        let results = readiness
+            .checks
+            .iter()
+            .map(|check| (check.name.as_str(), check.healthy))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            [("database", true), ("consensus", false), ("slow", false)]
+        );
+        assert_eq!(readiness.checks[1].message.as_deref(), Some("catching up"));
+
+        // Once all the checks pass, the service is ready.
+        caught_up.store(true, Ordering::SeqCst);
+        let (status, mut res) = get(&app, "/readyz").await;
+        assert_eq!(status, StatusCode::Ok);
+        assert!(res.body_json::<Readiness>().await.unwrap().ready);
+    }
+}
@@ -0,0 +1,207 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Idempotent handling of retried requests.
+//!
+//! A client on an unreliable network cannot tell whether a request which failed was never received
+//! or was processed and only the response was lost, so it retries, and the server may see the same
+//! request more than once. Clients mark each logical request with a unique [IDEMPOTENCY_KEY]
+//! header, which stays the same across retries (see
+//! [OutboundQueue](crate::client::queue::OutboundQueue)). The [Idempotency] middleware remembers
+//! the response to each keyed request and replays it for later requests with the same key, instead
+//! of running the handler again.
+//!
+//! Responses with a 5xx status are not remembered, since retrying them may succeed. Requests
+//! without the header, and GET requests, are passed through unchanged.
+//!
+//! Responses are only remembered once the handler finishes, so copies of a request which arrive
+//! while the first is still being handled would run the handler again. [Idempotency::claims]
+//! prevents this by claiming each key in a [replay store](super::replay) before running the
+//! handler.
+
+use super::coalesce::Snapshot;
+use super::replay::{ReplayError, Replays};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tide::http::Method;
+use tide::{Middleware, Next, Request, StatusCode};
+
+pub use crate::wire::IDEMPOTENCY_KEY;
+
+struct Responses {
+    snapshots: HashMap<String, Arc<Snapshot>>,
+    // Keys in insertion order, for eviction.
+    order: VecDeque<String>,
+}
+
+/// Server middleware which replays the responses to retried requests.
+#[derive(Clone)]
+pub struct Idempotency {
+    responses: Arc<Mutex<Responses>>,
+    max_entries: usize,
+    replayed: Arc<AtomicU64>,
+    claims: Option<(Replays, Duration)>,
+}
+
+impl Idempotency {
+    /// Remember the responses to at most `max_entries` requests.
+    ///
+    /// When the limit is reached, the oldest responses are forgotten, so the limit should cover
+    /// the number of requests received in the longest period over which clients retry.
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            responses: Arc::new(Mutex::new(Responses {
+                snapshots: HashMap::new(),
+                order: VecDeque::new(),
+            })),
+            max_entries,
+            replayed: Default::default(),
+            claims: None,
+        }
+    }
+
+    /// Claim each key in `replays` for `ttl` before running the handler.
+    ///
+    /// Of several copies of a request which arrive before the first has a response, only the
+    /// first runs the handler, and the others fail with 409 Conflict. So does a copy which arrives
+    /// after the response has been forgotten, until the claim expires, and a copy which reaches
+    /// another replica sharing `replays`, which does not have the response. A claim is released
+    /// if the response is not remembered, so that the request can be retried.
+    pub fn claims(mut self, replays: Replays, ttl: Duration) -> Self {
+        self.claims = Some((replays, ttl));
+        self
+    }
+
+    /// The total number of requests which have been answered with a remembered response.
+    pub fn replayed(&self) -> u64 {
+        self.replayed.load(Ordering::Relaxed)
+    }
+
+    fn key<State>(req: &Request<State>) -> Option<String> {
+        if req.method() == Method::Get {
+            return None;
+        }
+        let key = req.header(IDEMPOTENCY_KEY)?;
+        Some(format!("{} {}\n{}", req.method(), req.url().path(), key))
+    }
+
+    fn insert(&self, key: String, snapshot: Arc<Snapshot>) {
+        let mut responses = self.responses.lock().unwrap();
+        if responses.snapshots.insert(key.clone(), snapshot).is_none() {
+            responses.order.push_back(key);
+        }
+        while responses.snapshots.len() > self.max_entries {
+            match responses.order.pop_front() {
+                Some(oldest) => {
+                    responses.snapshots.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Idempotency {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let key = match Self::key(&req) {
+            Some(key) => key,
+            None => return Ok(next.run(req).await),
+        };
+        let snapshot = self.responses.lock().unwrap().snapshots.get(&key).cloned();
+        if let Some(snapshot) = snapshot {
+            self.replayed.fetch_add(1, Ordering::Relaxed);
+            return Ok(snapshot.to_response());
+        }
+
+        if let Some((replays, ttl)) = &self.claims {
+            replays
+                .check_for(&key, *ttl)
+                .await
+                .map_err(|err| match err {
+                    ReplayError::Replayed => tide::Error::from_str(
+                        StatusCode::Conflict,
+                        "a request with this idempotency key has already been received",
+                    ),
+                    err => err.into(),
+                })?;
+        }
+
+        let mut res = next.run(req).await;
+        if res.status().is_server_error() {
+            if let Some((replays, _)) = &self.claims {
+                replays.release(&key).await?;
+            }
+            return Ok(res);
+        }
+        let snapshot = Arc::new(Snapshot::take(&mut res).await?);
+        self.insert(key, snapshot.clone());
+        Ok(snapshot.to_response())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use tide::http::{Request, Url};
+
+    async fn post(server: &tide::Server<()>, key: Option<&str>) -> tide::http::Response {
+        let mut req = Request::new(Method::Post, Url::parse("http://localhost/memo").unwrap());
+        if let Some(key) = key {
+            req.insert_header(IDEMPOTENCY_KEY, key);
+        }
+        server.respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_idempotency() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let idempotency = Idempotency::new(10);
+        let mut server = tide::new();
+        server.with(idempotency.clone());
+        server.at("/memo").post({
+            let calls = calls.clone();
+            move |_req: tide::Request<()>| {
+                let n = calls.fetch_add(1, Ordering::SeqCst);
+                async move { Ok(format!("memo {}", n)) }
+            }
+        });
+
+        // A retried request gets the original response without running the handler again.
+        let mut res = post(&server, Some("a")).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.body_string().await.unwrap(), "memo 0");
+        let mut res = post(&server, Some("a")).await;
+        assert_eq!(res.body_string().await.unwrap(), "memo 0");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(idempotency.replayed(), 1);
+
+        // Different keys, and requests without keys, are handled separately.
+        let mut res = post(&server, Some("b")).await;
+        assert_eq!(res.body_string().await.unwrap(), "memo 1");
+        let mut res = post(&server, None).await;
+        assert_eq!(res.body_string().await.unwrap(), "memo 2");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // With claims, a request which is still being handled isn't handled again.
+        let claims = Replays::in_memory();
+        let mut server = tide::new();
+        server.with(Idempotency::new(10).claims(claims.clone(), Duration::from_secs(60)));
+        server.at("/memo").post(|_| async {
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            Ok("memo")
+        });
+        let (first, second) = futures::join!(post(&server, Some("a")), post(&server, Some("a")));
+        let statuses = [first.status(), second.status()];
+        assert!(statuses.contains(&StatusCode::Ok));
+        assert!(statuses.contains(&StatusCode::Conflict));
+        assert_eq!(claims.stats().replayed, 1);
+    }
+}
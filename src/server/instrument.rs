@@ -0,0 +1,151 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Instrumentation of handler latency and response size.
+//!
+//! The [Instrument] middleware measures how long each request takes to handle and how large the
+//! serialized response body is. When either exceeds a configurable threshold, it emits a WARN
+//! event. Every measurement can also be reported to a [Metrics] sink.
+//!
+//! Events and metrics are labeled with the route template (e.g. `/block/:id`) rather than the raw
+//! URL, so that they can be aggregated. Routes must be registered with [Instrument::route] to be
+//! labeled; requests which do not match any registered route are labeled [UNMATCHED_ROUTE].
+//! Warnings also name the client (see [client_info](super::forwarded::client_info)), so a slow
+//! request can be traced back to whoever sent it; metrics do not, to keep their labels few.
+
+use super::forwarded::client_info;
+use super::route::RouteTable;
+use super::tenancy::tenant;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tide::{Middleware, Next, Request};
+use tracing::{event, Level};
+
+/// The label used for requests which do not match any registered route.
+pub const UNMATCHED_ROUTE: &str = "<unmatched>";
+
+/// A sink for request measurements.
+pub trait Metrics: Send + Sync {
+    /// Record a request to `route` which took `latency` to handle.
+    ///
+    /// `body_size` is the size of the response body, if it is known.
+    fn record(&self, route: &str, latency: Duration, body_size: Option<usize>);
+
+    /// Record a request for the tenant labeled `tenant` (see [Tenants](super::Tenants)).
+    ///
+    /// By default, this ignores the tenant and calls [record](Self::record).
+    fn record_tenant(
+        &self,
+        tenant: &str,
+        route: &str,
+        latency: Duration,
+        body_size: Option<usize>,
+    ) {
+        let _ = tenant;
+        self.record(route, latency, body_size);
+    }
+}
+
+/// Server middleware which warns about slow requests and large responses.
+#[derive(Clone)]
+pub struct Instrument {
+    slow_request: Duration,
+    large_response: usize,
+    routes: RouteTable<()>,
+    metrics: Option<Arc<dyn Metrics>>,
+}
+
+impl Default for Instrument {
+    fn default() -> Self {
+        Self {
+            slow_request: Duration::from_secs(1),
+            large_response: 10 << 20,
+            routes: RouteTable::new(),
+            metrics: None,
+        }
+    }
+}
+
+impl Instrument {
+    /// Instrumentation with default thresholds of 1 second and 10 MB.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Warn about requests which take longer than `threshold` to handle.
+    pub fn slow_request(mut self, threshold: Duration) -> Self {
+        self.slow_request = threshold;
+        self
+    }
+
+    /// Warn about responses with bodies larger than `threshold` bytes.
+    pub fn large_response(mut self, threshold: usize) -> Self {
+        self.large_response = threshold;
+        self
+    }
+
+    /// Register a route template to use as a label.
+    pub fn route(mut self, template: impl Into<String>) -> Self {
+        self.routes.insert(template, ());
+        self
+    }
+
+    /// Report every measurement to `metrics`.
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Instrument {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let route = self
+            .routes
+            .lookup(req.url().path())
+            .map(|(template, _)| template.as_str().to_string())
+            .unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+        let method = req.method();
+        let client = client_info(&req).addr;
+        let tenant = tenant(&req).map(|tenant| tenant.label.clone());
+
+        let start = Instant::now();
+        let res = next.run(req).await;
+        let latency = start.elapsed();
+        let body_size = res.len();
+
+        if latency > self.slow_request {
+            event!(
+                Level::WARN,
+                "slow request {{route: {}, method: {}, client: {:?}, latency: {:?}}}",
+                route,
+                method,
+                client,
+                latency
+            );
+        }
+        if let Some(size) = body_size {
+            if size > self.large_response {
+                event!(
+                    Level::WARN,
+                    "large response {{route: {}, method: {}, client: {:?}, size: {}}}",
+                    route,
+                    method,
+                    client,
+                    size
+                );
+            }
+        }
+        if let Some(metrics) = &self.metrics {
+            match &tenant {
+                Some(tenant) => metrics.record_tenant(tenant, &route, latency, body_size),
+                None => metrics.record(&route, latency, body_size),
+            }
+        }
+        Ok(res)
+    }
+}
@@ -0,0 +1,347 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Allowing or denying requests by client address.
+//!
+//! Permissioned APIs, like the admin routes of a validator, are often exposed on networks shared
+//! with other parties. The [ip_filter] middleware admits requests only from the addresses a
+//! [Policy] allows, and fails the others with 403 Forbidden:
+//!  * a request from an address in a denied range is rejected
+//!  * if the policy has any allowed ranges, a request from an address outside all of them is
+//!    rejected
+//!  * any other request is admitted
+//!
+//! The client address is the address of the peer, unless the peer is a trusted proxy, in which
+//! case it is taken from the forwarding headers: the addresses in the headers are read from right
+//! to left, skipping trusted proxies, and the first untrusted address is the client. Entries added
+//! by untrusted hops are never consulted, so a client cannot spoof its address by sending the
+//! headers itself. Trusted proxies are configured with [Policy::trust_proxies] or, for the whole
+//! server, with [ProxyHeaders](super::forwarded::ProxyHeaders); see [forwarded](super::forwarded).
+//!
+//! ```
+//! use net::server::forwarded::{ForwardingHeader, TrustedProxies};
+//! use net::server::ip_filter::{ip_filter, Policy};
+//!
+//! let policy = Policy::new()
+//!     .allow("10.0.0.0/8".parse().unwrap())
+//!     .deny("10.0.13.0/24".parse().unwrap())
+//!     .trust_proxies(
+//!         TrustedProxies::new(ForwardingHeader::XForwardedFor).trust("10.0.0.1".parse().unwrap()),
+//!     );
+//! let mut app = tide::new();
+//! app.at("/admin").with(ip_filter(policy)).get(|_| async { Ok("ok") });
+//! ```
+
+use super::forwarded::{client_info, resolve, TrustedProxies};
+use std::fmt::{self, Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+/// A range of IP addresses, written as `address/prefix-length`.
+///
+/// A bare address is a range containing only that address. IPv4 ranges also contain the
+/// IPv4-mapped IPv6 forms of their addresses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// The range of addresses whose first `prefix` bits are the same as those of `addr`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `prefix` is longer than `addr`.
+    pub fn new(addr: IpAddr, prefix: u8) -> Self {
+        let bits = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        assert!(prefix <= bits, "prefix length must be at most {}", bits);
+        let network = match addr {
+            IpAddr::V4(addr) => IpAddr::V4((u32::from(addr) & mask(prefix, 32) as u32).into()),
+            IpAddr::V6(addr) => IpAddr::V6((u128::from(addr) & mask(prefix, 128)).into()),
+        };
+        Self { network, prefix }
+    }
+
+    /// Whether `addr` is in this range.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            addr => addr,
+        };
+        match (self.network, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                u32::from(addr) & mask(self.prefix, 32) as u32 == u32::from(network)
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                u128::from(addr) & mask(self.prefix, 128) == u128::from(network)
+            }
+            _ => false,
+        }
+    }
+}
+
+// The mask selecting the first `prefix` bits of a `bits`-bit address.
+fn mask(prefix: u8, bits: u32) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        (u128::MAX << (128 - prefix as u32)) >> (128 - bits)
+    }
+}
+
+impl From<IpAddr> for Cidr {
+    fn from(addr: IpAddr) -> Self {
+        match addr {
+            IpAddr::V4(_) => Self::new(addr, 32),
+            IpAddr::V6(_) => Self::new(addr, 128),
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|err| format!("invalid address {:?}: {}", addr, err))?;
+        let cidr = Self::from(addr);
+        match prefix {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= cidr.prefix => Ok(Self::new(addr, prefix)),
+                _ => Err(format!("invalid prefix length {:?}", prefix)),
+            },
+            None => Ok(cidr),
+        }
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Which client addresses [ip_filter] admits.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Policy {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    trusted_proxies: Option<TrustedProxies>,
+}
+
+impl Policy {
+    /// A policy which admits every request.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Admit requests from `range`.
+    ///
+    /// Once a range is allowed, requests from addresses outside every allowed range are rejected.
+    pub fn allow(mut self, range: Cidr) -> Self {
+        self.allow.push(range);
+        self
+    }
+
+    /// Reject requests from `range`, even if it is also allowed.
+    pub fn deny(mut self, range: Cidr) -> Self {
+        self.deny.push(range);
+        self
+    }
+
+    /// Believe the forwarding headers of requests from `proxies`.
+    ///
+    /// If no proxies are trusted, the client is the one determined by
+    /// [ProxyHeaders](super::forwarded::ProxyHeaders), if it is installed.
+    pub fn trust_proxies(mut self, proxies: TrustedProxies) -> Self {
+        self.trusted_proxies = Some(proxies);
+        self
+    }
+
+    /// Whether requests from `addr` are admitted.
+    pub fn admits(&self, addr: IpAddr) -> bool {
+        !self.deny.iter().any(|range| range.contains(addr))
+            && (self.allow.is_empty() || self.allow.iter().any(|range| range.contains(addr)))
+    }
+
+    // The address of the client which sent `req`, or [None] if it cannot be determined.
+    fn client_addr<State>(&self, req: &Request<State>) -> Option<IpAddr> {
+        match &self.trusted_proxies {
+            Some(proxies) => resolve(req, proxies).addr,
+            None => client_info(req).addr,
+        }
+    }
+}
+
+/// Server middleware which admits only requests from the addresses `policy` allows.
+///
+/// See the [module documentation](self).
+pub fn ip_filter(policy: Policy) -> IpFilter {
+    IpFilter {
+        policy: Arc::new(policy),
+    }
+}
+
+/// Server middleware which filters requests by client address, created by [ip_filter].
+#[derive(Clone, Debug)]
+pub struct IpFilter {
+    policy: Arc<Policy>,
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for IpFilter {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let reason = match self.policy.client_addr(&req) {
+            Some(addr) if self.policy.admits(addr) => return Ok(next.run(req).await),
+            Some(addr) => format!("requests from {} are not allowed", addr),
+            // If we don't know who the client is, we can only admit it if the policy admits
+            // everyone who isn't denied.
+            None if self.policy.allow.is_empty() => return Ok(next.run(req).await),
+            None => "could not determine the client address".to_string(),
+        };
+        let mut res = Response::new(StatusCode::Forbidden);
+        res.set_error(tide::Error::from_str(StatusCode::Forbidden, reason));
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::forwarded::ForwardingHeader;
+    use tide::http::{Method, Url};
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let range = cidr("192.168.1.77/24");
+        assert_eq!(range.to_string(), "192.168.1.0/24");
+        assert!(range.contains(ip("192.168.1.1")));
+        assert!(range.contains(ip("::ffff:192.168.1.200")));
+        assert!(!range.contains(ip("192.168.2.1")));
+        assert!(cidr("0.0.0.0/0").contains(ip("8.8.8.8")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+
+        let range = cidr("2001:db8::/32");
+        assert!(range.contains(ip("2001:db8:ffff::1")));
+        assert!(!range.contains(ip("2001:db9::1")));
+        assert_eq!(cidr("::1"), Cidr::new(ip("::1"), 128));
+
+        for invalid in ["10.0.0.0/33", "10.0.0.0/", "10.0.0/8", "::/129", "host"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+    }
+
+    async fn status(
+        policy: Policy,
+        peer: Option<&'static str>,
+        forwarded_for: Option<&'static str>,
+    ) -> StatusCode {
+        let mut app = tide::new();
+        app.at("/admin")
+            .with(ip_filter(policy))
+            .get(|_| async { Ok("ok") });
+        let mut req =
+            tide::http::Request::new(Method::Get, Url::parse("http://localhost/admin").unwrap());
+        req.set_peer_addr(peer);
+        if let Some(forwarded_for) = forwarded_for {
+            req.insert_header("X-Forwarded-For", forwarded_for);
+        }
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        res.status()
+    }
+
+    #[async_std::test]
+    async fn test_ip_filter() {
+        let policy = Policy::new()
+            .allow(cidr("10.0.0.0/8"))
+            .deny(cidr("10.0.13.0/24"));
+        let check = |peer: &'static str| status(policy.clone(), Some(peer), None);
+        assert_eq!(check("10.1.2.3:4000").await, StatusCode::Ok);
+        assert_eq!(check("10.0.13.7:4000").await, StatusCode::Forbidden);
+        assert_eq!(check("192.168.0.1:4000").await, StatusCode::Forbidden);
+        assert_eq!(status(policy, None, None).await, StatusCode::Forbidden);
+
+        // A denylist on its own admits everyone else.
+        let policy = Policy::new().deny(cidr("192.168.0.0/16"));
+        assert_eq!(
+            status(policy.clone(), Some("192.168.0.1:1"), None).await,
+            StatusCode::Forbidden
+        );
+        assert_eq!(
+            status(policy.clone(), Some("[2001:db8::1]:1"), None).await,
+            StatusCode::Ok
+        );
+        assert_eq!(status(policy, None, None).await, StatusCode::Ok);
+    }
+
+    #[async_std::test]
+    async fn test_forwarded_for() {
+        let policy = Policy::new().allow(cidr("10.0.0.0/8")).trust_proxies(
+            TrustedProxies::new(ForwardingHeader::XForwardedFor).trust(cidr("172.16.0.0/12")),
+        );
+        let check = |peer: &'static str, forwarded_for: &'static str| {
+            status(policy.clone(), Some(peer), Some(forwarded_for))
+        };
+
+        // The header is used when the peer is a trusted proxy, skipping other trusted proxies.
+        assert_eq!(check("172.16.0.1:1", "10.0.0.5").await, StatusCode::Ok);
+        assert_eq!(
+            check("172.16.0.1:1", "10.0.0.5, 172.16.0.2").await,
+            StatusCode::Ok
+        );
+        assert_eq!(
+            check("172.16.0.1:1", "10.0.0.5, 8.8.8.8").await,
+            StatusCode::Forbidden
+        );
+
+        // Entries added before the first untrusted hop are ignored, so clients can't spoof them.
+        assert_eq!(
+            check("172.16.0.1:1", "10.0.0.5, 8.8.8.8, 172.16.0.2").await,
+            StatusCode::Forbidden
+        );
+
+        // The header is ignored when the peer is not a trusted proxy.
+        assert_eq!(check("8.8.8.8:1", "10.0.0.5").await, StatusCode::Forbidden);
+
+        // A Forwarded header is not believed when proxies set X-Forwarded-For.
+        let mut app = tide::new();
+        app.at("/admin")
+            .with(ip_filter(policy.clone()))
+            .get(|_| async { Ok("ok") });
+        let mut req =
+            tide::http::Request::new(Method::Get, Url::parse("http://localhost/admin").unwrap());
+        req.set_peer_addr(Some("172.16.0.1:1"));
+        req.insert_header("Forwarded", "for=10.0.0.5");
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Forbidden);
+
+        // A malformed header means we don't know who the client is.
+        assert_eq!(
+            check("172.16.0.1:1", "garbage").await,
+            StatusCode::Forbidden
+        );
+    }
+}
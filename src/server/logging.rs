@@ -0,0 +1,281 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Configurable request and response logging.
+//!
+//! [Logger] is a middleware which logs each request and response, like [trace](super::trace), but
+//! can be configured to:
+//!  * log at different levels for different routes
+//!  * log headers and capture bodies up to a size limit
+//!  * redact sensitive headers and JSON fields before logging
+//!
+//! Requests are logged with their client address, as determined by
+//! [ProxyHeaders](super::forwarded::ProxyHeaders) if it is installed.
+//!
+//! Redaction is applied to everything that is logged. Binary bodies cannot be redacted, so they are
+//! never logged, only their size.
+
+use super::forwarded::client_info;
+use serde_json::Value;
+use std::collections::HashSet;
+use tide::http::{headers::Headers, mime, Mime};
+use tide::{Body, Middleware, Next, Request};
+use tracing::{event, Level};
+
+const REDACTED: &str = "<redacted>";
+
+// Emit an event at a level which is not known at compile time.
+macro_rules! event_at {
+    ($level:expr, $($arg:tt)+) => {
+        match $level {
+            Level::ERROR => event!(Level::ERROR, $($arg)+),
+            Level::WARN => event!(Level::WARN, $($arg)+),
+            Level::INFO => event!(Level::INFO, $($arg)+),
+            Level::DEBUG => event!(Level::DEBUG, $($arg)+),
+            Level::TRACE => event!(Level::TRACE, $($arg)+),
+        }
+    };
+}
+
+/// Server middleware which logs requests and responses.
+///
+/// The default configuration logs exactly what [trace](super::trace) logs, at level INFO. Headers
+/// in the redaction list are `Authorization`, `Cookie`, and `Set-Cookie`; fields in the redaction
+/// list are `signature`, `memo`, and `memos`.
+#[derive(Clone, Debug)]
+pub struct Logger {
+    level: Level,
+    route_levels: Vec<(String, Level)>,
+    log_headers: bool,
+    max_body_size: usize,
+    redacted_headers: HashSet<String>,
+    redacted_fields: HashSet<String>,
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Self {
+            level: Level::INFO,
+            route_levels: Vec::new(),
+            log_headers: false,
+            max_body_size: 0,
+            redacted_headers: ["authorization", "cookie", "set-cookie"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            redacted_fields: ["signature", "memo", "memos"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl Logger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The level at which to log routes which are not configured with [route_level](Self::route_level).
+    pub fn level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// The level at which to log requests whose path starts with `prefix`.
+    ///
+    /// If several prefixes match a path, the longest one wins.
+    pub fn route_level(mut self, prefix: impl Into<String>, level: Level) -> Self {
+        self.route_levels.push((prefix.into(), level));
+        self
+    }
+
+    /// Log request and response headers.
+    pub fn log_headers(mut self, log_headers: bool) -> Self {
+        self.log_headers = log_headers;
+        self
+    }
+
+    /// Capture and log request and response bodies of at most `max_body_size` bytes.
+    ///
+    /// Bodies whose length is not known in advance (such as streaming bodies) are never captured.
+    /// The default is 0, which disables body capture.
+    pub fn capture_bodies(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Redact the header `name` (case-insensitive) when logging headers.
+    pub fn redact_header(mut self, name: impl AsRef<str>) -> Self {
+        self.redacted_headers.insert(name.as_ref().to_lowercase());
+        self
+    }
+
+    /// Redact fields named `name`, at any depth, when logging JSON bodies.
+    pub fn redact_field(mut self, name: impl Into<String>) -> Self {
+        self.redacted_fields.insert(name.into());
+        self
+    }
+
+    fn level_for(&self, path: &str) -> Level {
+        self.route_levels
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.level)
+    }
+
+    fn headers(&self, headers: &Headers) -> String {
+        if !self.log_headers {
+            return String::new();
+        }
+        let headers = headers
+            .iter()
+            .map(|(name, values)| {
+                if self
+                    .redacted_headers
+                    .contains(&name.as_str().to_lowercase())
+                {
+                    format!("{}: {}", name, REDACTED)
+                } else {
+                    format!("{}: {}", name, values)
+                }
+            })
+            .collect::<Vec<_>>();
+        format!(", headers: {{{}}}", headers.join(", "))
+    }
+
+    /// Capture a body for logging, returning the body (which must be put back in the message) and
+    /// a description of it to log.
+    async fn capture(&self, body: Body, content_type: Option<Mime>) -> (Body, String) {
+        if self.max_body_size == 0 {
+            return (body, String::new());
+        }
+        let len = match body.len() {
+            Some(len) if len <= self.max_body_size => len,
+            Some(len) => return (body, format!(", body: <{} bytes, not captured>", len)),
+            None => return (body, ", body: <streaming, not captured>".to_string()),
+        };
+        let bytes = match body.into_bytes().await {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                return (
+                    Body::empty(),
+                    format!(", body: <failed to read body: {}>", err),
+                )
+            }
+        };
+        let logged = match content_type {
+            Some(ty) if ty.essence() == mime::JSON.essence() => {
+                match serde_json::from_slice::<Value>(&bytes) {
+                    Ok(mut json) => {
+                        self.redact(&mut json);
+                        json.to_string()
+                    }
+                    Err(_) => format!("<{} bytes of invalid JSON>", len),
+                }
+            }
+            Some(ty) if ty.basetype() == "text" => String::from_utf8_lossy(&bytes).into_owned(),
+            _ => format!("<{} bytes, binary>", len),
+        };
+        (Body::from(bytes), format!(", body: {}", logged))
+    }
+
+    fn redact(&self, json: &mut Value) {
+        match json {
+            Value::Object(fields) => {
+                for (name, value) in fields.iter_mut() {
+                    if self.redacted_fields.contains(name) {
+                        *value = Value::String(REDACTED.to_string());
+                    } else {
+                        self.redact(value);
+                    }
+                }
+            }
+            Value::Array(values) => {
+                for value in values {
+                    self.redact(value);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Logger {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let level = self.level_for(req.url().path());
+        let headers = self.headers(req.as_ref());
+        let (body, logged_body) = self.capture(req.take_body(), req.content_type()).await;
+        if !body.is_empty().unwrap_or(false) {
+            req.set_body(body);
+        }
+        event_at!(
+            level,
+            "<-- received request {{url: {}, client: {:?}, content-type: {:?}, accept: {:?}{}{}}}",
+            req.url(),
+            client_info(&req).addr,
+            req.content_type(),
+            tide::http::content::Accept::from_headers(&req),
+            headers,
+            logged_body,
+        );
+
+        let mut res = next.run(req).await;
+        let headers = self.headers(res.as_ref());
+        let (body, logged_body) = self.capture(res.take_body(), res.content_type()).await;
+        if !body.is_empty().unwrap_or(false) {
+            res.set_body(body);
+        }
+        event_at!(
+            level,
+            "--> responding with {{content-type: {:?}, error: {:?}{}{}}}",
+            res.content_type(),
+            res.error(),
+            headers,
+            logged_body,
+        );
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_redact() {
+        let logger = Logger::new().redact_field("secret");
+        let mut body = json!({
+            "public": 1,
+            "secret": "key material",
+            "nested": [{"signature": "sig", "public": 2}],
+        });
+        logger.redact(&mut body);
+        assert_eq!(
+            body,
+            json!({
+                "public": 1,
+                "secret": REDACTED,
+                "nested": [{"signature": REDACTED, "public": 2}],
+            })
+        );
+    }
+
+    #[test]
+    fn test_route_level() {
+        let logger = Logger::new()
+            .route_level("/block", Level::DEBUG)
+            .route_level("/block/latest", Level::TRACE);
+        assert_eq!(logger.level_for("/status"), Level::INFO);
+        assert_eq!(logger.level_for("/block/5"), Level::DEBUG);
+        assert_eq!(logger.level_for("/block/latest"), Level::TRACE);
+    }
+}
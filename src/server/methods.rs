@@ -0,0 +1,355 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! HEAD and OPTIONS requests, answered for every route without handlers of their own.
+//!
+//! Tide routes a HEAD request to the GET handler of its route, but the response it gets still has
+//! a body, and OPTIONS requests fail with 405 Method Not Allowed. With the [auto_methods]
+//! middleware:
+//!  * a HEAD request gets the response of the GET handler, with the same headers and the same
+//!    length, but no body. If the GET handler streams its body, the length is not known without
+//!    producing the body, so the HEAD response has no Content-Length.
+//!  * an OPTIONS request for a declared route gets 204 No Content, with an Allow header listing the
+//!    methods of the route
+//!  * the router's 405 Method Not Allowed for a declared route gets the same Allow header
+//!
+//! ```
+//! # use net::server::AutoMethods;
+//! # use tide::http::Method;
+//! let mut app = tide::new();
+//! app.with(AutoMethods::new().route("/block/:id", [Method::Get]));
+//! app.at("/block/:id").get(|_| async { Ok("") });
+//! ```
+//!
+//! Tide does not say which methods a route has, so OPTIONS requests are only answered for routes
+//! whose methods are declared with [AutoMethods::route]. Requests for other routes get the
+//! router's 405 Method Not Allowed, rather than an Allow header which might be wrong. A service
+//! whose routes are all GET routes can declare them all at once with the template `/*`, and
+//! declare the exceptions with more specific templates. [AutoMethods] can also answer CORS
+//! preflight requests, and add CORS headers to other responses, for the origins it is configured
+//! to allow:
+//!
+//! ```
+//! # use net::server::AutoMethods;
+//! # use tide::http::Method;
+//! let mut app = tide::new();
+//! app.with(
+//!     AutoMethods::new()
+//!         .route("/submit", [Method::Post])
+//!         .allow_origin("https://explorer.espressosys.com"),
+//! );
+//! # app.at("/submit").post(|_| async { Ok("") });
+//! ```
+//!
+//! Routes which register their own HEAD or OPTIONS handlers are not affected.
+
+use super::error_body::is_unrouted;
+use super::route::RouteTable;
+use futures::future::BoxFuture;
+use tide::http::{Body, Method};
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+/// How long clients may cache the result of a CORS preflight request, in seconds.
+pub const PREFLIGHT_MAX_AGE: u64 = 600;
+
+/// Server middleware which answers HEAD and OPTIONS requests.
+///
+/// This is equivalent to the default configuration of [AutoMethods], which answers HEAD requests
+/// only. [AutoMethods] can be used to declare the methods of routes, so that OPTIONS requests are
+/// answered, and to allow cross-origin requests.
+pub fn auto_methods<'a, State: Clone + Send + Sync + 'static>(
+    req: Request<State>,
+    next: Next<'a, State>,
+) -> BoxFuture<'a, tide::Result> {
+    Box::pin(async { AutoMethods::default().handle(req, next).await })
+}
+
+/// Configurable server middleware which answers HEAD and OPTIONS requests.
+#[derive(Clone, Debug, Default)]
+pub struct AutoMethods {
+    routes: RouteTable<Vec<Method>>,
+    origins: Vec<String>,
+}
+
+impl AutoMethods {
+    /// Declare no routes, and allow no cross-origin requests.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare the methods of routes matching `template`.
+    ///
+    /// HEAD is allowed if `methods` includes GET, and OPTIONS is always allowed.
+    pub fn route(
+        mut self,
+        template: impl Into<String>,
+        methods: impl IntoIterator<Item = Method>,
+    ) -> Self {
+        self.routes.insert(template, methods.into_iter().collect());
+        self
+    }
+
+    /// Allow cross-origin requests from `origin`, or from any origin if `origin` is `*`.
+    pub fn allow_origin(mut self, origin: impl Into<String>) -> Self {
+        self.origins.push(origin.into());
+        self
+    }
+
+    /// The methods allowed on `path`, as a header value, if they have been declared.
+    fn allowed(&self, path: &str) -> Option<String> {
+        let (_, methods) = self.routes.lookup(path)?;
+        let mut methods = methods.clone();
+        if methods.contains(&Method::Get) && !methods.contains(&Method::Head) {
+            methods.push(Method::Head);
+        }
+        if !methods.contains(&Method::Options) {
+            methods.push(Method::Options);
+        }
+        Some(
+            methods
+                .iter()
+                .map(|method| method.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        )
+    }
+
+    /// The Access-Control-Allow-Origin for a request from `origin`, if it is allowed.
+    fn allow_origin_for(&self, origin: &str) -> Option<String> {
+        if self.origins.iter().any(|allowed| allowed == "*") {
+            Some("*".into())
+        } else if self.origins.iter().any(|allowed| allowed == origin) {
+            Some(origin.into())
+        } else {
+            None
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AutoMethods {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let path = req.url().path().to_string();
+        let allow_origin = req
+            .header("Origin")
+            .and_then(|origin| self.allow_origin_for(origin.last().as_str()));
+        let request_headers = req
+            .header("Access-Control-Request-Headers")
+            .map(|headers| headers.last().as_str().to_string());
+
+        let mut res = match req.method() {
+            Method::Head => without_body(next.run(req).await),
+            Method::Options => {
+                let res = next.run(req).await;
+                // The router answers 405 for a route which exists but has no OPTIONS handler. Any
+                // other response either comes from a handler or means there is no such route.
+                if res.status() != StatusCode::MethodNotAllowed {
+                    return Ok(res);
+                }
+                let allowed = match self.allowed(&path) {
+                    Some(allowed) => allowed,
+                    None => return Ok(res),
+                };
+                let mut res = Response::new(StatusCode::NoContent);
+                if allow_origin.is_some() {
+                    res.insert_header("Access-Control-Allow-Methods", &allowed);
+                    if let Some(headers) = request_headers {
+                        res.insert_header("Access-Control-Allow-Headers", headers);
+                    }
+                    res.insert_header("Access-Control-Max-Age", PREFLIGHT_MAX_AGE.to_string());
+                }
+                res.insert_header("Allow", allowed);
+                res
+            }
+            _ => next.run(req).await,
+        };
+        if res.status() == StatusCode::MethodNotAllowed
+            && res.header("Allow").is_none()
+            && is_unrouted(&res)
+        {
+            if let Some(allowed) = self.allowed(&path) {
+                res.insert_header("Allow", allowed);
+            }
+        }
+        if let Some(allow_origin) = allow_origin {
+            if allow_origin != "*" {
+                res.append_header("Vary", "Origin");
+            }
+            res.insert_header("Access-Control-Allow-Origin", allow_origin);
+        }
+        Ok(res)
+    }
+}
+
+/// Replace the body of `res` with an empty body of the same length and type.
+///
+/// The length of the response is the length of the original body, so the server still sends the
+/// right Content-Length, but it has nothing to send after the headers. The length of a streaming
+/// body is only known once it has all been produced, which a HEAD request should not cost, so it is
+/// left unknown, and the response has no Content-Length.
+fn without_body(mut res: Response) -> Response {
+    let body = res.take_body();
+    let mut empty = Body::from_reader(futures::io::empty(), body.len());
+    empty.set_mime(body.mime().clone());
+    res.set_body(empty);
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tide::http::{mime, Url};
+
+    async fn request(
+        app: &tide::Server<()>,
+        method: Method,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> tide::http::Response {
+        let mut req = tide::http::Request::new(
+            method,
+            Url::parse("http://localhost").unwrap().join(path).unwrap(),
+        );
+        for (name, value) in headers {
+            req.insert_header(*name, *value);
+        }
+        app.respond(req).await.unwrap()
+    }
+
+    fn server(middleware: AutoMethods) -> tide::Server<()> {
+        let mut app = tide::new();
+        app.with(middleware);
+        app.at("/block/:id").get(|_| async {
+            let mut res = Response::new(StatusCode::Ok);
+            res.set_body(Body::from_json(&[1u64, 2, 3])?);
+            res.insert_header("Cache-Control", "max-age=60");
+            Ok(res)
+        });
+        app.at("/submit").post(|_| async { Ok("") });
+        app.at("/stream").get(|_| async {
+            let body = futures::io::Cursor::new(b"streamed".to_vec());
+            Ok(Response::builder(StatusCode::Ok)
+                .body(Body::from_reader(body, None))
+                .build())
+        });
+        app
+    }
+
+    #[async_std::test]
+    async fn test_head() {
+        let app = server(AutoMethods::new());
+
+        let mut get = request(&app, Method::Get, "/block/1", &[]).await;
+        let mut head = request(&app, Method::Head, "/block/1", &[]).await;
+        assert_eq!(head.status(), StatusCode::Ok);
+        assert_eq!(head.content_type(), Some(mime::JSON));
+        assert_eq!(head["Cache-Control"], get["Cache-Control"]);
+        assert_eq!(head.len(), get.len());
+        assert_eq!(get.body_string().await.unwrap(), "[1,2,3]");
+        assert_eq!(head.body_string().await.unwrap(), "");
+
+        // A streamed body is not produced just to measure it.
+        let head = request(&app, Method::Head, "/stream", &[]).await;
+        assert_eq!(head.status(), StatusCode::Ok);
+        assert_eq!(head.len(), None);
+
+        // Routes without a GET handler still reject HEAD requests.
+        let res = request(&app, Method::Head, "/submit", &[]).await;
+        assert_eq!(res.status(), StatusCode::MethodNotAllowed);
+    }
+
+    #[async_std::test]
+    async fn test_options() {
+        let app = server(
+            AutoMethods::new()
+                .route("/*", [Method::Get])
+                .route("/submit", [Method::Post]),
+        );
+
+        let res = request(&app, Method::Options, "/block/1", &[]).await;
+        assert_eq!(res.status(), StatusCode::NoContent);
+        assert_eq!(res["Allow"], "GET, HEAD, OPTIONS");
+        let res = request(&app, Method::Options, "/submit", &[]).await;
+        assert_eq!(res["Allow"], "POST, OPTIONS");
+        let res = request(&app, Method::Options, "/nothing", &[]).await;
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        // Routes whose methods are not declared are not answered.
+        let app = server(AutoMethods::new().route("/block/:id", [Method::Get]));
+        let res = request(&app, Method::Options, "/submit", &[]).await;
+        assert_eq!(res.status(), StatusCode::MethodNotAllowed);
+        assert!(res.header("Allow").is_none());
+
+        // The router's 405s get an Allow header for declared routes.
+        let res = request(&app, Method::Post, "/block/1", &[]).await;
+        assert_eq!(res.status(), StatusCode::MethodNotAllowed);
+        assert_eq!(res["Allow"], "GET, HEAD, OPTIONS");
+
+        // Without any allowed origins, there are no CORS headers.
+        let res = request(
+            &app,
+            Method::Options,
+            "/block/1",
+            &[("Origin", "https://app.example")],
+        )
+        .await;
+        assert!(res.header("Access-Control-Allow-Origin").is_none());
+    }
+
+    #[async_std::test]
+    async fn test_cors() {
+        let app = server(
+            AutoMethods::new()
+                .route("/block/:id", [Method::Get])
+                .allow_origin("https://app.example"),
+        );
+        let preflight = |origin| {
+            request(
+                &app,
+                Method::Options,
+                "/block/1",
+                &[
+                    ("Origin", origin),
+                    ("Access-Control-Request-Method", "GET"),
+                    ("Access-Control-Request-Headers", "Accept"),
+                ],
+            )
+        };
+
+        let res = preflight("https://app.example").await;
+        assert_eq!(res.status(), StatusCode::NoContent);
+        assert_eq!(res["Access-Control-Allow-Origin"], "https://app.example");
+        assert_eq!(res["Access-Control-Allow-Methods"], "GET, HEAD, OPTIONS");
+        assert_eq!(res["Access-Control-Allow-Headers"], "Accept");
+        assert_eq!(res["Vary"], "Origin");
+
+        let res = preflight("https://evil.example").await;
+        assert!(res.header("Access-Control-Allow-Origin").is_none());
+        assert!(res.header("Access-Control-Allow-Methods").is_none());
+
+        // Other requests from allowed origins get the origin header too.
+        let res = request(
+            &app,
+            Method::Get,
+            "/block/1",
+            &[("Origin", "https://app.example")],
+        )
+        .await;
+        assert_eq!(res["Access-Control-Allow-Origin"], "https://app.example");
+
+        let app = server(AutoMethods::new().allow_origin("*"));
+        let res = request(
+            &app,
+            Method::Get,
+            "/block/1",
+            &[("Origin", "https://any.example")],
+        )
+        .await;
+        assert_eq!(res["Access-Control-Allow-Origin"], "*");
+        assert!(res.header("Vary").is_none());
+    }
+}
@@ -0,0 +1,473 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Negotiating the response type once per request.
+//!
+//! [response](super::response) parses the Accept header every time it is called. With the
+//! [negotiate] middleware, the Accept header is parsed once, before the handler runs, and the
+//! chosen type is stored in the request as a [ResponseType]. Handlers then serialize their
+//! responses with [respond], passing `req.ext()`:
+//!
+//! ```
+//! # use net::server::{negotiate, respond};
+//! let mut app = tide::new();
+//! app.with(negotiate);
+//! app.at("/height").get(|req: tide::Request<()>| async move { respond(req.ext(), 42u64) });
+//! ```
+//!
+//! Requests whose Accept header cannot be satisfied fail with 406 Not Acceptable before reaching
+//! the handler. If JSON is chosen for a request which asks for pretty-printing (see
+//! [PRETTY_PARAM](super::PRETTY_PARAM)), the type is [pretty_json](super::pretty_json), so that
+//! [respond] pretty-prints it.
+//!
+//! By default, JSON is preferred when the client accepts either type equally. [Negotiator] can
+//! change the order of preference, for all routes or for particular routes; for instance, to prefer
+//! binary for endpoints with large responses:
+//!
+//! ```
+//! # use net::server::Negotiator;
+//! # use tide::http::mime;
+//! let mut app = tide::new();
+//! app.with(Negotiator::new().route("/block/:id", [mime::BYTE_STREAM, mime::JSON]));
+//! # app.at("/block/:id").get(|_| async { Ok("") });
+//! ```
+//!
+//! Requests without an Accept header get the most preferred type for their route. The type they
+//! get can also be set separately from the order of preference, globally with
+//! [Negotiator::default_type] or for particular routes with [Negotiator::route_default]; for
+//! instance, to send proofs to clients which don't say what they accept in binary, while still
+//! preferring JSON when a client accepts both:
+//!
+//! ```
+//! # use net::server::Negotiator;
+//! # use tide::http::mime;
+//! let mut app = tide::new();
+//! app.with(Negotiator::new().route_default("/proof/*", mime::BYTE_STREAM));
+//! # app.at("/proof/*").get(|_| async { Ok("") });
+//! ```
+//!
+//! The chosen type is used for everything sent in response to a request which passed through the
+//! negotiator, not only by [respond]: [response](super::response),
+//! [response_tabular](super::response_tabular) and [BodyCache](super::BodyCache) use it too, and so
+//! do error bodies (see [ErrorBody](super::ErrorBody)), which find it in the extensions of the
+//! response.
+
+use super::route::RouteTable;
+use super::{best_response_type, respond_with, tabular_response, with_pretty, RESPONSE_TYPES};
+use crate::redact::SafeToServe;
+use crate::wire::csv::{self, TabularSerialize};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use tide::http::{content::Accept, mime, Mime};
+use tide::{Middleware, Next, Request, Response};
+
+/// The response type chosen for a request by [negotiate].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResponseType(pub Mime);
+
+/// Server middleware which chooses the response type for each request.
+///
+/// The chosen type is added to the request extensions, and to the extensions of the response, as a
+/// [ResponseType].
+///
+/// This is equivalent to the default configuration of [Negotiator], which can be used to change
+/// the preferred response types.
+pub fn negotiate<'a, State: Clone + Send + Sync + 'static>(
+    req: Request<State>,
+    next: Next<'a, State>,
+) -> BoxFuture<'a, tide::Result> {
+    Box::pin(async { Negotiator::default().handle(req, next).await })
+}
+
+/// Configurable server middleware which chooses the response type for each request.
+#[derive(Clone, Debug)]
+pub struct Negotiator {
+    types: Vec<Mime>,
+    routes: RouteTable<Vec<Mime>>,
+    default: Option<Mime>,
+    route_defaults: RouteTable<Mime>,
+}
+
+impl Default for Negotiator {
+    fn default() -> Self {
+        Self {
+            types: RESPONSE_TYPES.to_vec(),
+            routes: RouteTable::new(),
+            default: None,
+            route_defaults: RouteTable::new(),
+        }
+    }
+}
+
+impl Negotiator {
+    /// Prefer JSON, then binary, on all routes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Offer `types`, in order of preference, on routes without their own preferences.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `types` is empty or contains a type other than `application/json`,
+    /// `application/octet-stream` and `text/csv`. CSV should only be offered on routes which respond
+    /// with [respond_tabular].
+    pub fn prefer(mut self, types: impl IntoIterator<Item = Mime>) -> Self {
+        self.types = checked_types(types);
+        self
+    }
+
+    /// Offer `types`, in order of preference, on routes matching `template`.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as [prefer](Self::prefer).
+    pub fn route(
+        mut self,
+        template: impl Into<String>,
+        types: impl IntoIterator<Item = Mime>,
+    ) -> Self {
+        self.routes.insert(template, checked_types(types));
+        self
+    }
+
+    /// Respond with `ty` to requests without an Accept header, except on routes with a default.
+    ///
+    /// A route which does not offer `ty` uses its most preferred type instead.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as [prefer](Self::prefer).
+    pub fn default_type(mut self, ty: Mime) -> Self {
+        self.default = checked_types([ty]).pop();
+        self
+    }
+
+    /// Respond with `ty` to requests without an Accept header on routes matching `template`.
+    ///
+    /// If the route does not offer `ty`, its most preferred type is used instead.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as [prefer](Self::prefer).
+    pub fn route_default(mut self, template: impl Into<String>, ty: Mime) -> Self {
+        let ty = checked_types([ty]).pop().unwrap();
+        self.route_defaults.insert(template, ty);
+        self
+    }
+
+    fn types(&self, path: &str) -> &[Mime] {
+        self.routes
+            .lookup(path)
+            .map(|(_, types)| types.as_slice())
+            .unwrap_or(&self.types)
+    }
+
+    // The type for requests to `path` without an Accept header, if it is not the most preferred.
+    fn default_for(&self, path: &str) -> Option<&Mime> {
+        self.route_defaults
+            .lookup(path)
+            .map(|(_, ty)| ty)
+            .or(self.default.as_ref())
+    }
+}
+
+fn checked_types(types: impl IntoIterator<Item = Mime>) -> Vec<Mime> {
+    let types = types.into_iter().collect::<Vec<_>>();
+    assert!(!types.is_empty(), "at least one response type is required");
+    for ty in &types {
+        assert!(
+            RESPONSE_TYPES.contains(ty) || *ty == csv::mime(),
+            "unsupported response type {}",
+            ty
+        );
+    }
+    types
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Negotiator {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let path = req.url().path();
+        let available = self.types(path);
+        let default = self
+            .default_for(path)
+            .filter(|ty| available.contains(ty))
+            .cloned();
+        let ty = match (Accept::from_headers(&req), default) {
+            (Ok(None), Some(default)) => Ok(default),
+            (Ok(mut accept), _) => best_response_type(&mut accept, available),
+            (Err(err), _) => Err(err),
+        };
+        let ty = match ty {
+            Ok(ty) => ty,
+            Err(err) => {
+                let mut res = Response::new(err.status());
+                res.set_error(err);
+                return Ok(res);
+            }
+        };
+        let ty = with_pretty(&req, ty);
+        req.set_ext(ResponseType(ty.clone()));
+        let mut res = next.run(req).await;
+        res.insert_ext(ResponseType(ty));
+        Ok(res)
+    }
+}
+
+/// Serialize the body of a response using the type chosen by [negotiate].
+///
+/// `ty` should be `req.ext()`. If the request did not pass through [negotiate], it is [None], and
+/// the response is JSON.
+pub fn respond<T: Serialize + SafeToServe>(
+    ty: Option<&ResponseType>,
+    body: T,
+) -> Result<Response, tide::Error> {
+    match ty {
+        Some(ResponseType(ty)) => respond_with(ty, body),
+        None => respond_with(&mime::JSON, body),
+    }
+}
+
+/// Serialize the body of a response using the type chosen by [negotiate], which may be CSV.
+///
+/// This is like [respond], for routes which offer `text/csv` as well as the default types; see
+/// [Negotiator::route].
+pub fn respond_tabular<T: Serialize + TabularSerialize + SafeToServe>(
+    ty: Option<&ResponseType>,
+    body: T,
+) -> Result<Response, tide::Error> {
+    match ty {
+        Some(ResponseType(ty)) => tabular_response(ty.clone(), &body),
+        None => respond_with(&mime::JSON, body),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::Error;
+    use tide::http::{Method, Url};
+    use tide::StatusCode;
+
+    async fn get(app: &tide::Server<()>, path: &str, accept: &str) -> tide::http::Response {
+        let mut req = tide::http::Request::new(
+            Method::Get,
+            Url::parse("http://localhost").unwrap().join(path).unwrap(),
+        );
+        req.insert_header("Accept", accept);
+        app.respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_negotiate() {
+        let mut app = tide::new();
+        app.with(negotiate);
+        app.at("/height")
+            .get(|req: tide::Request<()>| async move { respond(req.ext(), 42u64) });
+        app.at("/proof")
+            .get(|_| async { respond_with(&mime::BYTE_STREAM, vec![1u8, 2, 3]) });
+
+        let mut res = get(&app, "/height", "application/json").await;
+        assert_eq!(res.content_type(), Some(mime::JSON));
+        assert_eq!(res.body_string().await.unwrap(), "42");
+
+        let mut res = get(&app, "/height", "application/octet-stream").await;
+        assert_eq!(res.content_type(), Some(mime::BYTE_STREAM));
+        assert_eq!(res.body_bytes().await.unwrap(), 42u64.to_le_bytes());
+
+        let res = get(&app, "/height", "text/html").await;
+        assert_eq!(res.status(), StatusCode::NotAcceptable);
+
+        // A forced type ignores the Accept header.
+        let res = get(&app, "/proof", "application/json").await;
+        assert_eq!(res.content_type(), Some(mime::BYTE_STREAM));
+    }
+
+    #[async_std::test]
+    async fn test_pretty() {
+        let mut app = tide::new();
+        app.with(negotiate);
+        app.at("/block")
+            .get(|req: tide::Request<()>| async move { respond(req.ext(), vec![1u64, 2]) });
+        app.at("/heights").get(|req: tide::Request<()>| async move {
+            crate::server::response(&req, vec![1u64, 2])
+        });
+        let body = |mut res: tide::http::Response| async move {
+            assert_eq!(res.content_type(), Some(mime::JSON));
+            res.body_string().await.unwrap()
+        };
+
+        assert_eq!(body(get(&app, "/block", "*/*").await).await, "[1,2]");
+        assert_eq!(
+            body(get(&app, "/block?pretty", "*/*").await).await,
+            "[\n  1,\n  2\n]"
+        );
+        assert_eq!(
+            body(get(&app, "/block", "application/json; pretty=true").await).await,
+            "[\n  1,\n  2\n]"
+        );
+        assert_eq!(
+            body(get(&app, "/block?pretty=false", "*/*").await).await,
+            "[1,2]"
+        );
+        assert_eq!(
+            body(get(&app, "/heights?pretty=1", "*/*").await).await,
+            "[\n  1,\n  2\n]"
+        );
+
+        // Binary responses are unaffected.
+        let res = get(&app, "/block?pretty", "application/octet-stream").await;
+        assert_eq!(res.content_type(), Some(mime::BYTE_STREAM));
+    }
+
+    #[async_std::test]
+    async fn test_preferences() {
+        let mut app = tide::new();
+        app.with(Negotiator::new().route("/block/:id", [mime::BYTE_STREAM, mime::JSON]));
+        app.at("/*")
+            .get(|req: tide::Request<()>| async move { respond(req.ext(), 0u64) });
+        let ty = |res: tide::http::Response| res.content_type().unwrap();
+
+        // Ties go to the server's preference for the route.
+        assert_eq!(ty(get(&app, "/status", "*/*").await), mime::JSON);
+        assert_eq!(ty(get(&app, "/block/1", "*/*").await), mime::BYTE_STREAM);
+        assert_eq!(
+            ty(get(&app, "/block/1", "application/json, application/*").await),
+            mime::BYTE_STREAM
+        );
+
+        // Weights take precedence over preferences, even when they come from wildcards.
+        assert_eq!(
+            ty(get(&app, "/block/1", "application/octet-stream;q=0.5, */*").await),
+            mime::JSON
+        );
+        assert_eq!(
+            ty(get(
+                &app,
+                "/status",
+                "application/json;q=0.5, application/*;q=0.8"
+            )
+            .await),
+            mime::BYTE_STREAM
+        );
+
+        // The most specific proposal determines the weight, and a weight of 0 excludes a type.
+        let res = get(
+            &app,
+            "/block/1",
+            "application/*, application/octet-stream;q=0",
+        )
+        .await;
+        assert_eq!(ty(res), mime::JSON);
+        let res = get(&app, "/block/1", "*/*;q=0").await;
+        assert_eq!(res.status(), StatusCode::NotAcceptable);
+    }
+
+    #[async_std::test]
+    async fn test_defaults() {
+        let mut app = tide::new();
+        app.with(
+            Negotiator::new()
+                .default_type(mime::BYTE_STREAM)
+                .route("/status", [mime::JSON])
+                .route_default("/height", mime::JSON),
+        );
+        app.at("/*")
+            .get(|req: tide::Request<()>| async move { respond(req.ext(), 0u64) });
+        let ty = |path: &str| {
+            let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+            let req = tide::http::Request::new(Method::Get, url);
+            let app = app.clone();
+            async move {
+                let res: tide::http::Response = app.respond(req).await.unwrap();
+                res.content_type().unwrap()
+            }
+        };
+
+        // Requests without an Accept header get the route's default, or else the global default,
+        // unless the route does not offer it.
+        assert_eq!(ty("/block/1").await, mime::BYTE_STREAM);
+        assert_eq!(ty("/height").await, mime::JSON);
+        assert_eq!(ty("/status").await, mime::JSON);
+
+        // The defaults do not change the order of preference.
+        assert_eq!(
+            get(&app, "/block/1", "*/*").await.content_type(),
+            Some(mime::JSON)
+        );
+    }
+
+    #[async_std::test]
+    async fn test_defaults_without_respond() {
+        let mut app = tide::new();
+        app.with(crate::server::add_error_body::<_, Error>);
+        app.with(Negotiator::new().default_type(mime::BYTE_STREAM));
+        app.at("/height")
+            .get(|req: tide::Request<()>| async move { crate::server::response(&req, 0u64) });
+        app.at("/balances")
+            .get(|req: tide::Request<()>| async move {
+                crate::server::response_tabular(&req, vec![Balance("alice", 10)])
+            });
+        app.at("/error").get(|_| async {
+            Err::<String, _>(tide::Error::from_str(StatusCode::BadRequest, "bad"))
+        });
+
+        // Responses serialized by functions which negotiate for themselves, and error bodies, get
+        // the negotiator's default.
+        for path in ["/height", "/balances", "/error"] {
+            let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+            let req = tide::http::Request::new(Method::Get, url);
+            let res: tide::http::Response = app.respond(req).await.unwrap();
+            assert_eq!(res.content_type(), Some(mime::BYTE_STREAM), "{}", path);
+        }
+        let res = get(&app, "/error", "application/json").await;
+        assert_eq!(res.content_type(), Some(mime::JSON));
+    }
+
+    #[derive(serde::Serialize)]
+    struct Balance(&'static str, u64);
+
+    impl SafeToServe for Balance {}
+
+    impl TabularSerialize for Balance {
+        fn header() -> Vec<String> {
+            vec!["account".into(), "balance".into()]
+        }
+
+        fn records(&self) -> Vec<Vec<String>> {
+            vec![vec![self.0.into(), self.1.to_string()]]
+        }
+    }
+
+    #[async_std::test]
+    async fn test_csv() {
+        let balances = || vec![Balance("alice", 10), Balance("bob", 20)];
+        let mut app = tide::new();
+        app.at("/balances")
+            .get(move |req: tide::Request<()>| async move {
+                crate::server::response_tabular(&req, balances())
+            });
+        app.at("/negotiated/balances")
+            .with(Negotiator::new().prefer([mime::JSON, mime::BYTE_STREAM, csv::mime()]))
+            .get(
+                move |req: tide::Request<()>| async move { respond_tabular(req.ext(), balances()) },
+            );
+
+        for path in ["/balances", "/negotiated/balances"] {
+            // CSV is only used when the client asks for it.
+            let res = get(&app, path, "*/*").await;
+            assert_eq!(res.content_type(), Some(mime::JSON));
+            let mut res = get(&app, path, "text/csv, application/json;q=0.5").await;
+            assert_eq!(res.content_type().unwrap().essence(), csv::CONTENT_TYPE);
+            assert_eq!(
+                res.body_string().await.unwrap(),
+                "account,balance\r\nalice,10\r\nbob,20\r\n"
+            );
+        }
+    }
+}
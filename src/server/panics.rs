@@ -0,0 +1,142 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Turning handler panics into error responses.
+//!
+//! Without this, a panic in a handler unwinds through the server and tears down the connection, so
+//! the client gets no response at all. [catch_panics] catches the unwind, logs the panic along with
+//! its backtrace, and responds with a 500 whose body is an [Error::catch_all]. The message in the
+//! body does not include the panic message, which may contain internal details, but it does include
+//! a request ID which can be used to find the panic in the server logs.
+//!
+//! The request ID is taken from the [REQUEST_ID] header of the request if there is one, or
+//! generated otherwise, and is returned in the [REQUEST_ID] header of the response.
+
+use super::respond_to_accept;
+use crate::error::Error;
+use futures::future::{BoxFuture, FutureExt};
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Once;
+use tide::http::content::Accept;
+use tide::{Next, Request, StatusCode};
+use tracing::{event, Level};
+
+pub use crate::headers::REQUEST_ID;
+
+thread_local! {
+    // The backtrace of the last panic on this thread.
+    static BACKTRACE: RefCell<Option<Backtrace>> = const { RefCell::new(None) };
+}
+
+// The backtrace of a panic is only available while the panic is unwinding, so we record it in a
+// panic hook, which then defers to whatever hook was installed before.
+pub(crate) fn install_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let prev = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            BACKTRACE.with(|bt| *bt.borrow_mut() = Some(Backtrace::force_capture()));
+            prev(info);
+        }));
+    });
+}
+
+// The backtrace of the last panic on this thread, if it has not already been taken.
+pub(crate) fn take_backtrace() -> Option<Backtrace> {
+    BACKTRACE.with(|bt| bt.borrow_mut().take())
+}
+
+fn next_request_id() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    format!("{:016x}", NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+pub(crate) fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Server middleware which turns handler panics into 500 responses.
+///
+/// This should be the first middleware added to the server, so that it catches panics in other
+/// middleware as well as in handlers.
+pub fn catch_panics<'a, T: Clone + Send + Sync + 'static, E: Error>(
+    req: Request<T>,
+    next: Next<'a, T>,
+) -> BoxFuture<'a, tide::Result> {
+    install_hook();
+    Box::pin(async move {
+        let mut accept = Accept::from_headers(&req)?;
+        let id = match req.header(REQUEST_ID) {
+            Some(id) => id.as_str().to_string(),
+            None => next_request_id(),
+        };
+        let method = req.method();
+        let path = req.url().path().to_string();
+
+        match AssertUnwindSafe(next.run(req)).catch_unwind().await {
+            Ok(res) => Ok(res),
+            Err(payload) => {
+                let backtrace = take_backtrace();
+                event!(
+                    Level::ERROR,
+                    "request {} ({} {}) panicked: {}\n{}",
+                    id,
+                    method,
+                    path,
+                    panic_message(&*payload),
+                    backtrace
+                        .map(|bt| bt.to_string())
+                        .unwrap_or_else(|| "no backtrace available".into())
+                );
+                let error = E::catch_all(format!("internal server error (request ID {})", id));
+                let mut res = respond_to_accept(&mut accept, &error)?;
+                res.set_status(StatusCode::InternalServerError);
+                res.insert_header(REQUEST_ID, id);
+                Ok(res)
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::Error;
+
+    #[async_std::test]
+    async fn test_catch_panics() {
+        let mut app = tide::new();
+        app.with(catch_panics::<(), Error>);
+        app.at("/panic").get(|_| async {
+            if true {
+                panic!("secret internal state");
+            }
+            Ok("unreachable")
+        });
+
+        let mut req = tide::http::Request::new(
+            tide::http::Method::Get,
+            tide::http::Url::parse("http://localhost/panic").unwrap(),
+        );
+        req.insert_header("Accept", "application/json");
+        req.insert_header(REQUEST_ID, "abc123");
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::InternalServerError);
+        assert_eq!(res[REQUEST_ID], "abc123");
+        let err: Error = res.body_json().await.unwrap();
+        assert_eq!(err.msg, "internal server error (request ID abc123)");
+    }
+}
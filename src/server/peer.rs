@@ -0,0 +1,387 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! The identity of clients authenticated with TLS client certificates.
+//!
+//! This server does not terminate TLS itself (see [serve](super::serve)), so mutual TLS is done by
+//! the proxy in front of it, which reports the certificate its client presented in the
+//! `X-Forwarded-Client-Cert` header. The header has the format used by Envoy: a comma-separated
+//! list of elements, one per proxy, each a `;`-separated list of `Key=Value` pairs like
+//!
+//! ```text
+//! Hash=8d5c…;Subject="CN=relayer,O=Espresso";URI=spiffe://espresso/relayer;DNS=relayer.local
+//! ```
+//!
+//! The [ClientCerts] middleware believes this header only from the proxies it trusts (the same
+//! [TrustedProxies] as [ProxyHeaders](super::forwarded::ProxyHeaders)), and stores the
+//! [PeerIdentity] from the element added by the nearest one in the request. Handlers get it with
+//! [peer_identity], to authorize calls from other nodes; the per-client
+//! [rate limit](super::rate_limit) uses it in place of the client address, and the
+//! [audit log](super::audit) records it.
+//!
+//! # Configuring the proxy
+//!
+//! The trusted proxy must overwrite any `X-Forwarded-Client-Cert` header sent by its client, rather
+//! than append to it or pass it on. Otherwise a client without a certificate can send the header
+//! itself, and its element is the last one, so it is believed: a client could claim to be
+//! `spiffe://espresso/relayer` and be given the relayer's roles. Envoy does this with
+//! `forward_client_cert_details: SANITIZE_SET` (in `APPEND_FORWARD` mode, it passes the client's
+//! header through when the client presents no certificate), and nginx and HAProxy with a header
+//! directive which sets the header unconditionally, since neither removes it by default.
+//!
+//! As a second line of defence, [ClientCerts::trusted_hops] rejects requests whose header has more
+//! elements than there are trusted proxies in the chain, which a client-supplied element would
+//! add.
+//!
+//! With the `mtls` feature, a `Cert` field (the URL-encoded PEM certificate) is parsed, and the
+//! identity is taken from the certificate itself rather than from the other fields.
+//!
+//! ```
+//! use net::server::forwarded::{ForwardingHeader, TrustedProxies};
+//! use net::server::peer::{peer_identity, ClientCerts};
+//! use tide::StatusCode;
+//!
+//! let proxies =
+//!     TrustedProxies::new(ForwardingHeader::XForwardedFor).trust("10.0.0.0/8".parse().unwrap());
+//! let mut app = tide::new();
+//! app.with(ClientCerts::new(proxies).trusted_hops(1));
+//! app.at("/relay").post(|req: tide::Request<()>| async move {
+//!     match peer_identity(&req) {
+//!         Some(peer) if peer.has_name("spiffe://espresso/relayer") => Ok("relayed".into()),
+//!         _ => Ok(tide::Response::new(StatusCode::Forbidden)),
+//!     }
+//! });
+//! ```
+
+use super::forwarded::{peer_info, TrustedProxies};
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+use tide::{Middleware, Next, Request, StatusCode};
+
+/// The header in which a TLS-terminating proxy reports its client's certificate.
+pub const CLIENT_CERT: &str = "X-Forwarded-Client-Cert";
+
+/// The identity in a TLS client certificate.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PeerIdentity {
+    /// The distinguished name of the subject, like `CN=relayer,O=Espresso`.
+    pub subject: String,
+    /// The subject alternative names: DNS names, URIs (like SPIFFE IDs) and IP addresses.
+    pub san: Vec<String>,
+    /// The SHA-256 hash of the DER-encoded certificate.
+    #[serde(with = "hex::serde")]
+    pub fingerprint: [u8; 32],
+}
+
+impl PeerIdentity {
+    /// Whether `name` is one of the subject alternative names of this certificate.
+    pub fn has_name(&self, name: &str) -> bool {
+        self.san.iter().any(|san| san == name)
+    }
+
+    /// Parse a DER-encoded certificate.
+    #[cfg(feature = "mtls")]
+    pub fn from_der(der: &[u8]) -> Result<Self, String> {
+        use sha2::{Digest, Sha256};
+        use std::convert::TryFrom;
+        use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+        let (_, cert) = X509Certificate::from_der(der).map_err(|err| err.to_string())?;
+        let mut san = vec![];
+        if let Some(ext) = cert
+            .subject_alternative_name()
+            .map_err(|err| err.to_string())?
+        {
+            for name in &ext.value.general_names {
+                match name {
+                    GeneralName::DNSName(name) | GeneralName::URI(name) => {
+                        san.push(name.to_string())
+                    }
+                    GeneralName::IPAddress(bytes) => {
+                        let addr = match bytes.len() {
+                            4 => IpAddr::from(<[u8; 4]>::try_from(*bytes).unwrap()),
+                            16 => IpAddr::from(<[u8; 16]>::try_from(*bytes).unwrap()),
+                            _ => continue,
+                        };
+                        san.push(addr.to_string());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(Self {
+            subject: cert.subject().to_string(),
+            san,
+            fingerprint: Sha256::digest(der).into(),
+        })
+    }
+
+    /// Parse a PEM-encoded certificate.
+    #[cfg(feature = "mtls")]
+    pub fn from_pem(pem: &[u8]) -> Result<Self, String> {
+        let (_, pem) = x509_parser::pem::parse_x509_pem(pem).map_err(|err| err.to_string())?;
+        Self::from_der(&pem.contents)
+    }
+
+    // Parse one element of an `X-Forwarded-Client-Cert` header.
+    fn from_element(element: &str) -> Result<Self, String> {
+        let mut hash = None;
+        let mut subject = None;
+        let mut san = vec![];
+        for pair in split_unquoted(element, ';') {
+            let (name, value) = match pair.split_once('=') {
+                Some(pair) => pair,
+                None => continue,
+            };
+            let value = unquote(value.trim());
+            match name.trim().to_ascii_lowercase().as_str() {
+                "hash" => hash = Some(value),
+                "subject" => subject = Some(value),
+                "uri" | "dns" => san.push(value),
+                #[cfg(feature = "mtls")]
+                "cert" => {
+                    let pem = percent_encoding::percent_decode_str(&value).collect::<Vec<_>>();
+                    return Self::from_pem(&pem);
+                }
+                _ => {}
+            }
+        }
+        let hash = hash.ok_or_else(|| "client certificate has no hash".to_string())?;
+        let fingerprint = hex::decode(&hash)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| format!("invalid client certificate hash {}", hash))?;
+        Ok(Self {
+            subject: subject.unwrap_or_default(),
+            san,
+            fingerprint,
+        })
+    }
+}
+
+impl Display for PeerIdentity {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.subject, hex::encode(self.fingerprint))
+    }
+}
+
+/// The identity of the client of a request, if it presented a certificate.
+///
+/// This is only set by [ClientCerts]; without it, every request is anonymous.
+pub fn peer_identity<State>(req: &Request<State>) -> Option<PeerIdentity> {
+    req.ext::<PeerIdentity>().cloned()
+}
+
+// Split `s` on `sep`, except where `sep` is in a quoted string.
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == sep && !quoted => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+// Remove the quotes and escapes from a quoted string. Other values are returned unchanged.
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(quoted) => {
+            let mut unquoted = String::with_capacity(quoted.len());
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => unquoted.extend(chars.next()),
+                    c => unquoted.push(c),
+                }
+            }
+            unquoted
+        }
+        None => value.to_string(),
+    }
+}
+
+/// Server middleware which determines the identity of each client from the certificate reported by
+/// a trusted TLS-terminating proxy.
+///
+/// See the [module documentation](self), in particular on how the proxy must be configured.
+#[derive(Clone, Debug)]
+pub struct ClientCerts {
+    trusted: Arc<TrustedProxies>,
+    hops: Option<usize>,
+}
+
+impl ClientCerts {
+    /// Believe the client certificates reported by `trusted` proxies.
+    ///
+    /// Requests from other peers never have a [PeerIdentity].
+    pub fn new(trusted: TrustedProxies) -> Self {
+        Self {
+            trusted: Arc::new(trusted),
+            hops: None,
+        }
+    }
+
+    /// Reject requests whose [CLIENT_CERT] header has more than `hops` elements.
+    ///
+    /// `hops` is the number of trusted proxies which add an element to the header; usually 1, for
+    /// the proxy which terminates TLS. A request with more elements than that carries one which no
+    /// trusted proxy added, which means the proxy is not overwriting the header sent by the client,
+    /// so the request fails with 400 Bad Request rather than be believed.
+    pub fn trusted_hops(mut self, hops: usize) -> Self {
+        self.hops = Some(hops);
+        self
+    }
+
+    fn is_trusted<State>(&self, req: &Request<State>) -> bool {
+        peer_info(req)
+            .addr
+            .map_or(false, |addr| self.trusted.contains(addr))
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ClientCerts {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if self.is_trusted(&req) {
+            let elements = req
+                .header(CLIENT_CERT)
+                .map(|values| {
+                    values
+                        .iter()
+                        .flat_map(|value| split_unquoted(value.as_str(), ','))
+                        .map(|element| element.trim().to_string())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            if let Some(hops) = self.hops {
+                if elements.len() > hops {
+                    return Err(tide::Error::from_str(
+                        StatusCode::BadRequest,
+                        format!(
+                            "{} header has {} elements, but only {} trusted proxies add them",
+                            CLIENT_CERT,
+                            elements.len(),
+                            hops
+                        ),
+                    ));
+                }
+            }
+            // The nearest proxy adds the last element, describing the client it authenticated.
+            let element = elements.last();
+            if let Some(element) = element {
+                let peer = PeerIdentity::from_element(element)
+                    .map_err(|err| tide::Error::from_str(StatusCode::BadRequest, err))?;
+                req.set_ext(peer);
+            }
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::forwarded::ForwardingHeader;
+    use tide::http::{Method, Url};
+
+    const HASH: &str = "8d5c0a2e0bd3e0d6b7ef3e45e29bfa6a1d9e3d7b3e4e1c9b6a1f0c2d3e4f5a6b";
+
+    async fn send(certs: ClientCerts, peer: &str, header: Option<&str>) -> tide::http::Response {
+        let mut app = tide::new();
+        app.with(certs);
+        app.at("/").get(|req: tide::Request<()>| async move {
+            Ok(serde_json::to_string(&peer_identity(&req))?)
+        });
+        let mut req =
+            tide::http::Request::new(Method::Get, Url::parse("http://localhost/").unwrap());
+        req.set_peer_addr(Some(peer));
+        if let Some(header) = header {
+            req.insert_header(CLIENT_CERT, header);
+        }
+        app.respond(req).await.unwrap()
+    }
+
+    async fn identity(mut res: tide::http::Response) -> Option<PeerIdentity> {
+        assert_eq!(res.status(), StatusCode::Ok);
+        res.body_json().await.unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let element = format!(
+            r#"Hash={};Subject="CN=relayer,O=\"Espresso\"";URI={};DNS=relayer"#,
+            HASH, "spiffe://espresso/relayer"
+        );
+        let peer = PeerIdentity::from_element(&element).unwrap();
+        assert_eq!(peer.subject, r#"CN=relayer,O="Espresso""#);
+        assert_eq!(peer.san, ["spiffe://espresso/relayer", "relayer"]);
+        assert_eq!(hex::encode(peer.fingerprint), HASH);
+        assert!(peer.has_name("relayer"));
+
+        assert!(PeerIdentity::from_element("Subject=\"CN=relayer\"").is_err());
+        assert!(PeerIdentity::from_element("Hash=abcd").is_err());
+        assert_eq!(split_unquoted(r#"a="x,y",b"#, ','), [r#"a="x,y""#, "b"]);
+    }
+
+    #[async_std::test]
+    async fn test_client_certs() {
+        let certs = ClientCerts::new(
+            TrustedProxies::new(ForwardingHeader::XForwardedFor)
+                .trust("10.0.0.0/8".parse().unwrap()),
+        );
+        let header = format!(
+            r#"Hash={};Subject="CN=proxy", Hash={};Subject="CN=relayer,O=Espresso""#,
+            "00".repeat(32),
+            HASH
+        );
+
+        // The element added by the nearest trusted proxy is used.
+        let peer = identity(send(certs.clone(), "10.0.0.1:1", Some(&header)).await)
+            .await
+            .unwrap();
+        assert_eq!(peer.subject, "CN=relayer,O=Espresso");
+        assert_eq!(hex::encode(peer.fingerprint), HASH);
+
+        // The header is ignored from untrusted peers, and requests without it are anonymous.
+        assert_eq!(
+            identity(send(certs.clone(), "8.8.8.8:1", Some(&header)).await).await,
+            None
+        );
+        assert_eq!(
+            identity(send(certs.clone(), "10.0.0.1:1", None).await).await,
+            None
+        );
+
+        // A malformed header from a trusted proxy is rejected.
+        let res = send(certs.clone(), "10.0.0.1:1", Some("Subject=\"CN=relayer\"")).await;
+        assert_eq!(res.status(), StatusCode::BadRequest);
+
+        // With one trusted hop, a header with an element the client added is rejected.
+        let certs = certs.trusted_hops(1);
+        let res = send(certs.clone(), "10.0.0.1:1", Some(&header)).await;
+        assert_eq!(res.status(), StatusCode::BadRequest);
+        let element = format!(r#"Hash={};Subject="CN=relayer,O=Espresso""#, HASH);
+        let peer = identity(send(certs, "10.0.0.1:1", Some(&element)).await)
+            .await
+            .unwrap();
+        assert_eq!(peer.subject, "CN=relayer,O=Espresso");
+    }
+}
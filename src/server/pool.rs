@@ -0,0 +1,207 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Reusable buffers for serializing response bodies.
+//!
+//! Under high load, allocating a fresh buffer for every serialized response dominates the cost of
+//! serialization. Instead, [response](super::response) serializes into a [PooledBuffer] taken from
+//! a [BufferPool]. The buffer becomes the body of the response, and is returned to the pool when
+//! the response has been sent and the body is dropped.
+//!
+//! The pool used by [response](super::response) is [global]. Its limits can be adjusted at any
+//! time with [BufferPool::set_limits], and its effectiveness monitored with [BufferPool::stats].
+
+use futures::io::Cursor;
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tide::Body;
+
+static GLOBAL: Lazy<Arc<BufferPool>> = Lazy::new(|| Arc::new(BufferPool::new(64, 4 << 20)));
+
+/// The pool used for serializing responses.
+pub fn global() -> &'static Arc<BufferPool> {
+    &GLOBAL
+}
+
+/// Statistics about the usage of a [BufferPool].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Requests for a buffer which were satisfied by reusing a pooled buffer.
+    pub hits: u64,
+    /// Requests for a buffer which required a new allocation.
+    pub misses: u64,
+    /// The number of buffers currently in the pool.
+    pub pooled: usize,
+}
+
+impl PoolStats {
+    /// The fraction of requests for a buffer which were satisfied from the pool.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+/// A pool of reusable byte buffers.
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    max_buffers: AtomicUsize,
+    max_capacity: AtomicUsize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BufferPool {
+    /// A pool which retains at most `max_buffers` buffers, each of capacity at most `max_capacity`.
+    ///
+    /// Buffers which grow beyond `max_capacity` are freed rather than returned to the pool, so that
+    /// an occasional huge response does not pin a huge buffer forever.
+    pub fn new(max_buffers: usize, max_capacity: usize) -> Self {
+        Self {
+            buffers: Mutex::new(Vec::new()),
+            max_buffers: AtomicUsize::new(max_buffers),
+            max_capacity: AtomicUsize::new(max_capacity),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Change the limits of the pool.
+    ///
+    /// If the pool holds more than `max_buffers` buffers, the excess are freed immediately.
+    pub fn set_limits(&self, max_buffers: usize, max_capacity: usize) {
+        self.max_buffers.store(max_buffers, Ordering::Relaxed);
+        self.max_capacity.store(max_capacity, Ordering::Relaxed);
+        let mut buffers = self.buffers.lock().unwrap();
+        buffers.truncate(max_buffers);
+        buffers.retain(|buf| buf.capacity() <= max_capacity);
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            pooled: self.buffers.lock().unwrap().len(),
+        }
+    }
+
+    /// Take an empty buffer from the pool, or allocate one if the pool is empty.
+    pub fn get(self: &Arc<Self>) -> PooledBuffer {
+        let buf = match self.buffers.lock().unwrap().pop() {
+            Some(buf) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                Vec::new()
+            }
+        };
+        PooledBuffer {
+            buf,
+            pool: self.clone(),
+        }
+    }
+
+    fn put(&self, mut buf: Vec<u8>) {
+        if buf.capacity() == 0 || buf.capacity() > self.max_capacity.load(Ordering::Relaxed) {
+            return;
+        }
+        buf.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers.load(Ordering::Relaxed) {
+            buffers.push(buf);
+        }
+    }
+}
+
+/// A buffer which is returned to its [BufferPool] when dropped.
+pub struct PooledBuffer {
+    buf: Vec<u8>,
+    pool: Arc<BufferPool>,
+}
+
+impl PooledBuffer {
+    pub fn as_mut_vec(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+
+    /// Convert the buffer into a message body.
+    ///
+    /// The buffer is returned to the pool when the body is dropped.
+    pub fn into_body(self) -> Body {
+        let len = self.buf.len();
+        Body::from_reader(Cursor::new(self), Some(len))
+    }
+}
+
+impl AsRef<[u8]> for PooledBuffer {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buf));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_reuse() {
+        let pool = Arc::new(BufferPool::new(1, 1024));
+
+        let mut buf = pool.get();
+        buf.as_mut_vec().extend_from_slice(b"response");
+        let body = buf.into_body();
+        assert_eq!(body.len(), Some(8));
+        assert_eq!(body.into_bytes().await.unwrap(), b"response");
+
+        // The buffer was returned to the pool when the body was dropped, so the next request for a
+        // buffer reuses it.
+        let buf = pool.get();
+        assert!(buf.as_ref().is_empty());
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                hits: 1,
+                misses: 1,
+                pooled: 0
+            }
+        );
+        drop(buf);
+        assert_eq!(pool.stats().pooled, 1);
+    }
+
+    #[test]
+    fn test_limits() {
+        let pool = Arc::new(BufferPool::new(1, 16));
+
+        // Oversized buffers are not retained.
+        let mut buf = pool.get();
+        buf.as_mut_vec().resize(32, 0);
+        drop(buf);
+        assert_eq!(pool.stats().pooled, 0);
+
+        // At most `max_buffers` buffers are retained.
+        let mut bufs = vec![pool.get(), pool.get()];
+        for buf in &mut bufs {
+            buf.as_mut_vec().push(0);
+        }
+        drop(bufs);
+        assert_eq!(pool.stats().pooled, 1);
+    }
+}
@@ -0,0 +1,264 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Forwarding requests to other services, for gateways.
+//!
+//! A deployment with several services (the query service, the address book, the faucet) can expose
+//! them all through one public gateway, instead of keeping a separate reverse proxy configuration
+//! in sync with the services. The [proxy] endpoint forwards every request under a route prefix to
+//! an upstream service, with the prefix replaced by the upstream URL:
+//!
+//! ```
+//! use net::server::proxy;
+//! use surf::Url;
+//!
+//! let mut app = tide::new();
+//! let eqs = proxy("/eqs", Url::parse("http://eqs.internal:50087/").unwrap());
+//! app.at("/eqs").all(eqs.clone());
+//! app.at("/eqs/*").all(eqs);
+//! ```
+//!
+//! Request and response bodies are streamed through unchanged, along with their headers, so content
+//! negotiation and error bodies work end to end: an error response from the upstream service
+//! reaches the client as the upstream service encoded it, and is not re-encoded by the gateway's
+//! own [ErrorBody](super::ErrorBody). Hop-by-hop headers, including any named in `Connection`, are
+//! not forwarded. The upstream service is told about the client with the `X-Forwarded-For`,
+//! `X-Forwarded-Proto` and `X-Forwarded-Host` headers, so it should trust the gateway as a proxy
+//! which sets [ForwardingHeader::XForwardedFor](super::forwarded::ForwardingHeader) (see
+//! [forwarded](super::forwarded)). A `Forwarded` header sent by the client is removed, so that it
+//! cannot pass for one set by the gateway.
+//!
+//! If the upstream service cannot be reached, the request fails with 502 Bad Gateway.
+
+use super::forwarded::client_info;
+use surf::Url;
+use tide::http::headers::{HeaderName, HeaderValues};
+use tide::{Endpoint, Request, Response, StatusCode};
+
+// Headers which describe a single connection, and so are not forwarded by proxies (RFC 7230
+// section 6.1), along with the headers the proxy sets or removes itself.
+const HOP_BY_HOP: [&str; 11] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+    "Host",
+    "Content-Length",
+    "Forwarded",
+];
+
+// The headers which must not be forwarded from a message whose `Connection` header is
+// `connection`: the standard hop-by-hop headers, and those named in `Connection`.
+fn hop_by_hop(connection: Option<&HeaderValues>) -> impl Fn(&HeaderName) -> bool {
+    let named = connection
+        .into_iter()
+        .flat_map(|values| values.iter())
+        .flat_map(|value| value.as_str().split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<_>>();
+    move |name| {
+        let name = name.as_str();
+        HOP_BY_HOP.iter().any(|hop| hop.eq_ignore_ascii_case(name))
+            || named.iter().any(|hop| hop.eq_ignore_ascii_case(name))
+    }
+}
+
+/// An endpoint which forwards requests under `route_prefix` to the service at `upstream`.
+///
+/// The part of the request path after `route_prefix` is appended to the path of `upstream`, and
+/// the query string is kept. See the [module documentation](self).
+pub fn proxy(route_prefix: impl Into<String>, upstream: Url) -> Proxy {
+    Proxy {
+        prefix: route_prefix.into().trim_end_matches('/').to_string(),
+        upstream,
+        client: surf::Client::new(),
+    }
+}
+
+/// An endpoint which forwards requests to another service, created by [proxy].
+#[derive(Clone)]
+pub struct Proxy {
+    prefix: String,
+    upstream: Url,
+    client: surf::Client,
+}
+
+impl Proxy {
+    /// Use `client` to send requests upstream.
+    pub fn client(mut self, client: surf::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    fn target(&self, url: &Url) -> Url {
+        let path = url.path();
+        let rest = match path.strip_prefix(&self.prefix) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+            _ => path,
+        };
+        let mut target = self.upstream.clone();
+        let path = format!(
+            "{}/{}",
+            self.upstream.path().trim_end_matches('/'),
+            rest.trim_start_matches('/')
+        );
+        target.set_path(&path);
+        target.set_query(url.query());
+        target
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Endpoint<State> for Proxy {
+    async fn call(&self, mut req: Request<State>) -> tide::Result {
+        let client = client_info(&req);
+        let mut upstream = surf::Request::new(req.method(), self.target(req.url()));
+        let is_hop_by_hop = hop_by_hop(req.header("Connection"));
+        for (name, values) in req.iter() {
+            if !is_hop_by_hop(name) {
+                upstream.insert_header(name, values);
+            }
+        }
+        let forwarded_for = match (req.header("X-Forwarded-For"), client.addr) {
+            (Some(prev), Some(addr)) => Some(format!("{}, {}", prev.as_str(), addr)),
+            (None, Some(addr)) => Some(addr.to_string()),
+            (prev, None) => prev.map(|prev| prev.as_str().to_string()),
+        };
+        if let Some(forwarded_for) = forwarded_for {
+            upstream.insert_header("X-Forwarded-For", forwarded_for);
+        }
+        upstream.insert_header("X-Forwarded-Proto", client.scheme);
+        if let Some(host) = client.host {
+            upstream.insert_header("X-Forwarded-Host", host);
+        }
+        upstream.set_body(req.take_body());
+
+        let res = match self.client.send(upstream).await {
+            Ok(res) => res,
+            Err(err) => {
+                return Err(tide::Error::from_str(
+                    StatusCode::BadGateway,
+                    format!("upstream service is unavailable: {}", err),
+                ))
+            }
+        };
+        let mut res: tide::http::Response = res.into();
+        let is_hop_by_hop = hop_by_hop(res.header("Connection"));
+        let hop_by_hop = res
+            .header_names()
+            .filter(|name| is_hop_by_hop(name))
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in hop_by_hop {
+            res.remove_header(name);
+        }
+        Ok(Response::from(res))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::Server;
+    use tide::http::{mime, Method};
+
+    fn gateway() -> tide::Server<()> {
+        let mut eqs = tide::new();
+        eqs.at("/v1/echo/:id")
+            .post(|mut req: tide::Request<()>| async move {
+                let body = req.body_bytes().await?;
+                let mut res = Response::new(StatusCode::Ok);
+                let header = |name: &str| req.header(name).map(|h| h.as_str().to_string());
+                res.insert_header(
+                    "X-Echo",
+                    format!(
+                        "{} {:?} {:?} {:?} {:?}",
+                        req.url(),
+                        header("X-Forwarded-For"),
+                        header("X-Forwarded-Proto"),
+                        header("Forwarded"),
+                        header("X-Session-Token"),
+                    ),
+                );
+                res.insert_header("Connection", "X-Upstream-Hop");
+                res.insert_header("X-Upstream-Hop", "1");
+                res.set_body(body);
+                res.set_content_type(req.content_type().unwrap());
+                Ok(res)
+            });
+        eqs.at("/v1/missing").get(|_| async {
+            let mut res = Response::new(StatusCode::NotFound);
+            res.set_body(r#"{"msg":"no such block"}"#);
+            res.set_content_type(mime::JSON);
+            Ok(res)
+        });
+
+        let eqs = proxy("/eqs/", Url::parse("http://eqs.internal/v1").unwrap())
+            .client(surf::Client::new().with(Server(eqs)));
+        let unreachable = proxy("/faucet", Url::parse("http://127.0.0.1:1/").unwrap());
+        let mut app = tide::new();
+        app.at("/eqs/*").all(eqs);
+        app.at("/faucet/*").all(unreachable);
+        app
+    }
+
+    #[async_std::test]
+    async fn test_proxy() {
+        let app = gateway();
+        let mut req = tide::http::Request::new(
+            Method::Post,
+            Url::parse("http://gateway/eqs/echo/3?x=1").unwrap(),
+        );
+        req.set_peer_addr(Some("192.0.2.1:4000"));
+        req.insert_header("X-Forwarded-For", "198.51.100.7");
+        req.insert_header("Forwarded", "for=10.0.0.5");
+        req.insert_header("Connection", "keep-alive, X-Session-Token");
+        req.insert_header("X-Session-Token", "secret");
+        req.set_body(vec![1u8, 2, 3]);
+        req.set_content_type(mime::BYTE_STREAM);
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.content_type(), Some(mime::BYTE_STREAM));
+        // The client's Forwarded header, and the headers named in Connection, are not forwarded in
+        // either direction.
+        assert_eq!(
+            res["X-Echo"].as_str(),
+            concat!(
+                r#"http://eqs.internal/v1/echo/3?x=1 Some("198.51.100.7, 192.0.2.1") "#,
+                r#"Some("http") None None"#
+            )
+        );
+        assert!(res.header("X-Upstream-Hop").is_none());
+        assert_eq!(res.body_bytes().await.unwrap(), vec![1, 2, 3]);
+
+        // Error bodies are passed through unchanged.
+        let req = tide::http::Request::new(
+            Method::Get,
+            Url::parse("http://gateway/eqs/missing").unwrap(),
+        );
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NotFound);
+        assert_eq!(res.content_type(), Some(mime::JSON));
+        assert_eq!(
+            res.body_string().await.unwrap(),
+            r#"{"msg":"no such block"}"#
+        );
+
+        // An unreachable upstream is a bad gateway.
+        let req = tide::http::Request::new(
+            Method::Get,
+            Url::parse("http://gateway/faucet/request").unwrap(),
+        );
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BadGateway);
+    }
+}
@@ -0,0 +1,696 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Accounting for the cumulative usage of each account, with daily and monthly caps.
+//!
+//! Rate limits (see [rate_limit](super::rate_limit)) smooth out bursts, but a hosted service also
+//! has to know how much each customer has used over a billing period, and stop serving them when
+//! they reach their allowance. The [Quotas] middleware bills each request to an [Account], and
+//! records the request and the size of the response body against the account, in each [Period].
+//! Requests whose account has reached a [Limit] in any period fail with 429 Too Many Requests, and
+//! a Retry-After header saying when the period ends.
+//!
+//! The account of a request is the identity which something has checked (see [Account::of]): its
+//! tenant, if it passed through [Tenants](super::Tenants), or else a client certificate or an
+//! identity recorded with [authenticate](crate::authz::authenticate). A bare bearer token is not
+//! an account, since a client could get a fresh quota by making one up, so middleware which checks
+//! API keys should record them with `authenticate`. Clients which have not authenticated share
+//! the quota of [Account::Anonymous].
+//!
+//! Usage is kept in a [QuotaStore]. [MemoryQuotaStore] keeps it in memory, which is enough for a
+//! single replica which can afford to forget usage when it restarts; services which bill for usage
+//! should implement the trait on their database. [Quotas::serve] adds a `/usage` endpoint, where
+//! clients can check how much of their quota is left:
+//!
+//! ```
+//! # use net::server::quota::{Account, Limit, Period, Quotas};
+//! let quotas = Quotas::in_memory()
+//!     .limit(Period::Daily, Limit::new().requests(10_000))
+//!     .limit(Period::Monthly, Limit::new().bytes(10 << 30))
+//!     .account_limit(Account::tenant("partner"), Period::Monthly, Limit::new().bytes(1 << 40));
+//! let mut app = tide::new();
+//! app.with(quotas.clone());
+//! quotas.serve(&mut app);
+//! ```
+//!
+//! Streamed bodies are counted as they are read, and their size is recorded once the body has been
+//! sent, or dropped. The limit is checked before the request is handled and the usage is recorded
+//! after, so concurrent requests from one account can overshoot the limit by at most the usage of
+//! the requests in flight.
+
+use super::rate_limit::too_many_requests;
+use super::response;
+use super::tenancy::tenant;
+use crate::authz::{authenticated, Identity};
+use crate::redact::SafeToServe;
+use crate::wire::body::observe_body;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tide::{Middleware, Next, Request, Response, StatusCode};
+use tracing::{event, Level};
+
+/// A period over which usage accumulates, in UTC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Daily,
+    Monthly,
+}
+
+const PERIODS: [Period; 2] = [Period::Daily, Period::Monthly];
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+impl Period {
+    /// The start and end of the period containing `time`, in seconds since the Unix epoch.
+    pub fn window(self, time: u64) -> (u64, u64) {
+        let day = time / SECONDS_PER_DAY;
+        match self {
+            Self::Daily => (day * SECONDS_PER_DAY, (day + 1) * SECONDS_PER_DAY),
+            Self::Monthly => {
+                let (year, month, _) = civil_from_days(day);
+                let (next_year, next_month) = if month == 12 {
+                    (year + 1, 1)
+                } else {
+                    (year, month + 1)
+                };
+                (
+                    days_from_civil(year, month, 1) * SECONDS_PER_DAY,
+                    days_from_civil(next_year, next_month, 1) * SECONDS_PER_DAY,
+                )
+            }
+        }
+    }
+}
+
+// The (year, month, day) of `days` since the Unix epoch, and its inverse, from Howard Hinnant's
+// date algorithms, specialized to dates after the epoch.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as u64;
+    (year, month, day)
+}
+
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = year - (month <= 2) as u64;
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Who the usage of a request is billed to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Account {
+    /// A tenant, by its ID.
+    Tenant(String),
+    /// A client certificate, by its fingerprint.
+    Peer([u8; 32]),
+    /// A key which signed the request.
+    Signer(String),
+    /// An API key which middleware has checked, by its SHA-256 hash, so that keys are not kept in
+    /// the store.
+    ApiKey([u8; 32]),
+    /// Every client which has not authenticated.
+    Anonymous,
+}
+
+impl Account {
+    pub fn tenant(id: impl Into<String>) -> Self {
+        Self::Tenant(id.into())
+    }
+
+    pub fn api_key(key: &str) -> Self {
+        Self::ApiKey(Sha256::digest(key.as_bytes()).into())
+    }
+
+    /// The account the usage of `req` is billed to.
+    ///
+    /// This is the tenant of `req`, if it has one, or else the first identity which has been
+    /// [authenticated], or else [Account::Anonymous].
+    pub fn of<State>(req: &Request<State>) -> Self {
+        if let Some(tenant) = tenant(req) {
+            return Self::Tenant(tenant.id.clone());
+        }
+        match authenticated(req).into_iter().next() {
+            Some(Identity::ApiKey(key)) => Self::api_key(&key),
+            Some(Identity::Peer(peer)) => Self::Peer(peer.fingerprint),
+            Some(Identity::Signer(key)) => Self::Signer(key),
+            None => Self::Anonymous,
+        }
+    }
+}
+
+/// The key under which the usage of the account is stored.
+impl Display for Account {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Tenant(id) => write!(f, "tenant:{}", id),
+            Self::Peer(fingerprint) => write!(f, "peer:{}", hex::encode(fingerprint)),
+            Self::Signer(key) => write!(f, "signer:{}", key),
+            Self::ApiKey(hash) => write!(f, "key:{}", hex::encode(hash)),
+            Self::Anonymous => write!(f, "anonymous"),
+        }
+    }
+}
+
+/// Cumulative usage of an account.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub requests: u64,
+    /// The total size of the response bodies served.
+    pub bytes: u64,
+}
+
+/// A cap on the usage of an account in a period.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limit {
+    /// The maximum number of requests, or [None] for no maximum.
+    pub requests: Option<u64>,
+    /// The maximum number of bytes served, or [None] for no maximum.
+    pub bytes: Option<u64>,
+}
+
+impl Limit {
+    /// No limit.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn requests(mut self, max: u64) -> Self {
+        self.requests = Some(max);
+        self
+    }
+
+    pub fn bytes(mut self, max: u64) -> Self {
+        self.bytes = Some(max);
+        self
+    }
+
+    fn is_reached(&self, usage: &Usage) -> bool {
+        self.requests.map_or(false, |max| usage.requests >= max)
+            || self.bytes.map_or(false, |max| usage.bytes >= max)
+    }
+
+    // The usage left before `usage` reaches this limit.
+    fn remaining(&self, usage: &Usage) -> Limit {
+        Limit {
+            requests: self.requests.map(|max| max.saturating_sub(usage.requests)),
+            bytes: self.bytes.map(|max| max.saturating_sub(usage.bytes)),
+        }
+    }
+}
+
+/// Storage for the usage of each account.
+///
+/// Usage is stored per account, keyed by the [Display] form of the [Account], per [Period], per
+/// window, where a window is identified by its start time in seconds since the Unix epoch (see
+/// [Period::window]). A store may forget windows which have ended.
+#[tide::utils::async_trait]
+pub trait QuotaStore: Send + Sync {
+    /// The usage of `key` in the window of `period` starting at `start`.
+    async fn usage(&self, key: &str, period: Period, start: u64) -> Result<Usage, String>;
+
+    /// Add `usage` to the usage of `key` in the window of `period` starting at `start`.
+    async fn record(
+        &self,
+        key: &str,
+        period: Period,
+        start: u64,
+        usage: Usage,
+    ) -> Result<(), String>;
+}
+
+/// A [QuotaStore] which keeps usage in memory.
+///
+/// Only the usage of accounts which have been used in the current window of a period is kept, so
+/// memory is bounded by the number of accounts active in a day or a month.
+#[derive(Debug, Default)]
+pub struct MemoryQuotaStore {
+    // The current window of each key and period, and the usage in it.
+    usage: Mutex<HashMap<(String, Period), (u64, Usage)>>,
+    // The latest window of each period which has been recorded.
+    windows: Mutex<HashMap<Period, u64>>,
+}
+
+impl MemoryQuotaStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[tide::utils::async_trait]
+impl QuotaStore for MemoryQuotaStore {
+    async fn usage(&self, key: &str, period: Period, start: u64) -> Result<Usage, String> {
+        let usage = self.usage.lock().unwrap();
+        Ok(match usage.get(&(key.to_string(), period)) {
+            Some((window, usage)) if *window == start => *usage,
+            _ => Usage::default(),
+        })
+    }
+
+    async fn record(
+        &self,
+        key: &str,
+        period: Period,
+        start: u64,
+        usage: Usage,
+    ) -> Result<(), String> {
+        let mut entries = self.usage.lock().unwrap();
+        // When a new window starts, forget every key's usage from the windows which have ended.
+        let mut windows = self.windows.lock().unwrap();
+        let latest = windows.entry(period).or_insert(start);
+        if *latest < start {
+            *latest = start;
+            entries.retain(|(_, p), (window, _)| *p != period || *window >= start);
+        }
+        drop(windows);
+        let entry = entries
+            .entry((key.to_string(), period))
+            .or_insert((start, Usage::default()));
+        // Only the current window is kept; usage from earlier windows is forgotten.
+        if entry.0 < start {
+            *entry = (start, Usage::default());
+        }
+        if entry.0 == start {
+            entry.1.requests += usage.requests;
+            entry.1.bytes += usage.bytes;
+        }
+        Ok(())
+    }
+}
+
+/// The usage of an account in one period, as reported by the `/usage` endpoint.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeriodUsage {
+    pub period: Period,
+    /// The start of the current window, in seconds since the Unix epoch.
+    pub start: u64,
+    /// When usage is next reset, in seconds since the Unix epoch.
+    pub resets_at: u64,
+    pub used: Usage,
+    pub limit: Limit,
+    pub remaining: Limit,
+}
+
+/// The body of a `/usage` response.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageReport {
+    pub periods: Vec<PeriodUsage>,
+}
+
+impl SafeToServe for PeriodUsage {}
+impl SafeToServe for UsageReport {}
+
+/// Server middleware which accounts for the usage of each account and enforces its quota.
+///
+/// See the [module documentation](self).
+#[derive(Clone)]
+pub struct Quotas {
+    store: Arc<dyn QuotaStore>,
+    limits: HashMap<Period, Limit>,
+    account_limits: HashMap<Account, HashMap<Period, Limit>>,
+}
+
+impl Quotas {
+    /// Record usage in `store`, with no limits.
+    pub fn new(store: impl QuotaStore + 'static) -> Self {
+        Self {
+            store: Arc::new(store),
+            limits: HashMap::new(),
+            account_limits: HashMap::new(),
+        }
+    }
+
+    /// Record usage in a [MemoryQuotaStore], with no limits.
+    pub fn in_memory() -> Self {
+        Self::new(MemoryQuotaStore::new())
+    }
+
+    /// Limit the usage of each account without its own limit in `period`.
+    ///
+    /// This also limits [Account::Anonymous], which is shared by every client which has not
+    /// authenticated.
+    pub fn limit(mut self, period: Period, limit: Limit) -> Self {
+        self.limits.insert(period, limit);
+        self
+    }
+
+    /// Limit the usage of `account` in `period`, instead of the limit for all accounts.
+    pub fn account_limit(mut self, account: Account, period: Period, limit: Limit) -> Self {
+        self.account_limits
+            .entry(account)
+            .or_default()
+            .insert(period, limit);
+        self
+    }
+
+    fn limit_for(&self, account: &Account, period: Period) -> Limit {
+        self.account_limits
+            .get(account)
+            .and_then(|limits| limits.get(&period))
+            .or_else(|| self.limits.get(&period))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The usage of `account` in each period, as of `now`.
+    pub async fn report(&self, account: &Account, now: u64) -> Result<UsageReport, String> {
+        let key = account.to_string();
+        let mut periods = Vec::new();
+        for period in PERIODS {
+            let (start, resets_at) = period.window(now);
+            let used = self.store.usage(&key, period, start).await?;
+            let limit = self.limit_for(account, period);
+            periods.push(PeriodUsage {
+                period,
+                start,
+                resets_at,
+                used,
+                limit,
+                remaining: limit.remaining(&used),
+            });
+        }
+        Ok(UsageReport { periods })
+    }
+
+    /// Add a `/usage` endpoint to `app`, reporting the usage of the account of each request.
+    ///
+    /// Requests from [Account::Anonymous] fail with 401 Unauthorized. Requests to this endpoint do
+    /// not count towards the quota, and are served even when it has been used up.
+    pub fn serve<State: Clone + Send + Sync + 'static>(&self, app: &mut tide::Server<State>) {
+        let quotas = self.clone();
+        app.at(USAGE_PATH).get(move |req: tide::Request<State>| {
+            let quotas = quotas.clone();
+            async move {
+                let account = Account::of(&req);
+                if account == Account::Anonymous {
+                    return Err(tide::Error::from_str(
+                        StatusCode::Unauthorized,
+                        "authentication is required",
+                    ));
+                }
+                let report = quotas
+                    .report(&account, unix_time())
+                    .await
+                    .map_err(|err| tide::Error::from_str(StatusCode::ServiceUnavailable, err))?;
+                response(&req, report)
+            }
+        });
+    }
+}
+
+// The path of the usage endpoint, which is neither limited nor counted, so that clients can always
+// find out why their requests are being refused.
+const USAGE_PATH: &str = "/usage";
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn unavailable(err: String) -> Response {
+    let mut res = Response::new(StatusCode::ServiceUnavailable);
+    res.set_error(tide::Error::from_str(
+        StatusCode::ServiceUnavailable,
+        format!("quota store unavailable: {}", err),
+    ));
+    res
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Quotas {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if req.url().path() == USAGE_PATH {
+            return Ok(next.run(req).await);
+        }
+        let account = Account::of(&req);
+        let key = account.to_string();
+        let now = unix_time();
+
+        // Quotas are billing-grade, so if the store cannot say whether a key is within its quota,
+        // the request is refused rather than served for free.
+        for period in PERIODS {
+            let (start, end) = period.window(now);
+            let used = match self.store.usage(&key, period, start).await {
+                Ok(used) => used,
+                Err(err) => return Ok(unavailable(err)),
+            };
+            if self.limit_for(&account, period).is_reached(&used) {
+                let mut res = too_many_requests(Duration::from_secs(end - now));
+                res.set_error(tide::Error::from_str(
+                    StatusCode::TooManyRequests,
+                    format!(
+                        "{:?} quota exceeded, retry after {} seconds",
+                        period,
+                        end - now
+                    ),
+                ));
+                return Ok(res);
+            }
+        }
+
+        let mut res = next.run(req).await;
+        let usage = match res.len() {
+            Some(len) => Usage {
+                requests: 1,
+                bytes: len as u64,
+            },
+            None => {
+                // The size of a streamed body is only known once it has been read, so the bytes
+                // are recorded separately, when the body is dropped.
+                let store = self.store.clone();
+                let key = key.clone();
+                let body = res.take_body();
+                res.set_body(observe_body(
+                    body,
+                    0u64,
+                    |bytes, chunk| *bytes += chunk.len() as u64,
+                    move |bytes| {
+                        async_std::task::spawn(async move {
+                            let usage = Usage { requests: 0, bytes };
+                            record(&*store, &key, now, usage).await;
+                        });
+                    },
+                ));
+                Usage {
+                    requests: 1,
+                    bytes: 0,
+                }
+            }
+        };
+        record(&*self.store, &key, now, usage).await;
+        Ok(res)
+    }
+}
+
+async fn record(store: &dyn QuotaStore, key: &str, now: u64, usage: Usage) {
+    for period in PERIODS {
+        let (start, _) = period.window(now);
+        if let Err(err) = store.record(key, period, start, usage).await {
+            event!(
+                Level::ERROR,
+                "failed to record usage of {:?} for {}: {}",
+                period,
+                key,
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::authz::authenticate;
+    use tide::http::{Body, Method, Url};
+
+    #[test]
+    fn test_windows() {
+        // 2024-02-29T12:00:00Z, in a leap year.
+        let time = 1_709_208_000;
+        assert_eq!(Period::Daily.window(time), (1_709_164_800, 1_709_251_200));
+        // 2024-02-01 to 2024-03-01.
+        assert_eq!(Period::Monthly.window(time), (1_706_745_600, 1_709_251_200));
+        // 2023-12-31T23:59:59Z to 2023-12-01 and 2024-01-01.
+        assert_eq!(
+            Period::Monthly.window(1_704_067_199),
+            (1_701_388_800, 1_704_067_200)
+        );
+        assert_eq!(Period::Monthly.window(0), (0, 31 * SECONDS_PER_DAY));
+    }
+
+    async fn get(app: &tide::Server<()>, path: &str, key: Option<&str>) -> tide::http::Response {
+        let mut req = tide::http::Request::new(
+            Method::Get,
+            Url::parse("http://localhost").unwrap().join(path).unwrap(),
+        );
+        if let Some(key) = key {
+            req.insert_header("Authorization", format!("Bearer {}", key));
+        }
+        app.respond(req).await.unwrap()
+    }
+
+    // Stands in for middleware which checks API keys: it authenticates the keys it knows.
+    struct CheckKeys;
+
+    #[tide::utils::async_trait]
+    impl Middleware<()> for CheckKeys {
+        async fn handle(&self, mut req: Request<()>, next: Next<'_, ()>) -> tide::Result {
+            let key = req
+                .header("Authorization")
+                .and_then(|value| value.as_str().strip_prefix("Bearer "))
+                .map(String::from);
+            if let Some(key) = key.filter(|key| ["a", "b", "big", "stream"].contains(&key.as_str()))
+            {
+                authenticate(&mut req, Identity::ApiKey(key));
+            }
+            Ok(next.run(req).await)
+        }
+    }
+
+    #[async_std::test]
+    async fn test_quotas() {
+        let quotas = Quotas::in_memory()
+            .limit(Period::Daily, Limit::new().requests(3))
+            .account_limit(
+                Account::api_key("big"),
+                Period::Daily,
+                Limit::new().bytes(25),
+            );
+        let mut app = tide::new();
+        app.with(CheckKeys);
+        app.with(quotas.clone());
+        app.at("/data").get(|_| async { Ok("0123456789") });
+        quotas.serve(&mut app);
+
+        // Each key has its own quota.
+        for _ in 0..3 {
+            assert_eq!(get(&app, "/data", Some("a")).await.status(), StatusCode::Ok);
+        }
+        let res = get(&app, "/data", Some("a")).await;
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+        assert!(res.header("Retry-After").is_some());
+        assert_eq!(get(&app, "/data", Some("b")).await.status(), StatusCode::Ok);
+
+        // Clients which have not authenticated share one quota, so a made-up key does not get a
+        // fresh one.
+        for key in [None, Some("made-up"), Some("another")] {
+            assert_eq!(get(&app, "/data", key).await.status(), StatusCode::Ok);
+        }
+        assert_eq!(
+            get(&app, "/data", Some("yet-another")).await.status(),
+            StatusCode::TooManyRequests
+        );
+
+        // Keys can have their own limits, on bytes as well as requests.
+        for _ in 0..3 {
+            assert_eq!(
+                get(&app, "/data", Some("big")).await.status(),
+                StatusCode::Ok
+            );
+        }
+        assert_eq!(
+            get(&app, "/data", Some("big")).await.status(),
+            StatusCode::TooManyRequests
+        );
+
+        // Clients can see their usage.
+        let mut res = get(&app, "/usage", Some("b")).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        let report: UsageReport = res.body_json().await.unwrap();
+        let daily = &report.periods[0];
+        assert_eq!(daily.period, Period::Daily);
+        assert_eq!(
+            daily.used,
+            Usage {
+                requests: 1,
+                bytes: 10
+            }
+        );
+        assert_eq!(daily.remaining, Limit::new().requests(2));
+        assert_eq!(report.periods[1].limit, Limit::new());
+        assert_eq!(
+            get(&app, "/usage", Some("a")).await.status(),
+            StatusCode::Ok
+        );
+        for key in [None, Some("made-up")] {
+            assert_eq!(
+                get(&app, "/usage", key).await.status(),
+                StatusCode::Unauthorized
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn test_streamed_bytes() {
+        let quotas = Quotas::in_memory();
+        let mut app = tide::new();
+        app.with(CheckKeys);
+        app.with(quotas.clone());
+        app.at("/stream").get(|_| async {
+            let mut res = Response::new(StatusCode::Ok);
+            res.set_body(Body::from_reader(
+                futures::io::Cursor::new(vec![0u8; 100]),
+                None,
+            ));
+            Ok(res)
+        });
+
+        let mut res = get(&app, "/stream", Some("stream")).await;
+        assert_eq!(res.body_bytes().await.unwrap().len(), 100);
+        drop(res);
+
+        // The bytes are recorded in the background once the body has been dropped.
+        let account = Account::api_key("stream");
+        for _ in 0..100 {
+            let report = quotas.report(&account, unix_time()).await.unwrap();
+            if report.periods[0].used.bytes == 100 {
+                assert_eq!(report.periods[0].used.requests, 1);
+                return;
+            }
+            async_std::task::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("streamed bytes were not recorded");
+    }
+
+    #[async_std::test]
+    async fn test_forget_ended_windows() {
+        let store = MemoryQuotaStore::new();
+        let usage = Usage {
+            requests: 1,
+            bytes: 0,
+        };
+        for key in ["a", "b", "c"] {
+            store.record(key, Period::Daily, 0, usage).await.unwrap();
+        }
+        store.record("a", Period::Monthly, 0, usage).await.unwrap();
+        store
+            .record("d", Period::Daily, SECONDS_PER_DAY, usage)
+            .await
+            .unwrap();
+        // Daily usage from the first day is gone, but monthly usage is kept.
+        let entries = store.usage.lock().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.contains_key(&("a".to_string(), Period::Monthly)));
+    }
+}
@@ -0,0 +1,327 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Limiting the rate of requests to a server.
+//!
+//! The [rate_limit] middleware admits requests at a steady rate, with bursts up to a limit, using a
+//! token bucket shared by all clients. Requests beyond the limit fail with 429 Too Many Requests
+//! and a Retry-After header saying when a request will next be admitted.
+//!
+//! This protects the server as a whole. To limit clients separately, use [rate_limit_per_client],
+//! which gives each client address (see [client_info](super::forwarded::client_info)) its own
+//! bucket, or each client certificate (see [peer_identity](super::peer::peer_identity)) if clients
+//! authenticate with one, or identify clients as tenants and give each tenant its own limit with
+//! [Tenants](super::tenancy::Tenants).
+//!
+//! IPv6 clients are limited by their /64 prefix, since a single host is usually given a whole /64
+//! and can send from as many addresses in it as it likes. The limiter keeps buckets for at most
+//! [MAX_CLIENTS] clients, forgetting the least recently seen when there are more, so a flood of
+//! new addresses cannot exhaust the server's memory.
+
+use super::forwarded::client_info;
+use super::peer::peer_identity;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+/// The most clients [rate_limit_per_client] keeps buckets for.
+pub const MAX_CLIENTS: usize = 10_000;
+
+/// Server middleware which limits requests to `per_second` on average, with bursts of up to
+/// `burst` requests.
+///
+/// # Panics
+///
+/// Panics if `per_second` is not positive, or if `burst` is 0.
+pub fn rate_limit(per_second: f64, burst: u32) -> RateLimit {
+    assert!(per_second > 0.0, "rate limit must be positive");
+    assert!(burst > 0, "burst must allow at least one request");
+    RateLimit {
+        buckets: Arc::new(Mutex::new(Buckets::Shared(TokenBucket::new(
+            per_second,
+            burst as f64,
+        )))),
+    }
+}
+
+/// Server middleware which limits requests from each client to `per_second` on average, with
+/// bursts of up to `burst` requests.
+///
+/// Clients which present a certificate are identified by it, and others by their address, or the
+/// /64 prefix of their address for IPv6. Requests whose client address is unknown share one limit.
+///
+/// # Panics
+///
+/// Panics if `per_second` is not positive, or if `burst` is 0.
+pub fn rate_limit_per_client(per_second: f64, burst: u32) -> RateLimit {
+    assert!(per_second > 0.0, "rate limit must be positive");
+    assert!(burst > 0, "burst must allow at least one request");
+    RateLimit {
+        buckets: Arc::new(Mutex::new(Buckets::PerClient {
+            per_second,
+            burst: burst as f64,
+            clients: Default::default(),
+        })),
+    }
+}
+
+enum Buckets {
+    Shared(TokenBucket),
+    PerClient {
+        per_second: f64,
+        burst: f64,
+        clients: Clients,
+    },
+}
+
+// The key of a per-client bucket.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Client {
+    Addr(Option<IpAddr>),
+    Peer([u8; 32]),
+}
+
+impl Client {
+    fn addr(addr: Option<IpAddr>) -> Self {
+        Self::Addr(addr.map(|addr| match addr {
+            IpAddr::V4(_) => addr,
+            IpAddr::V6(v6) => match v6.segments() {
+                // An IPv4 address seen through a dual-stack socket.
+                [0, 0, 0, 0, 0, 0xffff, ..] => IpAddr::V4(v6.to_ipv4().unwrap()),
+                _ => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u64::MAX as u128))),
+            },
+        }))
+    }
+}
+
+// Per-client buckets, which forget the least recently used client when there are more than
+// [MAX_CLIENTS].
+#[derive(Default)]
+struct Clients {
+    buckets: HashMap<Client, (TokenBucket, u64)>,
+    // Clients by the time they were last used, counted in requests.
+    used: BTreeMap<u64, Client>,
+    requests: u64,
+}
+
+impl Clients {
+    fn get(&mut self, client: Client, per_second: f64, burst: f64) -> &mut TokenBucket {
+        let now = self.requests;
+        self.requests += 1;
+        if !self.buckets.contains_key(&client) && self.buckets.len() >= MAX_CLIENTS {
+            if let Some(oldest) = self.used.keys().next().copied() {
+                let evicted = self.used.remove(&oldest).unwrap();
+                self.buckets.remove(&evicted);
+            }
+        }
+        let (bucket, used) = self
+            .buckets
+            .entry(client)
+            .or_insert_with(|| (TokenBucket::new(per_second, burst), now));
+        self.used.remove(used);
+        *used = now;
+        self.used.insert(now, client);
+        bucket
+    }
+}
+
+impl Buckets {
+    fn take(&mut self, client: Client) -> Result<(), Duration> {
+        match self {
+            Self::Shared(bucket) => bucket.take(),
+            Self::PerClient {
+                per_second,
+                burst,
+                clients,
+            } => clients.get(client, *per_second, *burst).take(),
+        }
+    }
+}
+
+/// Server middleware which limits the rate of requests, created by [rate_limit] or
+/// [rate_limit_per_client].
+///
+/// Clones share the same limit.
+#[derive(Clone)]
+pub struct RateLimit {
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RateLimit {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let taken = {
+            let mut buckets = self.buckets.lock().unwrap();
+            let client = match &*buckets {
+                Buckets::Shared(_) => Client::Addr(None),
+                Buckets::PerClient { .. } => match peer_identity(&req) {
+                    Some(peer) => Client::Peer(peer.fingerprint),
+                    None => Client::addr(client_info(&req).addr),
+                },
+            };
+            buckets.take(client)
+        };
+        match taken {
+            Ok(()) => Ok(next.run(req).await),
+            Err(wait) => Ok(too_many_requests(wait)),
+        }
+    }
+}
+
+// A token bucket, which starts full.
+pub(crate) struct TokenBucket {
+    per_second: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(per_second: f64, burst: f64) -> Self {
+        Self {
+            per_second,
+            burst,
+            tokens: burst,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.burst);
+        self.updated = now;
+    }
+
+    // Take a token from the bucket, or return how long until one is available.
+    pub(crate) fn take(&mut self) -> Result<(), Duration> {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.per_second,
+            ))
+        }
+    }
+}
+
+// A 429 response telling the client to retry after `wait`, rounded up to whole seconds.
+pub(crate) fn too_many_requests(wait: Duration) -> Response {
+    let secs = wait.as_secs() + (wait.subsec_nanos() > 0) as u64;
+    let mut res = Response::new(StatusCode::TooManyRequests);
+    res.set_error(tide::Error::from_str(
+        StatusCode::TooManyRequests,
+        format!("rate limit exceeded, retry after {} seconds", secs),
+    ));
+    res.insert_header("Retry-After", secs.to_string());
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::peer::PeerIdentity;
+    use tide::http::{Method, Url};
+
+    #[async_std::test]
+    async fn test_rate_limit() {
+        let mut app = tide::new();
+        app.with(rate_limit(0.001, 2));
+        app.at("/").get(|_| async { Ok("ok") });
+        let get = || {
+            let req =
+                tide::http::Request::new(Method::Get, Url::parse("http://localhost/").unwrap());
+            app.respond::<_, tide::http::Response>(req)
+        };
+
+        // The burst is allowed, and then requests are limited.
+        for _ in 0..2 {
+            assert_eq!(get().await.unwrap().status(), StatusCode::Ok);
+        }
+        let res = get().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+        assert!(res["Retry-After"].as_str().parse::<u64>().unwrap() > 0);
+    }
+
+    #[async_std::test]
+    async fn test_rate_limit_per_client() {
+        let mut app = tide::new();
+        app.with(rate_limit_per_client(0.001, 1));
+        app.at("/").get(|_| async { Ok("ok") });
+        let get = |peer: &'static str| {
+            let mut req =
+                tide::http::Request::new(Method::Get, Url::parse("http://localhost/").unwrap());
+            req.set_peer_addr(Some(peer));
+            app.respond::<_, tide::http::Response>(req)
+        };
+
+        // Each client has its own limit.
+        assert_eq!(get("1.2.3.4:1").await.unwrap().status(), StatusCode::Ok);
+        assert_eq!(
+            get("1.2.3.4:2").await.unwrap().status(),
+            StatusCode::TooManyRequests
+        );
+        assert_eq!(get("5.6.7.8:1").await.unwrap().status(), StatusCode::Ok);
+
+        // IPv6 clients are limited by /64, and IPv4 clients seen over IPv6 by their IPv4 address.
+        assert_eq!(
+            get("[2001:db8::1]:1").await.unwrap().status(),
+            StatusCode::Ok
+        );
+        assert_eq!(
+            get("[2001:db8::2]:1").await.unwrap().status(),
+            StatusCode::TooManyRequests
+        );
+        assert_eq!(
+            get("[2001:db8:0:1::1]:1").await.unwrap().status(),
+            StatusCode::Ok
+        );
+        assert_eq!(
+            get("[::ffff:5.6.7.8]:1").await.unwrap().status(),
+            StatusCode::TooManyRequests
+        );
+
+        // Clients with certificates are limited by certificate, not address.
+        let get_as = |fingerprint: u8| {
+            let mut req =
+                tide::http::Request::new(Method::Get, Url::parse("http://localhost/").unwrap());
+            req.set_peer_addr(Some("1.2.3.4:1"));
+            req.set_ext(PeerIdentity {
+                subject: "CN=relayer".into(),
+                san: vec![],
+                fingerprint: [fingerprint; 32],
+            });
+            app.respond::<_, tide::http::Response>(req)
+        };
+        assert_eq!(get_as(1).await.unwrap().status(), StatusCode::Ok);
+        assert_eq!(get_as(2).await.unwrap().status(), StatusCode::Ok);
+        assert_eq!(
+            get_as(1).await.unwrap().status(),
+            StatusCode::TooManyRequests
+        );
+    }
+
+    #[test]
+    fn test_forget_least_recently_used() {
+        let mut clients = Clients::default();
+        let client = |i: u32| Client::Addr(Some(IpAddr::from(std::net::Ipv4Addr::from(i))));
+        for i in 0..MAX_CLIENTS as u32 {
+            clients.get(client(i), 0.001, 1.0).take().unwrap();
+        }
+        // Seeing the first client again makes the second the least recently used.
+        assert!(clients.get(client(0), 0.001, 1.0).take().is_err());
+        clients.get(client(MAX_CLIENTS as u32), 0.001, 1.0);
+        assert_eq!(clients.buckets.len(), MAX_CLIENTS);
+        assert_eq!(clients.used.len(), MAX_CLIENTS);
+        assert!(clients.buckets.contains_key(&client(0)));
+        assert!(!clients.buckets.contains_key(&client(1)));
+    }
+}
@@ -0,0 +1,456 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Rejecting replayed nonces, like solved challenges and idempotency keys.
+//!
+//! Some values are meant to be accepted only once: a proof-of-work solution (see
+//! [Challenges](crate::challenge::Challenges)), a signed request's nonce, or an idempotency key
+//! whose request is already being handled (see [Idempotency](super::Idempotency)). A server which
+//! accepts them has to remember each one until it expires, and it has to check for a nonce and
+//! record it in a single step: a server which checks first and records after lets two concurrent
+//! copies of a request both pass the check.
+//!
+//! A [ReplayStore] does both atomically. [MemoryReplayStore] is enough for a single replica which
+//! can afford to forget nonces when it restarts, and with the `sled` feature, [SledReplayStore]
+//! keeps them on disk. Replicas which must each reject the nonces seen by the others should
+//! implement the trait on their shared database. [Replays] wraps a store, forgets expired nonces
+//! from time to time in a background task, and counts what it sees:
+//!
+//! ```
+//! use net::server::replay::{ReplayError, Replays};
+//! use std::time::Duration;
+//!
+//! # async_std::task::block_on(async {
+//! let replays = Replays::in_memory();
+//! let ttl = Duration::from_secs(60);
+//! assert_eq!(replays.check_for(b"nonce", ttl).await, Ok(()));
+//! assert_eq!(replays.check_for(b"nonce", ttl).await, Err(ReplayError::Replayed));
+//! assert_eq!(replays.stats().replayed, 1);
+//! # });
+//! ```
+
+use snafu::Snafu;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tide::StatusCode;
+use tracing::{event, Level};
+
+#[cfg(feature = "sled")]
+pub use sled_store::SledReplayStore;
+
+/// Storage for the nonces which have been seen, until they expire.
+///
+/// Times are in seconds since the Unix epoch, and a nonce has expired at `now` if its expiry is no
+/// later than `now`.
+#[tide::utils::async_trait]
+pub trait ReplayStore: Send + Sync {
+    /// Record `nonce` as seen until `expires`, unless it has been seen already.
+    ///
+    /// Returns `true` if the nonce is new, or was seen before but has since expired, and `false` if
+    /// it is a replay. The check and the record must be a single atomic step, so that of several
+    /// concurrent inserts of the same nonce, only one returns `true`.
+    async fn insert(&self, nonce: &[u8], expires: u64, now: u64) -> Result<bool, String>;
+
+    /// Forget `nonce` before it expires, so that it can be used again.
+    async fn remove(&self, nonce: &[u8]) -> Result<(), String>;
+
+    /// Forget the nonces which have expired at `now`, returning how many were forgotten.
+    async fn remove_expired(&self, now: u64) -> Result<usize, String>;
+}
+
+/// A [ReplayStore] which keeps nonces in memory.
+#[derive(Debug, Default)]
+pub struct MemoryReplayStore {
+    // The expiry of each nonce.
+    nonces: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl MemoryReplayStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of nonces stored, including those which have expired but not yet been removed.
+    pub fn len(&self) -> usize {
+        self.nonces.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[tide::utils::async_trait]
+impl ReplayStore for MemoryReplayStore {
+    async fn insert(&self, nonce: &[u8], expires: u64, now: u64) -> Result<bool, String> {
+        let mut nonces = self.nonces.lock().unwrap();
+        match nonces.get_mut(nonce) {
+            Some(expiry) if *expiry > now => Ok(false),
+            Some(expiry) => {
+                *expiry = expires;
+                Ok(true)
+            }
+            None => {
+                nonces.insert(nonce.to_vec(), expires);
+                Ok(true)
+            }
+        }
+    }
+
+    async fn remove(&self, nonce: &[u8]) -> Result<(), String> {
+        self.nonces.lock().unwrap().remove(nonce);
+        Ok(())
+    }
+
+    async fn remove_expired(&self, now: u64) -> Result<usize, String> {
+        let mut nonces = self.nonces.lock().unwrap();
+        let before = nonces.len();
+        nonces.retain(|_, expires| *expires > now);
+        Ok(before - nonces.len())
+    }
+}
+
+#[cfg(feature = "sled")]
+mod sled_store {
+    use super::ReplayStore;
+    use std::convert::TryInto;
+    use std::path::Path;
+
+    /// A [ReplayStore] which keeps nonces in a [sled] database, so they survive restarts.
+    #[derive(Clone, Debug)]
+    pub struct SledReplayStore {
+        tree: sled::Tree,
+    }
+
+    impl SledReplayStore {
+        /// A store in `tree`, which should not be used for anything else.
+        pub fn new(tree: sled::Tree) -> Self {
+            Self { tree }
+        }
+
+        /// A store in the database at `path`, which is created if it does not exist.
+        pub fn open(path: impl AsRef<Path>) -> Result<Self, String> {
+            let db = sled::open(path).map_err(|err| err.to_string())?;
+            let tree = db.open_tree("replay").map_err(|err| err.to_string())?;
+            Ok(Self::new(tree))
+        }
+    }
+
+    // An entry which cannot be decoded is treated as expired, so it is replaced or removed.
+    fn expiry(value: &[u8]) -> u64 {
+        value.try_into().map(u64::from_be_bytes).unwrap_or(0)
+    }
+
+    #[tide::utils::async_trait]
+    impl ReplayStore for SledReplayStore {
+        async fn insert(&self, nonce: &[u8], expires: u64, now: u64) -> Result<bool, String> {
+            loop {
+                let current = self.tree.get(nonce).map_err(|err| err.to_string())?;
+                if matches!(&current, Some(value) if expiry(value) > now) {
+                    return Ok(false);
+                }
+                // If another insert or removal changes the entry in between, look again.
+                let swapped = self
+                    .tree
+                    .compare_and_swap(nonce, current, Some(expires.to_be_bytes().to_vec()))
+                    .map_err(|err| err.to_string())?;
+                if swapped.is_ok() {
+                    return Ok(true);
+                }
+            }
+        }
+
+        async fn remove(&self, nonce: &[u8]) -> Result<(), String> {
+            self.tree.remove(nonce).map_err(|err| err.to_string())?;
+            Ok(())
+        }
+
+        async fn remove_expired(&self, now: u64) -> Result<usize, String> {
+            // This scans the whole tree, so keep it off the executor's threads.
+            let tree = self.tree.clone();
+            async_std::task::spawn_blocking(move || {
+                let mut removed = 0;
+                for entry in tree.iter() {
+                    let (nonce, value) = entry.map_err(|err| err.to_string())?;
+                    if expiry(&value) > now {
+                        continue;
+                    }
+                    // Only remove the entry if it has not been replaced in the meantime.
+                    let swapped = tree
+                        .compare_and_swap(nonce, Some(value), None::<sled::IVec>)
+                        .map_err(|err| err.to_string())?;
+                    removed += swapped.is_ok() as usize;
+                }
+                Ok(removed)
+            })
+            .await
+        }
+    }
+}
+
+/// Why a nonce was not accepted.
+#[derive(Clone, Debug, Snafu, PartialEq, Eq)]
+pub enum ReplayError {
+    #[snafu(display("this request has already been seen"))]
+    Replayed,
+    #[snafu(display("failed to check for replays: {}", reason))]
+    Store { reason: String },
+}
+
+impl ReplayError {
+    /// The status of a response to a request whose nonce was not accepted.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Replayed => StatusCode::Conflict,
+            Self::Store { .. } => StatusCode::ServiceUnavailable,
+        }
+    }
+}
+
+impl From<ReplayError> for tide::Error {
+    fn from(err: ReplayError) -> Self {
+        tide::Error::new(err.status(), err)
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    accepted: AtomicU64,
+    replayed: AtomicU64,
+    failed: AtomicU64,
+    collected: AtomicU64,
+}
+
+/// Statistics about the nonces checked by [Replays].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    /// Nonces which were new.
+    pub accepted: u64,
+    /// Nonces which were rejected as replays.
+    pub replayed: u64,
+    /// Nonces which could not be checked, because the store failed.
+    pub failed: u64,
+    /// Expired nonces which have been removed from the store.
+    pub collected: u64,
+}
+
+/// A [ReplayStore], with garbage collection and statistics.
+///
+/// Clones share the same store and statistics. See the [module documentation](self).
+#[derive(Clone)]
+pub struct Replays {
+    store: Arc<dyn ReplayStore>,
+    gc_interval: Duration,
+    // The time after which the next check should remove expired nonces.
+    next_gc: Arc<AtomicU64>,
+    counters: Arc<Counters>,
+}
+
+impl Replays {
+    /// Check nonces against `store`.
+    ///
+    /// By default, expired nonces are removed from the store once a minute.
+    pub fn new(store: impl ReplayStore + 'static) -> Self {
+        Self {
+            store: Arc::new(store),
+            gc_interval: Duration::from_secs(60),
+            next_gc: Default::default(),
+            counters: Default::default(),
+        }
+    }
+
+    /// Check nonces against a new [MemoryReplayStore].
+    pub fn in_memory() -> Self {
+        Self::new(MemoryReplayStore::new())
+    }
+
+    /// Remove expired nonces from the store at most once every `interval`.
+    ///
+    /// Whichever check comes first after the interval has passed starts a task to remove expired
+    /// nonces, without waiting for it, so no task needs to run between collections and no request
+    /// waits for one.
+    pub fn gc_interval(mut self, interval: Duration) -> Self {
+        self.gc_interval = interval;
+        self
+    }
+
+    /// Accept `nonce` if it has not been seen, and remember it until `expires`, in seconds since
+    /// the Unix epoch.
+    pub async fn check(&self, nonce: impl AsRef<[u8]>, expires: u64) -> Result<(), ReplayError> {
+        let now = unix_time();
+        self.collect_garbage(now);
+        match self.store.insert(nonce.as_ref(), expires, now).await {
+            Ok(true) => {
+                self.counters.accepted.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Ok(false) => {
+                self.counters.replayed.fetch_add(1, Ordering::Relaxed);
+                Err(ReplayError::Replayed)
+            }
+            Err(reason) => {
+                self.counters.failed.fetch_add(1, Ordering::Relaxed);
+                event!(Level::ERROR, "replay store failed: {}", reason);
+                Err(ReplayError::Store { reason })
+            }
+        }
+    }
+
+    /// Accept `nonce` if it has not been seen, and remember it for `ttl`.
+    ///
+    /// Expiry times are in whole seconds, so `ttl` is rounded up to a whole number of seconds.
+    pub async fn check_for(
+        &self,
+        nonce: impl AsRef<[u8]>,
+        ttl: Duration,
+    ) -> Result<(), ReplayError> {
+        let secs = ttl
+            .as_secs()
+            .saturating_add((ttl.subsec_nanos() > 0) as u64);
+        self.check(nonce, unix_time().saturating_add(secs)).await
+    }
+
+    /// Forget `nonce`, so that it is accepted again.
+    ///
+    /// This is for nonces which were accepted for work which then failed, and can be retried.
+    pub async fn release(&self, nonce: impl AsRef<[u8]>) -> Result<(), ReplayError> {
+        self.store
+            .remove(nonce.as_ref())
+            .await
+            .map_err(|reason| ReplayError::Store { reason })
+    }
+
+    pub fn stats(&self) -> ReplayStats {
+        ReplayStats {
+            accepted: self.counters.accepted.load(Ordering::Relaxed),
+            replayed: self.counters.replayed.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+            collected: self.counters.collected.load(Ordering::Relaxed),
+        }
+    }
+
+    fn collect_garbage(&self, now: u64) {
+        let next = self.next_gc.load(Ordering::Relaxed);
+        // Only the check which moves the next collection forward does this one.
+        if now < next
+            || self
+                .next_gc
+                .compare_exchange(
+                    next,
+                    now.saturating_add(self.gc_interval.as_secs()),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_err()
+        {
+            return;
+        }
+        let store = self.store.clone();
+        let counters = self.counters.clone();
+        async_std::task::spawn(async move {
+            match store.remove_expired(now).await {
+                Ok(removed) => {
+                    counters
+                        .collected
+                        .fetch_add(removed as u64, Ordering::Relaxed);
+                }
+                Err(reason) => event!(Level::WARN, "failed to remove expired nonces: {}", reason),
+            }
+        });
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn test_store(store: &impl ReplayStore) {
+        assert_eq!(store.insert(b"a", 10, 5).await, Ok(true));
+        assert_eq!(store.insert(b"a", 10, 6).await, Ok(false));
+        assert_eq!(store.insert(b"b", 20, 6).await, Ok(true));
+
+        // An expired nonce can be used again.
+        assert_eq!(store.insert(b"a", 30, 10).await, Ok(true));
+        assert_eq!(store.insert(b"a", 30, 11).await, Ok(false));
+
+        assert_eq!(store.remove_expired(20).await, Ok(1));
+        assert_eq!(store.insert(b"b", 40, 20).await, Ok(true));
+        assert_eq!(store.insert(b"a", 40, 20).await, Ok(false));
+
+        // A removed nonce can be used again before it expires.
+        assert_eq!(store.remove(b"a").await, Ok(()));
+        assert_eq!(store.insert(b"a", 40, 20).await, Ok(true));
+    }
+
+    #[async_std::test]
+    async fn test_memory_store() {
+        let store = MemoryReplayStore::new();
+        test_store(&store).await;
+        assert_eq!(store.len(), 2);
+    }
+
+    #[cfg(feature = "sled")]
+    #[async_std::test]
+    async fn test_sled_store() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        test_store(&SledReplayStore::new(db.open_tree("replay").unwrap())).await;
+    }
+
+    #[async_std::test]
+    async fn test_replays() {
+        let replays = Replays::in_memory().gc_interval(Duration::ZERO);
+        let expired = unix_time() - 1;
+        assert_eq!(replays.check(b"old", expired).await, Ok(()));
+        assert_eq!(
+            replays.check_for(b"new", Duration::from_secs(60)).await,
+            Ok(())
+        );
+        assert_eq!(
+            replays.check_for(b"new", Duration::from_secs(60)).await,
+            Err(ReplayError::Replayed)
+        );
+
+        // Expired nonces are collected in the background.
+        async_std::future::timeout(Duration::from_secs(5), async {
+            while replays.stats().collected == 0 {
+                async_std::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            replays.stats(),
+            ReplayStats {
+                accepted: 2,
+                replayed: 1,
+                failed: 0,
+                collected: 1,
+            }
+        );
+    }
+
+    #[async_std::test]
+    async fn test_sub_second_ttl() {
+        // A nonce is remembered for at least as long as asked, even for less than a second.
+        let replays = Replays::in_memory();
+        let ttl = Duration::from_millis(500);
+        assert_eq!(replays.check_for(b"nonce", ttl).await, Ok(()));
+        assert_eq!(
+            replays.check_for(b"nonce", ttl).await,
+            Err(ReplayError::Replayed)
+        );
+    }
+}
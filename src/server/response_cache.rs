@@ -0,0 +1,308 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Cached response bodies for hot, immutable resources.
+//!
+//! Serializing a large resource (like a block) on every request wastes CPU when the resource never
+//! changes. A [BodyCache] stores the serialized (and optionally gzip-compressed) bodies of
+//! resources, keyed by an application-defined key such as a block height, so that handlers can
+//! respond with the cached bytes instead. Bodies are serialized lazily on the first request for
+//! each content type, or eagerly with [BodyCache::precompute], which moves the work off the request
+//! path entirely.
+//!
+//! Cached bodies are shared, not copied, between responses. When compression is enabled, responses
+//! have a `Vary: Accept-Encoding` header, so that HTTP caches keep the two encodings apart.
+
+use super::{best_response_type, negotiated_type, serialize_body, RESPONSE_TYPES};
+use flate2::{write::GzEncoder, Compression};
+use futures::io::Cursor;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::hash::Hash;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tide::http::content::{Accept, AcceptEncoding, Encoding};
+use tide::http::{Body, Mime};
+use tide::{Request, Response, StatusCode};
+
+// The serialized body of a resource in one content type.
+struct Encoded {
+    identity: Arc<[u8]>,
+    gzip: Option<Arc<[u8]>>,
+}
+
+struct Shared(Arc<[u8]>);
+
+impl AsRef<[u8]> for Shared {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+fn shared_body(bytes: &Arc<[u8]>) -> Body {
+    Body::from_reader(Cursor::new(Shared(bytes.clone())), Some(bytes.len()))
+}
+
+struct Entries<K> {
+    bodies: HashMap<(K, String), Arc<Encoded>>,
+    // Keys in insertion order, for eviction.
+    order: VecDeque<(K, String)>,
+}
+
+/// A cache of serialized response bodies.
+pub struct BodyCache<K> {
+    entries: Arc<Mutex<Entries<K>>>,
+    max_entries: usize,
+    compress: bool,
+}
+
+impl<K> Clone for BodyCache<K> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+            max_entries: self.max_entries,
+            compress: self.compress,
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash> BodyCache<K> {
+    /// Cache the bodies of at most `max_entries` resources.
+    ///
+    /// Each content type of a resource counts as a separate entry. When the cache is full, the
+    /// oldest entries are evicted.
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(Entries {
+                bodies: HashMap::new(),
+                order: VecDeque::new(),
+            })),
+            max_entries,
+            compress: false,
+        }
+    }
+
+    /// Also cache a gzip-compressed copy of each body, for clients which accept it.
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Serialize `body` in every supported content type and cache the results under `key`.
+    pub fn precompute<T: Serialize>(&self, key: K, body: &T) -> Result<(), tide::Error> {
+        for ty in &RESPONSE_TYPES {
+            self.insert(key.clone(), ty, body)?;
+        }
+        Ok(())
+    }
+
+    /// Respond with the body cached under `key`.
+    ///
+    /// If there is no body cached under `key` in the content type requested by the client,
+    /// `compute` is called to get the resource, which is then serialized and cached.
+    pub async fn respond<S, T, F, Fut>(
+        &self,
+        req: &Request<S>,
+        key: K,
+        compute: F,
+    ) -> Result<Response, tide::Error>
+    where
+        T: Serialize,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, tide::Error>>,
+    {
+        let ty = match negotiated_type(req.ext(), &RESPONSE_TYPES) {
+            Some(ty) => ty,
+            None => best_response_type(&mut Accept::from_headers(req)?, &RESPONSE_TYPES)?,
+        };
+        let encoded = match self.get(&key, &ty) {
+            Some(encoded) => encoded,
+            None => self.insert(key, &ty, &compute().await?)?,
+        };
+
+        let mut res = Response::new(StatusCode::Ok);
+        if encoded.gzip.is_some() {
+            // Caches between us and the client must not serve one encoding to a client which asked
+            // for the other.
+            res.append_header("Vary", "Accept-Encoding");
+        }
+        match &encoded.gzip {
+            Some(gzip) if accepts_gzip(req)? => {
+                res.set_body(shared_body(gzip));
+                res.insert_header("Content-Encoding", "gzip");
+            }
+            _ => res.set_body(shared_body(&encoded.identity)),
+        }
+        res.set_content_type(ty);
+        Ok(res)
+    }
+
+    /// Remove the bodies cached under `key`.
+    pub fn invalidate(&self, key: &K) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.bodies.retain(|(k, _), _| k != key);
+        entries.order.retain(|(k, _)| k != key);
+    }
+
+    /// Remove all cached bodies.
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.bodies.clear();
+        entries.order.clear();
+    }
+
+    /// The number of cached bodies.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().bodies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn get(&self, key: &K, ty: &Mime) -> Option<Arc<Encoded>> {
+        self.entries
+            .lock()
+            .unwrap()
+            .bodies
+            .get(&(key.clone(), ty.essence().to_string()))
+            .cloned()
+    }
+
+    fn insert<T: Serialize>(
+        &self,
+        key: K,
+        ty: &Mime,
+        body: &T,
+    ) -> Result<Arc<Encoded>, tide::Error> {
+        // Serialize and compress before taking the lock, so that other requests are not blocked.
+        let identity = serialize_body(ty, body)?;
+        let gzip = if self.compress {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&identity)?;
+            Some(encoder.finish()?.into())
+        } else {
+            None
+        };
+        let encoded = Arc::new(Encoded {
+            identity: identity.into(),
+            gzip,
+        });
+
+        let mut entries = self.entries.lock().unwrap();
+        let key = (key, ty.essence().to_string());
+        if entries
+            .bodies
+            .insert(key.clone(), encoded.clone())
+            .is_none()
+        {
+            entries.order.push_back(key);
+        }
+        while entries.bodies.len() > self.max_entries {
+            match entries.order.pop_front() {
+                Some(oldest) => {
+                    entries.bodies.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+        Ok(encoded)
+    }
+}
+
+fn accepts_gzip<S>(req: &Request<S>) -> Result<bool, tide::Error> {
+    Ok(match AcceptEncoding::from_headers(req)? {
+        Some(mut accept) => matches!(
+            accept.negotiate(&[Encoding::Gzip, Encoding::Identity]),
+            Ok(encoding) if encoding.encoding() == Encoding::Gzip
+        ),
+        None => false,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tide::http::{Method, Url};
+
+    #[async_std::test]
+    async fn test_body_cache() {
+        let cache = BodyCache::new(10).compress(true);
+        let computed = Arc::new(AtomicUsize::new(0));
+        let mut app = tide::new();
+        app.at("/block/:height").get({
+            let cache = cache.clone();
+            let computed = computed.clone();
+            move |req: tide::Request<()>| {
+                let cache = cache.clone();
+                let computed = computed.clone();
+                async move {
+                    let height: u64 = req.param("height")?.parse()?;
+                    cache
+                        .respond(&req, height, || async {
+                            computed.fetch_add(1, Ordering::SeqCst);
+                            Ok(vec![height; 100])
+                        })
+                        .await
+                }
+            }
+        });
+
+        let get = |encoding: Option<&'static str>| {
+            let mut req = tide::http::Request::new(
+                Method::Get,
+                Url::parse("http://localhost/block/7").unwrap(),
+            );
+            req.insert_header("Accept", "application/json");
+            if let Some(encoding) = encoding {
+                req.insert_header("Accept-Encoding", encoding);
+            }
+            let app = app.clone();
+            async move {
+                let mut res: tide::http::Response = app.respond(req).await.unwrap();
+                let encoding = res
+                    .header("Content-Encoding")
+                    .map(|h| h.as_str().to_string());
+                assert_eq!(res["Vary"], "Accept-Encoding");
+                (encoding, res.body_bytes().await.unwrap())
+            }
+        };
+
+        let expected = serde_json::to_vec(&vec![7u64; 100]).unwrap();
+        assert_eq!(get(None).await, (None, expected.clone()));
+        let (encoding, compressed) = get(Some("gzip")).await;
+        assert_eq!(encoding.as_deref(), Some("gzip"));
+        let mut decompressed = Vec::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, expected);
+
+        // The body was only computed once.
+        assert_eq!(computed.load(Ordering::SeqCst), 1);
+
+        // After invalidation, the body is computed again.
+        cache.invalidate(&7);
+        assert!(cache.is_empty());
+        get(None).await;
+        assert_eq!(computed.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let cache = BodyCache::new(2);
+        cache.precompute(0, &0).unwrap();
+        cache.precompute(1, &1).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&0, &RESPONSE_TYPES[0]).is_none());
+        assert!(cache.get(&1, &RESPONSE_TYPES[0]).is_some());
+    }
+}
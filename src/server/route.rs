@@ -0,0 +1,158 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Matching request paths against route templates.
+//!
+//! tide does not tell middleware which route a request matched, so middleware which needs to
+//! aggregate or configure by route is given the route templates explicitly, using the same syntax
+//! as `tide::Server::at`: literal segments, `:name` parameters which match any one segment, and a
+//! trailing `*` wildcard which matches any number of segments.
+
+/// A route template, like `/block/:id`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteTemplate {
+    template: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param,
+    Wildcard,
+}
+
+impl RouteTemplate {
+    pub fn new(template: impl Into<String>) -> Self {
+        let template = template.into();
+        let segments = split(&template)
+            .map(|segment| {
+                if segment.starts_with(':') {
+                    Segment::Param
+                } else if segment.starts_with('*') {
+                    Segment::Wildcard
+                } else {
+                    Segment::Literal(segment.to_string())
+                }
+            })
+            .collect();
+        Self { template, segments }
+    }
+
+    /// The template string, suitable for use as a metrics label.
+    pub fn as_str(&self) -> &str {
+        &self.template
+    }
+
+    /// Whether `path` matches this template.
+    pub fn matches(&self, path: &str) -> bool {
+        let mut path = split(path);
+        for segment in &self.segments {
+            match segment {
+                Segment::Wildcard => return true,
+                Segment::Param => {
+                    if path.next().is_none() {
+                        return false;
+                    }
+                }
+                Segment::Literal(literal) => {
+                    if path.next() != Some(literal.as_str()) {
+                        return false;
+                    }
+                }
+            }
+        }
+        path.next().is_none()
+    }
+
+    // A score used to choose between templates which match the same path: literal segments are
+    // more specific than parameters, which are more specific than wildcards.
+    fn specificity(&self) -> (usize, usize, bool) {
+        let literals = self
+            .segments
+            .iter()
+            .filter(|segment| matches!(segment, Segment::Literal(_)))
+            .count();
+        let params = self
+            .segments
+            .iter()
+            .filter(|segment| matches!(segment, Segment::Param))
+            .count();
+        let wildcard = self.segments.contains(&Segment::Wildcard);
+        (literals, params, !wildcard)
+    }
+}
+
+fn split(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+/// A collection of route templates, each associated with a value.
+#[derive(Clone, Debug)]
+pub struct RouteTable<T> {
+    routes: Vec<(RouteTemplate, T)>,
+}
+
+impl<T> Default for RouteTable<T> {
+    fn default() -> Self {
+        Self { routes: Vec::new() }
+    }
+}
+
+impl<T> RouteTable<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, template: impl Into<String>, value: T) {
+        self.routes.push((RouteTemplate::new(template), value));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Find the most specific template matching `path`, and its value.
+    pub fn lookup(&self, path: &str) -> Option<(&RouteTemplate, &T)> {
+        self.routes
+            .iter()
+            .filter(|(template, _)| template.matches(path))
+            .max_by_key(|(template, _)| template.specificity())
+            .map(|(template, value)| (template, value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let template = RouteTemplate::new("/block/:id");
+        assert!(template.matches("/block/5"));
+        assert!(template.matches("block/5/"));
+        assert!(!template.matches("/block"));
+        assert!(!template.matches("/block/5/6"));
+        assert!(!template.matches("/transaction/5"));
+
+        let template = RouteTemplate::new("/static/*");
+        assert!(template.matches("/static"));
+        assert!(template.matches("/static/js/app.js"));
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut table = RouteTable::new();
+        table.insert("/block/*", 0);
+        table.insert("/block/:id", 1);
+        table.insert("/block/latest", 2);
+        assert_eq!(*table.lookup("/block/latest").unwrap().1, 2);
+        assert_eq!(*table.lookup("/block/5").unwrap().1, 1);
+        assert_eq!(*table.lookup("/block/5/transactions").unwrap().1, 0);
+        assert!(table.lookup("/status").is_none());
+    }
+}
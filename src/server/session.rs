@@ -0,0 +1,523 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Sessions stored in signed cookies.
+//!
+//! Most routes of an Espresso service are stateless API routes, and should stay that way. A few
+//! routes are used from a browser, though, like the faucet's captcha flow or an admin UI, and need
+//! to remember something between requests. The [Sessions] middleware keeps that state in a cookie
+//! on the client, so the server still stores nothing: the session is serialized as JSON and signed
+//! with an HMAC, and a cookie whose signature does not match is ignored.
+//!
+//! Sessions can be signed with the keys in a [Keyring], so that the secret can be rotated without
+//! ending the sessions signed with the old one (see [Sessions::with_keyring]).
+//!
+//! The cookie is signed, not encrypted. The client can read the session, so it must not contain
+//! secrets; what the signature guarantees is that the client cannot change it.
+//!
+//! Sessions should only be added to the routes which need them, with `app.at(..).with(..)`, so that
+//! API routes neither read nor set the cookie:
+//!
+//! ```
+//! use net::server::session::{session, Sessions};
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Clone, Serialize, Deserialize)]
+//! struct Captcha {
+//!     solved: bool,
+//! }
+//!
+//! let mut app = tide::new();
+//! app.at("/faucet")
+//!     .with(Sessions::<Captcha>::new([0x42; 32]))
+//!     .post(|req: tide::Request<()>| async move {
+//!         let session = session::<Captcha, _>(&req).unwrap();
+//!         if !matches!(session.get(), Some(Captcha { solved: true })) {
+//!             return Ok(tide::Response::new(403));
+//!         }
+//!         session.clear();
+//!         Ok(tide::Response::new(200))
+//!     });
+//! ```
+
+use crate::keyring::Keyring;
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tide::http::cookies::SameSite;
+use tide::{Middleware, Next, Request};
+
+/// The default name of the session cookie.
+pub const DEFAULT_COOKIE_NAME: &str = "net_session";
+
+/// The minimum length of the signing secret, in bytes.
+pub const MIN_SECRET_LEN: usize = crate::keyring::MIN_SECRET_LEN;
+
+#[derive(Debug)]
+struct State<T> {
+    data: Option<T>,
+    changed: bool,
+}
+
+/// The session of a request, available to handlers through [session].
+///
+/// Clones of a session refer to the same session, so changes made by a handler are seen by the
+/// [Sessions] middleware when it sets the cookie.
+#[derive(Debug)]
+pub struct Session<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Clone for Session<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T: Clone> Session<T> {
+    fn new(data: Option<T>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                data,
+                changed: false,
+            })),
+        }
+    }
+
+    /// The data of the session, or [None] if the request had no valid session cookie.
+    pub fn get(&self) -> Option<T> {
+        self.state.lock().unwrap().data.clone()
+    }
+
+    /// Replace the data of the session.
+    ///
+    /// The response sets a new cookie, which expires one time to live from now.
+    pub fn set(&self, data: T) {
+        let mut state = self.state.lock().unwrap();
+        state.data = Some(data);
+        state.changed = true;
+    }
+
+    /// End the session.
+    ///
+    /// The response removes the cookie from the client.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.data = None;
+        state.changed = true;
+    }
+
+    fn take_change(&self) -> Option<Option<T>> {
+        let mut state = self.state.lock().unwrap();
+        if state.changed {
+            state.changed = false;
+            Some(state.data.clone())
+        } else {
+            None
+        }
+    }
+}
+
+/// The session of a request, if it passed through [Sessions] with session data of type `T`.
+pub fn session<T: Send + Sync + 'static, State>(req: &Request<State>) -> Option<&Session<T>> {
+    req.ext()
+}
+
+#[derive(Serialize, Deserialize)]
+struct Payload<T> {
+    data: T,
+    // Seconds since the Unix epoch.
+    expires: u64,
+}
+
+/// Server middleware which keeps a session of type `T` in a signed cookie.
+///
+/// The cookie is `HttpOnly`, `Secure`, `SameSite=Lax` and has path `/` by default. Sessions expire
+/// after a day; the expiry is part of the signed payload, so an old cookie cannot be replayed after
+/// it expires even if the client keeps it.
+pub struct Sessions<T> {
+    keys: Keyring<Vec<u8>>,
+    name: String,
+    path: String,
+    ttl: Duration,
+    secure: bool,
+    same_site: SameSite,
+    _data: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Sessions<T> {
+    fn clone(&self) -> Self {
+        Self {
+            keys: self.keys.clone(),
+            name: self.name.clone(),
+            path: self.path.clone(),
+            ttl: self.ttl,
+            secure: self.secure,
+            same_site: self.same_site,
+            _data: PhantomData,
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Clone + Send + Sync + 'static> Sessions<T> {
+    /// Sign sessions with `secret`.
+    ///
+    /// Every server sharing sessions must use the same secret, and changing it ends all sessions,
+    /// unless it is rotated with [with_keyring](Self::with_keyring).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `secret` is shorter than [MIN_SECRET_LEN] bytes.
+    pub fn new(secret: impl AsRef<[u8]>) -> Self {
+        Self::with_keyring(Keyring::new("default", secret.as_ref().to_vec()))
+    }
+
+    /// Sign sessions with the active key in `keys`, and accept sessions signed with any of its
+    /// keys.
+    ///
+    /// When the key is rotated, sessions signed with the old key last until the end of its grace
+    /// period, or until they are next set, whichever comes first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the active key is shorter than [MIN_SECRET_LEN] bytes. Keys loaded into the
+    /// keyring from a [KeyringFile](crate::keyring::KeyringFile) later are checked when they are
+    /// loaded; keys added with [Keyring::rotate] should be at least as long.
+    pub fn with_keyring(keys: Keyring<Vec<u8>>) -> Self {
+        assert!(
+            keys.active().1.len() >= MIN_SECRET_LEN,
+            "session secret must be at least {} bytes",
+            MIN_SECRET_LEN
+        );
+        Self {
+            keys,
+            name: DEFAULT_COOKIE_NAME.to_string(),
+            path: "/".to_string(),
+            ttl: Duration::from_secs(24 * 60 * 60),
+            secure: true,
+            same_site: SameSite::Lax,
+            _data: PhantomData,
+        }
+    }
+
+    /// Store the session in the cookie `name`.
+    pub fn cookie_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Only send the cookie with requests for paths under `path`.
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Sessions expire `ttl` after they were last set.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Whether the cookie is only sent over HTTPS.
+    ///
+    /// This should only be disabled for local development over plain HTTP.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// The SameSite attribute of the cookie.
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = same_site;
+        self
+    }
+
+    fn mac(&self, key: &[u8], payload: &[u8]) -> Hmac<Sha256> {
+        // HMAC accepts keys of any length.
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        // Bind the signature to the cookie name, so a value cannot be moved to another cookie
+        // signed with the same secret.
+        mac.update(self.name.as_bytes());
+        mac.update(&[0]);
+        mac.update(payload);
+        mac
+    }
+
+    fn encode(&self, data: T) -> String {
+        let payload = Payload {
+            data,
+            // A very long ttl means the session never expires, not that it wraps into the past.
+            expires: unix_time().saturating_add(self.ttl.as_secs()),
+        };
+        let payload = serde_json::to_vec(&payload).expect("session data must be serializable");
+        let (_, key) = self.keys.active();
+        let tag = self.mac(&key, &payload).finalize().into_bytes();
+        format!("{}.{}", hex::encode(&payload), hex::encode(tag))
+    }
+
+    // The data of a cookie, if its signature is valid and it has not expired.
+    fn decode(&self, value: &str) -> Option<T> {
+        let (payload, tag) = value.split_once('.')?;
+        let payload = hex::decode(payload).ok()?;
+        let tag = hex::decode(tag).ok()?;
+        // The cookie does not say which key signed it, so try each key which is still accepted.
+        self.keys
+            .valid()
+            .iter()
+            .find(|(_, key)| self.mac(key, &payload).verify_slice(&tag).is_ok())?;
+        let payload: Payload<T> = serde_json::from_slice(&payload).ok()?;
+        if payload.expires <= unix_time() {
+            return None;
+        }
+        Some(payload.data)
+    }
+
+    fn set_cookie(&self, data: Option<T>) -> String {
+        let (value, max_age) = match data {
+            Some(data) => (self.encode(data), self.ttl.as_secs()),
+            None => (String::new(), 0),
+        };
+        let mut cookie = format!(
+            "{}={}; Path={}; Max-Age={}; HttpOnly; SameSite={}",
+            self.name, value, self.path, max_age, self.same_site
+        );
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        cookie
+    }
+
+    fn read<State>(&self, req: &Request<State>) -> Option<T> {
+        let values = req.header("Cookie")?;
+        values
+            .iter()
+            .flat_map(|value| value.as_str().split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .filter(|(name, _)| *name == self.name)
+            .find_map(|(_, value)| self.decode(value))
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[tide::utils::async_trait]
+impl<T, State> Middleware<State> for Sessions<T>
+where
+    T: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+    State: Clone + Send + Sync + 'static,
+{
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let session = Session::new(self.read(&req));
+        req.set_ext(session.clone());
+        let mut res = next.run(req).await;
+        // Only set the cookie if the handler changed the session, so that reading a session does
+        // not extend it.
+        if let Some(data) = session.take_change() {
+            res.append_header("Set-Cookie", self.set_cookie(data));
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tide::http::{Method, Url};
+    use tide::Response;
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Visits(u32);
+
+    fn server(sessions: Sessions<Visits>) -> tide::Server<()> {
+        let mut app = tide::new();
+        app.at("/visit")
+            .with(sessions.clone())
+            .get(|req: tide::Request<()>| async move {
+                let session = session::<Visits, _>(&req).unwrap();
+                let visits = session.get().map_or(1, |Visits(n)| n + 1);
+                session.set(Visits(visits));
+                Ok(visits.to_string())
+            });
+        app.at("/peek")
+            .with(sessions.clone())
+            .get(|req: tide::Request<()>| async move {
+                let session = session::<Visits, _>(&req).unwrap();
+                Ok(format!("{:?}", session.get()))
+            });
+        app.at("/logout")
+            .with(sessions)
+            .get(|req: tide::Request<()>| async move {
+                session::<Visits, _>(&req).unwrap().clear();
+                Ok("")
+            });
+        app.at("/api").get(|req: tide::Request<()>| async move {
+            Ok(session::<Visits, _>(&req).is_some().to_string())
+        });
+        app
+    }
+
+    async fn get(app: &tide::Server<()>, path: &str, cookie: Option<&str>) -> Response {
+        let url = Url::parse("https://localhost").unwrap().join(path).unwrap();
+        let mut req = tide::http::Request::new(Method::Get, url);
+        if let Some(cookie) = cookie {
+            req.insert_header("Cookie", cookie);
+        }
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        res.into()
+    }
+
+    fn set_cookie(res: &Response) -> Option<String> {
+        Some(res.header("Set-Cookie")?.last().as_str().to_string())
+    }
+
+    // The `name=value` part of a Set-Cookie header.
+    fn cookie(set_cookie: &str) -> &str {
+        set_cookie.split(';').next().unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_session() {
+        let app = server(Sessions::new([1; 32]));
+
+        // The session is created and updated.
+        let mut res = get(&app, "/visit", None).await;
+        assert_eq!(res.take_body().into_string().await.unwrap(), "1");
+        let first = set_cookie(&res).unwrap();
+        assert!(first.starts_with("net_session="));
+        for attr in [
+            "Path=/",
+            "Max-Age=86400",
+            "HttpOnly",
+            "SameSite=Lax",
+            "Secure",
+        ] {
+            assert!(first.split("; ").any(|a| a == attr), "{}", first);
+        }
+        let mut res = get(&app, "/visit", Some(cookie(&first))).await;
+        assert_eq!(res.take_body().into_string().await.unwrap(), "2");
+        let second = set_cookie(&res).unwrap();
+
+        // Reading a session does not set the cookie.
+        let mut res = get(&app, "/peek", Some(cookie(&second))).await;
+        assert_eq!(
+            res.take_body().into_string().await.unwrap(),
+            "Some(Visits(2))"
+        );
+        assert_eq!(set_cookie(&res), None);
+
+        // Other cookies are ignored.
+        let cookies = format!("other=1; {}", cookie(&second));
+        let mut res = get(&app, "/peek", Some(&cookies)).await;
+        assert_eq!(
+            res.take_body().into_string().await.unwrap(),
+            "Some(Visits(2))"
+        );
+
+        // Clearing the session removes the cookie.
+        let res = get(&app, "/logout", Some(cookie(&second))).await;
+        let cleared = set_cookie(&res).unwrap();
+        assert!(cleared.starts_with("net_session=;"));
+        assert!(cleared.contains("Max-Age=0"));
+
+        // Routes without the middleware are stateless.
+        let mut res = get(&app, "/api", Some(cookie(&second))).await;
+        assert_eq!(res.take_body().into_string().await.unwrap(), "false");
+        assert_eq!(set_cookie(&res), None);
+    }
+
+    #[async_std::test]
+    async fn test_invalid() {
+        let app = server(Sessions::new([1; 32]));
+        let res = get(&app, "/visit", None).await;
+        let valid = set_cookie(&res).unwrap();
+        let valid = cookie(&valid);
+        let (payload, tag) = valid
+            .strip_prefix("net_session=")
+            .unwrap()
+            .split_once('.')
+            .unwrap();
+
+        // A session changed by the client is ignored.
+        let forged = hex::encode(br#"{"data":1000,"expires":18446744073709551615}"#);
+        for value in [
+            format!("net_session={}.{}", forged, tag),
+            format!("net_session={}", payload),
+            "net_session=garbage".to_string(),
+        ] {
+            let mut res = get(&app, "/peek", Some(&value)).await;
+            assert_eq!(res.take_body().into_string().await.unwrap(), "None");
+        }
+
+        // A session signed with a different secret, or for a different cookie, is ignored.
+        let other = server(Sessions::new([2; 32]));
+        let mut res = get(&other, "/peek", Some(valid)).await;
+        assert_eq!(res.take_body().into_string().await.unwrap(), "None");
+        let renamed = server(Sessions::new([1; 32]).cookie_name("renamed"));
+        let value = valid.replacen("net_session", "renamed", 1);
+        let mut res = get(&renamed, "/peek", Some(&value)).await;
+        assert_eq!(res.take_body().into_string().await.unwrap(), "None");
+
+        // An expired session is ignored.
+        let expiring = server(Sessions::new([1; 32]).ttl(Duration::ZERO));
+        let res = get(&expiring, "/visit", None).await;
+        let expired = set_cookie(&res).unwrap();
+        let mut res = get(&expiring, "/peek", Some(cookie(&expired))).await;
+        assert_eq!(res.take_body().into_string().await.unwrap(), "None");
+
+        // A session which never expires does not overflow its expiry time.
+        let forever = server(Sessions::new([1; 32]).ttl(Duration::MAX));
+        let res = get(&forever, "/visit", None).await;
+        let kept = set_cookie(&res).unwrap();
+        let mut res = get(&forever, "/peek", Some(cookie(&kept))).await;
+        assert_eq!(
+            res.take_body().into_string().await.unwrap(),
+            "Some(Visits(1))"
+        );
+    }
+
+    #[async_std::test]
+    async fn test_key_rotation() {
+        let keys = Keyring::new("old", vec![1; 32]);
+        let app = server(Sessions::with_keyring(keys.clone()));
+        let res = get(&app, "/visit", None).await;
+        let old = set_cookie(&res).unwrap();
+
+        // After a rotation, sessions signed with the old key are still valid, and new sessions are
+        // signed with the new key.
+        keys.rotate("new", vec![2; 32], Duration::from_secs(60));
+        let mut res = get(&app, "/visit", Some(cookie(&old))).await;
+        assert_eq!(res.take_body().into_string().await.unwrap(), "2");
+        let new = set_cookie(&res).unwrap();
+        let other = server(Sessions::new([2; 32]));
+        let mut res = get(&other, "/peek", Some(cookie(&new))).await;
+        assert_eq!(
+            res.take_body().into_string().await.unwrap(),
+            "Some(Visits(2))"
+        );
+
+        // Once the grace period ends, they are not.
+        keys.rotate("newer", vec![3; 32], Duration::ZERO);
+        let mut res = get(&app, "/peek", Some(cookie(&new))).await;
+        assert_eq!(res.take_body().into_string().await.unwrap(), "None");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_short_secret() {
+        Sessions::<Visits>::new(b"secret");
+    }
+}
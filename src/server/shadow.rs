@@ -0,0 +1,212 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Mirroring production traffic to a secondary deployment.
+//!
+//! Before cutting over to a new version of a service, operators can check it against real traffic
+//! by adding the [Shadow] middleware (created with [shadow_to]) to the production server. A sample
+//! of incoming requests is copied to the new deployment in the background; the copies are marked
+//! with a [SHADOWED] header, and their responses are ignored. The request to the production server
+//! is handled as usual and never waits for its copy.
+//!
+//! Sampling is deterministic: with a sample rate of 0.25, exactly every fourth request is mirrored.
+//! To protect the production server if the secondary is slow, at most a fixed number of copies are
+//! in flight at once; requests beyond that are not mirrored.
+
+use crate::wire::body::read_body;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use surf::Url;
+use tide::http::Body;
+use tide::{Middleware, Next, Request};
+use tracing::{event, Level};
+
+pub use crate::headers::SHADOWED;
+
+/// Mirror a fraction `sample_rate` (between 0 and 1) of requests to the deployment at `url`.
+///
+/// The path and query of each mirrored request are appended to the path of `url`. By default, at
+/// most 100 copies are in flight at once.
+pub fn shadow_to(url: Url, sample_rate: f64) -> Shadow {
+    Shadow {
+        target: url,
+        sample_rate: sample_rate.clamp(0.0, 1.0),
+        max_in_flight: 100,
+        client: surf::Client::new(),
+        seen: Default::default(),
+        in_flight: Default::default(),
+        mirrored: Default::default(),
+    }
+}
+
+/// Server middleware which mirrors a sample of requests to another deployment.
+#[derive(Clone)]
+pub struct Shadow {
+    target: Url,
+    sample_rate: f64,
+    max_in_flight: usize,
+    client: surf::Client,
+    seen: Arc<AtomicU64>,
+    in_flight: Arc<AtomicUsize>,
+    mirrored: Arc<AtomicU64>,
+}
+
+impl Shadow {
+    /// Limit the number of copies in flight at once.
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    /// Use `client` to send copies.
+    pub fn client(mut self, client: surf::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// The total number of requests which have been mirrored.
+    pub fn mirrored(&self) -> u64 {
+        self.mirrored.load(Ordering::Relaxed)
+    }
+
+    fn sample(&self) -> bool {
+        // Request n is sampled if it brings the expected number of samples to a new integer.
+        let n = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.sample_rate).ceil() > (n * self.sample_rate).ceil()
+    }
+
+    fn target(&self, url: &Url) -> Url {
+        let mut target = self.target.clone();
+        let path = format!("{}{}", self.target.path().trim_end_matches('/'), url.path());
+        target.set_path(&path);
+        target.set_query(url.query());
+        target
+    }
+}
+
+// Decrements the number of copies in flight when a copy finishes.
+struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Shadow {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if !self.sample() {
+            return Ok(next.run(req).await);
+        }
+        if self.in_flight.fetch_add(1, Ordering::Relaxed) >= self.max_in_flight {
+            self.in_flight.fetch_sub(1, Ordering::Relaxed);
+            return Ok(next.run(req).await);
+        }
+        let guard = InFlight(self.in_flight.clone());
+
+        // Buffer the body, so that it can be sent to both deployments.
+        let body = req.take_body();
+        let mime = body.mime().clone();
+        let bytes = read_body(body).await?;
+        let mut copy = surf::Request::new(req.method(), self.target(req.url()));
+        copy.set_body(bytes.clone());
+        for (name, values) in req.iter() {
+            if name != "Host" {
+                copy.insert_header(name, values);
+            }
+        }
+        copy.insert_header(SHADOWED, "true");
+        let mut body = Body::from_bytes(bytes);
+        body.set_mime(mime);
+        req.set_body(body);
+
+        let client = self.client.clone();
+        self.mirrored.fetch_add(1, Ordering::Relaxed);
+        async_std::task::spawn(async move {
+            let _guard = guard;
+            let url = copy.url().clone();
+            if let Err(err) = client.send(copy).await {
+                event!(Level::DEBUG, "failed to mirror request to {}: {}", url, err);
+            }
+        });
+
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_std::channel::{unbounded, Sender};
+    use surf::middleware::Next;
+    use tide::http::{Method, Url};
+    use tide::StatusCode;
+
+    // A fake secondary deployment which reports the requests it receives.
+    struct Secondary(Sender<(String, Option<String>, String)>);
+
+    #[surf::utils::async_trait]
+    impl surf::middleware::Middleware for Secondary {
+        async fn handle(
+            &self,
+            mut req: surf::Request,
+            _client: surf::Client,
+            _next: Next<'_>,
+        ) -> surf::Result {
+            let body = req.take_body().into_string().await?;
+            let shadowed = req.header(SHADOWED).map(|h| h.as_str().to_string());
+            self.0
+                .send((req.url().to_string(), shadowed, body))
+                .await
+                .unwrap();
+            Ok(surf::http::Response::new(StatusCode::InternalServerError).into())
+        }
+    }
+
+    #[async_std::test]
+    async fn test_shadow() {
+        let (sender, receiver) = unbounded();
+        let shadow = shadow_to(Url::parse("http://canary/v2/").unwrap(), 0.5)
+            .client(surf::Client::new().with(Secondary(sender)));
+        let mut app = tide::new();
+        app.with(shadow.clone());
+        app.at("/memo")
+            .post(|mut req: tide::Request<()>| async move { req.body_string().await });
+
+        for i in 0..4 {
+            let mut req = tide::http::Request::new(
+                Method::Post,
+                Url::parse(&format!("http://localhost/memo?n={}", i)).unwrap(),
+            );
+            req.set_body(format!("memo {}", i));
+            let mut res: tide::http::Response = app.respond(req).await.unwrap();
+            // Production responses are unaffected by the secondary.
+            assert_eq!(res.status(), StatusCode::Ok);
+            assert_eq!(res.body_string().await.unwrap(), format!("memo {}", i));
+        }
+
+        // Every other request was mirrored.
+        assert_eq!(shadow.mirrored(), 2);
+        let mut received = vec![
+            receiver.recv().await.unwrap(),
+            receiver.recv().await.unwrap(),
+        ];
+        received.sort();
+        assert_eq!(
+            received,
+            [0, 2]
+                .iter()
+                .map(|i| (
+                    format!("http://canary/v2/memo?n={}", i),
+                    Some("true".to_string()),
+                    format!("memo {}", i)
+                ))
+                .collect::<Vec<_>>()
+        );
+    }
+}
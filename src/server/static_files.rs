@@ -0,0 +1,262 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Serving static files, such as the web UIs bundled with some services.
+//!
+//! [serve_static] creates an endpoint which serves the files in a directory, with:
+//!  * content types based on file extensions, including the types web UIs need (like
+//!    `application/wasm`) which tide does not know about
+//!  * a `Cache-Control` header: assets can be cached for a configurable time, while HTML files must
+//!    always be revalidated, since they refer to the (possibly changed) assets
+//!  * precompressed variants: if `file.br` or `file.gz` exists alongside `file`, and the client
+//!    accepts that encoding, the compressed file is served instead
+//!  * optionally, a fallback to `index.html` for paths which don't exist, for single page apps
+//!    which do their own routing
+//!
+//! Since the files are served by an ordinary endpoint, requests for them go through all the
+//! server's middleware.
+
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use tide::http::content::{AcceptEncoding, Encoding};
+use tide::http::{Body, Mime};
+use tide::{Request, Response, StatusCode};
+
+/// The content type of a file, based on its extension.
+pub fn content_type(path: &Path) -> Mime {
+    let ty = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("html") | Some("htm") => "text/html;charset=utf-8",
+        Some("js") | Some("mjs") => "application/javascript;charset=utf-8",
+        Some("css") => "text/css;charset=utf-8",
+        Some("json") | Some("map") => "application/json",
+        Some("webmanifest") => "application/manifest+json",
+        Some("txt") => "text/plain;charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("wasm") => "application/wasm",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    };
+    ty.parse().unwrap()
+}
+
+/// Serve the files in `dir`.
+///
+/// Mount the endpoint with [StaticFiles::mount].
+pub fn serve_static(dir: impl Into<PathBuf>) -> StaticFiles {
+    StaticFiles {
+        dir: dir.into(),
+        max_age: Duration::from_secs(3600),
+        spa: false,
+    }
+}
+
+/// An endpoint which serves static files.
+#[derive(Clone, Debug)]
+pub struct StaticFiles {
+    dir: PathBuf,
+    max_age: Duration,
+    spa: bool,
+}
+
+impl StaticFiles {
+    /// How long clients may cache files other than HTML files.
+    ///
+    /// The default is 1 hour. If asset file names include a hash of their contents, this can be
+    /// much longer.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Serve `index.html` for paths which do not exist.
+    pub fn spa(mut self, spa: bool) -> Self {
+        self.spa = spa;
+        self
+    }
+
+    /// Serve the files at `route` and all paths under it.
+    pub fn mount<State: Clone + Send + Sync + 'static>(self, route: &mut tide::Route<'_, State>) {
+        let files = self.clone();
+        route.get(move |req: Request<State>| {
+            let files = files.clone();
+            async move { files.respond(&req, "").await }
+        });
+        route.at("*path").get(move |req: Request<State>| {
+            let files = self.clone();
+            async move {
+                let path = req.param("path").unwrap_or("").to_string();
+                files.respond(&req, &path).await
+            }
+        });
+    }
+
+    async fn respond<State>(&self, req: &Request<State>, path: &str) -> tide::Result {
+        let path = match self.resolve(path) {
+            Some(path) => path,
+            None => return Ok(Response::new(StatusCode::NotFound)),
+        };
+        let path = if is_file(&path).await {
+            path
+        } else if is_file(&path.join("index.html")).await {
+            path.join("index.html")
+        } else if self.spa && is_file(&self.dir.join("index.html")).await {
+            self.dir.join("index.html")
+        } else {
+            return Ok(Response::new(StatusCode::NotFound));
+        };
+
+        let ty = content_type(&path);
+        let mut res = Response::new(StatusCode::Ok);
+        let (file, encoding) = self.variant(req, &path).await?;
+        let mut body = Body::from_file(&file).await?;
+        body.set_mime(ty.clone());
+        res.set_body(body);
+        if let Some(encoding) = encoding {
+            res.insert_header("Content-Encoding", encoding);
+        }
+        res.insert_header("Vary", "Accept-Encoding");
+        if ty.essence() == "text/html" {
+            res.insert_header("Cache-Control", "no-cache");
+        } else {
+            res.insert_header(
+                "Cache-Control",
+                format!("public, max-age={}", self.max_age.as_secs()),
+            );
+        }
+        Ok(res)
+    }
+
+    // Map a request path to a file path, refusing paths which could escape `dir`.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let mut resolved = self.dir.clone();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(part) => resolved.push(part),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+        Some(resolved)
+    }
+
+    // The best precompressed variant of `path` which the client accepts.
+    async fn variant<State>(
+        &self,
+        req: &Request<State>,
+        path: &Path,
+    ) -> tide::Result<(PathBuf, Option<&'static str>)> {
+        let mut available = vec![];
+        for (encoding, ext) in [(Encoding::Brotli, "br"), (Encoding::Gzip, "gz")] {
+            if is_file(&with_suffix(path, ext)).await {
+                available.push(encoding);
+            }
+        }
+        if available.is_empty() {
+            return Ok((path.to_path_buf(), None));
+        }
+        available.push(Encoding::Identity);
+        let chosen = match AcceptEncoding::from_headers(req)? {
+            Some(mut accept) => accept
+                .negotiate(&available)
+                .map(|encoding| encoding.encoding())
+                .unwrap_or(Encoding::Identity),
+            None => Encoding::Identity,
+        };
+        Ok(match chosen {
+            Encoding::Brotli => (with_suffix(path, "br"), Some("br")),
+            Encoding::Gzip => (with_suffix(path, "gz"), Some("gzip")),
+            _ => (path.to_path_buf(), None),
+        })
+    }
+}
+
+fn with_suffix(path: &Path, ext: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(ext);
+    path.into()
+}
+
+async fn is_file(path: &Path) -> bool {
+    async_std::fs::metadata(path)
+        .await
+        .map(|meta| meta.is_file())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use tide::http::{Method, Url};
+
+    async fn get(
+        app: &tide::Server<()>,
+        path: &str,
+        encoding: Option<&str>,
+    ) -> tide::http::Response {
+        let mut req = tide::http::Request::new(
+            Method::Get,
+            Url::parse("http://localhost").unwrap().join(path).unwrap(),
+        );
+        if let Some(encoding) = encoding {
+            req.insert_header("Accept-Encoding", encoding);
+        }
+        app.respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_serve_static() {
+        let dir = std::env::temp_dir().join(format!("net-static-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("assets")).unwrap();
+        fs::write(dir.join("index.html"), "<html></html>").unwrap();
+        fs::write(dir.join("assets/app.wasm"), b"\0asm").unwrap();
+        fs::write(dir.join("assets/app.js"), "console.log()").unwrap();
+        fs::write(dir.join("assets/app.js.gz"), "compressed").unwrap();
+
+        let mut app = tide::new();
+        serve_static(&dir).spa(true).mount(&mut app.at("/ui"));
+
+        let mut res = get(&app, "/ui", None).await;
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.content_type().unwrap().essence(), "text/html");
+        assert_eq!(res["Cache-Control"], "no-cache");
+        assert_eq!(res.body_string().await.unwrap(), "<html></html>");
+
+        let res = get(&app, "/ui/assets/app.wasm", None).await;
+        assert_eq!(res.content_type().unwrap().essence(), "application/wasm");
+        assert_eq!(res["Cache-Control"], "public, max-age=3600");
+
+        // The compressed variant is served to clients which accept it.
+        let mut res = get(&app, "/ui/assets/app.js", Some("gzip")).await;
+        assert_eq!(res["Content-Encoding"], "gzip");
+        assert_eq!(
+            res.content_type().unwrap().essence(),
+            "application/javascript"
+        );
+        assert_eq!(res.body_string().await.unwrap(), "compressed");
+        let mut res = get(&app, "/ui/assets/app.js", None).await;
+        assert!(res.header("Content-Encoding").is_none());
+        assert_eq!(res.body_string().await.unwrap(), "console.log()");
+
+        // Unknown paths fall back to the index, but paths outside the directory are refused.
+        let mut res = get(&app, "/ui/explorer/block/1", None).await;
+        assert_eq!(res.body_string().await.unwrap(), "<html></html>");
+        assert_eq!(serve_static(&dir).resolve("../secret"), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
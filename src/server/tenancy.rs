@@ -0,0 +1,321 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Serving several tenants from one server.
+//!
+//! A hosted query service may serve several rollups from one process. The [Tenants] middleware
+//! identifies the tenant each request is for, from one of:
+//!  * the first label of the Host header (`rollup-a.example.com`)
+//!  * a request header, conventionally [TENANT] (`X-Tenant: rollup-a`)
+//!  * the first segment of the path (`/rollup-a/block/3`)
+//!
+//! and then applies the tenant's configuration:
+//!  * if the tenant has auth keys, the request must have an `Authorization: Bearer <key>` header
+//!    with one of them, or it fails with 401
+//!  * if the tenant has a rate limit, requests beyond it fail with 429 and a Retry-After header
+//!
+//! Requests for unknown tenants fail with 404. Handlers can find out which tenant they are serving
+//! with [tenant], and [Instrument](super::Instrument) reports metrics for each tenant under its
+//! label (see [Metrics::record_tenant](super::instrument::Metrics::record_tenant)).
+//!
+//! Tide routes requests before running middleware, so identifying tenants by path prefix does not
+//! strip the prefix. Routes for such tenants should be registered with a `:tenant` parameter, as in
+//! `app.at("/:tenant/block/:id")`.
+
+use super::constant_time_eq;
+use super::rate_limit::{too_many_requests, TokenBucket};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+pub use crate::headers::TENANT;
+
+/// Where to find the tenant a request is for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TenantSource {
+    /// The first label of the Host header.
+    Subdomain,
+    /// The value of a request header.
+    Header(String),
+    /// The first segment of the path.
+    PathPrefix,
+}
+
+impl TenantSource {
+    fn identify<State>(&self, req: &Request<State>) -> Option<String> {
+        match self {
+            Self::Subdomain => {
+                let host = req.host()?;
+                let (label, _) = host.split_once('.')?;
+                Some(label.to_string())
+            }
+            Self::Header(name) => Some(req.header(name.as_str())?.as_str().to_string()),
+            Self::PathPrefix => {
+                let segment = req.url().path().trim_start_matches('/').split('/').next()?;
+                if segment.is_empty() {
+                    None
+                } else {
+                    Some(segment.to_string())
+                }
+            }
+        }
+    }
+}
+
+/// The tenant a request is for, available to handlers through [tenant].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tenant {
+    pub id: String,
+    /// The label under which metrics for this tenant are reported.
+    pub label: String,
+}
+
+/// The tenant a request is for, if it passed through [Tenants].
+pub fn tenant<State>(req: &Request<State>) -> Option<&Tenant> {
+    req.ext()
+}
+
+/// The configuration of a single tenant.
+#[derive(Clone, Debug)]
+pub struct TenantConfig {
+    id: String,
+    label: String,
+    keys: Vec<String>,
+    rate_limit: Option<(f64, f64)>,
+}
+
+impl TenantConfig {
+    /// A tenant with no auth keys and no rate limit, labeled with its ID.
+    pub fn new(id: impl Into<String>) -> Self {
+        let id = id.into();
+        Self {
+            label: id.clone(),
+            id,
+            keys: Vec::new(),
+            rate_limit: None,
+        }
+    }
+
+    /// Report metrics for this tenant under `label`.
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        self.label = label.into();
+        self
+    }
+
+    /// Accept requests bearing `key`.
+    ///
+    /// This can be called more than once. If it is never called, requests do not need a key.
+    pub fn auth_key(mut self, key: impl Into<String>) -> Self {
+        self.keys.push(key.into());
+        self
+    }
+
+    /// Allow `per_second` requests per second on average, with bursts of up to `burst` requests.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `per_second` is not positive, or if `burst` is 0.
+    pub fn rate_limit(mut self, per_second: f64, burst: u32) -> Self {
+        assert!(per_second > 0.0, "rate limit must be positive");
+        assert!(burst > 0, "burst must allow at least one request");
+        self.rate_limit = Some((per_second, burst as f64));
+        self
+    }
+}
+
+struct TenantState {
+    config: TenantConfig,
+    bucket: Option<Mutex<TokenBucket>>,
+}
+
+impl TenantState {
+    fn authorize<State>(&self, req: &Request<State>) -> bool {
+        if self.config.keys.is_empty() {
+            return true;
+        }
+        let token = match req
+            .header("Authorization")
+            .and_then(|auth| auth.as_str().strip_prefix("Bearer "))
+        {
+            Some(token) => token,
+            None => return false,
+        };
+        self.config
+            .keys
+            .iter()
+            .any(|key| constant_time_eq(token.as_bytes(), key.as_bytes()))
+    }
+
+    // Take a token from the bucket, or return how long until one is available.
+    fn take(&self) -> Result<(), Duration> {
+        match &self.bucket {
+            Some(bucket) => bucket.lock().unwrap().take(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Server middleware which identifies and applies the configuration of tenants.
+#[derive(Clone)]
+pub struct Tenants {
+    source: TenantSource,
+    tenants: HashMap<String, Arc<TenantState>>,
+}
+
+impl Tenants {
+    /// Identify tenants using `source`.
+    ///
+    /// Tenants must be registered with [tenant](Self::tenant).
+    pub fn new(source: TenantSource) -> Self {
+        Self {
+            source,
+            tenants: HashMap::new(),
+        }
+    }
+
+    /// Register a tenant.
+    pub fn tenant(mut self, config: TenantConfig) -> Self {
+        let bucket = config
+            .rate_limit
+            .map(|(per_second, burst)| Mutex::new(TokenBucket::new(per_second, burst)));
+        self.tenants
+            .insert(config.id.clone(), Arc::new(TenantState { config, bucket }));
+        self
+    }
+}
+
+fn error(status: StatusCode, msg: impl Into<String>) -> Response {
+    let mut res = Response::new(status);
+    res.set_error(tide::Error::from_str(status, msg.into()));
+    res
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Tenants {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let id = match self.source.identify(&req) {
+            Some(id) => id,
+            None => return Ok(error(StatusCode::BadRequest, "no tenant specified")),
+        };
+        let tenant = match self.tenants.get(&id) {
+            Some(tenant) => tenant,
+            None => {
+                return Ok(error(
+                    StatusCode::NotFound,
+                    format!("unknown tenant {}", id),
+                ))
+            }
+        };
+        if !tenant.authorize(&req) {
+            return Ok(error(
+                StatusCode::Unauthorized,
+                format!("tenant {} requires a valid bearer token", id),
+            ));
+        }
+        if let Err(wait) = tenant.take() {
+            return Ok(too_many_requests(wait));
+        }
+        req.set_ext(Tenant {
+            id,
+            label: tenant.config.label.clone(),
+        });
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tide::http::{Method, Url};
+
+    async fn get(app: &tide::Server<()>, url: &str, headers: &[(&str, &str)]) -> Response {
+        let mut req = tide::http::Request::new(Method::Get, Url::parse(url).unwrap());
+        for (name, value) in headers {
+            req.insert_header(*name, *value);
+        }
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        res.into()
+    }
+
+    fn server(source: TenantSource) -> tide::Server<()> {
+        let mut app = tide::new();
+        app.with(
+            Tenants::new(source)
+                .tenant(TenantConfig::new("a").label("rollup-a"))
+                .tenant(
+                    TenantConfig::new("b")
+                        .auth_key("secret")
+                        .rate_limit(0.001, 2),
+                ),
+        );
+        let handler =
+            |req: tide::Request<()>| async move { Ok(tenant(&req).unwrap().label.clone()) };
+        app.at("/block").get(handler);
+        app.at("/:tenant/block").get(handler);
+        app
+    }
+
+    fn app_with_header() -> tide::Server<()> {
+        server(TenantSource::Header(TENANT.to_string()))
+    }
+
+    #[async_std::test]
+    async fn test_identify() {
+        let app = server(TenantSource::Subdomain);
+        let mut res = get(&app, "http://a.example.com/block", &[]).await;
+        assert_eq!(res.take_body().into_string().await.unwrap(), "rollup-a");
+        let res = get(&app, "http://c.example.com/block", &[]).await;
+        assert_eq!(res.status(), StatusCode::NotFound);
+
+        let app = app_with_header();
+        let mut res = get(&app, "http://localhost/block", &[(TENANT, "a")]).await;
+        assert_eq!(res.take_body().into_string().await.unwrap(), "rollup-a");
+        let res = get(&app, "http://localhost/block", &[]).await;
+        assert_eq!(res.status(), StatusCode::BadRequest);
+
+        let app = server(TenantSource::PathPrefix);
+        let mut res = get(&app, "http://localhost/a/block", &[]).await;
+        assert_eq!(res.take_body().into_string().await.unwrap(), "rollup-a");
+    }
+
+    #[async_std::test]
+    async fn test_auth_and_rate_limit() {
+        let app = app_with_header();
+        let res = get(&app, "http://localhost/block", &[(TENANT, "b")]).await;
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+        let res = get(
+            &app,
+            "http://localhost/block",
+            &[(TENANT, "b"), ("Authorization", "Bearer wrong")],
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        // The burst is allowed, and then requests are limited.
+        let headers = [(TENANT, "b"), ("Authorization", "Bearer secret")];
+        for _ in 0..2 {
+            let res = get(&app, "http://localhost/block", &headers).await;
+            assert_eq!(res.status(), StatusCode::Ok);
+        }
+        let res = get(&app, "http://localhost/block", &headers).await;
+        assert_eq!(res.status(), StatusCode::TooManyRequests);
+        assert!(res["Retry-After"].as_str().parse::<u64>().unwrap() > 0);
+
+        // Other tenants are not affected.
+        for _ in 0..3 {
+            let res = get(&app, "http://localhost/block", &[(TENANT, "a")]).await;
+            assert_eq!(res.status(), StatusCode::Ok);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_zero_burst() {
+        TenantConfig::new("a").rate_limit(1.0, 0);
+    }
+}
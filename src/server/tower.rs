@@ -0,0 +1,294 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Adapters for serving the protocol defined by this crate from tower-based servers, like axum.
+//!
+//! This module provides the same functionality as the tide middleware in the parent module:
+//!  * [Negotiated] is an axum extractor which performs content negotiation exactly like
+//!    [response](super::response), and can then serialize the response body
+//!  * [ErrorBodyLayer] is the equivalent of [add_error_body](super::add_error_body). Handlers
+//!    return errors by wrapping them in [ApiError], and the layer serializes them into the body of
+//!    the response using the content type requested by the client
+//!  * [TraceLayer] is the equivalent of [trace](super::trace)
+//!
+//! A tide server and an axum server using these adapters are indistinguishable on the wire, so
+//! clients using the `client` module work with either.
+
+use super::{best_response_type, serialize_body, RESPONSE_TYPES};
+use crate::error::Error;
+use axum::async_trait;
+use axum::body::{boxed, Full};
+use axum::extract::{FromRequest, RequestParts};
+use axum::http::{header, HeaderMap, Request, StatusCode};
+use axum::response::{IntoResponse, Response};
+use futures::future::BoxFuture;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::task::{Context, Poll};
+use tide::http::{content::Accept, Mime};
+use tower_layer::Layer;
+use tower_service::Service;
+use tracing::{event, Level};
+
+/// Parse the Accept header from an axum request.
+pub fn parse_accept(headers: &HeaderMap) -> Result<Option<Accept>, tide::Error> {
+    // The only way to construct an [Accept] is from a set of tide headers, so we copy the relevant
+    // header into a scratch message.
+    let mut scratch = tide::http::Response::new(tide::StatusCode::Ok);
+    for value in headers.get_all(header::ACCEPT) {
+        let value = value.to_str().map_err(|err| {
+            tide::Error::from_str(
+                tide::StatusCode::BadRequest,
+                format!("invalid Accept header: {}", err),
+            )
+        })?;
+        scratch.append_header("Accept", value);
+    }
+    Accept::from_headers(&scratch)
+}
+
+/// The content type negotiated for a response.
+///
+/// As an axum extractor, this performs content negotiation using the Accept header of the request.
+/// If no acceptable content type is available, the request is rejected, and the rejection is
+/// converted to an error body by [ErrorBodyLayer].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Negotiated(pub Mime);
+
+impl Negotiated {
+    /// Serialize a successful response body using the negotiated content type.
+    pub fn respond<T: Serialize>(&self, body: &T) -> Response {
+        match serialize_body(&self.0, body) {
+            Ok(bytes) => Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, self.0.to_string())
+                .body(boxed(Full::from(bytes)))
+                .unwrap(),
+            Err(err) => catch_all(err),
+        }
+    }
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Negotiated {
+    type Rejection = Response;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        parse_accept(req.headers())
+            .and_then(|mut accept| best_response_type(&mut accept, &RESPONSE_TYPES))
+            .map(Self)
+            .map_err(catch_all)
+    }
+}
+
+/// An error returned from an axum handler.
+///
+/// The error is serialized into the body of the response by [ErrorBodyLayer]. If the layer is not
+/// installed, the error is serialized as JSON.
+#[derive(Clone, Debug)]
+pub struct ApiError<E>(pub E);
+
+impl<E> From<E> for ApiError<E> {
+    fn from(err: E) -> Self {
+        Self(err)
+    }
+}
+
+impl<E: Error> IntoResponse for ApiError<E> {
+    fn into_response(self) -> Response {
+        let mut res = error_response(&mut None, &self.0);
+        res.extensions_mut().insert(TypedError(self.0));
+        res
+    }
+}
+
+// Response extensions used to pass errors from handlers to [ErrorBody].
+struct TypedError<E>(E);
+struct CatchAll(String);
+
+// A response for errors generated within this module, which don't have a specific error type.
+fn catch_all(err: tide::Error) -> Response {
+    let msg = err.to_string();
+    let mut res = Response::builder()
+        .status(status_code(err.status()))
+        .body(boxed(Full::from(msg.clone())))
+        .unwrap();
+    res.extensions_mut().insert(CatchAll(msg));
+    res
+}
+
+fn error_response<E: Error>(accept: &mut Option<Accept>, err: &E) -> Response {
+    // If we can't find a content type the client will accept, fall back to JSON so that the client
+    // at least gets an error message.
+    let ty =
+        best_response_type(accept, &RESPONSE_TYPES).unwrap_or_else(|_| RESPONSE_TYPES[0].clone());
+    let (ty, bytes) = match serialize_body(&ty, err) {
+        Ok(bytes) => (ty.to_string(), bytes),
+        Err(_) => ("text/plain".to_string(), err.to_string().into_bytes()),
+    };
+    Response::builder()
+        .status(status_code(err.status()))
+        .header(header::CONTENT_TYPE, ty)
+        .body(boxed(Full::from(bytes)))
+        .unwrap()
+}
+
+fn status_code(status: tide::StatusCode) -> StatusCode {
+    StatusCode::from_u16(status as u16).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Layer which automatically populates the body of error responses.
+///
+/// If a handler returns an [ApiError], the error is serialized into the body of the response,
+/// using the content type requested by the client. Errors generated by this module, such as
+/// content negotiation failures, are converted into an `E` using [Error::catch_all] and then
+/// serialized in the same way.
+///
+/// All other responses are passed through unchanged.
+pub struct ErrorBodyLayer<E> {
+    _error: PhantomData<fn() -> E>,
+}
+
+impl<E> ErrorBodyLayer<E> {
+    pub fn new() -> Self {
+        Self {
+            _error: Default::default(),
+        }
+    }
+}
+
+impl<E> Default for ErrorBodyLayer<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E> Clone for ErrorBodyLayer<E> {
+    fn clone(&self) -> Self {
+        Self::new()
+    }
+}
+
+impl<S, E> Layer<S> for ErrorBodyLayer<E> {
+    type Service = ErrorBody<S, E>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ErrorBody {
+            inner,
+            _error: Default::default(),
+        }
+    }
+}
+
+/// The service created by [ErrorBodyLayer].
+pub struct ErrorBody<S, E> {
+    inner: S,
+    _error: PhantomData<fn() -> E>,
+}
+
+impl<S: Clone, E> Clone for ErrorBody<S, E> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            _error: Default::default(),
+        }
+    }
+}
+
+impl<S, E, B> Service<Request<B>> for ErrorBody<S, E>
+where
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    E: Error,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        // An Accept header which fails to parse is treated as if it were missing, so that the
+        // client still gets an error body.
+        let mut accept = parse_accept(req.headers()).unwrap_or(None);
+        // Use the instance of the inner service which was driven to readiness, leaving a fresh
+        // clone in its place.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move {
+            let mut res = inner.call(req).await?;
+            let error =
+                if let Some(TypedError(error)) = res.extensions_mut().remove::<TypedError<E>>() {
+                    error
+                } else if let Some(CatchAll(msg)) = res.extensions_mut().remove::<CatchAll>() {
+                    E::catch_all(msg)
+                } else {
+                    return Ok(res);
+                };
+            event!(Level::WARN, "responding with error: {}", error);
+            Ok(error_response(&mut accept, &error))
+        })
+    }
+}
+
+/// Layer which logs requests and responses.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TraceLayer;
+
+impl<S> Layer<S> for TraceLayer {
+    type Service = Trace<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Trace { inner }
+    }
+}
+
+/// The service created by [TraceLayer].
+#[derive(Clone, Debug)]
+pub struct Trace<S> {
+    inner: S,
+}
+
+impl<S, B, ResBody> Service<Request<B>> for Trace<S>
+where
+    S: Service<Request<B>, Response = axum::http::Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<S::Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        event!(
+            Level::INFO,
+            "<-- received request {{url: {}, content-type: {:?}, accept: {:?}}}",
+            req.uri(),
+            req.headers().get(header::CONTENT_TYPE),
+            parse_accept(req.headers()),
+        );
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move {
+            let res = inner.call(req).await?;
+            event!(
+                Level::INFO,
+                "--> responding with {{content-type: {:?}, status: {}}}",
+                res.headers().get(header::CONTENT_TYPE),
+                res.status(),
+            );
+            Ok(res)
+        })
+    }
+}
@@ -0,0 +1,217 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Transforming responses after they are serialized.
+//!
+//! Some changes to responses depend on the deployment rather than the handler: a public deployment
+//! may hide fields which an internal one shows, or sign its responses, or annotate them with the
+//! ledger height. The [Transforms] middleware lets a service register such changes, for all routes
+//! or for routes matching a template, without changing its handlers or reimplementing
+//! [respond_with](super::respond_with).
+//!
+//! A transform is given the response and its serialized body, and may change either.
+//! [json transforms](Transforms::json) are given the body as a JSON value instead. Binary bodies
+//! cannot be edited field by field, so routes with JSON transforms are only served as JSON: any
+//! other response fails with 406 Not Acceptable, rather than being sent without the transform,
+//! since a transform which hides fields must not be bypassed by asking for another format.
+//!
+//! ```
+//! use net::headers::{LedgerHeight, TypedHeader};
+//! use net::server::Transforms;
+//!
+//! let mut app = tide::new();
+//! app.with(
+//!     Transforms::new()
+//!         .all(|res, _body| {
+//!             LedgerHeight(42).insert(res);
+//!             Ok(())
+//!         })
+//!         .json("/account/:address", |_res, body| {
+//!             if let Some(fields) = body.as_object_mut() {
+//!                 fields.remove("balance");
+//!             }
+//!             Ok(())
+//!         }),
+//! );
+//! ```
+//!
+//! Transforms run in the order they were registered, and only on successful responses. The body is
+//! read into memory before the transforms run, so they should not be applied to routes with
+//! streaming responses.
+
+use super::route::RouteTemplate;
+use crate::wire::body::read_body;
+use std::sync::Arc;
+use tide::http::{mime, Body};
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+type Hook = Arc<dyn Fn(&mut Response, &mut Vec<u8>) -> tide::Result<()> + Send + Sync>;
+
+/// Server middleware which transforms serialized responses.
+///
+/// See the [module documentation](self).
+#[derive(Clone, Default)]
+pub struct Transforms {
+    // Each transform, with the template of the routes it applies to, or [None] for all routes.
+    hooks: Vec<(Option<RouteTemplate>, Hook)>,
+}
+
+impl Transforms {
+    /// Transform no responses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Transform responses from all routes with `f`.
+    pub fn all(
+        mut self,
+        f: impl Fn(&mut Response, &mut Vec<u8>) -> tide::Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.push((None, Arc::new(f)));
+        self
+    }
+
+    /// Transform responses from routes matching `template` with `f`.
+    pub fn route(
+        mut self,
+        template: impl Into<String>,
+        f: impl Fn(&mut Response, &mut Vec<u8>) -> tide::Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks
+            .push((Some(RouteTemplate::new(template)), Arc::new(f)));
+        self
+    }
+
+    /// Transform JSON responses from routes matching `template` with `f`.
+    ///
+    /// The template `*` matches all routes. Successful responses from matching routes which are not
+    /// JSON fail with 406 Not Acceptable.
+    pub fn json<F>(self, template: impl Into<String>, f: F) -> Self
+    where
+        F: Fn(&mut Response, &mut serde_json::Value) -> tide::Result<()> + Send + Sync + 'static,
+    {
+        self.route(template, move |res, body| {
+            if !is_json(res) {
+                return Err(tide::Error::from_str(
+                    StatusCode::NotAcceptable,
+                    "this route is only served as JSON",
+                ));
+            }
+            let mut value = serde_json::from_slice(body)?;
+            f(res, &mut value)?;
+            *body = serde_json::to_vec(&value)?;
+            Ok(())
+        })
+    }
+
+    fn hooks<'a>(&'a self, path: &'a str) -> impl Iterator<Item = &'a Hook> + 'a {
+        self.hooks
+            .iter()
+            .filter(move |(template, _)| template.as_ref().map_or(true, |t| t.matches(path)))
+            .map(|(_, hook)| hook)
+    }
+}
+
+fn is_json(res: &Response) -> bool {
+    res.content_type()
+        .map_or(false, |ty| ty.essence() == mime::JSON.essence())
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Transforms {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let path = req.url().path().to_string();
+        let mut res = next.run(req).await;
+        if res.error().is_some() || !res.status().is_success() {
+            return Ok(res);
+        }
+        let mut hooks = self.hooks(&path).peekable();
+        if hooks.peek().is_none() {
+            return Ok(res);
+        }
+
+        let body = res.take_body();
+        let mime = body.mime().clone();
+        let mut bytes = read_body(body).await?;
+        for hook in hooks {
+            hook(&mut res, &mut bytes)?;
+        }
+        let mut body = Body::from_bytes(bytes);
+        body.set_mime(mime);
+        res.set_body(body);
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::headers::{LedgerHeight, TypedHeader};
+    use crate::redact::Public;
+    use crate::server::response;
+    use serde_json::json;
+    use tide::http::{Method, Url};
+
+    async fn get(app: &tide::Server<()>, path: &str, accept: &str) -> tide::http::Response {
+        let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+        let mut req = tide::http::Request::new(Method::Get, url);
+        req.insert_header("Accept", accept);
+        app.respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_transforms() {
+        let mut app = tide::new();
+        app.with(
+            Transforms::new()
+                .all(|res, _| {
+                    LedgerHeight(7).insert(res);
+                    Ok(())
+                })
+                .json("/account/:address", |_, body| {
+                    body.as_object_mut().unwrap().remove("balance");
+                    Ok(())
+                }),
+        );
+        app.at("/account/:address")
+            .get(|req: tide::Request<()>| async move {
+                response(&req, Public(json!({"address": "alice", "balance": 100})))
+            });
+        app.at("/status").get(|_| async {
+            Ok(Response::builder(StatusCode::Ok)
+                .body(json!({"balance": 100}))
+                .build())
+        });
+        app.at("/missing")
+            .get(|_| async { Ok(Response::new(StatusCode::NotFound)) });
+
+        // JSON transforms apply to JSON bodies on matching routes.
+        let mut res = get(&app, "/account/alice", "application/json").await;
+        assert_eq!(LedgerHeight::get(&res).unwrap(), Some(LedgerHeight(7)));
+        assert_eq!(res.content_type(), Some(mime::JSON));
+        assert_eq!(
+            res.body_json::<serde_json::Value>().await.unwrap(),
+            json!({"address": "alice"})
+        );
+
+        // Binary bodies cannot be transformed, so they are not served at all.
+        let res = get(&app, "/account/alice", "application/octet-stream").await;
+        assert_eq!(res.status(), StatusCode::NotAcceptable);
+
+        // Other routes only get the transforms for all routes.
+        let mut res = get(&app, "/status", "application/json").await;
+        assert_eq!(LedgerHeight::get(&res).unwrap(), Some(LedgerHeight(7)));
+        assert_eq!(
+            res.body_json::<serde_json::Value>().await.unwrap(),
+            json!({"balance": 100})
+        );
+
+        // Unsuccessful responses are not transformed.
+        let res = get(&app, "/missing", "application/json").await;
+        assert_eq!(LedgerHeight::get(&res).unwrap(), None);
+    }
+}
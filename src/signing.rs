@@ -0,0 +1,503 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Signed responses, for auditable records of what a service claimed.
+//!
+//! A server holding a service key can sign the responses it sends with [respond_signed]. So that a
+//! signed response cannot be passed off as the answer to a different request, the signature covers
+//! the request as well as the body: it is a signature of the
+//! [canonical JSON](crate::wire::canonical_json) encoding of the array
+//! `[method, path, query, timestamp, body]`, where `query` is the query string without the `?`
+//! (empty if there is none), `timestamp` is the time of signing in seconds since the Unix epoch,
+//! and `body` is the response body as a value, regardless of the content type it was sent in. The
+//! signature is sent in the [RESPONSE_SIGNATURE] header as hex-encoded Ed25519, and the timestamp
+//! in the [SIGNED_AT] header. Since canonical JSON is fully specified, clients in other languages
+//! can reproduce the signed bytes from the JSON body and verify the signature themselves.
+//!
+//! A client configured with the service's public key reads signed responses with
+//! [verified_response_body], passing the [SignedRequest] it sent. This fails with a
+//! [SignatureError] if the signature is missing or does not match, or if the timestamp is more
+//! than [MAX_SIGNATURE_SKEW] seconds from the local clock. The result is a [Signed] value, which
+//! keeps the request, timestamp and signature alongside the body, so that it can be stored and
+//! verified again later, by anyone with the public key, as proof of what the service said.
+//!
+//! The path and query are signed as the service sees them, so gateways between the client and the
+//! service must not rewrite them.
+//!
+//! To rotate its key without breaking clients, a service keeps its keys in a
+//! [Keyring](crate::keyring::Keyring) and signs with [respond_signed_by], which names the key it
+//! used in the [SIGNING_KEY_ID] header. Clients which keep the service's public keys in a keyring
+//! too read these responses with [verified_response_body_by], which checks the signature against
+//! the named key.
+
+#[cfg(feature = "client")]
+use crate::client::response_body;
+#[cfg(any(
+    feature = "client",
+    all(feature = "server", not(target_arch = "wasm32"))
+))]
+use crate::headers::{ResponseSignature, SignedAt, SigningKeyId, TypedHeader};
+#[cfg(any(
+    feature = "client",
+    all(feature = "server", not(target_arch = "wasm32"))
+))]
+use crate::keyring::Keyring;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+use crate::redact::SafeToServe;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+use crate::server::response_untrimmed;
+use crate::wire::canonical_json;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+use ed25519_dalek::Signer;
+use ed25519_dalek::{Signature, Verifier};
+#[cfg(feature = "client")]
+use http_types::StatusCode;
+#[cfg(feature = "client")]
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+#[cfg(all(
+    any(feature = "client", feature = "server"),
+    not(target_arch = "wasm32")
+))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+pub use crate::headers::{RESPONSE_SIGNATURE, SIGNED_AT, SIGNING_KEY_ID};
+
+/// How far, in seconds, the timestamp of a signed response may be from the client's clock.
+pub const MAX_SIGNATURE_SKEW: u64 = 5 * 60;
+
+/// Reasons a signed response fails to verify.
+#[derive(Clone, Debug, Snafu, PartialEq, Eq)]
+pub enum SignatureError {
+    #[snafu(display("response is not signed"))]
+    Missing,
+    #[snafu(display("malformed response signature: {}", reason))]
+    Malformed { reason: String },
+    #[snafu(display("response signature does not match the service key"))]
+    Mismatch,
+    #[snafu(display("response is signed by unknown key {}", id))]
+    UnknownKey { id: String },
+    #[snafu(display("response was signed at {}, too far from the local clock", timestamp))]
+    Stale { timestamp: u64 },
+}
+
+/// The canonical serialization of a value.
+pub fn canonical_bytes<T: Serialize>(body: &T) -> Result<Vec<u8>, serde_json::Error> {
+    canonical_json::to_vec(body)
+}
+
+/// The request which a signed response answers.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SignedRequest {
+    /// The method, in upper case.
+    pub method: String,
+    pub path: String,
+    /// The query string, without the leading `?`, or empty if there is none.
+    pub query: String,
+}
+
+impl SignedRequest {
+    /// A request with `method` for `url`.
+    pub fn new(method: impl ToString, url: &http_types::Url) -> Self {
+        Self {
+            method: method.to_string().to_uppercase(),
+            path: url.path().to_string(),
+            query: url.query().unwrap_or_default().to_string(),
+        }
+    }
+
+    /// A GET request for `url`.
+    pub fn get(url: &http_types::Url) -> Self {
+        Self::new("GET", url)
+    }
+}
+
+// The bytes signed for a response to `request`, with `body`, signed at `timestamp`.
+fn signed_bytes<T: Serialize>(
+    request: &SignedRequest,
+    timestamp: u64,
+    body: &T,
+) -> Result<Vec<u8>, serde_json::Error> {
+    canonical_bytes(&(
+        &request.method,
+        &request.path,
+        &request.query,
+        timestamp,
+        body,
+    ))
+}
+
+#[cfg(all(
+    any(feature = "client", feature = "server"),
+    not(target_arch = "wasm32")
+))]
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// The system clock is not available in browsers, so this asks JavaScript instead.
+#[cfg(all(feature = "client", target_arch = "wasm32"))]
+pub(crate) fn unix_time() -> u64 {
+    (js_sys::Date::now() / 1000.0) as u64
+}
+
+/// Respond to `req` with `body`, signed by `key`.
+///
+/// The signature covers the method, path and query of `req`, the current time and `body`; see the
+/// [module documentation](self). The body is serialized as by [response](crate::server::response),
+/// except that it is always sent in full, even if the request selects some of its
+/// [fields](crate::fields), and with integers as numbers, even if the request asks for strings
+/// with a [JsonIntegers](crate::headers::JsonIntegers) header, so that the body matches the
+/// signature.
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub fn respond_signed<T: Serialize + SafeToServe, S>(
+    req: &tide::Request<S>,
+    key: &SigningKey,
+    body: T,
+) -> Result<tide::Response, tide::Error> {
+    let request = SignedRequest::new(req.method(), req.url());
+    let timestamp = unix_time();
+    let signature = key.sign(&signed_bytes(&request, timestamp, &body)?);
+    let mut res = response_untrimmed(req, body)?;
+    ResponseSignature(signature.to_bytes().to_vec()).insert(&mut res);
+    SignedAt(timestamp).insert(&mut res);
+    Ok(res)
+}
+
+/// Respond with `body`, signed by the active key in `keys`.
+///
+/// The ID of the key is sent in the [SIGNING_KEY_ID] header.
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub fn respond_signed_by<T: Serialize + SafeToServe, S>(
+    req: &tide::Request<S>,
+    keys: &Keyring<SigningKey>,
+    body: T,
+) -> Result<tide::Response, tide::Error> {
+    let (id, key) = keys.active();
+    let mut res = respond_signed(req, &key, body)?;
+    SigningKeyId(id).insert(&mut res);
+    Ok(res)
+}
+
+/// A response body along with the request it answered and the service's signature of both.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Signed<T> {
+    pub request: SignedRequest,
+    /// When the response was signed, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub body: T,
+    pub signature: Vec<u8>,
+}
+
+impl<T: Serialize> Signed<T> {
+    /// Check that `key` signed this body, as the response to this request at this time.
+    ///
+    /// This does not check the timestamp, so that stored records can be verified at any later
+    /// time.
+    pub fn verify(&self, key: &VerifyingKey) -> Result<(), SignatureError> {
+        let signature =
+            Signature::from_slice(&self.signature).map_err(|err| SignatureError::Malformed {
+                reason: err.to_string(),
+            })?;
+        let bytes = signed_bytes(&self.request, self.timestamp, &self.body).map_err(|err| {
+            SignatureError::Malformed {
+                reason: err.to_string(),
+            }
+        })?;
+        key.verify(&bytes, &signature)
+            .map_err(|_| SignatureError::Mismatch)
+    }
+}
+
+/// Deserialize the body of a signed response to `request` and check that it was signed by `key`.
+///
+/// If the signature is missing or does not match, or it was made more than [MAX_SIGNATURE_SKEW]
+/// seconds from the local time, the error contains a [SignatureError], which can be recovered with
+/// [surf::Error::downcast_ref].
+#[cfg(feature = "client")]
+pub async fn verified_response_body<T: DeserializeOwned + Serialize>(
+    res: &mut surf::Response,
+    request: &SignedRequest,
+    key: &VerifyingKey,
+) -> Result<Signed<T>, surf::Error> {
+    let malformed = |err: String| signature_error(SignatureError::Malformed { reason: err });
+    let signature = ResponseSignature::get(&*res).map_err(|err| malformed(err.to_string()))?;
+    let timestamp = SignedAt::get(&*res).map_err(|err| malformed(err.to_string()))?;
+    let body = response_body(res).await?;
+    let signed = match (signature, timestamp) {
+        (Some(ResponseSignature(signature)), Some(SignedAt(timestamp))) => Signed {
+            request: request.clone(),
+            timestamp,
+            body,
+            signature,
+        },
+        _ => return Err(signature_error(SignatureError::Missing)),
+    };
+    signed.verify(key).map_err(signature_error)?;
+    if unix_time().abs_diff(signed.timestamp) > MAX_SIGNATURE_SKEW {
+        return Err(signature_error(SignatureError::Stale {
+            timestamp: signed.timestamp,
+        }));
+    }
+    Ok(signed)
+}
+
+/// Deserialize the body of a signed response to `request` and check that it was signed by one of
+/// `keys`.
+///
+/// The signature is checked against the key named in the [SIGNING_KEY_ID] header, or against the
+/// active key if the response does not name one. A response signed by a key which is not in
+/// `keys`, or whose grace period has ended, fails with [SignatureError::UnknownKey].
+#[cfg(feature = "client")]
+pub async fn verified_response_body_by<T: DeserializeOwned + Serialize>(
+    res: &mut surf::Response,
+    request: &SignedRequest,
+    keys: &Keyring<VerifyingKey>,
+) -> Result<Signed<T>, surf::Error> {
+    let id = SigningKeyId::get(&*res).map_err(|err| {
+        signature_error(SignatureError::Malformed {
+            reason: err.to_string(),
+        })
+    })?;
+    let key = match id {
+        Some(SigningKeyId(id)) => keys
+            .get(&id)
+            .ok_or_else(|| signature_error(SignatureError::UnknownKey { id }))?,
+        None => keys.active().1,
+    };
+    verified_response_body(res, request, &key).await
+}
+
+#[cfg(feature = "client")]
+fn signature_error(err: SignatureError) -> surf::Error {
+    surf::Error::new(StatusCode::InternalServerError, err)
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod test {
+    use super::*;
+    use tide::http::{Method, Request, Url};
+
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    struct Balance {
+        owner: String,
+        amount: u64,
+    }
+
+    impl SafeToServe for Balance {}
+
+    fn balance() -> Balance {
+        Balance {
+            owner: "alice".to_string(),
+            amount: 100,
+        }
+    }
+
+    fn request(path: &str) -> SignedRequest {
+        SignedRequest::get(&url(path))
+    }
+
+    fn url(path: &str) -> Url {
+        Url::parse("http://localhost").unwrap().join(path).unwrap()
+    }
+
+    async fn get(key: SigningKey, accept: &str) -> surf::Response {
+        get_path(key, "/balance", accept).await
+    }
+
+    async fn get_path(key: SigningKey, path: &str, accept: &str) -> surf::Response {
+        let mut server = tide::new();
+        server.at("/balance").get(move |req: tide::Request<()>| {
+            let key = key.clone();
+            async move { respond_signed(&req, &key, balance()) }
+        });
+        let mut req = Request::new(Method::Get, url(path));
+        req.insert_header("Accept", accept);
+        let res: tide::http::Response = server.respond(req).await.unwrap();
+        res.into()
+    }
+
+    #[async_std::test]
+    async fn test_signed_response() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let public = key.verifying_key();
+        let balance_request = request("/balance");
+
+        // The signature does not depend on the content type.
+        for accept in ["application/json", "application/octet-stream"] {
+            let mut res = get(key.clone(), accept).await;
+            let signed = verified_response_body::<Balance>(&mut res, &balance_request, &public)
+                .await
+                .unwrap();
+            assert_eq!(signed.body, balance());
+            assert_eq!(signed.request, balance_request);
+
+            // The record can be verified again later.
+            let record: Signed<Balance> =
+                crate::wire::binary::deserialize(&crate::wire::binary::serialize(&signed).unwrap())
+                    .unwrap();
+            record.verify(&public).unwrap();
+
+            // Tampering with any part of it is detected.
+            let mut forged = record.clone();
+            forged.body.amount = 1_000_000;
+            assert_eq!(forged.verify(&public), Err(SignatureError::Mismatch));
+            let mut forged = record.clone();
+            forged.request.path = "/other".into();
+            assert_eq!(forged.verify(&public), Err(SignatureError::Mismatch));
+            let mut forged = record;
+            forged.timestamp += 1;
+            assert_eq!(forged.verify(&public), Err(SignatureError::Mismatch));
+        }
+
+        // A verifier which only has the JSON body, like a client in another language, can
+        // reproduce the signed bytes by canonicalizing it along with the request.
+        let mut res = get(key.clone(), "application/json").await;
+        let signature = hex::decode(res[RESPONSE_SIGNATURE].as_str()).unwrap();
+        let timestamp: u64 = res[SIGNED_AT].as_str().parse().unwrap();
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        let signed = serde_json::json!(["GET", "/balance", "", timestamp, body]);
+        let bytes = canonical_json::canonicalize(&serde_json::to_vec(&signed).unwrap()).unwrap();
+        public
+            .verify(&bytes, &Signature::from_slice(&signature).unwrap())
+            .unwrap();
+
+        // A response to one request cannot be passed off as the response to another.
+        let mut res = get_path(key.clone(), "/balance?owner=alice", "application/json").await;
+        let err =
+            verified_response_body::<Balance>(&mut res, &request("/balance?owner=bob"), &public)
+                .await
+                .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SignatureError>(),
+            Some(&SignatureError::Mismatch)
+        );
+
+        // Signed responses are not trimmed to the selected fields, which would break the
+        // signature.
+        let path = "/balance?fields=amount";
+        let mut res = get_path(key.clone(), path, "application/json").await;
+        let signed = verified_response_body::<Balance>(&mut res, &request(path), &public)
+            .await
+            .unwrap();
+        assert_eq!(signed.body, balance());
+
+        // Nor are their integers written as strings, which would change the canonical JSON a
+        // verifier in another language reproduces from the body.
+        let mut server = tide::new();
+        server.at("/balance").get({
+            let key = key.clone();
+            move |req: tide::Request<()>| {
+                let key = key.clone();
+                async move { respond_signed(&req, &key, balance()) }
+            }
+        });
+        let mut req = Request::new(Method::Get, url("/balance"));
+        req.insert_header("Accept", "application/json");
+        req.insert_header(crate::headers::JSON_INTEGERS, "string");
+        let res: tide::http::Response = server.respond(req).await.unwrap();
+        let mut res = surf::Response::from(res);
+        let signature = hex::decode(res[RESPONSE_SIGNATURE].as_str()).unwrap();
+        let timestamp: u64 = res[SIGNED_AT].as_str().parse().unwrap();
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["amount"], serde_json::json!(100));
+        let signed = serde_json::json!(["GET", "/balance", "", timestamp, body]);
+        let bytes = canonical_json::canonicalize(&serde_json::to_vec(&signed).unwrap()).unwrap();
+        public
+            .verify(&bytes, &Signature::from_slice(&signature).unwrap())
+            .unwrap();
+
+        // A response signed by a different key is rejected.
+        let mut res = get(SigningKey::from_bytes(&[2; 32]), "application/json").await;
+        let err = verified_response_body::<Balance>(&mut res, &balance_request, &public)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SignatureError>(),
+            Some(&SignatureError::Mismatch)
+        );
+    }
+
+    #[async_std::test]
+    async fn test_stale_signature() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let balance_request = request("/balance");
+        let timestamp = unix_time() - MAX_SIGNATURE_SKEW - 1;
+        let signature = key.sign(&signed_bytes(&balance_request, timestamp, &balance()).unwrap());
+        let mut res = tide::http::Response::new(tide::StatusCode::Ok);
+        res.set_body(serde_json::to_vec(&balance()).unwrap());
+        res.set_content_type(tide::http::mime::JSON);
+        ResponseSignature(signature.to_bytes().to_vec()).insert(&mut res);
+        SignedAt(timestamp).insert(&mut res);
+        let mut res = surf::Response::from(res);
+
+        // The signature is valid, but too old to be the answer to a request just sent.
+        let public = key.verifying_key();
+        let err = verified_response_body::<Balance>(&mut res, &balance_request, &public)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SignatureError>(),
+            Some(&SignatureError::Stale { timestamp })
+        );
+    }
+
+    #[async_std::test]
+    async fn test_key_rotation() {
+        let keys = Keyring::new("old", SigningKey::from_bytes(&[1; 32]));
+        let mut server = tide::new();
+        server.at("/balance").get({
+            let keys = keys.clone();
+            move |req: tide::Request<()>| {
+                let keys = keys.clone();
+                async move { respond_signed_by(&req, &keys, balance()) }
+            }
+        });
+        let balance_request = request("/balance");
+        let get = || async {
+            let req = Request::new(Method::Get, url("/balance"));
+            let res: tide::http::Response = server.respond(req).await.unwrap();
+            surf::Response::from(res)
+        };
+
+        // A client which knows both keys accepts responses signed by either.
+        let public = Keyring::new("old", SigningKey::from_bytes(&[1; 32]).verifying_key());
+        let mut res = get().await;
+        assert_eq!(res[SIGNING_KEY_ID], "old");
+        verified_response_body_by::<Balance>(&mut res, &balance_request, &public)
+            .await
+            .unwrap();
+        keys.rotate(
+            "new",
+            SigningKey::from_bytes(&[2; 32]),
+            std::time::Duration::from_secs(60),
+        );
+        let mut res = get().await;
+        let err = verified_response_body_by::<Balance>(&mut res, &balance_request, &public)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SignatureError>(),
+            Some(&SignatureError::UnknownKey { id: "new".into() })
+        );
+        public.rotate(
+            "new",
+            SigningKey::from_bytes(&[2; 32]).verifying_key(),
+            std::time::Duration::from_secs(60),
+        );
+        let mut res = get().await;
+        verified_response_body_by::<Balance>(&mut res, &balance_request, &public)
+            .await
+            .unwrap();
+    }
+}
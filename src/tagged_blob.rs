@@ -5,29 +5,87 @@
 // This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
 // You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use alloc::string::String;
+use alloc::vec::Vec;
 use ark_serialize::*;
-use fmt::Debug;
+use core::fmt::Debug;
 use jf_utils::Tagged;
-use snafu::{ResultExt, Snafu};
-use std::fmt;
+use snafu::Snafu;
 use tagged_base64::TaggedBase64;
 
 // Helper trait with a blanket implementation allowing us to convert TaggedBase64 to any type which
 // implements Tagged and CanonicalDeserialize.
+//
+// Tagged base64 is the standard encoding, but the raw bytes of the canonical serialization are
+// also available, as hex or as they are, for tools which cannot produce tagged base64.
 pub trait TaggedBlob: Sized + Tagged + CanonicalDeserialize {
     fn from_tagged_blob(b64: &TaggedBase64) -> Result<Self, TaggedBlobError>;
+
+    /// The canonical serialization of this value, without a tag.
+    fn as_bytes(&self) -> Vec<u8>
+    where
+        Self: CanonicalSerialize,
+    {
+        let mut bytes = Vec::new();
+        self.serialize(&mut bytes)
+            .expect("serializing to a Vec cannot fail");
+        bytes
+    }
+
+    /// Deserialize a value from its canonical serialization, without a tag.
+    fn from_bytes(bytes: &[u8]) -> Result<Self, TaggedBlobError> {
+        Self::deserialize(bytes).map_err(|source| TaggedBlobError::SerError { source })
+    }
+
+    /// The canonical serialization of this value, as lowercase hex with a `0x` prefix.
+    fn to_hex(&self) -> String
+    where
+        Self: CanonicalSerialize,
+    {
+        alloc::format!("0x{}", hex::encode(self.as_bytes()))
+    }
+
+    /// Deserialize a value from the hex encoding of its canonical serialization.
+    ///
+    /// The `0x` prefix is optional, and either case is accepted.
+    fn from_hex(s: &str) -> Result<Self, TaggedBlobError> {
+        let digits = s
+            .strip_prefix("0x")
+            .or_else(|| s.strip_prefix("0X"))
+            .unwrap_or(s);
+        let bytes = hex::decode(digits).map_err(|err| TaggedBlobError::InvalidHex {
+            reason: alloc::format!("{}", err),
+        })?;
+        Self::from_bytes(&bytes)
+    }
 }
 
 #[derive(Debug, Snafu)]
 pub enum TaggedBlobError {
-    SerError { source: SerializationError },
-    TagMismatch { actual: String, expected: String },
+    // Without `std`, SerializationError does not implement the error trait snafu requires of a
+    // source.
+    SerError {
+        #[cfg_attr(not(feature = "std"), snafu(source(false)))]
+        source: SerializationError,
+    },
+    TagMismatch {
+        actual: String,
+        expected: String,
+    },
+    #[snafu(display("invalid hex: {}", reason))]
+    InvalidHex {
+        reason: String,
+    },
+    #[snafu(display("{} mixes the standard and URL-safe base64 alphabets", value))]
+    MixedAlphabets {
+        value: String,
+    },
 }
 
 impl<T: Tagged + CanonicalDeserialize> TaggedBlob for T {
     fn from_tagged_blob(b64: &TaggedBase64) -> Result<Self, TaggedBlobError> {
         if b64.tag() == Self::tag() {
-            Self::deserialize(&*b64.value()).context(SerSnafu)
+            Self::from_bytes(&b64.value())
         } else {
             Err(TaggedBlobError::TagMismatch {
                 actual: b64.tag(),
@@ -36,3 +94,229 @@ impl<T: Tagged + CanonicalDeserialize> TaggedBlob for T {
         }
     }
 }
+
+/// A serde adapter for tagged blobs which also accepts hex.
+///
+/// Use this with `#[serde(with = "net::tagged_or_hex")]` on a field of a [TaggedBlob] type. Values
+/// are always serialized the standard way, as tagged base64 in human-readable formats like JSON.
+/// When deserializing from a human-readable format, a hex string with a `0x` prefix is also
+/// accepted, and interpreted as the canonical serialization of the value, for interoperability
+/// with Ethereum tooling which cannot produce tagged base64. In binary formats, the value is the
+/// bytes of its canonical serialization.
+///
+/// ```ignore
+/// #[derive(Serialize, Deserialize)]
+/// struct Deposit {
+///     #[serde(with = "net::tagged_or_hex")]
+///     owner: UserAddress,
+///     amount: u64,
+/// }
+/// ```
+pub mod tagged_or_hex {
+    use super::*;
+    use serde::{de::Error as _, ser::Error as _, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: TaggedBlob + CanonicalSerialize,
+        S: Serializer,
+    {
+        let bytes = value.as_bytes();
+        if serializer.is_human_readable() {
+            let b64 = TaggedBase64::new(&T::tag(), &bytes).map_err(S::Error::custom)?;
+            serializer.collect_str(&b64)
+        } else {
+            serializer.serialize_bytes(&bytes)
+        }
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: TaggedBlob,
+        D: Deserializer<'de>,
+    {
+        if deserializer.is_human_readable() {
+            let s = <String as Deserialize>::deserialize(deserializer)?;
+            if s.starts_with("0x") || s.starts_with("0X") {
+                T::from_hex(&s).map_err(D::Error::custom)
+            } else {
+                let s = normalize_tagged_base64(&s).map_err(D::Error::custom)?;
+                let b64 = TaggedBase64::parse(&s).map_err(D::Error::custom)?;
+                T::from_tagged_blob(&b64).map_err(D::Error::custom)
+            }
+        } else {
+            let bytes = <Vec<u8> as Deserialize>::deserialize(deserializer)?;
+            T::from_bytes(&bytes).map_err(D::Error::custom)
+        }
+    }
+}
+
+/// A way in which a tagged base64 string differs from the canonical form.
+///
+/// The canonical form of a tagged base64 value uses the URL-safe base64 alphabet (`-` and `_`)
+/// without padding, so it can be used in a URL as it is. Values which were produced by other
+/// tools, or copied through systems which re-encode them, may use the standard alphabet (`+` and
+/// `/`) or padding instead. See [lint_tagged_base64].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Base64Lint {
+    /// The value uses `+` or `/`, which have meanings in URLs.
+    StandardAlphabet,
+    /// The value ends with `=` padding.
+    Padding,
+    /// The value uses characters from both alphabets, so it is not a valid encoding in either, and
+    /// it is ambiguous which was intended.
+    MixedAlphabets,
+}
+
+/// Find the ways in which a tagged base64 string differs from the canonical form.
+///
+/// The result is empty if `value` is canonical. Only the part after the `~` delimiter, if there is
+/// one, is checked, since tags may contain `-` and `_`.
+pub fn lint_tagged_base64(value: &str) -> Vec<Base64Lint> {
+    let data = value.split_once('~').map_or(value, |(_, data)| data);
+    let standard = data.contains(['+', '/']);
+    let url_safe = data.contains(['-', '_']);
+    let mut lints = Vec::new();
+    if standard && url_safe {
+        lints.push(Base64Lint::MixedAlphabets);
+    } else if standard {
+        lints.push(Base64Lint::StandardAlphabet);
+    }
+    if data.ends_with('=') {
+        lints.push(Base64Lint::Padding);
+    }
+    lints
+}
+
+/// Convert a tagged base64 string to the canonical form.
+///
+/// Values in the standard alphabet are translated to the URL-safe alphabet, and padding is removed.
+/// Values which mix the two alphabets are rejected, since they are not valid in either.
+pub fn normalize_tagged_base64(value: &str) -> Result<String, TaggedBlobError> {
+    let lints = lint_tagged_base64(value);
+    if lints.contains(&Base64Lint::MixedAlphabets) {
+        return Err(TaggedBlobError::MixedAlphabets {
+            value: value.into(),
+        });
+    }
+    if lints.is_empty() {
+        return Ok(value.into());
+    }
+    let (tag, data) = match value.split_once('~') {
+        Some((tag, data)) => (Some(tag), data),
+        None => (None, value),
+    };
+    let data: String = data
+        .trim_end_matches('=')
+        .chars()
+        .map(|c| match c {
+            '+' => '-',
+            '/' => '_',
+            c => c,
+        })
+        .collect();
+    Ok(match tag {
+        Some(tag) => alloc::format!("{}~{}", tag, data),
+        None => data,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, CanonicalSerialize, CanonicalDeserialize)]
+    struct Blob(u64);
+
+    impl Tagged for Blob {
+        fn tag() -> String {
+            "BLOB".into()
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Wrapper {
+        #[serde(with = "tagged_or_hex")]
+        blob: Blob,
+    }
+
+    #[test]
+    fn test_hex() {
+        let blob = Blob(0x0102030405060708);
+        assert_eq!(blob.as_bytes(), [8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(blob.to_hex(), "0x0807060504030201");
+        for hex in [
+            "0x0807060504030201",
+            "0807060504030201",
+            "0X0807060504030201",
+        ] {
+            assert_eq!(Blob::from_hex(hex).unwrap(), blob);
+        }
+        assert!(matches!(
+            Blob::from_hex("0x08070605040302"),
+            Err(TaggedBlobError::SerError { .. })
+        ));
+        assert!(matches!(
+            Blob::from_hex("0xzz"),
+            Err(TaggedBlobError::InvalidHex { .. })
+        ));
+    }
+
+    #[test]
+    fn test_tagged_or_hex() {
+        let wrapper = Wrapper {
+            blob: Blob(0x0102030405060708),
+        };
+        let b64 = TaggedBase64::new("BLOB", &wrapper.blob.as_bytes()).unwrap();
+
+        // JSON uses tagged base64, and also accepts hex.
+        let json = serde_json::to_value(&wrapper).unwrap();
+        assert_eq!(json, serde_json::json!({ "blob": b64.to_string() }));
+        assert_eq!(serde_json::from_value::<Wrapper>(json).unwrap(), wrapper);
+        let json = serde_json::json!({ "blob": "0x0807060504030201" });
+        assert_eq!(serde_json::from_value::<Wrapper>(json).unwrap(), wrapper);
+
+        // The tag is still checked.
+        let other = TaggedBase64::new("OTHER", &wrapper.blob.as_bytes()).unwrap();
+        let json = serde_json::json!({ "blob": other.to_string() });
+        assert!(serde_json::from_value::<Wrapper>(json).is_err());
+
+        // Binary formats use the canonical serialization.
+        #[cfg(feature = "std")]
+        {
+            let bytes = crate::wire::binary::serialize(&wrapper).unwrap();
+            assert_eq!(
+                crate::wire::binary::deserialize::<Wrapper>(&bytes).unwrap(),
+                wrapper
+            );
+        }
+    }
+
+    #[test]
+    fn test_normalize() {
+        // Canonical values are unchanged, including tags with URL-safe characters.
+        assert_eq!(lint_tagged_base64("TX~ab-c_d"), []);
+        assert_eq!(lint_tagged_base64("MY_TAG~abcd"), []);
+        assert_eq!(normalize_tagged_base64("TX~ab-c_d").unwrap(), "TX~ab-c_d");
+
+        assert_eq!(
+            lint_tagged_base64("TX~ab+c/dA=="),
+            [Base64Lint::StandardAlphabet, Base64Lint::Padding]
+        );
+        assert_eq!(
+            normalize_tagged_base64("TX~ab+c/dA==").unwrap(),
+            "TX~ab-c_dA"
+        );
+        assert_eq!(normalize_tagged_base64("ab+c").unwrap(), "ab-c");
+
+        assert_eq!(
+            lint_tagged_base64("TX~ab+c_d"),
+            [Base64Lint::MixedAlphabets]
+        );
+        assert!(matches!(
+            normalize_tagged_base64("TX~ab+c_d"),
+            Err(TaggedBlobError::MixedAlphabets { .. })
+        ));
+    }
+}
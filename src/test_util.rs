@@ -0,0 +1,56 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Helpers shared by the tests of other modules.
+
+use http_types::StatusCode;
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+
+/// The error type of services in tests.
+///
+/// Its status is 400, so that tests can tell errors of the service from the 500s of a failure in
+/// the framework.
+#[derive(Clone, Debug, Deserialize, Serialize, Snafu, PartialEq, Eq)]
+#[snafu(display("{}", msg))]
+pub struct Error {
+    pub msg: String,
+}
+
+impl crate::Error for Error {
+    fn catch_all(msg: String) -> Self {
+        Self { msg }
+    }
+
+    fn status(&self) -> StatusCode {
+        StatusCode::BadRequest
+    }
+}
+
+#[cfg(all(feature = "client", feature = "server"))]
+pub use server::Server;
+
+#[cfg(all(feature = "client", feature = "server"))]
+mod server {
+    use surf::middleware::{Middleware, Next};
+    use surf::{Client, Request};
+
+    /// Client middleware which serves requests with `app` in process, in place of the network.
+    ///
+    /// This must be the last middleware of a request, since it does not pass the request on.
+    #[derive(Clone)]
+    pub struct Server<State>(pub tide::Server<State>);
+
+    #[surf::utils::async_trait]
+    impl<State: Clone + Send + Sync + 'static> Middleware for Server<State> {
+        async fn handle(&self, req: Request, _client: Client, _next: Next<'_>) -> surf::Result {
+            let req: surf::http::Request = req.into();
+            let res: surf::http::Response = self.0.respond(req).await?;
+            Ok(res.into())
+        }
+    }
+}
@@ -0,0 +1,16 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Utilities for testing API types and services.
+//!
+//! This module is only available with the `testing` feature, which downstream crates should enable
+//! in their dev-dependencies.
+
+pub mod arbitrary;
+pub mod conformance;
+pub mod golden;
+pub mod roundtrip;
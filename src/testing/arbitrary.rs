@@ -0,0 +1,74 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! proptest strategies for the types defined in this crate.
+
+use crate::types::*;
+use jf_cap::structs::{ReceiverMemo, RecordCommitment, RecordOpening};
+use proptest::prelude::*;
+use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+
+pub fn hash() -> impl Strategy<Value = Hash> {
+    proptest::collection::vec(any::<u8>(), 0..64).prop_map(Hash)
+}
+
+pub fn block_id() -> impl Strategy<Value = BlockId> {
+    any::<usize>().prop_map(BlockId)
+}
+
+pub fn transaction_id() -> impl Strategy<Value = TransactionId> {
+    (block_id(), any::<usize>()).prop_map(|(block, index)| TransactionId(block, index))
+}
+
+/// Unspent records with random commitments and, optionally, random memos.
+///
+/// Cryptographic values are generated from a seeded RNG, so failing cases can be shrunk and
+/// replayed like any other proptest case.
+pub fn unspent_record() -> impl Strategy<Value = UnspentRecord> {
+    (any::<[u8; 32]>(), any::<u64>(), any::<bool>()).prop_map(|(seed, uid, has_memo)| {
+        let mut rng = ChaChaRng::from_seed(seed);
+        let ro = RecordOpening::rand_for_test(&mut rng);
+        let memo = if has_memo {
+            Some(ReceiverMemo::from_ro(&mut rng, &ro, &[]).unwrap())
+        } else {
+            None
+        };
+        UnspentRecord {
+            commitment: RecordCommitment::from(&ro),
+            uid,
+            memo,
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::roundtrip::assert_roundtrip;
+
+    proptest! {
+        #[test]
+        fn test_hash_roundtrip(value in hash()) {
+            assert_roundtrip(&value);
+        }
+
+        #[test]
+        fn test_block_id_roundtrip(value in block_id()) {
+            assert_roundtrip(&value);
+        }
+
+        #[test]
+        fn test_transaction_id_roundtrip(value in transaction_id()) {
+            assert_roundtrip(&value);
+        }
+
+        #[test]
+        fn test_unspent_record_roundtrip(value in unspent_record()) {
+            assert_roundtrip(&value);
+        }
+    }
+}
@@ -0,0 +1,333 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Checks that a server implements the wire protocol defined by this crate.
+//!
+//! Every service built on this crate should behave identically on the wire: the same content
+//! negotiation, the same error bodies, and the same treatment of malformed requests. The functions
+//! in this module drive a server through each of these cases, at the level of HTTP requests and
+//! responses, and panic with a description of the first deviation they find. They are meant to be
+//! called from a service's own tests, with routes chosen by the service:
+//! ```ignore
+//! #[async_std::test]
+//! async fn test_conformance() {
+//!     let app = my_service::app();
+//!     check_negotiation(&app, "/block/0", &expected_block).await;
+//!     check_error(&app, Method::Get, "/block/999999", &MyError::NotFound).await;
+//!     check_request_body(&app, Method::Post, "/submit", &valid_transaction).await;
+//! }
+//! ```
+//!
+//! The server is driven in-process, with [tide::Server::respond], so no network is needed.
+//!
+//! The protocol, as checked here, is:
+//!  * Responses are serialized as JSON or binary, according to the Accept header, with q-factors
+//!    honored. A request which accepts neither gets 406 Not Acceptable. Without an Accept header,
+//!    either type may be used, but the Content-Type must say which.
+//!  * Error responses have the status of the error, and a body which deserializes as the error in
+//!    whichever type the client accepts.
+//!  * Request bodies are deserialized according to their Content-Type. Bodies of unsupported or
+//!    missing types, bodies which fail to deserialize, and bodies over the size limit are rejected
+//!    with a 4xx status.
+
+use crate::client::{decode_body, decode_error};
+use crate::error::Error;
+use crate::server::serialize_body;
+use crate::wire::binary::SIZE_LIMIT;
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Debug;
+use tide::http::{mime, Body, Method, Request, Response, StatusCode, Url};
+
+async fn send<State: Clone + Send + Sync + 'static>(
+    app: &tide::Server<State>,
+    req: Request,
+) -> (Response, Vec<u8>) {
+    let description = format!("{} {}", req.method(), req.url().path());
+    let mut res: Response = app
+        .respond(req)
+        .await
+        .unwrap_or_else(|err| panic!("{}: server failed to respond: {}", description, err));
+    let bytes = res
+        .body_bytes()
+        .await
+        .unwrap_or_else(|err| panic!("{}: failed to read response body: {}", description, err));
+    (res, bytes)
+}
+
+fn request(method: Method, path: &str, accept: Option<&str>) -> Request {
+    let url = Url::parse("http://localhost")
+        .unwrap()
+        .join(path)
+        .unwrap_or_else(|err| panic!("invalid path {}: {}", path, err));
+    let mut req = Request::new(method, url);
+    if let Some(accept) = accept {
+        req.insert_header("Accept", accept);
+    }
+    req
+}
+
+/// Check that a successful GET of `path` responds with `expected` in every negotiable type.
+pub async fn check_negotiation<State, T>(app: &tide::Server<State>, path: &str, expected: &T)
+where
+    State: Clone + Send + Sync + 'static,
+    T: DeserializeOwned + PartialEq + Debug,
+{
+    // Each accepted type, with and without competing proposals, must be honored.
+    let cases = [
+        ("application/json", mime::JSON),
+        ("application/octet-stream", mime::BYTE_STREAM),
+        (
+            "application/json, application/octet-stream;q=0.5",
+            mime::JSON,
+        ),
+        (
+            "application/json;q=0.5, application/octet-stream",
+            mime::BYTE_STREAM,
+        ),
+        ("application/octet-stream;q=0, */*", mime::JSON),
+        ("application/json;q=0, application/*", mime::BYTE_STREAM),
+    ];
+    for (accept, ty) in cases {
+        let (res, bytes) = send(app, request(Method::Get, path, Some(accept))).await;
+        assert_eq!(
+            res.status(),
+            StatusCode::Ok,
+            "GET {} with Accept: {} failed",
+            path,
+            accept
+        );
+        assert_eq!(
+            res.content_type().map(|ty| ty.essence().to_string()),
+            Some(ty.essence().to_string()),
+            "GET {} with Accept: {} has the wrong Content-Type",
+            path,
+            accept
+        );
+        check_body(path, ty.essence(), &bytes, expected);
+    }
+
+    // Without a preference, either type may be chosen, but it must be labeled correctly.
+    for accept in [None, Some("*/*")] {
+        let (res, bytes) = send(app, request(Method::Get, path, accept)).await;
+        assert_eq!(
+            res.status(),
+            StatusCode::Ok,
+            "GET {} with Accept: {:?} failed",
+            path,
+            accept
+        );
+        let ty = res.content_type().unwrap_or_else(|| {
+            panic!("GET {} with Accept: {:?} has no Content-Type", path, accept)
+        });
+        check_body(path, ty.essence(), &bytes, expected);
+    }
+
+    // A request which accepts no supported type is refused.
+    for accept in [
+        "text/html",
+        "application/json;q=0, application/octet-stream;q=0",
+    ] {
+        let (res, _) = send(app, request(Method::Get, path, Some(accept))).await;
+        assert_eq!(
+            res.status(),
+            StatusCode::NotAcceptable,
+            "GET {} with Accept: {} should be refused",
+            path,
+            accept
+        );
+    }
+}
+
+fn check_body<T: DeserializeOwned + PartialEq + Debug>(
+    path: &str,
+    ty: &str,
+    bytes: &[u8],
+    expected: &T,
+) {
+    let body: T = decode_body(Some(ty), bytes)
+        .unwrap_or_else(|err| panic!("GET {}: {} body fails to deserialize: {}", path, ty, err));
+    assert_eq!(body, *expected, "GET {}: wrong {} body", path, ty);
+}
+
+/// Check that a request with `method` to `path` fails with `expected`, in every negotiable type.
+///
+/// The response must have the status of `expected`, and a body which a client decodes as exactly
+/// `expected`, rather than a catch-all error.
+pub async fn check_error<State, E>(
+    app: &tide::Server<State>,
+    method: Method,
+    path: &str,
+    expected: &E,
+) where
+    State: Clone + Send + Sync + 'static,
+    E: Error + PartialEq + Debug,
+{
+    for ty in [mime::JSON, mime::BYTE_STREAM] {
+        let (res, bytes) = send(app, request(method, path, Some(ty.essence()))).await;
+        assert_eq!(
+            res.status(),
+            expected.status(),
+            "{} {} with Accept: {} has the wrong status",
+            method,
+            path,
+            ty
+        );
+        assert_eq!(
+            res.content_type().map(|ty| ty.essence().to_string()),
+            Some(ty.essence().to_string()),
+            "{} {} with Accept: {} has the wrong Content-Type for its error",
+            method,
+            path,
+            ty
+        );
+        let err: E = decode_error(res.status(), Some(ty.essence()), &bytes);
+        assert_eq!(
+            err, *expected,
+            "{} {} with Accept: {} has the wrong error body",
+            method, path, ty
+        );
+    }
+}
+
+/// Check that a request with `method` to `path` accepts `valid` as its body in every supported
+/// type, and rejects malformed bodies.
+///
+/// The endpoint must succeed when given `valid`; it may have side effects, since it is called once
+/// for each supported type.
+pub async fn check_request_body<State, T>(
+    app: &tide::Server<State>,
+    method: Method,
+    path: &str,
+    valid: &T,
+) where
+    State: Clone + Send + Sync + 'static,
+    T: Serialize,
+{
+    let with_body = |ty: Option<&str>, body: Body| {
+        let mut req = request(method, path, Some("application/json"));
+        req.set_body(body);
+        req.remove_header("Content-Type");
+        if let Some(ty) = ty {
+            req.insert_header("Content-Type", ty);
+        }
+        req
+    };
+
+    for ty in [mime::JSON, mime::BYTE_STREAM] {
+        let bytes = serialize_body(&ty, valid).expect("failed to serialize request body");
+        let (res, _) = send(app, with_body(Some(ty.essence()), Body::from(bytes))).await;
+        assert!(
+            res.status().is_success(),
+            "{} {} with a valid {} body failed with {}",
+            method,
+            path,
+            ty,
+            res.status()
+        );
+    }
+
+    let json = serialize_body(&mime::JSON, valid).expect("failed to serialize request body");
+    let cases = [
+        (
+            "an unsupported content type",
+            Some("text/plain"),
+            Body::from(json.clone()),
+        ),
+        ("no content type", None, Body::from(json)),
+        ("malformed JSON", Some("application/json"), Body::from("{")),
+        (
+            "a truncated binary body",
+            Some("application/octet-stream"),
+            Body::from(Vec::<u8>::new()),
+        ),
+    ];
+    for (case, ty, body) in cases {
+        let (res, _) = send(app, with_body(ty, body)).await;
+        assert!(
+            res.status().is_client_error(),
+            "{} {} with {} should fail with a 4xx status, not {}",
+            method,
+            path,
+            case,
+            res.status()
+        );
+    }
+
+    // A body over the size limit is rejected based on its declared length, without reading it.
+    let body = Body::from_reader(futures::io::empty(), Some(SIZE_LIMIT as usize + 1));
+    let (res, _) = send(app, with_body(Some("application/octet-stream"), body)).await;
+    assert!(
+        res.status().is_client_error(),
+        "{} {} with an oversized body should fail with a 4xx status, not {}",
+        method,
+        path,
+        res.status()
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::{add_error_body, request_body, response};
+    use serde::Deserialize;
+    use snafu::Snafu;
+
+    #[derive(Clone, Debug, Deserialize, Serialize, Snafu, PartialEq, Eq)]
+    enum ApiError {
+        #[snafu(display("not found"))]
+        NotFound,
+        #[snafu(display("{}", msg))]
+        Other { msg: String },
+    }
+
+    impl Error for ApiError {
+        fn catch_all(msg: String) -> Self {
+            Self::Other { msg }
+        }
+
+        fn status(&self) -> StatusCode {
+            match self {
+                Self::NotFound => StatusCode::NotFound,
+                Self::Other { .. } => StatusCode::BadRequest,
+            }
+        }
+    }
+
+    fn app() -> tide::Server<()> {
+        let mut app = tide::new();
+        app.with(add_error_body::<_, ApiError>);
+        app.at("/value")
+            .get(|req: tide::Request<()>| async move { response(&req, vec![1u64, 2, 3]) });
+        app.at("/missing").get(|_| async {
+            Err::<tide::Response, _>(tide::Error::new(StatusCode::NotFound, ApiError::NotFound))
+        });
+        app.at("/submit")
+            .post(|mut req: tide::Request<()>| async move {
+                let value: Vec<u64> = request_body(&mut req).await?;
+                response(&req, value.len() as u64)
+            });
+        app
+    }
+
+    #[async_std::test]
+    async fn test_conformance() {
+        let app = app();
+        check_negotiation(&app, "/value", &vec![1u64, 2, 3]).await;
+        check_error(&app, Method::Get, "/missing", &ApiError::NotFound).await;
+        check_request_body(&app, Method::Post, "/submit", &vec![1u64, 2, 3]).await;
+    }
+
+    #[async_std::test]
+    #[should_panic(expected = "has the wrong Content-Type")]
+    async fn test_nonconformance() {
+        // A server which ignores the Accept header.
+        let mut app = tide::new();
+        app.at("/value")
+            .get(|_| async { tide::Body::from_json(&vec![1u64, 2, 3]) });
+        check_negotiation(&app, "/value", &vec![1u64, 2, 3]).await;
+    }
+}
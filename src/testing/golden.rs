@@ -0,0 +1,188 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Golden-file tests for wire compatibility.
+//!
+//! A golden test serializes representative instances of API types and compares the results against
+//! files committed to the repository. Any change to the serialization of a type, even one which
+//! still round-trips, changes the bytes on the wire and breaks compatibility with peers running
+//! older code, so it fails the test.
+//!
+//! Each registered value is stored in two files in the golden directory: `<name>.json`, a
+//! pretty-printed JSON serialization, and `<name>.bin`, the binary serialization. When a change in
+//! serialization is intended, set the environment variable [BLESS_ENV_VAR] when running the tests
+//! to overwrite the golden files with the new serializations, and commit the result.
+//!
+//! ```ignore
+//! #[test]
+//! fn test_golden() {
+//!     Golden::new(concat!(env!("CARGO_MANIFEST_DIR"), "/golden"))
+//!         .register("block_id", &BlockId(42))
+//!         .register("hash", &Hash(vec![1, 2, 3]))
+//!         .check();
+//! }
+//! ```
+
+use crate::wire::binary;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Environment variable which makes golden tests overwrite golden files instead of checking them.
+pub const BLESS_ENV_VAR: &str = "NET_BLESS";
+
+struct Entry {
+    name: String,
+    json: Vec<u8>,
+    binary: Vec<u8>,
+}
+
+/// A set of values to compare against golden files.
+pub struct Golden {
+    dir: PathBuf,
+    bless: bool,
+    entries: Vec<Entry>,
+}
+
+impl Golden {
+    /// Compare values against golden files in `dir`.
+    ///
+    /// The golden files are overwritten instead of checked if [BLESS_ENV_VAR] is set.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            bless: std::env::var_os(BLESS_ENV_VAR).is_some(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Override whether golden files are overwritten, ignoring [BLESS_ENV_VAR].
+    pub fn bless(mut self, bless: bool) -> Self {
+        self.bless = bless;
+        self
+    }
+
+    /// Register a value to compare against the golden files named `name`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `value` fails to serialize.
+    pub fn register<T: Serialize>(mut self, name: impl Into<String>, value: &T) -> Self {
+        let name = name.into();
+        let mut json = serde_json::to_vec_pretty(value)
+            .unwrap_or_else(|err| panic!("{} fails to serialize as JSON: {}", name, err));
+        json.push(b'\n');
+        let binary = binary::serialize(value)
+            .unwrap_or_else(|err| panic!("{} fails to serialize as binary: {}", name, err));
+        self.entries.push(Entry { name, json, binary });
+        self
+    }
+
+    /// Compare all registered values against their golden files, or bless them.
+    ///
+    /// Returns a description of each golden file which is missing or does not match.
+    pub fn verify(&self) -> Result<(), Vec<String>> {
+        let mut failures = Vec::new();
+        for entry in &self.entries {
+            for (ext, bytes) in [("json", &entry.json), ("bin", &entry.binary)] {
+                let path = self.dir.join(format!("{}.{}", entry.name, ext));
+                if let Err(msg) = self.verify_file(&path, bytes) {
+                    failures.push(msg);
+                }
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures)
+        }
+    }
+
+    /// Compare all registered values against their golden files, or bless them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any golden file is missing or does not match.
+    pub fn check(&self) {
+        if let Err(failures) = self.verify() {
+            panic!(
+                "serialization does not match golden files:\n  {}\nIf this change is intended, \
+                 re-run with {}=1 and commit the updated golden files.",
+                failures.join("\n  "),
+                BLESS_ENV_VAR
+            );
+        }
+    }
+
+    fn verify_file(&self, path: &Path, bytes: &[u8]) -> Result<(), String> {
+        if self.bless {
+            return fs::create_dir_all(&self.dir)
+                .and_then(|_| fs::write(path, bytes))
+                .map_err(|err| {
+                    format!("{}: failed to write golden file: {}", path.display(), err)
+                });
+        }
+        match fs::read(path) {
+            Ok(golden) if golden == bytes => Ok(()),
+            Ok(golden) => Err(format!(
+                "{}: expected {}, got {}",
+                path.display(),
+                display_bytes(&golden),
+                display_bytes(bytes)
+            )),
+            Err(err) => Err(format!("{}: {}", path.display(), err)),
+        }
+    }
+}
+
+fn display_bytes(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => s.trim_end().to_string(),
+        Err(_) => format!("0x{}", hex::encode(bytes)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_golden() {
+        let dir = std::env::temp_dir().join(format!("net-golden-test-{}", std::process::id()));
+
+        // Missing golden files are an error.
+        assert_eq!(
+            Golden::new(&dir)
+                .bless(false)
+                .register("value", &42u64)
+                .verify()
+                .unwrap_err()
+                .len(),
+            2
+        );
+
+        // Blessing creates the golden files, after which the same value passes.
+        Golden::new(&dir)
+            .bless(true)
+            .register("value", &42u64)
+            .check();
+        Golden::new(&dir)
+            .bless(false)
+            .register("value", &42u64)
+            .check();
+
+        // A different serialization fails.
+        let failures = Golden::new(&dir)
+            .bless(false)
+            .register("value", &43u64)
+            .verify()
+            .unwrap_err();
+        assert_eq!(failures.len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Assertions that API types survive every path they can take over the wire.
+//!
+//! A type which is sent in request or response bodies must deserialize to exactly the value that
+//! was serialized, in every content type the protocol supports. Errors must additionally survive
+//! the error-body path, where they are serialized by the server and recovered by the client.
+//!
+//! These assertions are designed to be used with proptest, for example:
+//! ```ignore
+//! proptest! {
+//!     #[test]
+//!     fn test_my_type(value in my_type_strategy()) {
+//!         assert_roundtrip(&value);
+//!     }
+//! }
+//! ```
+//! Strategies for the types defined in this crate are in the [arbitrary](super::arbitrary) module.
+
+use crate::client::{decode_body, decode_error};
+use crate::error::Error;
+use crate::server::serialize_body;
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Debug;
+use tide::http::mime;
+
+/// Assert that `value` round-trips through the JSON wire format.
+pub fn assert_json_roundtrip<T: Serialize + DeserializeOwned + PartialEq + Debug>(value: &T) {
+    let bytes = serialize_body(&mime::JSON, value).expect("failed to serialize as JSON");
+    let decoded: T = decode_body(Some(mime::JSON.essence()), &bytes)
+        .unwrap_or_else(|err| panic!("failed to deserialize JSON {:?}: {}", value, err));
+    assert_eq!(*value, decoded, "JSON round-trip changed the value");
+}
+
+/// Assert that `value` round-trips through the binary wire format.
+pub fn assert_binary_roundtrip<T: Serialize + DeserializeOwned + PartialEq + Debug>(value: &T) {
+    let bytes = serialize_body(&mime::BYTE_STREAM, value).expect("failed to serialize as binary");
+    let decoded: T = decode_body(Some(mime::BYTE_STREAM.essence()), &bytes)
+        .unwrap_or_else(|err| panic!("failed to deserialize binary {:?}: {}", value, err));
+    assert_eq!(*value, decoded, "binary round-trip changed the value");
+}
+
+/// Assert that `value` round-trips through every supported wire format.
+pub fn assert_roundtrip<T: Serialize + DeserializeOwned + PartialEq + Debug>(value: &T) {
+    assert_json_roundtrip(value);
+    assert_binary_roundtrip(value);
+}
+
+/// Assert that `err` round-trips through the error-body path in every supported wire format.
+///
+/// This checks that the client recovers exactly `err` from an error response whose body was
+/// serialized by the server, rather than falling back to [Error::catch_all].
+pub fn assert_error_roundtrip<E: Error + PartialEq + Debug>(err: &E) {
+    for ty in [mime::JSON, mime::BYTE_STREAM] {
+        let bytes = serialize_body(&ty, err)
+            .unwrap_or_else(|e| panic!("failed to serialize error as {}: {}", ty, e));
+        let decoded: E = decode_error(err.status(), Some(ty.essence()), &bytes);
+        assert_eq!(
+            *err, decoded,
+            "error-body round-trip through {} changed the error",
+            ty
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::Error;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_primitive_roundtrip(value in any::<(u64, String, Vec<u8>, Option<bool>)>()) {
+            assert_roundtrip(&value);
+        }
+
+        #[test]
+        fn test_error_roundtrip(msg in any::<String>()) {
+            assert_error_roundtrip(&Error { msg });
+        }
+    }
+}
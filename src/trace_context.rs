@@ -0,0 +1,393 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Distributed tracing across services, with W3C Trace Context headers.
+//!
+//! A request from a wallet may pass through a relayer to a validator. To follow it through the
+//! logs of all three, each request carries a [TRACEPARENT] header, naming the trace it belongs to
+//! and the span which sent it, and optionally a [TRACESTATE] header with vendor-specific data:
+//!  * the [trace_context] server middleware continues the trace of each request (or starts a new
+//!    one, if the request has no valid [TRACEPARENT]), adds the [TraceContext] to the request
+//!    extensions, and runs the rest of the stack in a `tracing` span with the trace and span IDs
+//!  * the [StartTrace] client middleware starts a new trace for each request which is not already
+//!    part of one
+//!  * a handler which calls another service passes its [TraceContext] to the client as
+//!    middleware, so the nested request continues the same trace
+//!
+//! ```
+//! use net::trace_context::{trace_context, TraceContext};
+//!
+//! let mut app = tide::new();
+//! app.with(trace_context);
+//! app.at("/transfer").post(|req: tide::Request<()>| async move {
+//!     let mut nested = surf::post("http://validator.example.com/submit");
+//!     if let Some(cx) = req.ext::<TraceContext>() {
+//!         nested = nested.middleware(cx.clone());
+//!     }
+//!     Ok(nested.recv_string().await?)
+//! });
+//! ```
+//!
+//! With the `otel` feature, the spans are also linked to their remote parents in OpenTelemetry, so
+//! a service which installs a `tracing-opentelemetry` layer (for example, with [otlp_tracer])
+//! exports them as parts of the same distributed trace. Spans which are exported take the IDs
+//! OpenTelemetry gives them, so the [TRACEPARENT] a service sends names the span its peer will see
+//! in the trace. Otherwise, IDs are derived from the time, the process and the operating system's
+//! randomness, so they cannot be predicted by other clients.
+
+use crate::headers::TypedHeader;
+use http_types::headers::Headers;
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub use crate::headers::{TraceParent, TraceState, TRACEPARENT, TRACESTATE};
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub use server::*;
+
+#[cfg(feature = "client")]
+pub use client::*;
+
+/// The `sampled` bit of the trace flags.
+pub const SAMPLED: u8 = 0x01;
+
+/// The position of the current span in a distributed trace.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    /// The span which sent the request which started this one, if it was part of a trace.
+    pub parent_id: Option<[u8; 8]>,
+    pub flags: u8,
+    pub state: Option<TraceState>,
+}
+
+impl TraceContext {
+    /// The first span of a new trace, which is sampled.
+    pub fn root() -> Self {
+        let id = new_id();
+        let mut trace_id = [0; 16];
+        trace_id.copy_from_slice(&id[..16]);
+        let mut span_id = [0; 8];
+        span_id.copy_from_slice(&id[16..24]);
+        Self {
+            trace_id,
+            span_id,
+            parent_id: None,
+            flags: SAMPLED,
+            state: None,
+        }
+    }
+
+    /// A new span continuing the trace in `headers`, if they contain a valid [TRACEPARENT].
+    ///
+    /// An invalid [TRACESTATE] is dropped, rather than invalidating the whole context.
+    pub fn from_headers(headers: impl AsRef<Headers>) -> Option<Self> {
+        let headers = headers.as_ref();
+        let parent = TraceParent::get(headers).ok()??;
+        Some(Self {
+            trace_id: parent.trace_id,
+            span_id: new_span_id(),
+            parent_id: Some(parent.parent_id),
+            flags: parent.flags,
+            state: TraceState::get(headers).ok().flatten(),
+        })
+    }
+
+    /// A new span in the same trace, whose parent is this span.
+    pub fn child(&self) -> Self {
+        Self {
+            trace_id: self.trace_id,
+            span_id: new_span_id(),
+            parent_id: Some(self.span_id),
+            flags: self.flags,
+            state: self.state.clone(),
+        }
+    }
+
+    pub fn is_sampled(&self) -> bool {
+        self.flags & SAMPLED != 0
+    }
+
+    /// The [TRACEPARENT] of requests sent from this span.
+    pub fn traceparent(&self) -> TraceParent {
+        TraceParent {
+            trace_id: self.trace_id,
+            parent_id: self.span_id,
+            flags: self.flags,
+        }
+    }
+
+    /// Mark a request as sent from this span.
+    pub fn insert(&self, mut headers: impl AsMut<Headers>) {
+        let headers = headers.as_mut();
+        self.traceparent().insert(&mut *headers);
+        match &self.state {
+            Some(state) => state.insert(headers),
+            None => {
+                headers.remove(TRACESTATE);
+            }
+        }
+    }
+
+    /// A `tracing` span recording the IDs of this span.
+    ///
+    /// With the `otel` feature, if the span is exported to OpenTelemetry, this context takes the
+    /// trace and span IDs it is exported with, so this should be called before the context is
+    /// [inserted](Self::insert) into any request.
+    pub fn span(&mut self) -> tracing::Span {
+        let span = tracing::info_span!(
+            "trace",
+            trace_id = tracing::field::Empty,
+            span_id = tracing::field::Empty,
+            parent_id = %self.parent_id.map(hex::encode).unwrap_or_default(),
+        );
+        #[cfg(feature = "otel")]
+        otel::link(&span, self);
+        span.record(
+            "trace_id",
+            &tracing::field::display(hex::encode(self.trace_id)),
+        );
+        span.record(
+            "span_id",
+            &tracing::field::display(hex::encode(self.span_id)),
+        );
+        span
+    }
+}
+
+// An ID which is unpredictable, and unique across processes and restarts with overwhelming
+// probability.
+fn new_id() -> [u8; 32] {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let mut hasher = Sha256::new();
+    hasher.update(nanos.to_le_bytes());
+    hasher.update(std::process::id().to_le_bytes());
+    hasher.update(COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    // The keys of a new `RandomState` are seeded from the operating system's randomness.
+    hasher.update(RandomState::new().build_hasher().finish().to_le_bytes());
+    hasher.finalize().into()
+}
+
+fn new_span_id() -> [u8; 8] {
+    let mut span_id = [0; 8];
+    span_id.copy_from_slice(&new_id()[..8]);
+    span_id
+}
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+mod server {
+    use super::*;
+    use futures::future::BoxFuture;
+    use tide::{Next, Request};
+    use tracing::Instrument;
+
+    /// Server middleware which continues the trace of each request.
+    ///
+    /// The [TraceContext] of the request is added to the request extensions, and the rest of the
+    /// stack runs in its [span](TraceContext::span).
+    pub fn trace_context<'a, State: Clone + Send + Sync + 'static>(
+        mut req: Request<State>,
+        next: Next<'a, State>,
+    ) -> BoxFuture<'a, tide::Result> {
+        let mut cx = TraceContext::from_headers(&req).unwrap_or_else(TraceContext::root);
+        let span = cx.span();
+        req.set_ext(cx);
+        Box::pin(async move { Ok(next.run(req).instrument(span).await) })
+    }
+}
+
+#[cfg(feature = "client")]
+mod client {
+    use super::*;
+    use surf::middleware::{Middleware, Next};
+    use surf::{Client, Request};
+    use tracing::Instrument;
+
+    /// A [TraceContext] is client middleware, which sends each request from a new child span.
+    #[surf::utils::async_trait]
+    impl Middleware for TraceContext {
+        async fn handle(&self, mut req: Request, client: Client, next: Next<'_>) -> surf::Result {
+            let mut cx = self.child();
+            let span = cx.span();
+            cx.insert(&mut req);
+            next.run(req, client).instrument(span).await
+        }
+    }
+
+    /// Client middleware which starts a new trace for each request which is not part of one.
+    ///
+    /// Requests which already have a [TRACEPARENT] header are sent unchanged.
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct StartTrace;
+
+    #[surf::utils::async_trait]
+    impl Middleware for StartTrace {
+        async fn handle(&self, mut req: Request, client: Client, next: Next<'_>) -> surf::Result {
+            if req.header(TRACEPARENT).is_some() {
+                return next.run(req, client).await;
+            }
+            let mut cx = TraceContext::root();
+            let span = cx.span();
+            cx.insert(&mut req);
+            next.run(req, client).instrument(span).await
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+pub use otel::otlp_tracer;
+
+#[cfg(feature = "otel")]
+mod otel {
+    use super::*;
+    use opentelemetry::sdk::{trace, Resource};
+    use opentelemetry::trace::{
+        SpanContext, SpanId, TraceContextExt, TraceError, TraceFlags, TraceId,
+    };
+    use opentelemetry::{Context, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+    use std::str::FromStr;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    // Make the remote parent of `cx` the OpenTelemetry parent of `span`, and give `cx` the IDs
+    // `span` is exported with, if it is exported at all.
+    pub(super) fn link(span: &tracing::Span, cx: &mut TraceContext) {
+        set_parent(span, cx);
+        let context = span.context();
+        let exported = context.span().span_context().clone();
+        if exported.is_valid() {
+            cx.trace_id = exported.trace_id().to_bytes();
+            cx.span_id = exported.span_id().to_bytes();
+        }
+    }
+
+    fn set_parent(span: &tracing::Span, cx: &TraceContext) {
+        let parent_id = match cx.parent_id {
+            Some(parent_id) => parent_id,
+            None => return,
+        };
+        let state = cx
+            .state
+            .as_ref()
+            .and_then(|state| opentelemetry::trace::TraceState::from_str(&state.0).ok())
+            .unwrap_or_default();
+        let parent = SpanContext::new(
+            TraceId::from_bytes(cx.trace_id),
+            SpanId::from_bytes(parent_id),
+            TraceFlags::new(cx.flags),
+            true,
+            state,
+        );
+        span.set_parent(Context::new().with_remote_span_context(parent));
+    }
+
+    /// A tracer which exports spans to the OTLP collector at `endpoint`, as `service_name`.
+    ///
+    /// The tracer is installed as the global OpenTelemetry tracer, and should be used to build a
+    /// `tracing_opentelemetry` layer for the service's subscriber.
+    pub fn otlp_tracer(
+        endpoint: impl Into<String>,
+        service_name: impl Into<String>,
+    ) -> Result<trace::Tracer, TraceError> {
+        opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint),
+            )
+            .with_trace_config(
+                trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                    "service.name",
+                    service_name.into(),
+                )])),
+            )
+            .install_batch(opentelemetry::runtime::AsyncStd)
+    }
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod test {
+    use super::*;
+    use crate::test_util::Server;
+    use surf::Client;
+
+    // A service which reports the trace context of each request.
+    fn service() -> Server<()> {
+        let mut app = tide::new();
+        app.with(trace_context);
+        app.at("/trace").get(|req: tide::Request<()>| async move {
+            let cx = req.ext::<TraceContext>().unwrap();
+            Ok(format!(
+                "{} {} {}",
+                hex::encode(cx.trace_id),
+                cx.parent_id.map(hex::encode).unwrap_or_default(),
+                cx.state.as_ref().map_or("", |state| &state.0)
+            ))
+        });
+        Server(app)
+    }
+
+    // Send `req` to `service` with `client`, and split the reported trace context into fields.
+    async fn trace(
+        client: &Client,
+        service: &Server<()>,
+        req: surf::RequestBuilder,
+    ) -> Vec<String> {
+        let req = req.middleware(service.clone()).build();
+        let body = client.recv_string(req).await.unwrap();
+        body.split(' ').map(String::from).collect()
+    }
+
+    #[async_std::test]
+    async fn test_propagate() {
+        let service = service();
+        let client = Client::new();
+        let get = || surf::get("http://service/trace");
+
+        // Requests outside any trace start their own.
+        let untraced = trace(&client, &service, get()).await;
+        assert_eq!(untraced[1], "");
+
+        // Nested requests continue the trace of the handler which sends them.
+        let mut cx = TraceContext::root();
+        cx.state = Some(TraceState("espresso=1".into()));
+        let nested = trace(&client, &service, get().middleware(cx.clone())).await;
+        assert_eq!(nested[0], hex::encode(cx.trace_id));
+        assert_ne!(nested[1], hex::encode(cx.span_id));
+        assert_eq!(nested[2], "espresso=1");
+        assert_ne!(nested[0], untraced[0]);
+
+        // StartTrace starts a trace which the server continues, and leaves existing traces alone.
+        let client = Client::new().with(StartTrace);
+        let started = trace(&client, &service, get()).await;
+        assert_eq!(started[0].len(), 32);
+        assert_eq!(started[1].len(), 16);
+        let parent = cx.traceparent();
+        let continued = trace(
+            &client,
+            &service,
+            get().header(TRACEPARENT, parent.encode()),
+        )
+        .await;
+        assert_eq!(continued[0], hex::encode(parent.trace_id));
+        assert_eq!(continued[1], hex::encode(parent.parent_id));
+
+        // A malformed traceparent is replaced with a new trace.
+        let restarted = trace(&client, &service, get().header(TRACEPARENT, "junk")).await;
+        assert_eq!(restarted[0].len(), 32);
+        assert_eq!(restarted[1], "");
+    }
+}
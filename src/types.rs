@@ -5,17 +5,41 @@
 // This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
 // You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::redact::SafeToServe;
+#[cfg(feature = "std")]
+use crate::signing::{canonical_bytes, SignatureError, SigningKey, VerifyingKey};
+#[cfg(feature = "std")]
+use crate::wire::binary;
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
 use ark_serialize::*;
 use commit::{Commitment, Committable};
-use fmt::{Debug, Display, Formatter};
+use core::convert::TryFrom;
+use core::fmt::{self, Debug, Display, Formatter};
+use core::ops::Range;
+use core::str::FromStr;
+use core::time::Duration;
+#[cfg(feature = "std")]
+use ed25519_dalek::{Signer, Verifier};
 use generic_array::{ArrayLength, GenericArray};
 use jf_cap::{
-    structs::{ReceiverMemo, RecordCommitment},
+    keys::{AuditorPubKey, FreezerPubKey, UserKeyPair},
+    structs::{Amount as RecordAmount, AssetCode, ReceiverMemo, RecordCommitment, RecordOpening},
     Signature,
 };
 use jf_utils::tagged_blob;
-use serde::{Deserialize, Serialize};
-use std::fmt;
+use serde::{
+    de::{self, Deserializer, Visitor},
+    ser::{self, Serializer},
+    Deserialize, Serialize,
+};
+use snafu::Snafu;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use std::time::{SystemTime, UNIX_EPOCH};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
 #[tagged_blob("HASH")]
 #[derive(Clone, Debug, CanonicalSerialize, CanonicalDeserialize, PartialEq, Eq)]
@@ -74,6 +98,9 @@ pub struct MerklePath(pub jf_cap::MerklePath);
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct UnspentRecord {
     pub commitment: RecordCommitment,
+    /// UIDs, like the ledger heights in this module, are written as JSON numbers, and also read
+    /// from decimal strings, for clients which cannot represent large integers exactly.
+    #[cfg_attr(feature = "std", serde(with = "crate::wire::integers::lenient"))]
     pub uid: u64,
     pub memo: Option<ReceiverMemo>,
 }
@@ -84,6 +111,25 @@ impl Display for UnspentRecord {
     }
 }
 
+impl UnspentRecord {
+    /// Decrypt the memo of this record with `key`.
+    ///
+    /// Returns [None] if the record has no memo, or if it is not owned by `key`.
+    pub fn open(&self, key: &UserKeyPair) -> Option<RecordOpening> {
+        let opening = self
+            .memo
+            .as_ref()?
+            .decrypt(key, &self.commitment, &[])
+            .ok()?;
+        // `decrypt` checks the opening against the commitment, but not who it is for.
+        if opening.pub_key.address() == key.address() {
+            Some(opening)
+        } else {
+            None
+        }
+    }
+}
+
 /// Request body for the bulletin board endpoint POST /memos.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct PostMemos {
@@ -97,8 +143,1245 @@ impl Display for PostMemos {
     }
 }
 
+/// Request body for the address book endpoint POST /insert_pubkey.
+///
+/// Binds a public key to its address. The key is serialized in `pub_key_bytes`, and `sig` is a
+/// signature of those bytes by the key itself, so only the owner of a key can insert it.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct InsertPubKey {
+    pub pub_key_bytes: Vec<u8>,
+    pub sig: Signature,
+}
+
+impl Display for InsertPubKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_as_json(self, f)
+    }
+}
+
+/// Reasons an [InsertPubKey] request is invalid.
+#[derive(Clone, Debug, Snafu, PartialEq, Eq)]
+pub enum InsertPubKeyError {
+    #[snafu(display("malformed public key: {}", reason))]
+    MalformedPubKey { reason: String },
+    #[snafu(display("public key was not signed by its owner"))]
+    BadSignature,
+}
+
+#[cfg(feature = "std")]
+impl InsertPubKey {
+    /// A request to insert the public key of `key_pair`, signed by `key_pair`.
+    pub fn new(key_pair: &UserKeyPair) -> Result<Self, bincode::Error> {
+        let pub_key_bytes = binary::serialize(&key_pair.pub_key())?;
+        let sig = key_pair.sign(&pub_key_bytes);
+        Ok(Self { pub_key_bytes, sig })
+    }
+
+    /// Deserialize the public key and check that it signed this request.
+    pub fn verify(&self) -> Result<UserPubKey, InsertPubKeyError> {
+        let pub_key: UserPubKey = binary::deserialize(&self.pub_key_bytes).map_err(|err| {
+            InsertPubKeyError::MalformedPubKey {
+                reason: err.to_string(),
+            }
+        })?;
+        pub_key
+            .verify_sig(&self.pub_key_bytes, &self.sig)
+            .map_err(|_| InsertPubKeyError::BadSignature)?;
+        Ok(pub_key)
+    }
+}
+
+/// Request body for the address book endpoint POST /request_pubkey.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LookupPubKey {
+    pub address: UserAddress,
+}
+
+impl Display for LookupPubKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_as_json(self, f)
+    }
+}
+
+/// Response body for the address book endpoint POST /request_pubkey.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AddressBookEntry {
+    pub address: UserAddress,
+    pub pub_key: UserPubKey,
+}
+
+impl AddressBookEntry {
+    /// Whether the public key actually belongs to the address.
+    ///
+    /// Clients should check this rather than trusting the address book.
+    pub fn is_consistent(&self) -> bool {
+        self.pub_key.address() == self.address.0
+    }
+}
+
+impl Display for AddressBookEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_as_json(self, f)
+    }
+}
+
+/// Query for public keys.
+///
+/// Only public keys can be queried. The private halves (user, auditor and freezer key pairs) do not
+/// implement [SafeToServe], so they cannot be sent in a response even by mistake.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum KeyQuery {
+    /// The public key of the user with this address.
+    User(UserAddress),
+    /// The auditing and freezing keys of the asset with this code.
+    Asset(AssetCode),
+}
+
+impl Display for KeyQuery {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_as_json(self, f)
+    }
+}
+
+/// Response body for [KeyQuery::Asset].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AssetKeys {
+    pub asset: AssetCode,
+    /// The key which can view transactions of this asset, if it is audited.
+    pub auditor: Option<AuditorPubKey>,
+    /// The key which can freeze records of this asset, if it is freezable.
+    pub freezer: Option<FreezerPubKey>,
+}
+
+impl Display for AssetKeys {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_as_json(self, f)
+    }
+}
+
+/// Query for memos posted to the bulletin board.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum MemoQuery {
+    /// The memos for all outputs of a transaction.
+    Transaction(TransactionId),
+    /// The memos posted with the output record with this UID.
+    Record(#[cfg_attr(feature = "std", serde(with = "crate::wire::integers::lenient"))] u64),
+}
+
+impl Display for MemoQuery {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_as_json(self, f)
+    }
+}
+
+/// Response body for bulletin board memo queries.
+///
+/// Contains the memos as they were posted in [PostMemos], along with their position in the ledger
+/// and the bulletin board's signature accepting them. A wallet can keep the acceptance as proof that
+/// the memos were published.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PostedMemos {
+    pub txn: TransactionId,
+    /// The UID of the record the first memo is for. The memos are for consecutive records.
+    #[cfg_attr(feature = "std", serde(with = "crate::wire::integers::lenient"))]
+    pub first_uid: u64,
+    pub memos: Vec<ReceiverMemo>,
+    /// The signature from the [PostMemos] request.
+    pub signature: Signature,
+    /// When the bulletin board accepted the memos.
+    pub accepted: Timestamp,
+    /// The bulletin board's Ed25519 signature of everything above.
+    pub acceptance: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl PostedMemos {
+    /// Accept `posted` for the outputs of `txn`, signing the acceptance with `key`.
+    pub fn accept(
+        txn: TransactionId,
+        first_uid: u64,
+        posted: PostMemos,
+        key: &SigningKey,
+    ) -> Result<Self, serde_json::Error> {
+        let mut memos = Self {
+            txn,
+            first_uid,
+            memos: posted.memos,
+            signature: posted.signature,
+            accepted: Timestamp::now(),
+            acceptance: Vec::new(),
+        };
+        memos.acceptance = key.sign(&memos.acceptance_bytes()?).to_bytes().to_vec();
+        Ok(memos)
+    }
+
+    /// The bytes signed by the bulletin board.
+    pub fn acceptance_bytes(&self) -> Result<Vec<u8>, serde_json::Error> {
+        canonical_bytes(&(
+            &self.txn,
+            self.first_uid,
+            &self.memos,
+            &self.signature,
+            self.accepted,
+        ))
+    }
+
+    /// Check that the bulletin board with public key `key` accepted these memos.
+    pub fn verify_acceptance(&self, key: &VerifyingKey) -> Result<(), SignatureError> {
+        let signature = ed25519_dalek::Signature::from_slice(&self.acceptance).map_err(|err| {
+            SignatureError::Malformed {
+                reason: err.to_string(),
+            }
+        })?;
+        let bytes = self
+            .acceptance_bytes()
+            .map_err(|err| SignatureError::Malformed {
+                reason: err.to_string(),
+            })?;
+        key.verify(&bytes, &signature)
+            .map_err(|_| SignatureError::Mismatch)
+    }
+}
+
+impl PostedMemos {
+    /// The UIDs of the records the memos are for.
+    ///
+    /// Returns [None] if the UIDs would run past [u64::MAX], in which case the memos are malformed.
+    pub fn uids(&self) -> Option<Range<u64>> {
+        let len = u64::try_from(self.memos.len()).ok()?;
+        Some(self.first_uid..self.first_uid.checked_add(len)?)
+    }
+
+    /// The memo for the record with UID `uid`, if it is one of these.
+    pub fn memo(&self, uid: u64) -> Option<&ReceiverMemo> {
+        if self.uids()?.contains(&uid) {
+            self.memos.get(usize::try_from(uid - self.first_uid).ok()?)
+        } else {
+            None
+        }
+    }
+
+    /// Whether these memos answer `query`.
+    pub fn matches(&self, query: &MemoQuery) -> bool {
+        match query {
+            MemoQuery::Transaction(txn) => *txn == self.txn,
+            MemoQuery::Record(uid) => self.uids().map_or(false, |uids| uids.contains(uid)),
+        }
+    }
+}
+
+impl Display for PostedMemos {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_as_json(self, f)
+    }
+}
+
+/// Query for unspent records.
+///
+/// There is no query for the records viewable with an auditor's key: a viewing key decrypts the
+/// viewing memos of transactions, which records do not carry, so a [RecordList] could not be
+/// checked against it.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum RecordQuery {
+    /// The records owned by the user with this address.
+    Owner(UserAddress),
+}
+
+impl Display for RecordQuery {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_as_json(self, f)
+    }
+}
+
+/// Response body for [RecordQuery].
+///
+/// A record's memo is included if it has been posted to the bulletin board. Record commitments do
+/// not reveal their owners, so a service may return records which do not match the query; clients
+/// should only trust the records whose memos they can [open](Self::open).
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RecordList {
+    pub records: Vec<UnspentRecord>,
+}
+
+impl RecordList {
+    /// Fill in missing memos from memos posted to the bulletin board.
+    ///
+    /// Memos already in the list are kept. This lets a wallet combine records from a query service
+    /// which does not index memos with the memos it gets from the bulletin board.
+    pub fn add_memos<'a>(&mut self, posted: impl IntoIterator<Item = &'a PostedMemos>) {
+        for posted in posted {
+            for record in &mut self.records {
+                if record.memo.is_none() {
+                    record.memo = posted.memo(record.uid).cloned();
+                }
+            }
+        }
+    }
+
+    /// The records owned by `key`, with their decrypted memos.
+    ///
+    /// Records without memos, and records owned by other keys, are skipped.
+    pub fn open<'a>(&'a self, key: &'a UserKeyPair) -> impl Iterator<Item = OpenedRecord> + 'a {
+        self.records.iter().filter_map(move |record| {
+            Some(OpenedRecord {
+                opening: record.open(key)?,
+                record: record.clone(),
+            })
+        })
+    }
+}
+
+impl Display for RecordList {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_as_json(self, f)
+    }
+}
+
+/// An unspent record, along with the opening decrypted from its memo.
+///
+/// The opening contains the record's secrets, so this is not [SafeToServe].
+#[derive(Clone, Debug)]
+pub struct OpenedRecord {
+    pub record: UnspentRecord,
+    pub opening: RecordOpening,
+}
+
+/// The version of the [KeystoreBackup] format written by this crate.
+pub const KEYSTORE_BACKUP_VERSION: u32 = 1;
+
+#[tagged_blob("SALT")]
+#[derive(Clone, Debug, CanonicalSerialize, CanonicalDeserialize, PartialEq, Eq)]
+pub struct KeystoreSalt(pub Vec<u8>);
+
+#[tagged_blob("NONCE")]
+#[derive(Clone, Debug, CanonicalSerialize, CanonicalDeserialize, PartialEq, Eq)]
+pub struct KeystoreNonce(pub Vec<u8>);
+
+#[tagged_blob("KEYSTORE")]
+#[derive(Clone, Debug, CanonicalSerialize, CanonicalDeserialize, PartialEq, Eq)]
+pub struct KeystoreCiphertext(pub Vec<u8>);
+
+#[tagged_blob("MAC")]
+#[derive(Clone, Debug, CanonicalSerialize, CanonicalDeserialize, PartialEq, Eq)]
+pub struct IntegrityTag(pub Vec<u8>);
+
+/// How the key encrypting a [KeystoreBackup] is derived from the user's password.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Kdf {
+    Argon2id {
+        salt: KeystoreSalt,
+        /// Memory cost, in KiB.
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+    Scrypt {
+        salt: KeystoreSalt,
+        /// Base 2 logarithm of the CPU/memory cost.
+        log_n: u8,
+        r: u32,
+        p: u32,
+    },
+}
+
+/// The cipher a [KeystoreBackup] is encrypted with.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum KeystoreCipher {
+    XChaCha20Poly1305 { nonce: KeystoreNonce },
+    Aes256Gcm { nonce: KeystoreNonce },
+}
+
+/// An encrypted keystore, as exported for backup and imported for recovery.
+///
+/// Every wallet reads and writes this format, so a backup made by one can be restored by any
+/// other. The keystore itself is opaque at this layer: it is encrypted with `cipher`, under a key
+/// derived from the user's password with `kdf`. `tag` authenticates everything else, keyed with
+/// the derived key, so a wrong password or tampered parameters are detected before decrypting.
+///
+/// The keystore is only ever sent encrypted, so backups are [SafeToServe].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KeystoreBackup {
+    /// The version of the backup format. Readers should reject versions they do not know.
+    pub version: u32,
+    pub kdf: Kdf,
+    pub cipher: KeystoreCipher,
+    pub ciphertext: KeystoreCiphertext,
+    pub created: Timestamp,
+    pub tag: IntegrityTag,
+}
+
+impl KeystoreBackup {
+    /// Whether this crate understands the format of this backup.
+    pub fn is_supported(&self) -> bool {
+        self.version == KEYSTORE_BACKUP_VERSION
+    }
+}
+
+#[cfg(feature = "std")]
+impl KeystoreBackup {
+    /// The bytes authenticated by `tag`: the canonical serialization of every other field.
+    pub fn authenticated_bytes(&self) -> Result<Vec<u8>, serde_json::Error> {
+        canonical_bytes(&(
+            self.version,
+            &self.kdf,
+            &self.cipher,
+            &self.ciphertext,
+            self.created,
+        ))
+    }
+}
+
+impl Display for KeystoreBackup {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_as_json(self, f)
+    }
+}
+
+/// Request body for transaction submission endpoints.
+///
+/// The transaction type depends on the service; wallets, relayers and validators should all use
+/// this wrapper so that submissions can be forwarded between them unchanged.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SubmitTransaction<T> {
+    pub transaction: T,
+    /// If set, the transaction should be dropped rather than included after this time.
+    pub expires: Option<Timestamp>,
+}
+
+impl<T: Serialize> Display for SubmitTransaction<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_as_json(self, f)
+    }
+}
+
+/// The progress of a submitted transaction.
+///
+/// `E` is the service's error type. A rejected transaction carries the same error the service
+/// would have responded with. Like the other variants, it is tagged with its name, so the error is
+/// nested as in `{"Rejected": {...}}` rather than serializing like an error response body.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TransactionStatus<E> {
+    /// Accepted, but not yet included in a block.
+    Pending,
+    /// Included in a block which is not yet final.
+    Included {
+        id: TransactionId,
+        /// The number of blocks committed after the one including the transaction.
+        confirmations: u64,
+    },
+    /// Included in a block which is final.
+    Final { id: TransactionId, at: Timestamp },
+    /// Rejected, and will never be included.
+    Rejected(E),
+}
+
+/// Response body for transaction submission and status endpoints.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TransactionReceipt<E> {
+    /// The hash of the submitted transaction, which identifies it before it is included.
+    pub hash: Hash,
+    pub status: TransactionStatus<E>,
+}
+
+impl<E> TransactionReceipt<E> {
+    /// The position of the transaction in the ledger, if it has been included.
+    pub fn id(&self) -> Option<&TransactionId> {
+        match &self.status {
+            TransactionStatus::Included { id, .. } | TransactionStatus::Final { id, .. } => {
+                Some(id)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_final(&self) -> bool {
+        matches!(self.status, TransactionStatus::Final { .. })
+    }
+
+    /// The reason the transaction was rejected, if it was.
+    pub fn rejection(&self) -> Option<&E> {
+        match &self.status {
+            TransactionStatus::Rejected(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl<E: Serialize> Display for TransactionReceipt<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_as_json(self, f)
+    }
+}
+
+/// The view a validator's consensus protocol is in.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ConsensusView {
+    /// The view number, which increases by one each time a new leader is elected.
+    pub view: u64,
+    /// The identity of the leader of this view.
+    pub leader: String,
+    /// When this validator entered the view.
+    pub started: Timestamp,
+}
+
+impl Display for ConsensusView {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_as_json(self, f)
+    }
+}
+
+/// A validator's connections to the other validators.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// The number of peers this validator is currently connected to.
+    pub connected: u64,
+    /// The number of peers this validator expects to be connected to, such as the size of the
+    /// committee, not counting itself.
+    pub expected: u64,
+}
+
+impl Display for PeerInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_as_json(self, f)
+    }
+}
+
+/// Whether a validator has caught up to the rest of the network.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum SyncStatus {
+    /// Caught up, and participating in consensus.
+    Synced,
+    /// Catching up on blocks it missed.
+    Syncing {
+        /// The height of the validator's ledger.
+        #[cfg_attr(feature = "std", serde(with = "crate::wire::integers::lenient"))]
+        height: u64,
+        /// The height of the ledger of the rest of the network, as far as the validator knows.
+        #[cfg_attr(feature = "std", serde(with = "crate::wire::integers::lenient"))]
+        target: u64,
+    },
+    /// Not connected to enough peers to know whether it is caught up.
+    Disconnected,
+}
+
+impl SyncStatus {
+    pub fn is_synced(&self) -> bool {
+        matches!(self, Self::Synced)
+    }
+}
+
+impl Display for SyncStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_as_json(self, f)
+    }
+}
+
+/// Response body for validator status endpoints.
+///
+/// Every validator reports its status in this form, so that monitoring tools and the status pages
+/// of other services can read any of them.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ValidatorStatus {
+    /// The identity of the validator reporting its status.
+    pub node: String,
+    /// The number of blocks in the validator's ledger.
+    #[cfg_attr(feature = "std", serde(with = "crate::wire::integers::lenient"))]
+    pub ledger_height: u64,
+    pub view: ConsensusView,
+    pub peers: PeerInfo,
+    pub sync: SyncStatus,
+}
+
+impl ValidatorStatus {
+    /// Whether this validator is the leader of its current view.
+    pub fn is_leader(&self) -> bool {
+        self.view.leader == self.node
+    }
+}
+
+impl Display for ValidatorStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fmt_as_json(self, f)
+    }
+}
+
+/// A point in time, with a resolution of one second.
+///
+/// Serializes as an RFC 3339 string (e.g. `2022-06-01T12:00:00Z`) in human-readable formats like
+/// JSON, and as an `i64` number of seconds since the Unix epoch in binary formats.
+///
+/// Clocks on different machines never agree exactly, so timestamps from other parties should be
+/// checked with a tolerance, using [within](Self::within).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(pub i64);
+
+impl Timestamp {
+    /// The current time, according to the local clock.
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    pub fn now() -> Self {
+        let secs = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() as i64,
+            Err(err) => -(err.duration().as_secs() as i64),
+        };
+        Self(secs)
+    }
+
+    /// The current time, according to the local clock.
+    ///
+    /// The system clock is not available in browsers, so this asks JavaScript instead.
+    #[cfg(all(feature = "std", target_arch = "wasm32"))]
+    pub fn now() -> Self {
+        Self((js_sys::Date::now() / 1000.0).floor() as i64)
+    }
+
+    /// Seconds since the Unix epoch.
+    pub fn unix(self) -> i64 {
+        self.0
+    }
+
+    /// Whether this time is within `tolerance` of the local clock, in either direction.
+    #[cfg(feature = "std")]
+    pub fn within(self, tolerance: Duration) -> bool {
+        self.within_of(Self::now(), tolerance)
+    }
+
+    /// Whether this time is within `tolerance` of `reference`, in either direction.
+    pub fn within_of(self, reference: Timestamp, tolerance: Duration) -> bool {
+        self.0.abs_diff(reference.0) <= tolerance.as_secs()
+    }
+
+    /// Seconds from `reference` to this time, which is negative if this time is earlier.
+    pub fn skew_from(self, reference: Timestamp) -> i64 {
+        self.0.saturating_sub(reference.0)
+    }
+
+    fn to_datetime(self) -> Result<OffsetDateTime, String> {
+        OffsetDateTime::from_unix_timestamp(self.0).map_err(|err| err.to_string())
+    }
+
+    // Formatted by hand, since the formatting support in `time` requires `std`. Times are whole
+    // seconds in UTC, so this is the same as formatting with [Rfc3339].
+    fn to_rfc3339(self) -> Result<String, String> {
+        let dt = self.to_datetime()?;
+        if !(0..=9999).contains(&dt.year()) {
+            return Err(format!("year {} cannot be written in RFC 3339", dt.year()));
+        }
+        Ok(format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            dt.year(),
+            dt.month() as u8,
+            dt.day(),
+            dt.hour(),
+            dt.minute(),
+            dt.second()
+        ))
+    }
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.to_rfc3339() {
+            Ok(s) => write!(f, "{}", s),
+            // Times which can't be written as RFC 3339 (beyond the year 9999) are shown as seconds.
+            Err(_) => write!(f, "{}", self.0),
+        }
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = String;
+
+    /// Parse an RFC 3339 time, with any UTC offset.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let datetime = OffsetDateTime::parse(s, &Rfc3339)
+            .map_err(|err| format!("invalid timestamp {}: {}", s, err))?;
+        Ok(Self(datetime.unix_timestamp()))
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_rfc3339().map_err(ser::Error::custom)?)
+        } else {
+            serializer.serialize_i64(self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            String::deserialize(deserializer)?
+                .parse()
+                .map_err(de::Error::custom)
+        } else {
+            Ok(Self(i64::deserialize(deserializer)?))
+        }
+    }
+}
+
+// Defines a u128-backed quantity with checked arithmetic and precision-safe serialization.
+macro_rules! amount_type {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        ///
+        /// Serializes as a decimal string in human-readable formats like JSON, since JSON numbers
+        /// are doubles in JavaScript and lose precision above 2^53, and as a `u128` in binary
+        /// formats. Both strings and integers are accepted when deserializing JSON.
+        ///
+        /// The binary wire format (`wire::binary`) encodes integers with a fixed width, so every
+        /// amount takes 16 little-endian bytes, however small it is. This is part of the format:
+        /// peers using bincode's default options decode the same bytes, and would not decode a
+        /// varint encoding.
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name(pub u128);
+
+        impl $name {
+            pub const ZERO: Self = Self(0);
+
+            /// `self + other`, or [None] on overflow.
+            pub fn checked_add(self, other: Self) -> Option<Self> {
+                self.0.checked_add(other.0).map(Self)
+            }
+
+            /// `self - other`, or [None] if `other` is greater than `self`.
+            pub fn checked_sub(self, other: Self) -> Option<Self> {
+                self.0.checked_sub(other.0).map(Self)
+            }
+
+            /// `self * n`, or [None] on overflow.
+            pub fn checked_mul(self, n: u128) -> Option<Self> {
+                self.0.checked_mul(n).map(Self)
+            }
+
+            /// The sum of `amounts`, or [None] on overflow.
+            pub fn checked_sum(amounts: impl IntoIterator<Item = Self>) -> Option<Self> {
+                amounts
+                    .into_iter()
+                    .try_fold(Self::ZERO, |sum, amount| sum.checked_add(amount))
+            }
+        }
+
+        impl From<u64> for $name {
+            fn from(n: u64) -> Self {
+                Self(n as u128)
+            }
+        }
+
+        impl From<u128> for $name {
+            fn from(n: u128) -> Self {
+                Self(n)
+            }
+        }
+
+        impl From<$name> for u128 {
+            fn from(amount: $name) -> Self {
+                amount.0
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", self.0)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = String;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                s.parse()
+                    .map(Self)
+                    .map_err(|err| format!("invalid {} {}: {}", stringify!($name), s, err))
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                if serializer.is_human_readable() {
+                    serializer.collect_str(self)
+                } else {
+                    serializer.serialize_u128(self.0)
+                }
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                if deserializer.is_human_readable() {
+                    deserializer.deserialize_any(AmountVisitor).map(Self)
+                } else {
+                    Ok(Self(u128::deserialize(deserializer)?))
+                }
+            }
+        }
+    };
+}
+
+// Accepts an amount as a decimal string or a non-negative integer.
+struct AmountVisitor;
+
+impl<'de> Visitor<'de> for AmountVisitor {
+    type Value = u128;
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "a non-negative integer or a decimal string")
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<u128, E> {
+        s.parse()
+            .map_err(|_| E::invalid_value(de::Unexpected::Str(s), &self))
+    }
+
+    fn visit_u64<E: de::Error>(self, n: u64) -> Result<u128, E> {
+        Ok(n as u128)
+    }
+
+    fn visit_u128<E: de::Error>(self, n: u128) -> Result<u128, E> {
+        Ok(n)
+    }
+
+    fn visit_i64<E: de::Error>(self, n: i64) -> Result<u128, E> {
+        u128::try_from(n).map_err(|_| E::invalid_value(de::Unexpected::Signed(n), &self))
+    }
+}
+
+amount_type!(
+    /// An amount of an asset.
+    Amount
+);
+
+amount_type!(
+    /// A transaction fee, in units of the native asset.
+    Fee
+);
+
+impl From<Fee> for Amount {
+    fn from(fee: Fee) -> Self {
+        Self(fee.0)
+    }
+}
+
+impl From<RecordAmount> for Amount {
+    fn from(amount: RecordAmount) -> Self {
+        Self(amount.into())
+    }
+}
+
+impl From<Amount> for RecordAmount {
+    fn from(amount: Amount) -> Self {
+        amount.0.into()
+    }
+}
+
+impl From<RecordAmount> for Fee {
+    fn from(amount: RecordAmount) -> Self {
+        Self(amount.into())
+    }
+}
+
+impl From<Fee> for RecordAmount {
+    fn from(fee: Fee) -> Self {
+        fee.0.into()
+    }
+}
+
+// None of these types contain private key material.
+impl SafeToServe for Hash {}
+impl SafeToServe for BlockId {}
+impl SafeToServe for TransactionId {}
+impl SafeToServe for UserAddress {}
+impl SafeToServe for UserPubKey {}
+impl SafeToServe for AuditorPubKey {}
+impl SafeToServe for FreezerPubKey {}
+impl SafeToServe for AssetCode {}
+impl SafeToServe for MerklePath {}
+impl SafeToServe for UnspentRecord {}
+impl SafeToServe for PostMemos {}
+impl SafeToServe for LookupPubKey {}
+impl SafeToServe for AddressBookEntry {}
+impl SafeToServe for KeyQuery {}
+impl SafeToServe for AssetKeys {}
+impl SafeToServe for MemoQuery {}
+impl SafeToServe for PostedMemos {}
+impl SafeToServe for RecordQuery {}
+impl SafeToServe for RecordList {}
+impl SafeToServe for KeystoreBackup {}
+impl<T: SafeToServe> SafeToServe for SubmitTransaction<T> {}
+#[cfg(feature = "std")]
+impl<E: crate::Error> SafeToServe for TransactionStatus<E> {}
+#[cfg(feature = "std")]
+impl<E: crate::Error> SafeToServe for TransactionReceipt<E> {}
+impl SafeToServe for ConsensusView {}
+impl SafeToServe for PeerInfo {}
+impl SafeToServe for SyncStatus {}
+impl SafeToServe for ValidatorStatus {}
+impl SafeToServe for Timestamp {}
+impl SafeToServe for Amount {}
+impl SafeToServe for Fee {}
+
 // Display implementation for types which serialize to JSON. Displays as a valid JSON object.
 pub fn fmt_as_json<T: Serialize>(v: &T, f: &mut Formatter<'_>) -> fmt::Result {
     let string = serde_json::to_string(v).map_err(|_| fmt::Error)?;
     write!(f, "{}", string)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wire::binary;
+    use ark_std::rand::rngs::StdRng;
+    use jf_cap::structs::{AssetDefinition, FreezeFlag};
+
+    #[test]
+    fn test_amount() {
+        let big = Amount(u128::MAX - 1);
+        assert_eq!(big.checked_add(Amount(1)), Some(Amount(u128::MAX)));
+        assert_eq!(big.checked_add(Amount(2)), None);
+        assert_eq!(Amount(1).checked_sub(Amount(2)), None);
+        assert_eq!(big.checked_mul(2), None);
+        assert_eq!(
+            Amount::checked_sum(vec![Amount(1), Amount(2), Fee(3).into()]),
+            Some(Amount(6))
+        );
+        assert_eq!(Amount::checked_sum(vec![big, big]), None);
+
+        // Large amounts survive a round trip through JSON without losing precision.
+        let json = serde_json::to_string(&big).unwrap();
+        assert_eq!(json, format!("\"{}\"", u128::MAX - 1));
+        assert_eq!(serde_json::from_str::<Amount>(&json).unwrap(), big);
+        assert_eq!(serde_json::from_str::<Fee>("42").unwrap(), Fee(42));
+        assert!(serde_json::from_str::<Fee>("-1").is_err());
+        assert!(serde_json::from_str::<Fee>("\"1.5\"").is_err());
+
+        let bytes = binary::serialize(&big).unwrap();
+        assert_eq!(bytes.len(), 16);
+        assert_eq!(binary::deserialize::<Amount>(&bytes).unwrap(), big);
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(Timestamp(0).to_string(), "1970-01-01T00:00:00Z");
+        assert_eq!(Timestamp(1654084800).to_string(), "2022-06-01T12:00:00Z");
+        assert_eq!(Timestamp(-1).to_string(), "1969-12-31T23:59:59Z");
+        // Beyond the year 9999, RFC 3339 can't represent the time.
+        assert_eq!(Timestamp(253402300800).to_string(), "253402300800");
+
+        let t = Timestamp(1654084800);
+        let json = serde_json::to_string(&t).unwrap();
+        assert_eq!(json, "\"2022-06-01T12:00:00Z\"");
+        assert_eq!(serde_json::from_str::<Timestamp>(&json).unwrap(), t);
+        assert_eq!("2022-06-01T14:00:00+02:00".parse::<Timestamp>().unwrap(), t);
+    }
+
+    #[test]
+    fn test_validator_status() {
+        let status = ValidatorStatus {
+            node: "validator-1".into(),
+            ledger_height: 100,
+            view: ConsensusView {
+                view: 7,
+                leader: "validator-2".into(),
+                started: Timestamp(1654084800),
+            },
+            peers: PeerInfo {
+                connected: 3,
+                expected: 4,
+            },
+            sync: SyncStatus::Syncing {
+                height: 100,
+                target: 102,
+            },
+        };
+        assert!(!status.is_leader());
+        assert!(!status.sync.is_synced());
+
+        let json: serde_json::Value = serde_json::from_str(&status.to_string()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "node": "validator-1",
+                "ledger_height": 100,
+                "view": {"view": 7, "leader": "validator-2", "started": "2022-06-01T12:00:00Z"},
+                "peers": {"connected": 3, "expected": 4},
+                "sync": {"Syncing": {"height": 100, "target": 102}},
+            })
+        );
+        assert_eq!(
+            serde_json::from_value::<ValidatorStatus>(json.clone()).unwrap(),
+            status
+        );
+        assert_eq!(SyncStatus::Synced.to_string(), "\"Synced\"");
+
+        // Heights may also be sent as strings, by clients which cannot represent them exactly.
+        let mut json = json;
+        json["ledger_height"] = "100".into();
+        json["sync"]["Syncing"]["target"] = "102".into();
+        assert_eq!(
+            serde_json::from_value::<ValidatorStatus>(json).unwrap(),
+            status
+        );
+        let bytes = binary::serialize(&status).unwrap();
+        assert_eq!(
+            binary::deserialize::<ValidatorStatus>(&bytes).unwrap(),
+            status
+        );
+        assert_eq!(
+            serde_json::from_str::<MemoQuery>(r#"{"Record": "18446744073709551615"}"#).unwrap(),
+            MemoQuery::Record(u64::MAX)
+        );
+    }
+
+    // A record owned by `owner`, without its memo, and the memo.
+    fn record(uid: u64, owner: &UserKeyPair, rng: &mut StdRng) -> (UnspentRecord, ReceiverMemo) {
+        let ro = RecordOpening::new(
+            rng,
+            RecordAmount::from(10u64),
+            AssetDefinition::native(),
+            owner.pub_key(),
+            FreezeFlag::Unfrozen,
+        );
+        let memo = ReceiverMemo::from_ro(rng, &ro, &[]).unwrap();
+        let record = UnspentRecord {
+            commitment: RecordCommitment::from(&ro),
+            uid,
+            memo: None,
+        };
+        (record, memo)
+    }
+
+    #[test]
+    fn test_open_records() {
+        let mut rng = ark_std::test_rng();
+        let alice = UserKeyPair::generate(&mut rng);
+        let bob = UserKeyPair::generate(&mut rng);
+        let (ours, our_memo) = record(1, &alice, &mut rng);
+        let (theirs, their_memo) = record(2, &bob, &mut rng);
+        let mut list = RecordList {
+            records: vec![ours.clone(), theirs],
+        };
+
+        // Records can't be opened until their memos are added.
+        assert_eq!(list.open(&alice).count(), 0);
+        let posted = PostedMemos {
+            txn: TransactionId(BlockId(0), 0),
+            first_uid: 1,
+            memos: vec![our_memo.clone(), their_memo],
+            signature: alice.sign(b"memos"),
+            accepted: Timestamp(0),
+            acceptance: vec![],
+        };
+        list.add_memos([&posted]);
+        assert_eq!(list.records[0].memo, Some(our_memo));
+
+        let opened = list.open(&alice).collect::<Vec<_>>();
+        assert_eq!(opened.len(), 1);
+        assert_eq!(opened[0].record.uid, ours.uid);
+        assert_eq!(opened[0].opening.pub_key, alice.pub_key());
+        assert_eq!(list.open(&bob).count(), 1);
+    }
+
+    #[test]
+    fn test_posted_memos() {
+        let mut rng = ark_std::test_rng();
+        let alice = UserKeyPair::generate(&mut rng);
+        let (_, memo) = record(1, &alice, &mut rng);
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let posted = PostedMemos::accept(
+            TransactionId(BlockId(3), 1),
+            10,
+            PostMemos {
+                memos: vec![memo.clone(), memo.clone()],
+                signature: alice.sign(b"memos"),
+            },
+            &key,
+        )
+        .unwrap();
+        assert_eq!(posted.uids(), Some(10..12));
+        assert_eq!(posted.memo(11), Some(&memo));
+        assert_eq!(posted.memo(12), None);
+        assert!(posted.matches(&MemoQuery::Record(10)));
+        assert!(posted.matches(&MemoQuery::Transaction(TransactionId(BlockId(3), 1))));
+        assert!(!posted.matches(&MemoQuery::Record(9)));
+
+        // The acceptance verifies with the bulletin board's key, and covers every field.
+        posted.verify_acceptance(&key.verifying_key()).unwrap();
+        assert_eq!(
+            posted.verify_acceptance(&SigningKey::from_bytes(&[2; 32]).verifying_key()),
+            Err(SignatureError::Mismatch)
+        );
+        let mut tampered = posted.clone();
+        tampered.first_uid = 11;
+        assert_eq!(
+            tampered.verify_acceptance(&key.verifying_key()),
+            Err(SignatureError::Mismatch)
+        );
+        tampered = posted.clone();
+        tampered.acceptance.truncate(10);
+        assert!(matches!(
+            tampered.verify_acceptance(&key.verifying_key()),
+            Err(SignatureError::Malformed { .. })
+        ));
+
+        // UIDs which would overflow are not for any record.
+        tampered = posted;
+        tampered.first_uid = u64::MAX - 1;
+        assert_eq!(tampered.uids(), None);
+        assert_eq!(tampered.memo(u64::MAX - 1), None);
+        assert!(!tampered.matches(&MemoQuery::Record(u64::MAX - 1)));
+    }
+
+    #[test]
+    fn test_insert_pub_key() {
+        let mut rng = ark_std::test_rng();
+        let alice = UserKeyPair::generate(&mut rng);
+        let bob = UserKeyPair::generate(&mut rng);
+
+        let req = InsertPubKey::new(&alice).unwrap();
+        assert_eq!(req.verify(), Ok(alice.pub_key()));
+        assert_eq!(
+            serde_json::from_str::<InsertPubKey>(&req.to_string()).unwrap(),
+            req
+        );
+
+        // Only the owner of a key can insert it.
+        let forged = InsertPubKey {
+            pub_key_bytes: req.pub_key_bytes.clone(),
+            sig: bob.sign(&req.pub_key_bytes),
+        };
+        assert_eq!(forged.verify(), Err(InsertPubKeyError::BadSignature));
+        let malformed = InsertPubKey {
+            pub_key_bytes: vec![1, 2, 3],
+            sig: req.sig,
+        };
+        assert!(matches!(
+            malformed.verify(),
+            Err(InsertPubKeyError::MalformedPubKey { .. })
+        ));
+
+        // Clients can tell when the address book returns the wrong key for an address.
+        let entry = AddressBookEntry {
+            address: alice.pub_key().address().into(),
+            pub_key: alice.pub_key(),
+        };
+        assert!(entry.is_consistent());
+        let entry = AddressBookEntry {
+            pub_key: bob.pub_key(),
+            ..entry
+        };
+        assert!(!entry.is_consistent());
+    }
+
+    #[test]
+    fn test_transaction_receipt() {
+        let submit = SubmitTransaction {
+            transaction: vec![1u8, 2, 3],
+            expires: Some(Timestamp(1654084800)),
+        };
+        assert_eq!(
+            serde_json::to_value(&submit).unwrap(),
+            serde_json::json!({"transaction": [1, 2, 3], "expires": "2022-06-01T12:00:00Z"})
+        );
+
+        let id = TransactionId(BlockId(3), 1);
+        let mut receipt = TransactionReceipt::<String> {
+            hash: Hash::from([7u8; 32]),
+            status: TransactionStatus::Pending,
+        };
+        assert_eq!(receipt.id(), None);
+        assert!(!receipt.is_final());
+        receipt.status = TransactionStatus::Included {
+            id: id.clone(),
+            confirmations: 2,
+        };
+        assert_eq!(receipt.id(), Some(&id));
+        assert!(!receipt.is_final());
+        receipt.status = TransactionStatus::Final {
+            id: id.clone(),
+            at: Timestamp(1654084800),
+        };
+        assert_eq!(receipt.id(), Some(&id));
+        assert!(receipt.is_final());
+        assert_eq!(receipt.rejection(), None);
+
+        // A rejection is nested under its variant name.
+        receipt.status = TransactionStatus::Rejected("insufficient fee".to_string());
+        assert_eq!(receipt.id(), None);
+        assert_eq!(receipt.rejection().unwrap(), "insufficient fee");
+        let json = serde_json::to_value(&receipt).unwrap();
+        assert_eq!(
+            json["status"],
+            serde_json::json!({"Rejected": "insufficient fee"})
+        );
+        assert_eq!(
+            serde_json::from_value::<TransactionReceipt<String>>(json).unwrap(),
+            receipt
+        );
+        let bytes = binary::serialize(&receipt).unwrap();
+        assert_eq!(
+            binary::deserialize::<TransactionReceipt<String>>(&bytes).unwrap(),
+            receipt
+        );
+    }
+
+    #[test]
+    fn test_keystore_backup() {
+        let backup = KeystoreBackup {
+            version: KEYSTORE_BACKUP_VERSION,
+            kdf: Kdf::Argon2id {
+                salt: KeystoreSalt(vec![1; 16]),
+                memory_kib: 65536,
+                iterations: 3,
+                parallelism: 4,
+            },
+            cipher: KeystoreCipher::XChaCha20Poly1305 {
+                nonce: KeystoreNonce(vec![2; 24]),
+            },
+            ciphertext: KeystoreCiphertext(vec![3; 100]),
+            created: Timestamp(1654084800),
+            tag: IntegrityTag(vec![4; 32]),
+        };
+        assert!(backup.is_supported());
+
+        // Binary fields are tagged, so they can't be mixed up.
+        let json: serde_json::Value = serde_json::from_str(&backup.to_string()).unwrap();
+        assert!(json["ciphertext"]
+            .as_str()
+            .unwrap()
+            .starts_with("KEYSTORE~"));
+        assert!(json["tag"].as_str().unwrap().starts_with("MAC~"));
+        assert!(json["kdf"]["Argon2id"]["salt"]
+            .as_str()
+            .unwrap()
+            .starts_with("SALT~"));
+        assert_eq!(
+            serde_json::from_value::<KeystoreBackup>(json).unwrap(),
+            backup
+        );
+        let bytes = binary::serialize(&backup).unwrap();
+        assert_eq!(
+            binary::deserialize::<KeystoreBackup>(&bytes).unwrap(),
+            backup
+        );
+
+        // The tag covers every other field.
+        let mut tampered = backup.clone();
+        tampered.kdf = Kdf::Scrypt {
+            salt: KeystoreSalt(vec![1; 16]),
+            log_n: 15,
+            r: 8,
+            p: 1,
+        };
+        assert_ne!(
+            tampered.authenticated_bytes().unwrap(),
+            backup.authenticated_bytes().unwrap()
+        );
+        tampered = backup.clone();
+        tampered.tag = IntegrityTag(vec![]);
+        assert_eq!(
+            tampered.authenticated_bytes().unwrap(),
+            backup.authenticated_bytes().unwrap()
+        );
+    }
+}
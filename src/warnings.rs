@@ -0,0 +1,157 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Warnings about partially degraded responses.
+//!
+//! A request can succeed even though something went wrong: a query service which has fallen behind
+//! the ledger can still answer from the state it has, and a deprecated route still works. Failing
+//! the request would be worse than answering it, but the client should be told. Servers attach
+//! [Warning]s to a response with [add_warning], which sends them in the [WARNINGS] header, so the
+//! body, and the type the client decodes it as, do not change:
+//!
+//! ```
+//! use net::warnings::{add_warning, Warning};
+//!
+//! let mut app = tide::new();
+//! app.at("/balance").get(|_| async {
+//!     let mut res = tide::Response::from("42");
+//!     add_warning(&mut res, Warning::new("stale_state", "answered from 3 blocks behind"));
+//!     Ok(res)
+//! });
+//! ```
+//!
+//! Clients can read the warnings of a response with [warnings], or add the [ReportWarnings]
+//! middleware, which passes the warnings of every response to a callback, so that code which only
+//! sees decoded bodies does not miss them.
+
+use crate::headers::TypedHeader;
+use http_types::headers::Headers;
+
+pub use crate::headers::{Warning, Warnings, WARNINGS};
+
+#[cfg(feature = "client")]
+pub use client::*;
+
+/// Add `warning` to the warnings of a response.
+///
+/// Warnings already attached to the response are kept.
+pub fn add_warning<H: AsRef<Headers> + AsMut<Headers>>(headers: &mut H, warning: Warning) {
+    let mut warnings = warnings(&*headers);
+    warnings.push(warning);
+    Warnings(warnings).insert(headers);
+}
+
+/// The warnings attached to a response.
+///
+/// A malformed [WARNINGS] header is treated as no warnings, since it should not cause an otherwise
+/// successful response to fail.
+pub fn warnings(headers: impl AsRef<Headers>) -> Vec<Warning> {
+    match Warnings::get(headers) {
+        Ok(Some(Warnings(warnings))) => warnings,
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(feature = "client")]
+mod client {
+    use super::*;
+    use std::sync::Arc;
+    use surf::middleware::{Middleware, Next};
+    use surf::{Client, Request, Url};
+    use tracing::{event, Level};
+
+    type Callback = Arc<dyn Fn(&Url, &Warning) + Send + Sync>;
+
+    /// Client middleware which reports the warnings attached to responses.
+    ///
+    /// Responses are passed through unchanged, so this can be added to any client.
+    #[derive(Clone)]
+    pub struct ReportWarnings {
+        callback: Callback,
+    }
+
+    impl ReportWarnings {
+        /// Call `callback` with the URL of the request and each warning attached to its response.
+        pub fn new(callback: impl Fn(&Url, &Warning) + Send + Sync + 'static) -> Self {
+            Self {
+                callback: Arc::new(callback),
+            }
+        }
+
+        /// Log each warning attached to a response.
+        pub fn log() -> Self {
+            Self::new(|url, warning| event!(Level::WARN, "warning from {}: {}", url, warning))
+        }
+    }
+
+    #[surf::utils::async_trait]
+    impl Middleware for ReportWarnings {
+        async fn handle(&self, req: Request, client: Client, next: Next<'_>) -> surf::Result {
+            let url = req.url().clone();
+            let res = next.run(req, client).await?;
+            for warning in warnings(&res) {
+                (self.callback)(&url, &warning);
+            }
+            Ok(res)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod test {
+    use super::*;
+    use crate::test_util::Server;
+    use std::sync::{Arc, Mutex};
+    use surf::{Client, StatusCode};
+
+    #[async_std::test]
+    async fn test_warnings() {
+        let mut app = tide::new();
+        app.at("/balance").get(|_| async {
+            let mut res = tide::Response::from("42");
+            add_warning(&mut res, Warning::new("stale_state", "3 blocks behind"));
+            add_warning(&mut res, Warning::new("deprecated", "use /v2/balance"));
+            Ok(res)
+        });
+        app.at("/height").get(|_| async { Ok("100") });
+
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let client = Client::new()
+            .with(ReportWarnings::new({
+                let reported = reported.clone();
+                move |url, warning| {
+                    reported
+                        .lock()
+                        .unwrap()
+                        .push((url.path().to_string(), warning.code.clone()))
+                }
+            }))
+            .with(Server(app));
+
+        // The body is unaffected by the warnings, which can also be read from the response.
+        let mut res = client.get("http://localhost/balance").await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(res.body_string().await.unwrap(), "42");
+        assert_eq!(
+            warnings(&res),
+            [
+                Warning::new("stale_state", "3 blocks behind"),
+                Warning::new("deprecated", "use /v2/balance")
+            ]
+        );
+        let res = client.get("http://localhost/height").await.unwrap();
+        assert!(warnings(&res).is_empty());
+
+        assert_eq!(
+            *reported.lock().unwrap(),
+            [
+                ("/balance".to_string(), "stale_state".to_string()),
+                ("/balance".to_string(), "deprecated".to_string())
+            ]
+        );
+    }
+}
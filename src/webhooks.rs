@@ -0,0 +1,702 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Push delivery of typed events to subscriber URLs.
+//!
+//! A service which wants to notify clients of new events (such as new memos on the bulletin board)
+//! without making them poll keeps a [Webhooks] registry. Subscribers register a URL and a shared
+//! secret, and each event passed to [Webhooks::publish] is POSTed to every subscriber as
+//! [canonical JSON](crate::wire::canonical_json), so that a subscriber which re-encodes the event
+//! can still reproduce the signed bytes. Each event has a [DELIVERY_ID], which is the same for
+//! every attempt to deliver it. IDs are based on the time the event was published, so they are not
+//! reused when the service restarts. The delivery ID, the time of the attempt, sent in the
+//! [TIMESTAMP] header, and the body are signed with HMAC-SHA256 using the subscriber's secret, and
+//! the signature is sent in the [SIGNATURE] header, so subscribers can check that the event came
+//! from the service and is not an old delivery being replayed.
+//!
+//! Deliveries which fail (because the subscriber is unreachable or responds with a non-2xx
+//! status) are retried with exponential backoff. Deliveries which still fail after the configured
+//! number of attempts are kept as [DeadLetter]s, which can be inspected and redelivered later.
+//!
+//! On the subscriber side, [receive] checks the signature and timestamp of an incoming delivery and
+//! deserializes the event. Deliveries signed more than [MAX_DELIVERY_AGE] seconds from the local
+//! clock are rejected, and the delivery ID and timestamp of each delivery are recorded in a
+//! [Replays](crate::server::replay::Replays) store until then, so a captured delivery cannot be
+//! replayed. Retries of a delivery have new timestamps and are accepted, so a subscriber which must
+//! process each event once should still ignore delivery IDs it has processed before. A subscriber
+//! which rotates its secret uses
+//! [receive_with_keyring] instead, which accepts deliveries signed with any secret in a
+//! [Keyring](crate::keyring::Keyring).
+
+#[cfg(any(
+    feature = "client",
+    all(feature = "server", not(target_arch = "wasm32"))
+))]
+use crate::headers::WebhookTimestamp;
+use crate::headers::{TypedHeader, WebhookSignature};
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+use crate::keyring::Keyring;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+use crate::server::replay::Replays;
+#[cfg(any(
+    feature = "client",
+    all(feature = "server", not(target_arch = "wasm32"))
+))]
+use crate::signing::unix_time;
+#[cfg(feature = "client")]
+use crate::wire::canonical_json;
+#[cfg(feature = "client")]
+use futures::future::join_all;
+use hmac::{Hmac, Mac};
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+use http_types::StatusCode;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "client")]
+use serde::Serialize;
+use sha2::Sha256;
+#[cfg(feature = "client")]
+use std::collections::BTreeMap;
+#[cfg(feature = "client")]
+use std::marker::PhantomData;
+#[cfg(feature = "client")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "client")]
+use std::time::Duration;
+#[cfg(feature = "client")]
+use surf::http::mime;
+#[cfg(feature = "client")]
+use surf::Url;
+
+/// Header containing the signature of a delivery, as `sha256=<hex HMAC>` of the [DELIVERY_ID], the
+/// [TIMESTAMP] and the body.
+pub use crate::headers::WEBHOOK_SIGNATURE as SIGNATURE;
+
+/// Header giving the time at which a delivery was signed, in seconds since the Unix epoch.
+pub use crate::headers::WEBHOOK_TIMESTAMP as TIMESTAMP;
+
+/// Header containing a unique identifier for each event, which is the same for every attempt to
+/// deliver it, so subscribers can ignore duplicates.
+pub use crate::headers::WEBHOOK_DELIVERY_ID as DELIVERY_ID;
+
+/// How far, in seconds, the [TIMESTAMP] of a delivery may be from the subscriber's clock.
+pub const MAX_DELIVERY_AGE: u64 = 5 * 60;
+
+fn mac(secret: &[u8], delivery: u64, timestamp: u64, body: &[u8]) -> Hmac<Sha256> {
+    // HMAC accepts keys of any length.
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+    // The ID and timestamp are decimal integers, so they cannot contain the separator.
+    mac.update(format!("{}.{}.", delivery, timestamp).as_bytes());
+    mac.update(body);
+    mac
+}
+
+/// The value of the [SIGNATURE] header for an attempt at `delivery` of `body` made at `timestamp`.
+pub fn sign(secret: &[u8], delivery: u64, timestamp: u64, body: &[u8]) -> String {
+    let signature = mac(secret, delivery, timestamp, body)
+        .finalize()
+        .into_bytes();
+    WebhookSignature(signature.to_vec()).encode()
+}
+
+/// Check the value of a [SIGNATURE] header against the delivery ID, timestamp and body of a
+/// delivery.
+///
+/// This does not check that the timestamp is recent, or that the delivery is not a replay;
+/// [receive] does.
+pub fn verify(secret: &[u8], signature: &str, delivery: u64, timestamp: u64, body: &[u8]) -> bool {
+    let signature = match WebhookSignature::decode(signature) {
+        Ok(WebhookSignature(signature)) => signature,
+        Err(_) => return false,
+    };
+    // `verify_slice` compares in constant time.
+    mac(secret, delivery, timestamp, body)
+        .verify_slice(&signature)
+        .is_ok()
+}
+
+/// Receive an event delivered by [Webhooks].
+///
+/// Fails with status 401 (Unauthorized) if the delivery is not signed with `secret` or its
+/// [TIMESTAMP] is more than [MAX_DELIVERY_AGE] seconds from the local clock, with the status of a
+/// [ReplayError](crate::server::replay::ReplayError) if `replays` has seen the delivery before, and
+/// with status 422 (Unprocessable Entity) if the body is not a valid event.
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub async fn receive<T: DeserializeOwned, S>(
+    secret: &[u8],
+    replays: &Replays,
+    req: &mut tide::Request<S>,
+) -> Result<T, tide::Error> {
+    receive_verified(req, replays, |signature, delivery, timestamp, body| {
+        verify(secret, signature, delivery, timestamp, body)
+    })
+    .await
+}
+
+/// Receive an event delivered by [Webhooks], signed with any of the keys in `keys`.
+///
+/// This lets a subscriber rotate its secret: it adds the new secret to `keys`, registers it with
+/// the service, and keeps accepting deliveries signed with the old secret during its grace period,
+/// while deliveries already in flight or being retried arrive.
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub async fn receive_with_keyring<T: DeserializeOwned, S>(
+    keys: &Keyring<Vec<u8>>,
+    replays: &Replays,
+    req: &mut tide::Request<S>,
+) -> Result<T, tide::Error> {
+    let keys = keys.valid();
+    receive_verified(req, replays, |signature, delivery, timestamp, body| {
+        keys.iter()
+            .any(|(_, secret)| verify(secret, signature, delivery, timestamp, body))
+    })
+    .await
+}
+
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+async fn receive_verified<T: DeserializeOwned, S>(
+    req: &mut tide::Request<S>,
+    replays: &Replays,
+    verify: impl FnOnce(&str, u64, u64, &[u8]) -> bool,
+) -> Result<T, tide::Error> {
+    let signature = req
+        .header(SIGNATURE)
+        .map(|signature| signature.as_str().to_string());
+    let delivery = req
+        .header(DELIVERY_ID)
+        .and_then(|delivery| delivery.as_str().parse::<u64>().ok());
+    let timestamp = match WebhookTimestamp::get(&*req) {
+        Ok(Some(WebhookTimestamp(timestamp)))
+            if timestamp.abs_diff(unix_time()) <= MAX_DELIVERY_AGE =>
+        {
+            timestamp
+        }
+        _ => {
+            return Err(tide::Error::from_str(
+                StatusCode::Unauthorized,
+                "missing or stale webhook timestamp",
+            ))
+        }
+    };
+    let body = req.body_bytes().await?;
+    let delivery = match (signature, delivery) {
+        (Some(signature), Some(delivery)) if verify(&signature, delivery, timestamp, &body) => {
+            delivery
+        }
+        _ => {
+            return Err(tide::Error::from_str(
+                StatusCode::Unauthorized,
+                "missing or invalid webhook signature",
+            ))
+        }
+    };
+    // Only the signed delivery ID and timestamp identify an attempt, and once the timestamp is
+    // stale the delivery is rejected anyway, so it only needs to be remembered until then.
+    let attempt = format!("webhook {}.{}", delivery, timestamp);
+    replays
+        .check(attempt, timestamp + MAX_DELIVERY_AGE + 1)
+        .await?;
+    serde_json::from_slice(&body)
+        .map_err(|err| tide::Error::new(StatusCode::UnprocessableEntity, err))
+}
+
+/// A registered subscriber.
+#[cfg(feature = "client")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Subscription {
+    pub id: u64,
+    pub url: Url,
+}
+
+/// An event which could not be delivered to a subscriber.
+#[cfg(feature = "client")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeadLetter {
+    pub subscription: u64,
+    pub url: Url,
+    pub delivery: u64,
+    /// The serialized event.
+    pub body: Vec<u8>,
+    pub attempts: u32,
+    /// The reason the last attempt failed.
+    pub error: String,
+}
+
+#[cfg(feature = "client")]
+struct Subscriber {
+    url: Url,
+    secret: Vec<u8>,
+}
+
+#[cfg(feature = "client")]
+#[derive(Default)]
+struct State {
+    next_subscription: u64,
+    last_delivery: u64,
+    subscribers: BTreeMap<u64, Arc<Subscriber>>,
+    dead_letters: Vec<DeadLetter>,
+}
+
+#[cfg(feature = "client")]
+impl State {
+    /// The current time in nanoseconds, or one more than the last ID if the clock has not moved
+    /// on, so that IDs are unique within the process and keep increasing across restarts.
+    fn next_delivery(&mut self) -> u64 {
+        self.last_delivery = unix_nanos().max(self.last_delivery + 1);
+        self.last_delivery
+    }
+}
+
+/// A registry of subscribers to events of type `T`.
+///
+/// Clones share the same subscribers and dead letters.
+#[cfg(feature = "client")]
+pub struct Webhooks<T> {
+    client: surf::Client,
+    max_attempts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    state: Arc<Mutex<State>>,
+    _event: PhantomData<fn(&T)>,
+}
+
+#[cfg(feature = "client")]
+impl<T> Clone for Webhooks<T> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            max_attempts: self.max_attempts,
+            backoff: self.backoff,
+            max_backoff: self.max_backoff,
+            state: self.state.clone(),
+            _event: Default::default(),
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+impl<T> Default for Webhooks<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "client")]
+impl<T> Webhooks<T> {
+    pub fn new() -> Self {
+        Self {
+            client: surf::Client::new(),
+            max_attempts: 5,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+            state: Default::default(),
+            _event: Default::default(),
+        }
+    }
+
+    /// Use `client` to make deliveries.
+    pub fn client(mut self, client: surf::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// The number of times to attempt each delivery before giving up (default 5).
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// The delay before the first retry, which doubles after each failed attempt up to `max`
+    /// (default 1 second, doubling up to 5 minutes).
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Register a subscriber, returning an ID which can be used to unsubscribe.
+    pub fn subscribe(&self, url: Url, secret: impl Into<Vec<u8>>) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_subscription;
+        state.next_subscription += 1;
+        state.subscribers.insert(
+            id,
+            Arc::new(Subscriber {
+                url,
+                secret: secret.into(),
+            }),
+        );
+        id
+    }
+
+    /// Remove a subscriber, returning whether it was registered.
+    pub fn unsubscribe(&self, id: u64) -> bool {
+        self.state.lock().unwrap().subscribers.remove(&id).is_some()
+    }
+
+    pub fn subscriptions(&self) -> Vec<Subscription> {
+        self.state
+            .lock()
+            .unwrap()
+            .subscribers
+            .iter()
+            .map(|(id, subscriber)| Subscription {
+                id: *id,
+                url: subscriber.url.clone(),
+            })
+            .collect()
+    }
+
+    /// Events which could not be delivered.
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.state.lock().unwrap().dead_letters.clone()
+    }
+
+    /// Try again to deliver all dead letters whose subscribers are still registered.
+    ///
+    /// Letters which fail again are returned to the dead letter list. Letters for subscribers
+    /// which have since unsubscribed are dropped.
+    pub async fn redeliver_dead_letters(&self) {
+        let (letters, subscribers) = {
+            let mut state = self.state.lock().unwrap();
+            let letters = std::mem::take(&mut state.dead_letters);
+            let subscribers = letters
+                .iter()
+                .map(|letter| state.subscribers.get(&letter.subscription).cloned())
+                .collect::<Vec<_>>();
+            (letters, subscribers)
+        };
+        join_all(
+            letters
+                .into_iter()
+                .zip(subscribers)
+                .filter_map(|(letter, subscriber)| {
+                    Some(self.deliver(
+                        letter.subscription,
+                        subscriber?,
+                        letter.delivery,
+                        letter.body,
+                    ))
+                }),
+        )
+        .await;
+    }
+
+    async fn deliver(&self, id: u64, subscriber: Arc<Subscriber>, delivery: u64, body: Vec<u8>) {
+        let mut backoff = self.backoff;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            // Each attempt is signed afresh, so that retries, which can come long after the event
+            // was published, are not rejected as stale.
+            let timestamp = unix_time();
+            let req = surf::post(subscriber.url.clone())
+                .header(
+                    SIGNATURE,
+                    sign(&subscriber.secret, delivery, timestamp, &body),
+                )
+                .header(TIMESTAMP, WebhookTimestamp(timestamp).encode())
+                .header(DELIVERY_ID, delivery.to_string())
+                .content_type(mime::JSON)
+                .body(body.clone());
+            let error = match self.client.send(req).await {
+                Ok(res) if res.status().is_success() => return,
+                Ok(res) => format!("subscriber responded with status {}", res.status()),
+                Err(err) => err.to_string(),
+            };
+            if attempts >= self.max_attempts {
+                tracing::warn!(
+                    "giving up on delivery {} to {} after {} attempts: {}",
+                    delivery,
+                    subscriber.url,
+                    attempts,
+                    error
+                );
+                self.state.lock().unwrap().dead_letters.push(DeadLetter {
+                    subscription: id,
+                    url: subscriber.url.clone(),
+                    delivery,
+                    body,
+                    attempts,
+                    error,
+                });
+                return;
+            }
+            tracing::debug!(
+                "delivery {} to {} failed, retrying in {:?}: {}",
+                delivery,
+                subscriber.url,
+                backoff,
+                error
+            );
+            async_std::task::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.max_backoff);
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+impl<T: Serialize> Webhooks<T> {
+    /// Deliver `event` to every subscriber.
+    ///
+    /// This resolves once every delivery has either succeeded or been added to the dead letters,
+    /// which, with retries, can take a while. Services which do not want to wait can spawn it as a
+    /// task on a clone of the registry.
+    pub async fn publish(&self, event: &T) -> Result<(), serde_json::Error> {
+        let body = canonical_json::to_vec(event)?;
+        let (delivery, subscribers) = {
+            let mut state = self.state.lock().unwrap();
+            let delivery = state.next_delivery();
+            let subscribers = state
+                .subscribers
+                .iter()
+                .map(|(id, subscriber)| (*id, subscriber.clone()))
+                .collect::<Vec<_>>();
+            (delivery, subscribers)
+        };
+        join_all(
+            subscribers
+                .into_iter()
+                .map(|(id, subscriber)| self.deliver(id, subscriber, delivery, body.clone())),
+        )
+        .await;
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "client", not(target_arch = "wasm32")))]
+fn unix_nanos() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+// The system clock is not available in browsers, so this asks JavaScript instead.
+#[cfg(all(feature = "client", target_arch = "wasm32"))]
+fn unix_nanos() -> u64 {
+    (js_sys::Date::now() * 1e6) as u64
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use surf::middleware::{Middleware, Next};
+    use tide::http::Request;
+
+    #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+    struct Memo {
+        text: String,
+    }
+
+    // A subscriber server, reached in-process instead of over the network, which fails the first
+    // `failures` requests.
+    struct MockSubscriber {
+        server: tide::Server<()>,
+        failures: AtomicUsize,
+    }
+
+    #[surf::utils::async_trait]
+    impl Middleware for MockSubscriber {
+        async fn handle(
+            &self,
+            req: surf::Request,
+            _client: surf::Client,
+            _next: Next<'_>,
+        ) -> surf::Result {
+            if self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                return Ok(surf::http::Response::new(StatusCode::ServiceUnavailable).into());
+            }
+            let req: Request = req.into();
+            let res: tide::http::Response = self.server.respond(req).await?;
+            Ok(res.into())
+        }
+    }
+
+    fn subscriber(failures: usize) -> (surf::Client, Arc<Mutex<Vec<Memo>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let replays = Replays::in_memory();
+        let mut server = tide::new();
+        server.at("/hook").post({
+            let received = received.clone();
+            move |mut req: tide::Request<()>| {
+                let received = received.clone();
+                let replays = replays.clone();
+                async move {
+                    let memo = receive::<Memo, _>(b"secret", &replays, &mut req).await?;
+                    received.lock().unwrap().push(memo);
+                    Ok(tide::Response::new(StatusCode::Ok))
+                }
+            }
+        });
+        let client = surf::Client::new().with(MockSubscriber {
+            server,
+            failures: AtomicUsize::new(failures),
+        });
+        (client, received)
+    }
+
+    fn url() -> Url {
+        Url::parse("http://subscriber/hook").unwrap()
+    }
+
+    fn memo() -> Memo {
+        Memo {
+            text: "hello".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_signature() {
+        let signature = sign(b"secret", 7, 1, b"body");
+        assert!(verify(b"secret", &signature, 7, 1, b"body"));
+        assert!(!verify(b"other", &signature, 7, 1, b"body"));
+        assert!(!verify(b"secret", &signature, 8, 1, b"body"));
+        assert!(!verify(b"secret", &signature, 7, 2, b"body"));
+        assert!(!verify(b"secret", &signature, 7, 1, b"other"));
+        assert!(!verify(b"secret", "garbage", 7, 1, b"body"));
+    }
+
+    fn hook() -> tide::Server<()> {
+        let replays = Replays::in_memory();
+        let mut server = tide::new();
+        server.at("/hook").post(move |mut req: tide::Request<()>| {
+            let replays = replays.clone();
+            async move {
+                receive::<Memo, _>(b"secret", &replays, &mut req).await?;
+                Ok(tide::Response::new(StatusCode::Ok))
+            }
+        });
+        server
+    }
+
+    // An attempt at delivery `id` of a memo, signed at `timestamp`.
+    fn delivery(id: u64, timestamp: Option<u64>) -> Request {
+        let body = canonical_json::to_vec(&memo()).unwrap();
+        let mut req = Request::new(tide::http::Method::Post, url());
+        if let Some(timestamp) = timestamp {
+            req.insert_header(SIGNATURE, sign(b"secret", id, timestamp, &body));
+            req.insert_header(TIMESTAMP, timestamp.to_string());
+        }
+        req.insert_header(DELIVERY_ID, id.to_string());
+        req.set_body(body);
+        req
+    }
+
+    #[async_std::test]
+    async fn test_stale_delivery() {
+        let server = hook();
+        let now = unix_time();
+        for (timestamp, status) in [
+            (Some(now), StatusCode::Ok),
+            (Some(now - MAX_DELIVERY_AGE - 60), StatusCode::Unauthorized),
+            (Some(now + MAX_DELIVERY_AGE + 60), StatusCode::Unauthorized),
+            (None, StatusCode::Unauthorized),
+        ] {
+            let res: tide::http::Response = server.respond(delivery(1, timestamp)).await.unwrap();
+            assert_eq!(res.status(), status, "{:?}", timestamp);
+        }
+
+        // Changing the timestamp of a captured delivery breaks the signature.
+        let mut req = delivery(2, Some(now - MAX_DELIVERY_AGE - 60));
+        req.insert_header(TIMESTAMP, now.to_string());
+        let res: tide::http::Response = server.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+    }
+
+    #[async_std::test]
+    async fn test_replayed_delivery() {
+        let server = hook();
+        let now = unix_time();
+        let res: tide::http::Response = server.respond(delivery(1, Some(now))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+
+        // A captured delivery cannot be replayed while its timestamp is fresh.
+        let res: tide::http::Response = server.respond(delivery(1, Some(now))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Conflict);
+
+        // Nor under a different delivery ID, which is signed.
+        let mut req = delivery(1, Some(now));
+        req.insert_header(DELIVERY_ID, "2");
+        let res: tide::http::Response = server.respond(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::Unauthorized);
+
+        // A retry of the delivery is signed at a different time, and so is accepted, as is another
+        // delivery signed at the same time.
+        for req in [delivery(1, Some(now - 1)), delivery(2, Some(now))] {
+            let res: tide::http::Response = server.respond(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::Ok);
+        }
+    }
+
+    #[test]
+    fn test_delivery_ids() {
+        let mut state = State::default();
+        let ids = (0..3).map(|_| state.next_delivery()).collect::<Vec<_>>();
+        assert!(ids[0] < ids[1] && ids[1] < ids[2]);
+
+        // A registry created after a restart does not reuse IDs, even if they were issued faster
+        // than the clock ticks.
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(State::default().next_delivery() > ids[2]);
+    }
+
+    #[async_std::test]
+    async fn test_retry() {
+        let (client, received) = subscriber(2);
+        let hooks = Webhooks::new()
+            .client(client)
+            .backoff(Duration::from_millis(1), Duration::from_millis(10));
+        hooks.subscribe(url(), "secret");
+        hooks.publish(&memo()).await.unwrap();
+        assert_eq!(*received.lock().unwrap(), vec![memo()]);
+        assert!(hooks.dead_letters().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_dead_letters() {
+        let (client, received) = subscriber(3);
+        let hooks = Webhooks::new()
+            .client(client)
+            .max_attempts(3)
+            .backoff(Duration::from_millis(1), Duration::from_millis(10));
+        let id = hooks.subscribe(url(), "secret");
+        hooks.publish(&memo()).await.unwrap();
+        assert!(received.lock().unwrap().is_empty());
+        let letters = hooks.dead_letters();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].subscription, id);
+        assert_eq!(letters[0].attempts, 3);
+
+        // The subscriber has recovered.
+        hooks.redeliver_dead_letters().await;
+        assert_eq!(*received.lock().unwrap(), vec![memo()]);
+        assert!(hooks.dead_letters().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_bad_signature() {
+        // A subscriber with a different secret rejects the delivery.
+        let (client, received) = subscriber(0);
+        let hooks = Webhooks::new().client(client).max_attempts(1);
+        hooks.subscribe(url(), "wrong");
+        hooks.publish(&memo()).await.unwrap();
+        assert!(received.lock().unwrap().is_empty());
+        assert_eq!(
+            hooks.dead_letters()[0].error,
+            format!(
+                "subscriber responded with status {}",
+                StatusCode::Unauthorized
+            )
+        );
+    }
+}
@@ -0,0 +1,27 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Wire formats shared by the client and server.
+//!
+//! Every encoding used on the wire is defined in exactly one place in this module, so that the
+//! client and server cannot drift apart, and so that upgrading a serialization library cannot
+//! silently change the bytes we produce.
+
+pub mod binary;
+pub mod body;
+pub mod canonical_json;
+pub mod csv;
+pub mod envelope;
+pub mod error;
+pub mod evolve;
+pub mod integers;
+pub mod ndjson;
+pub mod patch;
+pub mod problem;
+pub mod stream;
+
+pub use crate::headers::IDEMPOTENCY_KEY;
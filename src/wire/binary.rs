@@ -0,0 +1,121 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! The binary (`application/octet-stream`) wire format.
+//!
+//! Binary bodies are encoded with bincode. The top-level `bincode::serialize` and
+//! `bincode::deserialize` functions use whatever defaults the linked version of bincode happens to
+//! choose, and those defaults have changed between releases. Instead, all binary encoding in this
+//! crate goes through [options], which pins every setting explicitly:
+//!  * integers are encoded with a fixed width (not varint)
+//!  * integers are little endian
+//!  * trailing bytes after a value are allowed
+//!  * encoded values may be at most [SIZE_LIMIT] bytes
+//!
+//! The first three settings match the historical behavior of `bincode::serialize`, so this format
+//! is byte-for-byte compatible with peers that still use the bincode defaults, as long as they
+//! stay within the size limit.
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+/// The maximum size, in bytes, of a binary encoded value.
+///
+/// This bounds the amount of memory a peer can make us allocate by sending a malicious length
+/// prefix.
+pub const SIZE_LIMIT: u64 = 1 << 30;
+
+/// The bincode configuration used for all binary bodies.
+pub fn options() -> impl Options + Copy {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+        .allow_trailing_bytes()
+        .with_limit(SIZE_LIMIT)
+}
+
+/// Serialize a value in the binary wire format.
+pub fn serialize<T: ?Sized + Serialize>(value: &T) -> bincode::Result<Vec<u8>> {
+    options().serialize(value)
+}
+
+/// Deserialize a value from the binary wire format.
+pub fn deserialize<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> bincode::Result<T> {
+    options().deserialize(bytes)
+}
+
+/// A canonical binary profile for cryptographic types.
+///
+/// bincode encodes types according to their serde implementations, which for some cryptographic
+/// types are not canonical (the same value may have several valid encodings). Types which
+/// implement [CanonicalSerialize](ark_serialize::CanonicalSerialize) can use this profile instead,
+/// which produces exactly one encoding per value and rejects any input which is not exactly the
+/// canonical encoding of some value, including input with trailing bytes.
+#[cfg(feature = "canonical")]
+pub mod canonical {
+    use ark_serialize::{CanonicalDeserialize, CanonicalSerialize, SerializationError};
+
+    /// Serialize a value in the canonical binary format.
+    pub fn serialize<T: CanonicalSerialize>(value: &T) -> Result<Vec<u8>, SerializationError> {
+        let mut bytes = Vec::with_capacity(value.serialized_size());
+        value.serialize(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Deserialize a value from the canonical binary format.
+    pub fn deserialize<T: CanonicalDeserialize>(bytes: &[u8]) -> Result<T, SerializationError> {
+        let mut reader = bytes;
+        let value = T::deserialize(&mut reader)?;
+        if reader.is_empty() {
+            Ok(value)
+        } else {
+            Err(SerializationError::InvalidData)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+    struct Data {
+        small: u8,
+        large: u64,
+        bytes: Vec<u8>,
+    }
+
+    #[test]
+    fn test_compatible_with_bincode_defaults() {
+        let data = Data {
+            small: 1,
+            large: u64::MAX,
+            bytes: vec![1, 2, 3],
+        };
+        let bytes = serialize(&data).unwrap();
+        assert_eq!(bytes, bincode::serialize(&data).unwrap());
+        assert_eq!(data, deserialize::<Data>(&bytes).unwrap());
+        assert_eq!(data, bincode::deserialize::<Data>(&bytes).unwrap());
+    }
+
+    #[test]
+    fn test_size_limit() {
+        // A length prefix claiming more than `SIZE_LIMIT` bytes should be rejected without trying
+        // to allocate the buffer.
+        let bytes = (SIZE_LIMIT + 1).to_le_bytes();
+        assert!(deserialize::<Vec<u8>>(&bytes).is_err());
+    }
+
+    #[cfg(feature = "canonical")]
+    #[test]
+    fn test_canonical_rejects_trailing_bytes() {
+        let mut bytes = canonical::serialize(&42u64).unwrap();
+        assert_eq!(42u64, canonical::deserialize::<u64>(&bytes).unwrap());
+        bytes.push(0);
+        assert!(canonical::deserialize::<u64>(&bytes).is_err());
+    }
+}
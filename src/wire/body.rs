@@ -0,0 +1,285 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Reading and decoding message bodies.
+//!
+//! Bodies are read into memory with [read_body], which allocates a buffer of exactly the right size
+//! up front when the length of the body is known, rather than growing (and copying) a buffer as the
+//! body arrives. This matters for multi-megabyte bodies like Merkle frontiers. Bodies larger than
+//! [SIZE_LIMIT] are rejected before they are read; larger uploads can be sent as a
+//! [stream](super::stream) of smaller items.
+//!
+//! A body which has been read can be kept as a [BodyBytes], which can be decoded into types that
+//! borrow from it (such as `&str` or `&[u8]` fields, or `Cow`s), avoiding a second copy of large
+//! fields.
+
+use super::binary::{self, SIZE_LIMIT};
+use super::evolve::{self, Decoding};
+use futures::io::{AsyncRead, BufReader};
+use futures::AsyncReadExt;
+use http_types::{Body, Error, StatusCode};
+use serde::Deserialize;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Read a body into memory.
+///
+/// Fails with status 413 (Payload Too Large) if the body is larger than [SIZE_LIMIT].
+pub async fn read_body(body: Body) -> Result<Vec<u8>, Error> {
+    let capacity = match body.len() {
+        Some(len) if len as u64 > SIZE_LIMIT => return Err(too_large()),
+        Some(len) => len,
+        None => 0,
+    };
+    let mut bytes = Vec::with_capacity(capacity);
+    // Read at most one byte more than the limit, so we can tell if a body of unknown length is too
+    // large without reading all of it.
+    body.take(SIZE_LIMIT + 1).read_to_end(&mut bytes).await?;
+    if bytes.len() as u64 > SIZE_LIMIT {
+        return Err(too_large());
+    }
+    Ok(bytes)
+}
+
+pub(super) fn too_large() -> Error {
+    Error::from_str(
+        StatusCode::PayloadTooLarge,
+        format!("body exceeds the limit of {} bytes", SIZE_LIMIT),
+    )
+}
+
+/// Wrap `body` so that `observe` is called with `state` and each chunk read from it, and `done` is
+/// called with the final state when the body is dropped, whether or not it was read to the end.
+///
+/// This lets middleware account for a body, by counting or hashing it, without reading it into
+/// memory, so that streamed bodies stay streamed. The length and content type of `body` are kept.
+pub fn observe_body<S: Unpin + Send + Sync + 'static>(
+    body: Body,
+    state: S,
+    observe: fn(&mut S, &[u8]),
+    done: impl FnOnce(S) + Send + Sync + 'static,
+) -> Body {
+    let len = body.len();
+    let mime = body.mime().clone();
+    let mut observed = Body::from_reader(
+        BufReader::new(Observed {
+            body,
+            state: Some(state),
+            observe,
+            done: Some(Box::new(done)),
+        }),
+        len,
+    );
+    observed.set_mime(mime);
+    observed
+}
+
+struct Observed<S> {
+    body: Body,
+    state: Option<S>,
+    observe: fn(&mut S, &[u8]),
+    done: Option<Box<dyn FnOnce(S) + Send + Sync>>,
+}
+
+impl<S: Unpin> AsyncRead for Observed<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let res = Pin::new(&mut this.body).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(n)), Some(state)) = (&res, &mut this.state) {
+            (this.observe)(state, &buf[..*n]);
+        }
+        res
+    }
+}
+
+impl<S> Drop for Observed<S> {
+    fn drop(&mut self) {
+        if let (Some(state), Some(done)) = (self.state.take(), self.done.take()) {
+            done(state);
+        }
+    }
+}
+
+/// Whether a body was received by a server or a client.
+///
+/// This determines the status codes of decoding errors: a server reports a bad request, while a
+/// client reports that the server responded with something it cannot understand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Origin {
+    Request,
+    Response,
+}
+
+/// A body which has been read into memory, along with its content type.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BodyBytes {
+    origin: Origin,
+    content_type: Option<String>,
+    bytes: Vec<u8>,
+}
+
+impl BodyBytes {
+    pub fn new(origin: Origin, content_type: Option<String>, bytes: Vec<u8>) -> Self {
+        Self {
+            origin,
+            content_type,
+            bytes,
+        }
+    }
+
+    /// Read a body with the given content type into memory.
+    pub async fn read(
+        origin: Origin,
+        content_type: Option<String>,
+        body: Body,
+    ) -> Result<Self, Error> {
+        Ok(Self::new(origin, content_type, read_body(body).await?))
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// Deserialize the body, using its content type to determine the serialization format.
+    ///
+    /// The result may borrow from `self`.
+    pub fn decode<'a, T: Deserialize<'a>>(&'a self) -> Result<T, Error> {
+        decode(self.origin, self.content_type(), &self.bytes)
+    }
+
+    /// Deserialize the body, as strictly as `decoding` says.
+    pub fn decode_with<'a, T: Deserialize<'a>>(&'a self, decoding: Decoding) -> Result<T, Error> {
+        decode_with(self.origin, self.content_type(), &self.bytes, decoding)
+    }
+}
+
+/// Deserialize a body, using `content_type` to determine the serialization format.
+pub fn decode<'a, T: Deserialize<'a>>(
+    origin: Origin,
+    content_type: Option<&str>,
+    bytes: &'a [u8],
+) -> Result<T, Error> {
+    decode_with(origin, content_type, bytes, Decoding::Strict)
+}
+
+/// Deserialize a body, as strictly as `decoding` says.
+///
+/// See [evolve](super::evolve) for what [Decoding::Tolerant] accepts.
+pub fn decode_with<'a, T: Deserialize<'a>>(
+    origin: Origin,
+    content_type: Option<&str>,
+    bytes: &'a [u8],
+    decoding: Decoding,
+) -> Result<T, Error> {
+    match (origin, content_type) {
+        (Origin::Request, Some("application/json")) => evolve::from_json(bytes, decoding)
+            .map_err(|err| Error::new(StatusCode::UnprocessableEntity, err)),
+        (Origin::Response, Some("application/json")) => Ok(evolve::from_json(bytes, decoding)?),
+        (Origin::Request, Some("application/octet-stream")) => {
+            binary::deserialize(bytes).map_err(|err| {
+                Error::from_str(
+                    StatusCode::BadRequest,
+                    format!("unable to deserialize request body: {}", err),
+                )
+            })
+        }
+        (Origin::Response, Some("application/octet-stream")) => {
+            binary::deserialize(bytes).map_err(|err| {
+                Error::from_str(
+                    StatusCode::InternalServerError,
+                    format!("response body fails to deserialize: {}", err),
+                )
+            })
+        }
+        (Origin::Request, Some(content_type)) => Err(Error::from_str(
+            StatusCode::BadRequest,
+            format!("unsupported content type {}", content_type),
+        )),
+        (Origin::Response, Some(content_type)) => Err(Error::from_str(
+            StatusCode::UnsupportedMediaType,
+            format!("unsupported content type {}", content_type),
+        )),
+        (Origin::Request, None) => Err(Error::from_str(
+            StatusCode::BadRequest,
+            "unspecified content type",
+        )),
+        (Origin::Response, None) => Err(Error::from_str(
+            StatusCode::UnsupportedMediaType,
+            "unspecified content type in response",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Serialize;
+
+    #[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+    struct Borrowed<'a> {
+        name: &'a str,
+        #[serde(with = "borrowed_bytes")]
+        data: &'a [u8],
+    }
+
+    // serde only borrows `&[u8]` from formats which support borrowing bytes directly.
+    mod borrowed_bytes {
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(data: &[u8], s: S) -> Result<S::Ok, S::Error> {
+            s.serialize_bytes(data)
+        }
+
+        pub fn deserialize<'a, D: Deserializer<'a>>(d: D) -> Result<&'a [u8], D::Error> {
+            <&[u8]>::deserialize(d)
+        }
+    }
+
+    #[async_std::test]
+    async fn test_borrowed_binary() {
+        let value = Borrowed {
+            name: "frontier",
+            data: &[1, 2, 3],
+        };
+        let body = Body::from(binary::serialize(&value).unwrap());
+        let bytes = BodyBytes::read(
+            Origin::Response,
+            Some("application/octet-stream".to_string()),
+            body,
+        )
+        .await
+        .unwrap();
+        let decoded: Borrowed = bytes.decode().unwrap();
+        assert_eq!(decoded, value);
+        // The decoded value points into the body buffer, rather than a copy.
+        assert!(bytes
+            .as_bytes()
+            .as_ptr_range()
+            .contains(&decoded.data.as_ptr()));
+    }
+
+    #[async_std::test]
+    async fn test_size_limit() {
+        // A body whose declared length exceeds the limit is rejected without reading it.
+        let body = Body::from_reader(futures::io::empty(), Some(SIZE_LIMIT as usize + 1));
+        let err = read_body(body).await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::PayloadTooLarge);
+    }
+}
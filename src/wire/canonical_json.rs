@@ -0,0 +1,211 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Canonical JSON, for payloads which are signed.
+//!
+//! A signature is over bytes, so a verifier must reproduce the exact bytes that were signed. The
+//! ordinary JSON encoding leaves too much to the serializer for that to work across languages: the
+//! order of object keys, whitespace, and the formatting of numbers all vary. The canonical encoding
+//! fixes each of these, following the JSON Canonicalization Scheme (RFC 8785):
+//!  * object keys are sorted, by their UTF-16 code units
+//!  * there is no whitespace outside of strings
+//!  * strings escape only `"`, `\`, and control characters, using the short escapes (`\n`) where
+//!    they exist and lowercase `\u00xx` otherwise
+//!  * floating point numbers are formatted as by JavaScript's `Number.prototype.toString`, the
+//!    shortest representation which round-trips, so `1.0` is written `1`
+//!
+//! Integers are written exactly, even beyond the range JavaScript numbers can represent exactly;
+//! verifiers in languages which parse JSON numbers as doubles should canonicalize from the original
+//! text rather than a parsed value. In Go, for instance, decoding with `UseNumber` preserves them.
+//!
+//! Since canonical JSON is valid JSON, a signed payload can be sent in canonical form and parsed as
+//! usual by receivers that do not check the signature.
+
+use serde::Serialize;
+use serde_json::{Number, Value};
+use std::fmt::Write;
+
+/// Serialize `value` as canonical JSON.
+pub fn to_string<T: Serialize + ?Sized>(value: &T) -> Result<String, serde_json::Error> {
+    let value = serde_json::to_value(value)?;
+    let mut out = String::new();
+    write_value(&mut out, &value);
+    Ok(out)
+}
+
+/// Serialize `value` as canonical JSON bytes.
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, serde_json::Error> {
+    to_string(value).map(String::into_bytes)
+}
+
+/// Convert a JSON document to canonical form.
+///
+/// This is for checking the signature of a payload which was received in a different form, such
+/// as pretty-printed.
+pub fn canonicalize(json: &[u8]) -> Result<Vec<u8>, serde_json::Error> {
+    to_vec(&serde_json::from_slice::<Value>(json)?)
+}
+
+fn write_value(out: &mut String, value: &Value) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) => write_number(out, n),
+        Value::String(s) => write_string(out, s),
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(out, item);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut entries = map.iter().collect::<Vec<_>>();
+            entries.sort_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+            out.push('{');
+            for (i, (key, item)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_string(out, key);
+                out.push(':');
+                write_value(out, item);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\u{8}' => out.push_str("\\b"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\u{c}' => out.push_str("\\f"),
+            '\r' => out.push_str("\\r"),
+            c if c < ' ' => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_number(out: &mut String, n: &Number) {
+    if let Some(n) = n.as_u64() {
+        write!(out, "{}", n).unwrap();
+    } else if let Some(n) = n.as_i64() {
+        write!(out, "{}", n).unwrap();
+    } else if let Some(x) = n.as_f64() {
+        write_float(out, x);
+    }
+}
+
+// Format a finite float as by ECMAScript's Number::toString (ECMA-262, section 6.1.6.1.20).
+fn write_float(out: &mut String, x: f64) {
+    if x == 0.0 {
+        // Both zeros are written as `0`.
+        out.push('0');
+        return;
+    }
+    if x < 0.0 {
+        out.push('-');
+    }
+    // Rust's exponential formatting gives the shortest digits which round-trip, as ECMAScript
+    // requires, in the form `d.ddde±x`.
+    let sci = format!("{:e}", x.abs());
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let digits = mantissa.replace('.', "");
+    let k = digits.len() as i32;
+    // The position of the decimal point relative to the start of the digits.
+    let n = exp.parse::<i32>().unwrap() + 1;
+    if k <= n && n <= 21 {
+        out.push_str(&digits);
+        out.extend((0..n - k).map(|_| '0'));
+    } else if 0 < n && n <= 21 {
+        out.push_str(&digits[..n as usize]);
+        out.push('.');
+        out.push_str(&digits[n as usize..]);
+    } else if -6 < n && n <= 0 {
+        out.push_str("0.");
+        out.extend((0..-n).map(|_| '0'));
+        out.push_str(&digits);
+    } else {
+        out.push_str(&digits[..1]);
+        if k > 1 {
+            out.push('.');
+            out.push_str(&digits[1..]);
+        }
+        write!(out, "e{}{}", if n > 0 { "+" } else { "-" }, (n - 1).abs()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_canonical() {
+        // Keys are sorted at every level, and there is no whitespace.
+        let value = json!({
+            "b": [1, {"z": null, "a": true}],
+            "a": "x",
+            "\u{e9}": 1,
+            "\u{1f600}": 2,
+            "\u{ff61}": 3,
+        });
+        assert_eq!(
+            to_string(&value).unwrap(),
+            "{\"a\":\"x\",\"b\":[1,{\"a\":true,\"z\":null}],\"\u{e9}\":1,\"\u{1f600}\":2,\"\u{ff61}\":3}"
+        );
+
+        // Only the required characters are escaped.
+        assert_eq!(
+            to_string("\"\\/\u{8}\t\n\u{c}\r\u{1}\u{7f}\u{e9}").unwrap(),
+            "\"\\\"\\\\/\\b\\t\\n\\f\\r\\u0001\u{7f}\u{e9}\""
+        );
+
+        // Pretty-printed input gives the same result.
+        let pretty = serde_json::to_vec_pretty(&value).unwrap();
+        assert_eq!(canonicalize(&pretty).unwrap(), to_vec(&value).unwrap());
+    }
+
+    #[test]
+    fn test_numbers() {
+        let number = |x: Value| to_string(&x).unwrap();
+        assert_eq!(number(json!(u64::MAX)), "18446744073709551615");
+        assert_eq!(number(json!(i64::MIN)), "-9223372036854775808");
+
+        // Examples from RFC 8785, appendix B.
+        for (x, expected) in [
+            (0.0, "0"),
+            (-0.0, "0"),
+            (1.0, "1"),
+            (-1.5, "-1.5"),
+            (0.1, "0.1"),
+            (1e-7, "1e-7"),
+            (0.000001, "0.000001"),
+            (123e-20, "1.23e-18"),
+            (1e21, "1e+21"),
+            (1e20, "100000000000000000000"),
+            (4.5e15, "4500000000000000"),
+            (333333333.3333333, "333333333.3333333"),
+            (5e-324, "5e-324"),
+            (1.7976931348623157e308, "1.7976931348623157e+308"),
+            (295147905179352830000.0, "295147905179352830000"),
+        ] {
+            assert_eq!(number(json!(x)), expected, "{}", x);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! CSV, for tabular query results.
+//!
+//! Responses which are naturally tables, like lists of transactions, can also be served as CSV for
+//! loading into spreadsheets. CSV is not a serde format: a type opts in by implementing
+//! [TabularSerialize], which describes the columns of the table and the fields of each row. A
+//! sequence of rows is a table with the same columns as a single row, so usually only the row type
+//! needs an implementation.
+//!
+//! The server offers CSV alongside JSON and binary with
+//! [response_tabular](crate::server::response_tabular), and only uses it when the client asks for
+//! `text/csv` in its Accept header.
+//!
+//! The output follows RFC 4180: the first line is the header, lines end with `\r\n`, and fields
+//! containing commas, quotes or line breaks are quoted.
+//!
+//! Spreadsheets evaluate a cell starting with `=`, `+`, `-` or `@` as a formula, so a field chosen
+//! by one user, like a memo, could run a formula on the machine of whoever opens the export. Fields
+//! starting with one of these characters, or with a tab or carriage return, are prefixed with `'`
+//! so that they are shown as text. This includes negative numbers, which read as `'-2`.
+
+use http_types::Mime;
+use std::str::FromStr;
+
+/// The content type of CSV.
+pub const CONTENT_TYPE: &str = "text/csv";
+
+/// The [Mime] for [CONTENT_TYPE].
+pub fn mime() -> Mime {
+    Mime::from_str(CONTENT_TYPE).unwrap()
+}
+
+/// A value which can be serialized as a table of strings.
+///
+/// ```
+/// use net::wire::csv::{to_csv, TabularSerialize};
+///
+/// struct Transfer {
+///     from: String,
+///     to: String,
+///     amount: u64,
+/// }
+///
+/// impl TabularSerialize for Transfer {
+///     fn header() -> Vec<String> {
+///         vec!["from".into(), "to".into(), "amount".into()]
+///     }
+///
+///     fn records(&self) -> Vec<Vec<String>> {
+///         vec![vec![self.from.clone(), self.to.clone(), self.amount.to_string()]]
+///     }
+/// }
+///
+/// let transfers = vec![Transfer { from: "alice".into(), to: "bob, jr.".into(), amount: 10 }];
+/// assert_eq!(
+///     String::from_utf8(to_csv(&transfers)).unwrap(),
+///     "from,to,amount\r\nalice,\"bob, jr.\",10\r\n"
+/// );
+/// ```
+pub trait TabularSerialize {
+    /// The names of the columns.
+    ///
+    /// This does not depend on a value, so that an empty table still has a header.
+    fn header() -> Vec<String>;
+
+    /// The rows of the table, each with one field for each column of the [header](Self::header).
+    fn records(&self) -> Vec<Vec<String>>;
+}
+
+impl<T: TabularSerialize + ?Sized> TabularSerialize for &T {
+    fn header() -> Vec<String> {
+        T::header()
+    }
+
+    fn records(&self) -> Vec<Vec<String>> {
+        (**self).records()
+    }
+}
+
+impl<T: TabularSerialize> TabularSerialize for [T] {
+    fn header() -> Vec<String> {
+        T::header()
+    }
+
+    fn records(&self) -> Vec<Vec<String>> {
+        self.iter().flat_map(T::records).collect()
+    }
+}
+
+impl<T: TabularSerialize> TabularSerialize for Vec<T> {
+    fn header() -> Vec<String> {
+        T::header()
+    }
+
+    fn records(&self) -> Vec<Vec<String>> {
+        self.as_slice().records()
+    }
+}
+
+/// Serialize a table as CSV, with a header line.
+pub fn to_csv<T: TabularSerialize + ?Sized>(table: &T) -> Vec<u8> {
+    let mut csv = String::new();
+    write_record(&mut csv, &T::header());
+    for record in table.records() {
+        write_record(&mut csv, &record);
+    }
+    csv.into_bytes()
+}
+
+fn write_record(csv: &mut String, fields: &[String]) {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            csv.push(',');
+        }
+        let escaped;
+        let field = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+            escaped = format!("'{}", field);
+            &escaped
+        } else {
+            field
+        };
+        if field.contains([',', '"', '\r', '\n']) {
+            csv.push('"');
+            csv.push_str(&field.replace('"', "\"\""));
+            csv.push('"');
+        } else {
+            csv.push_str(field);
+        }
+    }
+    csv.push_str("\r\n");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Row(&'static str, i64);
+
+    impl TabularSerialize for Row {
+        fn header() -> Vec<String> {
+            vec!["name".into(), "balance".into()]
+        }
+
+        fn records(&self) -> Vec<Vec<String>> {
+            vec![vec![self.0.into(), self.1.to_string()]]
+        }
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let csv = |rows: &[Row]| String::from_utf8(to_csv(rows)).unwrap();
+        assert_eq!(csv(&[]), "name,balance\r\n");
+        assert_eq!(
+            csv(&[
+                Row("plain", 1),
+                Row("with \"quotes\"", -2),
+                Row("line\nbreak", 3),
+                Row("", 0)
+            ]),
+            "name,balance\r\nplain,1\r\n\"with \"\"quotes\"\"\",'-2\r\n\"line\nbreak\",3\r\n,0\r\n"
+        );
+
+        // Fields which a spreadsheet would evaluate as formulas are escaped.
+        assert_eq!(
+            csv(&[
+                Row("=HYPERLINK(\"http://evil\")", 0),
+                Row("+1", 0),
+                Row("@SUM(A1)", 0),
+                Row("\t=1", 0),
+                Row("a=b", 0)
+            ]),
+            "name,balance\r\n\"'=HYPERLINK(\"\"http://evil\"\")\",0\r\n'+1,0\r\n'@SUM(A1),0\r\n\
+             '\t=1,0\r\na=b,0\r\n"
+        );
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Response envelopes, which carry metadata about a response along with its body.
+//!
+//! Explorers and monitoring tools want to know, for every response, the ledger height it was
+//! computed at, which node served it, and how long it took. A client which sends the [ENVELOPE]
+//! header with [VERSION] gets successful JSON responses as an [Envelope], `{"data": ..., "meta":
+//! {...}}`, where `data` is the body it would otherwise have received. The server confirms that
+//! the body is wrapped by sending the same header back.
+//!
+//! Clients which do not send the header get the bare body, as before. Error responses and non-JSON
+//! bodies are never wrapped, so a client must check the response header, or use
+//! [response_envelope](crate::client::response_envelope), which does.
+
+use serde::{Deserialize, Serialize};
+
+pub use crate::headers::{EnvelopeVersion, ENVELOPE};
+
+/// The current version of the envelope format.
+pub const VERSION: u64 = 1;
+
+/// A response body with metadata.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub data: T,
+    pub meta: Meta,
+}
+
+/// Metadata about a response.
+///
+/// Each field is omitted if the server does not know it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Meta {
+    /// The ledger height at which the response was computed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ledger_height: Option<u64>,
+    /// The identity of the node which served the response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    /// How long the server took to produce the response, in milliseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+}
+
+/// Wrap the serialized JSON body `data` in an envelope with `meta`.
+///
+/// The body is copied into the envelope as it is, rather than parsed and serialized again, so that
+/// its encoding (for example, of large integers) is preserved. An empty body becomes `null`.
+pub fn wrap(data: &[u8], meta: &Meta) -> Result<Vec<u8>, serde_json::Error> {
+    let data = if data.iter().all(u8::is_ascii_whitespace) {
+        &b"null"[..]
+    } else {
+        data
+    };
+    let meta = serde_json::to_vec(meta)?;
+    let mut body = Vec::with_capacity(data.len() + meta.len() + 20);
+    body.extend_from_slice(b"{\"data\":");
+    body.extend_from_slice(data);
+    body.extend_from_slice(b",\"meta\":");
+    body.extend_from_slice(&meta);
+    body.push(b'}');
+    Ok(body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wrap() {
+        let meta = Meta {
+            ledger_height: Some(42),
+            node: Some("node-0".into()),
+            duration_ms: None,
+        };
+        let body = wrap(br#"{"balance":"18446744073709551615"}"#, &meta).unwrap();
+        // The body is copied exactly, and missing metadata is omitted.
+        assert!(body.starts_with(br#"{"data":{"balance":"18446744073709551615"},"meta":"#));
+        assert!(!body.windows(8).any(|w| w == b"duration"));
+        let envelope: Envelope<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(envelope.meta, meta);
+
+        // There is no empty JSON value, so an empty body is wrapped as null.
+        let body = wrap(b"", &meta).unwrap();
+        let envelope: Envelope<Option<u64>> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(envelope.data, None);
+    }
+}
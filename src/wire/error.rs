@@ -0,0 +1,193 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! The `help` field of error bodies.
+//!
+//! An error body may carry, in addition to the serialized error, a help message which clients can
+//! show verbatim to end users (for example, instructions for resolving the error, or a support
+//! URL). The help message is encoded so that peers which don't know about it can still decode the
+//! error:
+//!  * in JSON bodies, if the error is a JSON object, the help message is added to it as a `help`
+//!    field, which is ignored when deserializing the error (unless the error type denies unknown
+//!    fields)
+//!  * in binary bodies, the help message is serialized as a string after the error, where it is
+//!    ignored as trailing bytes
+//!
+//! Errors asking the client to solve a [challenge](crate::challenge) carry the [Challenge] in a
+//! `challenge` field in the same way. Since a client must be able to find the challenge without
+//! knowing the error type, these errors are always sent as JSON.
+//!
+//! Errors from a service which is [under maintenance](crate::maintenance) carry the details of the
+//! maintenance in a `maintenance` field of JSON bodies. Unlike challenges, these errors are not
+//! forced to JSON, since the end of the maintenance is also sent in the Retry-After header.
+//!
+//! Errors from a route which needs a role the client does not have (see [authz](crate::authz))
+//! carry the [Forbidden] details, saying which roles were needed, in a `forbidden` field of JSON
+//! bodies.
+
+use super::binary;
+use crate::authz::Forbidden;
+use crate::challenge::Challenge;
+use crate::maintenance::UnderMaintenance;
+use bincode::Options;
+use http_types::mime;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// The name of the help field in JSON error bodies.
+pub const HELP_FIELD: &str = "help";
+
+/// The name of the challenge field in JSON error bodies.
+pub const CHALLENGE_FIELD: &str = "challenge";
+
+/// The name of the maintenance field in JSON error bodies.
+pub const MAINTENANCE_FIELD: &str = "maintenance";
+
+/// The name of the forbidden field in JSON error bodies.
+pub const FORBIDDEN_FIELD: &str = "forbidden";
+
+/// Add a help message to a serialized error body with content type `ty`.
+///
+/// If `ty` is JSON and the body is not a JSON object, there is nowhere to put the message, and the
+/// body is left unchanged.
+pub fn append_help(ty: &str, body: &mut Vec<u8>, help: &str) -> Result<(), http_types::Error> {
+    if ty == mime::JSON.essence() {
+        if let Ok(serde_json::Value::Object(mut fields)) = serde_json::from_slice(body) {
+            fields.insert(HELP_FIELD.into(), help.into());
+            *body = serde_json::to_vec(&fields)?;
+        }
+    } else if ty == mime::BYTE_STREAM.essence() {
+        binary::options().serialize_into(body, help)?;
+    }
+    Ok(())
+}
+
+/// Extract the help message from an error body with content type `ty`.
+///
+/// `error` is the error decoded from the body, which is needed to find where the help message
+/// starts in binary bodies.
+pub fn extract_help<E: Serialize>(ty: &str, body: &[u8], error: &E) -> Option<String> {
+    if ty == mime::JSON.essence() {
+        match serde_json::from_slice::<serde_json::Value>(body).ok()? {
+            serde_json::Value::Object(mut fields) => match fields.remove(HELP_FIELD)? {
+                serde_json::Value::String(help) => Some(help),
+                _ => None,
+            },
+            _ => None,
+        }
+    } else if ty == mime::BYTE_STREAM.essence() {
+        let len = binary::options().serialized_size(error).ok()? as usize;
+        binary::deserialize(body.get(len..)?).ok()
+    } else {
+        None
+    }
+}
+
+/// Add a challenge to a serialized JSON error body.
+///
+/// If the body is not a JSON object, there is nowhere to put the challenge, and the body is left
+/// unchanged.
+pub fn append_challenge(
+    body: &mut Vec<u8>,
+    challenge: &Challenge,
+) -> Result<(), http_types::Error> {
+    append_field(body, CHALLENGE_FIELD, challenge)
+}
+
+/// Extract the challenge from a JSON error body, if it has one.
+pub fn extract_challenge(body: &[u8]) -> Option<Challenge> {
+    extract_field(body, CHALLENGE_FIELD)
+}
+
+/// Add the details of a maintenance to a serialized JSON error body.
+///
+/// If the body is not a JSON object, there is nowhere to put the details, and the body is left
+/// unchanged.
+pub fn append_maintenance(
+    body: &mut Vec<u8>,
+    maintenance: &UnderMaintenance,
+) -> Result<(), http_types::Error> {
+    append_field(body, MAINTENANCE_FIELD, maintenance)
+}
+
+/// Extract the details of a maintenance from a JSON error body, if it has them.
+pub fn extract_maintenance(body: &[u8]) -> Option<UnderMaintenance> {
+    extract_field(body, MAINTENANCE_FIELD)
+}
+
+/// Add the details of a [Forbidden] error to a serialized JSON error body.
+///
+/// If the body is not a JSON object, there is nowhere to put the details, and the body is left
+/// unchanged.
+pub fn append_forbidden(
+    body: &mut Vec<u8>,
+    forbidden: &Forbidden,
+) -> Result<(), http_types::Error> {
+    append_field(body, FORBIDDEN_FIELD, forbidden)
+}
+
+/// Extract the details of a [Forbidden] error from a JSON error body, if it has them.
+pub fn extract_forbidden(body: &[u8]) -> Option<Forbidden> {
+    extract_field(body, FORBIDDEN_FIELD)
+}
+
+fn append_field<T: Serialize>(
+    body: &mut Vec<u8>,
+    name: &str,
+    value: &T,
+) -> Result<(), http_types::Error> {
+    if let Ok(serde_json::Value::Object(mut fields)) = serde_json::from_slice(body) {
+        fields.insert(name.into(), serde_json::to_value(value)?);
+        *body = serde_json::to_vec(&fields)?;
+    }
+    Ok(())
+}
+
+fn extract_field<T: DeserializeOwned>(body: &[u8], name: &str) -> Option<T> {
+    match serde_json::from_slice::<serde_json::Value>(body).ok()? {
+        serde_json::Value::Object(mut fields) => serde_json::from_value(fields.remove(name)?).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+    struct Error {
+        msg: String,
+    }
+
+    #[test]
+    fn test_help() {
+        let err = Error {
+            msg: "insufficient balance".into(),
+        };
+        for ty in [mime::JSON.essence(), mime::BYTE_STREAM.essence()] {
+            let mut body = if ty == mime::JSON.essence() {
+                serde_json::to_vec(&err).unwrap()
+            } else {
+                binary::serialize(&err).unwrap()
+            };
+            assert_eq!(extract_help(ty, &body, &err), None);
+
+            append_help(ty, &mut body, "see https://docs.espressosys.com").unwrap();
+            // The error can still be decoded by peers which don't know about the help message.
+            let decoded: Error = if ty == mime::JSON.essence() {
+                serde_json::from_slice(&body).unwrap()
+            } else {
+                binary::deserialize(&body).unwrap()
+            };
+            assert_eq!(decoded, err);
+            assert_eq!(
+                extract_help(ty, &body, &decoded).as_deref(),
+                Some("see https://docs.espressosys.com")
+            );
+        }
+    }
+}
@@ -0,0 +1,656 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Decoding bodies written by newer or older peers.
+//!
+//! Services add fields to their types over time, and clients which were built against an older
+//! version of a type must keep working. How well that goes depends on the format:
+//!  * In JSON, unknown fields are ignored and missing [Option] fields are [None], unless a type
+//!    opts out with `#[serde(deny_unknown_fields)]`. [Decoding::Tolerant] ignores unknown fields
+//!    even then, at every level of a value, so that a type can be strict about its own input and
+//!    still read bodies from newer peers. Missing fields are handled as usual: [Option] fields are
+//!    [None], and fields with `#[serde(default)]` take their default.
+//!  * In the binary format, fields are not named, so a body can only be decoded by a peer which
+//!    knows exactly which fields it has. A [Versioned] envelope records the version of the
+//!    representation of its body, so a peer can decode bodies of older versions (see
+//!    [Evolving::upgrade]), and can tell when a body is newer than it understands.
+//!
+//! ```
+//! use net::wire::evolve::{Evolving, Versioned};
+//! use serde::{Deserialize, Deserializer, Serialize};
+//!
+//! #[derive(Deserialize)]
+//! struct AccountV1 {
+//!     balance: u64,
+//! }
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct Account {
+//!     balance: u64,
+//!     frozen: bool,
+//! }
+//!
+//! impl Evolving for Account {
+//!     const VERSION: u16 = 2;
+//!
+//!     fn upgrade<'de, D: Deserializer<'de>>(version: u16, body: D) -> Result<Self, D::Error> {
+//!         // There is only one older version.
+//!         assert_eq!(version, 1);
+//!         let AccountV1 { balance } = AccountV1::deserialize(body)?;
+//!         Ok(Account { balance, frozen: false })
+//!     }
+//! }
+//!
+//! let old = net::wire::binary::serialize(&(1u16, 100u64)).unwrap();
+//! let account: Versioned<Account> = net::wire::binary::deserialize(&old).unwrap();
+//! assert_eq!(account.version, 1);
+//! assert_eq!(account.body.balance, 100);
+//! ```
+//!
+//! A body with a newer version than the receiver knows is decoded as the receiver's version. This
+//! works if the newer version only added fields at the end of the body: in JSON, the new fields are
+//! ignored, and in the binary format, they are trailing bytes, which are ignored as long as the
+//! envelope is the whole body, and not part of a larger value.
+
+use crate::redact::SafeToServe;
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, Deserializer, EnumAccess, IgnoredAny,
+    IntoDeserializer, MapAccess, SeqAccess, VariantAccess, Visitor,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Formatter};
+use std::marker::PhantomData;
+
+/// How strictly to decode a body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Decoding {
+    /// Decode according to the type's serde implementation.
+    Strict,
+    /// Also ignore unknown fields in JSON bodies, even in types which deny them.
+    Tolerant,
+}
+
+impl Default for Decoding {
+    fn default() -> Self {
+        Self::Strict
+    }
+}
+
+/// A type whose binary representation has versions.
+pub trait Evolving: DeserializeOwned {
+    /// The version of the representation written by this build.
+    const VERSION: u16;
+
+    /// Deserialize a body written with an older `version` of the representation.
+    ///
+    /// The default implementation fails, so that only the current and newer versions are accepted.
+    fn upgrade<'de, D: Deserializer<'de>>(version: u16, _body: D) -> Result<Self, D::Error> {
+        Err(de::Error::custom(format!(
+            "unsupported version {}, expected at least {}",
+            version,
+            Self::VERSION
+        )))
+    }
+}
+
+/// A body, with the version of its representation.
+///
+/// See the [module documentation](self).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Versioned<T> {
+    pub version: u16,
+    pub body: T,
+}
+
+impl<T: Evolving> Versioned<T> {
+    /// Wrap `body`, with the current version of its representation.
+    pub fn new(body: T) -> Self {
+        Self {
+            version: T::VERSION,
+            body,
+        }
+    }
+}
+
+impl<T: SafeToServe> SafeToServe for Versioned<T> {}
+
+impl<'de, T: Evolving> Deserialize<'de> for Versioned<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_struct(
+            "Versioned",
+            &["version", "body"],
+            VersionedVisitor(PhantomData),
+        )
+    }
+}
+
+struct VersionedVisitor<T>(PhantomData<T>);
+
+impl<'de, T: Evolving> Visitor<'de> for VersionedVisitor<T> {
+    type Value = Versioned<T>;
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("a versioned body")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let version = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let body = seq
+            .next_element_seed(Body(version, PhantomData))?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        Ok(Versioned { version, body })
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        // The version determines how to decode the body, so it must come first, as it does when
+        // an envelope is serialized.
+        let mut version: Option<u16> = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "version" => version = Some(map.next_value()?),
+                "body" => {
+                    let version = version
+                        .ok_or_else(|| de::Error::custom("`version` must come before `body`"))?;
+                    let body = map.next_value_seed(Body(version, PhantomData))?;
+                    while map.next_key::<IgnoredAny>()?.is_some() {
+                        map.next_value::<IgnoredAny>()?;
+                    }
+                    return Ok(Versioned { version, body });
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Err(de::Error::missing_field("body"))
+    }
+}
+
+// The body of an envelope with the given version.
+struct Body<T>(u16, PhantomData<T>);
+
+impl<'de, T: Evolving> DeserializeSeed<'de> for Body<T> {
+    type Value = T;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<T, D::Error> {
+        if self.0 < T::VERSION {
+            T::upgrade(self.0, deserializer)
+        } else {
+            T::deserialize(deserializer)
+        }
+    }
+}
+
+/// A deserializer which ignores unknown fields of structs, at every level of a value.
+///
+/// This is how [Decoding::Tolerant] decodes JSON. It has no effect on formats which do not name
+/// fields, like the binary format. Fields are recognized by the names the type's [Deserialize]
+/// implementation declares, so fields which a type accepts under other names (with
+/// `#[serde(alias)]`) are not recognized under those names.
+pub struct Tolerant<D>(pub D);
+
+// A visitor which wraps the deserializers and accessors it is given in [Tolerant], so that nested
+// values are decoded tolerantly too. `fields` are the names of the fields of the struct it visits,
+// if it visits a struct.
+struct Wrap<V> {
+    visitor: V,
+    fields: Option<&'static [&'static str]>,
+}
+
+impl<V> Wrap<V> {
+    fn new(visitor: V) -> Self {
+        Self {
+            visitor,
+            fields: None,
+        }
+    }
+}
+
+macro_rules! forward_deserialize {
+    ($($method:ident),*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, D::Error> {
+                self.0.$method(Wrap::new(visitor))
+            }
+        )*
+    };
+}
+
+impl<'de, D: Deserializer<'de>> Deserializer<'de> for Tolerant<D> {
+    type Error = D::Error;
+
+    forward_deserialize!(
+        deserialize_any,
+        deserialize_bool,
+        deserialize_i8,
+        deserialize_i16,
+        deserialize_i32,
+        deserialize_i64,
+        deserialize_i128,
+        deserialize_u8,
+        deserialize_u16,
+        deserialize_u32,
+        deserialize_u64,
+        deserialize_u128,
+        deserialize_f32,
+        deserialize_f64,
+        deserialize_char,
+        deserialize_str,
+        deserialize_string,
+        deserialize_bytes,
+        deserialize_byte_buf,
+        deserialize_option,
+        deserialize_unit,
+        deserialize_seq,
+        deserialize_map,
+        deserialize_identifier,
+        deserialize_ignored_any
+    );
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, D::Error> {
+        self.0.deserialize_unit_struct(name, Wrap::new(visitor))
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, D::Error> {
+        self.0.deserialize_newtype_struct(name, Wrap::new(visitor))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, D::Error> {
+        self.0.deserialize_tuple(len, Wrap::new(visitor))
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, D::Error> {
+        self.0
+            .deserialize_tuple_struct(name, len, Wrap::new(visitor))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, D::Error> {
+        let visitor = Wrap {
+            visitor,
+            fields: Some(fields),
+        };
+        self.0.deserialize_struct(name, fields, visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, D::Error> {
+        self.0.deserialize_enum(name, variants, Wrap::new(visitor))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.0.is_human_readable()
+    }
+}
+
+macro_rules! forward_visit {
+    ($($method:ident($ty:ty)),*) => {
+        $(
+            fn $method<E: de::Error>(self, v: $ty) -> Result<V::Value, E> {
+                self.visitor.$method(v)
+            }
+        )*
+    };
+}
+
+impl<'de, V: Visitor<'de>> Visitor<'de> for Wrap<V> {
+    type Value = V::Value;
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.visitor.expecting(f)
+    }
+
+    forward_visit!(
+        visit_bool(bool),
+        visit_i8(i8),
+        visit_i16(i16),
+        visit_i32(i32),
+        visit_i64(i64),
+        visit_i128(i128),
+        visit_u8(u8),
+        visit_u16(u16),
+        visit_u32(u32),
+        visit_u64(u64),
+        visit_u128(u128),
+        visit_f32(f32),
+        visit_f64(f64),
+        visit_char(char),
+        visit_str(&str),
+        visit_borrowed_str(&'de str),
+        visit_string(String),
+        visit_bytes(&[u8]),
+        visit_borrowed_bytes(&'de [u8]),
+        visit_byte_buf(Vec<u8>)
+    );
+
+    fn visit_none<E: de::Error>(self) -> Result<V::Value, E> {
+        self.visitor.visit_none()
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<V::Value, D::Error> {
+        self.visitor.visit_some(Tolerant(deserializer))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<V::Value, E> {
+        self.visitor.visit_unit()
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<V::Value, D::Error> {
+        self.visitor.visit_newtype_struct(Tolerant(deserializer))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<V::Value, A::Error> {
+        self.visitor.visit_seq(Tolerant(seq))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<V::Value, A::Error> {
+        self.visitor.visit_map(Fields {
+            map,
+            fields: self.fields,
+        })
+    }
+
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<V::Value, A::Error> {
+        self.visitor.visit_enum(Tolerant(data))
+    }
+}
+
+impl<'de, S: DeserializeSeed<'de>> DeserializeSeed<'de> for Tolerant<S> {
+    type Value = S::Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<S::Value, D::Error> {
+        self.0.deserialize(Tolerant(deserializer))
+    }
+}
+
+impl<'de, A: SeqAccess<'de>> SeqAccess<'de> for Tolerant<A> {
+    type Error = A::Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, A::Error> {
+        self.0.next_element_seed(Tolerant(seed))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.0.size_hint()
+    }
+}
+
+// The entries of a map. If the map is a struct with the given `fields`, entries for other fields
+// are skipped.
+struct Fields<A> {
+    map: A,
+    fields: Option<&'static [&'static str]>,
+}
+
+impl<'de, A: MapAccess<'de>> MapAccess<'de> for Fields<A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, A::Error> {
+        let fields = match self.fields {
+            Some(fields) => fields,
+            None => return self.map.next_key_seed(Tolerant(seed)),
+        };
+        while let Some(key) = self.map.next_key::<String>()? {
+            if fields.contains(&key.as_str()) {
+                let key: de::value::StringDeserializer<A::Error> = key.into_deserializer();
+                return seed.deserialize(key).map(Some);
+            }
+            self.map.next_value::<IgnoredAny>()?;
+        }
+        Ok(None)
+    }
+
+    fn next_value_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value, A::Error> {
+        self.map.next_value_seed(Tolerant(seed))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        match self.fields {
+            // Some entries may be skipped.
+            Some(_) => None,
+            None => self.map.size_hint(),
+        }
+    }
+}
+
+impl<'de, A: EnumAccess<'de>> EnumAccess<'de> for Tolerant<A> {
+    type Error = A::Error;
+    type Variant = Tolerant<A::Variant>;
+
+    fn variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<(T::Value, Self::Variant), A::Error> {
+        let (value, variant) = self.0.variant_seed(seed)?;
+        Ok((value, Tolerant(variant)))
+    }
+}
+
+impl<'de, A: VariantAccess<'de>> VariantAccess<'de> for Tolerant<A> {
+    type Error = A::Error;
+
+    fn unit_variant(self) -> Result<(), A::Error> {
+        self.0.unit_variant()
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, A::Error> {
+        self.0.newtype_variant_seed(Tolerant(seed))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, A::Error> {
+        self.0.tuple_variant(len, Wrap::new(visitor))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, A::Error> {
+        let visitor = Wrap {
+            visitor,
+            fields: Some(fields),
+        };
+        self.0.struct_variant(fields, visitor)
+    }
+}
+
+/// Deserialize JSON, as strictly as `decoding` says.
+pub fn from_json<'a, T: Deserialize<'a>>(
+    bytes: &'a [u8],
+    decoding: Decoding,
+) -> serde_json::Result<T> {
+    match decoding {
+        Decoding::Strict => serde_json::from_slice(bytes),
+        Decoding::Tolerant => {
+            let mut deserializer = serde_json::Deserializer::from_slice(bytes);
+            let value = T::deserialize(Tolerant(&mut deserializer))?;
+            deserializer.end()?;
+            Ok(value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wire::binary;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Record {
+        id: u64,
+        memo: Option<String>,
+        entries: Vec<Entry>,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    enum Entry {
+        Credit { amount: u64 },
+        Debit(Debit),
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Debit {
+        amount: u64,
+    }
+
+    #[test]
+    fn test_tolerant() {
+        // A body from a newer peer, with new fields at every level.
+        let json = br#"{
+            "id": 1,
+            "status": "final",
+            "entries": [
+                {"Credit": {"amount": 2, "source": "faucet"}},
+                {"Debit": {"amount": 3, "fee": 1}}
+            ]
+        }"#;
+        from_json::<Record>(json, Decoding::Strict).unwrap_err();
+        assert_eq!(
+            from_json::<Record>(json, Decoding::Tolerant).unwrap(),
+            Record {
+                id: 1,
+                memo: None,
+                entries: vec![
+                    Entry::Credit { amount: 2 },
+                    Entry::Debit(Debit { amount: 3 })
+                ],
+            }
+        );
+
+        // Missing required fields and malformed bodies are still errors.
+        from_json::<Record>(br#"{"memo": "hi", "entries": []}"#, Decoding::Tolerant).unwrap_err();
+        from_json::<Record>(br#"{"id": 1, "entries": []} x"#, Decoding::Tolerant).unwrap_err();
+    }
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct V1 {
+        balance: u64,
+    }
+
+    impl Evolving for V1 {
+        const VERSION: u16 = 1;
+    }
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct V2 {
+        balance: u64,
+        frozen: bool,
+    }
+
+    impl Evolving for V2 {
+        const VERSION: u16 = 2;
+
+        fn upgrade<'de, D: Deserializer<'de>>(version: u16, body: D) -> Result<Self, D::Error> {
+            if version != 1 {
+                return Err(de::Error::custom("unknown version"));
+            }
+            let V1 { balance } = V1::deserialize(body)?;
+            Ok(V2 {
+                balance,
+                frozen: false,
+            })
+        }
+    }
+
+    #[test]
+    fn test_versioned() {
+        let old = Versioned::new(V1 { balance: 10 });
+        let new = Versioned::new(V2 {
+            balance: 20,
+            frozen: true,
+        });
+
+        // Binary.
+        let bytes = binary::serialize(&new).unwrap();
+        assert_eq!(bytes[..2], 2u16.to_le_bytes());
+        assert_eq!(binary::deserialize::<Versioned<V2>>(&bytes).unwrap(), new);
+        let read_by_old = binary::deserialize::<Versioned<V1>>(&bytes).unwrap();
+        assert_eq!(read_by_old.version, 2);
+        assert_eq!(read_by_old.body, V1 { balance: 20 });
+        let bytes = binary::serialize(&old).unwrap();
+        let read_by_new = binary::deserialize::<Versioned<V2>>(&bytes).unwrap();
+        assert_eq!(read_by_new.version, 1);
+        assert_eq!(
+            read_by_new.body,
+            V2 {
+                balance: 10,
+                frozen: false
+            }
+        );
+
+        // JSON.
+        let json = serde_json::to_vec(&new).unwrap();
+        assert_eq!(
+            from_json::<Versioned<V1>>(&json, Decoding::Strict)
+                .unwrap()
+                .body,
+            V1 { balance: 20 }
+        );
+        let json = serde_json::to_vec(&old).unwrap();
+        assert_eq!(
+            from_json::<Versioned<V2>>(&json, Decoding::Strict)
+                .unwrap()
+                .body,
+            V2 {
+                balance: 10,
+                frozen: false
+            }
+        );
+
+        // Versions older than a type supports are rejected.
+        let bytes = binary::serialize(&Versioned {
+            version: 0,
+            body: V1 { balance: 0 },
+        })
+        .unwrap();
+        binary::deserialize::<Versioned<V1>>(&bytes).unwrap_err();
+        from_json::<Versioned<V1>>(
+            br#"{"body": {"balance": 0}, "version": 1}"#,
+            Decoding::Strict,
+        )
+        .unwrap_err();
+    }
+}
@@ -0,0 +1,572 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Large integers in JSON.
+//!
+//! JavaScript parses every JSON number as a double, which represents integers exactly only up to
+//! 2^53. Larger values, like uids, amounts and asset codes, are silently rounded, so a JavaScript
+//! client can display the wrong balance or refer to the wrong record. The usual remedy is to send
+//! such integers as decimal strings.
+//!
+//! This module supports that in two ways:
+//!  * per field, with the serde adapters [as_string], which always writes a string, and [lenient],
+//!    which writes a number; both accept a number or a string on input
+//!  * per response, with [IntegersAsStrings], which writes every 64- and 128-bit integer in a value
+//!    as a string, without changing its type. Servers do this for clients which ask for it with
+//!    the [JsonIntegers](crate::headers::JsonIntegers) header.
+//!
+//! Either way, only human-readable formats are affected. In the binary format, integers are always
+//! encoded as numbers. The UIDs and ledger heights in [types](crate::types) use [lenient], so that
+//! clients can send them back as strings.
+//!
+//! A JSON number larger than [u64::MAX] cannot be read exactly by the adapters, since it is parsed
+//! as a floating point number, and it is rejected. Such values must be sent as strings.
+
+use serde::de::{self, DeserializeOwned, Deserializer, Unexpected, Visitor};
+use serde::ser::{self, Serialize, Serializer};
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+/// How 64- and 128-bit integers are written in JSON.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IntegerFormat {
+    /// As JSON numbers.
+    Number,
+    /// As decimal strings.
+    String,
+}
+
+impl Default for IntegerFormat {
+    fn default() -> Self {
+        Self::Number
+    }
+}
+
+/// An integer type which can be written as a string.
+pub trait Integer:
+    Copy
+    + Display
+    + FromStr
+    + Serialize
+    + DeserializeOwned
+    + TryFrom<u64>
+    + TryFrom<i64>
+    + TryFrom<u128>
+    + TryFrom<i128>
+{
+}
+
+impl Integer for u64 {}
+impl Integer for i64 {}
+impl Integer for u128 {}
+impl Integer for i128 {}
+
+/// A serde adapter which writes an integer as a string in human-readable formats.
+///
+/// Use this with `#[serde(with = "net::wire::integers::as_string")]` on a `u64`, `i64`, `u128` or
+/// `i128` field. Both numbers and strings are accepted when deserializing.
+pub mod as_string {
+    use super::*;
+
+    pub fn serialize<T: Integer, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(value)
+        } else {
+            value.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, T: Integer, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        super::deserialize(deserializer)
+    }
+}
+
+/// A serde adapter which writes an integer as a number, but also accepts strings.
+///
+/// Use this with `#[serde(with = "net::wire::integers::lenient")]` on fields which must keep their
+/// existing encoding, but which clients may have converted to strings.
+pub mod lenient {
+    use super::*;
+
+    pub fn serialize<T: Integer, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        value.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T: Integer, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        super::deserialize(deserializer)
+    }
+}
+
+fn deserialize<'de, T: Integer, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_any(IntegerVisitor(PhantomData))
+    } else {
+        T::deserialize(deserializer)
+    }
+}
+
+struct IntegerVisitor<T>(PhantomData<T>);
+
+impl<'de, T: Integer> Visitor<'de> for IntegerVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("an integer, or a string containing one")
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<T, E> {
+        T::try_from(v).map_err(|_| E::invalid_value(Unexpected::Unsigned(v), &self))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<T, E> {
+        T::try_from(v).map_err(|_| E::invalid_value(Unexpected::Signed(v), &self))
+    }
+
+    fn visit_u128<E: de::Error>(self, v: u128) -> Result<T, E> {
+        T::try_from(v).map_err(|_| E::invalid_value(Unexpected::Other("128-bit integer"), &self))
+    }
+
+    fn visit_i128<E: de::Error>(self, v: i128) -> Result<T, E> {
+        T::try_from(v).map_err(|_| E::invalid_value(Unexpected::Other("128-bit integer"), &self))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<T, E> {
+        v.parse()
+            .map_err(|_| E::invalid_value(Unexpected::Str(v), &self))
+    }
+}
+
+/// A value whose 64- and 128-bit integers are written as strings in human-readable formats.
+///
+/// Smaller integers are written as usual, so the JSON type of each field depends only on its Rust
+/// type, and not on its value.
+///
+/// ```
+/// use net::wire::integers::IntegersAsStrings;
+///
+/// let json = serde_json::to_string(&IntegersAsStrings(&(u64::MAX, 7u32))).unwrap();
+/// assert_eq!(json, r#"["18446744073709551615",7]"#);
+/// ```
+pub struct IntegersAsStrings<'a, T: ?Sized>(pub &'a T);
+
+impl<T: Serialize + ?Sized> Serialize for IntegersAsStrings<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            self.0.serialize(Stringify(serializer))
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+// A serializer which writes large integers as strings, and passes everything else through to the
+// wrapped serializer. Compound values are wrapped too, so that nested integers are converted.
+struct Stringify<S>(S);
+
+impl<S: Serializer> Serializer for Stringify<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+    type SerializeSeq = Stringify<S::SerializeSeq>;
+    type SerializeTuple = Stringify<S::SerializeTuple>;
+    type SerializeTupleStruct = Stringify<S::SerializeTupleStruct>;
+    type SerializeTupleVariant = Stringify<S::SerializeTupleVariant>;
+    type SerializeMap = Stringify<S::SerializeMap>;
+    type SerializeStruct = Stringify<S::SerializeStruct>;
+    type SerializeStructVariant = Stringify<S::SerializeStructVariant>;
+
+    fn serialize_i64(self, v: i64) -> Result<S::Ok, S::Error> {
+        self.0.collect_str(&v)
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<S::Ok, S::Error> {
+        self.0.collect_str(&v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<S::Ok, S::Error> {
+        self.0.collect_str(&v)
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<S::Ok, S::Error> {
+        self.0.collect_str(&v)
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<S::Ok, S::Error> {
+        self.0.serialize_bool(v)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<S::Ok, S::Error> {
+        self.0.serialize_i8(v)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<S::Ok, S::Error> {
+        self.0.serialize_i16(v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<S::Ok, S::Error> {
+        self.0.serialize_i32(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<S::Ok, S::Error> {
+        self.0.serialize_u8(v)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<S::Ok, S::Error> {
+        self.0.serialize_u16(v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<S::Ok, S::Error> {
+        self.0.serialize_u32(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<S::Ok, S::Error> {
+        self.0.serialize_f32(v)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<S::Ok, S::Error> {
+        self.0.serialize_f64(v)
+    }
+
+    fn serialize_char(self, v: char) -> Result<S::Ok, S::Error> {
+        self.0.serialize_char(v)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<S::Ok, S::Error> {
+        self.0.serialize_str(v)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<S::Ok, S::Error> {
+        self.0.serialize_bytes(v)
+    }
+
+    fn serialize_none(self) -> Result<S::Ok, S::Error> {
+        self.0.serialize_none()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<S::Ok, S::Error> {
+        self.0.serialize_some(&IntegersAsStrings(value))
+    }
+
+    fn serialize_unit(self) -> Result<S::Ok, S::Error> {
+        self.0.serialize_unit()
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<S::Ok, S::Error> {
+        self.0.serialize_unit_struct(name)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+    ) -> Result<S::Ok, S::Error> {
+        self.0.serialize_unit_variant(name, index, variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<S::Ok, S::Error> {
+        self.0
+            .serialize_newtype_struct(name, &IntegersAsStrings(value))
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<S::Ok, S::Error> {
+        self.0
+            .serialize_newtype_variant(name, index, variant, &IntegersAsStrings(value))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, S::Error> {
+        self.0.serialize_seq(len).map(Stringify)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, S::Error> {
+        self.0.serialize_tuple(len).map(Stringify)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, S::Error> {
+        self.0.serialize_tuple_struct(name, len).map(Stringify)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, S::Error> {
+        self.0
+            .serialize_tuple_variant(name, index, variant, len)
+            .map(Stringify)
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, S::Error> {
+        self.0.serialize_map(len).map(Stringify)
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, S::Error> {
+        self.0.serialize_struct(name, len).map(Stringify)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, S::Error> {
+        self.0
+            .serialize_struct_variant(name, index, variant, len)
+            .map(Stringify)
+    }
+
+    fn collect_str<T: Display + ?Sized>(self, value: &T) -> Result<S::Ok, S::Error> {
+        self.0.collect_str(value)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.0.is_human_readable()
+    }
+}
+
+impl<S: ser::SerializeSeq> ser::SerializeSeq for Stringify<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), S::Error> {
+        self.0.serialize_element(&IntegersAsStrings(value))
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        self.0.end()
+    }
+}
+
+impl<S: ser::SerializeTuple> ser::SerializeTuple for Stringify<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), S::Error> {
+        self.0.serialize_element(&IntegersAsStrings(value))
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        self.0.end()
+    }
+}
+
+impl<S: ser::SerializeTupleStruct> ser::SerializeTupleStruct for Stringify<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), S::Error> {
+        self.0.serialize_field(&IntegersAsStrings(value))
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        self.0.end()
+    }
+}
+
+impl<S: ser::SerializeTupleVariant> ser::SerializeTupleVariant for Stringify<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), S::Error> {
+        self.0.serialize_field(&IntegersAsStrings(value))
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        self.0.end()
+    }
+}
+
+impl<S: ser::SerializeMap> ser::SerializeMap for Stringify<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), S::Error> {
+        self.0.serialize_key(&IntegersAsStrings(key))
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), S::Error> {
+        self.0.serialize_value(&IntegersAsStrings(value))
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        self.0.end()
+    }
+}
+
+impl<S: ser::SerializeStruct> ser::SerializeStruct for Stringify<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), S::Error> {
+        self.0.serialize_field(key, &IntegersAsStrings(value))
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), S::Error> {
+        self.0.skip_field(key)
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        self.0.end()
+    }
+}
+
+impl<S: ser::SerializeStructVariant> ser::SerializeStructVariant for Stringify<S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), S::Error> {
+        self.0.serialize_field(key, &IntegersAsStrings(value))
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), S::Error> {
+        self.0.skip_field(key)
+    }
+
+    fn end(self) -> Result<S::Ok, S::Error> {
+        self.0.end()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wire::binary;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Record {
+        #[serde(with = "as_string")]
+        uid: u64,
+        #[serde(with = "as_string")]
+        amount: u128,
+        #[serde(with = "lenient")]
+        height: u64,
+    }
+
+    #[test]
+    fn test_adapters() {
+        let record = Record {
+            uid: u64::MAX,
+            amount: u128::MAX,
+            height: 5,
+        };
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "uid": "18446744073709551615",
+                "amount": "340282366920938463463374607431768211455",
+                "height": 5,
+            })
+        );
+        assert_eq!(serde_json::from_value::<Record>(json).unwrap(), record);
+
+        // Both representations are accepted on input.
+        let json = r#"{"uid": 1, "amount": 2, "height": "3"}"#;
+        assert_eq!(
+            serde_json::from_str::<Record>(json).unwrap(),
+            Record {
+                uid: 1,
+                amount: 2,
+                height: 3
+            }
+        );
+        for json in [
+            r#"{"uid": -1, "amount": 2, "height": 3}"#,
+            r#"{"uid": "one", "amount": 2, "height": 3}"#,
+            r#"{"uid": 1.5, "amount": 2, "height": 3}"#,
+        ] {
+            serde_json::from_str::<Record>(json).unwrap_err();
+        }
+
+        // The binary format is unchanged.
+        let bytes = binary::serialize(&record).unwrap();
+        assert_eq!(
+            bytes,
+            binary::serialize(&(u64::MAX, u128::MAX, 5u64)).unwrap()
+        );
+        assert_eq!(binary::deserialize::<Record>(&bytes).unwrap(), record);
+    }
+
+    #[derive(Serialize)]
+    enum Event {
+        Transfer { amount: u64, memo: Option<i64> },
+        Mint(u128),
+    }
+
+    #[test]
+    fn test_integers_as_strings() {
+        let mut balances = BTreeMap::new();
+        balances.insert(1u64, vec![u64::MAX]);
+        let value = (
+            balances,
+            vec![
+                Event::Transfer {
+                    amount: 10,
+                    memo: Some(-1),
+                },
+                Event::Mint(20),
+            ],
+            (7u32, -7i8, 1.5f64, "text"),
+        );
+        assert_eq!(
+            serde_json::to_value(&IntegersAsStrings(&value)).unwrap(),
+            serde_json::json!([
+                {"1": ["18446744073709551615"]},
+                [{"Transfer": {"amount": "10", "memo": "-1"}}, {"Mint": "20"}],
+                [7, -7, 1.5, "text"],
+            ])
+        );
+
+        // The binary format is unchanged.
+        assert_eq!(
+            binary::serialize(&IntegersAsStrings(&value)).unwrap(),
+            binary::serialize(&value).unwrap()
+        );
+    }
+}
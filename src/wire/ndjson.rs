@@ -0,0 +1,183 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Newline-delimited JSON, for exporting long sequences.
+//!
+//! Endpoints which dump a whole data set, like every block or every memo in a ledger, cannot build
+//! the response in memory. Instead, the server produces the items as a stream, and [encode] writes
+//! each one as a line of JSON as it is produced. The body is only read as fast as the client
+//! consumes it, and items are only taken from the stream as the body is read, so a slow client
+//! slows down the producer rather than causing the server to buffer the export.
+//!
+//! The client parses the lines as they arrive with [decode]. Unlike the framed streams of
+//! [wire::stream](super::stream), NDJSON is a standard format (`application/x-ndjson`) which can
+//! also be consumed by tools like `jq`, so it is the preferred format for exports.
+//!
+//! Every line, including the last, ends with `\n`. A body which ends without a newline was cut off,
+//! and its last item is reported as an error rather than silently dropped. Blank lines are ignored.
+
+use super::binary::SIZE_LIMIT;
+use super::body::{self, too_large, Origin};
+use super::stream::{truncated, Encoder};
+use futures::io::{AsyncBufReadExt, AsyncReadExt};
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use http_types::{Body, Error, Mime};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io;
+use std::str::FromStr;
+
+/// The content type of newline-delimited JSON.
+pub const CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// The [Mime] for [CONTENT_TYPE].
+pub fn mime() -> Mime {
+    Mime::from_str(CONTENT_TYPE).unwrap()
+}
+
+/// A body containing each item of `items` as a line of JSON, as it becomes available.
+///
+/// The body has no declared length, so it is sent with chunked transfer encoding.
+pub fn encode<T, S>(items: S) -> Body
+where
+    T: Serialize + 'static,
+    S: Stream<Item = T> + Unpin + Send + Sync + 'static,
+{
+    let mut body = Body::from_reader(Encoder::new(items, encode_line), None);
+    body.set_mime(mime());
+    body
+}
+
+/// Deserialize the lines of a newline-delimited JSON body as they arrive.
+///
+/// The stream ends after the first error.
+pub fn decode<T: DeserializeOwned>(
+    origin: Origin,
+    body: Body,
+) -> BoxStream<'static, Result<T, Error>> {
+    stream::unfold(Some(body), move |body| async move {
+        let mut body = body?;
+        match read_line(origin, &mut body).await {
+            Ok(Some(line)) => {
+                let item = body::decode(origin, Some("application/json"), &line);
+                let next = if item.is_ok() { Some(body) } else { None };
+                Some((item, next))
+            }
+            Ok(None) => None,
+            Err(err) => Some((Err(err), None)),
+        }
+    })
+    .boxed()
+}
+
+fn encode_line<T: Serialize>(item: &T) -> io::Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(item).map_err(io::Error::from)?;
+    if line.len() as u64 > SIZE_LIMIT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("stream item exceeds the limit of {} bytes", SIZE_LIMIT),
+        ));
+    }
+    // Compact JSON never contains a raw newline, so the line cannot be split.
+    line.push(b'\n');
+    Ok(line)
+}
+
+// Read the next non-blank line, without the newline, or [None] at the end of the body.
+async fn read_line(origin: Origin, body: &mut Body) -> Result<Option<Vec<u8>>, Error> {
+    loop {
+        let mut line = Vec::new();
+        // Read at most one byte past the limit and the newline, enough to tell that a line is too
+        // long.
+        (&mut *body)
+            .take(SIZE_LIMIT + 2)
+            .read_until(b'\n', &mut line)
+            .await?;
+        match line.pop() {
+            None => return Ok(None),
+            Some(b'\n') if line.len() as u64 > SIZE_LIMIT => return Err(too_large()),
+            Some(b'\n') if line.iter().all(u8::is_ascii_whitespace) => continue,
+            Some(b'\n') => return Ok(Some(line)),
+            Some(_) if line.len() as u64 > SIZE_LIMIT => return Err(too_large()),
+            Some(_) => return Err(truncated(origin)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::TryStreamExt;
+    use http_types::StatusCode;
+    use serde::Deserialize;
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Block {
+        height: u64,
+        txns: Vec<String>,
+    }
+
+    fn block(height: u64) -> Block {
+        Block {
+            height,
+            txns: vec!["a\nb".to_string(); height as usize % 3],
+        }
+    }
+
+    #[async_std::test]
+    async fn test_round_trip() {
+        let blocks = (0..1000).map(block).collect::<Vec<_>>();
+        let body = encode(stream::iter(blocks.clone()));
+        assert_eq!(body.len(), None);
+        assert_eq!(body.mime().essence(), CONTENT_TYPE);
+        let decoded: Vec<Block> = decode(Origin::Response, body).try_collect().await.unwrap();
+        assert_eq!(decoded, blocks);
+    }
+
+    #[async_std::test]
+    async fn test_lines() {
+        let decode_str =
+            |s: &'static str| decode::<u64>(Origin::Response, Body::from(s)).collect::<Vec<_>>();
+
+        // One item per line, ignoring blank lines.
+        let items = decode_str("1\n\n2\r\n  \n3\n").await;
+        assert_eq!(
+            items.into_iter().collect::<Result<Vec<_>, _>>().unwrap(),
+            [1, 2, 3]
+        );
+
+        // A body cut off in the middle of a line.
+        let items = decode_str("1\n2").await;
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            items[1].as_ref().unwrap_err().status(),
+            StatusCode::InternalServerError
+        );
+    }
+
+    #[async_std::test]
+    async fn test_backpressure() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+
+        // Items are only produced as the body is read.
+        let produced = Arc::new(AtomicU64::new(0));
+        let items = stream::iter(0..u64::MAX).map({
+            let produced = produced.clone();
+            move |i| {
+                produced.fetch_add(1, Ordering::SeqCst);
+                i
+            }
+        });
+        let decoded: Vec<u64> = decode::<u64>(Origin::Response, encode(items))
+            .take(10)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(decoded, (0..10).collect::<Vec<_>>());
+        assert!(produced.load(Ordering::SeqCst) < 1000);
+    }
+}
@@ -0,0 +1,345 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Patches between two versions of a serializable value.
+//!
+//! A [JsonPatch] is a list of `add`, `remove` and `replace` operations on the JSON representation of
+//! a value, as in [RFC 6902](https://datatracker.ietf.org/doc/html/rfc6902). In JSON bodies, a patch
+//! is encoded exactly as RFC 6902 specifies. The binary format cannot encode arbitrary JSON values,
+//! so in binary bodies the value of each operation is encoded as a JSON string instead.
+
+use serde::de::{DeserializeOwned, Error as _};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use snafu::Snafu;
+
+#[derive(Debug, Snafu)]
+pub enum PatchError {
+    #[snafu(display("failed to convert value to or from JSON: {}", source))]
+    Json { source: serde_json::Error },
+    #[snafu(display("path {} does not exist", path))]
+    MissingPath { path: String },
+}
+
+impl From<serde_json::Error> for PatchError {
+    fn from(source: serde_json::Error) -> Self {
+        Self::Json { source }
+    }
+}
+
+/// A single patch operation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Operation {
+    /// Insert `value` at `path`.
+    ///
+    /// If `path` refers to an array element, the value is inserted before the existing element at
+    /// that index. The final token of `path` may be `-` to append to an array.
+    Add { path: String, value: Value },
+    /// Remove the value at `path`.
+    Remove { path: String },
+    /// Replace the value at `path` with `value`.
+    Replace { path: String, value: Value },
+}
+
+// The serialized form of an [Operation].
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum OperationKind {
+    Add,
+    Remove,
+    Replace,
+}
+
+// In human readable formats, `value` is the JSON value itself.
+#[derive(Serialize, Deserialize)]
+struct RawOperation {
+    op: OperationKind,
+    path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<Value>,
+}
+
+// In other formats, `value` is the JSON value encoded as a string. These formats are generally not
+// self-describing, and cannot skip fields, so `value` is always present.
+#[derive(Serialize, Deserialize)]
+struct BinaryOperation {
+    op: OperationKind,
+    path: String,
+    value: Option<String>,
+}
+
+impl Operation {
+    fn into_raw(self) -> RawOperation {
+        match self {
+            Self::Add { path, value } => RawOperation {
+                op: OperationKind::Add,
+                path,
+                value: Some(value),
+            },
+            Self::Remove { path } => RawOperation {
+                op: OperationKind::Remove,
+                path,
+                value: None,
+            },
+            Self::Replace { path, value } => RawOperation {
+                op: OperationKind::Replace,
+                path,
+                value: Some(value),
+            },
+        }
+    }
+
+    fn from_raw<E: serde::de::Error>(raw: RawOperation) -> Result<Self, E> {
+        let path = raw.path;
+        match (raw.op, raw.value) {
+            (OperationKind::Add, Some(value)) => Ok(Self::Add { path, value }),
+            (OperationKind::Remove, _) => Ok(Self::Remove { path }),
+            (OperationKind::Replace, Some(value)) => Ok(Self::Replace { path, value }),
+            _ => Err(E::missing_field("value")),
+        }
+    }
+}
+
+impl Serialize for Operation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let raw = self.clone().into_raw();
+        if serializer.is_human_readable() {
+            raw.serialize(serializer)
+        } else {
+            BinaryOperation {
+                op: raw.op,
+                path: raw.path,
+                value: raw.value.map(|value| value.to_string()),
+            }
+            .serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Operation {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = if deserializer.is_human_readable() {
+            RawOperation::deserialize(deserializer)?
+        } else {
+            let raw = BinaryOperation::deserialize(deserializer)?;
+            RawOperation {
+                op: raw.op,
+                path: raw.path,
+                value: raw
+                    .value
+                    .map(|value| serde_json::from_str(&value))
+                    .transpose()
+                    .map_err(D::Error::custom)?,
+            }
+        };
+        Self::from_raw(raw)
+    }
+}
+
+/// A patch which transforms one version of a value into another.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct JsonPatch(pub Vec<Operation>);
+
+impl JsonPatch {
+    /// Compute a patch which transforms `old` into `new`.
+    pub fn diff<T: Serialize>(old: &T, new: &T) -> Result<Self, PatchError> {
+        let mut ops = Vec::new();
+        diff_values(
+            &serde_json::to_value(old)?,
+            &serde_json::to_value(new)?,
+            String::new(),
+            &mut ops,
+        );
+        Ok(Self(ops))
+    }
+
+    /// Apply this patch to `base`.
+    pub fn apply<T: Serialize + DeserializeOwned>(&self, base: &T) -> Result<T, PatchError> {
+        let mut value = serde_json::to_value(base)?;
+        for op in &self.0 {
+            apply_operation(&mut value, op)?;
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+fn escape(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+
+fn unescape(token: &str) -> String {
+    token.replace("~1", "/").replace("~0", "~")
+}
+
+fn diff_values(old: &Value, new: &Value, path: String, ops: &mut Vec<Operation>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for key in old.keys().filter(|key| !new.contains_key(*key)) {
+                ops.push(Operation::Remove {
+                    path: format!("{}/{}", path, escape(key)),
+                });
+            }
+            for (key, value) in new {
+                let path = format!("{}/{}", path, escape(key));
+                match old.get(key) {
+                    Some(old) => diff_values(old, value, path, ops),
+                    None => ops.push(Operation::Add {
+                        path,
+                        value: value.clone(),
+                    }),
+                }
+            }
+        }
+        (Value::Array(old), Value::Array(new)) => {
+            for (i, (old, new)) in old.iter().zip(new).enumerate() {
+                diff_values(old, new, format!("{}/{}", path, i), ops);
+            }
+            // Remove extra elements from the end, so that the indices of the remaining elements do
+            // not change as we go.
+            for i in (new.len()..old.len()).rev() {
+                ops.push(Operation::Remove {
+                    path: format!("{}/{}", path, i),
+                });
+            }
+            for value in new.iter().skip(old.len()) {
+                ops.push(Operation::Add {
+                    path: format!("{}/-", path),
+                    value: value.clone(),
+                });
+            }
+        }
+        (old, new) if old != new => ops.push(Operation::Replace {
+            path,
+            value: new.clone(),
+        }),
+        _ => {}
+    }
+}
+
+fn apply_operation(root: &mut Value, op: &Operation) -> Result<(), PatchError> {
+    let path = match op {
+        Operation::Add { path, .. }
+        | Operation::Remove { path }
+        | Operation::Replace { path, .. } => path,
+    };
+    let missing = || PatchError::MissingPath { path: path.clone() };
+
+    // The empty path refers to the whole value.
+    let (parent_path, last) = match path.rfind('/') {
+        Some(i) => (&path[..i], unescape(&path[i + 1..])),
+        None if path.is_empty() => {
+            match op {
+                Operation::Add { value, .. } | Operation::Replace { value, .. } => {
+                    *root = value.clone()
+                }
+                Operation::Remove { .. } => *root = Value::Null,
+            }
+            return Ok(());
+        }
+        None => return Err(missing()),
+    };
+    let parent = root.pointer_mut(parent_path).ok_or_else(missing)?;
+
+    match parent {
+        Value::Object(map) => apply_to_object(map, last, op).ok_or_else(missing),
+        Value::Array(vec) => {
+            let index = if last == "-" {
+                vec.len()
+            } else {
+                last.parse().map_err(|_| missing())?
+            };
+            match op {
+                Operation::Add { value, .. } if index <= vec.len() => {
+                    vec.insert(index, value.clone());
+                }
+                Operation::Remove { .. } if index < vec.len() => {
+                    vec.remove(index);
+                }
+                Operation::Replace { value, .. } if index < vec.len() => {
+                    vec[index] = value.clone();
+                }
+                _ => return Err(missing()),
+            }
+            Ok(())
+        }
+        _ => Err(missing()),
+    }
+}
+
+fn apply_to_object(map: &mut Map<String, Value>, key: String, op: &Operation) -> Option<()> {
+    match op {
+        Operation::Add { value, .. } => {
+            map.insert(key, value.clone());
+        }
+        Operation::Remove { .. } => {
+            map.remove(&key)?;
+        }
+        Operation::Replace { value, .. } => {
+            *map.get_mut(&key)? = value.clone();
+        }
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wire::binary;
+    use std::collections::BTreeMap;
+
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    struct State {
+        height: u64,
+        balances: BTreeMap<String, u64>,
+        records: Vec<u64>,
+    }
+
+    fn state(height: u64, balances: &[(&str, u64)], records: &[u64]) -> State {
+        State {
+            height,
+            balances: balances.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+            records: records.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_diff_apply() {
+        let old = state(1, &[("a/b", 1), ("c", 2)], &[1, 2, 3]);
+        for new in [
+            state(1, &[("a/b", 1), ("c", 2)], &[1, 2, 3]),
+            state(2, &[("a/b", 5), ("d~", 2)], &[1, 2, 3, 4, 5]),
+            state(3, &[], &[2]),
+        ] {
+            let patch = JsonPatch::diff(&old, &new).unwrap();
+            assert_eq!(patch.apply(&old).unwrap(), new);
+
+            // The patch survives both wire formats.
+            let json: JsonPatch =
+                serde_json::from_str(&serde_json::to_string(&patch).unwrap()).unwrap();
+            assert_eq!(json, patch);
+            let bin: JsonPatch = binary::deserialize(&binary::serialize(&patch).unwrap()).unwrap();
+            assert_eq!(bin, patch);
+        }
+    }
+
+    #[test]
+    fn test_rfc6902_encoding() {
+        let patch = JsonPatch::diff(&vec![1], &vec![2, 3]).unwrap();
+        assert_eq!(
+            serde_json::to_value(&patch).unwrap(),
+            serde_json::json!([
+                {"op": "replace", "path": "/0", "value": 2},
+                {"op": "add", "path": "/-", "value": 3},
+            ])
+        );
+    }
+}
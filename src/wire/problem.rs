@@ -0,0 +1,165 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Problem details (RFC 7807), for error responses to consumers which do not know our error types.
+//!
+//! Errors are normally sent as the serialization of the API's [Error] type, which only a client
+//! that knows the type can interpret. Many HTTP tools and non-Rust clients understand the standard
+//! `application/problem+json` format instead, so the server sends errors in that format to clients
+//! which ask for it in their Accept header. It is never chosen otherwise.
+//!
+//! A [Problem] has the standard members:
+//!  * `type`, a URI identifying the kind of error, from [Error::problem_type], or `about:blank`
+//!  * `title`, the reason phrase of the status
+//!  * `status`, the HTTP status of the response
+//!  * `detail`, the error message ([Display] of the error)
+//!  * `instance`, the path of the request which failed
+//!
+//! and the serialized error itself as the extension member `error`, so that a Rust client which
+//! receives a problem still recovers the exact error. Help messages and challenges are added as
+//! extension members, as in other JSON error bodies (see [wire::error](super::error)).
+
+use crate::error::Error;
+use http_types::{Mime, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+/// The content type of problem details.
+pub const CONTENT_TYPE: &str = "application/problem+json";
+
+/// The [Mime] for [CONTENT_TYPE].
+pub fn mime() -> Mime {
+    Mime::from_str(CONTENT_TYPE).unwrap()
+}
+
+/// The `type` of problems which have no more specific type.
+pub const ABOUT_BLANK: &str = "about:blank";
+
+/// The name of the extension member containing the serialized error.
+pub const ERROR_FIELD: &str = "error";
+
+/// An RFC 7807 problem details object.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Problem {
+    #[serde(rename = "type", default = "about_blank")]
+    pub problem_type: String,
+    #[serde(default)]
+    pub title: String,
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    /// Extension members, including the serialized error.
+    #[serde(flatten)]
+    pub extensions: serde_json::Map<String, serde_json::Value>,
+}
+
+fn about_blank() -> String {
+    ABOUT_BLANK.into()
+}
+
+impl Problem {
+    /// Describe `err`, which was caused by a request for `instance`.
+    pub fn from_error<E: Error>(err: &E, instance: Option<String>) -> Self {
+        let status = err.status();
+        let mut extensions = serde_json::Map::new();
+        if let Ok(err) = serde_json::to_value(err) {
+            extensions.insert(ERROR_FIELD.into(), err);
+        }
+        Self {
+            problem_type: err.problem_type().unwrap_or_else(about_blank),
+            title: status.canonical_reason().into(),
+            status: status as u16,
+            detail: Some(err.to_string()),
+            instance,
+            extensions,
+        }
+    }
+
+    /// The status code of this problem.
+    ///
+    /// If the problem contains a status which is not a valid HTTP status code, this returns
+    /// `InternalServerError`.
+    pub fn status(&self) -> StatusCode {
+        StatusCode::try_from(self.status).unwrap_or(StatusCode::InternalServerError)
+    }
+
+    /// The error in the `error` extension member, if it is an `E`.
+    pub fn error<E: Error>(&self) -> Option<E> {
+        serde_json::from_value(self.extensions.get(ERROR_FIELD)?.clone()).ok()
+    }
+
+    /// Convert this problem to a specific error type.
+    ///
+    /// If the problem contains a serialized `E`, that is the result. Otherwise, the result is
+    /// constructed using [Error::catch_all_with_status], with the detail of the problem, or its
+    /// title if it has no detail.
+    pub fn into_error<E: Error>(self) -> E {
+        if let Some(err) = self.error() {
+            return err;
+        }
+        let status = self.status();
+        E::catch_all_with_status(status, self.detail.unwrap_or(self.title))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use snafu::Snafu;
+
+    #[derive(Clone, Debug, Deserialize, Serialize, Snafu, PartialEq, Eq)]
+    #[snafu(display("{}", msg))]
+    struct Error {
+        msg: String,
+    }
+
+    impl crate::Error for Error {
+        fn catch_all(msg: String) -> Self {
+            Self { msg }
+        }
+
+        fn status(&self) -> StatusCode {
+            StatusCode::NotFound
+        }
+
+        fn problem_type(&self) -> Option<String> {
+            Some("https://docs.espressosys.com/errors/not-found".into())
+        }
+    }
+
+    #[test]
+    fn test_problem() {
+        let err = Error {
+            msg: "no such block".into(),
+        };
+        let problem = Problem::from_error(&err, Some("/block/7".into()));
+        let json = serde_json::to_value(&problem).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "type": "https://docs.espressosys.com/errors/not-found",
+                "title": "Not Found",
+                "status": 404,
+                "detail": "no such block",
+                "instance": "/block/7",
+                "error": {"msg": "no such block"},
+            })
+        );
+        let problem: Problem = serde_json::from_value(json).unwrap();
+        assert_eq!(problem.into_error::<Error>(), err);
+
+        // Problems from other services have only the standard members, some of them optional.
+        let problem: Problem =
+            serde_json::from_str(r#"{"status": 503, "title": "Service Unavailable"}"#).unwrap();
+        assert_eq!(problem.problem_type, ABOUT_BLANK);
+        assert_eq!(problem.status(), StatusCode::ServiceUnavailable);
+        assert_eq!(problem.into_error::<Error>().msg, "Service Unavailable");
+    }
+}
@@ -0,0 +1,353 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Net library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Streaming bodies, which are never held in memory all at once.
+//!
+//! A body read with [read_body](super::body::read_body) must fit in memory, and under
+//! [SIZE_LIMIT]. For very large uploads, like a relayer's batch of transactions, the items can
+//! instead be sent as a stream: the sender serializes them one at a time with [encode], and the
+//! receiver deserializes them one at a time with [decode], as they arrive.
+//!
+//! A streaming body is a sequence of frames. Each frame is the length of an item, as 4 little-endian
+//! bytes, followed by the item, serialized according to the Content-Type of the body (JSON or
+//! binary). The size limit applies to each item separately, not to the whole body. Since the
+//! Content-Type is that of the items, the receiving endpoint must know to expect a stream.
+//!
+//! Progress of a body of either kind can be observed with [with_progress].
+
+use super::binary::{self, SIZE_LIMIT};
+use super::body::{self, too_large, Origin};
+use futures::io::{AsyncBufRead, AsyncRead, AsyncReadExt};
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use http_types::{mime, Body, Error, Mime, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A streaming body, containing each item of `items` as it becomes available.
+///
+/// The body has no declared length, so it is sent with chunked transfer encoding.
+///
+/// # Panics
+///
+/// Panics if `ty` is not `application/json` or `application/octet-stream`.
+pub fn encode<T, S>(ty: Mime, items: S) -> Body
+where
+    T: Serialize,
+    S: Stream<Item = T> + Unpin + Send + Sync + 'static,
+{
+    assert!(
+        ty == mime::JSON || ty == mime::BYTE_STREAM,
+        "unsupported content type {}",
+        ty
+    );
+    let encode = {
+        let ty = ty.clone();
+        move |item: &T| encode_item(&ty, item)
+    };
+    let mut body = Body::from_reader(Encoder::new(items, encode), None);
+    body.set_mime(ty);
+    body
+}
+
+/// Deserialize the items of a streaming body as they arrive.
+///
+/// `content_type` is the value of the Content-Type header of the message containing `body`. The
+/// stream ends after the first error.
+pub fn decode<T: DeserializeOwned>(
+    origin: Origin,
+    content_type: Option<String>,
+    body: Body,
+) -> BoxStream<'static, Result<T, Error>> {
+    stream::unfold(Some(body), move |body| {
+        let content_type = content_type.clone();
+        async move {
+            let mut body = body?;
+            match read_frame(origin, &mut body).await {
+                Ok(Some(frame)) => {
+                    let item = body::decode(origin, content_type.as_deref(), &frame);
+                    let next = if item.is_ok() { Some(body) } else { None };
+                    Some((item, next))
+                }
+                Ok(None) => None,
+                Err(err) => Some((Err(err), None)),
+            }
+        }
+    })
+    .boxed()
+}
+
+/// A body which reports its progress as it is read.
+///
+/// `progress` is called with the total number of bytes read so far each time more of the body is
+/// read. The length and content type of `body` are preserved.
+pub fn with_progress(body: Body, progress: impl Fn(u64) + Send + Sync + 'static) -> Body {
+    let len = body.len();
+    let mime = body.mime().clone();
+    let mut body = Body::from_reader(
+        Progress {
+            body,
+            read: 0,
+            progress: Box::new(progress),
+        },
+        len,
+    );
+    body.set_mime(mime);
+    body
+}
+
+fn encode_item<T: Serialize>(ty: &Mime, item: &T) -> io::Result<Vec<u8>> {
+    let bytes = if *ty == mime::JSON {
+        serde_json::to_vec(item).map_err(io::Error::from)?
+    } else {
+        binary::serialize(item).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+    };
+    if bytes.len() as u64 > SIZE_LIMIT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("stream item exceeds the limit of {} bytes", SIZE_LIMIT),
+        ));
+    }
+    let mut frame = Vec::with_capacity(4 + bytes.len());
+    frame.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&bytes);
+    Ok(frame)
+}
+
+// Read the next frame, or [None] at the end of the body.
+async fn read_frame(origin: Origin, body: &mut Body) -> Result<Option<Vec<u8>>, Error> {
+    let mut len = [0; 4];
+    let mut filled = 0;
+    while filled < len.len() {
+        match body.read(&mut len[filled..]).await? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(truncated(origin)),
+            n => filled += n,
+        }
+    }
+    let len = u32::from_le_bytes(len) as u64;
+    if len > SIZE_LIMIT {
+        return Err(too_large());
+    }
+    let mut frame = vec![0; len as usize];
+    body.read_exact(&mut frame)
+        .await
+        .map_err(|_| truncated(origin))?;
+    Ok(Some(frame))
+}
+
+pub(super) fn truncated(origin: Origin) -> Error {
+    let status = match origin {
+        Origin::Request => StatusCode::BadRequest,
+        Origin::Response => StatusCode::InternalServerError,
+    };
+    Error::from_str(status, "stream ends in the middle of an item")
+}
+
+// Serializes items into frames as the body is read, using `encode` to produce the frame for each
+// item. Items are only taken from the stream when the previous frame has been read, so a slow
+// reader slows down the producer of the items.
+pub(super) struct Encoder<S, F> {
+    items: S,
+    encode: F,
+    frame: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<S, F> Encoder<S, F> {
+    pub(super) fn new(items: S, encode: F) -> Self {
+        Self {
+            items,
+            encode,
+            frame: Vec::new(),
+            pos: 0,
+            done: false,
+        }
+    }
+}
+
+impl<T, S, F> AsyncBufRead for Encoder<S, F>
+where
+    S: Stream<Item = T> + Unpin,
+    F: Fn(&T) -> io::Result<Vec<u8>> + Unpin,
+{
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        while this.pos == this.frame.len() && !this.done {
+            match this.items.poll_next_unpin(cx) {
+                Poll::Ready(Some(item)) => {
+                    this.frame = (this.encode)(&item)?;
+                    this.pos = 0;
+                }
+                Poll::Ready(None) => this.done = true,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(&this.frame[this.pos..]))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.get_mut().pos += amt;
+    }
+}
+
+impl<T, S, F> AsyncRead for Encoder<S, F>
+where
+    S: Stream<Item = T> + Unpin,
+    F: Fn(&T) -> io::Result<Vec<u8>> + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let available = match self.as_mut().poll_fill_buf(cx) {
+            Poll::Ready(Ok(available)) => available,
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => return Poll::Pending,
+        };
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+struct Progress {
+    body: Body,
+    read: u64,
+    progress: Box<dyn Fn(u64) + Send + Sync>,
+}
+
+impl Progress {
+    fn advance(&mut self, n: usize) {
+        if n > 0 {
+            self.read += n as u64;
+            (self.progress)(self.read);
+        }
+    }
+}
+
+impl AsyncRead for Progress {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.body).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            this.advance(n);
+        }
+        res
+    }
+}
+
+impl AsyncBufRead for Progress {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().body).poll_fill_buf(cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        Pin::new(&mut this.body).consume(amt);
+        this.advance(amt);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::TryStreamExt;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    async fn round_trip(ty: Mime, items: Vec<u64>) -> Vec<u64> {
+        let body = encode(ty.clone(), stream::iter(items));
+        assert_eq!(body.len(), None);
+        decode::<u64>(Origin::Request, Some(ty.to_string()), body)
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_round_trip() {
+        let items = (0..1000).collect::<Vec<u64>>();
+        assert_eq!(round_trip(mime::JSON, items.clone()).await, items);
+        assert_eq!(round_trip(mime::BYTE_STREAM, items.clone()).await, items);
+        assert_eq!(
+            round_trip(mime::BYTE_STREAM, vec![]).await,
+            Vec::<u64>::new()
+        );
+    }
+
+    #[async_std::test]
+    async fn test_malformed() {
+        let ty = Some(mime::BYTE_STREAM.to_string());
+        let decode_bytes = |bytes: Vec<u8>| {
+            decode::<u64>(Origin::Request, ty.clone(), Body::from(bytes)).collect::<Vec<_>>()
+        };
+
+        // A truncated item.
+        let mut bytes = 8u32.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[1, 2, 3]);
+        let items = decode_bytes(bytes).await;
+        assert_eq!(items.len(), 1);
+        assert_eq!(
+            items[0].as_ref().unwrap_err().status(),
+            StatusCode::BadRequest
+        );
+
+        // An item which is too large is rejected before it is read.
+        let bytes = u32::MAX.to_le_bytes().to_vec();
+        let items = decode_bytes(bytes).await;
+        assert_eq!(
+            items[0].as_ref().unwrap_err().status(),
+            StatusCode::PayloadTooLarge
+        );
+    }
+
+    #[async_std::test]
+    async fn test_progress() {
+        let read = Arc::new(AtomicU64::new(0));
+        let body = with_progress(Body::from(vec![0u8; 10_000]), {
+            let read = read.clone();
+            move |n| read.store(n, Ordering::SeqCst)
+        });
+        assert_eq!(body.len(), Some(10_000));
+        assert_eq!(body.into_bytes().await.unwrap().len(), 10_000);
+        assert_eq!(read.load(Ordering::SeqCst), 10_000);
+    }
+
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[async_std::test]
+    async fn test_server() {
+        let mut app = tide::new();
+        app.at("/batch")
+            .post(|mut req: tide::Request<()>| async move {
+                let mut items = crate::server::request_body_stream::<u64, _>(&mut req);
+                let mut sum = 0;
+                while let Some(item) = items.next().await {
+                    sum += item?;
+                }
+                Ok(sum.to_string())
+            });
+        let mut req = tide::http::Request::new(
+            tide::http::Method::Post,
+            tide::http::Url::parse("http://localhost/batch").unwrap(),
+        );
+        req.set_body(crate::client::stream_body(
+            mime::BYTE_STREAM,
+            stream::iter(1..=100u64),
+        ));
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(res.body_string().await.unwrap(), "5050");
+    }
+}